@@ -1,58 +1,296 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use daemonize::Daemonize;
-use env_logger::Env;
 use libmount::mountinfo;
 use libmount::Overlay;
-use log::{error, info, LevelFilter};
-use nix::mount::umount;
-use nix::unistd::Uid;
+use nix::mount::{umount, umount2, MntFlags, MsFlags};
+use nix::sys::resource::{getrusage, UsageWho};
+use nix::sys::stat::{umask, Mode};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, ForkResult, Uid};
 use os_pipe::{PipeReader, PipeWriter};
+#[cfg(feature = "kernel-mount")]
+use puzzlefs_lib::reader::kernel;
 use puzzlefs_lib::{
-    builder::{add_rootfs_delta, build_initial_rootfs, enable_fs_verity},
+    api::{FileKind, FindOptions, ImageHandle, NamePattern, TagRef},
+    builder::{
+        add_rootfs_delta, build_initial_rootfs, enable_fs_verity, flatten, migrate_rootfs,
+        BuilderConfig, ChunkerConfig,
+    },
     compression::{Noop, Zstd},
-    extractor::extract_rootfs,
-    fsverity_helpers::get_fs_verity_digest,
-    oci::Image,
-    reader::{fuse::PipeDescriptor, mount, spawn_mount},
+    delta::{apply_delta, create_delta},
+    disk_usage::{chunk_graph, disk_usage},
+    export::{
+        composefs::export_composefs,
+        content_manifest::{attach_content_manifest, content_manifest},
+        oci_layer::export_oci_layer,
+        squashfs::export_squashfs,
+    },
+    extractor::{extract_rootfs, ExistingFiles, ExtractorConfig},
+    fsverity_helpers::{get_fs_verity_digest, FsVeritySigner, VerityHash},
+    idmap::{IdMap, IdRange},
+    kernel_layout::kernel_layout,
+    mode_policy::{ModeRule, PathPattern},
+    oci::{
+        blob_store::BlobStore,
+        copy,
+        encryption::{ChunkEncryption, DecryptionKeys},
+        parse_platform,
+        remote::{self, open_remote, BlobCache},
+        sbom::SbomFormat,
+        server, Image, ImageConfiguration, ImageManifest, ImageRef, MediaType, Platform, ReadHints,
+        Reference, ANNOTATION_REVISION,
+    },
+    reader::{
+        access_log::read_access_log,
+        control::{self, OverlayMount, RemountPolicy, Response},
+        fscache::FscacheDaemon,
+        fuse::PipeDescriptor,
+        layer_store, mount,
+        mount_state::{self, MountKind, MountState},
+        ninep, prefetch, spawn_mount, verify_file, FileReader, FuseConfig, PuzzleFS, WalkPuzzleFS,
+        PUZZLEFS_IMAGE_MANIFEST_VERSION,
+    },
+    symlink_policy::SymlinkPolicy,
+    trust_store::{TrustStore, TrustedDigest},
+    xattr_filter::{XattrFilter, XattrPattern},
 };
-use std::ffi::{OsStr, OsString};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::Arc;
-use syslog::{BasicLogger, Facility, Formatter3164};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use tracing::{error, info, warn, Level, Metadata};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer};
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Opts {
+    /// the format of the log messages; RUST_LOG selects which ones are logged
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
     #[command(subcommand)]
     subcmd: SubCommand,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Subcommand)]
 enum SubCommand {
     Build(Build),
     Mount(Mount),
     Umount(Umount),
     Extract(Extract),
+    Sign(Sign),
+    VerifySignature(VerifySignature),
+    Manifest(Manifest),
+    AttachSbom(AttachSbom),
     EnableFsVerity(FsVerity),
+    Gc(Gc),
+    LayerStore(LayerStore),
+    Convert(Convert),
+    Mounts(Mounts),
+    RemountTag(RemountTag),
+    Commit(Commit),
+    Migrate(Migrate),
+    Flatten(Flatten),
+    Delta(Delta),
+    Trust(Trust),
+    Serve(Serve),
+    Fscache(Fscache),
+    Serve9p(Serve9p),
+    Push(Push),
+    Pull(Pull),
+    Copy(CopyTag),
+    Prefetch(Prefetch),
+    Inspect(Inspect),
+    Referrers(Referrers),
+    Find(Find),
+    Cat(Cat),
+    Stat(Stat),
+    Verify(Verify),
+    Du(Du),
+    Analyze(Analyze),
+    Bench(Bench),
+    DumpKernelLayout(DumpKernelLayout),
 }
 
 #[derive(Args)]
 struct Build {
     rootfs: String,
-    oci_dir: String,
+    oci_dir: ImageRef,
     #[arg(short, long, value_name = "base-layer")]
     base_layer: Option<String>,
+    /// merge the delta and the metadata layers of its base layer into a single layer
+    #[arg(long, requires = "base_layer")]
+    squash: bool,
     #[arg(short, long, value_name = "compressed")]
     compression: bool,
+    #[arg(long, value_name = "build-cache")]
+    build_cache: Option<PathBuf>,
+    #[arg(long, value_name = "chunk-pool")]
+    chunk_pool: Option<PathBuf>,
+    #[arg(long, value_name = "blob-store")]
+    blob_store: Option<PathBuf>,
+    #[arg(long)]
+    stats: bool,
+    /// mark the image as requiring fs-verity, so it can only be mounted with its manifest digest
+    #[arg(long)]
+    require_verity: bool,
+    /// the hash algorithm of the fs-verity digests
+    #[arg(long, value_name = "sha256|sha512", default_value_t = VerityHash::Sha256)]
+    verity_hash: VerityHash,
+    /// make every file of the image belong to this uid and gid
+    #[arg(long, value_name = "uid:gid", value_parser = parse_owner)]
+    chown: Option<(u32, u32)>,
+    /// map the uids of the source files back to the image, as image uid:host uid:count; can be
+    /// repeated
+    #[arg(long, value_name = "inside:outside:count", conflicts_with = "chown")]
+    uid_map: Vec<IdRange>,
+    /// map the gids of the source files back to the image, as image gid:host gid:count; can be
+    /// repeated
+    #[arg(long, value_name = "inside:outside:count", conflicts_with = "chown")]
+    gid_map: Vec<IdRange>,
+    /// the architecture the image is for, e.g. arm64; the host's by default. Images built for
+    /// several platforms under the same tag are kept side by side
+    #[arg(long)]
+    arch: Option<String>,
+    /// the operating system the image is for; the host's by default
+    #[arg(long)]
+    os: Option<String>,
+    /// embed this OCI image config (environment, entrypoint, labels...) in the image
+    #[arg(long, value_name = "config.json")]
+    config: Option<PathBuf>,
+    /// add a label to the image config; can be repeated
+    #[arg(long, value_name = "key=value", value_parser = parse_label)]
+    label: Vec<(String, String)>,
+    /// add an environment variable to the image config; can be repeated
+    #[arg(long, value_name = "key=value")]
+    env: Vec<String>,
+    /// set the entrypoint of the image config, as a JSON array or a single executable
+    #[arg(long, value_parser = parse_entrypoint)]
+    entrypoint: Option<Vec<String>>,
+    /// annotate the manifest with the build time (SOURCE_DATE_EPOCH if set), the puzzlefs version
+    /// and the chunker parameters
+    #[arg(long)]
+    stamp: bool,
+    /// annotate the manifest with the revision of the sources the image was built from
+    #[arg(long)]
+    revision: Option<String>,
+    /// add an annotation to the manifest; can be repeated
+    #[arg(long, value_name = "key=value", value_parser = parse_label)]
+    annotation: Vec<(String, String)>,
+    /// fetch the chunks of this access log (see mount --record-access) first in lazy pulls
+    #[arg(long, value_name = "access log")]
+    landmarks: Option<PathBuf>,
+    /// fail if the metadata breaks the invariants the kernel driver relies on, see
+    /// dump-kernel-layout
+    #[arg(long)]
+    kernel_compat: bool,
+    #[command(flatten)]
+    xattrs: XattrArgs,
+    /// clear the setuid and setgid bits of everything but directories
+    #[arg(long)]
+    clear_setuid: bool,
+    /// force the mode of the paths matching this glob, e.g. '/usr/bin/*=0755'; can be repeated,
+    /// the last matching one wins
+    #[arg(long, value_name = "pattern=mode")]
+    force_mode: Vec<ModeRule>,
+    /// store the chunks smaller than this many bytes together in pack blobs, to get fewer blobs
+    /// out of rootfses with many small files
+    #[arg(long, value_name = "bytes")]
+    pack_chunks_below: Option<u32>,
+    /// store the contents of the files smaller than this many bytes in the metadata, e.g. 256;
+    /// older readers can't read the image
+    #[arg(long, value_name = "bytes")]
+    inline_files_below: Option<u64>,
+    /// keep about this much of the metadata in memory while building (e.g. 512M), writing the
+    /// rest to a temporary file in $TMPDIR
+    #[arg(long, value_name = "size", value_parser = parse_size)]
+    memory_budget: Option<u64>,
+    /// store the files which change during the build at the size they had when walked, instead
+    /// of failing
+    #[arg(long)]
+    ignore_changing_files: bool,
+    /// chunk the rootfs and report how much it would add to the image, without writing anything
+    #[arg(long)]
+    dry_run: bool,
+    /// the rootfs is the upper directory of an overlay mounted on the base layer: add what it
+    /// changes, deleting the files it has whiteouts for
+    #[arg(long, requires = "base_layer")]
+    from_overlay_upper: bool,
+    /// don't descend into the filesystems mounted below the rootfs, storing their mountpoints as
+    /// empty directories; the default
+    #[arg(long, overrides_with = "cross_filesystem")]
+    one_file_system: bool,
+    /// descend into the filesystems mounted below the rootfs, bind mounts included
+    #[arg(long, overrides_with = "one_file_system")]
+    cross_filesystem: bool,
+    /// store what the symlinks matching this glob point to rather than the symlinks, e.g.
+    /// '/etc/resolv.conf'; can be repeated
+    #[arg(long, value_name = "pattern")]
+    follow_symlinks: Vec<PathPattern>,
+    /// rewrite absolute symlink targets relative to the symlinks, so they point inside the image
+    #[arg(long)]
+    relative_symlinks: bool,
+    /// fail the build on symlinks whose target climbs above the root of the rootfs
+    #[arg(long)]
+    reject_escaping_symlinks: bool,
+    /// attach the listing of the files of the image, with their digests, see the manifest command
+    #[arg(long, conflicts_with = "dry_run")]
+    content_manifest: bool,
+    /// encrypt the chunks of the image for the holder of the private key matching this PEM RSA
+    /// public key; can be repeated
+    #[arg(long, value_name = "public key")]
+    encrypt_recipient: Vec<PathBuf>,
+    /// don't sync the blobs and index.json to disk as they're written: faster, but a crash may
+    /// leave the image with truncated blobs
+    #[arg(long)]
+    no_fsync: bool,
+    /// hash the files before chunking them, so the copies of a file aren't chunked again
+    #[arg(long)]
+    dedup_files: bool,
+    /// record the sha256 digest of each file in its inode, for the verify command and the checks
+    /// of extract --check and mount --verify-files
+    #[arg(long)]
+    file_digests: bool,
+    /// how files are split into chunks: fastcdc, gear or fixed, optionally followed by their
+    /// parameters in bytes, e.g. 'gear min=8192 avg=32768 max=131072' or 'fixed size=65536'.
+    /// Deltas default to the chunker of their base layer, fastcdc otherwise
+    #[arg(long, value_name = "chunker")]
+    chunker: Option<ChunkerConfig>,
+    /// restart the chunker at the start of each file, so adding or changing a file doesn't change
+    /// the chunks of the files after it; deltas default to what their base layer did
+    #[arg(long)]
+    anchor_files: bool,
+    /// chunk the files larger than this on their own, in worker threads, e.g. '256M'
+    #[arg(long, value_name = "size", value_parser = parse_size)]
+    parallel_chunking_above: Option<u64>,
+    /// the number of worker threads of --parallel-chunking-above, 0 for one per CPU
+    #[arg(long, value_name = "threads", default_value_t = 0)]
+    chunking_threads: usize,
 }
 
 #[derive(Args)]
 struct Mount {
-    oci_dir: String,
+    /// the image and the tag to mount, as oci_dir:tag, or oci_dir@sha256:<digest> to mount a
+    /// manifest whatever the tags point to
+    oci_dir: ImageRef,
     mountpoint: String,
     #[arg(short, long)]
     foreground: bool,
@@ -62,35 +300,773 @@ struct Mount {
     options: Option<Vec<String>>,
     #[arg(short, long, value_name = "fs verity root digest")]
     digest: Option<String>,
+    /// mount the image only if its digest is the one trusted under this name, see the trust
+    /// command
+    #[arg(long, value_name = "name", conflicts_with = "digest")]
+    trust: Option<String>,
     #[arg(short, long, conflicts_with = "foreground")]
     writable: bool,
     #[arg(short, long, conflicts_with = "foreground")]
     persist: Option<String>,
+    /// make the mount writable without overlayfs, storing the changes in this directory
+    #[arg(long, conflicts_with_all = ["writable", "persist"])]
+    upper: Option<PathBuf>,
+    /// stack another tag of the image below the mounted one, as tag[@fs verity digest]; can be
+    /// repeated, topmost first
+    #[arg(long, value_name = "tag")]
+    lower: Vec<String>,
+    /// refuse to mount the image unless it's signed with the key matching this public key
+    #[arg(long, value_name = "public key")]
+    verify_key: Option<PathBuf>,
+    /// refuse to read any file of the image which doesn't have fs-verity enabled
+    #[arg(long)]
+    require_verity: bool,
+    /// don't sandbox the background FUSE daemon (namespaces, capabilities and seccomp)
+    #[arg(long, conflicts_with = "foreground")]
+    no_sandbox: bool,
+    /// shift the uids of the image, as image uid:host uid:count; can be repeated
+    #[arg(long, value_name = "inside:outside:count")]
+    uid_map: Vec<IdRange>,
+    /// shift the gids of the image, as image gid:host gid:count; can be repeated
+    #[arg(long, value_name = "inside:outside:count")]
+    gid_map: Vec<IdRange>,
+    /// describe the image with user.puzzlefs.* xattrs on the root of the mount
+    #[arg(long)]
+    provenance_xattrs: bool,
+    /// mount the image built for this platform instead of the host's
+    #[arg(long, value_name = "os/arch[/variant]", value_parser = parse_platform_arg)]
+    platform: Option<Platform>,
+    /// run the daemon under a supervisor which mounts the image again if the daemon crashes
+    #[arg(long, conflicts_with_all = ["foreground", "writable", "persist"])]
+    supervise: bool,
+    /// serve Prometheus metrics of the mount over HTTP on this address, e.g. 127.0.0.1:9344
+    #[arg(long, value_name = "address")]
+    metrics_addr: Option<SocketAddr>,
+    /// write the digests of the chunks the mount reads to this file, in the order they are first
+    /// read, to build the image again with them as landmarks
+    #[arg(long, value_name = "access log")]
+    record_access: Option<PathBuf>,
+    #[command(flatten)]
+    remote: RemoteArgs,
+    #[command(flatten)]
+    read_hints: ReadHintArgs,
+    /// mount with FUSE even if the kernel has the puzzlefs driver
+    #[cfg(feature = "kernel-mount")]
+    #[arg(long)]
+    fuse: bool,
+    /// let the device nodes of the image be opened, instead of mounting it nodev; only works as
+    /// root
+    #[arg(long)]
+    allow_devices: bool,
+    /// decrypt the encrypted chunks of the image with this PEM private key; can be repeated
+    #[arg(long, value_name = "private key")]
+    decryption_key: Vec<PathBuf>,
+    /// check the whole contents of each file against its digest the first time it's opened; slow,
+    /// for debugging
+    #[arg(long)]
+    verify_files: bool,
+    #[command(flatten)]
+    kernel_cache: KernelCacheArgs,
+}
+
+#[derive(Args)]
+struct KernelCacheArgs {
+    /// how long the kernel caches the names it looks up, and their attributes; forever by
+    /// default, or a second for writable mounts
+    #[arg(long, value_name = "seconds", value_parser = parse_seconds)]
+    entry_timeout: Option<Duration>,
+    /// how long the kernel caches the attributes of the files, with the same defaults; the names
+    /// looked up aren't cached longer either
+    #[arg(long, value_name = "seconds", value_parser = parse_seconds)]
+    attr_timeout: Option<Duration>,
+    /// let the kernel read at most this much ahead of the reads from a file, e.g. 512K
+    #[arg(long, value_name = "size", value_parser = parse_readahead)]
+    max_readahead: Option<u32>,
+    /// have the kernel drop the cached contents of a file when its size or modification time
+    /// change; always on for writable mounts
+    #[arg(long)]
+    auto_inval_data: bool,
+}
+
+#[derive(Args)]
+struct ReadHintArgs {
+    /// don't update the access time of the chunk blobs when reading them
+    #[arg(long)]
+    noatime: bool,
+    /// tell the kernel the chunk blobs are read sequentially, so it reads further ahead
+    #[arg(long)]
+    sequential: bool,
+    /// drop the chunks from the page cache once they're read, so a large image doesn't evict
+    /// everything else
+    #[arg(long)]
+    drop_cache: bool,
+}
+
+impl ReadHintArgs {
+    fn hints(&self) -> ReadHints {
+        ReadHints {
+            noatime: self.noatime,
+            sequential: self.sequential,
+            drop_cache: self.drop_cache,
+        }
+    }
+}
+
+#[derive(Args)]
+struct RemoteArgs {
+    /// fetch the blobs the image doesn't have from this remote, for tags pulled with --lazy
+    #[arg(long, value_name = "url")]
+    remote: Option<String>,
+    /// keep the blobs fetched from the remote in this directory rather than in the image; mounts
+    /// of other images can share it
+    #[arg(long, value_name = "dir", requires = "remote")]
+    cache_dir: Option<PathBuf>,
+    /// evict the least recently used blobs once the cache grows past this size, e.g. 512M or 20G
+    #[arg(long, value_name = "size", default_value = "10G", value_parser = parse_size)]
+    cache_size: u64,
+}
+
+/// Fetch and decompress the chunks of some files ahead of time, so a container started from a
+/// lazily pulled image doesn't wait for them
+#[derive(Args)]
+struct Prefetch {
+    oci_dir: ImageRef,
+    /// a file or directory to prefetch, the whole image by default; can be repeated
+    #[arg(long)]
+    path: Vec<PathBuf>,
+    /// how many chunks to fetch at once
+    #[arg(short, long, default_value_t = 8)]
+    jobs: usize,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+    #[command(flatten)]
+    remote: RemoteArgs,
 }
 
 #[derive(Args)]
 struct Umount {
     mountpoint: String,
+    /// also remove the upper and work directories of a writable mount
+    #[arg(long)]
+    cleanup: bool,
 }
 
 #[derive(Args)]
 struct Extract {
-    oci_dir: String,
+    /// the image and the tag to extract, as oci_dir:tag or oci_dir@sha256:<manifest digest>
+    oci_dir: ImageRef,
     extract_dir: String,
+    /// refuse to extract the image unless it's signed with the key matching this public key
+    #[arg(long, value_name = "public key")]
+    verify_key: Option<PathBuf>,
+    /// shift the uids of the image, as image uid:host uid:count; can be repeated
+    #[arg(long, value_name = "inside:outside:count")]
+    uid_map: Vec<IdRange>,
+    /// shift the gids of the image, as image gid:host gid:count; can be repeated
+    #[arg(long, value_name = "inside:outside:count")]
+    gid_map: Vec<IdRange>,
+    /// extract the image built for this platform instead of the host's
+    #[arg(long, value_name = "os/arch[/variant]", value_parser = parse_platform_arg)]
+    platform: Option<Platform>,
+    #[command(flatten)]
+    xattrs: XattrArgs,
+    /// fail if a file other than a directory is setuid or setgid
+    #[arg(long)]
+    refuse_setuid: bool,
+    /// let the paths matching this glob be setuid or setgid despite --refuse-setuid; can be
+    /// repeated
+    #[arg(long, value_name = "pattern", requires = "refuse_setuid")]
+    allow_setuid: Vec<PathPattern>,
+    #[command(flatten)]
+    read_hints: ReadHintArgs,
+    /// replace the files already in the extract dir with the image's, instead of failing
+    #[arg(long, conflicts_with_all = ["skip_existing", "merge"])]
+    overwrite: bool,
+    /// leave the files already in the extract dir as they are, along with what's below them
+    #[arg(long, conflicts_with = "merge")]
+    skip_existing: bool,
+    /// only replace the files already in the extract dir which differ from the image's
+    #[arg(long)]
+    merge: bool,
+    /// delete what the extract dir has besides the files of the image
+    #[arg(long)]
+    delete: bool,
+    /// enable fs-verity on the extracted files, checking their digests against the image's
+    /// contents
+    #[arg(long)]
+    enable_verity: bool,
+    /// the hash algorithm of the fs-verity digests
+    #[arg(
+        long,
+        value_name = "sha256|sha512",
+        default_value_t = VerityHash::Sha256,
+        requires = "enable_verity"
+    )]
+    verity_hash: VerityHash,
+    /// make the extracted files immutable, like chattr +i
+    #[arg(long)]
+    immutable: bool,
+    /// create the device nodes of the image, which needs root; they are left out otherwise
+    #[arg(long)]
+    allow_devices: bool,
+    /// fail if an owner, device node, xattr or mode can't be restored, e.g. without root
+    #[arg(long)]
+    strict: bool,
+    /// print what couldn't be restored as JSON
+    #[arg(long)]
+    json: bool,
+    /// decrypt the encrypted chunks of the image with this PEM private key; can be repeated
+    #[arg(long, value_name = "private key")]
+    decryption_key: Vec<PathBuf>,
+    /// read back the extracted files and check them against their digests in the image
+    #[arg(long)]
+    check: bool,
+}
+
+impl Extract {
+    fn existing_files(&self) -> ExistingFiles {
+        if self.overwrite {
+            ExistingFiles::Overwrite
+        } else if self.skip_existing {
+            ExistingFiles::Skip
+        } else if self.merge {
+            ExistingFiles::Merge
+        } else {
+            ExistingFiles::Fail
+        }
+    }
+}
+
+#[derive(Args)]
+struct XattrArgs {
+    /// leave out the xattrs matching this name, or prefix followed by *, e.g. 'security.*'; can
+    /// be repeated. The xattrs overlayfs keeps for itself (trusted.overlay.*, user.overlay.*)
+    /// are always left out
+    #[arg(long, value_name = "pattern")]
+    drop_xattr: Vec<XattrPattern>,
+    /// keep the xattrs matching this pattern even if another one drops them; can be repeated
+    #[arg(long, value_name = "pattern")]
+    keep_xattr: Vec<XattrPattern>,
+}
+
+impl XattrArgs {
+    fn filter(self) -> XattrFilter {
+        XattrFilter::default()
+            .with_dropped(self.drop_xattr)
+            .with_kept(self.keep_xattr)
+    }
+}
+
+#[derive(Args)]
+struct Sign {
+    oci_dir: ImageRef,
+    /// the PEM private key to sign the image manifest with
+    key: PathBuf,
+}
+
+#[derive(Args)]
+struct VerifySignature {
+    oci_dir: ImageRef,
+    /// the PEM public key of the signer
+    key: PathBuf,
+}
+
+#[derive(Args)]
+struct AttachSbom {
+    oci_dir: ImageRef,
+    /// the SBOM, an SPDX or CycloneDX JSON document
+    sbom: PathBuf,
+    /// the format of the SBOM, detected from the document by default
+    #[arg(long, value_name = "spdx|cyclonedx")]
+    format: Option<SbomFormat>,
+}
+
+#[derive(Args)]
+struct Manifest {
+    oci_dir: ImageRef,
+    /// print the listing of the files instead of attaching it to the image
+    #[arg(long)]
+    print: bool,
 }
 
 #[derive(Args)]
 struct FsVerity {
-    oci_dir: String,
+    oci_dir: ImageRef,
     root_hash: String,
+    /// sign the fs-verity digests with this PEM private key, for kernels requiring signatures
+    #[arg(long, requires = "cert")]
+    signature_key: Option<PathBuf>,
+    /// the PEM certificate matching the signature key
+    #[arg(long, requires = "signature_key")]
+    cert: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct Gc {
+    blob_store: PathBuf,
+}
+
+#[derive(Args)]
+#[group(id = "format", required = true, args = ["to_composefs", "to_squashfs", "to_oci_layer"])]
+struct Convert {
+    oci_dir: ImageRef,
+    out_dir: PathBuf,
+    /// render the image as a composefs (EROFS) image plus an objects directory
+    #[arg(long)]
+    to_composefs: bool,
+    /// write the image as a squashfs file named <tag>.sqfs
+    #[arg(long)]
+    to_squashfs: bool,
+    /// write what the tag changes on top of its base as an OCI layer tar named <tag>.tar, with
+    /// whiteouts for the removed files
+    #[arg(long)]
+    to_oci_layer: bool,
+}
+
+#[derive(Args)]
+struct LayerStore {
+    root: PathBuf,
+    /// images to expose, as reference=oci_dir:tag
+    #[arg(required = true)]
+    images: Vec<String>,
+    #[arg(short, value_delimiter = ',')]
+    options: Option<Vec<String>>,
+}
+
+/// List the background mounts, or send a command to the daemon serving one of them
+#[derive(Args)]
+struct Mounts {
+    mountpoint: Option<PathBuf>,
+    /// status, stats, log-level <filter>, remount-tag <tag> [<digest>] or shutdown
+    #[arg(requires = "mountpoint", default_value = "status")]
+    command: Vec<String>,
+}
+
+/// Switch a read-only background mount to another tag of its image, without unmounting it
+#[derive(Args)]
+struct RemountTag {
+    mountpoint: PathBuf,
+    tag: String,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+}
+
+/// Write the changes made to a writable mount as a new tag, a delta of the mounted one
+#[derive(Args)]
+struct Commit {
+    mountpoint: PathBuf,
+    /// the image of the mount and the new tag, as oci_dir:tag
+    oci_dir: ImageRef,
+    #[arg(short, long, value_name = "compressed")]
+    compression: bool,
+    /// unmount the image and empty the directory of its changes once the tag is written, to
+    /// mount the new tag instead
+    #[arg(long)]
+    clear: bool,
+}
+
+/// Show the manifests of a tag, with their platform, annotations and image config
+#[derive(Args)]
+struct Inspect {
+    /// the image and the tag, as oci_dir:tag or oci_dir@sha256:<manifest or index digest>
+    oci_dir: ImageRef,
+    /// print the SBOM attached to the tag instead, see attach-sbom
+    #[arg(long)]
+    sbom: bool,
+}
+
+/// List the artifacts attached to a manifest, like its signatures and SBOMs
+#[derive(Args)]
+struct Referrers {
+    /// the manifest, as oci_dir:tag or oci_dir@sha256:<digest>
+    oci_dir: ImageRef,
+    /// only list the artifacts of this type
+    #[arg(long, value_name = "media type")]
+    artifact_type: Option<String>,
+}
+
+/// Print the content of a file of a tag, without mounting it
+#[derive(Args)]
+struct Cat {
+    oci_dir: ImageRef,
+    path: PathBuf,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+}
+
+/// Check the files of a tag against the digests recorded in their inodes, see build
+/// --file-digests
+#[derive(Args)]
+struct Verify {
+    oci_dir: ImageRef,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+}
+
+/// Print the metadata of a file of a tag, without mounting it
+#[derive(Args)]
+struct Stat {
+    oci_dir: ImageRef,
+    path: PathBuf,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+}
+
+/// List the files of a tag matching a name, kind, size, permissions or xattr, without extracting
+/// or mounting it
+#[derive(Args)]
+struct Find {
+    oci_dir: ImageRef,
+    /// a glob matching the names of the files, or their whole paths if it contains a /
+    pattern: Option<String>,
+    /// the pattern is a regular expression, searched in the whole paths of the files
+    #[arg(long, requires = "pattern")]
+    regex: bool,
+    #[arg(long = "type", value_name = "file|dir|symlink|fifo|char|block|socket")]
+    kind: Option<FileKind>,
+    #[arg(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// octal permission bits the files must all have, e.g. 4000 for setuid files
+    #[arg(long, value_parser = parse_permissions)]
+    perm: Option<u16>,
+    /// an xattr the files must have, or a prefix followed by *, e.g. security.*
+    #[arg(long)]
+    xattr: Option<String>,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+}
+
+/// Show how much room the files of each directory of a tag take: their size, the size of their
+/// compressed chunks, and their share of the chunks, those used by several files being split
+/// among them
+#[derive(Args)]
+struct Du {
+    oci_dir: ImageRef,
+    /// only show the directories this many levels below the root
+    #[arg(short = 'd', long, value_name = "depth")]
+    max_depth: Option<usize>,
+    #[arg(long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+    #[arg(long)]
+    json: bool,
+}
+
+/// Show which chunks of a tag are shared by the most files, or dump all of them along with the
+/// files using them
+#[derive(Args)]
+struct Analyze {
+    oci_dir: ImageRef,
+    /// write every chunk as digest,offset,size,stored,refcount,file to this CSV file, a line per
+    /// file using it
+    #[arg(long, value_name = "path")]
+    chunks_csv: Option<PathBuf>,
+    /// show the chunks with the most references and their files; 10 of them without --chunks-csv
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+}
+
+/// Run a standardized workload on a rootfs and report how long it took and how big the image is,
+/// to compare versions of puzzlefs with each other or with other tools
+#[derive(Args)]
+struct Bench {
+    workload: BenchWorkload,
+    rootfs: PathBuf,
+    /// where the image is built and extracted, in a directory of its own which is removed
+    /// afterwards; put it on the filesystem the numbers are wanted for
+    work_dir: PathBuf,
+    /// how many times the workload runs; the times reported are the median of the runs
+    #[arg(long, default_value_t = 3)]
+    runs: usize,
+    #[arg(short, long)]
+    compression: bool,
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BenchWorkload {
+    /// build an image of the rootfs from scratch
+    Build,
+    /// read every file of the image, without mounting it; the blobs are in the page cache after
+    /// the first run
+    Read,
+    /// extract the image
+    Extract,
+}
+
+/// Print the offsets of the capnp segments and of the chunk tables of the metadata of a tag, and
+/// check the invariants the kernel driver relies on
+#[derive(Args)]
+struct DumpKernelLayout {
+    oci_dir: ImageRef,
+    #[arg(long)]
+    json: bool,
+}
+
+/// Rewrite an image built by an older puzzlefs in the current layout and manifest version
+#[derive(Args)]
+struct Migrate {
+    oci_dir: ImageRef,
+}
+
+/// Write a tag again with its metadata layers flattened into one and only the chunks it still uses
+#[derive(Args)]
+struct Flatten {
+    oci_dir: ImageRef,
+    new_tag: String,
+}
+
+/// Serve the blobs and manifests of an image over HTTP, for mounts which fetch chunks lazily
+#[derive(Args)]
+struct Serve {
+    oci_dir: PathBuf,
+    /// the address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+}
+
+/// Serve an image to the kernel's EROFS driver through cachefiles, instead of mounting it with
+/// FUSE
+#[derive(Args)]
+struct Fscache {
+    oci_dir: ImageRef,
+    /// mount the image here once the daemon is ready; it can also be mounted with
+    /// mount -t erofs -o fsid=<fsid> none <mountpoint>
+    mountpoint: Option<PathBuf>,
+    /// the name of the image for the kernel, the tag by default
+    #[arg(long)]
+    fsid: Option<String>,
+    /// where the kernel caches the parts of the image it read
+    #[arg(
+        long,
+        value_name = "dir",
+        default_value = "/var/cache/puzzlefs/fscache"
+    )]
+    fscache_dir: PathBuf,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+    #[command(flatten)]
+    remote: RemoteArgs,
+}
+
+/// Export an image over 9P2000.L, for hosts where FUSE isn't available; mount it with
+/// mount -t 9p -o trans=unix,version=9p2000.L,ro <socket> <mountpoint>
+#[derive(Args)]
+struct Serve9p {
+    oci_dir: ImageRef,
+    /// the unix socket to listen on, which only its owner can connect to
+    #[arg(long, required_unless_present = "listen", conflicts_with = "listen")]
+    socket: Option<PathBuf>,
+    /// the TCP address to listen on instead; anyone who can connect can read the image
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+    #[arg(short, long, value_name = "fs verity root digest")]
+    digest: Option<String>,
+    #[command(flatten)]
+    remote: RemoteArgs,
+}
+
+/// Upload a tag to a remote, s3://bucket[/prefix] or http[s]://...
+#[derive(Args)]
+struct Push {
+    oci_dir: ImageRef,
+    remote: String,
+}
+
+/// Download a tag from a remote, s3://bucket[/prefix] or http[s]://...
+#[derive(Args)]
+struct Pull {
+    remote: String,
+    /// where to store the tag, as oci_dir:tag
+    oci_dir: ImageRef,
+    /// only download the manifest and the config, the other blobs are fetched when the image is
+    /// read, see mount --remote
+    #[arg(long)]
+    lazy: bool,
+}
+
+/// Copy a tag to another image, linking or copying only the blobs the other image doesn't have
+#[derive(Args)]
+struct CopyTag {
+    /// the tag to copy, as oci_dir:tag or oci_dir@sha256:<manifest digest>
+    src: ImageRef,
+    /// the image to copy it to, as oci_dir[:tag]; the tag is the same as the source's by default
+    dst: String,
+}
+
+/// Create or apply binary deltas between tags, to update images over the wire
+#[derive(Args)]
+struct Delta {
+    #[command(subcommand)]
+    command: DeltaCommand,
+}
+
+#[derive(Subcommand)]
+enum DeltaCommand {
+    /// Write the delta which adds the tag to an image which has the base tag
+    Create {
+        /// the new tag, as oci_dir:tag
+        oci_dir: ImageRef,
+        /// the tag the delta applies to
+        base: String,
+        output: PathBuf,
+        #[arg(long)]
+        stats: bool,
+    },
+    /// Add the tag of a delta to an image which has its base tag
+    Apply { oci_dir: PathBuf, delta: PathBuf },
+}
+
+/// Manage the fs-verity root digests trusted by name, for mount --trust; they're kept in
+/// ~/.config/puzzlefs/trust and /etc/puzzlefs/trust
+#[derive(Args)]
+struct Trust {
+    #[command(subcommand)]
+    command: TrustCommand,
+}
+
+#[derive(Subcommand)]
+enum TrustCommand {
+    /// Trust a digest under a name, replacing the one it had
+    Add {
+        name: String,
+        /// the hex fs-verity root digest
+        #[arg(
+            required_unless_present = "signed_file",
+            conflicts_with = "signed_file"
+        )]
+        digest: Option<String>,
+        /// read the digest and the name from this file every time the name is used, checking its
+        /// signature
+        #[arg(long, value_name = "file", requires_all = ["signature", "key"])]
+        signed_file: Option<PathBuf>,
+        /// the detached signature of the signed file, raw or base64 encoded
+        #[arg(long, requires = "signed_file")]
+        signature: Option<PathBuf>,
+        /// the PEM public key the signed file is checked with
+        #[arg(long, value_name = "public key", requires = "signed_file")]
+        key: Option<PathBuf>,
+    },
+    /// Stop trusting a name
+    Remove { name: String },
+    /// List the trusted names and the digests they resolve to
+    List,
+}
+
+// prints the fs-verity digest of the manifest of a tag which was just written
+fn print_manifest_digest(image: &Image, tag: &str) -> anyhow::Result<()> {
+    let verity_hash = VerityHash::from_digest(&image.get_pfs_rootfs_verity(tag)?)?;
+    let mut manifest = Vec::new();
+    image
+        .get_image_manifest_fd(tag)?
+        .read_to_end(&mut manifest)?;
+    println!(
+        "puzzlefs image manifest digest: {}",
+        hex::encode(get_fs_verity_digest(&manifest, verity_hash)?)
+    );
+    Ok(())
 }
 
 // set default log level when RUST_LOG environment variable is not set
-fn init_logging(log_level: &str) {
-    env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
+fn env_filter(log_level: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_level))
 }
 
-fn init_syslog(log_level: &str) -> std::io::Result<()> {
+// `timestamps` is false for syslog, which timestamps the messages itself
+fn init_tracing<W>(log_format: LogFormat, log_level: &str, writer: W, timestamps: bool)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let (filter, filter_handle) = reload::Layer::new(env_filter(log_level));
+    let layer = tracing_subscriber::fmt::layer()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(timestamps)
+        .with_writer(writer);
+    let layer = match (log_format, timestamps) {
+        (LogFormat::Text, true) => layer.boxed(),
+        (LogFormat::Text, false) => layer.without_time().boxed(),
+        (LogFormat::Json, true) => layer.json().boxed(),
+        (LogFormat::Json, false) => layer.json().without_time().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .init();
+    // lets `puzzlefs mounts <mountpoint> log-level <filter>` change the filter of a daemon
+    control::set_log_level_handler(move |filter| {
+        let filter = EnvFilter::try_new(filter).map_err(|e| e.to_string())?;
+        filter_handle.reload(filter).map_err(|e| e.to_string())
+    });
+}
+
+fn init_logging(log_format: LogFormat, log_level: &str) {
+    init_tracing(log_format, log_level, std::io::stderr, true);
+}
+
+// Writes each event to syslog, with the severity of its level. The formatter hands over a whole
+// event per writer, so the message is sent when the writer is dropped.
+#[derive(Clone)]
+struct SyslogMakeWriter(Arc<Mutex<Logger<LoggerBackend, Formatter3164>>>);
+
+struct SyslogWriter {
+    logger: Arc<Mutex<Logger<LoggerBackend, Formatter3164>>>,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogWriter {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.buf);
+        let message = message.trim_end();
+        if message.is_empty() {
+            return;
+        }
+        let mut logger = self.logger.lock().unwrap();
+        // there's nowhere left to report a failure to log
+        let _ = match self.level {
+            Level::ERROR => logger.err(message),
+            Level::WARN => logger.warning(message),
+            Level::INFO => logger.info(message),
+            _ => logger.debug(message),
+        };
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogMakeWriter {
+    type Writer = SyslogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogWriter {
+            logger: self.0.clone(),
+            level: Level::INFO,
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogWriter {
+            level: *meta.level(),
+            ..self.make_writer()
+        }
+    }
+}
+
+fn init_syslog(log_format: LogFormat, log_level: &str) -> std::io::Result<()> {
     let formatter = Formatter3164 {
         facility: Facility::LOG_USER,
         hostname: None,
@@ -105,81 +1081,634 @@ fn init_syslog(log_level: &str) -> std::io::Result<()> {
         }
         Ok(logger) => logger,
     };
-    log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-        .map(|()| {
-            log::set_max_level(match log_level {
-                "off" => LevelFilter::Off,
-                "error" => LevelFilter::Error,
-                "warn" => LevelFilter::Warn,
-                "info" => LevelFilter::Info,
-                "debug" => LevelFilter::Debug,
-                "trace" => LevelFilter::Trace,
-                _ => panic!("unexpected log level"),
-            })
-        })
-        .unwrap();
+    init_tracing(
+        log_format,
+        log_level,
+        SyslogMakeWriter(Arc::new(Mutex::new(logger))),
+        false,
+    );
     Ok(())
 }
 
+// a daemon which crashes this soon after it was started counts as crashing right away
+const QUICK_CRASH: Duration = Duration::from_secs(60);
+// the supervisor gives up after this many daemons in a row crashed right away
+const MAX_QUICK_CRASHES: u32 = 5;
+
+// the mountpoint of a dead daemon fails with ENOTCONN until it's unmounted
+fn detach_stale_mount(mountpoint: &Path) -> anyhow::Result<()> {
+    if Uid::effective().is_root() {
+        umount2(mountpoint, MntFlags::MNT_DETACH)?;
+        return Ok(());
+    }
+    let status = std::process::Command::new("fusermount")
+        .arg("-u")
+        .arg("-z")
+        .arg(mountpoint)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("fusermount -u -z {} failed: {status}", mountpoint.display());
+    }
+    Ok(())
+}
+
+// Runs `mount` in a child process and runs it again whenever the child dies without the image
+// having been unmounted. Only the first attempt is given `init_notify`, to tell the parent that
+// the image is mounted; the supervisor closes it, so the parent sees the pipe closed if the first
+// child dies before that.
+fn supervise(
+    mountpoint: &Path,
+    init_notify: PipeWriter,
+    mut mount: impl FnMut(Option<PipeWriter>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut init_notify = Some(init_notify);
+    let mut quick_crashes = 0;
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 0.. {
+        let started = Instant::now();
+        let notify = init_notify.take();
+        // SAFETY: the supervisor is single threaded
+        let child = match unsafe { fork() }? {
+            ForkResult::Child => match mount(notify) {
+                Ok(()) => exit(0),
+                Err(e) => {
+                    error!("mount failed: {e}");
+                    exit(1)
+                }
+            },
+            ForkResult::Parent { child } => {
+                drop(notify);
+                child
+            }
+        };
+        let reason = match waitpid(child, None)? {
+            // the image was unmounted
+            WaitStatus::Exited(_, 0) => return Ok(()),
+            WaitStatus::Exited(_, code) if attempt == 0 && started.elapsed() < QUICK_CRASH => {
+                anyhow::bail!("the daemon failed to mount the image, exit code {code}")
+            }
+            WaitStatus::Exited(_, code) => format!("exited with code {code}"),
+            WaitStatus::Signaled(_, signal, _) => format!("was killed by {signal}"),
+            status => format!("stopped with status {status:?}"),
+        };
+
+        if started.elapsed() < QUICK_CRASH {
+            quick_crashes += 1;
+        } else {
+            quick_crashes = 0;
+            backoff = Duration::from_secs(1);
+        }
+        if quick_crashes >= MAX_QUICK_CRASHES {
+            error!(
+                "puzzlefs daemon {child} for {} {reason}, giving up after {quick_crashes} crashes",
+                mountpoint.display()
+            );
+            detach_stale_mount(mountpoint)?;
+            anyhow::bail!("the daemon keeps crashing");
+        }
+        error!(
+            "puzzlefs daemon {child} for {} {reason}, mounting it again in {backoff:?}",
+            mountpoint.display()
+        );
+        if let Err(e) = detach_stale_mount(mountpoint) {
+            error!("cannot detach the stale mount: {e}");
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(Duration::from_secs(30));
+    }
+    unreachable!()
+}
+
 #[allow(clippy::too_many_arguments)]
 fn mount_background(
     image: Image,
+    oci_dir: &Path,
+    supervised: bool,
     tag: &str,
     mountpoint: &Path,
     options: Option<Vec<String>>,
     manifest_verity: Option<Vec<u8>>,
+    config: FuseConfig,
+    mut state: MountState,
     mut recv: PipeReader,
-    init_notify: &PipeWriter,
+    init_notify: PipeWriter,
     parent_action: impl FnOnce() -> anyhow::Result<()> + 'static,
 ) -> anyhow::Result<()> {
+    // the parent reads until the daemon closes the pipe, its own end mustn't keep it open; it
+    // exits without dropping the writer
+    let parent_notify = init_notify.as_raw_fd();
     let daemonize = Daemonize::new().exit_action(move || {
+        let _ = nix::unistd::close(parent_notify);
         let mut read_buffer = [0];
         if let Err(e) = recv.read_exact(&mut read_buffer) {
-            info!("error reading from pipe {e}")
+            // the daemon closed the pipe without a word, e.g. it crashed
+            error!("the daemon exited before mounting the image: {e}");
+            exit(1);
         } else if read_buffer[0] == b'f' {
             // in case of failure, 'f' is written into the pipe
             // we explicitly exit with an error code, otherwise exit(0) is done by daemonize
             exit(1);
         }
-        if let Err(e) = parent_action() {
-            error!("parent_action error {e}");
+        if let Err(e) = parent_action() {
+            error!("parent_action error {e}");
+        }
+    });
+
+    daemonize.start()?;
+    // the daemon records itself, the parent doesn't know its pid
+    state.pid = Some(std::process::id());
+    save_mount_state(&state);
+    let mut init_notify = Some(init_notify);
+    let result = (|| {
+        if supervised {
+            let options = options.unwrap_or_default();
+            let platform = image.platform();
+            let remote = image.remote();
+            let cache = image.cache();
+            let read_hints = image.read_hints();
+            let decryption_keys = image.decryption_keys();
+            let mut image = Some(image);
+            // .unwrap() here because the pipe is only taken here
+            supervise(mountpoint, init_notify.take().unwrap(), |init_notify| {
+                // the image is opened again for each restart, in case the crash came from it
+                let image = match image.take() {
+                    Some(image) => image,
+                    None => {
+                        let mut image = Image::open(oci_dir)?
+                            .with_platform(platform.clone())
+                            .with_read_hints(read_hints);
+                        if let Some(remote) = &remote {
+                            image = image.with_remote(remote.clone());
+                        }
+                        if let Some(cache) = &cache {
+                            image = image.with_cache(cache.clone());
+                        }
+                        if let Some(keys) = &decryption_keys {
+                            image = image.with_decryption_keys(keys.clone());
+                        }
+                        image
+                    }
+                };
+                Ok(mount(
+                    image,
+                    tag,
+                    mountpoint,
+                    &options[..],
+                    init_notify.map(PipeDescriptor::UnnamedPipe),
+                    manifest_verity.as_deref(),
+                    &config,
+                )?)
+            })?;
+        } else {
+            mount(
+                image,
+                tag,
+                mountpoint,
+                &options.unwrap_or_default()[..],
+                // .unwrap() here because the pipe is only taken here
+                Some(PipeDescriptor::UnnamedPipe(
+                    init_notify.as_ref().unwrap().try_clone()?,
+                )),
+                manifest_verity.as_deref(),
+                &config,
+            )?;
+        }
+        Ok(())
+    })();
+    if let (Err(_), Some(mut init_notify)) = (&result, init_notify) {
+        if let Err(e) = init_notify.write_all(b"f") {
+            error!("puzzlefs will hang because we couldn't write to pipe, {e}");
+        }
+    }
+    // the image is unmounted, or couldn't be mounted. A sandboxed daemon may not be able to
+    // reach the state file anymore, `umount` removes it then.
+    if let Ok(state_dir) = mount_state::state_dir() {
+        let _ = state.remove(&state_dir);
+    }
+    result
+}
+
+// the upper and work directories of a writable mount, next to the mountpoint: the overlay would
+// hide them from `puzzlefs commit` inside it
+fn writable_dirs(mountpoint: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let (Some(parent), Some(name)) = (mountpoint.parent(), mountpoint.file_name()) else {
+        anyhow::bail!("cannot mount a writable image on {}", mountpoint.display());
+    };
+    let dir = |kind: &str| {
+        let mut dir = OsString::from(".");
+        dir.push(name);
+        dir.push(format!(".puzzlefs-{kind}"));
+        parent.join(dir)
+    };
+    Ok((dir("upper"), dir("work")))
+}
+
+// the state is only needed to unmount, so mounting goes on without it
+fn save_mount_state(state: &MountState) {
+    if let Err(e) = mount_state::state_dir().and_then(|dir| state.save(&dir)) {
+        warn!(
+            "cannot record the state of {}, umount will guess it: {e}",
+            state.mountpoint.display()
+        );
+    }
+}
+
+// the image directory and the tag or, for the commands which can pin a manifest, the
+// sha256:<digest> of the manifest, which the library takes wherever it takes a tag
+fn manifest_ref(image_ref: &ImageRef) -> (&Path, String) {
+    (&image_ref.oci_dir, image_ref.reference.to_string())
+}
+
+// the image directory and the tag of the commands which work on tags
+fn tag_ref(image_ref: &ImageRef) -> anyhow::Result<(&Path, &str)> {
+    match image_ref.tag() {
+        Some(tag) => Ok((&image_ref.oci_dir, tag)),
+        None => anyhow::bail!("expected <oci_dir>:<tag>, not a digest: {image_ref}"),
+    }
+}
+
+fn decryption_keys(paths: &[PathBuf]) -> anyhow::Result<Option<Arc<DecryptionKeys>>> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(DecryptionKeys::load(paths)?)))
+}
+
+fn parse_owner(owner: &str) -> Result<(u32, u32), String> {
+    owner
+        .split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+        .ok_or_else(|| format!("expected uid:gid, got {owner}"))
+}
+
+// makes the image fetch the blobs it doesn't have from the remote of `args`, if any
+fn with_remote(
+    mut image: Image,
+    args: &RemoteArgs,
+    manifest_verity: Option<&[u8]>,
+) -> anyhow::Result<Image> {
+    let Some(url) = &args.remote else {
+        return Ok(image);
+    };
+    image = image.with_remote(open_remote(url)?);
+    if let Some(dir) = &args.cache_dir {
+        // fs-verity digests of the cached blobs use the hash of the image's
+        let verity_hash = manifest_verity
+            .map(VerityHash::from_digest)
+            .transpose()?
+            .unwrap_or_default();
+        let cache = BlobCache::open(dir, args.cache_size, verity_hash)?;
+        image = image.with_cache(Arc::new(cache));
+    }
+    Ok(image)
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, unit) = size.split_at(
+        size.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len()),
+    );
+    let shift = match unit {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("invalid size {size}, expected e.g. 512M or 20G")),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size {size}"))
+}
+
+fn parse_readahead(size: &str) -> Result<u32, String> {
+    u32::try_from(parse_size(size)?).map_err(|_| format!("readahead {size} is too large"))
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    seconds
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("invalid number of seconds {seconds}"))
+}
+
+fn parse_permissions(permissions: &str) -> Result<u16, String> {
+    u16::from_str_radix(permissions, 8)
+        .ok()
+        .filter(|permissions| *permissions <= 0o7777)
+        .ok_or_else(|| format!("invalid permissions {permissions}, expected e.g. 4000 or 0755"))
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    label
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got {label}"))
+}
+
+fn parse_entrypoint(entrypoint: &str) -> Result<Vec<String>, String> {
+    if entrypoint.starts_with('[') {
+        serde_json::from_str(entrypoint).map_err(|e| e.to_string())
+    } else {
+        Ok(vec![entrypoint.to_string()])
+    }
+}
+
+// the image config given by --config, amended by the other flags; None if there is none
+fn image_config(b: &Build) -> anyhow::Result<Option<ImageConfiguration>> {
+    if b.config.is_none() && b.label.is_empty() && b.env.is_empty() && b.entrypoint.is_none() {
+        return Ok(None);
+    }
+    let mut image_config = match &b.config {
+        Some(path) => ImageConfiguration::from_file(path)?,
+        None => ImageConfiguration::default(),
+    };
+    let mut config = image_config.config().clone().unwrap_or_default();
+    if !b.label.is_empty() {
+        let mut labels = config.labels().clone().unwrap_or_default();
+        labels.extend(b.label.iter().cloned());
+        config.set_labels(Some(labels));
+    }
+    if !b.env.is_empty() {
+        let mut env = config.env().clone().unwrap_or_default();
+        env.extend(b.env.iter().cloned());
+        config.set_env(Some(env));
+    }
+    if let Some(entrypoint) = &b.entrypoint {
+        config.set_entrypoint(Some(entrypoint.clone()));
+    }
+    image_config.set_config(Some(config));
+    Ok(Some(image_config))
+}
+
+fn parse_platform_arg(platform: &str) -> Result<Platform, String> {
+    parse_platform(platform).map_err(|e| e.to_string())
+}
+
+// the -o option of fuse-overlayfs; the directories are refused rather than escaped if they hold
+// one of the characters which separate the options and the lower directories
+fn fuse_overlayfs_options(
+    lowerdir: &Path,
+    upperdir: &Path,
+    workdir: &Path,
+    allow_devices: bool,
+) -> anyhow::Result<String> {
+    let mut options = Vec::new();
+    for (option, dir) in [
+        ("lowerdir", lowerdir),
+        ("upperdir", upperdir),
+        ("workdir", workdir),
+    ] {
+        let dir = dir
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("the {option} {} isn't UTF-8", dir.display()))?;
+        if dir.contains([',', ':', '\\']) {
+            anyhow::bail!(
+                "the {option} {dir} has a ',', ':' or '\\', which fuse-overlayfs splits on"
+            );
+        }
+        options.push(format!("{option}={dir}"));
+    }
+    if !allow_devices {
+        options.push("nodev".to_string());
+    }
+    Ok(options.join(","))
+}
+
+fn mount_fuse_overlayfs(
+    lowerdir: &Path,
+    upperdir: &Path,
+    workdir: &Path,
+    mountpoint: &Path,
+    allow_devices: bool,
+) -> anyhow::Result<()> {
+    let options = fuse_overlayfs_options(lowerdir, upperdir, workdir, allow_devices)?;
+    let status = std::process::Command::new("fuse-overlayfs")
+        .arg("-o")
+        .arg(options)
+        .arg(mountpoint)
+        .status()
+        .map_err(|e| anyhow::anyhow!("cannot run fuse-overlayfs: {e}"))?;
+    if !status.success() {
+        anyhow::bail!("fuse-overlayfs exited with {status}");
+    }
+    Ok(())
+}
+
+// whether the mount only needs what the kernel driver does; everything else is done by the FUSE
+// daemon, including the mount options, the cache settings and waiting in the foreground
+#[cfg(feature = "kernel-mount")]
+fn kernel_mountable(m: &Mount) -> bool {
+    !m.fuse
+        && !m.foreground
+        && m.options.is_none()
+        && m.kernel_cache.entry_timeout.is_none()
+        && m.kernel_cache.attr_timeout.is_none()
+        && m.kernel_cache.max_readahead.is_none()
+        && !m.kernel_cache.auto_inval_data
+        && !m.writable
+        && m.persist.is_none()
+        && m.upper.is_none()
+        && m.lower.is_empty()
+        && m.init_pipe.is_none()
+        && !m.require_verity
+        && m.uid_map.is_empty()
+        && m.gid_map.is_empty()
+        && !m.provenance_xattrs
+        && !m.supervise
+        && m.metrics_addr.is_none()
+        && m.record_access.is_none()
+        && m.remote.remote.is_none()
+        && m.read_hints.hints() == ReadHints::default()
+        && !m.verify_files
+}
+
+fn fusermount_umount(mountpoint: &Path) -> anyhow::Result<()> {
+    // We call "fusermount -u" because we don't have permissions to umount directly
+    // fusermount and umount binaries have the setuid bit set
+    let status = std::process::Command::new("fusermount")
+        .arg("-u")
+        .arg(mountpoint)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!(
+            "umount exited with status {}",
+            status
+                .code()
+                .map(|code| code.to_string())
+                .unwrap_or("terminated by signal".to_string())
+        );
+    }
+    Ok(())
+}
+
+// the mountpoint of a dead FUSE daemon can't be resolved itself
+fn canonical_mountpoint(mountpoint: &Path) -> anyhow::Result<PathBuf> {
+    match (mountpoint.parent(), mountpoint.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            Ok(fs::canonicalize(parent)?.join(name))
+        }
+        (Some(_), Some(name)) => Ok(std::env::current_dir()?.join(name)),
+        _ => Ok(fs::canonicalize(mountpoint)?),
+    }
+}
+
+fn remove_dir(dir: &Path) -> anyhow::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(anyhow::anyhow!("cannot remove {}: {e}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// takes down what `puzzlefs mount` set up at `mountpoint`, as recorded in its state file
+fn unmount(mountpoint: &Path, cleanup: bool) -> anyhow::Result<()> {
+    let state_dir = mount_state::state_dir()?;
+    let Some(state) = MountState::load(&state_dir, &canonical_mountpoint(mountpoint)?)? else {
+        if cleanup {
+            anyhow::bail!(
+                "no state was recorded for {}, its directories are unknown",
+                mountpoint.display()
+            );
+        }
+        return unmount_by_type(mountpoint);
+    };
+    // e.g. a rootless container which mounted the image in its own namespaces
+    if state.enter_namespaces()? {
+        info!(
+            "unmounting {} in the namespaces it was mounted in",
+            state.mountpoint.display()
+        );
+    }
+    match state.kind {
+        MountKind::Overlay => {
+            umount(&state.mountpoint)?;
+            umount(&state.image_mountpoint)?;
+        }
+        MountKind::FuseOverlayfs => {
+            fusermount_umount(&state.mountpoint)?;
+            fusermount_umount(&state.image_mountpoint)?;
+        }
+        MountKind::Fuse => fusermount_umount(&state.mountpoint)?,
+        MountKind::Kernel => umount(&state.mountpoint)?,
+    }
+    if cleanup {
+        for dir in [&state.upper_dir, &state.work_dir].into_iter().flatten() {
+            remove_dir(dir)?;
+        }
+        if state.image_mountpoint != state.mountpoint {
+            remove_dir(&state.image_mountpoint)?;
+        }
+    }
+    state.remove(&state_dir)?;
+    // the mounts which went away without `puzzlefs umount`
+    if let Err(e) = MountState::remove_stale(&state_dir) {
+        warn!("cannot remove stale mount states: {e}");
+    }
+    Ok(())
+}
+
+// for mounts without a state file, made by older releases
+fn unmount_by_type(mountpoint: &Path) -> anyhow::Result<()> {
+    let mount_type = get_mount_type(mountpoint)?;
+    match mount_type.to_str() {
+        Some("overlay") => {
+            if !Uid::effective().is_root() {
+                anyhow::bail!("Overlay mounts can only be unmounted by the root user!")
+            }
+            umount(mountpoint)?;
+            // Now unmount the read-only puzzlefs mountpoint
+            let pfs_mountpoint = mountpoint.join("ro");
+            umount(pfs_mountpoint.as_os_str())?;
+            // the work and upper directories are kept: without a state file we don't know
+            // whether the upper directory was persisted elsewhere
+        }
+        Some("fuse.fuse-overlayfs") => {
+            // rootless writable mount, the puzzlefs mountpoint is below it
+            fusermount_umount(mountpoint)?;
+            fusermount_umount(&mountpoint.join("ro"))?;
         }
-    });
+        Some("fuse") => fusermount_umount(mountpoint)?,
+        Some("puzzlefs") => umount(mountpoint)?,
+        _ => anyhow::bail!(
+            "Unknown mountpoint type {} for {}",
+            mount_type.to_str().unwrap_or("unknown mount type"),
+            mountpoint.display()
+        ),
+    }
+    Ok(())
+}
 
-    match daemonize.start() {
-        Ok(_) => {
-            mount(
-                image,
-                tag,
-                mountpoint,
-                &options.unwrap_or_default()[..],
-                Some(PipeDescriptor::UnnamedPipe(init_notify.try_clone()?)),
-                manifest_verity.as_deref(),
+// the wall clock time `f` takes, and the CPU time of the whole process meanwhile
+fn measure(f: impl FnOnce() -> anyhow::Result<()>) -> anyhow::Result<(Duration, Duration)> {
+    let cpu_time = || -> anyhow::Result<Duration> {
+        let usage = getrusage(UsageWho::RUSAGE_SELF)?;
+        Ok([usage.user_time(), usage.system_time()]
+            .into_iter()
+            .map(|t| Duration::new(t.tv_sec() as u64, t.tv_usec() as u32 * 1000))
+            .sum())
+    };
+    let (cpu, start) = (cpu_time()?, Instant::now());
+    f()?;
+    Ok((start.elapsed(), cpu_time()? - cpu))
+}
+
+// the size of the files below `dir`
+fn dir_size(dir: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let md = entry.metadata()?;
+        size += if md.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            md.len()
+        };
+    }
+    Ok(size)
+}
+
+// reads every file of `tag` once
+fn read_all_files(oci_dir: &Path, tag: &str) -> anyhow::Result<()> {
+    let mut pfs = PuzzleFS::open(Image::open(oci_dir)?, tag, None)?;
+    let oci = pfs.oci.clone();
+    let mut seen = std::collections::HashSet::new();
+    for entry in WalkPuzzleFS::walk(&mut pfs)? {
+        let entry = entry?;
+        if entry.inode.file_len().is_ok() && seen.insert(entry.inode.ino) {
+            std::io::copy(
+                &mut FileReader::new(&oci, &entry.inode)?,
+                &mut std::io::sink(),
             )?;
         }
-        Err(e) => {
-            return Err(e.into());
-        }
-    };
+    }
     Ok(())
 }
 
-fn parse_oci_dir(oci_dir: &str) -> anyhow::Result<(&str, &str)> {
-    let components: Vec<&str> = oci_dir.split_terminator(":").collect();
-    if components.len() != 2 {
-        anyhow::bail!("Expected oci_dir in the following format <oci_dir>:<tag> ")
+// empties `dir`, keeping the directory itself
+fn clear_dir(dir: &Path) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
     }
-
-    Ok((components[0], components[1]))
+    Ok(())
 }
 
-fn get_mount_type(mountpoint: &str) -> anyhow::Result<OsString> {
+fn get_mount_type(mountpoint: &Path) -> anyhow::Result<OsString> {
     let contents = fs::read_to_string("/proc/self/mountinfo")?;
     let mut parser = mountinfo::Parser::new(contents.as_bytes());
     let mount_info = parser.find(|mount_info| {
         mount_info
             .as_ref()
-            .map(|mount_info| mount_info.mount_point == OsStr::new(mountpoint))
+            .map(|mount_info| mount_info.mount_point == mountpoint.as_os_str())
             .unwrap_or(false)
     });
     let mount_info = mount_info
@@ -189,95 +1718,310 @@ fn get_mount_type(mountpoint: &str) -> anyhow::Result<OsString> {
 
 fn main() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
+    let log_format = opts.log_format;
     match opts.subcmd {
         SubCommand::Build(b) => {
+            // the build phases are logged as spans, RUST_LOG=info shows how long each one took
+            init_logging(log_format, "warn");
+            let image_config = image_config(&b)?;
             let rootfs = Path::new(&b.rootfs);
-            let (oci_dir, tag) = parse_oci_dir(&b.oci_dir)?;
-            let oci_dir = Path::new(oci_dir);
-            let image = Image::new(oci_dir)?;
-            let new_image = match b.base_layer {
+            let (oci_dir, tag) = tag_ref(&b.oci_dir)?;
+            let mut image = match b.blob_store {
+                Some(blob_store) => BlobStore::open(&blob_store)?.attach(oci_dir)?,
+                None => Image::new(oci_dir)?,
+            }
+            .with_durable_writes(!b.no_fsync);
+            let platform = if b.arch.is_some() || b.os.is_some() {
+                let host = Platform::default();
+                let os = b.os.unwrap_or_else(|| host.os().to_string());
+                let arch = b.arch.unwrap_or_else(|| host.architecture().to_string());
+                Some(parse_platform(&format!("{os}/{arch}"))?)
+            } else {
+                None
+            };
+            if let Some(platform) = platform.clone() {
+                image = image.with_platform(platform);
+            }
+            let mut annotations = BTreeMap::from_iter(b.annotation);
+            if let Some(revision) = b.revision {
+                annotations.insert(ANNOTATION_REVISION.to_string(), revision);
+            }
+            let config = BuilderConfig {
+                build_cache: b.build_cache,
+                chunk_pool: b.chunk_pool,
+                require_verity: b.require_verity,
+                verity_hash: b.verity_hash,
+                owner: b.chown,
+                uid_map: IdMap::new(b.uid_map),
+                gid_map: IdMap::new(b.gid_map),
+                image_config,
+                stamp: b.stamp,
+                annotations,
+                squash: b.squash,
+                landmarks: b
+                    .landmarks
+                    .as_deref()
+                    .map(read_access_log)
+                    .transpose()?
+                    .unwrap_or_default(),
+                kernel_compat: b.kernel_compat,
+                xattr_filter: b.xattrs.filter(),
+                clear_setuid: b.clear_setuid,
+                force_modes: b.force_mode,
+                pack_chunks_below: b.pack_chunks_below,
+                inline_files_below: b.inline_files_below,
+                memory_budget: b.memory_budget,
+                ignore_changing_files: b.ignore_changing_files,
+                dry_run: b.dry_run,
+                overlay_upper: b.from_overlay_upper,
+                cross_filesystems: b.cross_filesystem,
+                symlinks: SymlinkPolicy {
+                    follow: b.follow_symlinks,
+                    relative: b.relative_symlinks,
+                    reject_escaping: b.reject_escaping_symlinks,
+                },
+                encryption: (!b.encrypt_recipient.is_empty())
+                    .then(|| ChunkEncryption::new(&b.encrypt_recipient))
+                    .transpose()?,
+                dedup_files: b.dedup_files,
+                file_digests: b.file_digests,
+                chunker: b.chunker,
+                anchor_files: b.anchor_files,
+                parallel_chunking_above: b.parallel_chunking_above,
+                chunking_threads: b.chunking_threads,
+            };
+            let (desc, new_image, stats) = match b.base_layer {
                 Some(base_layer) => {
-                    let (_desc, image) = if b.compression {
-                        add_rootfs_delta::<Zstd>(rootfs, image, tag, &base_layer)?
+                    if b.compression {
+                        add_rootfs_delta::<Zstd>(rootfs, image, tag, &base_layer, &config)?
                     } else {
-                        add_rootfs_delta::<Noop>(rootfs, image, tag, &base_layer)?
-                    };
-                    image
+                        add_rootfs_delta::<Noop>(rootfs, image, tag, &base_layer, &config)?
+                    }
                 }
                 None => {
-                    if b.compression {
-                        build_initial_rootfs::<Zstd>(rootfs, &image, tag)?
+                    let (desc, stats) = if b.compression {
+                        build_initial_rootfs::<Zstd>(rootfs, &image, tag, &config)?
                     } else {
-                        build_initial_rootfs::<Noop>(rootfs, &image, tag)?
+                        build_initial_rootfs::<Noop>(rootfs, &image, tag, &config)?
                     };
-                    Arc::new(image)
+                    (desc, Arc::new(image), stats)
                 }
             };
+            if b.dry_run {
+                println!("dry run, nothing was written");
+                println!("rootfs: {}", desc.digest());
+                println!("{stats}");
+                return Ok(());
+            }
+            if b.stats {
+                println!("{stats}");
+            }
             let mut manifest_fd = new_image.get_image_manifest_fd(tag)?;
             let mut read_buffer = Vec::new();
             manifest_fd.read_to_end(&mut read_buffer)?;
-            let manifest_digest = get_fs_verity_digest(&read_buffer)?;
+            let manifest_digest = get_fs_verity_digest(&read_buffer, config.verity_hash)?;
             println!(
                 "puzzlefs image manifest digest: {}",
                 hex::encode(manifest_digest)
             );
+            if b.content_manifest {
+                // the listing is made from the image as written, through a handle of its own
+                let mut image = Image::open(oci_dir)?.with_durable_writes(!b.no_fsync);
+                if let Some(platform) = platform {
+                    image = image.with_platform(platform);
+                }
+                let listing = attach_content_manifest(image, tag)?;
+                println!("content manifest: {}", listing.digest());
+            }
             Ok(())
         }
         SubCommand::Mount(m) => {
             let log_level = "info";
             if m.foreground {
-                init_logging(log_level);
+                init_logging(log_format, log_level);
             } else {
-                init_syslog(log_level)?;
-            }
-
-            if (m.writable || m.persist.is_some()) && !Uid::effective().is_root() {
-                anyhow::bail!("Writable mounts can only be created by the root user!")
+                init_syslog(log_format, log_level)?;
             }
 
-            let (oci_dir, tag) = parse_oci_dir(&m.oci_dir)?;
-            let oci_dir = Path::new(oci_dir);
+            let (oci_dir, reference) = manifest_ref(&m.oci_dir);
             let oci_dir = fs::canonicalize(oci_dir)?;
-            let image = Image::open(&oci_dir)?;
+            let mut image = Image::open(&oci_dir)?.with_read_hints(m.read_hints.hints());
+            if let Some(platform) = m.platform {
+                image = image.with_platform(platform);
+            }
+            if let Some(keys) = decryption_keys(&m.decryption_key)? {
+                image = image.with_decryption_keys(keys);
+            }
+            let manifest_verity = match &m.trust {
+                Some(name) => Some(TrustStore::user().resolve(name)?),
+                None => m.digest.as_ref().map(hex::decode).transpose()?,
+            };
+            let image = with_remote(image, &m.remote, manifest_verity.as_deref())?;
+            // the tag may move once checked, so the verified manifest is mounted by digest
+            let reference = match &m.verify_key {
+                Some(key) => image
+                    .verify_signature(&reference, key)?
+                    .digest()
+                    .to_string(),
+                None => reference,
+            };
+            let tag = reference.as_str();
             let mountpoint = Path::new(&m.mountpoint);
             let mountpoint = fs::canonicalize(mountpoint)?;
 
-            let manifest_verity = m.digest.map(hex::decode).transpose()?;
+            #[cfg(feature = "kernel-mount")]
+            if kernel_mountable(&m) && kernel::driver_available() {
+                match kernel::mount(
+                    &oci_dir,
+                    &image,
+                    tag,
+                    &mountpoint,
+                    manifest_verity.as_deref(),
+                    m.allow_devices,
+                ) {
+                    Ok(()) => {
+                        info!("mounted {tag} with the puzzlefs kernel driver");
+                        save_mount_state(&MountState::new(
+                            MountKind::Kernel,
+                            &mountpoint,
+                            &mountpoint,
+                            &oci_dir,
+                            tag,
+                        )?);
+                        return Ok(());
+                    }
+                    Err(e) => info!("cannot mount {tag} with the kernel driver, using FUSE: {e}"),
+                }
+            }
+
+            // the daemon changes its working directory, so the upper directory must be absolute
+            let upper_dir = m
+                .upper
+                .map(|upper| {
+                    fs::create_dir_all(&upper)?;
+                    fs::canonicalize(upper)
+                })
+                .transpose()?;
+            let lower = m
+                .lower
+                .iter()
+                .map(|lower| match lower.split_once('@') {
+                    Some((tag, digest)) => Ok((tag.to_string(), Some(hex::decode(digest)?))),
+                    None => Ok((lower.clone(), None)),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let config = FuseConfig {
+                upper_dir,
+                lower,
+                require_verity: m.require_verity,
+                // the foreground mount runs in a thread of this process, which can't be sandboxed
+                sandbox: !m.foreground && !m.no_sandbox,
+                uid_map: IdMap::new(m.uid_map),
+                gid_map: IdMap::new(m.gid_map),
+                provenance_xattrs: m.provenance_xattrs,
+                metrics_addr: m.metrics_addr,
+                // only background mounts have a daemon to control
+                control_dir: (!m.foreground).then(control::socket_dir).transpose()?,
+                // the daemon changes its working directory
+                access_log: m.record_access.map(std::path::absolute).transpose()?,
+                overlay: None,
+                allow_devices: m.allow_devices,
+                verify_files: m.verify_files,
+                entry_timeout: m.kernel_cache.entry_timeout,
+                attr_timeout: m.kernel_cache.attr_timeout,
+                max_readahead: m.kernel_cache.max_readahead,
+                auto_inval_data: m.kernel_cache.auto_inval_data,
+                remount_policy: match (&m.verify_key, &m.trust) {
+                    (Some(key), _) => RemountPolicy::Signed(fs::canonicalize(key)?),
+                    (None, Some(name)) => RemountPolicy::Trusted(name.clone()),
+                    (None, None) => RemountPolicy::Any,
+                },
+            };
 
             if m.writable || m.persist.is_some() {
                 // We only support background mounts with the writable|persist flag
-                let (recv, mut init_notify) = os_pipe::pipe()?;
+                let (recv, init_notify) = os_pipe::pipe()?;
                 let pfs_mountpoint = mountpoint.join("ro");
                 fs::create_dir_all(&pfs_mountpoint)?;
+                let (default_upperdir, ovl_workdir) = writable_dirs(&mountpoint)?;
+                let ovl_upperdir = match m.persist {
+                    None => default_upperdir,
+                    Some(upperdir) => std::path::absolute(upperdir)?,
+                };
+                // so that `puzzlefs commit` finds the changes from the mountpoint
+                let config = FuseConfig {
+                    overlay: Some(OverlayMount {
+                        mountpoint: mountpoint.clone(),
+                        upper_dir: ovl_upperdir.clone(),
+                    }),
+                    ..config
+                };
+                // kernel overlayfs needs CAP_SYS_ADMIN, use fuse-overlayfs otherwise
+                let kernel_overlay = Uid::effective().is_root();
+                let allow_devices = config.allow_devices;
+                // refused before the image is mounted rather than once it is
+                if !kernel_overlay {
+                    fuse_overlayfs_options(
+                        &pfs_mountpoint,
+                        &ovl_upperdir,
+                        &ovl_workdir,
+                        allow_devices,
+                    )?;
+                }
+                let kind = match kernel_overlay {
+                    true => MountKind::Overlay,
+                    false => MountKind::FuseOverlayfs,
+                };
+                let mut state = MountState::new(kind, &mountpoint, &pfs_mountpoint, &oci_dir, tag)?;
+                state.upper_dir = Some(ovl_upperdir.clone());
+                state.work_dir = Some(ovl_workdir.clone());
 
                 if let Err(e) = mount_background(
                     image,
+                    &oci_dir,
+                    false,
                     tag,
                     &pfs_mountpoint.clone(),
                     m.options,
                     manifest_verity,
+                    config,
+                    state,
                     recv,
-                    &init_notify,
+                    init_notify,
                     move || {
-                        let ovl_workdir = mountpoint.join("work");
                         fs::create_dir_all(&ovl_workdir)?;
-                        let ovl_upperdir = match m.persist {
-                            None => mountpoint.join("upper"),
-                            Some(upperdir) => Path::new(&upperdir).to_path_buf(),
-                        };
                         fs::create_dir_all(&ovl_upperdir)?;
-                        let overlay = Overlay::writable(
-                            [pfs_mountpoint.as_path()].into_iter(),
-                            ovl_upperdir,
-                            ovl_workdir,
-                            &mountpoint,
-                        );
-                        overlay.mount().map_err(|e| anyhow::anyhow!("{e}"))
+                        if kernel_overlay {
+                            let overlay = Overlay::writable(
+                                [pfs_mountpoint.as_path()].into_iter(),
+                                ovl_upperdir,
+                                ovl_workdir,
+                                &mountpoint,
+                            );
+                            overlay.mount().map_err(|e| anyhow::anyhow!("{e}"))?;
+                            // the device nodes are opened through the overlay, so it has to be
+                            // nodev as well as the image below
+                            if !allow_devices {
+                                nix::mount::mount(
+                                    None::<&str>,
+                                    &mountpoint,
+                                    None::<&str>,
+                                    MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_NODEV,
+                                    None::<&str>,
+                                )?;
+                            }
+                            Ok(())
+                        } else {
+                            mount_fuse_overlayfs(
+                                &pfs_mountpoint,
+                                &ovl_upperdir,
+                                &ovl_workdir,
+                                &mountpoint,
+                                allow_devices,
+                            )
+                        }
                     },
                 ) {
-                    if let Err(e) = init_notify.write_all(b"f") {
-                        error!("puzzlefs will hang because we couldn't write to pipe, {e}");
-                    }
                     error!("mount_background failed: {e}");
                     return Err(e);
                 }
@@ -304,6 +2048,7 @@ fn main() -> anyhow::Result<()> {
                     named_pipe.clone().map(PipeDescriptor::NamedPipe),
                     Some(fuse_thread_finished),
                     manifest_verity.as_deref(),
+                    &config,
                 );
                 if let Err(e) = result {
                     if let Some(pipe) = named_pipe {
@@ -325,21 +2070,22 @@ fn main() -> anyhow::Result<()> {
                 // This blocks until either ctrl-c is pressed or the filesystem is unmounted
                 let () = recv.recv().unwrap();
             } else {
-                let (recv, mut init_notify) = os_pipe::pipe()?;
+                let (recv, init_notify) = os_pipe::pipe()?;
 
                 if let Err(e) = mount_background(
                     image,
+                    &oci_dir,
+                    m.supervise,
                     tag,
                     &mountpoint,
                     m.options,
                     manifest_verity,
+                    config,
+                    MountState::new(MountKind::Fuse, &mountpoint, &mountpoint, &oci_dir, tag)?,
                     recv,
-                    &init_notify,
+                    init_notify,
                     || Ok(()),
                 ) {
-                    if let Err(e) = init_notify.write_all(b"f") {
-                        error!("puzzlefs will hang because we couldn't write to pipe, {e}");
-                    }
                     error!("mount_background failed: {e}");
                     return Err(e);
                 }
@@ -347,66 +2093,851 @@ fn main() -> anyhow::Result<()> {
 
             Ok(())
         }
-        SubCommand::Umount(e) => {
-            let mountpoint = Path::new(&e.mountpoint);
-            let mount_type = get_mount_type(&e.mountpoint)?;
-            match mount_type.to_str() {
-                Some("overlay") => {
-                    if !Uid::effective().is_root() {
-                        anyhow::bail!("Overlay mounts can only be unmounted by the root user!")
-                    }
-                    umount(mountpoint)?;
-                    // Now unmount the read-only puzzlefs mountpoint
-                    let pfs_mountpoint = mountpoint.join("ro");
-                    umount(pfs_mountpoint.as_os_str())?;
-                    // TODO: Decide whether to remove the directories we've created. For the LXC
-                    // case, we don't want to remove them because we want to persist state between
-                    // multiple mounts. Should we add a --delete flag to unmount?
-                    // let ovl_workdir = mountpoint.join("work");
-                    // let ovl_upperdir = mountpoint.join("upper");
-                    // std::fs::remove_dir_all(&pfs_mountpoint)?;
-                    // std::fs::remove_dir_all(&ovl_workdir)?;
-                    // std::fs::remove_dir_all(&ovl_upperdir)?;
-                    return Ok(());
-                }
-                Some("fuse") => {
-                    // We call "fusermount -u" because we don't have permissions to umount directly
-                    // fusermount and umount binaries have the setuid bit set
-                    let status = std::process::Command::new("fusermount")
-                        .arg("-u")
-                        .arg(&e.mountpoint)
-                        .status()?;
-                    if !status.success() {
-                        anyhow::bail!(
-                            "umount exited with status {}",
-                            status
-                                .code()
-                                .map(|code| code.to_string())
-                                .unwrap_or("terminated by signal".to_string())
-                        );
-                    }
+        SubCommand::Umount(e) => unmount(Path::new(&e.mountpoint), e.cleanup),
+        SubCommand::Extract(e) => {
+            let (oci_dir, mut reference) = manifest_ref(&e.oci_dir);
+            init_logging(log_format, "info");
+            if let Some(key) = e.verify_key {
+                let mut image = Image::open(oci_dir)?;
+                if let Some(platform) = &e.platform {
+                    image = image.with_platform(platform.clone());
+                }
+                // the tag may move once checked, so the verified manifest is extracted by digest
+                reference = image
+                    .verify_signature(&reference, &key)?
+                    .digest()
+                    .to_string();
+            }
+            let tag = reference.as_str();
+            let config = ExtractorConfig {
+                existing: e.existing_files(),
+                delete: e.delete,
+                verity: e.enable_verity.then_some(e.verity_hash),
+                immutable: e.immutable,
+                allow_devices: e.allow_devices,
+                strict: e.strict,
+                uid_map: IdMap::new(e.uid_map),
+                gid_map: IdMap::new(e.gid_map),
+                platform: e.platform,
+                xattr_filter: e.xattrs.filter(),
+                refuse_setuid: e.refuse_setuid,
+                allow_setuid: e.allow_setuid,
+                read_hints: e.read_hints.hints(),
+                decryption_keys: decryption_keys(&e.decryption_key)?,
+                check: e.check,
+            };
+            let report = extract_rootfs(&oci_dir.to_string_lossy(), tag, &e.extract_dir, &config)?;
+            if e.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else if !report.is_complete() {
+                warn!("some of the image couldn't be restored, pass --strict to make it an error");
+                eprintln!("{:<10}  {:>8}  FIRST FILE", "LOST", "FILES");
+                for (kind, count, path) in report.summary() {
+                    eprintln!("{kind:<10}  {count:>8}  {}", path.display());
                 }
-                _ => anyhow::bail!(
-                    "Unknown mountpoint type {} for {}",
-                    mount_type.to_str().unwrap_or("unknown mount type"),
-                    &e.mountpoint
-                ),
             }
-
             Ok(())
         }
-        SubCommand::Extract(e) => {
-            let (oci_dir, tag) = parse_oci_dir(&e.oci_dir)?;
-            init_logging("info");
-            extract_rootfs(oci_dir, tag, &e.extract_dir)
+        SubCommand::Sign(s) => {
+            let image = Image::open(&s.oci_dir.oci_dir)?;
+            // the signature names the image by its full reference
+            let image_ref = ImageRef {
+                oci_dir: fs::canonicalize(&s.oci_dir.oci_dir)?,
+                reference: s.oci_dir.reference.clone(),
+            };
+            let signature = image.sign(&image_ref, &s.key)?;
+            println!("signature manifest: {}", signature.digest());
+            Ok(())
+        }
+        SubCommand::VerifySignature(v) => {
+            let (oci_dir, tag) = tag_ref(&v.oci_dir)?;
+            let image = Image::open(oci_dir)?;
+            image.verify_signature(tag, &v.key)?;
+            println!("{tag}: valid signature");
+            Ok(())
+        }
+        SubCommand::AttachSbom(a) => {
+            let (oci_dir, tag) = tag_ref(&a.oci_dir)?;
+            init_logging(log_format, "info");
+            let image = Image::open(oci_dir)?;
+            let sbom = image.attach_sbom(tag, &fs::read(&a.sbom)?, a.format)?;
+            println!("sbom manifest: {}", sbom.digest());
+            Ok(())
+        }
+        SubCommand::Manifest(m) => {
+            let (oci_dir, tag) = tag_ref(&m.oci_dir)?;
+            if m.print {
+                let listing = content_manifest(Image::open(oci_dir)?, tag)?;
+                println!("{}", serde_json::to_string_pretty(&listing)?);
+            } else {
+                init_logging(log_format, "info");
+                let listing = attach_content_manifest(Image::open(oci_dir)?, tag)?;
+                println!("content manifest: {}", listing.digest());
+            }
+            Ok(())
         }
         SubCommand::EnableFsVerity(v) => {
-            let (oci_dir, tag) = parse_oci_dir(&v.oci_dir)?;
-            let oci_dir = Path::new(oci_dir);
+            let (oci_dir, tag) = tag_ref(&v.oci_dir)?;
             let oci_dir = fs::canonicalize(oci_dir)?;
             let image = Image::open(&oci_dir)?;
-            enable_fs_verity(image, tag, &v.root_hash)?;
+            let signer = match (v.signature_key, v.cert) {
+                (Some(key), Some(cert)) => Some(FsVeritySigner::from_pem_files(&key, &cert)?),
+                _ => None,
+            };
+            enable_fs_verity(image, tag, &v.root_hash, signer.as_ref())?;
+            Ok(())
+        }
+        SubCommand::Serve(s) => {
+            init_logging(log_format, "info");
+            let image = Image::open(&s.oci_dir)?;
+            let listener = std::net::TcpListener::bind(s.listen)?;
+            server::serve(image, listener)?
+                .join()
+                .map_err(|_| anyhow::anyhow!("the server thread panicked"))?;
+            Ok(())
+        }
+        SubCommand::Fscache(f) => {
+            init_logging(log_format, "info");
+            let (oci_dir, tag) = tag_ref(&f.oci_dir)?;
+            let manifest_verity = f.digest.map(hex::decode).transpose()?;
+            let image = Image::open(oci_dir)?;
+            let image = with_remote(image, &f.remote, manifest_verity.as_deref())?;
+            let pfs = PuzzleFS::open(image, tag, manifest_verity.as_deref())?;
+            let fsid = f.fsid.as_deref().unwrap_or(tag);
+            let mut daemon = FscacheDaemon::bind(pfs, fsid, &f.fscache_dir)?;
+            if let Some(mountpoint) = f.mountpoint {
+                let options = format!("fsid={fsid}");
+                // the kernel asks the daemon for the metadata while mounting
+                thread::spawn(move || {
+                    match nix::mount::mount(
+                        None::<&str>,
+                        &mountpoint,
+                        Some("erofs"),
+                        MsFlags::MS_RDONLY,
+                        Some(options.as_str()),
+                    ) {
+                        Ok(()) => info!("mounted on {}", mountpoint.display()),
+                        Err(e) => {
+                            error!("cannot mount on {}: {e}", mountpoint.display());
+                            exit(1);
+                        }
+                    }
+                });
+            }
+            daemon.run()?;
+            Ok(())
+        }
+        SubCommand::Serve9p(s) => {
+            init_logging(log_format, "info");
+            let (oci_dir, tag) = tag_ref(&s.oci_dir)?;
+            let manifest_verity = s.digest.map(hex::decode).transpose()?;
+            let image = Image::open(oci_dir)?;
+            let image = with_remote(image, &s.remote, manifest_verity.as_deref())?;
+            let pfs = PuzzleFS::open(image, tag, manifest_verity.as_deref())?;
+            let listener = match (s.socket, s.listen) {
+                (Some(socket), _) => {
+                    // the socket is created private, so no one else can connect
+                    let old_umask = umask(Mode::from_bits_truncate(0o077));
+                    let listener = std::os::unix::net::UnixListener::bind(&socket);
+                    umask(old_umask);
+                    ninep::Listener::Unix(listener?)
+                }
+                // .unwrap() here because clap requires one of them
+                (None, listen) => {
+                    ninep::Listener::Tcp(std::net::TcpListener::bind(listen.unwrap())?)
+                }
+            };
+            ninep::serve(pfs, listener)?
+                .join()
+                .map_err(|_| anyhow::anyhow!("the server thread panicked"))?;
+            Ok(())
+        }
+        SubCommand::Push(p) => {
+            init_logging(log_format, "info");
+            let (oci_dir, tag) = tag_ref(&p.oci_dir)?;
+            let image = Image::open(oci_dir)?;
+            remote::push(&image, open_remote(&p.remote)?.as_ref(), tag)?;
+            Ok(())
+        }
+        SubCommand::Pull(p) => {
+            init_logging(log_format, "info");
+            let (oci_dir, tag) = tag_ref(&p.oci_dir)?;
+            let image = Image::new(oci_dir)?;
+            remote::pull(&image, open_remote(&p.remote)?.as_ref(), tag, p.lazy)?;
+            Ok(())
+        }
+        SubCommand::Copy(c) => {
+            init_logging(log_format, "info");
+            let (src_dir, reference) = manifest_ref(&c.src);
+            let (dst_dir, dst_tag) = match c.dst.parse::<ImageRef>() {
+                Ok(ImageRef {
+                    oci_dir,
+                    reference: Reference::Tag(tag),
+                }) => (oci_dir, tag),
+                _ => match c.src.tag() {
+                    Some(tag) => (PathBuf::from(&c.dst), tag.to_string()),
+                    None => {
+                        anyhow::bail!("a manifest copied by digest needs a tag: {}:<tag>", c.dst)
+                    }
+                },
+            };
+            let src = Image::open(src_dir)?;
+            let dst = Image::new(&dst_dir)?;
+            println!("{}", copy::copy(&src, &reference, &dst, &dst_tag)?);
+            Ok(())
+        }
+        SubCommand::Prefetch(p) => {
+            init_logging(log_format, "info");
+            let (oci_dir, tag) = tag_ref(&p.oci_dir)?;
+            let manifest_verity = p.digest.map(hex::decode).transpose()?;
+            let image = Image::open(oci_dir)?;
+            let image = with_remote(image, &p.remote, manifest_verity.as_deref())?;
+            let pfs = PuzzleFS::open(image, tag, manifest_verity.as_deref())?;
+            println!("{}", prefetch(&pfs, &p.path, p.jobs)?);
+            Ok(())
+        }
+        SubCommand::Delta(d) => {
+            init_logging(log_format, "info");
+            match d.command {
+                DeltaCommand::Create {
+                    oci_dir,
+                    base,
+                    output,
+                    stats,
+                } => {
+                    let (oci_dir, tag) = tag_ref(&oci_dir)?;
+                    let image = Image::open(oci_dir)?;
+                    let mut out = std::io::BufWriter::new(fs::File::create(&output)?);
+                    let delta_stats = create_delta(&image, &base, tag, &mut out)?;
+                    out.flush()?;
+                    if stats {
+                        println!("{delta_stats}");
+                    }
+                    Ok(())
+                }
+                DeltaCommand::Apply { oci_dir, delta } => {
+                    let image = Image::open(&oci_dir)?;
+                    let delta = std::io::BufReader::new(fs::File::open(delta)?);
+                    apply_delta(&image, delta)?;
+                    Ok(())
+                }
+            }
+        }
+        SubCommand::Trust(t) => {
+            init_logging(log_format, "info");
+            let store = TrustStore::user();
+            match t.command {
+                TrustCommand::Add {
+                    name,
+                    digest,
+                    signed_file,
+                    signature,
+                    key,
+                } => {
+                    let digest = match (digest, signed_file, signature, key) {
+                        (Some(digest), ..) => TrustedDigest::Digest(digest),
+                        // the paths are used by later mounts, from other directories
+                        (None, Some(file), Some(signature), Some(key)) => TrustedDigest::Signed {
+                            file: fs::canonicalize(file)?,
+                            signature: fs::canonicalize(signature)?,
+                            key: fs::canonicalize(key)?,
+                        },
+                        _ => anyhow::bail!("either a digest or a signed file is needed"),
+                    };
+                    store.add(&name, &digest)?;
+                    Ok(())
+                }
+                TrustCommand::Remove { name } => {
+                    if !store.remove(&name)? {
+                        anyhow::bail!("{name} isn't trusted");
+                    }
+                    Ok(())
+                }
+                TrustCommand::List => {
+                    for (name, digest) in store.list()? {
+                        match digest.resolve(&name) {
+                            Ok(resolved) => println!("{name}: {}", hex::encode(resolved)),
+                            Err(e) => println!("{name}: {e}"),
+                        }
+                    }
+                    Ok(())
+                }
+            }
+        }
+        SubCommand::Find(f) => {
+            let (oci_dir, tag) = tag_ref(&f.oci_dir)?;
+            let image = ImageHandle::open(oci_dir)?;
+            let tag = TagRef {
+                tag: tag.to_string(),
+                verity: f.digest.map(hex::decode).transpose()?,
+            };
+            let mut options = FindOptions::default();
+            options.name = f.pattern.map(|pattern| {
+                if f.regex {
+                    NamePattern::Regex(pattern)
+                } else {
+                    NamePattern::Glob(pattern)
+                }
+            });
+            options.kind = f.kind;
+            options.min_size = f.min_size;
+            options.max_size = f.max_size;
+            options.permissions = f.perm;
+            options.xattr = f.xattr;
+            for entry in image.find(&tag, &options)? {
+                println!(
+                    "{} {:04o} {}:{} {} {}",
+                    entry.kind,
+                    entry.permissions,
+                    entry.uid,
+                    entry.gid,
+                    entry.size,
+                    entry.path.display()
+                );
+            }
+            Ok(())
+        }
+        SubCommand::Cat(c) => {
+            let (oci_dir, tag) = tag_ref(&c.oci_dir)?;
+            let manifest_verity = c.digest.map(hex::decode).transpose()?;
+            let image = Image::open(oci_dir)?;
+            let pfs = PuzzleFS::open(image, tag, manifest_verity.as_deref())?;
+            let path = Path::new("/").join(&c.path);
+            let inode = pfs
+                .lookup(&path)?
+                .ok_or_else(|| anyhow::anyhow!("no such file {}", path.display()))?;
+            let mut reader = FileReader::new(&pfs.oci, &inode)
+                .map_err(|_| anyhow::anyhow!("{} isn't a regular file", path.display()))?;
+            std::io::copy(&mut reader, &mut std::io::stdout().lock())?;
+            Ok(())
+        }
+        SubCommand::Stat(s) => {
+            let (oci_dir, tag) = tag_ref(&s.oci_dir)?;
+            let image = ImageHandle::open(oci_dir)?;
+            let tag = TagRef {
+                tag: tag.to_string(),
+                verity: s.digest.map(hex::decode).transpose()?,
+            };
+            let path = Path::new("/").join(&s.path);
+            let stat = image.stat(&tag, &path)?;
+            println!("path: {}", path.display());
+            println!("inode: {}", stat.ino);
+            println!("type: {}", stat.kind);
+            println!("permissions: {:04o}", stat.permissions);
+            println!("owner: {}:{}", stat.uid, stat.gid);
+            if stat.kind == FileKind::File {
+                println!("size: {}", stat.size);
+                if stat.inline {
+                    println!("inline: true");
+                } else {
+                    println!("chunks: {}", stat.chunks);
+                }
+            }
+            if let Some((major, minor)) = stat.device {
+                println!("device: {major}:{minor}");
+            }
+            if let Some(target) = stat.symlink_target {
+                println!("target: {}", target.display());
+            }
+            for (key, value) in stat.xattrs {
+                println!("xattr: {}={}", key.escape_ascii(), value.escape_ascii());
+            }
+            Ok(())
+        }
+        SubCommand::Verify(v) => {
+            let (oci_dir, tag) = tag_ref(&v.oci_dir)?;
+            let manifest_verity = v.digest.map(hex::decode).transpose()?;
+            let image = Image::open(oci_dir)?;
+            let mut pfs = PuzzleFS::open(image, tag, manifest_verity.as_deref())?;
+            let oci = pfs.oci.clone();
+            let mut seen = std::collections::HashSet::new();
+            let (mut checked, mut unchecked, mut corrupted) = (0, 0, 0);
+            for entry in WalkPuzzleFS::walk(&mut pfs)? {
+                let entry = entry?;
+                if entry.inode.file_len().is_err() || !seen.insert(entry.inode.ino) {
+                    continue;
+                }
+                match verify_file(&oci, &entry.inode) {
+                    Ok(true) => checked += 1,
+                    Ok(false) => unchecked += 1,
+                    Err(e) => {
+                        println!("{}: {e}", entry.path.display());
+                        corrupted += 1;
+                    }
+                }
+            }
+            println!("{checked} files checked, {unchecked} without digest");
+            if corrupted > 0 {
+                anyhow::bail!("{corrupted} files don't match their digest");
+            }
+            Ok(())
+        }
+        SubCommand::Du(d) => {
+            let (oci_dir, tag) = tag_ref(&d.oci_dir)?;
+            let manifest_verity = d.digest.map(hex::decode).transpose()?;
+            let image = Image::open(oci_dir)?;
+            let usage = disk_usage(image, tag, manifest_verity.as_deref())?;
+            let dirs = usage
+                .up_to(d.max_depth.unwrap_or(usize::MAX))
+                .collect::<Vec<_>>();
+            if d.json {
+                let usage = serde_json::json!({"dirs": dirs, "metadata": usage.metadata});
+                println!("{}", serde_json::to_string_pretty(&usage)?);
+            } else {
+                println!(
+                    "{:>8}  {:>10}  {:>8}  PATH",
+                    "LOGICAL", "COMPRESSED", "PHYSICAL"
+                );
+                for dir in dirs {
+                    println!("{dir}");
+                }
+                println!("metadata: {} bytes", usage.metadata);
+            }
+            Ok(())
+        }
+        SubCommand::Analyze(a) => {
+            let (oci_dir, tag) = tag_ref(&a.oci_dir)?;
+            let manifest_verity = a.digest.map(hex::decode).transpose()?;
+            let image = Image::open(oci_dir)?;
+            let graph = chunk_graph(image, tag, manifest_verity.as_deref())?;
+            if let Some(path) = &a.chunks_csv {
+                let mut csv = std::io::BufWriter::new(fs::File::create(path)?);
+                graph.write_csv(&mut csv)?;
+                csv.flush()?;
+                info!("wrote {} chunks to {}", graph.chunks.len(), path.display());
+            }
+            let top = a.top.or(a.chunks_csv.is_none().then_some(10));
+            for chunk in graph.top(top.unwrap_or(0)) {
+                println!(
+                    "{} +{} {} bytes, {} references",
+                    chunk.digest, chunk.offset, chunk.size, chunk.refcount
+                );
+                for file in &chunk.files {
+                    println!("    {}", file.display());
+                }
+            }
+            Ok(())
+        }
+        SubCommand::Bench(b) => {
+            init_logging(log_format, "warn");
+            if b.runs == 0 {
+                anyhow::bail!("--runs must be at least 1");
+            }
+            // nothing of the user's in the work dir is touched
+            let work_dir = b
+                .work_dir
+                .join(format!("puzzlefs-bench.{}", std::process::id()));
+            fs::create_dir_all(&b.work_dir)?;
+            fs::create_dir(&work_dir)?;
+            let oci_dir = work_dir.join("oci");
+            let extract_dir = work_dir.join("extract");
+            let build = || -> anyhow::Result<()> {
+                let image = Image::new(&oci_dir)?;
+                let config = BuilderConfig::default();
+                if b.compression {
+                    build_initial_rootfs::<Zstd>(&b.rootfs, &image, "bench", &config)?;
+                } else {
+                    build_initial_rootfs::<Noop>(&b.rootfs, &image, "bench", &config)?;
+                }
+                Ok(())
+            };
+            let extract = || -> anyhow::Result<()> {
+                extract_rootfs(
+                    &oci_dir.to_string_lossy(),
+                    "bench",
+                    &extract_dir.to_string_lossy(),
+                    &ExtractorConfig::default(),
+                )?;
+                Ok(())
+            };
+
+            let bench = || -> anyhow::Result<_> {
+                if b.workload != BenchWorkload::Build {
+                    build()?;
+                }
+                let mut runs = Vec::new();
+                for _ in 0..b.runs {
+                    // the output of the previous run is removed outside of the measure
+                    runs.push(match b.workload {
+                        BenchWorkload::Build => {
+                            remove_dir(&oci_dir)?;
+                            measure(&build)?
+                        }
+                        BenchWorkload::Read => measure(|| read_all_files(&oci_dir, "bench"))?,
+                        BenchWorkload::Extract => {
+                            remove_dir(&extract_dir)?;
+                            measure(&extract)?
+                        }
+                    });
+                }
+                let (files, logical) =
+                    PuzzleFS::open(Image::open(&oci_dir)?, "bench", None)?.statistics()?;
+                Ok((runs, files, logical, dir_size(&oci_dir)?))
+            };
+            let result = bench();
+            remove_dir(&work_dir)?;
+            let (runs, files, logical, image_size) = result?;
+
+            let median = |mut times: Vec<Duration>| {
+                times.sort();
+                times[times.len() / 2]
+            };
+            let wall = median(runs.iter().map(|(wall, _)| *wall).collect());
+            let cpu = median(runs.iter().map(|(_, cpu)| *cpu).collect());
+            let (fastest, slowest) = (
+                runs.iter().map(|(wall, _)| *wall).min().unwrap_or_default(),
+                runs.iter().map(|(wall, _)| *wall).max().unwrap_or_default(),
+            );
+            let throughput = logical as f64 / wall.as_secs_f64() / (1024.0 * 1024.0);
+            let workload = b.workload.to_possible_value().unwrap();
+            if b.json {
+                let report = serde_json::json!({
+                    "workload": workload.get_name(),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "compression": b.compression,
+                    "runs": b.runs,
+                    "wall_seconds": wall.as_secs_f64(),
+                    "min_wall_seconds": fastest.as_secs_f64(),
+                    "max_wall_seconds": slowest.as_secs_f64(),
+                    "cpu_seconds": cpu.as_secs_f64(),
+                    "files": files,
+                    "logical_bytes": logical,
+                    "image_bytes": image_size,
+                    "throughput_mib_per_second": throughput,
+                });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("workload: {} ({} runs)", workload.get_name(), b.runs);
+                println!(
+                    "wall: {:.3}s (min {:.3}s, max {:.3}s)",
+                    wall.as_secs_f64(),
+                    fastest.as_secs_f64(),
+                    slowest.as_secs_f64()
+                );
+                println!("cpu: {:.3}s", cpu.as_secs_f64());
+                println!("files: {files}, {logical} bytes");
+                println!(
+                    "image: {image_size} bytes ({:.1}% of the files)",
+                    image_size as f64 * 100.0 / logical.max(1) as f64
+                );
+                println!("throughput: {throughput:.1} MiB/s");
+            }
+            Ok(())
+        }
+        SubCommand::DumpKernelLayout(d) => {
+            let (oci_dir, tag) = tag_ref(&d.oci_dir)?;
+            let image = Image::open(oci_dir)?;
+            let layout = kernel_layout(&image, tag)?;
+            if d.json {
+                println!("{}", serde_json::to_string_pretty(&layout)?);
+            } else {
+                print!("{layout}");
+            }
+            if !layout.is_compatible() {
+                anyhow::bail!("{tag} isn't compatible with the kernel driver");
+            }
+            Ok(())
+        }
+        SubCommand::Inspect(i) => {
+            let (oci_dir, reference) = manifest_ref(&i.oci_dir);
+            let tag = reference.as_str();
+            let image = Image::open(oci_dir)?;
+            if i.sbom {
+                let Some((_, sbom)) = image.find_sbom(tag)? else {
+                    anyhow::bail!("no SBOM attached to {tag}");
+                };
+                std::io::stdout().write_all(&sbom)?;
+                return Ok(());
+            }
+            let manifests = image.platform_manifests(tag)?;
+            if manifests.is_empty() {
+                anyhow::bail!("no such tag or manifest {tag}");
+            }
+            for desc in manifests {
+                println!("manifest: {}", desc.digest());
+                if let Some(platform) = desc.platform() {
+                    println!("platform: {}/{}", platform.os(), platform.architecture());
+                }
+                let manifest: ImageManifest = image.0.read_json_blob(&desc)?;
+                let mut annotations = manifest
+                    .annotations()
+                    .clone()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                annotations.sort();
+                for (key, value) in annotations {
+                    println!("annotation: {key}={value}");
+                }
+                if manifest.config().media_type() == &MediaType::ImageConfig {
+                    let image_config: ImageConfiguration =
+                        image.0.read_json_blob(manifest.config())?;
+                    if let Some(config) = image_config.config() {
+                        for env in config.env().iter().flatten() {
+                            println!("env: {env}");
+                        }
+                        if let Some(entrypoint) = config.entrypoint() {
+                            println!("entrypoint: {}", serde_json::to_string(entrypoint)?);
+                        }
+                        let mut labels = config
+                            .labels()
+                            .clone()
+                            .unwrap_or_default()
+                            .into_iter()
+                            .collect::<Vec<_>>();
+                        labels.sort();
+                        for (key, value) in labels {
+                            println!("label: {key}={value}");
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        SubCommand::Referrers(r) => {
+            let (oci_dir, reference) = manifest_ref(&r.oci_dir);
+            let image = Image::open(oci_dir)?;
+            // the artifacts of a tag are attached to the manifest of the platform
+            let digest = match r.oci_dir.tag() {
+                Some(tag) => image
+                    .find_manifest_descriptor(tag)?
+                    .ok_or_else(|| anyhow::anyhow!("no such tag {tag}"))?
+                    .digest()
+                    .to_string(),
+                None => reference,
+            };
+            for referrer in image.list_referrers(&digest, r.artifact_type.as_deref())? {
+                let artifact_type = referrer
+                    .artifact_type()
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                println!("{} {artifact_type}", referrer.digest());
+            }
+            Ok(())
+        }
+        SubCommand::Migrate(m) => {
+            let (oci_dir, tag) = tag_ref(&m.oci_dir)?;
+            init_logging(log_format, "info");
+            let image = Image::upgrade(oci_dir)?;
+            let Some((version, _)) = migrate_rootfs(&image, tag)? else {
+                println!("{tag} is already at manifest version {PUZZLEFS_IMAGE_MANIFEST_VERSION}");
+                return Ok(());
+            };
+            println!(
+                "migrated {tag} from manifest version {version} to {PUZZLEFS_IMAGE_MANIFEST_VERSION}"
+            );
+            print_manifest_digest(&image, tag)
+        }
+        SubCommand::Flatten(f) => {
+            let (oci_dir, tag) = tag_ref(&f.oci_dir)?;
+            init_logging(log_format, "info");
+            let image = Image::open(oci_dir)?;
+            flatten(&image, tag, &f.new_tag)?;
+            print_manifest_digest(&image, &f.new_tag)
+        }
+        SubCommand::Gc(g) => {
+            init_logging(log_format, "info");
+            let (removed, removed_bytes) = BlobStore::open(&g.blob_store)?.gc()?;
+            println!("removed {removed} blobs ({removed_bytes} bytes)");
+            Ok(())
+        }
+        SubCommand::Convert(c) => {
+            let (oci_dir, tag) = tag_ref(&c.oci_dir)?;
+            init_logging(log_format, "info");
+            if c.to_squashfs {
+                fs::create_dir_all(&c.out_dir)?;
+                let image = c.out_dir.join(format!("{tag}.sqfs"));
+                export_squashfs(oci_dir, tag, &image)?;
+                println!("squashfs image: {}", image.display());
+            } else if c.to_oci_layer {
+                fs::create_dir_all(&c.out_dir)?;
+                let layer = c.out_dir.join(format!("{tag}.tar"));
+                export_oci_layer(oci_dir, tag, &layer)?;
+                println!("oci layer: {}", layer.display());
+            } else {
+                let image = export_composefs(oci_dir, tag, &c.out_dir)?;
+                println!("composefs image: {}", image.display());
+            }
+            Ok(())
+        }
+        SubCommand::LayerStore(l) => {
+            init_logging(log_format, "info");
+            let options = l.options.unwrap_or_else(|| vec!["ro".to_string()]);
+            let mut store = layer_store::LayerStore::new(&l.root)?;
+            for image in &l.images {
+                let (reference, oci_dir) = image.split_once('=').ok_or_else(|| {
+                    anyhow::anyhow!("{image} is not in reference=oci_dir:tag format")
+                })?;
+                let image_ref: ImageRef = oci_dir.parse()?;
+                let (oci_dir, tag) = tag_ref(&image_ref)?;
+                store.add_image(oci_dir, tag, reference, &options)?;
+            }
+
+            let (send, recv) = std::sync::mpsc::channel();
+            ctrlc::set_handler(move || {
+                send.send(()).unwrap();
+            })?;
+            // the layers stay mounted until we're told to stop
+            let () = recv.recv().unwrap();
+            Ok(())
+        }
+        SubCommand::Mounts(m) => {
+            let mounts = control::list_mounts(&control::socket_dir()?)?;
+            let Some(mountpoint) = m.mountpoint else {
+                println!("{:>8}  {:<40}  TAGS", "PID", "MOUNTPOINT");
+                for (_, status) in mounts {
+                    println!(
+                        "{:>8}  {:<40}  {}",
+                        status.pid,
+                        status.mountpoint.display(),
+                        status.tags.join(",")
+                    );
+                }
+                return Ok(());
+            };
+            let mountpoint = fs::canonicalize(mountpoint)?;
+            let (socket, status) = mounts
+                .into_iter()
+                .find(|(_, status)| status.mountpoint == mountpoint)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("no puzzlefs daemon serves {}", mountpoint.display())
+                })?;
+            let command = m.command.join(" ");
+            match control::request(&socket, &command)? {
+                Response::Ok => (),
+                Response::Status(status) => println!("{}", serde_json::to_string_pretty(&status)?),
+                Response::Stats(stats) => print!("{stats}"),
+                // a sandboxed daemon can't unmount itself, do it for it
+                Response::Error(_) if command == "shutdown" && status.sandboxed => {
+                    fusermount_umount(&status.mountpoint)?
+                }
+                Response::Error(e) => anyhow::bail!(e),
+            }
+            Ok(())
+        }
+        SubCommand::RemountTag(r) => {
+            let mountpoint = fs::canonicalize(&r.mountpoint)?;
+            let (socket, _) = control::list_mounts(&control::socket_dir()?)?
+                .into_iter()
+                .find(|(_, status)| status.serves(&mountpoint))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("no puzzlefs daemon serves {}", mountpoint.display())
+                })?;
+            let command = match r.digest {
+                Some(digest) => format!("remount-tag {} {digest}", r.tag),
+                None => format!("remount-tag {}", r.tag),
+            };
+            match control::request(&socket, &command)? {
+                Response::Error(e) => anyhow::bail!(e),
+                _ => Ok(()),
+            }
+        }
+        SubCommand::Commit(c) => {
+            let (oci_dir, tag) = tag_ref(&c.oci_dir)?;
+            let mountpoint = fs::canonicalize(&c.mountpoint)?;
+            // the delta only makes sense in the image the changes were made on
+            let image_dir = fs::canonicalize(oci_dir)?;
+            match MountState::load(&mount_state::state_dir()?, &mountpoint)? {
+                Some(state) if state.oci_dir == image_dir => (),
+                Some(state) => anyhow::bail!(
+                    "{} is a mount of {}, not of {}",
+                    mountpoint.display(),
+                    state.oci_dir.display(),
+                    image_dir.display()
+                ),
+                None => anyhow::bail!(
+                    "no state was recorded for {}, its image is unknown",
+                    mountpoint.display()
+                ),
+            }
+            let (_, status) = control::list_mounts(&control::socket_dir()?)?
+                .into_iter()
+                .find(|(_, status)| status.serves(&mountpoint))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("no puzzlefs daemon serves {}", mountpoint.display())
+                })?;
+            let Some(upper_dir) = status.changes_dir().map(Path::to_path_buf) else {
+                anyhow::bail!(
+                    "{} has no changes to commit, mount it with --upper or --persist",
+                    mountpoint.display()
+                );
+            };
+            // the delta is built against the mounted tag alone
+            let [base_layer] = &status.tags[..] else {
+                anyhow::bail!(
+                    "{} stacks tags below the mounted one: {}",
+                    mountpoint.display(),
+                    status.tags.join(",")
+                );
+            };
+
+            let image = Image::open(oci_dir)?;
+            let config = BuilderConfig {
+                overlay_upper: true,
+                ..Default::default()
+            };
+            let (_, new_image, _) = if c.compression {
+                add_rootfs_delta::<Zstd>(&upper_dir, image, tag, base_layer, &config)?
+            } else {
+                add_rootfs_delta::<Noop>(&upper_dir, image, tag, base_layer, &config)?
+            };
+            let mut read_buffer = Vec::new();
+            new_image
+                .get_image_manifest_fd(tag)?
+                .read_to_end(&mut read_buffer)?;
+            let manifest_digest = get_fs_verity_digest(&read_buffer, config.verity_hash)?;
+            println!(
+                "puzzlefs image manifest digest: {}",
+                hex::encode(manifest_digest)
+            );
+
+            if c.clear {
+                unmount(&mountpoint, false)?;
+                clear_dir(&upper_dir)?;
+            }
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse_overlayfs_options() -> anyhow::Result<()> {
+        let lower = Path::new("/mnt/image/ro");
+        let work = Path::new("/mnt/image/work");
+        assert_eq!(
+            fuse_overlayfs_options(lower, Path::new("/mnt/image/upper"), work, false)?,
+            "lowerdir=/mnt/image/ro,upperdir=/mnt/image/upper,workdir=/mnt/image/work,nodev"
+        );
+        assert_eq!(
+            fuse_overlayfs_options(lower, Path::new("/mnt/image/upper"), work, true)?,
+            "lowerdir=/mnt/image/ro,upperdir=/mnt/image/upper,workdir=/mnt/image/work"
+        );
+        // they would be taken for other options or other lower directories
+        for upper in ["/tmp/a,lowerdir=/", "/tmp/a:/etc", "/tmp/a\\,b"] {
+            let err = fuse_overlayfs_options(lower, Path::new(upper), work, false).unwrap_err();
+            assert!(err.to_string().contains("upperdir"), "{err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_supervise_closes_init_notify() -> anyhow::Result<()> {
+        let mountpoint = tempfile::tempdir()?;
+        let (mut recv, init_notify) = os_pipe::pipe()?;
+        // the first daemon fails at once, without a word to the parent
+        let result = supervise(mountpoint.path(), init_notify, |_| {
+            anyhow::bail!("no mount")
+        });
+        assert!(result.is_err());
+        // so nothing is left to keep the pipe open
+        let mut read = Vec::new();
+        recv.read_to_end(&mut read)?;
+        assert!(read.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use tempfile::tempdir;
+
+pub mod helpers;
+use helpers::puzzlefs;
+
+#[test]
+fn commit_changes_of_a_mount() -> anyhow::Result<()> {
+    // the mount needs fuse
+    if !Path::new("/dev/fuse").exists() {
+        return Ok(());
+    }
+    let dir = tempdir()?;
+    let rootfs = Path::new("../puzzlefs-lib/src/builder/test/test-1/");
+    let oci = dir.path().join("oci");
+    let image_ref = |tag: &str| {
+        let mut image_ref = oci.clone().into_os_string();
+        image_ref.push(format!(":{tag}"));
+        image_ref
+    };
+    puzzlefs([
+        OsStr::new("build"),
+        rootfs.as_ref(),
+        image_ref("base").as_os_str(),
+    ])?;
+
+    let upper = dir.path().join("upper");
+    let mountpoint = dir.path().join("mount");
+    fs::create_dir_all(&mountpoint)?;
+    puzzlefs([
+        OsStr::new("mount"),
+        OsStr::new("--upper"),
+        upper.as_os_str(),
+        image_ref("base").as_os_str(),
+        mountpoint.as_os_str(),
+    ])?;
+    fs::write(mountpoint.join("new-file"), b"changed")?;
+
+    // the changes belong to the mounted image, not to another one
+    let other = dir.path().join("other");
+    puzzlefs([
+        OsStr::new("build"),
+        rootfs.as_ref(),
+        OsStr::new(&format!("{}:base", other.display())),
+    ])?;
+    let err = puzzlefs([
+        OsStr::new("commit"),
+        mountpoint.as_os_str(),
+        OsStr::new(&format!("{}:v2", other.display())),
+    ])
+    .unwrap_err();
+    assert!(err.to_string().contains("is a mount of"), "{err}");
+
+    let committed = puzzlefs([
+        OsStr::new("commit"),
+        mountpoint.as_os_str(),
+        image_ref("v2").as_os_str(),
+    ]);
+    puzzlefs([OsStr::new("umount"), mountpoint.as_os_str()])?;
+    committed?;
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs([
+        OsStr::new("extract"),
+        image_ref("v2").as_os_str(),
+        extracted.as_os_str(),
+    ])?;
+    assert_eq!(fs::read(extracted.join("new-file"))?, b"changed");
+    assert!(extracted.join("SekienAkashita.jpg").exists());
+    Ok(())
+}
@@ -54,13 +54,13 @@ fn fuser_umount(puzzlefs_mountpoint: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn check_tamper(oci_path: &Path) -> anyhow::Result<()> {
-    for file in WalkDir::new(oci_path.join("blobs").join("sha256")).into_iter() {
+fn check_tamper(dir: &Path) -> anyhow::Result<()> {
+    for file in WalkDir::new(dir).into_iter() {
         let file = file?;
         if !file.metadata()?.is_file() {
             continue;
         }
-        // we should get permission denied when trying to open blobs for writing
+        // we should get permission denied when trying to open files for writing
         let error = OpenOptions::new()
             .write(true)
             .open(file.path())
@@ -102,7 +102,7 @@ fn test_fs_verity() -> anyhow::Result<()> {
         OsStr::new(digest),
     ])?;
 
-    check_tamper(&oci)?;
+    check_tamper(&oci.join("blobs").join("sha256"))?;
 
     let puzzlefs_mountpoint = mount_path.join("mount");
     fs::create_dir_all(&puzzlefs_mountpoint)?;
@@ -134,5 +134,15 @@ fn test_fs_verity() -> anyhow::Result<()> {
 
     fuser_umount(puzzlefs_mountpoint)?;
 
+    // the extracted files can get fs-verity too
+    let extracted = mount_path.join("extracted");
+    puzzlefs([
+        OsStr::new("extract"),
+        OsStr::new("--enable-verity"),
+        oci_arg.as_ref(),
+        extracted.as_os_str(),
+    ])?;
+    check_tamper(&extracted)?;
+
     Ok(())
 }
@@ -0,0 +1,68 @@
+#ifndef PUZZLEFS_H
+#define PUZZLEFS_H
+
+/* Generated by cbindgen from puzzlefs-capi, do not edit. */
+
+#include <stdbool.h>
+
+/*
+ * A mounted puzzlefs image, created by `puzzlefs_mount` and released by `puzzlefs_umount`.
+ */
+typedef struct PuzzlefsMount PuzzlefsMount;
+
+/*
+ * Returns the message of the last error of the calling thread, or NULL if there was none. The
+ * string is owned by puzzlefs and valid until the next call into puzzlefs from this thread.
+ */
+const char *puzzlefs_last_error(void);
+
+/*
+ * Builds the directory `rootfs` into the image `tag` of the OCI directory `oci_dir`, which is
+ * created if needed. If `base` isn't NULL, the new image is a delta on top of the tag `base`.
+ *
+ * # Safety
+ *
+ * `oci_dir`, `rootfs` and `tag` must be NUL terminated strings, `base` must be NULL or a NUL
+ * terminated string.
+ */
+int puzzlefs_build(const char *oci_dir,
+                   const char *rootfs,
+                   const char *tag,
+                   const char *base,
+                   bool compression);
+
+/*
+ * Mounts the image `tag` of `oci_dir` on `mountpoint`, served by a thread of the calling
+ * process. `tag` can be followed by `@` and the hex fs-verity digest of its manifest to require
+ * verity. On success, `*mount` is set to a handle which must be passed to `puzzlefs_umount`.
+ *
+ * # Safety
+ *
+ * `oci_dir`, `tag` and `mountpoint` must be NUL terminated strings, `mount` must point to
+ * writable memory for a pointer.
+ */
+int puzzlefs_mount(const char *oci_dir,
+                   const char *tag,
+                   const char *mountpoint,
+                   PuzzlefsMount **mount);
+
+/*
+ * Unmounts an image mounted by `puzzlefs_mount` and waits for its thread to exit. `mount` is
+ * freed, even on failure.
+ *
+ * # Safety
+ *
+ * `mount` must be NULL or a handle returned by `puzzlefs_mount` which wasn't unmounted yet.
+ */
+int puzzlefs_umount(PuzzlefsMount *mount);
+
+/*
+ * Extracts the image `tag` of `oci_dir` into the directory `dest`.
+ *
+ * # Safety
+ *
+ * `oci_dir`, `tag` and `dest` must be NUL terminated strings.
+ */
+int puzzlefs_extract(const char *oci_dir, const char *tag, const char *dest);
+
+#endif  /* PUZZLEFS_H */
@@ -0,0 +1,229 @@
+//! C bindings of [`puzzlefs_lib::api`], declared in `include/puzzlefs.h`.
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+use nix::errno::Errno;
+use puzzlefs_lib::api::{self, BuildOptions, ImageHandle, MountHandle, MountOptions, TagRef};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A mounted puzzlefs image, created by `puzzlefs_mount` and released by `puzzlefs_umount`.
+pub struct PuzzlefsMount {
+    handle: MountHandle,
+}
+
+fn set_last_error(message: &str) {
+    // the message is for humans, interior NULs would only truncate it
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning its errors and panics into a negative errno. Panics must not unwind into C.
+fn ffi_call(f: impl FnOnce() -> api::Result<()>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(&e.to_string());
+            -e.errno()
+        }
+        Err(_) => {
+            set_last_error("puzzlefs panicked");
+            -(Errno::EIO as c_int)
+        }
+    }
+}
+
+fn invalid_argument(what: &str) -> api::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid {what}")).into()
+}
+
+/// Borrows a NUL terminated C string as a path; `what` names the argument in the error.
+///
+/// # Safety
+///
+/// `s` must be NULL or point to a NUL terminated string which outlives the returned path.
+unsafe fn path_arg<'a>(s: *const c_char, what: &str) -> api::Result<&'a Path> {
+    if s.is_null() {
+        return Err(invalid_argument(what));
+    }
+    Ok(Path::new(OsStr::from_bytes(CStr::from_ptr(s).to_bytes())))
+}
+
+/// Borrows a NUL terminated C string which must be valid UTF-8.
+///
+/// # Safety
+///
+/// Same as [`path_arg`].
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> api::Result<&'a str> {
+    if s.is_null() {
+        return Err(invalid_argument(what));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| invalid_argument(what))
+}
+
+/// Returns the message of the last error of the calling thread, or NULL if there was none. The
+/// string is owned by puzzlefs and valid until the next call into puzzlefs from this thread.
+#[no_mangle]
+pub extern "C" fn puzzlefs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Builds the directory `rootfs` into the image `tag` of the OCI directory `oci_dir`, which is
+/// created if needed. If `base` isn't NULL, the new image is a delta on top of the tag `base`.
+///
+/// # Safety
+///
+/// `oci_dir`, `rootfs` and `tag` must be NUL terminated strings, `base` must be NULL or a NUL
+/// terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_build(
+    oci_dir: *const c_char,
+    rootfs: *const c_char,
+    tag: *const c_char,
+    base: *const c_char,
+    compression: bool,
+) -> c_int {
+    ffi_call(|| {
+        let oci_dir = path_arg(oci_dir, "oci_dir")?;
+        let rootfs = path_arg(rootfs, "rootfs")?;
+        let tag = str_arg(tag, "tag")?;
+        let base = if base.is_null() {
+            None
+        } else {
+            Some(str_arg(base, "base")?.to_string())
+        };
+        let image = if base.is_some() {
+            ImageHandle::open(oci_dir)?
+        } else {
+            ImageHandle::create(oci_dir)?
+        };
+        let mut options = BuildOptions::default();
+        options.compression = compression;
+        options.base = base;
+        image.build(rootfs, tag, &options)
+    })
+}
+
+/// Mounts the image `tag` of `oci_dir` on `mountpoint`, served by a thread of the calling
+/// process. `tag` can be followed by `@` and the hex fs-verity digest of its manifest to require
+/// verity. On success, `*mount` is set to a handle which must be passed to `puzzlefs_umount`.
+///
+/// # Safety
+///
+/// `oci_dir`, `tag` and `mountpoint` must be NUL terminated strings, `mount` must point to
+/// writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_mount(
+    oci_dir: *const c_char,
+    tag: *const c_char,
+    mountpoint: *const c_char,
+    mount: *mut *mut PuzzlefsMount,
+) -> c_int {
+    ffi_call(|| {
+        if mount.is_null() {
+            return Err(invalid_argument("mount"));
+        }
+        let oci_dir = path_arg(oci_dir, "oci_dir")?;
+        let tag: TagRef = str_arg(tag, "tag")?.parse()?;
+        let mountpoint = path_arg(mountpoint, "mountpoint")?;
+        let handle =
+            ImageHandle::open(oci_dir)?.mount(&tag, mountpoint, &MountOptions::default())?;
+        *mount = Box::into_raw(Box::new(PuzzlefsMount { handle }));
+        Ok(())
+    })
+}
+
+/// Unmounts an image mounted by `puzzlefs_mount` and waits for its thread to exit. `mount` is
+/// freed, even on failure.
+///
+/// # Safety
+///
+/// `mount` must be NULL or a handle returned by `puzzlefs_mount` which wasn't unmounted yet.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_umount(mount: *mut PuzzlefsMount) -> c_int {
+    ffi_call(|| {
+        if mount.is_null() {
+            return Err(invalid_argument("mount"));
+        }
+        Box::from_raw(mount).handle.unmount();
+        Ok(())
+    })
+}
+
+/// Extracts the image `tag` of `oci_dir` into the directory `dest`.
+///
+/// # Safety
+///
+/// `oci_dir`, `tag` and `dest` must be NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_extract(
+    oci_dir: *const c_char,
+    tag: *const c_char,
+    dest: *const c_char,
+) -> c_int {
+    ffi_call(|| {
+        let oci_dir = path_arg(oci_dir, "oci_dir")?;
+        let tag = str_arg(tag, "tag")?;
+        let dest = path_arg(dest, "dest")?;
+        ImageHandle::open(oci_dir)?.extract(tag, dest)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn c_path(path: &Path) -> CString {
+        CString::new(path.as_os_str().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_build_and_extract() {
+        let dir = tempdir().unwrap();
+        let oci = c_path(&dir.path().join("oci"));
+        let rootfs = Path::new("../puzzlefs-lib/src/builder/test/test-1");
+        let tag = CString::new("test").unwrap();
+        let dest = dir.path().join("extracted");
+        let c_rootfs = c_path(rootfs);
+        let c_dest = c_path(&dest);
+
+        let ret = unsafe {
+            puzzlefs_build(
+                oci.as_ptr(),
+                c_rootfs.as_ptr(),
+                tag.as_ptr(),
+                ptr::null(),
+                true,
+            )
+        };
+        assert_eq!(ret, 0);
+        let ret = unsafe { puzzlefs_extract(oci.as_ptr(), tag.as_ptr(), c_dest.as_ptr()) };
+        assert_eq!(ret, 0);
+        assert_eq!(
+            fs::read(dest.join("SekienAkashita.jpg")).unwrap(),
+            fs::read(rootfs.join("SekienAkashita.jpg")).unwrap()
+        );
+
+        let missing = CString::new("missing").unwrap();
+        let ret = unsafe { puzzlefs_extract(oci.as_ptr(), missing.as_ptr(), c_dest.as_ptr()) };
+        assert!(ret < 0);
+        assert!(!puzzlefs_last_error().is_null());
+
+        let ret = unsafe { puzzlefs_extract(ptr::null(), tag.as_ptr(), c_dest.as_ptr()) };
+        assert_eq!(ret, -(Errno::EINVAL as c_int));
+    }
+}
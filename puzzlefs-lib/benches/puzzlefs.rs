@@ -0,0 +1,127 @@
+//! Micro-benchmarks of building and reading images, run with `cargo bench -p puzzlefs-lib`.
+use std::fs;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tempfile::{tempdir, TempDir};
+
+use puzzlefs_lib::builder::{build_initial_rootfs, chunk_lengths, BuilderConfig, ChunkerConfig};
+use puzzlefs_lib::compression::{Compression, Zstd};
+use puzzlefs_lib::oci::Image;
+use puzzlefs_lib::reader::PuzzleFS;
+use puzzlefs_lib::test_utils::XorShift;
+
+// data which compresses about as well as binaries do, the same on every run
+fn synthetic_data(len: usize, seed: u32) -> Vec<u8> {
+    let mut data = XorShift::new(seed).bytes(len);
+    data.iter_mut().for_each(|byte| *byte %= 64);
+    data
+}
+
+// `dirs` directories of `files` files of `size` bytes each, returning the files and their sizes
+fn synthetic_tree(root: &Path, dirs: usize, files: usize, size: usize) -> Vec<(PathBuf, u64)> {
+    let mut tree = Vec::new();
+    for d in 0..dirs {
+        let dir = root.join(format!("dir-{d}"));
+        fs::create_dir_all(&dir).unwrap();
+        for f in 0..files {
+            let path = dir.join(format!("file-{f}"));
+            fs::write(&path, synthetic_data(size, (d * files + f) as u32)).unwrap();
+            tree.push((path, size as u64));
+        }
+    }
+    tree
+}
+
+fn build(rootfs: &Path) -> TempDir {
+    let dir = tempdir().unwrap();
+    let image = Image::new(dir.path()).unwrap().with_durable_writes(false);
+    build_initial_rootfs::<Zstd>(rootfs, &image, "bench", &BuilderConfig::default()).unwrap();
+    dir
+}
+
+fn chunker(c: &mut Criterion) {
+    let dir = tempdir().unwrap();
+    let files = synthetic_tree(dir.path(), 4, 16, 256 * 1024);
+    let mut group = c.benchmark_group("chunker");
+    group.throughput(Throughput::Bytes(files.iter().map(|(_, size)| size).sum()));
+    for chunker in ["fastcdc", "gear", "fixed"] {
+        let config = BuilderConfig {
+            chunker: Some(chunker.parse::<ChunkerConfig>().unwrap()),
+            ..Default::default()
+        };
+        group.bench_function(chunker, |b| {
+            b.iter(|| chunk_lengths(&files, &config).unwrap())
+        });
+    }
+    let anchored = BuilderConfig {
+        anchor_files: true,
+        ..Default::default()
+    };
+    group.bench_function("fastcdc anchored", |b| {
+        b.iter(|| chunk_lengths(&files, &anchored).unwrap())
+    });
+    let parallel = BuilderConfig {
+        parallel_chunking_above: Some(0),
+        ..Default::default()
+    };
+    group.bench_function("fastcdc parallel", |b| {
+        b.iter(|| chunk_lengths(&files, &parallel).unwrap())
+    });
+    group.finish();
+}
+
+fn find_inode(c: &mut Criterion) {
+    let dir = tempdir().unwrap();
+    synthetic_tree(&dir.path().join("rootfs"), 100, 100, 0);
+    let oci = build(&dir.path().join("rootfs"));
+    let pfs = PuzzleFS::open(Image::open(oci.path()).unwrap(), "bench", None).unwrap();
+    let max = pfs.max_inode().unwrap();
+    let mut ino = 0;
+    c.bench_function("find_inode/10k", |b| {
+        b.iter(|| {
+            // a stride coprime with the number of inodes, so the lookups jump around
+            ino = (ino + 7919) % max;
+            pfs.find_inode(ino + 1).unwrap()
+        })
+    });
+}
+
+fn zstd_seek(c: &mut Criterion) {
+    let data = synthetic_data(16 * 1024 * 1024, 1);
+    let mut compressed = Vec::new();
+    let mut compressor = Zstd::compress(&mut compressed).unwrap();
+    compressor.write_all(&data).unwrap();
+    compressor.end().unwrap();
+    let mut decompressor = Zstd::decompress(Cursor::new(&compressed)).unwrap();
+
+    let mut group = c.benchmark_group("zstd");
+    group.throughput(Throughput::Bytes(4096));
+    let mut buf = vec![0; 4096];
+    let mut offset = 0u64;
+    group.bench_function("random 4KiB reads", |b| {
+        b.iter(|| {
+            offset = (offset + 5_003_219) % (data.len() - buf.len()) as u64;
+            decompressor.seek(SeekFrom::Start(offset)).unwrap();
+            decompressor.read_exact(&mut buf).unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn build_tree(c: &mut Criterion) {
+    let dir = tempdir().unwrap();
+    let files = synthetic_tree(dir.path(), 20, 20, 32 * 1024);
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(files.iter().map(|(_, size)| size).sum()));
+    // the images are deleted outside of the measurements
+    group.bench_function("400 files", |b| {
+        b.iter_with_large_drop(|| build(dir.path()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, chunker, find_inode, zstd_seek, build_tree);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+//! Feeds arbitrary rootfs blobs to the checks run before the metadata of an image is read.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use puzzlefs_lib::oci::MetadataLimits;
+
+fuzz_target!(|data: &[u8]| {
+    // rootfs blobs are mapped, so they're aligned like capnp words
+    let mut words = capnp::Word::allocate_zeroed_vec(data.len() / 8);
+    let aligned = capnp::Word::words_to_bytes_mut(&mut words);
+    aligned.copy_from_slice(&data[..aligned.len()]);
+    let _ = MetadataLimits::default().check(aligned);
+
+    let tight = MetadataLimits {
+        max_inodes: 16,
+        max_dir_entries: 8,
+        max_name_len: 16,
+        max_chunks: 8,
+        ..Default::default()
+    };
+    let _ = tight.check(aligned);
+});
@@ -0,0 +1,746 @@
+//! A small API for embedding puzzlefs, which only exposes its own types and follows semver.
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use nix::errno::Errno;
+use ocidir::oci_spec::image::ANNOTATION_REF_NAME;
+
+use crate::builder::{add_rootfs_delta, build_initial_rootfs, BuilderConfig};
+use crate::compression::{Noop, Zstd};
+use crate::extractor::{extract_rootfs, ExtractError, ExtractorConfig};
+use crate::format::{Inode, InodeMode, WireFormatError};
+use crate::fsverity_helpers::VerityHash;
+use crate::mode_policy::PathPattern;
+use crate::oci::Image;
+use crate::reader::fuse::to_errno;
+use crate::reader::{self, BackgroundSession, FuseConfig, PuzzleFS};
+use crate::xattr_filter::XattrPattern;
+
+/// The error of every function of this module: an errno, plus a message for humans.
+#[derive(Debug)]
+pub struct Error {
+    errno: i32,
+    message: String,
+}
+
+impl Error {
+    /// The errno which best describes the error.
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<WireFormatError> for Error {
+    fn from(e: WireFormatError) -> Self {
+        Error {
+            errno: to_errno(&e),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<ExtractError> for Error {
+    fn from(e: ExtractError) -> Self {
+        let errno = match &e {
+            ExtractError::Image(e) => to_errno(e),
+            ExtractError::Io(e) | ExtractError::PathComponent { source: e, .. } => {
+                e.raw_os_error().unwrap_or(Errno::EIO as i32)
+            }
+            ExtractError::Sys(errno) => *errno as i32,
+            ExtractError::Unrestorable { .. } | ExtractError::Setuid { .. } => Errno::EPERM as i32,
+            ExtractError::SymlinkPrefix { .. } | ExtractError::EscapingPath { .. } => {
+                Errno::EXDEV as i32
+            }
+            ExtractError::ExistingFile { .. } => Errno::EEXIST as i32,
+            ExtractError::DigestMismatch { .. } => Errno::EIO as i32,
+            ExtractError::BadInodeMode { .. } => Errno::EINVAL as i32,
+        };
+        Error {
+            errno,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        WireFormatError::from(e).into()
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        let errno = if let Some(e) = e.downcast_ref::<WireFormatError>() {
+            to_errno(e)
+        } else if let Some(e) = e.downcast_ref::<io::Error>() {
+            e.raw_os_error().unwrap_or(Errno::EIO as i32)
+        } else {
+            Errno::EINVAL as i32
+        };
+        Error {
+            errno,
+            message: format!("{e:#}"),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn invalid_argument(message: String) -> Error {
+    Error {
+        errno: Errno::EINVAL as i32,
+        message,
+    }
+}
+
+/// A tag of an image, optionally with the fs-verity digest of its manifest, written as
+/// `tag[@hex digest]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagRef {
+    pub tag: String,
+    pub verity: Option<Vec<u8>>,
+}
+
+impl TagRef {
+    pub fn new(tag: &str) -> Self {
+        TagRef {
+            tag: tag.to_string(),
+            verity: None,
+        }
+    }
+}
+
+impl FromStr for TagRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('@') {
+            Some((tag, verity)) => Ok(TagRef {
+                tag: tag.to_string(),
+                verity: Some(hex::decode(verity).map_err(WireFormatError::from)?),
+            }),
+            None => Ok(TagRef::new(s)),
+        }
+    }
+}
+
+impl fmt::Display for TagRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.tag)?;
+        if let Some(verity) = &self.verity {
+            write!(f, "@{}", hex::encode(verity))?;
+        }
+        Ok(())
+    }
+}
+
+/// How to build an image.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct BuildOptions {
+    /// Compress the chunks with zstd.
+    pub compression: bool,
+    /// Build a delta on top of this tag of the same image.
+    pub base: Option<String>,
+    /// Mark the image as requiring fs-verity to be mounted.
+    pub require_verity: bool,
+    /// Use sha512 rather than sha256 fs-verity digests.
+    pub sha512_verity: bool,
+    /// The uid and gid of every file of the image.
+    pub owner: Option<(u32, u32)>,
+}
+
+/// How to mount an image.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct MountOptions {
+    /// Options passed to the kernel, e.g. `allow_other`.
+    pub options: Vec<String>,
+    /// Make the mount writable, storing the changes in this directory.
+    pub upper_dir: Option<PathBuf>,
+    /// Tags to stack below the mounted one, topmost first.
+    pub lower: Vec<TagRef>,
+    /// Refuse to read anything without fs-verity.
+    pub require_verity: bool,
+}
+
+/// The kind of a file of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Socket,
+}
+
+impl FileKind {
+    const NAMES: [(FileKind, &'static str); 7] = [
+        (FileKind::File, "file"),
+        (FileKind::Dir, "dir"),
+        (FileKind::Symlink, "symlink"),
+        (FileKind::Fifo, "fifo"),
+        (FileKind::CharDevice, "char"),
+        (FileKind::BlockDevice, "block"),
+        (FileKind::Socket, "socket"),
+    ];
+}
+
+impl FromStr for FileKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        FileKind::NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(kind, _)| *kind)
+            .ok_or_else(|| {
+                invalid_argument(format!(
+                    "invalid file kind {s}, expected file, dir, symlink, fifo, char, block or socket"
+                ))
+            })
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // .unwrap() here because every kind has a name
+        let (_, name) = FileKind::NAMES
+            .iter()
+            .find(|(kind, _)| kind == self)
+            .unwrap();
+        f.write_str(name)
+    }
+}
+
+/// A file of an image, as returned by [`ImageHandle::walk`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    pub kind: FileKind,
+    /// The size of regular files, 0 for the other kinds.
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// The permission bits, including setuid, setgid and sticky.
+    pub permissions: u16,
+}
+
+/// The metadata of a file of an image, as returned by [`ImageHandle::stat`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FileStat {
+    pub ino: u64,
+    pub kind: FileKind,
+    /// The size of regular files, 0 for the other kinds.
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// The permission bits, including setuid, setgid and sticky.
+    pub permissions: u16,
+    /// The number of chunks of regular files.
+    pub chunks: usize,
+    /// Whether the content of a regular file is stored in the metadata rather than in chunks.
+    pub inline: bool,
+    /// The major and minor numbers of devices.
+    pub device: Option<(u64, u64)>,
+    pub symlink_target: Option<PathBuf>,
+    /// The xattrs, as names and values.
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// How [`ImageHandle::find`] matches the paths of files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamePattern {
+    /// A glob like `*.so`, matching the name of a file, or, if it contains a `/`, like
+    /// `usr/lib/*.so`, its whole path. `*` matches anything but `/`, `**` matches anything and
+    /// `?` matches one character other than `/`.
+    Glob(String),
+    /// A regular expression, found anywhere in the whole path of a file.
+    Regex(String),
+}
+
+/// What [`ImageHandle::find`] looks for; it returns the files matching every criterion which is
+/// set.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct FindOptions {
+    pub name: Option<NamePattern>,
+    pub kind: Option<FileKind>,
+    /// The smallest size of the files, as in [`WalkEntry::size`].
+    pub min_size: Option<u64>,
+    /// The largest size of the files, as in [`WalkEntry::size`].
+    pub max_size: Option<u64>,
+    /// Permission bits the files must all have, e.g. `0o4000` for setuid files.
+    pub permissions: Option<u16>,
+    /// An xattr the files must have: its name, or a prefix followed by `*`, like `security.*`.
+    pub xattr: Option<String>,
+}
+
+enum NameMatcher {
+    Name(PathPattern),
+    Path(PathPattern),
+    Regex(regex::bytes::Regex),
+}
+
+impl NameMatcher {
+    fn new(pattern: &NamePattern) -> Result<Self> {
+        match pattern {
+            NamePattern::Glob(glob) => {
+                let pattern = glob.parse().map_err(invalid_argument)?;
+                if glob.contains('/') {
+                    Ok(NameMatcher::Path(pattern))
+                } else {
+                    Ok(NameMatcher::Name(pattern))
+                }
+            }
+            NamePattern::Regex(regex) => regex::bytes::Regex::new(regex)
+                .map(NameMatcher::Regex)
+                .map_err(|e| invalid_argument(format!("invalid regular expression: {e}"))),
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            // a name has no /, so it matches like a path with a single component
+            NameMatcher::Name(pattern) => path
+                .file_name()
+                .is_some_and(|name| pattern.matches(Path::new(name))),
+            NameMatcher::Path(pattern) => pattern.matches(path),
+            NameMatcher::Regex(regex) => regex.is_match(path.as_os_str().as_bytes()),
+        }
+    }
+}
+
+/// A mounted image, unmounted when dropped.
+pub struct MountHandle {
+    session: BackgroundSession,
+}
+
+impl MountHandle {
+    /// Unmounts the image and waits for the FUSE daemon thread to exit.
+    pub fn unmount(self) {
+        self.session.join()
+    }
+}
+
+/// An OCI directory holding puzzlefs images.
+pub struct ImageHandle {
+    path: PathBuf,
+}
+
+impl ImageHandle {
+    /// Opens an existing OCI directory.
+    pub fn open(path: &Path) -> Result<Self> {
+        Image::open(path)?;
+        Ok(ImageHandle {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Creates an OCI directory, or opens it if it already exists.
+    pub fn create(path: &Path) -> Result<Self> {
+        Image::new(path)?;
+        Ok(ImageHandle {
+            path: path.to_path_buf(),
+        })
+    }
+
+    // each operation opens the directory again, so handles stay cheap and independent of each
+    // other
+    fn image(&self) -> Result<Image> {
+        Ok(Image::open(&self.path)?)
+    }
+
+    fn open_tag(&self, tag: &TagRef) -> Result<PuzzleFS> {
+        Ok(PuzzleFS::open(
+            self.image()?,
+            &tag.tag,
+            tag.verity.as_deref(),
+        )?)
+    }
+
+    /// Returns the tags of the image.
+    pub fn tags(&self) -> Result<Vec<String>> {
+        Ok(self
+            .image()?
+            .get_index()?
+            .manifests()
+            .iter()
+            .filter_map(|desc| {
+                desc.annotations()
+                    .as_ref()?
+                    .get(ANNOTATION_REF_NAME)
+                    .cloned()
+            })
+            .collect())
+    }
+
+    /// Returns the digest of the manifest of `tag`, as `sha256:<hex>`.
+    pub fn manifest_digest(&self, tag: &str) -> Result<String> {
+        let desc = self
+            .image()?
+            .find_manifest_descriptor(tag)?
+            .ok_or_else(|| Error {
+                errno: Errno::ENOENT as i32,
+                message: format!("no such tag {tag}"),
+            })?;
+        Ok(desc.digest().to_string())
+    }
+
+    /// Builds the directory `rootfs` into `tag`.
+    pub fn build(&self, rootfs: &Path, tag: &str, options: &BuildOptions) -> Result<()> {
+        let config = BuilderConfig {
+            require_verity: options.require_verity,
+            verity_hash: if options.sha512_verity {
+                VerityHash::Sha512
+            } else {
+                VerityHash::Sha256
+            },
+            owner: options.owner,
+            ..Default::default()
+        };
+        let image = self.image()?;
+        match (&options.base, options.compression) {
+            (Some(base), true) => {
+                add_rootfs_delta::<Zstd>(rootfs, image, tag, base, &config).map(|_| ())?
+            }
+            (Some(base), false) => {
+                add_rootfs_delta::<Noop>(rootfs, image, tag, base, &config).map(|_| ())?
+            }
+            (None, true) => {
+                build_initial_rootfs::<Zstd>(rootfs, &image, tag, &config).map(|_| ())?
+            }
+            (None, false) => {
+                build_initial_rootfs::<Noop>(rootfs, &image, tag, &config).map(|_| ())?
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts `tag` into the directory `dest`.
+    pub fn extract(&self, tag: &str, dest: &Path) -> Result<()> {
+        let path = self.path.to_str().ok_or_else(|| Error {
+            errno: Errno::EINVAL as i32,
+            message: format!("{} is not valid UTF-8", self.path.display()),
+        })?;
+        let dest = dest.to_str().ok_or_else(|| Error {
+            errno: Errno::EINVAL as i32,
+            message: format!("{} is not valid UTF-8", dest.display()),
+        })?;
+        extract_rootfs(path, tag, dest, &ExtractorConfig::default())?;
+        Ok(())
+    }
+
+    /// Mounts `tag` on `mountpoint`, served by a thread of this process.
+    pub fn mount(
+        &self,
+        tag: &TagRef,
+        mountpoint: &Path,
+        options: &MountOptions,
+    ) -> Result<MountHandle> {
+        let config = FuseConfig {
+            upper_dir: options.upper_dir.clone(),
+            lower: options
+                .lower
+                .iter()
+                .map(|lower| (lower.tag.clone(), lower.verity.clone()))
+                .collect(),
+            require_verity: options.require_verity,
+            ..Default::default()
+        };
+        let session = reader::spawn_mount(
+            self.image()?,
+            &tag.tag,
+            mountpoint,
+            &options.options,
+            None,
+            None,
+            tag.verity.as_deref(),
+            &config,
+        )?;
+        Ok(MountHandle { session })
+    }
+
+    /// Lists all the files of `tag`, breadth first.
+    pub fn walk(&self, tag: &TagRef) -> Result<Vec<WalkEntry>> {
+        self.walk_filtered(tag, |_, _| true)
+    }
+
+    /// Lists the files of `tag` matching `options`, breadth first, without extracting or
+    /// mounting it.
+    pub fn find(&self, tag: &TagRef, options: &FindOptions) -> Result<Vec<WalkEntry>> {
+        let name = options.name.as_ref().map(NameMatcher::new).transpose()?;
+        let xattr = options
+            .xattr
+            .as_deref()
+            .map(str::parse::<XattrPattern>)
+            .transpose()
+            .map_err(invalid_argument)?;
+        self.walk_filtered(tag, |entry, inode| {
+            name.as_ref().map_or(true, |name| name.matches(&entry.path))
+                && options.kind.map_or(true, |kind| kind == entry.kind)
+                && options.min_size.map_or(true, |size| entry.size >= size)
+                && options.max_size.map_or(true, |size| entry.size <= size)
+                && options
+                    .permissions
+                    .map_or(true, |bits| entry.permissions & bits == bits)
+                && xattr.as_ref().map_or(true, |pattern| {
+                    inode.additional.as_ref().is_some_and(|additional| {
+                        additional.xattrs.iter().any(|x| pattern.matches(&x.key))
+                    })
+                })
+        })
+    }
+
+    // the files of `tag` for which `keep` is true, breadth first
+    fn walk_filtered(
+        &self,
+        tag: &TagRef,
+        mut keep: impl FnMut(&WalkEntry, &Inode) -> bool,
+    ) -> Result<Vec<WalkEntry>> {
+        let mut pfs = self.open_tag(tag)?;
+        let mut entries = Vec::new();
+        for entry in reader::WalkPuzzleFS::walk(&mut pfs)? {
+            let entry = entry?;
+            let inode = &entry.inode;
+            let walk_entry = WalkEntry {
+                path: entry.path.clone(),
+                kind: file_kind(inode)?,
+                size: inode.file_len().unwrap_or(0),
+                uid: inode.uid,
+                gid: inode.gid,
+                permissions: inode.permissions,
+            };
+            if keep(&walk_entry, inode) {
+                entries.push(walk_entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Reads the whole content of the regular file at `path` in `tag`.
+    pub fn read_file(&self, tag: &TagRef, path: &Path) -> Result<Vec<u8>> {
+        let pfs = self.open_tag(tag)?;
+        let inode = pfs.lookup(path)?.ok_or_else(|| Error {
+            errno: Errno::ENOENT as i32,
+            message: format!("no such file {}", path.display()),
+        })?;
+        let mut content = Vec::new();
+        reader::FileReader::new(&pfs.oci, &inode)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Returns the metadata of the file at `path` in `tag`.
+    pub fn stat(&self, tag: &TagRef, path: &Path) -> Result<FileStat> {
+        let pfs = self.open_tag(tag)?;
+        let inode = pfs.lookup(path)?.ok_or_else(|| Error {
+            errno: Errno::ENOENT as i32,
+            message: format!("no such file {}", path.display()),
+        })?;
+        let (chunks, inline) = match &inode.mode {
+            InodeMode::File { chunks, inline } => (chunks.len(), inline.is_some()),
+            _ => (0, false),
+        };
+        let device = match inode.mode {
+            InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+                Some((major, minor))
+            }
+            _ => None,
+        };
+        Ok(FileStat {
+            ino: inode.ino,
+            kind: file_kind(&inode)?,
+            size: inode.file_len().unwrap_or(0),
+            uid: inode.uid,
+            gid: inode.gid,
+            permissions: inode.permissions,
+            chunks,
+            inline,
+            device,
+            symlink_target: inode.symlink_target().ok().map(PathBuf::from),
+            xattrs: inode
+                .additional
+                .as_ref()
+                .map(|additional| {
+                    additional
+                        .xattrs
+                        .iter()
+                        .map(|x| (x.key.clone(), x.val.clone()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Returns the target of the symlink at `path` in `tag`.
+    pub fn read_link(&self, tag: &TagRef, path: &Path) -> Result<PathBuf> {
+        let pfs = self.open_tag(tag)?;
+        let inode = pfs.lookup(path)?.ok_or_else(|| Error {
+            errno: Errno::ENOENT as i32,
+            message: format!("no such file {}", path.display()),
+        })?;
+        Ok(PathBuf::from(inode.symlink_target()?))
+    }
+}
+
+fn file_kind(inode: &Inode) -> Result<FileKind> {
+    match inode.mode {
+        InodeMode::File { .. } => Ok(FileKind::File),
+        InodeMode::Dir { .. } => Ok(FileKind::Dir),
+        InodeMode::Lnk => Ok(FileKind::Symlink),
+        InodeMode::Fifo => Ok(FileKind::Fifo),
+        InodeMode::Chr { .. } => Ok(FileKind::CharDevice),
+        InodeMode::Blk { .. } => Ok(FileKind::BlockDevice),
+        InodeMode::Sock => Ok(FileKind::Socket),
+        InodeMode::Unknown | InodeMode::Wht => {
+            Err(WireFormatError::from_errno(Errno::EINVAL).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_api() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = ImageHandle::create(&dir.path().join("oci"))?;
+        image.build(
+            Path::new("src/builder/test/test-1"),
+            "test",
+            &BuildOptions {
+                compression: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(image.tags()?, vec!["test".to_string()]);
+        assert!(image.manifest_digest("test")?.starts_with("sha256:"));
+        assert_eq!(
+            image.manifest_digest("missing").unwrap_err().errno(),
+            Errno::ENOENT as i32
+        );
+
+        let tag: TagRef = "test".parse()?;
+        let entries = image.walk(&tag)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].kind, FileKind::Dir);
+        assert_eq!(entries[1].path, Path::new("/SekienAkashita.jpg"));
+        let content = image.read_file(&tag, &entries[1].path)?;
+        assert_eq!(content.len() as u64, entries[1].size);
+        let stat = image.stat(&tag, &entries[1].path)?;
+        assert_eq!(stat.kind, FileKind::File);
+        assert_eq!(stat.size, entries[1].size);
+        assert!(stat.chunks > 0 && !stat.inline);
+        assert_eq!(
+            image.stat(&tag, Path::new("/missing")).unwrap_err().errno(),
+            Errno::ENOENT as i32
+        );
+
+        let tag: TagRef = "test@00ff".parse()?;
+        assert_eq!(tag.verity, Some(vec![0, 255]));
+        assert_eq!(tag.to_string(), "test@00ff");
+        Ok(())
+    }
+
+    #[test]
+    fn test_find() -> anyhow::Result<()> {
+        // not in /tmp, which may not support user xattrs
+        let dir = tempfile::TempDir::new_in(".")?;
+        let rootfs = dir.path().join("rootfs");
+        std::fs::create_dir_all(rootfs.join("usr/lib"))?;
+        std::fs::write(rootfs.join("usr/lib/libfoo.so"), b"foo")?;
+        std::fs::write(rootfs.join("usr/lib/libbar.so"), vec![0; 4096])?;
+        std::fs::write(rootfs.join("su"), b"su")?;
+        let mut permissions = std::fs::metadata(rootfs.join("su"))?.permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o4755);
+        std::fs::set_permissions(rootfs.join("su"), permissions)?;
+        xattr::set(rootfs.join("usr/lib/libbar.so"), "user.label", b"bar")?;
+
+        let image = ImageHandle::create(&dir.path().join("oci"))?;
+        image.build(&rootfs, "test", &BuildOptions::default())?;
+        let tag = TagRef::new("test");
+        let find = |options: FindOptions| -> Result<Vec<PathBuf>> {
+            Ok(image
+                .find(&tag, &options)?
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect())
+        };
+        let glob = |glob: &str| Some(NamePattern::Glob(glob.to_string()));
+
+        let libs = [
+            PathBuf::from("/usr/lib/libbar.so"),
+            PathBuf::from("/usr/lib/libfoo.so"),
+        ];
+        assert_eq!(
+            find(FindOptions {
+                name: glob("*.so"),
+                ..Default::default()
+            })?,
+            libs
+        );
+        assert_eq!(
+            find(FindOptions {
+                name: glob("usr/*"),
+                ..Default::default()
+            })?,
+            [PathBuf::from("/usr/lib")]
+        );
+        assert_eq!(
+            find(FindOptions {
+                name: Some(NamePattern::Regex("lib/.*foo".to_string())),
+                ..Default::default()
+            })?,
+            [PathBuf::from("/usr/lib/libfoo.so")]
+        );
+        assert_eq!(
+            find(FindOptions {
+                kind: Some(FileKind::File),
+                min_size: Some(1024),
+                ..Default::default()
+            })?,
+            [PathBuf::from("/usr/lib/libbar.so")]
+        );
+        assert_eq!(
+            find(FindOptions {
+                permissions: Some(0o4000),
+                ..Default::default()
+            })?,
+            [PathBuf::from("/su")]
+        );
+        assert_eq!(
+            find(FindOptions {
+                xattr: Some("user.*".to_string()),
+                ..Default::default()
+            })?,
+            [PathBuf::from("/usr/lib/libbar.so")]
+        );
+        assert!(find(FindOptions {
+            name: Some(NamePattern::Regex("(".to_string())),
+            ..Default::default()
+        })
+        .is_err());
+
+        assert_eq!("symlink".parse::<FileKind>()?, FileKind::Symlink);
+        assert_eq!(FileKind::CharDevice.to_string(), "char");
+        assert!("door".parse::<FileKind>().is_err());
+        Ok(())
+    }
+}
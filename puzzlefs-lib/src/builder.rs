@@ -1,58 +1,325 @@
-use crate::common::{AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::common::{mountpoints, rfc3339, PACK_SIZE};
 use crate::compression::{Compression, Noop, Zstd};
 use crate::fsverity_helpers::{
-    check_fs_verity, fsverity_enable, InnerHashAlgorithm, FS_VERITY_BLOCK_SIZE_DEFAULT,
+    enable_and_check_verity_for_file, enable_verity_for_file, get_fs_verity_digest, FsVeritySigner,
+    VerityHash,
 };
+use crate::idmap::IdMap;
+use crate::kernel_layout::rootfs_layout;
+use crate::mode_policy::{forced_mode, ModeRule, SETID_BITS};
 use crate::oci::Digest;
+use crate::symlink_policy::SymlinkPolicy;
+use crate::xattr_filter::XattrFilter;
+use sha2::{Digest as Sha2Digest, Sha256};
 use std::any::Any;
-use std::backtrace::Backtrace;
 use std::cmp::min;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
 use std::io;
-use std::os::fd::AsRawFd;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use thiserror::Error;
+use tracing::{debug, debug_span, info, info_span, warn};
 use walkdir::WalkDir;
 
 use crate::format::{
-    BlobRef, DirEnt, DirList, FileChunk, FileChunkList, Ino, Inode, InodeAdditional, InodeMode,
-    Result, Rootfs, VerityData, WireFormatError,
+    resolve_inode, BlobRef, DirEnt, DirList, FileChunk, Ino, Inode, InodeAdditional, InodeMode,
+    InodeVector, Result, Rootfs, VerityData, WireFormatError,
 };
 use crate::metadata_capnp;
-use crate::oci::media_types;
-use crate::oci::{Descriptor, Image};
+use crate::oci::encryption::ChunkEncryption;
+use crate::oci::media_types::{self, PuzzleFSMediaType};
+use crate::oci::{Descriptor, Image, ImageError, StoredBlob};
 use crate::reader::{PuzzleFS, PUZZLEFS_IMAGE_MANIFEST_VERSION};
-use ocidir::oci_spec::image::{ImageManifest, Platform};
+use ocidir::oci_spec::image::{
+    Digest as OciDigest, HistoryBuilder, ImageConfiguration, ImageManifest, MediaType,
+    ANNOTATION_CREATED,
+};
 
 use nix::errno::Errno;
 
-use fastcdc::v2020::StreamCDC;
+mod cache;
+use cache::{BuildCache, CacheKey};
+mod chunker;
+pub use chunker::{Chunker, ChunkerConfig};
 mod filesystem;
 use filesystem::FilesystemStream;
+mod overlay;
+use overlay::UpperEntry;
+mod pool;
+use pool::ChunkPool;
+mod spill;
+use spill::InodeSpill;
+
+/// Why a build failed because of its source.
+#[derive(Error, Debug)]
+pub enum BuildError {
+    /// The file at `path` in the rootfs changed size while it was read, see
+    /// [`BuilderConfig::ignore_changing_files`].
+    #[error("{} changed while the image was built", .path.display())]
+    SourceChanged { path: PathBuf },
+    /// The symlink at `path` in the rootfs points outside of it, which the symlink policy
+    /// rejects.
+    #[error("the symlink {} points outside of the rootfs", .path.display())]
+    EscapingSymlink { path: PathBuf },
+    /// `op` failed on the file of the rootfs at `path`, e.g. for lack of permissions.
+    #[error("cannot {op} {}: {source}", .path.display())]
+    Io {
+        op: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The image can't be mounted by the kernel driver, for the reasons given.
+    #[error("the image isn't compatible with the kernel driver: {reason}")]
+    KernelCompat { reason: String },
+}
+
+/// Options controlling how an image is built.
+#[derive(Debug, Default, Clone)]
+pub struct BuilderConfig {
+    /// Path to a build cache; regular files whose (dev, ino, mtime, size) match an entry in the
+    /// cache reuse their previous chunk list instead of being read and chunked again. The cache
+    /// is created if it doesn't exist and updated at the end of the build.
+    pub build_cache: Option<PathBuf>,
+    /// Path to a chunk pool shared between images. Chunks are always deduplicated against the
+    /// blobs already in the image; with a pool, they are also hard linked with the chunks of any
+    /// other image built against the same pool, regardless of its base layer.
+    pub chunk_pool: Option<PathBuf>,
+    /// Mark the image as requiring fs-verity: the reader refuses to open it without the fs-verity
+    /// digest of its manifest.
+    pub require_verity: bool,
+    /// The hash algorithm of the fs-verity digests of the blobs the build adds to the image.
+    /// Deltas may use a different algorithm than their base layer, but the chunks they share with
+    /// it keep the digests of the base, since fs-verity may be enabled on them with its algorithm
+    /// already: only the new chunks, the metadata and the manifest are hashed with this one.
+    pub verity_hash: VerityHash,
+    /// The uid and gid of every file of the image, e.g. `(0, 0)` for a rootfs unpacked by a
+    /// regular user which should belong to root. Takes precedence over the id maps.
+    pub owner: Option<(u32, u32)>,
+    /// Map the owners of the source files back to the ids stored in the image, e.g. for a rootfs
+    /// unpacked in a user namespace: with `0:100000:65536`, files owned by uid 100000 on the host
+    /// belong to uid 0 in the image.
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    /// The OCI image config (environment, entrypoint, labels...) to reference from the manifest,
+    /// for the runtimes which use it. Its platform is set to the one of the image. Without it,
+    /// an image gets an empty config and a delta gets the config of its base layer.
+    pub image_config: Option<ImageConfiguration>,
+    /// Stamp the manifest with the time of the build (`SOURCE_DATE_EPOCH` if set, so builds can
+    /// stay reproducible), the version of puzzlefs and the parameters of the chunker.
+    pub stamp: bool,
+    /// More annotations for the manifest, e.g. `org.opencontainers.image.revision`.
+    pub annotations: BTreeMap<String, String>,
+    /// Merge a delta and the metadata layers of its base into a single layer, so that delta
+    /// chains don't grow without bound. The chunks are left as they are.
+    pub squash: bool,
+    /// The digests of the chunks the image reads first, e.g. from an
+    /// [access log](crate::reader::access_log). They are marked as landmarks and come right after
+    /// the rootfs in the layers of the manifest, so lazy pulls fetch them before anything else.
+    pub landmarks: Vec<String>,
+    /// Fail the build if the metadata breaks the invariants the kernel driver relies on, see
+    /// [`kernel_layout`](crate::kernel_layout).
+    pub kernel_compat: bool,
+    /// Which xattrs of the source files are stored in the image; by default, everything but the
+    /// xattrs overlayfs keeps for itself.
+    pub xattr_filter: XattrFilter,
+    /// Clear the setuid and setgid bits of everything but directories, where setgid only makes
+    /// new files inherit the group of the directory.
+    pub clear_setuid: bool,
+    /// Force the mode of the paths matching a rule, regardless of the mode of the source file and
+    /// of `clear_setuid`; when several rules match a path, the last one wins.
+    pub force_modes: Vec<ModeRule>,
+    /// Store the chunks smaller than this many bytes together in pack blobs of about 1MiB instead
+    /// of one blob each, so rootfses with many small files don't turn into as many tiny blobs.
+    /// Readers need nothing special, chunks just point inside the packs; but the chunks in a pack
+    /// are only deduplicated as a whole.
+    pub pack_chunks_below: Option<u32>,
+    /// Store the contents of the regular files smaller than this many bytes in their inode
+    /// rather than in chunks, which saves opening a blob to read them. Readers older than this
+    /// option can't read images built with it.
+    pub inline_files_below: Option<u64>,
+    /// Keep about this many bytes of rendered inodes and chunk lists in memory while building,
+    /// writing the rest to a temporary file in `$TMPDIR` (which should be on disk rather than a
+    /// tmpfs for this to help) and streaming it back into the metadata at the end. Lets small
+    /// machines build huge filesystems; the metadata of the base layers of a delta is still
+    /// loaded whole.
+    pub memory_budget: Option<u64>,
+    /// Store the regular files whose size changed while the image was built at the size they had
+    /// when walked, truncated or padded with zeroes, rather than failing the build with
+    /// [`BuildError::SourceChanged`]. Either way, their contents may be a mix of before and after
+    /// the change, like any copy of a file being written.
+    pub ignore_changing_files: bool,
+    /// Go through the whole build, chunking and compressing the files to find out which chunks
+    /// the image lacks, but don't write anything: no blob, no build cache, no tag. The stats
+    /// and the descriptor of the rootfs are the ones of the real build, except that the chunks
+    /// aren't marked as landmarks and the kernel layout isn't checked.
+    pub dry_run: bool,
+    /// The rootfs of a delta is the upper directory of an overlay mounted on its base layer, e.g.
+    /// the one a writable mount persisted, rather than a whole rootfs: what it lacks is kept from
+    /// the base layer, and its whiteouts and opaque directories, written the overlayfs or the OCI
+    /// way, delete files of the base layer.
+    pub overlay_upper: bool,
+    /// Descend into the filesystems mounted below the rootfs. By default, like `--one-file-system`
+    /// of `tar`, their mountpoints are stored as empty directories and a warning lists them;
+    /// bind mounts count as mountpoints too.
+    pub cross_filesystems: bool,
+    /// Which symlinks are followed, and how the targets of the others are stored.
+    pub symlinks: SymlinkPolicy,
+    /// Encrypt the chunks written by the build, so that only the recipients of the key can read
+    /// the contents of the files; the metadata stays in clear. The build cache isn't used, and
+    /// the files a delta keeps from its base layer keep the chunks they had.
+    pub encryption: Option<ChunkEncryption>,
+    /// Hash the regular files before chunking them: the files with the same contents as one
+    /// already seen in the build reuse its chunk list instead of going through the chunker
+    /// again. Costs a read of every file, which pays off on rootfses with many copies of the same
+    /// files.
+    pub dedup_files: bool,
+    /// Record the sha256 of the contents of every regular file in its inode, so readers can
+    /// check whole files (see [`crate::reader::verify_file`]) rather than only the chunks. Costs
+    /// a read of every file, shared with `dedup_files`.
+    pub file_digests: bool,
+    /// How files are split into chunks. `None` chunks with FastCDC, unless building a delta of
+    /// an image whose manifest records another chunker, which is then used again so that the
+    /// unchanged files are chunked the same way. The chunker is recorded in the manifest when
+    /// it's given, or with `stamp`.
+    pub chunker: Option<ChunkerConfig>,
+    /// Restart the chunker at the start of each file, rather than chunking the files as one
+    /// stream. Adding or changing a file then doesn't move the boundaries of the chunks of the
+    /// files after it, at the cost of a short chunk at the end of every file, so small files
+    /// dedup the same way across builds; combine with `pack_chunks_below` to keep the number of
+    /// blobs down. Recorded in the manifest, and deltas with no `chunker` inherit it.
+    pub anchor_files: bool,
+    /// Chunk the regular files larger than this on their own, ahead of time, in
+    /// `chunking_threads` worker threads, so that reading and chunking large files (VM disks...)
+    /// overlaps with compressing and writing the chunks. The chunks only depend on this size,
+    /// not on the number of threads: those of the large files and of the files around them start
+    /// and end with the large files, as with `anchor_files`. Recorded in the manifest, and
+    /// deltas with no `chunker` inherit it.
+    pub parallel_chunking_above: Option<u64>,
+    /// The number of worker threads of `parallel_chunking_above`; 0 uses one per CPU.
+    pub chunking_threads: usize,
+}
+
+/// Statistics about a build, mostly useful for figuring out how well deduplication worked.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildStats {
+    /// chunks which were not yet present in the image and had to be written
+    pub new_chunks: u64,
+    /// chunks which were already present in the image (e.g. from the base layer or other tags)
+    /// or in the chunk pool
+    pub reused_chunks: u64,
+    /// uncompressed size of all the chunks produced by the chunker
+    pub chunked_bytes: u64,
+    /// uncompressed size of the new chunks
+    pub new_bytes: u64,
+    /// size of the new chunks after compression, as stored in the image
+    pub stored_bytes: u64,
+    /// uncompressed size of the reused chunks, i.e. the bytes we didn't have to store again
+    pub deduplicated_bytes: u64,
+    /// regular files whose chunk list was taken from the build cache
+    pub cached_files: u64,
+    /// size of the serialized PuzzleFS metadata
+    pub metadata_bytes: u64,
+    /// pack blobs written, and the small chunks stored in them rather than in blobs of their own
+    pub packs: u64,
+    pub packed_chunks: u64,
+    /// regular files whose contents are stored in their inode
+    pub inlined_files: u64,
+    /// regular files with the same contents as another file of the build, which weren't chunked
+    pub duplicate_files: u64,
+    /// inodes of a delta identical to the ones of its base layer, which were left out of its
+    /// metadata layer
+    pub unchanged_inodes: u64,
+}
+
+impl BuildStats {
+    fn add_chunk(&mut self, uncompressed_size: u64, stored_size: u64, existing: bool) {
+        self.chunked_bytes += uncompressed_size;
+        if existing {
+            self.reused_chunks += 1;
+            self.deduplicated_bytes += uncompressed_size;
+        } else {
+            self.new_chunks += 1;
+            self.new_bytes += uncompressed_size;
+            self.stored_bytes += stored_size;
+        }
+    }
+}
 
-fn walker(rootfs: &Path) -> WalkDir {
-    // breadth first search for sharing, don't cross filesystems just to be safe, order by file
-    // name. we only return directories here, so we can more easily do delta generation to detect
-    // what's missing in an existing puzzlefs.
+impl fmt::Display for BuildStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "chunks: {} new, {} reused",
+            self.new_chunks, self.reused_chunks
+        )?;
+        writeln!(f, "chunked bytes: {}", self.chunked_bytes)?;
+        writeln!(f, "deduplicated bytes: {}", self.deduplicated_bytes)?;
+        writeln!(
+            f,
+            "new bytes: {} ({} after compression)",
+            self.new_bytes, self.stored_bytes
+        )?;
+        writeln!(f, "files from build cache: {}", self.cached_files)?;
+        if self.inlined_files > 0 {
+            writeln!(f, "inlined files: {}", self.inlined_files)?;
+        }
+        if self.duplicate_files > 0 {
+            writeln!(f, "duplicate files: {}", self.duplicate_files)?;
+        }
+        if self.packs > 0 {
+            writeln!(f, "packs: {} ({} chunks)", self.packs, self.packed_chunks)?;
+        }
+        if self.unchanged_inodes > 0 {
+            writeln!(f, "unchanged inodes: {}", self.unchanged_inodes)?;
+        }
+        write!(f, "metadata bytes: {}", self.metadata_bytes)
+    }
+}
+
+fn walker(rootfs: &Path, follow_links: bool) -> WalkDir {
+    // breadth first search for sharing, order by file name. we only return directories here, so
+    // we can more easily do delta generation to detect what's missing in an existing puzzlefs.
     WalkDir::new(rootfs)
         .contents_first(false)
-        .follow_links(false)
-        .same_file_system(true)
+        .follow_links(follow_links)
         .sort_by(|a, b| a.file_name().cmp(b.file_name()))
 }
 
-// a struct to hold a directory's information before it can be rendered into a InodeSpecific::Dir
-// (aka the offset is unknown because we haven't accumulated all the inodes yet)
+// the mountpoints below `root`, which must be canonical. Unlike device numbers, they also give
+// away bind mounts of directories of the same filesystem.
+fn mountpoints_below(root: &Path) -> io::Result<HashSet<PathBuf>> {
+    Ok(mountpoints()?
+        .into_iter()
+        .filter(|mountpoint| mountpoint != root && mountpoint.starts_with(root))
+        .collect())
+}
+
+// a file of the host: inode numbers are only unique within a filesystem
+type HostIno = (u64, u64);
+
+fn host_ino(md: &fs::Metadata) -> HostIno {
+    (md.dev(), md.ino())
+}
+
+// a struct to hold a directory's information before it can be rendered into an InodeMode::Dir
+// (aka until all of its entries were walked)
 struct Dir {
     ino: u64,
     dir_list: DirList,
     md: fs::Metadata,
     additional: Option<InodeAdditional>,
+    // whether the directory hides what the base layer has at its path, which overlay upper
+    // directories say, or because it has none of the base layer's entries left
+    opaque: bool,
 }
 
 impl Dir {
@@ -64,21 +331,28 @@ impl Dir {
     }
 }
 
-// similar to the above, but holding file metadata
+// a regular file going through the chunker; its inode is rendered already, without the chunks
 struct File {
     ino: u64,
-    chunk_list: FileChunkList,
-    md: fs::Metadata,
-    additional: Option<InodeAdditional>,
+    size: u64,
+    key: CacheKey,
+    chunks: Vec<FileChunk>,
+    // the files with the same contents, which get the same chunk list, see dedup_files
+    copies: Vec<(u64, CacheKey)>,
+    // the digest of the contents as they go through the chunker, with file_digests for the files
+    // which weren't hashed before
+    hasher: Option<Sha256>,
 }
 
-struct Other {
-    ino: u64,
-    md: fs::Metadata,
-    additional: Option<InodeAdditional>,
+// the sha256 of the contents of a file
+fn file_digest(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
 }
 
-fn serialize_metadata(rootfs: Rootfs) -> Result<Vec<u8>> {
+pub(crate) fn serialize_metadata(rootfs: Rootfs) -> Result<Vec<u8>> {
+    let _span = info_span!("serialize").entered();
     let mut message = ::capnp::message::Builder::new_default();
     let mut capnp_rootfs = message.init_root::<metadata_capnp::rootfs::Builder<'_>>();
 
@@ -89,95 +363,329 @@ fn serialize_metadata(rootfs: Rootfs) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+// like serialize_metadata, with the inodes of the top layer streamed out of `top` rather than
+// all loaded at once, above the layers `below`; an empty top layer is left out. `inspect` sees
+// each inode on the way
+fn serialize_spilled_metadata(
+    top: InodeSpill,
+    below: &[Vec<Inode>],
+    fs_verity_data: &VerityData,
+    mut inspect: impl FnMut(&Inode),
+) -> Result<Vec<u8>> {
+    let _span = info_span!("serialize").entered();
+    let mut message = ::capnp::message::Builder::new_default();
+    let mut capnp_rootfs = message.init_root::<metadata_capnp::rootfs::Builder<'_>>();
+    capnp_rootfs.set_manifest_version(PUZZLEFS_IMAGE_MANIFEST_VERSION);
+
+    let top_layers = usize::from(!top.is_empty());
+    let mut capnp_metadatas = capnp_rootfs
+        .reborrow()
+        .init_metadatas((top_layers + below.len()).try_into()?);
+    if top_layers > 0 {
+        let mut capnp_inodes = capnp_metadatas
+            .reborrow()
+            .get(0)
+            .init_inodes(top.len().try_into()?);
+        let mut i = 0;
+        top.into_each(|inode| {
+            inspect(&inode);
+            // we already checked that the number of inodes fits inside a u32
+            inode.fill_capnp(&mut capnp_inodes.reborrow().get(i))?;
+            i += 1;
+            Ok(())
+        })?;
+    }
+    for (i, layer) in below.iter().enumerate() {
+        layer.iter().for_each(&mut inspect);
+        // we already checked that the number of layers fits inside a u32
+        let mut capnp_metadata = capnp_metadatas.reborrow().get((top_layers + i) as u32);
+        InodeVector::fill_capnp(layer, &mut capnp_metadata)?;
+    }
+    Rootfs::fill_verity_capnp(fs_verity_data, &mut capnp_rootfs)?;
+
+    let mut buf = Vec::new();
+    ::capnp::serialize::write_message(&mut buf, &message)?;
+    Ok(buf)
+}
+
+// the small chunks waiting to be written together in a pack blob, along with the (file, chunk)
+// indices of the FileChunks which point into it
+#[derive(Default)]
+struct Pack {
+    data: Vec<u8>,
+    chunk_count: u64,
+    refs: Vec<(usize, usize)>,
+}
+
+// writes a blob (a chunk or a pack of chunks) and returns its digest and whether it's compressed
+fn write_chunk_blob<C: Compression + Any>(
+    oci: &Image,
+    data: &[u8],
+    verity_data: &mut VerityData,
+    image_manifest: &mut ImageManifest,
+    pool: Option<&ChunkPool>,
+    config: &BuilderConfig,
+    stats: &mut BuildStats,
+) -> Result<([u8; 32], bool)> {
+    let _span = debug_span!("compress", size = data.len()).entered();
+    let blob = if config.dry_run {
+        // the chunks of the build aren't written, so the ones it already made are in verity_data
+        let blob = oci.prepare_blob_with::<C>(
+            data,
+            &media_types::Chunk {},
+            config.verity_hash,
+            config.encryption.as_ref(),
+        )?;
+        let digest = Digest::try_from(blob.descriptor.digest().digest())?;
+        let existing = blob.existing
+            || verity_data.contains_key(&digest.underlying())
+            || pool.is_some_and(|pool| pool.contains(blob.descriptor.digest().digest()));
+        StoredBlob {
+            descriptor: blob.descriptor,
+            fs_verity_digest: blob.fs_verity_digest,
+            compressed: blob.compressed,
+            existing,
+        }
+    } else {
+        let mut blob = oci.put_blob_with::<C>(
+            data,
+            image_manifest,
+            media_types::Chunk {},
+            config.verity_hash,
+            config.encryption.as_ref(),
+        )?;
+        if let Some(pool) = pool.filter(|_| !blob.existing) {
+            blob.existing = pool.share(oci, blob.descriptor.digest().digest())?;
+        }
+        blob
+    };
+    let digest = Digest::try_from(blob.descriptor.digest().digest())?.underlying();
+    stats.add_chunk(data.len() as u64, blob.descriptor.size(), blob.existing);
+
+    verity_data.insert(digest, blob.fs_verity_digest);
+    Ok((digest, blob.compressed))
+}
+
+// the chunker of the files of `stream`, restarted at each of them with `anchor_files` and at the
+// large ones with `parallel_chunking_above`
+fn stream_chunker(config: &BuilderConfig, stream: FilesystemStream) -> Box<dyn Chunker> {
+    let chunker = config.chunker.unwrap_or_default();
+    if !config.anchor_files && config.parallel_chunking_above.is_none() {
+        return chunker.chunker(Box::new(stream));
+    }
+    let parallel = |size| {
+        config
+            .parallel_chunking_above
+            .is_some_and(|above| size > above)
+    };
+    let sources =
+        stream.split(|size| (config.anchor_files || parallel(size)).then_some(parallel(size)));
+    let threads = match config.chunking_threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        threads => threads,
+    };
+    chunker.anchored_chunker(sources, threads)
+}
+
+/// Splits the contents of `files`, given along with their sizes, into chunks the way builds with
+/// `config` do (its chunker, `anchor_files` and `parallel_chunking_above`), and returns the length
+/// of each chunk; nothing is compressed or written. Unless anchored, chunks span the ends of
+/// files, so the order of the files matters. Only public for the benchmarks.
+#[doc(hidden)]
+pub fn chunk_lengths(files: &[(PathBuf, u64)], config: &BuilderConfig) -> Result<Vec<usize>> {
+    let mut fs_stream = FilesystemStream::new(false);
+    for (file, size) in files {
+        fs_stream.push(file, *size);
+    }
+    let mut chunker = stream_chunker(config, fs_stream);
+    let mut lengths = Vec::new();
+    while let Some(chunk) = chunker.next_chunk()? {
+        lengths.push(chunk.len());
+    }
+    Ok(lengths)
+}
+
+// chunks `files`, handing each of them to `done` once its chunk list is complete
+#[allow(clippy::too_many_arguments)]
 fn process_chunks<C: Compression + Any>(
     oci: &Image,
-    mut chunker: StreamCDC,
+    mut chunker: Box<dyn Chunker>,
     files: &mut [File],
     verity_data: &mut VerityData,
     image_manifest: &mut ImageManifest,
+    pool: Option<&ChunkPool>,
+    config: &BuilderConfig,
+    stats: &mut BuildStats,
+    mut done: impl FnMut(&mut File, &VerityData) -> Result<()>,
 ) -> Result<()> {
-    let mut file_iter = files.iter_mut();
+    let _span = info_span!("chunk", files = files.len()).entered();
+    let next_file = |files: &[File], from: usize| (from..files.len()).find(|&i| files[i].size > 0);
     let mut file_used = 0;
-    let mut file = None;
-    for f in file_iter.by_ref() {
-        if f.md.size() > 0 {
-            file = Some(f);
-            break;
+    let mut file = next_file(files, 0);
+    let mut pack = Pack::default();
+
+    let flush_pack = |pack: &mut Pack,
+                      files: &mut [File],
+                      verity_data: &mut VerityData,
+                      image_manifest: &mut ImageManifest,
+                      stats: &mut BuildStats|
+     -> Result<()> {
+        if pack.refs.is_empty() {
+            return Ok(());
         }
-    }
+        let (digest, compressed) = write_chunk_blob::<C>(
+            oci,
+            &pack.data,
+            verity_data,
+            image_manifest,
+            pool,
+            config,
+            stats,
+        )?;
+        stats.packed_chunks += pack.chunk_count;
+        stats.packs += 1;
+        for (f, c) in pack.refs.drain(..) {
+            let blob = &mut files[f].chunks[c].blob;
+            blob.digest = digest;
+            blob.compressed = compressed;
+        }
+        pack.data.clear();
+        pack.chunk_count = 0;
+        Ok(())
+    };
 
-    'outer: for result in &mut chunker {
-        let chunk = result.unwrap();
-        let mut chunk_used: u64 = 0;
+    // the files before `upto` are done, unless some of their chunks still wait for their pack
+    let mut retired = 0;
+    let mut retire = |files: &mut [File], pack: &Pack, upto: usize, verity_data: &VerityData| {
+        let upto = pack.refs.first().map_or(upto, |&(f, _)| min(f, upto));
+        while retired < upto {
+            done(&mut files[retired], verity_data)?;
+            retired += 1;
+        }
+        Ok::<(), WireFormatError>(())
+    };
 
-        let (desc, fs_verity_digest, compressed) =
-            oci.put_blob::<C>(&chunk.data, image_manifest, media_types::Chunk {})?;
-        let digest = Digest::try_from(desc.digest().digest())?.underlying();
+    'outer: while let Some(chunk) = chunker.next_chunk()? {
+        let mut chunk_used: u64 = 0;
 
-        let verity_hash = fs_verity_digest;
-        verity_data.insert(digest, verity_hash);
+        // small chunks are appended to the current pack, whose digest isn't known until it's
+        // written: their FileChunks are fixed up then
+        let packed = config
+            .pack_chunks_below
+            .is_some_and(|below| chunk.len() < below as usize);
+        let (digest, compressed, pack_offset) = if packed {
+            let pack_offset = pack.data.len() as u64;
+            pack.data.extend_from_slice(&chunk);
+            pack.chunk_count += 1;
+            ([0; 32], false, pack_offset)
+        } else {
+            let (digest, compressed) = write_chunk_blob::<C>(
+                oci,
+                &chunk,
+                verity_data,
+                image_manifest,
+                pool,
+                config,
+                stats,
+            )?;
+            (digest, compressed, 0)
+        };
 
-        while chunk_used < chunk.length as u64 {
-            let room = min(
-                file.as_ref().unwrap().md.len() - file_used,
-                chunk.length as u64 - chunk_used,
-            );
+        while chunk_used < chunk.len() as u64 {
+            // .unwrap() here because the chunker doesn't produce more data than the files have
+            let f = file.unwrap();
+            let room = min(files[f].size - file_used, chunk.len() as u64 - chunk_used);
 
             let blob = BlobRef {
-                offset: chunk_used,
+                offset: pack_offset + chunk_used,
                 digest,
                 compressed,
             };
 
-            file.as_mut()
-                .unwrap()
-                .chunk_list
-                .chunks
-                .push(FileChunk { blob, len: room });
+            let chunks = &mut files[f].chunks;
+            if packed {
+                pack.refs.push((f, chunks.len()));
+            }
+            chunks.push(FileChunk { blob, len: room });
+            if let Some(hasher) = &mut files[f].hasher {
+                hasher.update(&chunk[chunk_used as usize..(chunk_used + room) as usize]);
+            }
 
             chunk_used += room;
             file_used += room;
 
             // get next file
-            if file_used == file.as_ref().unwrap().md.len() {
+            if file_used == files[f].size {
                 file_used = 0;
-                file = None;
-
-                for f in file_iter.by_ref() {
-                    if f.md.size() > 0 {
-                        file = Some(f);
-                        break;
-                    }
-                }
+                file = next_file(files, f + 1);
 
                 if file.is_none() {
                     break 'outer;
                 }
             }
         }
+
+        if pack.data.len() >= PACK_SIZE as usize {
+            flush_pack(&mut pack, files, verity_data, image_manifest, stats)?;
+        }
+        retire(files, &pack, file.unwrap_or(files.len()), verity_data)?;
     }
+    flush_pack(&mut pack, files, verity_data, image_manifest, stats)?;
+    retire(files, &pack, files.len(), verity_data)?;
 
     // If there are no files left we also expect there are no chunks left
-    assert!(chunker.next().is_none());
+    assert!(chunker.next_chunk()?.is_none());
 
     Ok(())
 }
 
+// attributes the failure of `op` on the file of the rootfs at `path` to it
+fn source_error<'a, E: Into<WireFormatError>>(
+    op: &'static str,
+    path: &'a Path,
+) -> impl FnOnce(E) -> WireFormatError + 'a {
+    move |e| match e.into() {
+        WireFormatError::IOError(source, _) => BuildError::Io {
+            op,
+            path: path.to_path_buf(),
+            source,
+        }
+        .into(),
+        e => e,
+    }
+}
+
 fn build_delta<C: Compression + Any>(
     rootfs: &Path,
     oci: &Image,
     mut existing: Option<PuzzleFS>,
     verity_data: &mut VerityData,
     image_manifest: &mut ImageManifest,
-) -> Result<Vec<Inode>> {
-    let mut dirs = HashMap::<u64, Dir>::new();
+    config: &BuilderConfig,
+    stats: &mut BuildStats,
+) -> Result<InodeSpill> {
+    let mut dirs = HashMap::<HostIno, Dir>::new();
     let mut files = Vec::<File>::new();
-    let mut others = Vec::<Other>::new();
-    let mut pfs_inodes = Vec::<Inode>::new();
-    let mut fs_stream = FilesystemStream::new();
+    // the files to chunk by the digest of their contents, with dedup_files
+    let mut file_digests = HashMap::<[u8; 32], usize>::new();
+    // the chunk lists of the cache may point to blobs stored in clear
+    let mut build_cache = config
+        .build_cache
+        .as_deref()
+        .filter(|_| config.encryption.is_none())
+        .map(BuildCache::open)
+        .transpose()?;
+    let pool = config
+        .chunk_pool
+        .as_deref()
+        .map(ChunkPool::open)
+        .transpose()?;
+    // every inode is rendered as soon as it's complete
+    let mut pfs_inodes = InodeSpill::new(config.memory_budget);
+    let mut fs_stream = FilesystemStream::new(config.ignore_changing_files);
 
     // host to puzzlefs inode mapping for hard link deteciton
-    let mut host_to_pfs = HashMap::<u64, Ino>::new();
+    let mut host_to_pfs = HashMap::<HostIno, Ino>::new();
 
     let mut next_ino: u64 = existing
         .as_mut()
@@ -192,35 +700,91 @@ fn build_delta<C: Compression + Any>(
             .map(|o| o.flatten())
     }
 
-    let rootfs_dirs = walker(rootfs)
+    // the filesystems mounted below the rootfs, e.g. /proc or a separate /var, are left out
+    // unless asked for: their mountpoints are stored as empty directories
+    let canonical_root = fs::canonicalize(rootfs)?;
+    let mountpoints = match config.cross_filesystems {
+        true => HashSet::new(),
+        false => mountpoints_below(&canonical_root)?,
+    };
+    let bind_mounted = |path: &Path| {
+        path.strip_prefix(rootfs)
+            .is_ok_and(|relative| mountpoints.contains(&canonical_root.join(relative)))
+    };
+    let mut skipped_mountpoints = Vec::new();
+
+    // the walker itself doesn't leave the filesystem of the rootfs
+    let rootfs_relative = |p: &Path| {
+        // .unwrap() here because we assume no programmer errors in this function (i.e. it is a
+        // puzzlefs bug here)
+        Path::new("/").join(p.strip_prefix(rootfs).unwrap())
+    };
+    let follows =
+        |path: &Path| path.is_symlink() && config.symlinks.follows(&rootfs_relative(path));
+
+    // a directory on another filesystem than its parent's is a mountpoint, unless it's the
+    // target of a symlink which is followed, wherever that is
+    let same_file_system = |de: &walkdir::DirEntry| {
+        config.cross_filesystems
+            || de.depth() == 0
+            || de.path_is_symlink()
+            || de.path().parent().is_some_and(|parent| {
+                match (de.metadata(), fs::metadata(parent)) {
+                    (Ok(md), Ok(parent)) => md.dev() == parent.dev(),
+                    // the walk reports the error
+                    _ => true,
+                }
+            })
+    };
+
+    // the walker itself doesn't leave the filesystem of the rootfs. it only follows symlinks if
+    // some are to be followed, and then only walks the ones which are
+    let rootfs_dirs = walker(rootfs, !config.symlinks.follow.is_empty())
         .into_iter()
-        .filter_entry(|de| de.metadata().map(|md| md.is_dir()).unwrap_or(true));
+        .filter_entry(|de| {
+            de.metadata().map(|md| md.is_dir()).unwrap_or(true)
+                && !bind_mounted(de.path())
+                && (!de.path_is_symlink() || de.depth() == 0 || follows(de.path()))
+                && same_file_system(de)
+        });
 
     // we specially create the "/" InodeMode::Dir object, since we will not iterate over it as a
-    // child of some other directory
-    let root_metadata = fs::symlink_metadata(rootfs)?;
-    let root_additional = InodeAdditional::new(rootfs, &root_metadata)?;
+    // child of some other directory. directories are looked up by their metadata, which is the
+    // one of their target for the symlinks to directories which are followed
+    let root_metadata = fs::metadata(rootfs).map_err(source_error("stat", rootfs))?;
+    let root_additional = InodeAdditional::new(rootfs, &root_metadata, &config.xattr_filter)
+        .map_err(source_error("read the attributes of", rootfs))?;
     dirs.insert(
-        root_metadata.ino(),
+        host_ino(&root_metadata),
         Dir {
             ino: 1,
             md: root_metadata,
             dir_list: DirList {
                 entries: Vec::<DirEnt>::new(),
                 look_below: false,
+                opaque: false,
             },
             additional: root_additional,
+            opaque: false,
         },
     );
 
-    let rootfs_relative = |p: &Path| {
-        // .unwrap() here because we assume no programmer errors in this function (i.e. it is a
-        // puzzlefs bug here)
-        Path::new("/").join(p.strip_prefix(rootfs).unwrap())
-    };
-
+    let walk_span = info_span!("walk", rootfs = %rootfs.display()).entered();
     for dir in rootfs_dirs {
-        let d = dir.map_err(io::Error::from)?;
+        let d = match dir {
+            Ok(d) => d,
+            // following symlinks, the walker trips on the dangling ones it doesn't need to follow
+            Err(e)
+                if e.path()
+                    .is_some_and(|path| path.is_symlink() && !follows(path)) =>
+            {
+                continue
+            }
+            Err(e) => {
+                let path = e.path().unwrap_or(rootfs).to_path_buf();
+                return Err(source_error("walk", &path)(io::Error::from(e)));
+            }
+        };
         let dir_path = rootfs_relative(d.path());
         let existing_dirents: Vec<_> = lookup_existing(&mut existing, &dir_path)?
             .and_then(|ex| -> Option<Vec<_>> {
@@ -232,27 +796,77 @@ fn build_delta<C: Compression + Any>(
             })
             .unwrap_or_default();
 
-        let mut new_dirents = fs::read_dir(d.path())?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+        let mut new_dirents = fs::read_dir(d.path())
+            .and_then(|entries| entries.collect::<io::Result<Vec<fs::DirEntry>>>())
+            .map_err(source_error("list", d.path()))?;
         // sort the entries so we have reproducible puzzlefs images
         new_dirents.sort_by_key(|a| a.file_name());
 
-        // add whiteout information
-        let this_metadata = fs::symlink_metadata(d.path())?;
+        let this_metadata = fs::metadata(d.path()).map_err(source_error("stat", d.path()))?;
         let this_dir = dirs
-            .get_mut(&this_metadata.ino())
+            .get_mut(&host_ino(&this_metadata))
             .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
-        for dir_ent in existing_dirents {
-            if !(new_dirents).iter().any(|new| {
-                new.path().file_name().unwrap_or_else(|| OsStr::new(""))
-                    == OsStr::from_bytes(&dir_ent.name)
-            }) {
-                pfs_inodes.push(Inode::new_whiteout(dir_ent.ino));
+
+        // in an overlay upper directory, whiteouts delete entries of the base layer
+        let mut removed = HashSet::new();
+        if config.overlay_upper {
+            this_dir.opaque |= overlay::is_opaque(d.path());
+            let mut kept = Vec::with_capacity(new_dirents.len());
+            for e in new_dirents {
+                let path = e.path();
+                let md = e.metadata().map_err(source_error("stat", &path))?;
+                match overlay::upper_entry(&e, &md)
+                    .map_err(source_error("read the attributes of", &path))?
+                {
+                    UpperEntry::File => kept.push(e),
+                    UpperEntry::Whiteout(name) => {
+                        removed.insert(name);
+                    }
+                    UpperEntry::OpaqueMarker => this_dir.opaque = true,
+                }
+            }
+            new_dirents = kept;
+        }
+
+        // the entries of the base layer which are gone
+        let is_new = |name: &OsStr| {
+            new_dirents
+                .iter()
+                .any(|new| new.path().file_name().unwrap_or_else(|| OsStr::new("")) == name)
+        };
+        let (gone, left): (Vec<_>, Vec<_>) = existing_dirents
+            .into_iter()
+            .partition(|dir_ent| !is_new(OsStr::from_bytes(&dir_ent.name)));
+        // an overlay keeps the entries of the base layer it has no whiteout for
+        let overlay_opaque = this_dir.opaque;
+        let kept = |dir_ent: &DirEnt| {
+            let name = OsStr::from_bytes(&dir_ent.name);
+            config.overlay_upper && !overlay_opaque && !removed.contains(name)
+        };
+        // a directory none of whose entries are left, e.g. deleted and created again, is opaque
+        // rather than whiting them all out
+        if left.is_empty() && !gone.is_empty() && !gone.iter().any(kept) {
+            this_dir.opaque = true;
+        }
+        let opaque = this_dir.opaque;
+
+        // add whiteout information
+        for dir_ent in gone {
+            if kept(&dir_ent) {
                 this_dir.add_entry(OsString::from_vec(dir_ent.name), dir_ent.ino);
+            } else if !opaque {
+                pfs_inodes.push(Inode::new_whiteout(dir_ent.ino))?;
             }
         }
 
         for e in new_dirents {
-            let md = e.metadata()?;
+            // a symlink which is followed is stored as the file it points to
+            let followed = follows(&e.path());
+            let source = match followed {
+                true => fs::canonicalize(e.path()).map_err(source_error("follow", &e.path()))?,
+                false => e.path(),
+            };
+            let md = fs::symlink_metadata(&source).map_err(source_error("stat", &source))?;
 
             let existing_inode = existing
                 .as_mut()
@@ -273,22 +887,26 @@ fn build_delta<C: Compression + Any>(
             // this is not "/" for our image, aka inode #1)
             if cur_ino != 1 {
                 // is this a hard link? if so, just use the existing ino we have rendered. otherewise,
-                // use a new one
-                let the_ino = host_to_pfs.get(&md.ino()).copied().unwrap_or(cur_ino);
+                // use a new one. directories can't be hard links, only bind mounted
+                let hard_link = host_to_pfs
+                    .get(&host_ino(&md))
+                    .copied()
+                    .filter(|_| !md.is_dir());
+                let the_ino = hard_link.unwrap_or(cur_ino);
                 let parent_path = e.path().parent().map(|p| p.to_path_buf()).ok_or_else(|| {
                     io::Error::new(
                         io::ErrorKind::Other,
                         format!("no parent for {}", e.path().display()),
                     )
                 })?;
-                let parent = dirs
-                    .get_mut(&fs::symlink_metadata(parent_path)?.ino())
-                    .ok_or_else(|| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("no pfs inode for {}", e.path().display()),
-                        )
-                    })?;
+                let parent_md =
+                    fs::metadata(&parent_path).map_err(source_error("stat", &parent_path))?;
+                let parent = dirs.get_mut(&host_ino(&parent_md)).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("no pfs inode for {}", e.path().display()),
+                    )
+                })?;
                 parent.add_entry(
                     e.path()
                         .file_name()
@@ -298,127 +916,446 @@ fn build_delta<C: Compression + Any>(
                 );
 
                 // if it was a hard link, we don't need to actually render it again
-                if host_to_pfs.contains_key(&md.ino()) {
+                if hard_link.is_some() {
                     continue;
                 }
             }
 
-            host_to_pfs.insert(md.ino(), cur_ino);
+            host_to_pfs.insert(host_ino(&md), cur_ino);
+            let mode = forced_mode(&config.force_modes, &rootfs_relative(&e.path()));
 
-            // render as much of the inode as we can
-            // TODO: here are a bunch of optimizations we should do: no need to re-render things
-            // that are the same (whole inodes, metadata, etc.). For now we just re-render the
-            // whole metadata tree.
-            let additional = InodeAdditional::new(&e.path(), &md)?;
+            // render as much of the inode as we can. the whole tree is rendered, the inodes of a
+            // delta which didn't change are only left out of its layer afterwards
+            let mut additional = InodeAdditional::new(&source, &md, &config.xattr_filter)
+                .map_err(source_error("read the attributes of", &source))?;
+            if let Some(target) = additional
+                .as_mut()
+                .and_then(|additional| additional.symlink_target.as_mut())
+            {
+                let path = rootfs_relative(&e.path());
+                *target = config
+                    .symlinks
+                    .target(&path, target)
+                    .ok_or_else(|| BuildError::EscapingSymlink { path: path.clone() })?;
+            }
 
-            if md.is_dir() {
+            if !config.cross_filesystems
+                && md.is_dir()
+                && !followed
+                && (md.dev() != this_metadata.dev() || bind_mounted(&e.path()))
+            {
+                skipped_mountpoints.push(rootfs_relative(&e.path()));
+                let dir_list = DirList {
+                    entries: Vec::new(),
+                    look_below: false,
+                    opaque: false,
+                };
+                let inode = Inode::new_dir(cur_ino, &md, dir_list, additional)?;
+                pfs_inodes.push(finish_inode(inode, config, mode))?;
+            } else if md.is_dir() {
                 dirs.insert(
-                    md.ino(),
+                    host_ino(&md),
                     Dir {
                         ino: cur_ino,
                         md,
                         dir_list: DirList {
                             entries: Vec::<DirEnt>::new(),
                             look_below: false,
+                            opaque: false,
                         },
                         additional,
+                        // overlays don't merge what's below an opaque directory either, nor
+                        // below a directory deleted and created again
+                        opaque: opaque
+                            || e.path()
+                                .file_name()
+                                .is_some_and(|name| removed.contains(name)),
                     },
                 );
+            } else if md.is_file()
+                && config
+                    .inline_files_below
+                    .is_some_and(|below| md.size() > 0 && md.size() < below)
+            {
+                let data = fs::read(e.path()).map_err(source_error("read", &e.path()))?;
+                if config.file_digests {
+                    additional.get_or_insert_with(Default::default).digest =
+                        Some(Sha256::digest(&data).into());
+                }
+                stats.inlined_files += 1;
+                let inode = Inode::new_inline_file(cur_ino, &md, data, additional)?;
+                pfs_inodes.push(finish_inode(inode, config, mode))?;
             } else if md.is_file() {
-                fs_stream.push(&e.path());
-
-                let file = File {
-                    ino: cur_ino,
-                    md,
-                    chunk_list: FileChunkList {
-                        chunks: Vec::<FileChunk>::new(),
-                    },
-                    additional,
+                // the files which are deduplicated are hashed first, to find their copies
+                let digest = (config.dedup_files && md.size() > 0)
+                    .then(|| file_digest(&e.path()))
+                    .transpose()
+                    .map_err(source_error("read", &e.path()))?;
+                let key = CacheKey::new(&md, config.chunker.unwrap_or_default());
+                let cache_hit = match &build_cache {
+                    Some(cache) if md.size() > 0 => cache.lookup(&key, oci)?,
+                    _ => None,
                 };
+                // the others as they go through the chunker, or now if it's skipped for them
+                let hashed_later = config.file_digests && digest.is_none() && cache_hit.is_none();
+                if config.file_digests && !hashed_later {
+                    let digest = match digest {
+                        Some(digest) => digest,
+                        None => file_digest(&e.path()).map_err(source_error("read", &e.path()))?,
+                    };
+                    additional.get_or_insert_with(Default::default).digest = Some(digest);
+                }
 
-                files.push(file);
-            } else {
-                let o = Other {
-                    ino: cur_ino,
-                    md,
-                    additional,
+                // files whose chunk list was found in the build cache don't go through the
+                // chunker
+                let chunks = if let Some(hit) = cache_hit {
+                    for chunk in &hit.chunks {
+                        let digest = Digest::new(&chunk.blob.digest);
+                        if !verity_data.contains_key(&chunk.blob.digest) {
+                            // with the media type it was written with, whatever the compression
+                            // of this build
+                            let media_type = &hit.media_types[&chunk.blob.digest];
+                            oci.reference_blob_as(
+                                &digest,
+                                image_manifest,
+                                MediaType::Other(media_type.clone()),
+                            )?;
+                        }
+                    }
+                    stats.cached_files += 1;
+                    if let Some(cache) = &mut build_cache {
+                        cache.insert_hit(key, &hit);
+                    }
+                    verity_data.extend(hit.verity_data);
+                    hit.chunks
+                } else {
+                    let digest = digest.filter(|_| config.dedup_files && md.size() > 0);
+                    match digest.and_then(|digest| file_digests.get(&digest)) {
+                        // the chunk list is filled in once the first copy is chunked
+                        Some(&first) => {
+                            files[first].copies.push((cur_ino, key));
+                            stats.duplicate_files += 1;
+                        }
+                        None => {
+                            if let Some(digest) = digest {
+                                file_digests.insert(digest, files.len());
+                            }
+                            fs_stream.push(&e.path(), md.size());
+                            files.push(File {
+                                ino: cur_ino,
+                                size: md.size(),
+                                key,
+                                chunks: Vec::new(),
+                                copies: Vec::new(),
+                                hasher: hashed_later.then(Sha256::new),
+                            });
+                        }
+                    }
+                    Vec::new()
                 };
-                others.push(o);
+                let inode = Inode::new_file(cur_ino, &md, chunks, additional)?;
+                pfs_inodes.push(finish_inode(inode, config, mode))?;
+            } else {
+                let inode = Inode::new_other(cur_ino, &md, additional)?;
+                pfs_inodes.push(finish_inode(inode, config, mode))?;
             }
         }
+
+        // all the entries of the directory are known now
+        if let Some(mut d) = dirs.remove(&host_ino(&this_metadata)) {
+            // whiteouts and the entries kept from the base layer were added first
+            d.dir_list.entries.sort_by(|a, b| a.name.cmp(&b.name));
+            d.dir_list.opaque = d.opaque;
+            let mode = forced_mode(&config.force_modes, &dir_path);
+            let inode = Inode::new_dir(d.ino, &d.md, d.dir_list, d.additional)?;
+            pfs_inodes.push(finish_inode(inode, config, mode))?;
+        }
     }
 
-    let fcdc = StreamCDC::new(
-        Box::new(fs_stream),
-        MIN_CHUNK_SIZE,
-        AVG_CHUNK_SIZE,
-        MAX_CHUNK_SIZE,
-    );
-    process_chunks::<C>(oci, fcdc, &mut files, verity_data, image_manifest)?;
+    drop(walk_span);
 
-    // TODO: not render this whole thing in memory, stick it all in the same blob, etc.
-    let mut sorted_dirs = dirs.into_values().collect::<Vec<_>>();
+    if !skipped_mountpoints.is_empty() {
+        let list: Vec<_> = skipped_mountpoints
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        warn!(
+            "left out the filesystems mounted below the rootfs, at {}",
+            list.join(", ")
+        );
+    }
 
-    // render dirs
-    pfs_inodes.extend(
-        sorted_dirs
-            .drain(..)
-            .map(|d| Ok(Inode::new_dir(d.ino, &d.md, d.dir_list, d.additional)?))
-            .collect::<Result<Vec<Inode>>>()?,
-    );
+    // the chunks written by the build, which the cache records for the next ones
+    let chunk_media_type = C::append_extension(media_types::Chunk {}.name());
+    process_chunks::<C>(
+        oci,
+        stream_chunker(config, fs_stream),
+        &mut files,
+        verity_data,
+        image_manifest,
+        pool.as_ref(),
+        config,
+        stats,
+        |file, verity_data| {
+            if let Some(cache) = &mut build_cache {
+                if file.size > 0 {
+                    cache.insert(file.key, &file.chunks, verity_data, &chunk_media_type);
+                    for (_, key) in &file.copies {
+                        cache.insert(*key, &file.chunks, verity_data, &chunk_media_type);
+                    }
+                }
+            }
+            for (ino, _) in std::mem::take(&mut file.copies) {
+                pfs_inodes.push_chunks(ino, file.chunks.clone(), None)?;
+            }
+            let digest = file.hasher.take().map(|hasher| hasher.finalize().into());
+            pfs_inodes.push_chunks(file.ino, std::mem::take(&mut file.chunks), digest)
+        },
+    )?;
 
-    // render files
-    pfs_inodes.extend(
-        files
-            .drain(..)
-            .map(|f| {
-                Ok(Inode::new_file(
-                    f.ino,
-                    &f.md,
-                    f.chunk_list.chunks,
-                    f.additional,
-                )?)
-            })
-            .collect::<Result<Vec<Inode>>>()?,
-    );
+    if let Some(cache) = build_cache.filter(|_| !config.dry_run) {
+        cache.save()?;
+    }
+
+    Ok(pfs_inodes)
+}
 
-    pfs_inodes.extend(
-        others
-            .drain(..)
-            .map(|o| Ok(Inode::new_other(o.ino, &o.md, o.additional)?))
-            .collect::<Result<Vec<Inode>>>()?,
+// applies the ownership and mode policies of `config` to a freshly rendered inode, `forced_mode`
+// being the mode forced for its path if any
+fn finish_inode(mut inode: Inode, config: &BuilderConfig, forced_mode: Option<u16>) -> Inode {
+    (inode.uid, inode.gid) = match config.owner {
+        Some(owner) => owner,
+        None => (
+            config.uid_map.map_back(inode.uid),
+            config.gid_map.map_back(inode.gid),
+        ),
+    };
+    if config.clear_setuid && !matches!(inode.mode, InodeMode::Dir { .. }) {
+        inode.permissions &= !SETID_BITS;
+    }
+    if let Some(mode) = forced_mode {
+        inode.permissions = mode;
+    }
+    inode
+}
+
+// writes the image config of the build and makes the manifest reference it
+fn write_image_config(
+    oci: &Image,
+    image_manifest: &mut ImageManifest,
+    image_config: &ImageConfiguration,
+) -> Result<()> {
+    let mut image_config = image_config.clone();
+    let platform = oci.platform();
+    image_config.set_os(platform.os().clone());
+    image_config.set_architecture(platform.architecture().clone());
+    image_config.set_variant(platform.variant().clone());
+    let mut history = image_config.history().clone().unwrap_or_default();
+    history.push(
+        HistoryBuilder::default()
+            .created_by(format!("puzzlefs build {}", env!("CARGO_PKG_VERSION")))
+            .build()?,
     );
+    image_config.set_history(Some(history));
 
-    pfs_inodes.sort_by(|a, b| a.ino.cmp(&b.ino));
+    let desc = oci.write_blob(&serde_json::to_vec(&image_config)?, MediaType::ImageConfig)?;
+    image_manifest.set_config(desc);
+    Ok(())
+}
 
-    Ok(pfs_inodes)
+fn build_time() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        })
+}
+
+fn annotate_manifest(
+    image_manifest: &mut ImageManifest,
+    config: &BuilderConfig,
+    require_verity: bool,
+) {
+    let mut annotations = image_manifest.annotations().clone().unwrap_or_default();
+    if require_verity {
+        annotations.insert(
+            media_types::REQUIRE_VERITY_ANNOTATION.to_string(),
+            "true".to_string(),
+        );
+    }
+    if config.stamp {
+        annotations.insert(ANNOTATION_CREATED.to_string(), rfc3339(build_time()));
+        annotations.insert(
+            media_types::VERSION_ANNOTATION.to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        );
+    }
+    if config.stamp || config.chunker.is_some() {
+        annotations.insert(
+            media_types::CHUNKER_ANNOTATION.to_string(),
+            config.chunker.unwrap_or_default().to_string(),
+        );
+    }
+    if config.anchor_files {
+        annotations.insert(
+            media_types::CHUNK_ANCHOR_ANNOTATION.to_string(),
+            "files".to_string(),
+        );
+    }
+    if let Some(above) = config.parallel_chunking_above {
+        annotations.insert(
+            media_types::PARALLEL_CHUNKING_ANNOTATION.to_string(),
+            above.to_string(),
+        );
+    }
+    annotations.extend(config.annotations.clone());
+    if !annotations.is_empty() {
+        image_manifest.set_annotations(Some(annotations));
+    }
+}
+
+// records whether the chunks of `inode` are compressed, by digest
+fn chunk_compression(compressed: &mut HashMap<String, bool>, inode: &Inode) {
+    if let InodeMode::File { chunks, .. } = &inode.mode {
+        for chunk in chunks {
+            let digest = Digest::new(&chunk.blob.digest).to_string();
+            compressed.insert(digest, chunk.blob.compressed);
+        }
+    }
+}
+
+// moves the chunks of `config.landmarks` to the front of the layers of the manifest (the rootfs is
+// added before them), in order, and marks them; the ones reused from the base layer of a delta
+// are added to its manifest. `compressed` has the chunks of the image, see chunk_compression.
+fn mark_landmarks(
+    oci: &Image,
+    image_manifest: &mut ImageManifest,
+    compressed: &HashMap<String, bool>,
+    config: &BuilderConfig,
+) -> Result<()> {
+    // the new chunks of a dry run aren't there to be described
+    if config.landmarks.is_empty() || config.dry_run {
+        return Ok(());
+    }
+
+    let mut layers = image_manifest.layers().clone();
+    let mut landmarks = Vec::new();
+    let mut seen = HashSet::new();
+    for digest in &config.landmarks {
+        let Some(&is_compressed) = compressed.get(digest) else {
+            debug!("landmark {digest} isn't a chunk of the image");
+            continue;
+        };
+        if !seen.insert(digest) {
+            continue;
+        }
+        let index = layers
+            .iter()
+            .position(|desc| desc.digest().digest() == digest);
+        let mut desc = match index {
+            Some(index) => layers.remove(index),
+            None => {
+                let media_type = if is_compressed {
+                    Zstd::append_extension(media_types::PUZZLEFS_CHUNK_DATA)
+                } else {
+                    Noop::append_extension(media_types::PUZZLEFS_CHUNK_DATA)
+                };
+                Descriptor::new(
+                    MediaType::Other(media_type),
+                    oci.0.blobs_dir().metadata(digest)?.len(),
+                    OciDigest::from_str(&format!("sha256:{digest}"))?,
+                )
+            }
+        };
+        let mut annotations = desc.annotations().clone().unwrap_or_default();
+        annotations.insert(
+            media_types::LANDMARK_ANNOTATION.to_string(),
+            "true".to_string(),
+        );
+        desc.set_annotations(Some(annotations));
+        landmarks.push(desc);
+    }
+    info!("marked {} chunks as landmarks", landmarks.len());
+    landmarks.extend(layers);
+    image_manifest.set_layers(landmarks);
+    Ok(())
 }
 
 pub fn build_initial_rootfs<C: Compression + Any>(
     rootfs: &Path,
     oci: &Image,
     tag: &str,
-) -> Result<Descriptor> {
+    config: &BuilderConfig,
+) -> Result<(Descriptor, BuildStats)> {
+    let _span = info_span!("build", tag).entered();
     let mut verity_data: VerityData = BTreeMap::new();
     let mut image_manifest = oci.get_empty_manifest()?;
-    let inodes = build_delta::<C>(rootfs, oci, None, &mut verity_data, &mut image_manifest)?;
+    if let Some(image_config) = config.image_config.as_ref().filter(|_| !config.dry_run) {
+        write_image_config(oci, &mut image_manifest, image_config)?;
+    }
+    let mut stats = BuildStats::default();
+    let inodes = build_delta::<C>(
+        rootfs,
+        oci,
+        None,
+        &mut verity_data,
+        &mut image_manifest,
+        config,
+        &mut stats,
+    )?;
 
-    let rootfs_buf = serialize_metadata(Rootfs {
-        metadatas: vec![inodes],
-        fs_verity_data: verity_data,
-        manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
+    let mut compressed = HashMap::new();
+    let rootfs_buf = serialize_spilled_metadata(inodes, &[], &verity_data, |inode| {
+        if !config.landmarks.is_empty() {
+            chunk_compression(&mut compressed, inode);
+        }
     })?;
+    mark_landmarks(oci, &mut image_manifest, &compressed, config)?;
+    stats.metadata_bytes = rootfs_buf.len() as u64;
+
+    let rootfs_descriptor = write_rootfs(
+        oci,
+        image_manifest,
+        &rootfs_buf,
+        tag,
+        config,
+        config.require_verity,
+    )?;
+    Ok((rootfs_descriptor, stats))
+}
 
+// writes the rootfs of a build and tags its manifest; a dry run only returns the descriptor the
+// rootfs would have
+fn write_rootfs(
+    oci: &Image,
+    mut image_manifest: ImageManifest,
+    rootfs_buf: &[u8],
+    tag: &str,
+    config: &BuilderConfig,
+    require_verity: bool,
+) -> Result<Descriptor> {
+    if config.dry_run {
+        let blob =
+            oci.prepare_blob::<Noop>(rootfs_buf, &media_types::Rootfs {}, config.verity_hash)?;
+        return Ok(blob.descriptor);
+    }
     let rootfs_descriptor = oci
         .put_blob::<Noop>(
-            rootfs_buf.as_slice(),
+            rootfs_buf,
             &mut image_manifest,
             media_types::Rootfs {},
+            config.verity_hash,
         )?
-        .0;
-    oci.0
-        .insert_manifest(image_manifest, Some(tag), Platform::default())?;
-
+        .descriptor;
+    if config.kernel_compat {
+        rootfs_layout(oci, &rootfs_descriptor)?.check()?;
+    }
+    annotate_manifest(&mut image_manifest, config, require_verity);
+    oci.tag_manifest(image_manifest, tag)?;
     Ok(rootfs_descriptor)
 }
 
@@ -429,13 +1366,57 @@ pub fn add_rootfs_delta<C: Compression + Any>(
     oci: Image,
     tag: &str,
     base_layer: &str,
-) -> Result<(Descriptor, Arc<Image>)> {
+    config: &BuilderConfig,
+) -> Result<(Descriptor, Arc<Image>, BuildStats)> {
+    let _span = info_span!("build", tag, base_layer).entered();
     let mut verity_data: VerityData = BTreeMap::new();
     let mut image_manifest = oci.get_empty_manifest()?;
+    let mut stats = BuildStats::default();
+    // deltas of an image which requires fs-verity require it too
+    let require_verity = config.require_verity || oci.requires_verity(base_layer)?;
 
-    let pfs = PuzzleFS::open(oci, base_layer, None)?;
+    let pfs = PuzzleFS::open_base(oci, base_layer)?;
     let oci = Arc::clone(&pfs.oci);
     let mut rootfs = Rootfs::try_from(oci.open_rootfs_blob(base_layer, None)?)?;
+    let base = oci
+        .find_manifest(base_layer)?
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: base_layer.to_string(),
+        })?;
+    match &config.image_config {
+        Some(image_config) if !config.dry_run => {
+            write_image_config(&oci, &mut image_manifest, image_config)?
+        }
+        _ => image_manifest.set_config(base.config().clone()),
+    }
+    // the unchanged files are only chunked the same way with the chunker of the base layer
+    let base_annotation = |key: &str| {
+        base.annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(key))
+            .filter(|_| config.chunker.is_none())
+    };
+    let base_chunker = base_annotation(media_types::CHUNKER_ANNOTATION).and_then(|chunker| {
+        chunker
+            .parse::<ChunkerConfig>()
+            .map_err(|e| warn!("{base_layer} was built with {chunker:?}: {e}"))
+            .ok()
+    });
+    let base_anchored = base_annotation(media_types::CHUNK_ANCHOR_ANNOTATION)
+        .is_some_and(|anchor| *anchor == "files");
+    let base_parallel =
+        base_annotation(media_types::PARALLEL_CHUNKING_ANNOTATION).and_then(|above| {
+            above
+                .parse::<u64>()
+                .map_err(|e| warn!("{base_layer} was chunked in parallel above {above:?}: {e}"))
+                .ok()
+        });
+    let config = &BuilderConfig {
+        chunker: config.chunker.or(base_chunker),
+        anchor_files: config.anchor_files || base_anchored,
+        parallel_chunking_above: config.parallel_chunking_above.or(base_parallel),
+        ..config.clone()
+    };
 
     let inodes = build_delta::<C>(
         rootfs_path,
@@ -443,83 +1424,273 @@ pub fn add_rootfs_delta<C: Compression + Any>(
         Some(pfs),
         &mut verity_data,
         &mut image_manifest,
+        config,
+        &mut stats,
     )?;
 
-    if !rootfs.metadatas.iter().any(|x| *x == inodes) {
-        rootfs.metadatas.insert(0, inodes);
+    let rendered = inodes.len();
+    // the kernel driver doesn't merge directories
+    let mut changed = changed_inodes(
+        &rootfs.metadatas,
+        inodes,
+        !config.kernel_compat,
+        config.memory_budget,
+    )?;
+    stats.unchanged_inodes = (rendered - changed.len()) as u64;
+    if changed.is_empty() {
+        info!("no changes since {base_layer}, not adding a metadata layer");
     }
+    if config.squash {
+        // squashing needs all the layers at hand
+        let top = std::mem::replace(&mut changed, InodeSpill::new(config.memory_budget));
+        if !top.is_empty() {
+            rootfs.metadatas.insert(0, top.into_inodes()?);
+        }
+        if rootfs.metadatas.len() > 1 {
+            rootfs.metadatas = vec![squash_layers(std::mem::take(&mut rootfs.metadatas))?];
+        }
+    }
+
+    // the chunks shared with the base keep their digests, see BuilderConfig::verity_hash
+    for (digest, verity) in verity_data {
+        rootfs.fs_verity_data.entry(digest).or_insert(verity);
+    }
+    // the base layer may be of an older, still readable version: the delta is written with the
+    // current one
+    let mut compressed = HashMap::new();
+    let rootfs_buf = serialize_spilled_metadata(
+        changed,
+        &rootfs.metadatas,
+        &rootfs.fs_verity_data,
+        |inode| {
+            if !config.landmarks.is_empty() {
+                chunk_compression(&mut compressed, inode);
+            }
+        },
+    )?;
+    mark_landmarks(&oci, &mut image_manifest, &compressed, config)?;
+    stats.metadata_bytes = rootfs_buf.len() as u64;
+    let rootfs_descriptor = write_rootfs(
+        &oci,
+        image_manifest,
+        &rootfs_buf,
+        tag,
+        config,
+        require_verity,
+    )?;
+    Ok((rootfs_descriptor, oci, stats))
+}
+
+// the inodes of a delta which differ from the ones its base resolves their inode numbers to, the
+// `layers` of the base being topmost first. The others are left out of the delta: lookups fall
+// through to the layers below. With `look_below`, the directories the base has too only keep
+// the entries it doesn't have, and look below for the others; deleted entries are hidden by the
+// whiteouts of the delta. Opaque directories keep all their entries. The inodes are streamed
+// out of `inodes`, and the changed ones spilled again within `budget`.
+fn changed_inodes(
+    layers: &[Vec<Inode>],
+    inodes: InodeSpill,
+    look_below: bool,
+    budget: Option<u64>,
+) -> Result<InodeSpill> {
+    let mut changed = InodeSpill::new(budget);
+    inodes.into_each(|mut inode| {
+        let mut below = resolve_inode(layers, inode.ino)?;
+        // a directory which was replaced once doesn't need to be again
+        if let Some(InodeMode::Dir { dir_list }) = below.as_mut().map(|below| &mut below.mode) {
+            dir_list.opaque = false;
+        }
+        if below.as_ref() == Some(&inode) {
+            return Ok(());
+        }
+        if let (InodeMode::Dir { dir_list }, Some(InodeMode::Dir { dir_list: below })) =
+            (&mut inode.mode, below.map(|below| below.mode))
+        {
+            if look_below && !dir_list.opaque {
+                dir_list
+                    .entries
+                    .retain(|entry| !below.entries.contains(entry));
+                dir_list.look_below = true;
+            }
+        }
+        changed.push(inode)
+    })?;
+    Ok(changed)
+}
+
+// writes the manifest of `tag` again under `new_tag`, with `rootfs` instead of its rootfs and only
+// the chunks `keep_chunk` accepts. The annotations and the config of the manifest are kept.
+fn replace_rootfs(
+    oci: &Image,
+    tag: &str,
+    new_tag: &str,
+    rootfs: Rootfs,
+    keep_chunk: impl Fn(&[u8; 32]) -> bool,
+) -> Result<Descriptor> {
+    let mut image_manifest =
+        oci.find_manifest(tag)?
+            .ok_or_else(|| ImageError::MissingManifest {
+                tag: tag.to_string(),
+            })?;
+    let verity_hash = VerityHash::from_digest(&oci.get_pfs_rootfs_verity(tag)?)?;
+    let rootfs_media_type = MediaType::Other(media_types::PUZZLEFS_ROOTFS.to_string());
+    let mut layers = Vec::new();
+    for desc in image_manifest.layers() {
+        if desc.media_type() == &rootfs_media_type {
+            continue;
+        }
+        let is_chunk = matches!(desc.media_type(), MediaType::Other(media_type)
+            if media_type.starts_with(media_types::PUZZLEFS_CHUNK_DATA));
+        if is_chunk && !keep_chunk(&Digest::try_from(desc.digest().digest())?.underlying()) {
+            continue;
+        }
+        layers.push(desc.clone());
+    }
+    image_manifest.set_layers(layers);
 
-    rootfs.fs_verity_data.extend(verity_data);
     let rootfs_buf = serialize_metadata(rootfs)?;
     let rootfs_descriptor = oci
         .put_blob::<Noop>(
             rootfs_buf.as_slice(),
             &mut image_manifest,
             media_types::Rootfs {},
+            verity_hash,
         )?
-        .0;
-    oci.0
-        .insert_manifest(image_manifest, Some(tag), Platform::default())?;
-    Ok((rootfs_descriptor, oci))
+        .descriptor;
+    oci.tag_manifest(image_manifest, new_tag)?;
+    Ok(rootfs_descriptor)
+}
+
+/// Rewrites the rootfs of `tag` in the current manifest version, reusing its chunks. Returns the
+/// version it was migrated from and the descriptor of the new rootfs, or None if `tag` is already
+/// current. Since the manifest changes, its fs-verity digest changes too.
+pub fn migrate_rootfs(oci: &Image, tag: &str) -> Result<Option<(u64, Descriptor)>> {
+    let _span = info_span!("migrate", tag).entered();
+    let mut rootfs = Rootfs::try_from(oci.open_rootfs_blob(tag, None)?)?;
+    let version = rootfs.manifest_version;
+    if version == PUZZLEFS_IMAGE_MANIFEST_VERSION {
+        return Ok(None);
+    }
+    rootfs.manifest_version = PUZZLEFS_IMAGE_MANIFEST_VERSION;
+    Ok(Some((
+        version,
+        replace_rootfs(oci, tag, tag, rootfs, |_| true)?,
+    )))
 }
 
-fn enable_verity_for_file(file: &cap_std::fs::File) -> Result<()> {
-    if let Err(e) = fsverity_enable(
-        file.as_raw_fd(),
-        FS_VERITY_BLOCK_SIZE_DEFAULT,
-        InnerHashAlgorithm::Sha256,
-        &[],
-    ) {
-        // if fsverity is enabled, ignore the error
-        if e.kind() != std::io::ErrorKind::AlreadyExists {
-            return Err(WireFormatError::from(e));
+// merges metadata layers, the newest first, into one: an inode is resolved the way readers do
+// (see resolve_inode), and only the inodes reachable from the root are kept, which drops the
+// whiteouts along with what they hid
+pub(crate) fn squash_layers(metadatas: Vec<Vec<Inode>>) -> Result<Vec<Inode>> {
+    // whether the newest layer having an inode doesn't have a whiteout
+    let visible = |ino| {
+        metadatas.iter().find_map(|layer| {
+            layer
+                .binary_search_by_key(&ino, |inode| inode.ino)
+                .ok()
+                .map(|i| !matches!(layer[i].mode, InodeMode::Wht))
+        }) == Some(true)
+    };
+
+    let mut squashed = BTreeMap::new();
+    let mut todo = vec![1];
+    while let Some(ino) = todo.pop() {
+        // hard links are reached more than once
+        if squashed.contains_key(&ino) {
+            continue;
         }
+        let Some(mut inode) = resolve_inode(&metadatas[..], ino)? else {
+            continue;
+        };
+        if let InodeMode::Dir { dir_list } = &mut inode.mode {
+            // there's nothing left below to hide
+            dir_list.opaque = false;
+            dir_list.entries.retain(|entry| visible(entry.ino));
+            todo.extend(dir_list.entries.iter().map(|entry| entry.ino));
+        }
+        squashed.insert(ino, inode);
     }
-    Ok(())
+    Ok(squashed.into_values().collect())
+}
+
+/// Merges the metadata layers of `tag` into a single one, reusing its chunks. Returns the
+/// descriptor of the new rootfs, or None if `tag` only has one layer. Since the manifest changes,
+/// its fs-verity digest changes too.
+pub fn squash_deltas(oci: &Image, tag: &str) -> Result<Option<Descriptor>> {
+    let _span = info_span!("squash", tag).entered();
+    let mut rootfs = Rootfs::try_from(oci.open_rootfs_blob(tag, None)?)?;
+    if rootfs.metadatas.len() < 2 {
+        return Ok(None);
+    }
+    rootfs.metadatas = vec![squash_layers(std::mem::take(&mut rootfs.metadatas))?];
+    rootfs.manifest_version = PUZZLEFS_IMAGE_MANIFEST_VERSION;
+    Ok(Some(replace_rootfs(oci, tag, tag, rootfs, |_| true)?))
 }
 
-fn enable_and_check_verity_for_file(file: &cap_std::fs::File, expected: &[u8]) -> Result<()> {
-    enable_verity_for_file(file)?;
-    check_fs_verity(file, expected)
+/// Writes `tag` again as `new_tag`, with its metadata layers flattened into one, whiteouts
+/// resolved, and only the chunks its files still use. Unlike a build with
+/// [`BuilderConfig::squash`], this doesn't need the rootfs the image was built from.
+pub fn flatten(oci: &Image, tag: &str, new_tag: &str) -> Result<Descriptor> {
+    let _span = info_span!("flatten", tag, new_tag).entered();
+    let mut rootfs = Rootfs::try_from(oci.open_rootfs_blob(tag, None)?)?;
+    let inodes = squash_layers(std::mem::take(&mut rootfs.metadatas))?;
+    let mut used = HashSet::new();
+    for inode in &inodes {
+        if let InodeMode::File { chunks, .. } = &inode.mode {
+            used.extend(chunks.iter().map(|chunk| chunk.blob.digest));
+        }
+    }
+    rootfs.metadatas = vec![inodes];
+    rootfs
+        .fs_verity_data
+        .retain(|digest, _| used.contains(digest));
+    rootfs.manifest_version = PUZZLEFS_IMAGE_MANIFEST_VERSION;
+    replace_rootfs(oci, tag, new_tag, rootfs, |digest| used.contains(digest))
 }
 
-pub fn enable_fs_verity(oci: Image, tag: &str, manifest_root_hash: &str) -> Result<()> {
+/// Enables fs-verity for all the blobs of `tag`, signing their digests with `signer` if given.
+pub fn enable_fs_verity(
+    oci: Image,
+    tag: &str,
+    manifest_root_hash: &str,
+    signer: Option<&FsVeritySigner>,
+) -> Result<()> {
     // first enable fs verity for the puzzlefs image manifest
     let manifest_fd = oci.get_image_manifest_fd(tag)?;
-    enable_and_check_verity_for_file(&manifest_fd, &hex::decode(manifest_root_hash)?[..])?;
+    let manifest_root_hash = hex::decode(manifest_root_hash)?;
+    enable_and_check_verity_for_file(&manifest_fd, &manifest_root_hash, signer)?;
+    let hash = VerityHash::from_digest(&manifest_root_hash)?;
 
-    let pfs = PuzzleFS::open(oci, tag, None)?;
-    let oci = Arc::clone(&pfs.oci);
     let rootfs = oci.open_rootfs_blob(tag, None)?;
 
     let rootfs_fd = oci.get_pfs_rootfs(tag, None)?;
     let rootfs_verity = oci.get_pfs_rootfs_verity(tag)?;
 
-    enable_and_check_verity_for_file(&rootfs_fd, &rootfs_verity[..])?;
+    enable_and_check_verity_for_file(&rootfs_fd, &rootfs_verity[..], signer)?;
 
     let manifest = oci
-        .0
-        .find_manifest_with_tag(tag)?
-        .ok_or_else(|| WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture()))?;
+        .find_manifest(tag)?
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: tag.to_string(),
+        })?;
     let config_digest = manifest.config().digest().digest();
-    let config_digest_path = Image::blob_path().join(config_digest);
-    enable_verity_for_file(&oci.0.dir().open(config_digest_path)?)?;
+    let config_fd = oci.0.blobs_dir().open(config_digest)?;
+    match signer {
+        // the config's digest isn't recorded anywhere, but it has to be signed as well
+        Some(signer) => {
+            let config_verity =
+                get_fs_verity_digest(&oci.0.blobs_dir().read(config_digest)?, hash)?;
+            enable_and_check_verity_for_file(&config_fd, &config_verity, Some(signer))?;
+        }
+        None => enable_verity_for_file(&config_fd, hash, &[])?,
+    }
 
     for (content_addressed_file, verity_hash) in rootfs.get_verity_data()? {
-        let file_path = Image::blob_path().join(Digest::new(&content_addressed_file).to_string());
-        let fd = oci.0.dir().open(&file_path)?;
-        if let Err(e) = fsverity_enable(
-            fd.as_raw_fd(),
-            FS_VERITY_BLOCK_SIZE_DEFAULT,
-            InnerHashAlgorithm::Sha256,
-            &[],
-        ) {
-            // if fsverity is enabled, ignore the error
-            if e.kind() != std::io::ErrorKind::AlreadyExists {
-                return Err(WireFormatError::from(e));
-            }
-        }
-        check_fs_verity(&fd, &verity_hash)?;
+        let fd = oci
+            .0
+            .blobs_dir()
+            .open(Digest::new(&content_addressed_file).to_string())?;
+        enable_and_check_verity_for_file(&fd, &verity_hash, signer)?;
     }
 
     Ok(())
@@ -527,7 +1698,7 @@ pub fn enable_fs_verity(oci: Image, tag: &str, manifest_root_hash: &str) -> Resu
 
 // TODO: figure out how to guard this with #[cfg(test)]
 pub fn build_test_fs(path: &Path, image: &Image, tag: &str) -> Result<Descriptor> {
-    build_initial_rootfs::<Zstd>(path, image, tag)
+    build_initial_rootfs::<Zstd>(path, image, tag, &BuilderConfig::default()).map(|(desc, _)| desc)
 }
 
 #[cfg(test)]
@@ -536,8 +1707,12 @@ pub mod tests {
 
     use tempfile::tempdir;
 
-    use crate::reader::WalkPuzzleFS;
+    use crate::common::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+    use crate::reader::{verify_file, version_support, FileReader, VersionSupport, WalkPuzzleFS};
+    use crate::test_utils::XorShift;
     use cap_std::fs::MetadataExt;
+    use ocidir::oci_spec::image::ConfigBuilder;
+    use std::io::Read;
     use std::path::PathBuf;
     use tempfile::TempDir;
 
@@ -595,7 +1770,7 @@ pub mod tests {
         assert_eq!(inodes[1].ino, 2);
         assert_eq!(inodes[1].uid, md.uid());
         assert_eq!(inodes[1].gid, md.gid());
-        if let InodeMode::File { ref chunks } = inodes[1].mode {
+        if let InodeMode::File { ref chunks, .. } = inodes[1].mode {
             assert_eq!(chunks.len(), 1);
             assert_eq!(
                 chunks[0].len,
@@ -625,10 +1800,20 @@ pub mod tests {
         image.0.fsck()?;
 
         let new_tag = "test2";
-        let (_desc, image) =
-            add_rootfs_delta::<DefaultCompression>(&delta_dir, image, new_tag, tag).unwrap();
+        let (_desc, image, stats) = add_rootfs_delta::<DefaultCompression>(
+            &delta_dir,
+            image,
+            new_tag,
+            tag,
+            &BuilderConfig::default(),
+        )
+        .unwrap();
         let delta = Rootfs::try_from(image.open_rootfs_blob(new_tag, None).unwrap()).unwrap();
         assert_eq!(delta.metadatas.len(), 2);
+        // the only file in the delta was already chunked in the base layer
+        assert_eq!(stats.new_chunks, 0);
+        assert!(stats.reused_chunks > 0);
+        assert_eq!(stats.deduplicated_bytes, 109466);
 
         let image = Image::new(dir.path()).unwrap();
         image.0.fsck()?;
@@ -655,6 +1840,173 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_unchanged_inodes() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("b"))?;
+        fs::write(rootfs.join("a"), b"a")?;
+        fs::write(rootfs.join("b/c"), b"c")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_initial_rootfs::<DefaultCompression>(
+            &rootfs,
+            &image,
+            "base",
+            &BuilderConfig::default(),
+        )?;
+
+        fs::write(rootfs.join("b/d"), b"d")?;
+        let (_, image, stats) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            image,
+            "delta",
+            "base",
+            &BuilderConfig::default(),
+        )?;
+        // the root, a and b/c are found in the base layer
+        assert_eq!(stats.unchanged_inodes, 3);
+        let delta = Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "delta", None)?;
+        let b = pfs.lookup(Path::new("/b"))?.unwrap();
+        let d = pfs.lookup(Path::new("/b/d"))?.unwrap();
+        let layer = delta.metadatas[0].iter().map(|inode| inode.ino);
+        assert_eq!(layer.collect::<Vec<_>>(), [b.ino, d.ino]);
+        // b only lists d, and looks below for c
+        let InodeMode::Dir { dir_list } = &delta.metadatas[0][0].mode else {
+            panic!("b isn't a directory");
+        };
+        assert!(dir_list.look_below);
+        assert_eq!(dir_list.entries.len(), 1);
+        assert_eq!(b.dir_entries()?.len(), 2);
+        assert_eq!(pfs.lookup(Path::new("/a"))?.unwrap().file_len()?, 1);
+        assert_eq!(pfs.lookup(Path::new("/b/c"))?.unwrap().file_len()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_look_below() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("b"))?;
+        for path in ["a", "b/c", "b/e"] {
+            fs::write(rootfs.join(path), path)?;
+        }
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs, &image, "base")?;
+
+        fs::remove_file(rootfs.join("a"))?;
+        fs::remove_file(rootfs.join("b/e"))?;
+        fs::write(rootfs.join("b/d"), "b/d")?;
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            image,
+            "delta",
+            "base",
+            &BuilderConfig::default(),
+        )?;
+        let paths = |tag: &str| -> anyhow::Result<Vec<String>> {
+            let mut pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, tag, None)?;
+            assert!(pfs.lookup(Path::new("/a"))?.is_none());
+            assert!(pfs.lookup(Path::new("/b/e"))?.is_none());
+            // c is only listed by the base layer, d only by the delta
+            let b = pfs.dir_lookup(1, b"b")?;
+            assert_eq!(pfs.find_inode(pfs.dir_lookup(b, b"c")?)?.file_len()?, 3);
+            assert_eq!(pfs.find_inode(pfs.dir_lookup(b, b"d")?)?.file_len()?, 3);
+            assert!(pfs.dir_lookup(b, b"e").is_err());
+            WalkPuzzleFS::walk(&mut pfs)?
+                .map(|entry| Ok(entry?.path.to_string_lossy().into_owned()))
+                .collect()
+        };
+        assert_eq!(paths("delta")?, ["/", "/b", "/b/c", "/b/d"]);
+
+        // the deleted entries aren't listed by the delta, only whited out
+        let delta = Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?;
+        for inode in &delta.metadatas[0] {
+            if let InodeMode::Dir { dir_list } = &inode.mode {
+                assert!(dir_list.look_below);
+                assert!(dir_list
+                    .entries
+                    .iter()
+                    .all(|entry| entry.name != b"a" && entry.name != b"e"));
+            }
+        }
+
+        // squashing merges the directories the same way
+        squash_deltas(&image, "delta")?;
+        let squashed = Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?;
+        assert_eq!(squashed.metadatas.len(), 1);
+        assert!(squashed.metadatas[0].iter().all(|inode| match &inode.mode {
+            InodeMode::Dir { dir_list } => !dir_list.look_below,
+            mode => !matches!(mode, InodeMode::Wht),
+        }));
+        assert_eq!(paths("delta")?, ["/", "/b", "/b/c", "/b/d"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_opaque_dirs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("d"))?;
+        for path in ["d/a", "d/b"] {
+            fs::write(rootfs.join(path), path)?;
+        }
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(&rootfs, &image, "base")?;
+
+        // d is replaced wholesale, so it's opaque rather than whiting out a and b
+        fs::remove_dir_all(rootfs.join("d"))?;
+        fs::create_dir_all(rootfs.join("d"))?;
+        fs::write(rootfs.join("d/c"), "d/c")?;
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            image,
+            "replaced",
+            "base",
+            &BuilderConfig::default(),
+        )?;
+        let delta = Rootfs::try_from(image.open_rootfs_blob("replaced", None)?)?;
+        assert!(delta.metadatas[0]
+            .iter()
+            .all(|inode| !matches!(inode.mode, InodeMode::Wht)));
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "replaced", None)?;
+        let d = pfs.lookup(Path::new("/d"))?.unwrap();
+        let InodeMode::Dir { dir_list } = &d.mode else {
+            panic!("d isn't a directory");
+        };
+        assert!(dir_list.opaque);
+        assert_eq!(dir_list.entries.len(), 1);
+        assert!(pfs.lookup(Path::new("/d/a"))?.is_none());
+        assert!(pfs.lookup(Path::new("/d/c"))?.is_some());
+
+        // an entry added to it later looks below again
+        fs::write(rootfs.join("d/e"), "d/e")?;
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            Image::open(&dir.path().join("oci"))?,
+            "added",
+            "replaced",
+            &BuilderConfig::default(),
+        )?;
+        let delta = Rootfs::try_from(image.open_rootfs_blob("added", None)?)?;
+        let d = delta.metadatas[0]
+            .iter()
+            .find(|inode| inode.ino == d.ino)
+            .unwrap();
+        let InodeMode::Dir { dir_list } = &d.mode else {
+            panic!("d isn't a directory");
+        };
+        assert!(!dir_list.opaque && dir_list.look_below);
+        assert_eq!(dir_list.entries.len(), 1);
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "added", None)?;
+        assert_eq!(
+            pfs.lookup(Path::new("/d"))?.unwrap().dir_entries()?.len(),
+            2
+        );
+        assert!(pfs.lookup(Path::new("/d/a"))?.is_none());
+        Ok(())
+    }
+
     fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
         if a.len() != b.len() {
             return false;
@@ -722,6 +2074,974 @@ pub mod tests {
         true
     }
 
+    #[test]
+    fn test_ownership_overrides() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let md = fs::symlink_metadata(rootfs)?;
+
+        let config = BuilderConfig {
+            owner: Some((1234, 5678)),
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(rootfs, &image, "owner", &config)?;
+        let config = BuilderConfig {
+            uid_map: IdMap::new(vec![format!("0:{}:1", md.uid()).parse().unwrap()]),
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(rootfs, &image, "map", &config)?;
+
+        let image = Image::open(dir.path())?;
+        let pfs = PuzzleFS::open(image, "owner", None)?;
+        let inode = pfs.find_inode(1)?;
+        assert_eq!((inode.uid, inode.gid), (1234, 5678));
+
+        let image = Image::open(dir.path())?;
+        let pfs = PuzzleFS::open(image, "map", None)?;
+        let inode = pfs.find_inode(1)?;
+        assert_eq!((inode.uid, inode.gid), (0, md.gid()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mode_overrides() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("shared"))?;
+        fs::write(rootfs.join("su"), b"su")?;
+        fs::write(rootfs.join("passwd"), b"root")?;
+        fs::set_permissions(rootfs.join("shared"), fs::Permissions::from_mode(0o2775))?;
+        fs::set_permissions(rootfs.join("su"), fs::Permissions::from_mode(0o4755))?;
+        fs::set_permissions(rootfs.join("passwd"), fs::Permissions::from_mode(0o664))?;
+
+        let config = BuilderConfig {
+            clear_setuid: true,
+            force_modes: vec!["/passwd=0644".parse().unwrap()],
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(&rootfs, &image, "test", &config)?;
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let mode = |path: &str| -> anyhow::Result<u16> {
+            Ok(pfs.lookup(Path::new(path))?.unwrap().permissions)
+        };
+        assert_eq!(mode("/shared")?, 0o2775);
+        assert_eq!(mode("/su")?, 0o755);
+        assert_eq!(mode("/passwd")?, 0o644);
+        Ok(())
+    }
+
+    #[test]
+    fn test_packs() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+
+        // incompressible data, so the chunker has some boundaries to find
+        let mut random = XorShift::new(0x2545f491);
+        let mut contents = BTreeMap::new();
+        for name in ["a", "b", "c", "d", "e"] {
+            let data = random.bytes(300 * 1024);
+            fs::write(rootfs.join(name), &data)?;
+            contents.insert(format!("/{name}"), data);
+        }
+
+        let config = BuilderConfig {
+            pack_chunks_below: Some(MAX_CHUNK_SIZE + 1),
+            ..Default::default()
+        };
+        let (_desc, stats) =
+            build_initial_rootfs::<DefaultCompression>(&rootfs, &image, "test", &config)?;
+        // 1.5MiB of chunks make a full pack and a partial one
+        assert_eq!(stats.packs, 2);
+        assert_eq!(stats.new_chunks, 2);
+        assert!(stats.packed_chunks > 2);
+        image.0.fsck()?;
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
+        walker.next().unwrap()?;
+        for de in walker {
+            let de = de?;
+            let mut data = Vec::new();
+            de.open()?.read_to_end(&mut data)?;
+            assert_eq!(&data, &contents[&*de.path.to_string_lossy()]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_files() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("resolv.conf"), b"nameserver 127.0.0.1\n")?;
+        fs::write(rootfs.join("empty"), b"")?;
+        fs::copy(
+            "src/builder/test/test-1/SekienAkashita.jpg",
+            rootfs.join("big"),
+        )?;
+
+        let config = BuilderConfig {
+            inline_files_below: Some(256),
+            ..Default::default()
+        };
+        let (_desc, stats) =
+            build_initial_rootfs::<DefaultCompression>(&rootfs, &image, "test", &config)?;
+        assert_eq!(stats.inlined_files, 1);
+        assert_eq!(stats.new_chunks, 1);
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let inode = pfs.lookup(Path::new("/resolv.conf"))?.unwrap();
+        assert!(matches!(
+            &inode.mode,
+            InodeMode::File { chunks, inline: Some(data) }
+                if chunks.is_empty() && data == b"nameserver 127.0.0.1\n"
+        ));
+        assert_eq!(inode.file_len()?, 21);
+        let inode = pfs.lookup(Path::new("/empty"))?.unwrap();
+        assert!(matches!(&inode.mode, InodeMode::File { inline: None, .. }));
+
+        let mut pfs = pfs;
+        for de in WalkPuzzleFS::walk(&mut pfs)? {
+            let de = de?;
+            if de.path == Path::new("/resolv.conf") {
+                let mut data = String::new();
+                de.open()?.read_to_string(&mut data)?;
+                assert_eq!(data, "nameserver 127.0.0.1\n");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("copies"))?;
+        let original = Path::new("src/builder/test/test-1/SekienAkashita.jpg");
+        fs::copy(original, rootfs.join("a.jpg"))?;
+        fs::copy(original, rootfs.join("copies/b.jpg"))?;
+        fs::write(rootfs.join("c"), b"meshuggah rocks")?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let config = BuilderConfig {
+            dedup_files: true,
+            ..Default::default()
+        };
+        let (_desc, stats) =
+            build_initial_rootfs::<DefaultCompression>(&rootfs, &image, "test", &config)?;
+        assert_eq!(stats.duplicate_files, 1);
+        // the copy didn't go through the chunker
+        assert_eq!(stats.chunked_bytes, fs::metadata(original)?.len() + 15);
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let a = pfs.lookup(Path::new("/a.jpg"))?.unwrap();
+        let b = pfs.lookup(Path::new("/copies/b.jpg"))?.unwrap();
+        assert_ne!(a.ino, b.ino);
+        assert_eq!(a.mode, b.mode);
+        let mut data = Vec::new();
+        FileReader::new(&pfs.oci, &b)?.read_to_end(&mut data)?;
+        assert_eq!(data, fs::read(original)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_lengths() -> anyhow::Result<()> {
+        let jpg = PathBuf::from("src/builder/test/test-1/SekienAkashita.jpg");
+        let size = fs::metadata(&jpg)?.len();
+        let files = [(jpg.clone(), size), (jpg, size)];
+        let fixed = |size| BuilderConfig {
+            chunker: Some(ChunkerConfig::Fixed { size }),
+            ..Default::default()
+        };
+        let lengths = chunk_lengths(&files, &BuilderConfig::default())?;
+        assert_eq!(lengths.iter().sum::<usize>() as u64, 2 * size);
+        assert!(lengths[..lengths.len() - 1]
+            .iter()
+            .all(|&len| len >= MIN_CHUNK_SIZE as usize && len <= MAX_CHUNK_SIZE as usize));
+        let stream = chunk_lengths(&files, &fixed(4096))?;
+        assert!(stream[..stream.len() - 1].iter().all(|&len| len == 4096));
+        // each copy starts a chunk
+        let anchored = BuilderConfig {
+            anchor_files: true,
+            ..fixed(4096)
+        };
+        let anchored = chunk_lengths(&files, &anchored)?;
+        let per_file = (size as usize).div_ceil(4096);
+        assert_eq!(anchored.len(), 2 * per_file);
+        assert_eq!(anchored[..per_file], anchored[per_file..]);
+        // and so do the large files chunked in parallel, whatever the number of threads
+        let small = PathBuf::from("Cargo.toml");
+        let small_size = fs::metadata(&small)?.len();
+        let files = [files[0].clone(), (small, small_size), files[1].clone()];
+        for chunking_threads in [1, 2] {
+            let parallel = BuilderConfig {
+                parallel_chunking_above: Some(small_size),
+                chunking_threads,
+                ..fixed(4096)
+            };
+            let mut expected = anchored[..per_file].to_vec();
+            expected.push(small_size as usize);
+            expected.extend_from_slice(&anchored[per_file..]);
+            assert_eq!(chunk_lengths(&files, &parallel)?, expected);
+        }
+        assert_eq!(
+            chunk_lengths(&[], &BuilderConfig::default())?,
+            Vec::<usize>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_digests() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let image = Image::new(&dir.path().join("oci"))?;
+        let config = BuilderConfig {
+            file_digests: true,
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(rootfs, &image, "digests", &config)?;
+        build_initial_rootfs::<DefaultCompression>(
+            rootfs,
+            &image,
+            "plain",
+            &BuilderConfig::default(),
+        )?;
+
+        let pfs = PuzzleFS::open(image, "digests", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        assert_eq!(
+            inode.file_digest().map(hex::encode).as_deref(),
+            Some("d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed")
+        );
+        assert!(verify_file(&pfs.oci, &inode)?);
+
+        // deduplicated files are hashed before they're chunked, to the same digest
+        let dedup = BuilderConfig {
+            dedup_files: true,
+            ..config
+        };
+        build_initial_rootfs::<DefaultCompression>(
+            rootfs,
+            &Image::open(&dir.path().join("oci"))?,
+            "dedup",
+            &dedup,
+        )?;
+        let dedup = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "dedup", None)?;
+        let dedup_inode = dedup.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        assert_eq!(dedup_inode.file_digest(), inode.file_digest());
+
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "plain", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        assert_eq!(inode.file_digest(), None);
+        assert!(!verify_file(&pfs.oci, &inode)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_budget() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        let mut random = XorShift::new(0x2545f491);
+        for (i, subdir) in ["a", "a/b", "c"].iter().enumerate() {
+            fs::create_dir_all(rootfs.join(subdir))?;
+            for (j, size) in [0, 10, 20 * 1024, 200 * 1024].iter().enumerate() {
+                let data = random.bytes(*size);
+                fs::write(rootfs.join(subdir).join(format!("{i}-{j}")), data)?;
+            }
+        }
+        std::os::unix::fs::symlink("a/b", rootfs.join("link"))?;
+
+        let build = |memory_budget| -> anyhow::Result<Descriptor> {
+            let image = Image::new(&dir.path().join("oci"))?;
+            let config = BuilderConfig {
+                pack_chunks_below: Some(MIN_CHUNK_SIZE * 2),
+                inline_files_below: Some(16),
+                memory_budget,
+                ..Default::default()
+            };
+            let (desc, _stats) =
+                build_initial_rootfs::<DefaultCompression>(&rootfs, &image, "test", &config)?;
+            Ok(desc)
+        };
+        // spilling every inode to disk builds the same image
+        assert_eq!(build(None)?, build(Some(1))?);
+
+        fs::write(rootfs.join("a/b/new"), b"new")?;
+        let delta = |memory_budget| -> anyhow::Result<Descriptor> {
+            let image = Image::new(&dir.path().join("oci"))?;
+            let config = BuilderConfig {
+                memory_budget,
+                ..Default::default()
+            };
+            let (desc, _image, stats) =
+                add_rootfs_delta::<DefaultCompression>(&rootfs, image, "delta", "test", &config)?;
+            assert!(stats.unchanged_inodes > 0);
+            Ok(desc)
+        };
+        // and so do the deltas
+        assert_eq!(delta(None)?, delta(Some(1))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let blobs = || {
+            fs::read_dir(dir.path().join("oci").join(Image::blob_path())).map_or(0, |d| d.count())
+        };
+
+        let before = blobs();
+        let config = BuilderConfig {
+            dry_run: true,
+            ..Default::default()
+        };
+        let (planned, plan) =
+            build_initial_rootfs::<DefaultCompression>(rootfs, &image, "test", &config)?;
+        assert_eq!(blobs(), before);
+        assert!(image.find_manifest("test")?.is_none());
+        assert_eq!(plan.new_chunks, 1);
+
+        let (desc, stats) = build_initial_rootfs::<DefaultCompression>(
+            rootfs,
+            &image,
+            "test",
+            &BuilderConfig::default(),
+        )?;
+        assert_eq!(planned, desc);
+        assert_eq!(plan, stats);
+
+        // everything is in the image now
+        let (_desc, plan) =
+            build_initial_rootfs::<DefaultCompression>(rootfs, &image, "again", &config)?;
+        assert_eq!(plan.new_chunks, 0);
+        assert_eq!(plan.reused_chunks, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay_upper() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let rootfs = dir.path().join("rootfs");
+        for (path, content) in [
+            ("a/one", "1"),
+            ("a/two", "2"),
+            ("b/three", "3"),
+            ("keep", "k"),
+        ] {
+            fs::create_dir_all(rootfs.join(path).parent().unwrap())?;
+            fs::write(rootfs.join(path), content)?;
+        }
+        build_test_fs(&rootfs, &image, "base")?;
+
+        // what a writable mount of base where a/one was deleted, a/new created, b emptied and
+        // refilled and a/two changed left behind
+        let upper = dir.path().join("upper");
+        fs::create_dir_all(upper.join("a"))?;
+        fs::create_dir_all(upper.join("b"))?;
+        fs::write(upper.join("a/.wh.one"), "")?;
+        fs::write(upper.join("a/new"), "new")?;
+        fs::write(upper.join("a/two"), "two")?;
+        fs::write(upper.join("b/.wh..wh..opq"), "")?;
+        fs::write(upper.join("b/four"), "4")?;
+
+        let config = BuilderConfig {
+            overlay_upper: true,
+            ..Default::default()
+        };
+        let (_, image, _) =
+            add_rootfs_delta::<DefaultCompression>(&upper, image, "delta", "base", &config)?;
+        let image = Image::open(&dir.path().join("oci"))?;
+        let pfs = PuzzleFS::open(image, "delta", None)?;
+        let read = |path: &str| -> anyhow::Result<Option<String>> {
+            let Ok(Some(inode)) = pfs.lookup(Path::new(path)) else {
+                return Ok(None);
+            };
+            let mut content = String::new();
+            FileReader::new(&pfs.oci, &inode)?.read_to_string(&mut content)?;
+            Ok(Some(content))
+        };
+        assert_eq!(read("/keep")?.as_deref(), Some("k"));
+        assert_eq!(read("/a/one")?, None);
+        assert_eq!(read("/a/two")?.as_deref(), Some("two"));
+        assert_eq!(read("/a/new")?.as_deref(), Some("new"));
+        assert_eq!(read("/a/.wh.one")?, None);
+        assert_eq!(read("/b/three")?, None);
+        assert_eq!(read("/b/four")?.as_deref(), Some("4"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_policy() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let host = dir.path().join("host");
+        fs::create_dir_all(host.join("ssl"))?;
+        fs::write(host.join("resolv.conf"), "nameserver 10.0.0.1")?;
+        fs::write(host.join("ssl/cert.pem"), "cert")?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc"))?;
+        fs::create_dir_all(rootfs.join("usr/bin"))?;
+        std::os::unix::fs::symlink(host.join("resolv.conf"), rootfs.join("etc/resolv.conf"))?;
+        std::os::unix::fs::symlink(host.join("ssl"), rootfs.join("etc/ssl"))?;
+        std::os::unix::fs::symlink("/bin/busybox", rootfs.join("usr/bin/sh"))?;
+        std::os::unix::fs::symlink("/nowhere", rootfs.join("etc/dangling"))?;
+
+        let mut config = BuilderConfig {
+            symlinks: SymlinkPolicy {
+                follow: vec!["/etc/resolv.conf".parse()?, "/etc/ssl".parse()?],
+                relative: true,
+                reject_escaping: true,
+            },
+            ..Default::default()
+        };
+        build_initial_rootfs::<Zstd>(&rootfs, &image, "test", &config)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "test", None)?;
+        let read = |path: &str| -> anyhow::Result<String> {
+            let inode = pfs.lookup(Path::new(path))?.unwrap();
+            let mut content = String::new();
+            FileReader::new(&pfs.oci, &inode)?.read_to_string(&mut content)?;
+            Ok(content)
+        };
+        assert_eq!(read("/etc/resolv.conf")?, "nameserver 10.0.0.1");
+        assert_eq!(read("/etc/ssl/cert.pem")?, "cert");
+        let target = |path: &str| -> anyhow::Result<OsString> {
+            let inode = pfs.lookup(Path::new(path))?.unwrap();
+            Ok(inode.symlink_target()?.to_os_string())
+        };
+        assert_eq!(target("/usr/bin/sh")?, "../../bin/busybox");
+        assert_eq!(target("/etc/dangling")?, "../nowhere");
+
+        std::os::unix::fs::symlink("../../..", rootfs.join("etc/escape"))?;
+        assert!(matches!(
+            build_initial_rootfs::<Zstd>(&rootfs, &image, "escape", &config),
+            Err(WireFormatError::Build(BuildError::EscapingSymlink { path }))
+                if path == Path::new("/etc/escape")
+        ));
+        config.symlinks.reject_escaping = false;
+        build_initial_rootfs::<Zstd>(&rootfs, &image, "escape", &config)?;
+
+        // the error of a symlink which can't be followed names it
+        config.symlinks.follow.push("/etc/dangling".parse()?);
+        let err = build_initial_rootfs::<Zstd>(&rootfs, &image, "dangling", &config).unwrap_err();
+        assert!(matches!(
+            &err,
+            WireFormatError::Build(BuildError::Io { op: "follow", path, source })
+                if *path == rootfs.join("etc/dangling")
+                    && source.kind() == io::ErrorKind::NotFound
+        ));
+        assert!(err.to_string().contains("etc/dangling"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_symlink_to_other_filesystem() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let Ok(host) = tempfile::tempdir_in("/dev/shm") else {
+            return Ok(());
+        };
+        let dev = |path: &Path| -> io::Result<u64> {
+            Ok(std::os::unix::fs::MetadataExt::dev(&fs::metadata(path)?))
+        };
+        if dev(host.path())? == dev(dir.path())? {
+            return Ok(());
+        }
+        fs::create_dir_all(host.path().join("ssl/certs"))?;
+        fs::write(host.path().join("ssl/certs/cert.pem"), "cert")?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc"))?;
+        std::os::unix::fs::symlink(host.path().join("ssl"), rootfs.join("etc/ssl"))?;
+
+        let image = Image::new(&dir.path().join("oci"))?;
+        let config = BuilderConfig {
+            symlinks: SymlinkPolicy {
+                follow: vec!["/etc/ssl".parse()?],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        build_initial_rootfs::<Zstd>(&rootfs, &image, "test", &config)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "test", None)?;
+        let inode = pfs.lookup(Path::new("/etc/ssl/certs/cert.pem"))?.unwrap();
+        let mut content = String::new();
+        FileReader::new(&pfs.oci, &inode)?.read_to_string(&mut content)?;
+        assert_eq!(content, "cert");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mountpoints() -> anyhow::Result<()> {
+        assert_eq!(
+            crate::common::unescape_mountinfo(br"/mnt/a\040b\134c\0"),
+            b"/mnt/a b\\c\\0"
+        );
+        if Path::new("/proc/self/mountinfo").exists() {
+            let mountpoints = mountpoints_below(Path::new("/"))?;
+            assert!(mountpoints.contains(Path::new("/proc")));
+            assert!(!mountpoints.contains(Path::new("/")));
+            assert!(mountpoints_below(Path::new("/proc/self/fd"))?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_verity() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let config = BuilderConfig {
+            require_verity: true,
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(
+            Path::new("src/builder/test/test-1"),
+            &image,
+            "test",
+            &config,
+        )?;
+        assert!(image.requires_verity("test")?);
+
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            Path::new("src/builder/test/test-1"),
+            image,
+            "delta",
+            "test",
+            &BuilderConfig::default(),
+        )?;
+        assert!(image.requires_verity("delta")?);
+
+        let image = Image::open(dir.path())?;
+        assert!(PuzzleFS::open(image, "test", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_cache_media_types() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let config = BuilderConfig {
+            build_cache: Some(dir.path().join("cache.json")),
+            ..Default::default()
+        };
+        build_initial_rootfs::<Zstd>(rootfs, &image, "zstd", &config)?;
+        let (_, stats) = build_initial_rootfs::<Noop>(rootfs, &image, "noop", &config)?;
+        assert_eq!(stats.cached_files, 1);
+
+        // the cached chunks keep the media type they were written with
+        let manifest = image.find_manifest("noop")?.unwrap();
+        let chunks = &manifest.layers()[1..];
+        assert!(!chunks.is_empty());
+        assert_eq!(chunks, &image.find_manifest("zstd")?.unwrap().layers()[1..]);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.media_type().to_string().ends_with("+zstd")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_verity_hash() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("old"), b"old")?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        // anchored, so that the delta chunks the old file the same way
+        let config = BuilderConfig {
+            anchor_files: true,
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(&rootfs, &image, "base", &config)?;
+        let base = Rootfs::try_from(image.open_rootfs_blob("base", None)?)?;
+
+        fs::write(rootfs.join("new"), b"new")?;
+        let config = BuilderConfig {
+            verity_hash: VerityHash::Sha512,
+            ..Default::default()
+        };
+        let (_, image, _) =
+            add_rootfs_delta::<DefaultCompression>(&rootfs, image, "delta", "base", &config)?;
+        let delta = Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?;
+        // the shared chunk keeps its sha256 digest, the new one gets a sha512 digest
+        let (shared, new): (Vec<_>, Vec<_>) = delta
+            .fs_verity_data
+            .iter()
+            .partition(|(digest, _)| base.fs_verity_data.contains_key(*digest));
+        assert_eq!(shared.len(), base.fs_verity_data.len());
+        assert!(shared.iter().all(|(digest, verity)| {
+            base.fs_verity_data[*digest] == **verity && verity.len() == 32
+        }));
+        assert!(!new.is_empty());
+        assert!(new.iter().all(|(_, verity)| verity.len() == 64));
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_config() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let mut image_config = ImageConfiguration::default();
+        image_config.set_config(Some(
+            ConfigBuilder::default()
+                .env(vec!["PATH=/bin".to_string()])
+                .entrypoint(vec!["/bin/sh".to_string()])
+                .labels(HashMap::from([("foo".to_string(), "bar".to_string())]))
+                .build()?,
+        ));
+        let config = BuilderConfig {
+            image_config: Some(image_config),
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(rootfs, &image, "test", &config)?;
+
+        let manifest = image.find_manifest("test")?.unwrap();
+        assert_eq!(manifest.config().media_type(), &MediaType::ImageConfig);
+        let written: ImageConfiguration = image.0.read_json_blob(manifest.config())?;
+        let written_config = written.config().as_ref().unwrap();
+        assert_eq!(
+            written_config.entrypoint(),
+            &Some(vec!["/bin/sh".to_string()])
+        );
+        assert_eq!(written_config.labels().as_ref().unwrap()["foo"], "bar");
+        assert_eq!(written.architecture(), image.platform().architecture());
+        assert_eq!(written.history().as_ref().unwrap().len(), 1);
+
+        // deltas keep the config of their base layer
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            rootfs,
+            image,
+            "delta",
+            "test",
+            &BuilderConfig::default(),
+        )?;
+        let delta = image.find_manifest("delta")?.unwrap();
+        assert_eq!(delta.config(), manifest.config());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stamp() -> anyhow::Result<()> {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_827_696), "2000-02-29T12:34:56Z");
+
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let config = BuilderConfig {
+            stamp: true,
+            annotations: BTreeMap::from([(
+                "org.opencontainers.image.revision".to_string(),
+                "abc123".to_string(),
+            )]),
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(
+            Path::new("src/builder/test/test-1"),
+            &image,
+            "test",
+            &config,
+        )?;
+        let manifest = image.find_manifest("test")?.unwrap();
+        let annotations = manifest.annotations().as_ref().unwrap();
+        assert!(annotations.contains_key(ANNOTATION_CREATED));
+        assert_eq!(
+            annotations[media_types::VERSION_ANNOTATION],
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(annotations[media_types::CHUNKER_ANNOTATION].starts_with("fastcdc"));
+        assert_eq!(annotations["org.opencontainers.image.revision"], "abc123");
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunker() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let chunker = ChunkerConfig::Fixed { size: 4096 };
+        let config = BuilderConfig {
+            chunker: Some(chunker),
+            parallel_chunking_above: Some(1 << 10),
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(rootfs, &image, "base", &config)?;
+        let annotation = |image: &Image, tag, key| -> anyhow::Result<Option<String>> {
+            let manifest = image.find_manifest(tag)?.unwrap();
+            Ok(manifest
+                .annotations()
+                .as_ref()
+                .and_then(|annotations| annotations.get(key))
+                .cloned())
+        };
+        let recorded = |image: &Image, tag| annotation(image, tag, media_types::CHUNKER_ANNOTATION);
+        let parallel =
+            |image: &Image, tag| annotation(image, tag, media_types::PARALLEL_CHUNKING_ANNOTATION);
+        assert_eq!(recorded(&image, "base")?, Some(chunker.to_string()));
+        assert_eq!(parallel(&image, "base")?, Some("1024".to_string()));
+
+        // a delta is chunked like its base, so the unchanged file doesn't need new chunks
+        let (_, image, stats) = add_rootfs_delta::<DefaultCompression>(
+            rootfs,
+            image,
+            "delta",
+            "base",
+            &BuilderConfig::default(),
+        )?;
+        assert_eq!(stats.new_chunks, 0);
+        assert_eq!(recorded(&image, "delta")?, Some(chunker.to_string()));
+        assert_eq!(parallel(&image, "delta")?, Some("1024".to_string()));
+
+        build_test_fs(rootfs, &image, "default")?;
+        assert_eq!(recorded(&image, "default")?, None);
+        assert_eq!(parallel(&image, "default")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_anchor_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir(&rootfs)?;
+        let mut random = XorShift::new(1);
+        for name in ["b", "c", "d", "e", "f", "g", "h", "i"] {
+            fs::write(rootfs.join(name), random.bytes(40_000))?;
+        }
+
+        // a file added in front of the others shifts all the fixed size chunks after it, unless
+        // each file starts its own chunks
+        let mut new_chunks = Vec::new();
+        for anchor_files in [false, true] {
+            let image = Image::new(&dir.path().join(format!("oci-{anchor_files}")))?;
+            let config = BuilderConfig {
+                chunker: Some(ChunkerConfig::Fixed { size: 16384 }),
+                anchor_files,
+                ..Default::default()
+            };
+            build_initial_rootfs::<DefaultCompression>(&rootfs, &image, "base", &config)?;
+            let mut upper = rootfs.clone();
+            upper.set_file_name(format!("upper-{anchor_files}"));
+            fs::create_dir(&upper)?;
+            for entry in fs::read_dir(&rootfs)? {
+                let entry = entry?;
+                fs::copy(entry.path(), upper.join(entry.file_name()))?;
+            }
+            fs::write(upper.join("a"), random.bytes(1000))?;
+            let (_, _, stats) = add_rootfs_delta::<DefaultCompression>(
+                &upper,
+                image,
+                "delta",
+                "base",
+                &BuilderConfig::default(),
+            )?;
+            new_chunks.push(stats.new_chunks);
+        }
+        // the deltas inherited the chunking of their base
+        assert_eq!(
+            new_chunks,
+            [(8 * 40_000 + 1000_usize).div_ceil(16384) as u64, 1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_landmarks() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        build_test_fs(rootfs, &image, "test")?;
+        let layers = image.find_manifest("test")?.unwrap().layers().clone();
+        // the rootfs, then the chunks
+        let last_chunk = layers.last().unwrap().clone();
+        let landmark = last_chunk.digest().digest().to_string();
+        let is_landmark = |desc: &Descriptor| {
+            desc.annotations()
+                .as_ref()
+                .is_some_and(|a| a.contains_key(media_types::LANDMARK_ANNOTATION))
+        };
+
+        let config = BuilderConfig {
+            landmarks: vec![landmark.clone(), "00".repeat(32), landmark.clone()],
+            ..Default::default()
+        };
+        build_initial_rootfs::<DefaultCompression>(rootfs, &image, "marked", &config)?;
+        let marked = image.find_manifest("marked")?.unwrap().layers().clone();
+        assert_eq!(marked.len(), layers.len());
+        assert_eq!(marked[1].digest(), last_chunk.digest());
+        assert!(is_landmark(&marked[1]));
+        assert_eq!(marked.iter().filter(|desc| is_landmark(desc)).count(), 1);
+
+        let (_, image, _) =
+            add_rootfs_delta::<DefaultCompression>(rootfs, image, "delta", "test", &config)?;
+        let delta = image.find_manifest("delta")?.unwrap().layers().clone();
+        assert_eq!(delta[1].digest(), last_chunk.digest());
+        assert_eq!(delta[1].size(), last_chunk.size());
+        assert_eq!(delta[1].media_type(), last_chunk.media_type());
+        assert!(is_landmark(&delta[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_versions() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        assert!(migrate_rootfs(&image, "test")?.is_none());
+
+        let rootfs = Rootfs::try_from(image.open_rootfs_blob("test", None)?)?;
+        let layers = |image: &Image| -> anyhow::Result<usize> {
+            Ok(image.find_manifest("test")?.unwrap().layers().len())
+        };
+        let initial_layers = layers(&image)?;
+        let with_version = |version| Rootfs {
+            metadatas: rootfs.metadatas.clone(),
+            fs_verity_data: rootfs.fs_verity_data.clone(),
+            manifest_version: version,
+        };
+        for version in [2, PUZZLEFS_IMAGE_MANIFEST_VERSION + 1] {
+            replace_rootfs(&image, "test", "test", with_version(version), |_| true)?;
+            assert!(matches!(
+                image.open_rootfs_blob("test", None),
+                Err(WireFormatError::Image(ImageError::UnsupportedManifestVersion {
+                    found,
+                    ..
+                })) if found == version
+            ));
+            assert!(migrate_rootfs(&image, "test").is_err());
+        }
+
+        // the rootfs of test-1 as the capnp releases before inline files wrote it
+        replace_rootfs(&image, "test", "test", with_version(3), |_| true)?;
+        assert_eq!(version_support(3), VersionSupport::Migratable);
+        let pfs = PuzzleFS::open(Image::open(dir.path())?, "test", None)?;
+        assert!(pfs.lookup(Path::new("/SekienAkashita.jpg"))?.is_some());
+        let (from, desc) = migrate_rootfs(&image, "test")?.unwrap();
+        assert_eq!(from, 3);
+        let migrated = Rootfs::try_from(image.open_rootfs_blob("test", None)?)?;
+        assert_eq!(migrated.manifest_version, PUZZLEFS_IMAGE_MANIFEST_VERSION);
+        assert_eq!(
+            image.find_manifest("test")?.unwrap().layers()[0].digest(),
+            desc.digest()
+        );
+        assert!(migrate_rootfs(&image, "test")?.is_none());
+
+        // the chunks stay in the manifest when its rootfs is replaced
+        assert_eq!(layers(&image)?, initial_layers);
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        assert!(pfs.lookup(Path::new("/SekienAkashita.jpg"))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_squash() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "base")?;
+
+        let delta_dir = dir.path().join("delta");
+        fs::create_dir_all(delta_dir.join("foo"))?;
+        fs::write(delta_dir.join("foo/bar"), b"bar")?;
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            &delta_dir,
+            image,
+            "delta",
+            "base",
+            &BuilderConfig::default(),
+        )?;
+        let layers = image.find_manifest("delta")?.unwrap().layers().len();
+        assert_eq!(
+            Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?
+                .metadatas
+                .len(),
+            2
+        );
+
+        // building the same tree again doesn't add a layer
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            &delta_dir,
+            Image::new(dir.path())?,
+            "again",
+            "delta",
+            &BuilderConfig::default(),
+        )?;
+        assert_eq!(
+            Rootfs::try_from(image.open_rootfs_blob("again", None)?)?
+                .metadatas
+                .len(),
+            2
+        );
+
+        assert!(squash_deltas(&image, "delta")?.is_some());
+        let rootfs = Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?;
+        assert_eq!(rootfs.metadatas.len(), 1);
+        assert!(rootfs.metadatas[0]
+            .iter()
+            .all(|inode| !matches!(inode.mode, InodeMode::Wht)));
+        assert_eq!(
+            image.find_manifest("delta")?.unwrap().layers().len(),
+            layers
+        );
+        assert!(squash_deltas(&image, "delta")?.is_none());
+
+        let image = Image::open(dir.path())?;
+        let pfs = PuzzleFS::open(image, "delta", None)?;
+        assert!(pfs.lookup(Path::new("/SekienAkashita.jpg"))?.is_none());
+        assert_eq!(pfs.lookup(Path::new("/foo/bar"))?.unwrap().file_len()?, 3);
+        assert_eq!(pfs.find_inode(1)?.dir_entries()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "base")?;
+
+        // the only file of the base is removed, its chunks aren't needed anymore
+        let delta_dir = dir.path().join("delta");
+        fs::create_dir_all(&delta_dir)?;
+        fs::write(delta_dir.join("foo"), b"foo")?;
+        let (_, image, _) = add_rootfs_delta::<DefaultCompression>(
+            &delta_dir,
+            image,
+            "delta",
+            "base",
+            &BuilderConfig::default(),
+        )?;
+
+        flatten(&image, "delta", "flat")?;
+        let rootfs = Rootfs::try_from(image.open_rootfs_blob("flat", None)?)?;
+        assert_eq!(rootfs.metadatas.len(), 1);
+        assert_eq!(rootfs.fs_verity_data.len(), 1);
+        // the rootfs and the chunk of foo
+        assert_eq!(image.find_manifest("flat")?.unwrap().layers().len(), 2);
+        // the original tag is untouched
+        assert_eq!(
+            Rootfs::try_from(image.open_rootfs_blob("delta", None)?)?
+                .metadatas
+                .len(),
+            2
+        );
+
+        let pfs = PuzzleFS::open(Image::open(dir.path())?, "flat", None)?;
+        assert!(pfs.lookup(Path::new("/SekienAkashita.jpg"))?.is_none());
+        assert_eq!(pfs.lookup(Path::new("/foo"))?.unwrap().file_len()?, 3);
+        Ok(())
+    }
+
     #[test]
     fn test_reproducibility() {
         fn build_dummy_fs(dir: &Path) -> PathBuf {
@@ -767,4 +3087,47 @@ pub mod tests {
             "build not reproducible"
         );
     }
+
+    #[test]
+    fn test_encrypted_chunks() -> anyhow::Result<()> {
+        use crate::oci::encryption::DecryptionKeys;
+        use openssl::rsa::Rsa;
+
+        let dir = tempdir()?;
+        let rsa = Rsa::generate(2048)?;
+        fs::write(dir.path().join("key.pub"), rsa.public_key_to_pem()?)?;
+        fs::write(dir.path().join("key.pem"), rsa.private_key_to_pem()?)?;
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let config = BuilderConfig {
+            encryption: Some(ChunkEncryption::new(&[dir.path().join("key.pub")])?),
+            ..Default::default()
+        };
+        build_initial_rootfs::<Noop>(rootfs, &image, "test", &config)?;
+
+        let manifest = image.find_manifest("test")?.unwrap();
+        let chunk = &manifest.layers()[1];
+        assert!(chunk.media_type().to_string().ends_with("+encrypted"));
+        let original = fs::read(rootfs.join("SekienAkashita.jpg"))?;
+        let blob = image.0.blobs_dir().read(chunk.digest().digest())?;
+        assert!(!blob.windows(64).any(|w| w == &original[..64]));
+
+        // the metadata is in clear, but the contents can't be read without a key
+        let pfs = PuzzleFS::open(Image::open(&oci_dir)?, "test", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        let mut data = Vec::new();
+        assert!(FileReader::new(&pfs.oci, &inode)?
+            .read_to_end(&mut data)
+            .is_err());
+
+        let keys = Arc::new(DecryptionKeys::load(&[dir.path().join("key.pem")])?);
+        let image = Image::open(&oci_dir)?.with_decryption_keys(keys);
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        let mut data = Vec::new();
+        FileReader::new(&pfs.oci, &inode)?.read_to_end(&mut data)?;
+        assert_eq!(data, original);
+        Ok(())
+    }
 }
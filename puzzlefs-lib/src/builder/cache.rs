@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::ChunkerConfig;
+use crate::format::{BlobRef, Digest, FileChunk, Result, VerityData};
+use crate::oci::Image;
+
+/// Identifies a source file across builds. If none of these change, we assume the file content
+/// didn't change either (this is the same heuristic rsync and friends use). The chunker is part
+/// of the key, so a build with another chunker doesn't reuse chunk lists it wouldn't produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    chunker: ChunkerConfig,
+}
+
+impl CacheKey {
+    pub fn new(md: &fs::Metadata, chunker: ChunkerConfig) -> Self {
+        CacheKey {
+            dev: md.dev(),
+            ino: md.ino(),
+            mtime: md.mtime(),
+            mtime_nsec: md.mtime_nsec(),
+            size: md.size(),
+            chunker,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedChunk {
+    digest: Digest,
+    offset: u64,
+    compressed: bool,
+    len: u64,
+    verity: String,
+    // the media type of the blob in the manifest of the build which cached it
+    media_type: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    chunks: Vec<CachedChunk>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    entries: Vec<CacheEntry>,
+}
+
+/// A file's chunk list as recorded by a previous build, along with the fs-verity digests and the
+/// media types of the blobs it references (the new rootfs and manifest need those too).
+pub struct CacheHit {
+    pub chunks: Vec<FileChunk>,
+    pub verity_data: VerityData,
+    pub media_types: HashMap<[u8; 32], String>,
+}
+
+/// The build cache maps source file identities to the chunk lists they had in a previous build,
+/// so that unchanged files don't have to be read and chunked again.
+pub struct BuildCache {
+    path: PathBuf,
+    previous: HashMap<CacheKey, Vec<CachedChunk>>,
+    current: HashMap<CacheKey, Vec<CachedChunk>>,
+}
+
+impl BuildCache {
+    pub fn open(path: &Path) -> Result<Self> {
+        let previous = match fs::read(path) {
+            Ok(contents) => match serde_json::from_slice::<CacheFile>(&contents) {
+                Ok(cache) => cache
+                    .entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.chunks))
+                    .collect(),
+                Err(e) => {
+                    // a corrupt cache is not fatal, we just chunk everything again
+                    warn!("ignoring invalid build cache {}: {e}", path.display());
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(BuildCache {
+            path: path.to_path_buf(),
+            previous,
+            current: HashMap::new(),
+        })
+    }
+
+    /// Returns the chunk list recorded for this file, but only if all the blobs it references
+    /// are still present in the image.
+    pub fn lookup(&self, key: &CacheKey, oci: &Image) -> Result<Option<CacheHit>> {
+        let cached = match self.previous.get(key) {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+
+        let mut chunks = Vec::with_capacity(cached.len());
+        let mut verity_data = VerityData::new();
+        let mut media_types = HashMap::new();
+        for chunk in cached {
+            if !oci.has_blob(&chunk.digest) {
+                return Ok(None);
+            }
+            let verity = hex::decode(&chunk.verity)?;
+            let digest = chunk.digest.underlying();
+            verity_data.insert(digest, verity);
+            media_types.insert(digest, chunk.media_type.clone());
+            chunks.push(FileChunk {
+                blob: BlobRef {
+                    digest,
+                    offset: chunk.offset,
+                    compressed: chunk.compressed,
+                },
+                len: chunk.len,
+            });
+        }
+
+        Ok(Some(CacheHit {
+            chunks,
+            verity_data,
+            media_types,
+        }))
+    }
+
+    /// Records the chunk list of a file for the next build; `verity_data` must contain the
+    /// fs-verity digests of all the blobs referenced by `chunks`, which the manifest of the build
+    /// lists with `media_type`.
+    pub fn insert(
+        &mut self,
+        key: CacheKey,
+        chunks: &[FileChunk],
+        verity_data: &VerityData,
+        media_type: &str,
+    ) {
+        self.insert_with(key, chunks, verity_data, |_| Some(media_type));
+    }
+
+    /// Records the chunk list of a cache hit again, for the next build.
+    pub fn insert_hit(&mut self, key: CacheKey, hit: &CacheHit) {
+        self.insert_with(key, &hit.chunks, &hit.verity_data, |digest| {
+            hit.media_types.get(digest).map(String::as_str)
+        });
+    }
+
+    fn insert_with<'a>(
+        &mut self,
+        key: CacheKey,
+        chunks: &[FileChunk],
+        verity_data: &VerityData,
+        media_type: impl Fn(&[u8; 32]) -> Option<&'a str>,
+    ) {
+        let cached = chunks
+            .iter()
+            .map(|chunk| {
+                let verity = verity_data.get(&chunk.blob.digest)?;
+                Some(CachedChunk {
+                    digest: Digest::new(&chunk.blob.digest),
+                    offset: chunk.blob.offset,
+                    compressed: chunk.blob.compressed,
+                    len: chunk.len,
+                    verity: hex::encode(verity),
+                    media_type: media_type(&chunk.blob.digest)?.to_string(),
+                })
+            })
+            .collect::<Option<Vec<_>>>();
+
+        if let Some(cached) = cached {
+            self.current.insert(key, cached);
+        }
+    }
+
+    /// Writes the cache back to disk. Only the files seen by this build are kept, so the cache
+    /// doesn't grow without bounds as the source tree changes.
+    pub fn save(self) -> Result<()> {
+        let cache = CacheFile {
+            entries: self
+                .current
+                .into_iter()
+                .map(|(key, chunks)| CacheEntry { key, chunks })
+                .collect(),
+        };
+
+        // write to a temporary file and rename it, so an interrupted build doesn't leave a
+        // truncated cache behind
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&cache)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsverity_helpers::VerityHash;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_cache_roundtrip() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        let mut image_manifest = image.get_empty_manifest()?;
+        let blob = image.put_blob::<crate::compression::Noop>(
+            "meshuggah rocks".as_bytes(),
+            &mut image_manifest,
+            crate::oci::media_types::Chunk {},
+            VerityHash::default(),
+        )?;
+        let digest = Digest::try_from(blob.descriptor.digest().digest())?.underlying();
+
+        let source = dir.path().join("source");
+        fs::write(&source, b"meshuggah rocks")?;
+        let md = fs::metadata(&source)?;
+
+        let chunks = vec![FileChunk {
+            blob: BlobRef {
+                digest,
+                offset: 0,
+                compressed: blob.compressed,
+            },
+            len: 15,
+        }];
+        let mut verity_data = VerityData::new();
+        verity_data.insert(digest, blob.fs_verity_digest);
+
+        let cache_path = dir.path().join("cache.json");
+        let chunker = ChunkerConfig::default();
+        let key = CacheKey::new(&md, chunker);
+        let mut cache = BuildCache::open(&cache_path)?;
+        assert!(cache.lookup(&key, &image)?.is_none());
+        cache.insert(
+            key,
+            &chunks,
+            &verity_data,
+            &blob.descriptor.media_type().to_string(),
+        );
+        cache.save()?;
+
+        let cache = BuildCache::open(&cache_path)?;
+        let hit = cache.lookup(&key, &image)?.unwrap();
+        assert_eq!(hit.chunks, chunks);
+        assert_eq!(hit.verity_data, verity_data);
+        // the media type the blob was written with, whatever compression the next build uses
+        assert_eq!(
+            hit.media_types[&digest],
+            blob.descriptor.media_type().to_string()
+        );
+
+        // another chunker doesn't reuse the entry
+        let fixed = ChunkerConfig::Fixed { size: 4096 };
+        assert!(cache.lookup(&CacheKey::new(&md, fixed), &image)?.is_none());
+
+        // touching the file invalidates the entry
+        fs::write(&source, b"meshuggah rocks!")?;
+        let key = CacheKey::new(&fs::metadata(&source)?, chunker);
+        assert!(cache.lookup(&key, &image)?.is_none());
+        Ok(())
+    }
+}
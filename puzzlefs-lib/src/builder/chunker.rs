@@ -0,0 +1,546 @@
+use std::cmp::min;
+use std::fmt;
+use std::io::{self, Read};
+use std::mem;
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+
+use super::filesystem::{SourceChanged, SourceUnreadable};
+use super::BuildError;
+use crate::common::{AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::format::{Result, WireFormatError};
+
+/// Splits a stream into chunks. The boundaries must only depend on the contents of the stream and
+/// on the parameters of the chunker, so that builds are reproducible and the chunks of similar
+/// files are shared.
+pub trait Chunker {
+    /// The next chunk of the stream, or `None` once it's exhausted.
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+/// Which chunker a build uses, and its parameters, in bytes. It's recorded in the manifest, in
+/// the format of [`fmt::Display`], so that deltas are chunked like their base layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChunkerConfig {
+    /// [FastCDC](https://www.usenix.org/conference/atc16/technical-sessions/presentation/xia),
+    /// in its 2020 version with normalized chunking.
+    FastCdc { min: u32, avg: u32, max: u32 },
+    /// Content defined chunking with a plain gear hash, like RapidCDC and QuickCDC: the bytes
+    /// before `min` aren't hashed, and the chunks end when the top bits of the hash are zero,
+    /// which happens about every `avg - min` bytes, or at `max`.
+    Gear { min: u32, avg: u32, max: u32 },
+    /// Chunks of `size` bytes, regardless of their contents. Cheap, but an insertion shifts all
+    /// the chunks after it, so little is shared between versions of a file.
+    Fixed { size: u32 },
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        ChunkerConfig::FastCdc {
+            min: MIN_CHUNK_SIZE,
+            avg: AVG_CHUNK_SIZE,
+            max: MAX_CHUNK_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for ChunkerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkerConfig::FastCdc { min, avg, max } => {
+                write!(f, "fastcdc-v2020 min={min} avg={avg} max={max}")
+            }
+            ChunkerConfig::Gear { min, avg, max } => {
+                write!(f, "gear min={min} avg={avg} max={max}")
+            }
+            ChunkerConfig::Fixed { size } => write!(f, "fixed size={size}"),
+        }
+    }
+}
+
+impl FromStr for ChunkerConfig {
+    type Err = String;
+
+    /// Parses the name of a chunker (`fastcdc`, `gear` or `fixed`) optionally followed by
+    /// `key=value` parameters, the others keeping their default, e.g. `fixed size=65536`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let (mut min, mut avg, mut max) = (MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        let mut size = AVG_CHUNK_SIZE;
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {word}"))?;
+            let value = value
+                .parse()
+                .map_err(|e| format!("invalid value of {key}: {e}"))?;
+            match (name, key) {
+                ("fixed", "size") => size = value,
+                ("fixed", _) => return Err(format!("unknown parameter {key}, expected size")),
+                (_, "min") => min = value,
+                (_, "avg") => avg = value,
+                (_, "max") => max = value,
+                _ => return Err(format!("unknown parameter {key}, expected min, avg or max")),
+            }
+        }
+        let config = match name {
+            "fastcdc" | "fastcdc-v2020" => {
+                // StreamCDC panics outside of these
+                if !(fastcdc::v2020::MINIMUM_MIN..=fastcdc::v2020::MINIMUM_MAX).contains(&min)
+                    || !(fastcdc::v2020::AVERAGE_MIN..=fastcdc::v2020::AVERAGE_MAX).contains(&avg)
+                    || !(fastcdc::v2020::MAXIMUM_MIN..=fastcdc::v2020::MAXIMUM_MAX).contains(&max)
+                {
+                    return Err(format!(
+                        "fastcdc sizes out of range: min={min} avg={avg} max={max}"
+                    ));
+                }
+                ChunkerConfig::FastCdc { min, avg, max }
+            }
+            // the chunks are buffered whole, so they're kept to the sizes fastcdc allows
+            "gear" if max > fastcdc::v2020::MAXIMUM_MAX => {
+                return Err(format!(
+                    "gear max={max} is above {}",
+                    fastcdc::v2020::MAXIMUM_MAX
+                ))
+            }
+            "gear" => ChunkerConfig::Gear { min, avg, max },
+            "fixed" if size > fastcdc::v2020::MAXIMUM_MAX => {
+                return Err(format!(
+                    "fixed size={size} is above {}",
+                    fastcdc::v2020::MAXIMUM_MAX
+                ))
+            }
+            "fixed" if size > 0 => ChunkerConfig::Fixed { size },
+            "fixed" => return Err("the size of fixed chunks can't be 0".to_string()),
+            _ => {
+                return Err(format!(
+                    "unknown chunker {name}, expected fastcdc, gear or fixed"
+                ))
+            }
+        };
+        if let ChunkerConfig::FastCdc { min, avg, max } | ChunkerConfig::Gear { min, avg, max } =
+            config
+        {
+            if min == 0 || min > avg || avg > max {
+                return Err(format!(
+                    "expected 0 < min <= avg <= max, got min={min} avg={avg} max={max}"
+                ));
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl ChunkerConfig {
+    /// A chunker splitting `source`.
+    pub fn chunker(&self, source: Box<dyn Read>) -> Box<dyn Chunker> {
+        let input = Lookahead {
+            source,
+            buf: Vec::new(),
+            eof: false,
+        };
+        match *self {
+            ChunkerConfig::FastCdc { min, avg, max } => {
+                Box::new(FastCdc(StreamCDC::new(input.source, min, avg, max)))
+            }
+            ChunkerConfig::Gear { min, avg, max } => {
+                let bits = (avg - min).max(1).ilog2();
+                Box::new(Gear {
+                    input,
+                    min: min as usize,
+                    max: max as usize,
+                    mask: if bits == 0 { 0 } else { !0 << (64 - bits) },
+                })
+            }
+            ChunkerConfig::Fixed { size } => Box::new(Fixed {
+                input,
+                size: size as usize,
+            }),
+        }
+    }
+
+    /// A chunker splitting each of `sources` on its own, in order, so the chunks of a source
+    /// don't depend on the ones before it; the last chunk of each source is usually shorter than
+    /// the minimum. The sources paired with `true` are read and chunked ahead of time by
+    /// `threads` worker threads, which doesn't change the chunks.
+    pub fn anchored_chunker<R: Read + Send + 'static>(
+        &self,
+        sources: Vec<(R, bool)>,
+        threads: usize,
+    ) -> Box<dyn Chunker> {
+        let config = *self;
+        let mut queue = Vec::new();
+        let sources = sources
+            .into_iter()
+            .map(|(source, parallel)| {
+                if parallel {
+                    let (sender, receiver) = sync_channel(AHEAD_CHUNKS);
+                    queue.push((source, sender));
+                    Source::Worker(receiver)
+                } else {
+                    Source::Inline(source)
+                }
+            })
+            .collect::<Vec<_>>();
+        let workers = Workers::spawn(config, queue, threads);
+        Box::new(Anchored {
+            sources: sources.into_iter(),
+            current: None,
+            config,
+            _workers: workers,
+        })
+    }
+}
+
+// the read errors of the chunkers, telling apart the files which changed during the build and
+// those which can't be read
+fn read_error(e: io::Error) -> WireFormatError {
+    if let Some(changed) = e.get_ref().and_then(|e| e.downcast_ref::<SourceChanged>()) {
+        return BuildError::SourceChanged {
+            path: changed.path.clone(),
+        }
+        .into();
+    }
+    if !e.get_ref().is_some_and(|e| e.is::<SourceUnreadable>()) {
+        return e.into();
+    }
+    // .unwrap()s because the payload was just checked
+    let unreadable = *e
+        .into_inner()
+        .unwrap()
+        .downcast::<SourceUnreadable>()
+        .unwrap();
+    BuildError::Io {
+        op: unreadable.op,
+        path: unreadable.path,
+        source: unreadable.error,
+    }
+    .into()
+}
+
+// how many chunks of a source a worker gets ahead of the build, which bounds the memory the
+// workers use to this many chunks each
+const AHEAD_CHUNKS: usize = 16;
+
+// the chunks of a source chunked by a worker, then None once it's exhausted
+type Chunks = Receiver<Result<Option<Vec<u8>>>>;
+
+enum Source<R> {
+    Inline(R),
+    Worker(Chunks),
+}
+
+struct Received(Chunks);
+
+impl Chunker for Received {
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        // a worker only hangs up before the end of its source if it panicked
+        self.0.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "a chunking worker stopped before the end of its source",
+            )
+            .into())
+        })
+    }
+}
+
+// the threads chunking the sources of an anchored chunker ahead of time. They take the sources in
+// order, and the chunker reads them in order, so the oldest source is always being chunked and
+// the workers blocked on the next ones can't hold it up.
+struct Workers(Vec<JoinHandle<()>>);
+
+impl Workers {
+    fn spawn<R: Read + Send + 'static>(
+        config: ChunkerConfig,
+        queue: Vec<(R, SyncSender<Result<Option<Vec<u8>>>>)>,
+        threads: usize,
+    ) -> Self {
+        let threads = min(threads.max(1), queue.len());
+        let queue = Arc::new(Mutex::new(queue.into_iter()));
+        let workers = (0..threads)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                thread::spawn(move || loop {
+                    let Some((source, sender)) = queue.lock().unwrap().next() else {
+                        return;
+                    };
+                    let mut chunker = config.chunker(Box::new(source));
+                    loop {
+                        let chunk = chunker.next_chunk();
+                        let done = !matches!(chunk, Ok(Some(_)));
+                        // the chunker was dropped: the build is over
+                        if sender.send(chunk).is_err() {
+                            return;
+                        }
+                        if done {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+        Workers(workers)
+    }
+}
+
+impl Drop for Workers {
+    // the receivers are dropped by then, so the workers stop at their next chunk
+    fn drop(&mut self) {
+        for worker in self.0.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Anchored<R> {
+    sources: std::vec::IntoIter<Source<R>>,
+    current: Option<Box<dyn Chunker>>,
+    config: ChunkerConfig,
+    // after the receivers, which must be dropped before the workers are joined
+    _workers: Workers,
+}
+
+impl<R: Read + 'static> Chunker for Anchored<R> {
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(current) = &mut self.current {
+                if let Some(chunk) = current.next_chunk()? {
+                    return Ok(Some(chunk));
+                }
+            }
+            self.current = match self.sources.next() {
+                Some(Source::Inline(source)) => Some(self.config.chunker(Box::new(source))),
+                Some(Source::Worker(receiver)) => Some(Box::new(Received(receiver))),
+                None => return Ok(None),
+            };
+        }
+    }
+}
+
+struct FastCdc(StreamCDC);
+
+impl Chunker for FastCdc {
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.0.next() {
+            None => Ok(None),
+            Some(Ok(chunk)) => Ok(Some(chunk.data)),
+            Some(Err(fastcdc::v2020::Error::IoError(e))) => Err(read_error(e)),
+            Some(Err(e)) => {
+                Err(io::Error::new(io::ErrorKind::Other, format!("chunker error: {e:?}")).into())
+            }
+        }
+    }
+}
+
+// the bytes of the source which aren't chunked yet
+struct Lookahead {
+    source: Box<dyn Read>,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl Lookahead {
+    // reads until `len` bytes are buffered, or the source is exhausted
+    fn fill(&mut self, len: usize) -> Result<&[u8]> {
+        while !self.eof && self.buf.len() < len {
+            let start = self.buf.len();
+            self.buf.resize(len, 0);
+            match self.source.read(&mut self.buf[start..]) {
+                Ok(n) => {
+                    self.buf.truncate(start + n);
+                    self.eof = n == 0;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(start),
+                Err(e) => return Err(read_error(e)),
+            }
+        }
+        Ok(&self.buf)
+    }
+
+    fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let rest = self.buf.split_off(len);
+        Some(mem::replace(&mut self.buf, rest))
+    }
+}
+
+// the random values the gear hash adds up, from splitmix64 with a fixed seed: changing them would
+// change the chunks of all the images built with the gear chunker
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+struct Gear {
+    input: Lookahead,
+    min: usize,
+    max: usize,
+    mask: u64,
+}
+
+// where the chunk at the start of `data` ends; each bit of the hash depends on the bytes shifted
+// in since, so the top bits depend on the last 64 bytes
+fn gear_cut(data: &[u8], min: usize, mask: u64) -> usize {
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().skip(min) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    data.len()
+}
+
+impl Chunker for Gear {
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let data = self.input.fill(self.max)?;
+        let len = gear_cut(data, self.min, self.mask);
+        Ok(self.input.take(len))
+    }
+}
+
+struct Fixed {
+    input: Lookahead,
+    size: usize,
+}
+
+impl Chunker for Fixed {
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let len = self.input.fill(self.size)?.len();
+        Ok(self.input.take(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::XorShift;
+
+    fn lengths(config: &str, data: &[u8]) -> anyhow::Result<Vec<usize>> {
+        let mut chunker = config
+            .parse::<ChunkerConfig>()
+            .map_err(anyhow::Error::msg)?
+            .chunker(Box::new(io::Cursor::new(data.to_vec())));
+        let mut lengths = Vec::new();
+        while let Some(chunk) = chunker.next_chunk()? {
+            lengths.push(chunk.len());
+        }
+        Ok(lengths)
+    }
+
+    #[test]
+    fn test_chunkers() -> anyhow::Result<()> {
+        let data = XorShift::new(0x2545f491).bytes(1024 * 1024);
+
+        assert_eq!(
+            lengths("fixed size=300000", &data)?,
+            [300000, 300000, 300000, 148576]
+        );
+        for config in ["fastcdc", "gear min=4096 avg=16384 max=65536"] {
+            let lengths = lengths(config, &data)?;
+            assert_eq!(lengths.iter().sum::<usize>(), data.len());
+            assert!(lengths.len() > 1, "{config} found no boundary");
+        }
+        let gear = lengths("gear min=4096 avg=16384 max=65536", &data)?;
+        assert!(gear.iter().all(|&len| len <= 65536));
+        assert!(gear[..gear.len() - 1].iter().all(|&len| len >= 4096));
+
+        // content defined chunks resynchronize after an insertion
+        let mut shifted = b"meshuggah rocks".to_vec();
+        shifted.extend_from_slice(&data);
+        let shifted = lengths("gear min=4096 avg=16384 max=65536", &shifted)?;
+        assert_eq!(gear[gear.len() - 3..], shifted[shifted.len() - 3..]);
+        assert!(lengths("fixed", &[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_anchored_chunker() -> anyhow::Result<()> {
+        let sources = [
+            vec![1; 5000],
+            vec![],
+            vec![2; 3000],
+            vec![3; 100_000],
+            vec![4; 9000],
+        ];
+        let chunks = |parallel: &[usize], threads| -> anyhow::Result<Vec<Vec<u8>>> {
+            let sources = sources
+                .iter()
+                .enumerate()
+                .map(|(i, data)| (io::Cursor::new(data.clone()), parallel.contains(&i)))
+                .collect();
+            let mut chunker =
+                ChunkerConfig::Fixed { size: 4096 }.anchored_chunker(sources, threads);
+            let mut chunks = Vec::new();
+            while let Some(chunk) = chunker.next_chunk()? {
+                chunks.push(chunk);
+            }
+            Ok(chunks)
+        };
+        let sequential = chunks(&[], 0)?;
+        let lengths = sequential.iter().map(Vec::len).collect::<Vec<_>>();
+        assert_eq!(lengths[..3], [4096, 904, 3000]);
+        assert_eq!(lengths.len(), 3 + 100_000_usize.div_ceil(4096) + 3);
+        // the workers don't change the chunks, nor their order
+        assert_eq!(chunks(&[0, 3, 4], 2)?, sequential);
+        assert_eq!(chunks(&[1, 2, 3, 4], 1)?, sequential);
+
+        // dropping the chunker halfway stops the workers
+        let sources = (0..8)
+            .map(|_| (io::Cursor::new(vec![0; 1 << 20]), true))
+            .collect();
+        let mut chunker = ChunkerConfig::Fixed { size: 4096 }.anchored_chunker(sources, 4);
+        chunker.next_chunk()?;
+        drop(chunker);
+
+        // a worker which hangs up early doesn't pass for the end of its source
+        let (sender, receiver) = sync_channel(1);
+        sender.send(Ok(Some(vec![0]))).unwrap();
+        drop(sender);
+        let mut received = Received(receiver);
+        assert_eq!(received.next_chunk()?, Some(vec![0]));
+        assert!(received.next_chunk().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunker_config() {
+        for config in [
+            ChunkerConfig::default(),
+            ChunkerConfig::Gear {
+                min: 1,
+                avg: 2,
+                max: 3,
+            },
+            ChunkerConfig::Fixed { size: 4096 },
+        ] {
+            assert_eq!(config.to_string().parse(), Ok(config));
+        }
+        assert_eq!("fastcdc".parse(), Ok(ChunkerConfig::default()));
+        assert!("fixed size=0".parse::<ChunkerConfig>().is_err());
+        assert!("fixed size=4294967295".parse::<ChunkerConfig>().is_err());
+        assert!("gear max=1073741824".parse::<ChunkerConfig>().is_err());
+        assert!("gear min=10 avg=5".parse::<ChunkerConfig>().is_err());
+        assert!("fastcdc min=1".parse::<ChunkerConfig>().is_err());
+        assert!("rabin".parse::<ChunkerConfig>().is_err());
+    }
+}
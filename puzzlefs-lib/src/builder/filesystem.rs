@@ -1,57 +1,214 @@
+use std::cmp::min;
+use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::io;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 
+use tracing::warn;
+
 struct ReaderLink {
     file: PathBuf,
-    done: bool,
+    // the size of the file when it was walked
+    size: u64,
+}
+
+struct CurrentReader {
+    // None once the file ended early, the rest of it is read as zeroes
+    file: Option<fs::File>,
+    left: u64,
+}
+
+/// The error a [`FilesystemStream`] fails with when one of its files doesn't have the size it had
+/// when it was pushed.
+#[derive(Debug)]
+pub struct SourceChanged {
+    pub path: PathBuf,
+    how: &'static str,
+}
+
+impl fmt::Display for SourceChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} during the build", self.path.display(), self.how)
+    }
+}
+
+impl Error for SourceChanged {}
+
+/// The error a [`FilesystemStream`] fails with when one of its files can't be opened or read,
+/// e.g. for lack of permissions.
+#[derive(Debug)]
+pub struct SourceUnreadable {
+    pub path: PathBuf,
+    pub op: &'static str,
+    pub error: io::Error,
+}
+
+impl fmt::Display for SourceUnreadable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot {} {}: {}",
+            self.op,
+            self.path.display(),
+            self.error
+        )
+    }
+}
+
+impl Error for SourceUnreadable {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+fn unreadable(path: &Path, op: &'static str, error: io::Error) -> io::Error {
+    let kind = error.kind();
+    let unreadable = SourceUnreadable {
+        path: path.to_path_buf(),
+        op,
+        error,
+    };
+    io::Error::new(kind, unreadable)
 }
 
 /// A structure used to chain multiple readers, similar to
 /// [chain](https://doc.rust-lang.org/std/io/trait.Read.html#method.chain)
 /// and [multi_reader](https://docs.rs/multi_reader/latest/multi_reader/)
+///
+/// Each file is read at the size it had when it was pushed, since the chunks are split between
+/// the files according to it. The files which changed since then fail the read with a
+/// [`SourceChanged`] error, or with `ignore_changes`, are truncated or padded with zeroes.
 pub struct FilesystemStream {
     reader_chain: Vec<ReaderLink>,
-    current_reader: Option<std::fs::File>,
+    next: usize,
+    current_reader: Option<CurrentReader>,
+    ignore_changes: bool,
 }
 
 impl FilesystemStream {
-    pub fn new() -> Self {
+    pub fn new(ignore_changes: bool) -> Self {
         FilesystemStream {
             reader_chain: Vec::new(),
+            next: 0,
             current_reader: None,
+            ignore_changes,
         }
     }
 
-    pub fn push(&mut self, file: &Path) {
+    pub fn push(&mut self, file: &Path, size: u64) {
         self.reader_chain.push(ReaderLink {
             file: file.into(),
-            done: false,
+            size,
+        })
+    }
+
+    /// Splits the stream into consecutive streams. `alone` is given the size of each file, and
+    /// the files for which it returns `Some(tag)` get a stream of their own, paired with `tag`;
+    /// the others go in the same stream as the files before them, paired with `false`.
+    pub fn split(self, alone: impl Fn(u64) -> Option<bool>) -> Vec<(FilesystemStream, bool)> {
+        let mut streams: Vec<(FilesystemStream, bool)> = Vec::new();
+        let mut last_alone = true;
+        for link in self.reader_chain {
+            let tag = alone(link.size);
+            if tag.is_none() && !last_alone {
+                // .unwrap() because the previous file is in the last stream
+                streams.last_mut().unwrap().0.reader_chain.push(link);
+            } else {
+                let mut stream = FilesystemStream::new(self.ignore_changes);
+                stream.reader_chain.push(link);
+                streams.push((stream, tag.unwrap_or(false)));
+            }
+            last_alone = tag.is_some();
+        }
+        streams
+    }
+
+    fn changed(&self, index: usize, how: &'static str) -> io::Result<()> {
+        let changed = SourceChanged {
+            path: self.reader_chain[index].file.clone(),
+            how,
+        };
+        if !self.ignore_changes {
+            return Err(io::Error::new(io::ErrorKind::Other, changed));
+        }
+        warn!("{changed}, storing it at its former size");
+        Ok(())
+    }
+
+    fn open(&self, index: usize) -> io::Result<CurrentReader> {
+        let link = &self.reader_chain[index];
+        let file = match fs::File::open(&link.file) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.changed(index, "disappeared")?;
+                None
+            }
+            Err(e) => return Err(unreadable(&link.file, "open", e)),
+        };
+        Ok(CurrentReader {
+            file,
+            left: link.size,
         })
     }
 }
 
 impl Read for FilesystemStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        for link in &mut self.reader_chain {
-            if link.done {
-                continue;
-            }
-
+        loop {
             let current_reader = match self.current_reader.as_mut() {
                 Some(reader) => reader,
-                None => self.current_reader.insert(std::fs::File::open(&link.file)?),
+                None if self.next < self.reader_chain.len() => {
+                    let reader = self.open(self.next)?;
+                    self.next += 1;
+                    self.current_reader.insert(reader)
+                }
+                None => return Ok(0),
             };
 
-            match current_reader.read(buf)? {
-                0 if !buf.is_empty() => {
-                    self.current_reader = None;
-                    link.done = true
+            if current_reader.left == 0 {
+                // the file must end where it did when it was walked
+                let grew = match &mut current_reader.file {
+                    Some(file) => {
+                        let path = &self.reader_chain[self.next - 1].file;
+                        file.read(&mut [0])
+                            .map_err(|e| unreadable(path, "read", e))?
+                            > 0
+                    }
+                    None => false,
+                };
+                self.current_reader = None;
+                if grew {
+                    self.changed(self.next - 1, "grew")?;
                 }
-                n => return Ok(n),
+                continue;
+            }
+            if buf.is_empty() {
+                return Ok(0);
             }
+
+            let want = min(buf.len() as u64, current_reader.left) as usize;
+            let n = match &mut current_reader.file {
+                Some(file) => {
+                    let path = &self.reader_chain[self.next - 1].file;
+                    file.read(&mut buf[..want])
+                        .map_err(|e| unreadable(path, "read", e))?
+                }
+                None => 0,
+            };
+            let n = if n == 0 {
+                current_reader.file = None;
+                buf[..want].fill(0);
+                self.changed(self.next - 1, "shrank")?;
+                want
+            } else {
+                n
+            };
+            // .unwrap() here because the reader was just used
+            self.current_reader.as_mut().unwrap().left -= n as u64;
+            return Ok(n);
         }
-        Ok(0)
     }
 }
 
@@ -78,10 +235,10 @@ pub mod tests {
         file2.write_all(b"dolor sit amet, ")?;
         file3.write_all(b"consectetur adipiscing elit.")?;
 
-        let mut fs_stream = FilesystemStream::new();
-        fs_stream.push(&file_name1);
-        fs_stream.push(&file_name2);
-        fs_stream.push(&file_name3);
+        let mut fs_stream = FilesystemStream::new(false);
+        fs_stream.push(&file_name1, 12);
+        fs_stream.push(&file_name2, 16);
+        fs_stream.push(&file_name3, 28);
 
         fs_stream.read_to_end(&mut buffer)?;
         assert_eq!(
@@ -91,4 +248,39 @@ pub mod tests {
 
         Ok(())
     }
+    #[test]
+    fn test_changed_files() -> anyhow::Result<()> {
+        let dir = tempdir().unwrap();
+        let foo = dir.path().join("foo");
+        let bar = dir.path().join("bar");
+        fs::write(&foo, b"foo")?;
+        fs::write(&bar, b"bar")?;
+
+        let read = |ignore_changes, sizes: &[u64]| {
+            let mut fs_stream = FilesystemStream::new(ignore_changes);
+            let files = [foo.clone(), bar.clone(), dir.path().join("baz")];
+            for (file, size) in files.iter().zip(sizes) {
+                fs_stream.push(file, *size);
+            }
+            let mut buffer = Vec::new();
+            fs_stream.read_to_end(&mut buffer).map(|_| buffer)
+        };
+        assert_eq!(read(false, &[3, 3])?, b"foobar");
+
+        // foo grew, bar shrank, baz disappeared
+        for sizes in [&[2][..], &[3, 5], &[3, 3, 1]] {
+            let e = read(false, sizes).unwrap_err();
+            let changed = e.get_ref().unwrap().downcast_ref::<SourceChanged>();
+            assert!(changed.is_some(), "{e}");
+        }
+        // a file which can't be read names itself
+        let mut fs_stream = FilesystemStream::new(false);
+        fs_stream.push(dir.path(), 1);
+        let e = fs_stream.read(&mut [0]).unwrap_err();
+        let unreadable = e.get_ref().unwrap().downcast_ref::<SourceUnreadable>();
+        assert_eq!(unreadable.unwrap().path, dir.path());
+        // with changes ignored, the files keep their former sizes
+        assert_eq!(read(true, &[2, 5, 1])?, b"fobar\0\0\0");
+        Ok(())
+    }
 }
@@ -0,0 +1,65 @@
+//! Reading the upper directory of an overlay mounted on an image as changes to the image.
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+
+// the upper directories of kernel overlays use trusted xattrs, or user ones when mounted with
+// userxattr
+const OVERLAY_XATTR_PREFIXES: [&str; 2] = ["trusted.overlay.", "user.overlay."];
+
+/// What an entry of an upper directory stands for.
+pub(crate) enum UpperEntry {
+    /// A file added or changed in the overlay.
+    File,
+    /// The file of the image with this name was deleted.
+    Whiteout(OsString),
+    /// The directory hides everything the image has at its path.
+    OpaqueMarker,
+}
+
+fn overlay_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    // trusted xattrs can't be read without CAP_SYS_ADMIN, in which case the overlay couldn't
+    // have set them either
+    OVERLAY_XATTR_PREFIXES
+        .iter()
+        .find_map(|prefix| xattr::get(path, format!("{prefix}{name}")).ok().flatten())
+}
+
+pub(crate) fn upper_entry(entry: &fs::DirEntry, md: &fs::Metadata) -> io::Result<UpperEntry> {
+    let name = entry.file_name();
+    if name.as_bytes() == OPAQUE_MARKER {
+        return Ok(UpperEntry::OpaqueMarker);
+    }
+    if let Some(target) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX) {
+        return Ok(UpperEntry::Whiteout(OsString::from_vec(target.to_vec())));
+    }
+    if md.file_type().is_char_device() && md.rdev() == 0 {
+        return Ok(UpperEntry::Whiteout(name));
+    }
+
+    // these leave the content of the file or directory in the lower layers, which an image
+    // can't point at
+    for unsupported in ["metacopy", "redirect"] {
+        if overlay_xattr(&entry.path(), unsupported).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} relies on the overlay {unsupported} feature, which images can't express",
+                    entry.path().display()
+                ),
+            ));
+        }
+    }
+    Ok(UpperEntry::File)
+}
+
+/// Whether the directory `path` of an upper directory is marked opaque with an xattr.
+pub(crate) fn is_opaque(path: &Path) -> bool {
+    overlay_xattr(path, "opaque").is_some_and(|value| value == b"y")
+}
@@ -0,0 +1,111 @@
+use std::io;
+use std::path::Path;
+
+use cap_std::fs::Dir;
+use nix::errno::Errno;
+use tracing::debug;
+
+use crate::format::Result;
+use crate::oci::Image;
+
+/// A directory of content addressed chunks shared between images. It has the same layout as the
+/// blobs directory of an OCI image (i.e. `sha256/<digest>`), so the blobs directory of an existing
+/// image can be used as a pool directly.
+///
+/// Chunks are hard linked between the pool and the images built against it, so identical chunks
+/// are only stored once on disk, no matter which image (or which base layer) they came from.
+pub struct ChunkPool {
+    dir: Dir,
+}
+
+impl ChunkPool {
+    pub fn open(path: &Path) -> Result<Self> {
+        let blobs = path.join("sha256");
+        std::fs::create_dir_all(&blobs)?;
+        let dir = Dir::open_ambient_dir(blobs, cap_std::ambient_authority())?;
+        Ok(ChunkPool { dir })
+    }
+
+    /// Whether the pool has the blob `digest`.
+    pub fn contains(&self, digest: &str) -> bool {
+        self.dir.exists(digest)
+    }
+
+    /// Shares the blob `digest`, which was just written to the image, with the pool. If the pool
+    /// already has this blob, the image's copy is replaced with a link to the pool's copy and
+    /// true is returned; otherwise the image's copy is added to the pool.
+    pub fn share(&self, oci: &Image, digest: &str) -> Result<bool> {
+        let blobs_dir = oci.0.blobs_dir();
+
+        if self.dir.exists(digest) {
+            // link next to the blob and rename it over, so the image never misses the blob
+            let tmp = format!("{digest}.pool");
+            match self.dir.hard_link(digest, blobs_dir, &tmp) {
+                Ok(()) => {
+                    blobs_dir.rename(&tmp, blobs_dir, digest)?;
+                    Ok(true)
+                }
+                Err(e) if e.raw_os_error() == Some(Errno::EXDEV as i32) => {
+                    // the pool lives on a different filesystem, keep the copy we have
+                    debug!("cannot link pool blob {digest}: {e}");
+                    Ok(false)
+                }
+                Err(e) => Err(e.into()),
+            }
+        } else {
+            match blobs_dir.hard_link(digest, &self.dir, digest) {
+                Ok(()) => Ok(false),
+                // someone else added this blob to the pool in the meantime
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+                Err(e) if e.raw_os_error() == Some(Errno::EXDEV as i32) => {
+                    blobs_dir.copy(digest, &self.dir, digest)?;
+                    Ok(false)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Noop;
+    use crate::fsverity_helpers::VerityHash;
+    use crate::oci::media_types::Chunk;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pool_links_blobs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let pool = ChunkPool::open(&dir.path().join("pool"))?;
+
+        let image1 = Image::new(&dir.path().join("image1"))?;
+        let mut manifest1 = image1.get_empty_manifest()?;
+        let blob = image1.put_blob::<Noop>(
+            b"meshuggah rocks",
+            &mut manifest1,
+            Chunk {},
+            VerityHash::default(),
+        )?;
+        let digest = blob.descriptor.digest().digest();
+        assert!(!pool.share(&image1, digest)?);
+
+        let image2 = Image::new(&dir.path().join("image2"))?;
+        let mut manifest2 = image2.get_empty_manifest()?;
+        image2.put_blob::<Noop>(
+            b"meshuggah rocks",
+            &mut manifest2,
+            Chunk {},
+            VerityHash::default(),
+        )?;
+        assert!(pool.share(&image2, digest)?);
+
+        let md1 = image1.0.blobs_dir().metadata(digest)?;
+        let md2 = image2.0.blobs_dir().metadata(digest)?;
+        assert_eq!(md1.ino(), md2.ino());
+        assert_eq!(md1.nlink(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use std::backtrace::Backtrace;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io::BufWriter;
+use std::mem::size_of;
+
+use capnp::{message, serialize};
+use memmap2::Mmap;
+use tracing::debug;
+
+use crate::format::{
+    DirEnt, FileChunk, Ino, Inode, InodeAdditional, InodeMode, InodeVector, Result,
+    WireFormatError, Xattr,
+};
+use crate::metadata_capnp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Inode,
+    // the chunks of a regular file, whose inode was rendered before the file went through the
+    // chunker; they're carried by an otherwise empty file inode
+    ChunkList,
+}
+
+type Source<'a> = Box<dyn Iterator<Item = Result<Inode>> + 'a>;
+
+/// The inodes rendered by a build, and the chunk lists of its regular files. They're kept in
+/// memory up to a budget; past it, they're written to a temporary file in groups sorted by inode
+/// number, which are merged back when the metadata is serialized. Without a budget, everything
+/// stays in memory.
+pub(crate) struct InodeSpill {
+    budget: Option<u64>,
+    bytes: u64,
+    inodes: Vec<Inode>,
+    chunk_lists: Vec<Inode>,
+    // the number of inodes, spilled or not
+    len: usize,
+    file: Option<BufWriter<fs::File>>,
+    groups: Vec<Kind>,
+}
+
+impl InodeSpill {
+    pub(crate) fn new(budget: Option<u64>) -> Self {
+        InodeSpill {
+            budget,
+            bytes: 0,
+            inodes: Vec::new(),
+            chunk_lists: Vec::new(),
+            len: 0,
+            file: None,
+            groups: Vec::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn push(&mut self, inode: Inode) -> Result<()> {
+        self.len += 1;
+        self.add(Kind::Inode, inode)
+    }
+
+    /// Sets the chunks of the regular file `ino`, pushed before with no chunks, and its digest if
+    /// it was only known once the file went through the chunker.
+    pub(crate) fn push_chunks(
+        &mut self,
+        ino: Ino,
+        chunks: Vec<FileChunk>,
+        digest: Option<[u8; 32]>,
+    ) -> Result<()> {
+        let chunk_list = Inode {
+            ino,
+            mode: InodeMode::File {
+                chunks,
+                inline: None,
+            },
+            uid: 0,
+            gid: 0,
+            permissions: 0,
+            additional: digest.map(|digest| InodeAdditional {
+                digest: Some(digest),
+                ..Default::default()
+            }),
+        };
+        self.add(Kind::ChunkList, chunk_list)
+    }
+
+    fn add(&mut self, kind: Kind, inode: Inode) -> Result<()> {
+        self.bytes += inode_bytes(&inode);
+        match kind {
+            Kind::Inode => self.inodes.push(inode),
+            Kind::ChunkList => self.chunk_lists.push(inode),
+        }
+        if self.budget.is_some_and(|budget| self.bytes > budget) {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(BufWriter::new(tempfile::tempfile()?));
+        }
+        // .unwrap() here because it was just created
+        let file = self.file.as_mut().unwrap();
+        for (kind, inodes) in [
+            (Kind::Inode, &mut self.inodes),
+            (Kind::ChunkList, &mut self.chunk_lists),
+        ] {
+            if inodes.is_empty() {
+                continue;
+            }
+            debug!("spilling {} {kind:?}s to disk", inodes.len());
+            inodes.sort_by_key(|inode| inode.ino);
+            let mut message = message::Builder::new_default();
+            let mut builder = message.init_root::<metadata_capnp::inode_vector::Builder<'_>>();
+            InodeVector::fill_capnp(inodes, &mut builder)?;
+            serialize::write_message(&mut *file, &message)?;
+            self.groups.push(kind);
+            inodes.clear();
+        }
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// Calls `f` on each inode, in inode number order, with the chunks of the regular files set.
+    pub(crate) fn into_each(mut self, mut f: impl FnMut(Inode) -> Result<()>) -> Result<()> {
+        let map = match self.file.take() {
+            Some(file) => {
+                let file = file.into_inner().map_err(|e| e.into_error())?;
+                // the file is ours alone, nobody changes it under the map
+                Some(unsafe { Mmap::map(&file)? })
+            }
+            None => None,
+        };
+        // we wrote these messages ourselves
+        let options = message::ReaderOptions {
+            traversal_limit_in_words: None,
+            nesting_limit: 64,
+        };
+        let mut messages = Vec::with_capacity(self.groups.len());
+        if let Some(map) = &map {
+            let mut groups = &map[..];
+            for _ in &self.groups {
+                messages.push(serialize::read_message_from_flat_slice(
+                    &mut groups,
+                    options,
+                )?);
+            }
+        }
+
+        let mut sources = Vec::<(Kind, Source<'_>)>::new();
+        for (message, kind) in messages.iter().zip(&self.groups) {
+            let inodes = message
+                .get_root::<metadata_capnp::inode_vector::Reader<'_>>()?
+                .get_inodes()?;
+            sources.push((
+                *kind,
+                Box::new(inodes.iter().map(Inode::from_capnp)) as Source<'_>,
+            ));
+        }
+        self.inodes.sort_by_key(|inode| inode.ino);
+        self.chunk_lists.sort_by_key(|inode| inode.ino);
+        sources.push((Kind::Inode, Box::new(self.inodes.into_iter().map(Ok))));
+        sources.push((
+            Kind::ChunkList,
+            Box::new(self.chunk_lists.into_iter().map(Ok)),
+        ));
+
+        // merge the sources, the chunk list of a file coming right after its inode
+        let mut heads = Vec::with_capacity(sources.len());
+        let mut heap = BinaryHeap::new();
+        for (i, (kind, source)) in sources.iter_mut().enumerate() {
+            let head = source.next().transpose()?;
+            if let Some(inode) = &head {
+                heap.push(Reverse((inode.ino, *kind, i)));
+            }
+            heads.push(head);
+        }
+
+        let mut pending: Option<Inode> = None;
+        while let Some(Reverse((_, kind, i))) = heap.pop() {
+            // .unwrap() here because only the sources with a head are in the heap
+            let inode = heads[i].take().unwrap();
+            heads[i] = sources[i].1.next().transpose()?;
+            if let Some(next) = &heads[i] {
+                heap.push(Reverse((next.ino, kind, i)));
+            }
+
+            match kind {
+                Kind::Inode => {
+                    if let Some(done) = pending.replace(inode) {
+                        f(done)?;
+                    }
+                }
+                Kind::ChunkList => set_chunks(pending.as_mut(), inode)?,
+            }
+        }
+        pending.map(f).transpose()?;
+        Ok(())
+    }
+
+    pub(crate) fn into_inodes(self) -> Result<Vec<Inode>> {
+        let mut inodes = Vec::with_capacity(self.len);
+        self.into_each(|inode| {
+            inodes.push(inode);
+            Ok(())
+        })?;
+        Ok(inodes)
+    }
+}
+
+fn set_chunks(file: Option<&mut Inode>, chunk_list: Inode) -> Result<()> {
+    match (file, chunk_list.mode) {
+        (
+            Some(Inode {
+                ino,
+                mode: InodeMode::File { chunks, .. },
+                additional,
+                ..
+            }),
+            InodeMode::File { chunks: list, .. },
+        ) if *ino == chunk_list.ino => {
+            *chunks = list;
+            if let Some(digest) = chunk_list.additional.and_then(|a| a.digest) {
+                additional.get_or_insert_with(Default::default).digest = Some(digest);
+            }
+            Ok(())
+        }
+        _ => Err(WireFormatError::InvalidSerializedData(Backtrace::capture())),
+    }
+}
+
+// roughly what an inode takes in memory
+fn inode_bytes(inode: &Inode) -> u64 {
+    let mode = match &inode.mode {
+        InodeMode::Dir { dir_list } => dir_list
+            .entries
+            .iter()
+            .map(|entry| size_of::<DirEnt>() + entry.name.len())
+            .sum(),
+        InodeMode::File { chunks, inline } => {
+            chunks.len() * size_of::<FileChunk>() + inline.as_ref().map_or(0, Vec::len)
+        }
+        _ => 0,
+    };
+    let additional = inode.additional.as_ref().map_or(0, |additional| {
+        let xattrs: usize = additional
+            .xattrs
+            .iter()
+            .map(|xattr| size_of::<Xattr>() + xattr.key.len() + xattr.val.len())
+            .sum();
+        size_of::<InodeAdditional>()
+            + xattrs
+            + additional.symlink_target.as_ref().map_or(0, Vec::len)
+    });
+    (size_of::<Inode>() + mode + additional) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{BlobRef, DirList};
+
+    fn file(ino: Ino) -> Inode {
+        Inode {
+            ino,
+            mode: InodeMode::File {
+                chunks: Vec::new(),
+                inline: None,
+            },
+            uid: 0,
+            gid: 0,
+            permissions: 0o644,
+            additional: None,
+        }
+    }
+
+    fn chunks(n: u64) -> Vec<FileChunk> {
+        (0..n)
+            .map(|len| FileChunk {
+                blob: BlobRef {
+                    digest: [n as u8; 32],
+                    offset: 0,
+                    compressed: true,
+                },
+                len,
+            })
+            .collect()
+    }
+
+    fn spilled(budget: Option<u64>) -> anyhow::Result<Vec<Inode>> {
+        let mut spill = InodeSpill::new(budget);
+        spill.push(Inode::new_whiteout(7))?;
+        for ino in [5, 2, 3] {
+            spill.push(file(ino))?;
+        }
+        spill.push_chunks(3, chunks(3), None)?;
+        spill.push(Inode {
+            ino: 1,
+            mode: InodeMode::Dir {
+                dir_list: DirList {
+                    entries: Vec::new(),
+                    look_below: false,
+                    opaque: false,
+                },
+            },
+            uid: 0,
+            gid: 0,
+            permissions: 0o755,
+            additional: None,
+        })?;
+        spill.push_chunks(5, chunks(5), Some([5; 32]))?;
+        spill.push_chunks(2, Vec::new(), None)?;
+        assert_eq!(spill.len(), 5);
+        Ok(spill.into_inodes()?)
+    }
+
+    #[test]
+    fn test_spill() -> anyhow::Result<()> {
+        let inodes = spilled(None)?;
+        assert_eq!(
+            inodes.iter().map(|inode| inode.ino).collect::<Vec<_>>(),
+            [1, 2, 3, 5, 7]
+        );
+        let InodeMode::File { chunks: five, .. } = &inodes[3].mode else {
+            panic!("bad inode mode: {:?}", inodes[3].mode);
+        };
+        assert_eq!(five, &chunks(5));
+        assert_eq!(inodes[3].file_digest(), Some([5; 32]));
+
+        // spilling every single inode gives the same result
+        assert_eq!(spilled(Some(0))?, inodes);
+
+        let mut spill = InodeSpill::new(None);
+        spill.push_chunks(1, chunks(1), None)?;
+        assert!(spill.into_inodes().is_err());
+        Ok(())
+    }
+}
@@ -1,5 +1,72 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
 // Quoting from https://github.com/ronomon/deduplication
 // An average chunk size of 64 KB is recommended for optimal end-to-end deduplication and compression efficiency
 pub const MIN_CHUNK_SIZE: u32 = 16 * 1024;
 pub const AVG_CHUNK_SIZE: u32 = 64 * 1024;
 pub const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+// the size past which a pack of small chunks is written out
+pub const PACK_SIZE: u32 = 1024 * 1024;
+
+// formats seconds since the epoch as an RFC 3339 date, in UTC
+pub(crate) fn rfc3339(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // the civil_from_days algorithm of Howard Hinnant, for days since 1970-01-01
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// mountinfo escapes spaces, tabs, newlines and backslashes in octal, e.g. \040
+pub(crate) fn unescape_mountinfo(field: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let octal = field
+            .get(i + 1..i + 4)
+            .filter(|_| field[i] == b'\\')
+            .and_then(|octal| std::str::from_utf8(octal).ok())
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match octal {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            None => {
+                unescaped.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    unescaped
+}
+
+// the mountpoints of the mount namespace of this process, none without /proc
+pub(crate) fn mountpoints() -> io::Result<Vec<PathBuf>> {
+    let mountinfo = match fs::read("/proc/self/mountinfo") {
+        Ok(mountinfo) => mountinfo,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(mountinfo
+        .split(|b| *b == b'\n')
+        .filter_map(|line| line.split(|b| *b == b' ').nth(4))
+        .map(|mountpoint| PathBuf::from(OsString::from_vec(unescape_mountinfo(mountpoint))))
+        .collect())
+}
@@ -0,0 +1,464 @@
+//! Binary deltas between two tags, to update an image over the wire.
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use ocidir::oci_spec::image::{Descriptor, ImageManifest, MediaType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as Sha2Digest, Sha256};
+use tracing::info_span;
+
+use crate::builder::squash_layers;
+use crate::compression::{Compression, Zstd};
+use crate::format::{BlobRef, InodeMode, Result, Rootfs, WireFormatError};
+use crate::oci::media_types::PUZZLEFS_ROOTFS;
+use crate::oci::{Digest, Image, ImageError};
+
+const DELTA_VERSION: u64 = 1;
+const HEADER: &str = "delta.json";
+// patches are made once and sent many times, so they're worth compressing hard
+const PATCH_LEVEL: i32 = 19;
+// the largest blob a delta may carry or patch, which bounds the memory applying it takes
+const MAX_BLOB_SIZE: u64 = 1 << 30;
+// the largest header, which lists every blob of the delta
+const MAX_HEADER_SIZE: u64 = 64 << 20;
+
+/// The first entry of a delta.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaHeader {
+    pub version: u64,
+    /// The manifest of the tag the delta applies to.
+    pub base: Descriptor,
+    /// The tag the delta creates and its manifest.
+    pub tag: String,
+    pub manifest: Descriptor,
+    /// The blobs in the delta, in the order of their entries.
+    pub blobs: Vec<DeltaBlob>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeltaBlob {
+    /// The blob as stored in the image.
+    Full { digest: String },
+    /// A patch of the uncompressed contents of the blob `base`. The patched contents are `size`
+    /// bytes long and compressed again if `compressed`, which gives back the blob.
+    Patch {
+        digest: String,
+        base: String,
+        base_compressed: bool,
+        compressed: bool,
+        size: u64,
+    },
+}
+
+impl DeltaBlob {
+    pub fn digest(&self) -> &str {
+        match self {
+            DeltaBlob::Full { digest } | DeltaBlob::Patch { digest, .. } => digest,
+        }
+    }
+}
+
+/// What went into a delta.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeltaStats {
+    /// blobs sent as they are
+    pub full_blobs: u64,
+    /// blobs sent as a patch
+    pub patched_blobs: u64,
+    /// size of the blobs the new tag doesn't share with the old one
+    pub blob_bytes: u64,
+    /// size of what's actually sent for them
+    pub delta_bytes: u64,
+}
+
+impl fmt::Display for DeltaStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "full blobs: {}", self.full_blobs)?;
+        writeln!(f, "patched blobs: {}", self.patched_blobs)?;
+        writeln!(f, "blob bytes: {}", self.blob_bytes)?;
+        write!(f, "delta bytes: {}", self.delta_bytes)
+    }
+}
+
+fn delta_error(message: String) -> WireFormatError {
+    WireFormatError::DeltaError(message, Backtrace::capture())
+}
+
+fn blob_digest(desc: &Descriptor) -> String {
+    desc.digest().digest().to_string()
+}
+
+fn blob_path(digest: &str) -> PathBuf {
+    Image::blob_path().join(digest)
+}
+
+// reads an entry of a delta, failing if it's larger than `limit`
+fn read_entry(entry: impl Read, limit: u64, what: &str) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    entry.take(limit + 1).read_to_end(&mut contents)?;
+    if contents.len() as u64 > limit {
+        return Err(delta_error(format!("{what} is larger than {limit} bytes")));
+    }
+    Ok(contents)
+}
+
+// the uncompressed contents of a blob
+fn read_contents(oci: &Image, digest: &str, compressed: bool) -> Result<Vec<u8>> {
+    if !compressed {
+        return Ok(oci.0.blobs_dir().read(digest)?);
+    }
+    let mut contents = Vec::new();
+    oci.open_compressed_blob::<Zstd>(&Digest::try_from(digest)?, None)?
+        .read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+// the blob of `contents`, compressed like `Image::put_blob` does
+fn store_contents(contents: &[u8], compressed: bool) -> Result<Vec<u8>> {
+    if !compressed {
+        return Ok(contents.to_vec());
+    }
+    let mut stored = Cursor::new(Vec::new());
+    let mut compressor = Zstd::compress(&mut stored)?;
+    compressor.write_all(contents)?;
+    compressor.end()?;
+    Ok(stored.into_inner())
+}
+
+// the chunks of the regular files of `tag`, by path
+fn file_chunks(oci: &Image, tag: &str) -> Result<HashMap<PathBuf, Vec<BlobRef>>> {
+    let rootfs = Rootfs::try_from(oci.open_rootfs_blob(tag, None)?)?;
+    let mut inodes = squash_layers(rootfs.metadatas)?
+        .into_iter()
+        .map(|inode| (inode.ino, inode))
+        .collect::<HashMap<_, _>>();
+    let mut files = HashMap::new();
+    let mut todo = vec![(PathBuf::from("/"), 1)];
+    while let Some((path, ino)) = todo.pop() {
+        let Some(inode) = inodes.remove(&ino) else {
+            continue;
+        };
+        match inode.mode {
+            InodeMode::Dir { dir_list } => todo.extend(
+                dir_list
+                    .entries
+                    .into_iter()
+                    .map(|entry| (path.join(OsStr::from_bytes(&entry.name)), entry.ino)),
+            ),
+            InodeMode::File { chunks, .. } => {
+                files.insert(path, chunks.into_iter().map(|chunk| chunk.blob).collect());
+            }
+            _ => (),
+        }
+    }
+    Ok(files)
+}
+
+// patches the contents of the blob `digest` against the ones of `base`, if the blob can be rebuilt
+// from its contents and the patch is smaller than the blob
+fn make_patch(
+    oci: &Image,
+    digest: &str,
+    stored: &[u8],
+    (base, base_compressed, compressed): &(String, bool, bool),
+) -> Result<Option<(DeltaBlob, Vec<u8>)>> {
+    let contents = read_contents(oci, digest, *compressed)?;
+    if *compressed && store_contents(&contents, true)? != stored {
+        return Ok(None);
+    }
+    let dictionary = read_contents(oci, base, *base_compressed)?;
+    let patch =
+        zstd::bulk::Compressor::with_dictionary(PATCH_LEVEL, &dictionary)?.compress(&contents)?;
+    if patch.len() >= stored.len() {
+        return Ok(None);
+    }
+    let blob = DeltaBlob::Patch {
+        digest: digest.to_string(),
+        base: base.clone(),
+        base_compressed: *base_compressed,
+        compressed: *compressed,
+        size: contents.len() as u64,
+    };
+    Ok(Some((blob, patch)))
+}
+
+/// Writes to `out` the delta which turns an image with `base_tag` into one with `tag` as well.
+pub fn create_delta(oci: &Image, base_tag: &str, tag: &str, out: impl Write) -> Result<DeltaStats> {
+    let _span = info_span!("delta", base_tag, tag).entered();
+    let missing = |tag: &str| ImageError::MissingManifest {
+        tag: tag.to_string(),
+    };
+    let base_desc = oci
+        .find_manifest_descriptor(base_tag)?
+        .ok_or_else(|| missing(base_tag))?;
+    let desc = oci
+        .find_manifest_descriptor(tag)?
+        .ok_or_else(|| missing(tag))?;
+    let base_manifest: ImageManifest = oci.0.read_json_blob(&base_desc)?;
+    let manifest: ImageManifest = oci.0.read_json_blob(&desc)?;
+
+    let mut have = base_manifest
+        .layers()
+        .iter()
+        .chain([base_manifest.config(), &base_desc])
+        .map(blob_digest)
+        .collect::<HashSet<_>>();
+
+    // the blob of the base each new blob is likely to resemble, whether it's compressed and
+    // whether the new blob is
+    let mut bases = HashMap::<String, (String, bool, bool)>::new();
+    let rootfs_media_type = MediaType::Other(PUZZLEFS_ROOTFS.to_string());
+    let rootfs = |manifest: &ImageManifest| {
+        manifest
+            .layers()
+            .iter()
+            .find(|desc| desc.media_type() == &rootfs_media_type)
+            .map(blob_digest)
+    };
+    if let (Some(new), Some(old)) = (rootfs(&manifest), rootfs(&base_manifest)) {
+        bases.insert(new, (old, false, false));
+    }
+    let base_files = file_chunks(oci, base_tag)?;
+    for (path, chunks) in file_chunks(oci, tag)? {
+        let Some(base_chunks) = base_files.get(&path).filter(|chunks| !chunks.is_empty()) else {
+            continue;
+        };
+        for (i, chunk) in chunks.iter().enumerate() {
+            let base = &base_chunks[i.min(base_chunks.len() - 1)];
+            bases
+                .entry(Digest::new(&chunk.digest).to_string())
+                .or_insert_with(|| {
+                    (
+                        Digest::new(&base.digest).to_string(),
+                        base.compressed,
+                        chunk.compressed,
+                    )
+                });
+        }
+    }
+
+    // the payloads are spooled to a temporary file, the header has to be written first
+    let mut payloads = tempfile::tempfile()?;
+    let mut header = DeltaHeader {
+        version: DELTA_VERSION,
+        base: base_desc,
+        tag: tag.to_string(),
+        manifest: desc.clone(),
+        blobs: Vec::new(),
+    };
+    let mut sizes = Vec::new();
+    let mut stats = DeltaStats::default();
+    for blob in [&desc, manifest.config()]
+        .into_iter()
+        .chain(manifest.layers())
+    {
+        let digest = blob_digest(blob);
+        if !have.insert(digest.clone()) {
+            continue;
+        }
+        let stored = oci.0.blobs_dir().read(&digest)?;
+        let patch = match bases.get(&digest) {
+            Some(base) if oci.0.blobs_dir().exists(&base.0) => {
+                make_patch(oci, &digest, &stored, base)?
+            }
+            _ => None,
+        };
+        let (entry, payload) = match patch {
+            Some(patch) => {
+                stats.patched_blobs += 1;
+                patch
+            }
+            None => {
+                stats.full_blobs += 1;
+                (DeltaBlob::Full { digest }, stored.clone())
+            }
+        };
+        stats.blob_bytes += stored.len() as u64;
+        stats.delta_bytes += payload.len() as u64;
+        payloads.write_all(&payload)?;
+        sizes.push(payload.len() as u64);
+        header.blobs.push(entry);
+    }
+
+    let mut archive = tar::Builder::new(out);
+    let mut append = |path: &Path, size: u64, data: &mut dyn Read| {
+        let mut entry = tar::Header::new_gnu();
+        entry.set_size(size);
+        entry.set_mode(0o644);
+        entry.set_mtime(0);
+        archive.append_data(&mut entry, path, data)
+    };
+    let header_buf = serde_json::to_vec(&header)?;
+    append(
+        Path::new(HEADER),
+        header_buf.len() as u64,
+        &mut header_buf.as_slice(),
+    )?;
+    payloads.rewind()?;
+    for (blob, size) in header.blobs.iter().zip(sizes) {
+        append(
+            &blob_path(blob.digest()),
+            size,
+            &mut (&mut payloads).take(size),
+        )?;
+    }
+    archive.into_inner()?.flush()?;
+    Ok(stats)
+}
+
+/// Applies the delta read from `input` to `oci`, which must have the manifest the delta was made
+/// against. Every blob is checked against its digest before being written. Returns the descriptor
+/// of the manifest of the new tag.
+pub fn apply_delta(oci: &Image, input: impl Read) -> Result<Descriptor> {
+    let mut archive = tar::Archive::new(input);
+    let mut entries = archive.entries()?;
+    let header: DeltaHeader = match entries.next() {
+        Some(entry) => {
+            let entry = entry?;
+            if entry.path()? != Path::new(HEADER) {
+                return Err(delta_error(format!(
+                    "the delta doesn't start with {HEADER}"
+                )));
+            }
+            serde_json::from_slice(&read_entry(entry, MAX_HEADER_SIZE, HEADER)?)?
+        }
+        None => return Err(delta_error("the delta is empty".to_string())),
+    };
+    if header.version != DELTA_VERSION {
+        return Err(delta_error(format!(
+            "unsupported delta version {}",
+            header.version
+        )));
+    }
+    let _span = info_span!("apply delta", tag = header.tag).entered();
+    if !oci.0.blobs_dir().exists(blob_digest(&header.base)) {
+        return Err(delta_error(format!(
+            "the delta applies to the manifest {}, which isn't in the image",
+            header.base.digest()
+        )));
+    }
+
+    let mut blobs = header.blobs.iter();
+    for entry in entries {
+        let mut entry = entry?;
+        let blob = blobs
+            .next()
+            .ok_or_else(|| delta_error("the delta has more entries than blobs".to_string()))?;
+        if entry.path()? != blob_path(blob.digest()) {
+            return Err(delta_error(format!(
+                "unexpected entry {}",
+                entry.path()?.display()
+            )));
+        }
+        let payload = read_entry(&mut entry, MAX_BLOB_SIZE, blob.digest())?;
+        let stored = match blob {
+            DeltaBlob::Full { .. } => payload,
+            DeltaBlob::Patch {
+                base,
+                base_compressed,
+                compressed,
+                size,
+                ..
+            } => {
+                // the contents are decompressed into a buffer of `size` bytes
+                if *size > MAX_BLOB_SIZE {
+                    return Err(delta_error(format!(
+                        "{} patches {size} bytes, more than {MAX_BLOB_SIZE}",
+                        blob.digest()
+                    )));
+                }
+                let dictionary = read_contents(oci, base, *base_compressed)?;
+                let contents = zstd::bulk::Decompressor::with_dictionary(&dictionary)?
+                    .decompress(&payload, usize::try_from(*size)?)?;
+                store_contents(&contents, *compressed)?
+            }
+        };
+        let digest = hex::encode(Sha256::digest(&stored));
+        if digest != blob.digest() {
+            // the delta was made against another image
+            return Err(ImageError::DigestMismatch {
+                expected: blob.digest().to_string(),
+                found: digest,
+            }
+            .into());
+        }
+        if !oci.0.blobs_dir().exists(&digest) {
+            oci.write_blob_file(&digest, &stored)?;
+        }
+    }
+    if blobs.next().is_some() {
+        return Err(delta_error("the delta is truncated".to_string()));
+    }
+
+    let manifest: ImageManifest = oci.0.read_json_blob(&header.manifest)?;
+    for desc in manifest.layers().iter().chain([manifest.config()]) {
+        if !oci.0.blobs_dir().exists(blob_digest(desc)) {
+            return Err(WireFormatError::from(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "blob {} is neither in the image nor in the delta",
+                    desc.digest()
+                ),
+            )));
+        }
+    }
+    oci.tag_descriptor(header.manifest, &header.tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{add_rootfs_delta, build_test_fs, BuilderConfig};
+    use crate::reader::PuzzleFS;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_delta() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("old"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "v1")?;
+
+        // the same file with a few bytes changed, which gives new chunks
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        let mut jpg = fs::read("src/builder/test/test-1/SekienAkashita.jpg")?;
+        jpg[5000..5010].copy_from_slice(b"0123456789");
+        fs::write(rootfs.join("SekienAkashita.jpg"), &jpg)?;
+        let (_, image, _) =
+            add_rootfs_delta::<Zstd>(&rootfs, image, "v2", "v1", &BuilderConfig::default())?;
+
+        let mut delta = Vec::new();
+        let stats = create_delta(&image, "v1", "v2", &mut delta)?;
+        assert!(stats.patched_blobs > 0);
+        assert!(stats.delta_bytes < stats.blob_bytes);
+
+        // a copy of the image with only v1
+        let copy = dir.path().join("copy");
+        let copy_image = Image::new(&copy)?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &copy_image, "v1")?;
+        let desc = apply_delta(&copy_image, Cursor::new(&delta))?;
+        assert_eq!(
+            desc.digest(),
+            image.find_manifest_descriptor("v2")?.unwrap().digest()
+        );
+        let pfs = PuzzleFS::open(Image::open(&copy)?, "v2", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        assert_eq!(inode.file_len()?, jpg.len() as u64);
+
+        // without v1, there's nothing to apply the delta to
+        let empty = Image::new(&dir.path().join("empty"))?;
+        assert!(apply_delta(&empty, Cursor::new(&delta)).is_err());
+
+        // entries are only read up to their limit
+        assert_eq!(read_entry(Cursor::new([0; 10]), 10, "blob")?.len(), 10);
+        assert!(read_entry(Cursor::new([0; 10]), 9, "blob").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! What takes up the room in an image, per directory like `du` and per chunk.
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use ocidir::oci_spec::image::MediaType;
+use serde::Serialize;
+
+use crate::format::{FileChunk, InodeMode, Result};
+use crate::oci::media_types::PUZZLEFS_ROOTFS;
+use crate::oci::{Image, ImageError};
+use crate::reader::{DirEntry, PuzzleFS, WalkPuzzleFS};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub files: u64,
+    pub logical: u64,
+    pub compressed: u64,
+    pub physical: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DirUsage {
+    pub path: PathBuf,
+    /// How many directories deep the path is, the root being 0.
+    pub depth: usize,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    /// Every directory of the image, sorted by path.
+    pub dirs: Vec<DirUsage>,
+    /// The size of the metadata blobs, which aren't charged to any directory.
+    pub metadata: u64,
+}
+
+impl DiskUsage {
+    /// The directories at most `max_depth` levels below the root.
+    pub fn up_to(&self, max_depth: usize) -> impl Iterator<Item = &DirUsage> {
+        self.dirs.iter().filter(move |dir| dir.depth <= max_depth)
+    }
+
+    /// The usage of the directory at `path`, if the image has one.
+    pub fn get(&self, path: &Path) -> Option<&Usage> {
+        self.dirs
+            .iter()
+            .find(|dir| dir.path == path)
+            .map(|dir| &dir.usage)
+    }
+}
+
+fn human(size: u64) -> String {
+    const UNITS: [&str; 5] = ["", "K", "M", "G", "T"];
+    let mut scaled = size as f64;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size}")
+    } else {
+        format!("{scaled:.1}{}", UNITS[unit])
+    }
+}
+
+impl fmt::Display for DirUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>8}  {:>10}  {:>8}  {}",
+            human(self.usage.logical),
+            human(self.usage.compressed),
+            human(self.usage.physical),
+            self.path.display()
+        )
+    }
+}
+
+/// A piece of a blob used as a chunk by the files of an image, for finding out what's shared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChunkUse {
+    /// The digest of the blob, which the chunk is only part of when the chunk is in a pack.
+    pub digest: String,
+    pub offset: u64,
+    pub size: u64,
+    /// The compressed size of the chunk, prorated like the physical sizes of [`disk_usage`].
+    pub stored: u64,
+    /// How many times files use the chunk, counting a file once per time it has the chunk.
+    pub refcount: u64,
+    /// The files which use the chunk, in the order they're found.
+    pub files: Vec<PathBuf>,
+}
+
+/// The chunks of an image and the files using them, in the order they're first used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChunkGraph {
+    pub chunks: Vec<ChunkUse>,
+}
+
+// quotes a CSV field if it needs to
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+impl ChunkGraph {
+    /// The `n` chunks with the most references, the bigger ones first among those with as many.
+    pub fn top(&self, n: usize) -> Vec<&ChunkUse> {
+        let mut chunks = self.chunks.iter().collect::<Vec<_>>();
+        chunks.sort_by_key(|chunk| Reverse((chunk.refcount, chunk.size)));
+        chunks.truncate(n);
+        chunks
+    }
+
+    /// Writes one line per file using each chunk, `digest,offset,size,stored,refcount,file`, so a
+    /// chunk used by several files takes several lines.
+    pub fn write_csv(&self, mut w: impl Write) -> io::Result<()> {
+        writeln!(w, "digest,offset,size,stored,refcount,file")?;
+        for chunk in &self.chunks {
+            for file in &chunk.files {
+                writeln!(
+                    w,
+                    "{},{},{},{},{},{}",
+                    chunk.digest,
+                    chunk.offset,
+                    chunk.size,
+                    chunk.stored,
+                    chunk.refcount,
+                    csv_field(&file.to_string_lossy())
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// the sizes of the chunk blobs of `tag`, from its manifest, and the size of its metadata
+fn blob_sizes(oci: &Image, tag: &str) -> Result<(HashMap<String, u64>, u64)> {
+    let manifest = oci
+        .find_manifest(tag)?
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: tag.to_string(),
+        })?;
+    let mut sizes = HashMap::new();
+    let mut metadata = 0;
+    for layer in manifest.layers() {
+        match layer.media_type() {
+            MediaType::Other(media_type) if media_type.starts_with(PUZZLEFS_ROOTFS) => {
+                metadata += layer.size();
+            }
+            _ => {
+                sizes.insert(layer.digest().digest().to_string(), layer.size());
+            }
+        }
+    }
+    Ok((sizes, metadata))
+}
+
+// the paths of the directories and the regular files of the image, hard links once
+fn walk(pfs: &mut PuzzleFS) -> Result<(Vec<PathBuf>, Vec<DirEntry>)> {
+    let mut seen = HashSet::new();
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    for entry in WalkPuzzleFS::walk(pfs)? {
+        let entry = entry?;
+        match &entry.inode.mode {
+            InodeMode::Dir { .. } => dirs.push(entry.path),
+            InodeMode::File { .. } if seen.insert(entry.inode.ino) => files.push(entry),
+            _ => (),
+        }
+    }
+    Ok((dirs, files))
+}
+
+fn chunks(file: &DirEntry) -> &[FileChunk] {
+    match &file.inode.mode {
+        InodeMode::File { chunks, .. } => chunks,
+        _ => &[],
+    }
+}
+
+// prorates the compressed sizes of the blobs among the bytes of the chunks in them
+struct BlobSizes {
+    sizes: HashMap<String, u64>,
+    // how far into each blob the chunks go
+    ends: HashMap<[u8; 32], u64>,
+}
+
+impl BlobSizes {
+    fn new(sizes: HashMap<String, u64>, files: &[DirEntry]) -> Self {
+        let mut ends = HashMap::new();
+        for chunk in files.iter().flat_map(chunks) {
+            let end = ends.entry(chunk.blob.digest).or_insert(0);
+            *end = (*end).max(chunk.blob.offset + chunk.len);
+        }
+        BlobSizes { sizes, ends }
+    }
+
+    fn stored(&self, chunk: &FileChunk) -> f64 {
+        let end = self.ends[&chunk.blob.digest];
+        let size = self
+            .sizes
+            .get(&hex::encode(chunk.blob.digest))
+            .copied()
+            .unwrap_or(end);
+        chunk.len as f64 * size as f64 / end.max(1) as f64
+    }
+}
+
+// the usage of a directory while it's summed up, the shares of the chunks being fractions of bytes
+#[derive(Default)]
+struct Sum {
+    files: u64,
+    logical: u64,
+    compressed: f64,
+    physical: f64,
+}
+
+/// Returns the usage of every directory of `tag`.
+pub fn disk_usage(oci: Image, tag: &str, manifest_verity: Option<&[u8]>) -> Result<DiskUsage> {
+    let (sizes, metadata) = blob_sizes(&oci, tag)?;
+    let mut pfs = PuzzleFS::open(oci, tag, manifest_verity)?;
+    let (dirs, files) = walk(&mut pfs)?;
+    let blobs = BlobSizes::new(sizes, &files);
+    let mut dirs = dirs
+        .into_iter()
+        .map(|dir| (dir, Sum::default()))
+        .collect::<BTreeMap<_, _>>();
+    // how many files use each piece of the blobs
+    let mut users = HashMap::new();
+    for chunk in files.iter().flat_map(chunks) {
+        *users
+            .entry((chunk.blob.digest, chunk.blob.offset, chunk.len))
+            .or_insert(0u64) += 1;
+    }
+
+    for file in &files {
+        let mut usage = Sum {
+            files: 1,
+            logical: file.inode.file_len()?,
+            ..Default::default()
+        };
+        for chunk in chunks(file) {
+            let compressed = blobs.stored(chunk);
+            let users = users[&(chunk.blob.digest, chunk.blob.offset, chunk.len)];
+            usage.compressed += compressed;
+            usage.physical += compressed / users as f64;
+        }
+        for dir in file.path.ancestors().skip(1) {
+            if let Some(sum) = dirs.get_mut(dir) {
+                sum.files += usage.files;
+                sum.logical += usage.logical;
+                sum.compressed += usage.compressed;
+                sum.physical += usage.physical;
+            }
+        }
+    }
+
+    let dirs = dirs
+        .into_iter()
+        .map(|(path, sum)| DirUsage {
+            depth: path.components().count() - 1,
+            path,
+            usage: Usage {
+                files: sum.files,
+                logical: sum.logical,
+                compressed: sum.compressed.round() as u64,
+                physical: sum.physical.round() as u64,
+            },
+        })
+        .collect();
+    Ok(DiskUsage { dirs, metadata })
+}
+
+/// Returns the chunks of `tag` along with the files using them.
+pub fn chunk_graph(oci: Image, tag: &str, manifest_verity: Option<&[u8]>) -> Result<ChunkGraph> {
+    let (sizes, _) = blob_sizes(&oci, tag)?;
+    let mut pfs = PuzzleFS::open(oci, tag, manifest_verity)?;
+    let (_, files) = walk(&mut pfs)?;
+    let blobs = BlobSizes::new(sizes, &files);
+    let mut uses = Vec::<ChunkUse>::new();
+    let mut indexes = HashMap::new();
+    for file in &files {
+        for chunk in chunks(file) {
+            let key = (chunk.blob.digest, chunk.blob.offset, chunk.len);
+            let index = *indexes.entry(key).or_insert_with(|| {
+                uses.push(ChunkUse {
+                    digest: hex::encode(chunk.blob.digest),
+                    offset: chunk.blob.offset,
+                    size: chunk.len,
+                    stored: blobs.stored(chunk).round() as u64,
+                    refcount: 0,
+                    files: Vec::new(),
+                });
+                uses.len() - 1
+            });
+            let chunk = &mut uses[index];
+            chunk.refcount += 1;
+            if chunk.files.last() != Some(&file.path) {
+                chunk.files.push(file.path.clone());
+            }
+        }
+    }
+    Ok(ChunkGraph { chunks: uses })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{build_initial_rootfs, BuilderConfig};
+    use crate::compression::Zstd;
+    use crate::test_utils::XorShift;
+    use std::fs;
+    use tempfile::tempdir;
+
+    // two copies of a file and some noise, in directories of their own
+    fn build_copies(dir: &Path) -> anyhow::Result<(Image, u64, u64)> {
+        let rootfs = dir.join("rootfs");
+        let original = Path::new("src/builder/test/test-1/SekienAkashita.jpg");
+        for copy in ["a/b", "c"] {
+            fs::create_dir_all(rootfs.join(copy))?;
+            fs::copy(original, rootfs.join(copy).join("image.jpg"))?;
+        }
+        fs::create_dir_all(rootfs.join("d"))?;
+        let noise = XorShift::new(0x2545f491).bytes(64 * 1024);
+        fs::write(rootfs.join("d/noise"), &noise)?;
+
+        let image = Image::new(&dir.join("oci"))?;
+        // so that the copies have the same chunks, rather than the chunks of the second one
+        // starting where the last chunk of the first one ends
+        let config = BuilderConfig {
+            dedup_files: true,
+            ..Default::default()
+        };
+        build_initial_rootfs::<Zstd>(&rootfs, &image, "test", &config)?;
+        Ok((image, fs::metadata(original)?.len(), noise.len() as u64))
+    }
+
+    #[test]
+    fn test_disk_usage() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let (image, size, noise) = build_copies(dir.path())?;
+        let usage = disk_usage(image, "test", None)?;
+        assert!(usage.metadata > 0);
+        assert_eq!(
+            usage.dirs.iter().map(|dir| &dir.path).collect::<Vec<_>>(),
+            ["/", "/a", "/a/b", "/c", "/d"].map(Path::new)
+        );
+        assert_eq!(usage.up_to(1).count(), 4);
+
+        let root = usage.get(Path::new("/")).unwrap();
+        assert_eq!(root.files, 3);
+        assert_eq!(root.logical, 2 * size + noise);
+        assert_eq!(usage.get(Path::new("/a")), usage.get(Path::new("/a/b")));
+
+        // the copies share their chunks
+        let copy = usage.get(Path::new("/c")).unwrap();
+        assert_eq!(copy.logical, size);
+        assert!(copy.physical.abs_diff(copy.compressed / 2) <= 1);
+        // random data doesn't compress, and isn't shared
+        let unique = usage.get(Path::new("/d")).unwrap();
+        assert!(unique.compressed >= noise);
+        assert_eq!(unique.physical, unique.compressed);
+        assert!(root.physical.abs_diff(copy.compressed + unique.compressed) <= 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_graph() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let (image, size, noise) = build_copies(dir.path())?;
+        let graph = chunk_graph(image, "test", None)?;
+        assert_eq!(
+            graph.chunks.iter().map(|chunk| chunk.size).sum::<u64>(),
+            size + noise
+        );
+
+        let top = graph.top(1);
+        assert_eq!(top[0].refcount, 2);
+        let mut files = top[0].files.clone();
+        files.sort();
+        assert_eq!(files, ["/a/b/image.jpg", "/c/image.jpg"].map(PathBuf::from));
+        assert!(graph
+            .top(usize::MAX)
+            .windows(2)
+            .all(|w| w[0].refcount >= w[1].refcount));
+
+        let mut csv = Vec::new();
+        graph.write_csv(&mut csv)?;
+        let csv = String::from_utf8(csv)?;
+        assert_eq!(
+            csv.lines().count(),
+            graph
+                .chunks
+                .iter()
+                .map(|chunk| chunk.files.len())
+                .sum::<usize>()
+                + 1
+        );
+        assert!(csv.starts_with("digest,offset,size,stored,refcount,file\n"));
+        // a line for each of the files of the most used chunk
+        let prefix = format!("{},{},", top[0].digest, top[0].offset);
+        assert_eq!(
+            csv.lines().filter(|line| line.starts_with(&prefix)).count(),
+            2
+        );
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+        Ok(())
+    }
+}
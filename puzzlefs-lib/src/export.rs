@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use nix::sys::stat::SFlag;
+use tar::{EntryType, Header};
+use tracing::info;
+
+use crate::format::{Ino, Inode, InodeMode, Result};
+
+pub mod composefs;
+pub mod content_manifest;
+pub mod oci_layer;
+pub mod squashfs;
+
+// the file type bits of st_mode for an inode, None for whiteouts and unknown inodes, which
+// can't be represented in a regular filesystem
+fn file_type(mode: &InodeMode) -> Option<SFlag> {
+    match mode {
+        InodeMode::Fifo => Some(SFlag::S_IFIFO),
+        InodeMode::Chr { .. } => Some(SFlag::S_IFCHR),
+        InodeMode::Dir { .. } => Some(SFlag::S_IFDIR),
+        InodeMode::Blk { .. } => Some(SFlag::S_IFBLK),
+        InodeMode::File { .. } => Some(SFlag::S_IFREG),
+        InodeMode::Lnk => Some(SFlag::S_IFLNK),
+        InodeMode::Sock => Some(SFlag::S_IFSOCK),
+        InodeMode::Unknown | InodeMode::Wht => None,
+    }
+}
+
+// appends `inode` to `tar` as `path`, xattrs included (as PAX SCHILY.xattr records), or as a hard
+// link to the path it was first appended as, which `seen` keeps; `open` opens the contents of
+// regular files
+fn append_inode<W: Write, R: Read>(
+    tar: &mut tar::Builder<W>,
+    seen: &mut HashMap<Ino, PathBuf>,
+    path: &Path,
+    inode: &Inode,
+    open: impl FnOnce() -> Result<R>,
+) -> anyhow::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_uid(inode.uid.into());
+    header.set_gid(inode.gid.into());
+    header.set_mtime(0);
+    header.set_mode(inode.permissions.into());
+    header.set_size(0);
+
+    if let Some(target) = seen.get(&inode.ino) {
+        header.set_entry_type(EntryType::Link);
+        tar.append_link(&mut header, path, target)?;
+        return Ok(());
+    }
+    seen.insert(inode.ino, path.to_path_buf());
+
+    if file_type(&inode.mode).is_none() {
+        bail!("cannot export inode {} of type {:?}", inode.ino, inode.mode);
+    }
+    // tar has no representation for sockets, and they are meaningless in an image anyway
+    if matches!(inode.mode, InodeMode::Sock) {
+        info!("skipping socket {}", path.display());
+        return Ok(());
+    }
+
+    if let Some(additional) = &inode.additional {
+        let xattrs = additional
+            .xattrs
+            .iter()
+            .map(|x| {
+                (
+                    format!("SCHILY.xattr.{}", String::from_utf8_lossy(&x.key)),
+                    &x.val[..],
+                )
+            })
+            .collect::<Vec<_>>();
+        if !xattrs.is_empty() {
+            tar.append_pax_extensions(xattrs.iter().map(|(k, v)| (k.as_str(), *v)))?;
+        }
+    }
+
+    match &inode.mode {
+        InodeMode::File { .. } => {
+            info!("exporting {}", path.display());
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(inode.file_len()?);
+            tar.append_data(&mut header, path, open()?)?;
+        }
+        InodeMode::Dir { .. } => {
+            header.set_entry_type(EntryType::Directory);
+            tar.append_data(&mut header, path, std::io::empty())?;
+        }
+        InodeMode::Lnk => {
+            header.set_entry_type(EntryType::Symlink);
+            tar.append_link(&mut header, path, Path::new(inode.symlink_target()?))?;
+        }
+        InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+            header.set_entry_type(if matches!(inode.mode, InodeMode::Chr { .. }) {
+                EntryType::Char
+            } else {
+                EntryType::Block
+            });
+            header.set_device_major((*major).try_into()?)?;
+            header.set_device_minor((*minor).try_into()?)?;
+            tar.append_data(&mut header, path, std::io::empty())?;
+        }
+        InodeMode::Fifo => {
+            header.set_entry_type(EntryType::Fifo);
+            tar.append_data(&mut header, path, std::io::empty())?;
+        }
+        InodeMode::Sock | InodeMode::Unknown | InodeMode::Wht => unreachable!(),
+    }
+    Ok(())
+}
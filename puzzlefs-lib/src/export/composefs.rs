@@ -0,0 +1,188 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use fs_verity::FsVeritySha256;
+use nix::sys::stat::makedev;
+use sha2::Digest;
+use tracing::info;
+
+use super::file_type;
+use crate::format::InodeMode;
+use crate::oci::Image;
+use crate::reader::{PuzzleFS, WalkPuzzleFS};
+
+// escapes a field of the composefs dump format, see composefs-dump(5)
+fn escape(out: &mut Vec<u8>, field: &[u8], escape_eq: bool) {
+    if field.is_empty() {
+        out.push(b'-');
+        return;
+    }
+    for &b in field {
+        if b.is_ascii_graphic() && b != b'\\' && !(escape_eq && b == b'=') {
+            out.push(b);
+        } else {
+            out.extend_from_slice(format!("\\x{b:02x}").as_bytes());
+        }
+    }
+}
+
+// copies `reader` into the objects directory, named after its fs-verity digest like composefs
+// expects, and returns the object's path relative to the objects directory and its digest
+fn write_object(objects: &Path, mut reader: impl Read) -> io::Result<(String, String)> {
+    let mut tmp = tempfile::NamedTempFile::new_in(objects)?;
+    let mut verity = FsVeritySha256::new();
+    let mut buf = vec![0; 128 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        tmp.write_all(&buf[..n])?;
+        verity.write_all(&buf[..n])?;
+    }
+
+    let digest = hex::encode(verity.finalize());
+    let object = format!("{}/{}", &digest[..2], &digest[2..]);
+    let path = objects.join(&object);
+    // identical files are only stored once
+    if !path.exists() {
+        fs::create_dir_all(objects.join(&digest[..2]))?;
+        tmp.persist(&path)?;
+    }
+    Ok((object, digest))
+}
+
+/// Renders `tag` into a composefs image: the file contents go to `out_dir/objects` and the
+/// metadata to `out_dir/<tag>.cfs` (an EROFS image), which mounts without FUSE using
+/// `mount -t composefs <tag>.cfs -o basedir=objects`. The image is generated by `mkcomposefs`
+/// from the dump file in `out_dir/<tag>.dump`.
+pub fn export_composefs(oci_dir: &Path, tag: &str, out_dir: &Path) -> anyhow::Result<PathBuf> {
+    let image = Image::open(oci_dir)?;
+    let objects = out_dir.join("objects");
+    fs::create_dir_all(&objects)?;
+
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    let entries = WalkPuzzleFS::walk(&mut pfs)?.collect::<crate::format::Result<Vec<_>>>()?;
+
+    let dirs = entries
+        .iter()
+        .filter(|e| matches!(e.inode.mode, InodeMode::Dir { .. }))
+        .map(|e| e.inode.ino)
+        .collect::<HashSet<_>>();
+    let mut nlinks = HashMap::<u64, u64>::new();
+    for entry in &entries {
+        match &entry.inode.mode {
+            InodeMode::Dir { dir_list } => {
+                let subdirs = dir_list
+                    .entries
+                    .iter()
+                    .filter(|de| dirs.contains(&de.ino))
+                    .count() as u64;
+                nlinks.insert(entry.inode.ino, 2 + subdirs);
+            }
+            _ => *nlinks.entry(entry.inode.ino).or_insert(0) += 1,
+        }
+    }
+
+    let mut dump = Vec::new();
+    let mut seen = HashMap::<u64, &Path>::new();
+    for entry in &entries {
+        let inode = &entry.inode;
+        let Some(file_type) = file_type(&inode.mode) else {
+            bail!("cannot export inode {} of type {:?}", inode.ino, inode.mode);
+        };
+        let mode = file_type.bits() | u32::from(inode.permissions);
+
+        escape(&mut dump, entry.path.as_os_str().as_bytes(), false);
+
+        if let Some(target) = seen.get(&inode.ino) {
+            // hard links only name their target, the other fields are ignored
+            dump.extend_from_slice(format!(" 0 @{mode:o} 0 0 0 0 0.0 ").as_bytes());
+            escape(&mut dump, target.as_os_str().as_bytes(), false);
+            dump.extend_from_slice(b" - -\n");
+            continue;
+        }
+        seen.insert(inode.ino, &entry.path);
+
+        let (size, rdev, payload, digest) = match &inode.mode {
+            InodeMode::File { .. } => {
+                let size = inode.file_len()?;
+                if size == 0 {
+                    (0, 0, Vec::new(), None)
+                } else {
+                    info!("exporting {}", entry.path.display());
+                    let (object, digest) = write_object(&objects, entry.open()?)?;
+                    (size, 0, object.into_bytes(), Some(digest))
+                }
+            }
+            InodeMode::Lnk => {
+                let target = inode.symlink_target()?.as_bytes().to_vec();
+                (target.len() as u64, 0, target, None)
+            }
+            InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+                (0, makedev(*major, *minor), Vec::new(), None)
+            }
+            _ => (0, 0, Vec::new(), None),
+        };
+
+        // puzzlefs doesn't store timestamps
+        dump.extend_from_slice(
+            format!(
+                " {size} {mode:o} {} {} {} {rdev} 0.0 ",
+                nlinks[&inode.ino], inode.uid, inode.gid
+            )
+            .as_bytes(),
+        );
+        escape(&mut dump, &payload, false);
+        dump.extend_from_slice(b" - ");
+        dump.extend_from_slice(digest.as_deref().unwrap_or("-").as_bytes());
+        if let Some(additional) = &inode.additional {
+            for xattr in &additional.xattrs {
+                dump.push(b' ');
+                escape(&mut dump, &xattr.key, true);
+                dump.push(b'=');
+                escape(&mut dump, &xattr.val, true);
+            }
+        }
+        dump.push(b'\n');
+    }
+
+    let dump_path = out_dir.join(format!("{tag}.dump"));
+    fs::write(&dump_path, dump)?;
+
+    let cfs_path = out_dir.join(format!("{tag}.cfs"));
+    let status = Command::new("mkcomposefs")
+        .arg("--from-file")
+        .arg(&dump_path)
+        .arg(&cfs_path)
+        .status()
+        .map_err(|e| anyhow!("cannot run mkcomposefs ({e}), the dump is in {dump_path:?}"))?;
+    if !status.success() {
+        bail!("mkcomposefs exited with {status}");
+    }
+    Ok(cfs_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        let mut out = Vec::new();
+        escape(&mut out, b"/foo bar\\baz=", false);
+        assert_eq!(out, b"/foo\\x20bar\\x5cbaz=");
+
+        out.clear();
+        escape(&mut out, b"user.a=b", true);
+        assert_eq!(out, b"user.a\\x3db");
+
+        out.clear();
+        escape(&mut out, b"", false);
+        assert_eq!(out, b"-");
+    }
+}
@@ -0,0 +1,168 @@
+//! Per-file listings of the contents of an image, attached to it as referrers.
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+
+use ocidir::oci_spec::image::{Descriptor, MediaType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use super::file_type;
+use crate::format::{Ino, InodeMode};
+use crate::oci::media_types::{CONTENT_MANIFEST, CONTENT_MANIFEST_ARTIFACT};
+use crate::oci::{Image, ImageError};
+use crate::reader::{PuzzleFS, WalkPuzzleFS};
+
+/// A file of an image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentEntry {
+    /// The path of the file from the root of the image, lossily converted to UTF-8.
+    pub path: String,
+    /// The size of a regular file, 0 for the other files.
+    pub size: u64,
+    /// The mode of the file, file type bits included, like `st_mode`.
+    pub mode: u32,
+    /// The digest of the contents of a regular file, as `sha256:<hex>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+/// The files of an image, in the order [`WalkPuzzleFS`] walks them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentManifest {
+    pub files: Vec<ContentEntry>,
+}
+
+fn list_files(pfs: &mut PuzzleFS) -> anyhow::Result<ContentManifest> {
+    // the contents of hard linked files are only read once
+    let mut digests = HashMap::<Ino, String>::new();
+    let mut files = Vec::new();
+    for entry in WalkPuzzleFS::walk(pfs)? {
+        let entry = entry?;
+        let inode = &entry.inode;
+        // whiteouts hide files, they aren't files of the image
+        let Some(file_type) = file_type(&inode.mode) else {
+            continue;
+        };
+        let (size, digest) = match inode.mode {
+            InodeMode::File { .. } => {
+                let mut reader = entry.open()?;
+                let digest = match digests.entry(inode.ino) {
+                    Entry::Occupied(digest) => digest.get().clone(),
+                    Entry::Vacant(vacant) => {
+                        let mut hasher = Sha256::new();
+                        io::copy(&mut reader, &mut hasher)?;
+                        let digest = format!("sha256:{}", hex::encode(hasher.finalize()));
+                        vacant.insert(digest).clone()
+                    }
+                };
+                (reader.len(), Some(digest))
+            }
+            _ => (0, None),
+        };
+        files.push(ContentEntry {
+            path: entry.path.to_string_lossy().into_owned(),
+            size,
+            mode: file_type.bits() | u32::from(inode.permissions),
+            digest,
+        });
+    }
+    Ok(ContentManifest { files })
+}
+
+// the layers of the content manifests attached to `subject`, oldest first
+fn attached(image: &Image, subject: &Descriptor) -> anyhow::Result<Vec<(Descriptor, Descriptor)>> {
+    Ok(image
+        .find_artifacts(subject, &[CONTENT_MANIFEST_ARTIFACT])?
+        .into_iter()
+        .filter_map(|(desc, manifest)| Some((desc, manifest.layers().first()?.clone())))
+        .collect())
+}
+
+fn find_subject(image: &Image, tag: &str) -> anyhow::Result<Descriptor> {
+    Ok(image
+        .find_manifest_descriptor(tag)?
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: tag.to_string(),
+        })?)
+}
+
+/// Lists the files of `tag`, reading the regular files to compute their digests.
+pub fn content_manifest(image: Image, tag: &str) -> anyhow::Result<ContentManifest> {
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    list_files(&mut pfs)
+}
+
+/// Lists the files of `tag` and attaches the listing to its manifest: an artifact manifest whose
+/// subject is the manifest of `tag` and whose only layer is the listing, in JSON. Returns the
+/// descriptor of the artifact manifest; the same listing is only attached once.
+pub fn attach_content_manifest(image: Image, tag: &str) -> anyhow::Result<Descriptor> {
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    let listing = list_files(&mut pfs)?;
+    let image = &pfs.oci;
+    let subject = find_subject(image, tag)?;
+
+    let layer = image.write_blob(
+        &serde_json::to_vec(&listing)?,
+        MediaType::Other(CONTENT_MANIFEST.to_string()),
+    )?;
+    if let Some((desc, _)) = attached(image, &subject)?
+        .into_iter()
+        .find(|(_, existing)| existing.digest() == layer.digest())
+    {
+        return Ok(desc);
+    }
+    info!("attaching the content manifest of {tag}");
+    Ok(image.attach_artifact(subject, CONTENT_MANIFEST_ARTIFACT, layer)?)
+}
+
+/// The content manifest last attached to `tag`, if any.
+pub fn find_content_manifest(image: &Image, tag: &str) -> anyhow::Result<Option<ContentManifest>> {
+    let subject = find_subject(image, tag)?;
+    let Some((_, layer)) = attached(image, &subject)?.pop() else {
+        return Ok(None);
+    };
+    let listing = image.0.blobs_dir().read(layer.digest().digest())?;
+    Ok(Some(serde_json::from_slice(&listing)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use nix::sys::stat::SFlag;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_content_manifest() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        assert_eq!(find_content_manifest(&image, "test")?, None);
+
+        let desc = attach_content_manifest(Image::open(dir.path())?, "test")?;
+        let listing = find_content_manifest(&image, "test")?.unwrap();
+        assert_eq!(listing, content_manifest(image, "test")?);
+        let [root, file] = &listing.files[..] else {
+            panic!("unexpected listing {listing:?}");
+        };
+        assert_eq!(root.path, "/");
+        assert_eq!(root.mode & SFlag::S_IFMT.bits(), SFlag::S_IFDIR.bits());
+        assert_eq!(root.digest, None);
+        assert_eq!(file.path, "/SekienAkashita.jpg");
+        assert_eq!(file.size, 109466);
+        assert_eq!(file.mode & SFlag::S_IFMT.bits(), SFlag::S_IFREG.bits());
+        assert_eq!(
+            file.digest.as_deref(),
+            Some("sha256:d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed")
+        );
+
+        // the image didn't change, so neither did its listing
+        let image = Image::open(dir.path())?;
+        assert!(find_content_manifest(&image, "missing").is_err());
+        assert_eq!(attach_content_manifest(image, "test")?, desc);
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use tar::{EntryType, Header};
+
+use super::append_inode;
+use crate::format::{resolve_inode, Ino, Inode, InodeMode, MetadataLayers, Result, RootfsReader};
+use crate::oci::Image;
+use crate::reader::{FileReader, MountError};
+
+// the OCI layer conventions for the files removed from the layers below
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+// the metadata layers of a rootfs below its topmost one
+struct Below<'a>(&'a RootfsReader);
+
+impl MetadataLayers for Below<'_> {
+    fn layer_count(&self) -> Result<usize> {
+        Ok(self.0.layer_count()?.saturating_sub(1))
+    }
+
+    fn layer_inode(&self, layer: usize, ino: Ino) -> Result<Option<Inode>> {
+        self.0.layer_inode(layer + 1, ino)
+    }
+
+    fn is_whiteout(&self, layer: usize, ino: Ino) -> Result<bool> {
+        self.0.is_whiteout(layer + 1, ino)
+    }
+}
+
+// a file of the rootfs, with the file the layers below have at the same path
+struct Entry {
+    path: PathBuf,
+    inode: Inode,
+    lower: Option<Inode>,
+    // whether it's below a directory which replaces the one of the layers below, so everything
+    // in it goes into the layer
+    replaced: bool,
+}
+
+// appends the empty file the whiteouts and opaque markers are
+fn append_marker<W: Write>(tar: &mut tar::Builder<W>, path: &Path) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_size(0);
+    tar.append_data(&mut header, path, io::empty())
+}
+
+// writes the topmost metadata layer of `rootfs` as an OCI layer, see export_oci_layer
+fn write_layer(image: &Image, rootfs: &RootfsReader, writer: impl Write) -> anyhow::Result<()> {
+    let mut tar = tar::Builder::new(writer);
+    let mut seen = HashMap::new();
+    let below = Below(rootfs);
+
+    let mut queue = VecDeque::from([Entry {
+        path: PathBuf::new(),
+        inode: rootfs.find_inode(1)?,
+        lower: resolve_inode(&below, 1)?,
+        replaced: false,
+    }]);
+    while let Some(Entry {
+        path,
+        inode,
+        lower,
+        replaced,
+    }) = queue.pop_front()
+    {
+        let top = rootfs.layer_inode(0, inode.ino)?;
+        let lower_ino = lower.as_ref().map(|lower| lower.ino);
+        let changed = replaced || top.is_some() || lower_ino != Some(inode.ino);
+        // the root directory is implicit
+        if changed && !path.as_os_str().is_empty() {
+            append_inode(&mut tar, &mut seen, &path, &inode, || {
+                FileReader::new(image, &inode)
+            })?;
+        }
+        let InodeMode::Dir { dir_list } = &inode.mode else {
+            continue;
+        };
+
+        // the entries of the directory the layers below have at the same path
+        let lower_entries = match lower.map(|lower| lower.mode) {
+            Some(InodeMode::Dir { dir_list }) if !replaced => Some(dir_list.entries),
+            _ => None,
+        };
+        // a directory which doesn't merge the one below hides all of it; with a different inode
+        // it's a directory created again
+        let opaque =
+            matches!(top.map(|top| top.mode), Some(InodeMode::Dir { dir_list }) if dir_list.opaque);
+        let replaces = lower_entries.is_some() && (opaque || lower_ino != Some(inode.ino));
+        if replaces {
+            append_marker(&mut tar, &path.join(OPAQUE_MARKER))?;
+        } else if let Some(lower_entries) = &lower_entries {
+            let names = dir_list
+                .entries
+                .iter()
+                .map(|entry| &entry.name[..])
+                .collect::<HashSet<_>>();
+            for entry in lower_entries {
+                if !names.contains(&entry.name[..]) {
+                    let mut name = OsString::from(WHITEOUT_PREFIX);
+                    name.push(OsStr::from_bytes(&entry.name));
+                    append_marker(&mut tar, &path.join(name))?;
+                }
+            }
+        }
+
+        for entry in &dir_list.entries {
+            let lower = match lower_entries.as_ref().filter(|_| !replaces) {
+                Some(lower_entries) => lower_entries
+                    .iter()
+                    .find(|lower| lower.name == entry.name)
+                    .map(|lower| resolve_inode(&below, lower.ino))
+                    .transpose()?
+                    .flatten(),
+                None => None,
+            };
+            queue.push_back(Entry {
+                path: path.join(OsStr::from_bytes(&entry.name)),
+                inode: rootfs.find_inode(entry.ino)?,
+                lower,
+                replaced: replaced || replaces,
+            });
+        }
+    }
+
+    tar.finish()?;
+    Ok(())
+}
+
+/// Writes what the topmost metadata layer of `tag` changes, e.g. what a delta changed on top of
+/// its base, as an OCI layer to `output`: an uncompressed tar with a `.wh.<name>` whiteout for
+/// each removed file and a `.wh..wh..opq` marker in each directory which replaces the one below.
+/// The layer of a tag which isn't a delta has the whole filesystem.
+pub fn export_oci_layer(oci_dir: &Path, tag: &str, output: &Path) -> anyhow::Result<()> {
+    let image = Image::open(oci_dir)?;
+    // like mounting, a tag whose manifest requires fs-verity isn't read without its checks
+    if image.requires_verity(tag)? {
+        return Err(MountError::VerityRequired {
+            tag: tag.to_string(),
+        }
+        .into());
+    }
+    let rootfs = image.open_rootfs_blob(tag, None)?;
+    let mut writer = io::BufWriter::new(fs::File::create(output)?);
+    write_layer(&image, &rootfs, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{add_rootfs_delta, build_test_fs, BuilderConfig};
+    use crate::compression::DefaultCompression;
+    use tempfile::tempdir;
+
+    // the paths of the entries of the layer of `tag`
+    fn layer_paths(image: &Image, tag: &str) -> anyhow::Result<Vec<String>> {
+        let rootfs = image.open_rootfs_blob(tag, None)?;
+        let mut buf = Vec::new();
+        write_layer(image, &rootfs, &mut buf)?;
+        let mut archive = tar::Archive::new(&buf[..]);
+        let mut paths = archive
+            .entries()?
+            .map(|entry| Ok(entry?.path()?.to_string_lossy().into_owned()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    #[test]
+    fn test_oci_layer() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        for path in ["d/a", "d/b", "e/x", "keep", "gone"] {
+            let path = rootfs.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "base")?;
+        }
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        build_test_fs(&rootfs, &image, "base")?;
+        assert_eq!(
+            layer_paths(&image, "base")?,
+            ["d", "d/a", "d/b", "e", "e/x", "gone", "keep"]
+        );
+
+        fs::write(rootfs.join("keep"), "changed")?;
+        fs::remove_file(rootfs.join("gone"))?;
+        fs::write(rootfs.join("new"), "new")?;
+        // d is replaced wholesale, which hides all of the old one
+        fs::remove_dir_all(rootfs.join("d"))?;
+        fs::create_dir_all(rootfs.join("d"))?;
+        fs::write(rootfs.join("d/c"), "c")?;
+        add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            image,
+            "delta",
+            "base",
+            &BuilderConfig::default(),
+        )?;
+
+        let image = Image::open(&oci_dir)?;
+        assert_eq!(
+            layer_paths(&image, "delta")?,
+            [".wh.gone", "d", "d/.wh..wh..opq", "d/c", "keep", "new"]
+        );
+
+        // an entry added to the opaque directory later doesn't hide the ones below
+        fs::write(rootfs.join("d/e"), "e")?;
+        add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            image,
+            "added",
+            "delta",
+            &BuilderConfig::default(),
+        )?;
+        let image = Image::open(&oci_dir)?;
+        assert_eq!(layer_paths(&image, "added")?, ["d", "d/e"]);
+
+        let output = dir.path().join("delta.tar");
+        export_oci_layer(&oci_dir, "delta", &output)?;
+        assert!(fs::metadata(&output)?.len() > 0);
+        Ok(())
+    }
+}
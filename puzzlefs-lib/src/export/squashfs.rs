@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::append_inode;
+use crate::format::{Ino, Inode};
+use crate::oci::Image;
+use crate::reader::{PuzzleFS, WalkPuzzleFS};
+
+// serializes the whole filesystem as a tar archive
+fn write_tar(pfs: &mut PuzzleFS, writer: impl Write) -> anyhow::Result<()> {
+    let mut tar = tar::Builder::new(writer);
+    let mut seen = HashMap::<Ino, PathBuf>::new();
+
+    for entry in WalkPuzzleFS::walk(pfs)? {
+        let entry = entry?;
+        let path = entry.path.strip_prefix("/")?;
+        // the root directory is implicit
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        append_inode(&mut tar, &mut seen, path, &entry.inode, || entry.open())?;
+    }
+
+    tar.finish()?;
+    Ok(())
+}
+
+// the options of sqfstar which give the root directory, which the tar archive leaves out, the
+// mode and owner of the root inode
+fn root_options(root: &Inode) -> Vec<String> {
+    vec![
+        "-root-mode".to_string(),
+        format!("{:o}", root.permissions),
+        "-root-uid".to_string(),
+        root.uid.to_string(),
+        "-root-gid".to_string(),
+        root.gid.to_string(),
+    ]
+}
+
+/// Writes `tag` as a squashfs image to `output`, for devices which should run a conventional
+/// read-only image. The squashfs image is generated by `sqfstar` (squashfs-tools >= 4.6).
+pub fn export_squashfs(oci_dir: &Path, tag: &str, output: &Path) -> anyhow::Result<()> {
+    let image = Image::open(oci_dir)?;
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    let root = pfs.find_inode(1)?;
+
+    let mut sqfstar = Command::new("sqfstar")
+        .args(root_options(&root))
+        .arg(output)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("cannot run sqfstar: {e}"))?;
+    // the stdin of the child is closed when write_tar is done with it
+    let stdin = sqfstar.stdin.take().unwrap();
+    let result = write_tar(&mut pfs, stdin);
+    let status = sqfstar.wait()?;
+    result?;
+    if !status.success() {
+        bail!("sqfstar exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_tar() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let mut buf = Vec::new();
+        write_tar(&mut pfs, &mut buf)?;
+
+        let mut archive = tar::Archive::new(&buf[..]);
+        let entries = archive
+            .entries()?
+            .map(|e| {
+                let e = e?;
+                Ok((e.path()?.into_owned(), e.header().size()?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(entries, vec![(PathBuf::from("SekienAkashita.jpg"), 109466)]);
+
+        let md = std::fs::metadata("src/builder/test/test-1")?;
+        assert_eq!(
+            root_options(&pfs.find_inode(1)?),
+            [
+                "-root-mode".to_string(),
+                format!("{:o}", md.mode() & 0o7777),
+                "-root-uid".to_string(),
+                md.uid().to_string(),
+                "-root-gid".to_string(),
+                md.gid().to_string(),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,22 +1,332 @@
-use crate::format::InodeMode;
-use crate::oci::Image;
-use crate::reader::{PuzzleFS, WalkPuzzleFS};
-use log::info;
+use crate::format::{InodeMode, WireFormatError};
+use crate::fsverity_helpers::{
+    enable_and_check_verity_for_file, read_fs_verity_digest, VerityDigest, VerityHash,
+};
+use crate::idmap::IdMap;
+use crate::mode_policy::{PathPattern, SETID_BITS};
+use crate::oci::encryption::DecryptionKeys;
+use crate::oci::{Image, Platform, ReadHints};
+use crate::reader::{DirEntry, PuzzleFS, WalkPuzzleFS};
+use crate::xattr_filter::XattrFilter;
+use nix::errno::Errno;
+use nix::libc;
 use nix::sys::stat::{makedev, mknod, Mode, SFlag};
-use nix::unistd::{chown, mkfifo, symlinkat, Gid, Uid};
-use std::collections::HashMap;
+use nix::unistd::{fchownat, mkfifo, symlinkat, FchownatFlags, Gid, Uid};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::Permissions;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use std::{fs, io};
+use thiserror::Error;
+use tracing::{debug, info};
+
+/// What the extraction does about the files already in the extract dir. The directories the
+/// image also has are always kept and filled with its files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingFiles {
+    /// Fail on the first one.
+    #[default]
+    Fail,
+    /// Replace them with the files of the image.
+    Overwrite,
+    /// Leave them as they are, along with what's below them.
+    Skip,
+    /// Replace the ones which differ from the files of the image in type or contents; the others
+    /// keep their contents and get the owner, mode and xattrs of the image's.
+    Merge,
+}
+
+/// Options controlling how an image is extracted.
+#[derive(Debug, Default, Clone)]
+pub struct ExtractorConfig {
+    /// Shift the owners of the extracted files, e.g. to extract a rootfs for a rootless
+    /// container.
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    /// Extract the manifest of this platform from tags with one manifest per platform, instead of
+    /// the host's.
+    pub platform: Option<Platform>,
+    /// Which xattrs of the image are set on the extracted files. Regardless of the filter,
+    /// unprivileged extractions skip the `trusted.*` xattrs, which only root may set.
+    pub xattr_filter: XattrFilter,
+    /// Fail the extraction when a file other than a directory is setuid or setgid, unless its
+    /// path matches one of `allow_setuid`.
+    pub refuse_setuid: bool,
+    pub allow_setuid: Vec<PathPattern>,
+    /// How the chunk blobs are read; extracting streams each chunk once, so there's little point
+    /// in keeping them in the page cache.
+    pub read_hints: ReadHints,
+    pub existing: ExistingFiles,
+    /// Delete what the extract dir has besides the files of the image, so that it ends up
+    /// matching the image, e.g. when updating a rootfs extracted from a previous version. The
+    /// directories which a delta layer replaced (opaque ones) are always cleared of what the
+    /// image doesn't have in them.
+    pub delete: bool,
+    /// Enable fs-verity on the extracted files with this hash algorithm, checking that the kernel
+    /// measures the digest of their contents in the image; reading them fails once tampered with.
+    /// The digests are computed as the files are written, so they aren't copied with reflinks.
+    pub verity: Option<VerityHash>,
+    /// Make the extracted files immutable, like `chattr +i`, which needs `CAP_LINUX_IMMUTABLE`.
+    /// Those already there from a previous extraction lose the attribute to be replaced, updated
+    /// or deleted, whether this is set or not.
+    pub immutable: bool,
+    /// Create the device nodes of the image, which takes root; they are left out otherwise, and
+    /// reported as lost.
+    pub allow_devices: bool,
+    /// Fail on the first owner, device, xattr or mode which can't be restored, instead of
+    /// reporting it.
+    pub strict: bool,
+    /// The private keys the encrypted chunks of the image are decrypted with.
+    pub decryption_keys: Option<Arc<DecryptionKeys>>,
+    /// Read back each extracted file and check its contents against the digest recorded in the
+    /// image, for the files which have one.
+    pub check: bool,
+}
+
+/// Something an extraction couldn't restore, typically because it didn't run as root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Loss {
+    /// The file belongs to whoever extracted it instead of this owner.
+    Ownership { uid: u32, gid: u32 },
+    /// The device node wasn't created, as it takes root or wasn't allowed.
+    Device { major: u64, minor: u64 },
+    /// The xattr wasn't set.
+    Xattr { name: String },
+    /// The file didn't get this mode, e.g. chmod dropped its setgid bit.
+    Mode { mode: u16 },
+}
+
+impl Loss {
+    /// The name of the kind of loss, as in the JSON representation.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Loss::Ownership { .. } => "ownership",
+            Loss::Device { .. } => "device",
+            Loss::Xattr { .. } => "xattr",
+            Loss::Mode { .. } => "mode",
+        }
+    }
+}
+
+impl fmt::Display for Loss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Loss::Ownership { uid, gid } => write!(f, "owner {uid}:{gid}"),
+            Loss::Device { major, minor } => write!(f, "device {major}:{minor}"),
+            Loss::Xattr { name } => write!(f, "xattr {name}"),
+            Loss::Mode { mode } => write!(f, "mode {mode:04o}"),
+        }
+    }
+}
+
+/// Why an extraction failed.
+#[derive(Error, Debug)]
+pub enum ExtractError {
+    /// [`ExtractorConfig::strict`] is set and the file at `path` in the image lost this.
+    #[error("cannot restore the {loss} of {}", .path.display())]
+    Unrestorable { path: PathBuf, loss: Loss },
+    /// A directory on the way to the file is a symlink, which isn't followed.
+    #[error("symlink prefixes are not allowed: {}", .path.display())]
+    SymlinkPrefix { path: PathBuf },
+    /// A directory on the way to the file can't be looked at.
+    #[error("problem accessing path component {}: {source}", .path.display())]
+    PathComponent { path: PathBuf, source: io::Error },
+    /// The path of the file in the image goes out of the extract dir.
+    #[error("image path escapes extract dir: {}", .path.display())]
+    EscapingPath { path: PathBuf },
+    /// There's already a file at `path` and [`ExistingFiles::Fail`] is the policy.
+    #[error("refusing to replace {}, which already exists", .path.display())]
+    ExistingFile { path: PathBuf },
+    /// The file at `path` in the image is setuid or setgid, which
+    /// [`ExtractorConfig::refuse_setuid`] refuses.
+    #[error(
+        "refusing to extract {}, it has mode {mode:04o} and setuid/setgid files aren't allowed",
+        .path.display()
+    )]
+    Setuid { path: PathBuf, mode: u16 },
+    /// The extracted file at `path` in the image, read back, doesn't have the digest the image
+    /// records for it.
+    #[error(
+        "{} doesn't match its digest in the image, expected {expected}, found {found}",
+        .path.display()
+    )]
+    DigestMismatch {
+        path: PathBuf,
+        expected: String,
+        found: String,
+    },
+    /// The file at `path` in the image is of a type which can't be extracted.
+    #[error("bad inode mode of {}", .path.display())]
+    BadInodeMode { path: PathBuf },
+    /// Reading the image failed.
+    #[error(transparent)]
+    Image(#[from] WireFormatError),
+    /// Writing the extracted files failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Creating a special file or changing the owner of a file failed.
+    #[error(transparent)]
+    Sys(#[from] Errno),
+}
+
+/// A loss of the file at `path` in the image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileLoss {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub loss: Loss,
+}
+
+/// What an extraction couldn't restore, file by file.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ExtractionReport {
+    pub losses: Vec<FileLoss>,
+}
+
+impl ExtractionReport {
+    /// Whether everything was restored.
+    pub fn is_complete(&self) -> bool {
+        self.losses.is_empty()
+    }
+
+    /// The kinds of losses in the order they first happened, with how many there were of each
+    /// and the first file affected.
+    pub fn summary(&self) -> Vec<(&'static str, usize, &Path)> {
+        let mut summary = Vec::<(&'static str, usize, &Path)>::new();
+        for lost in &self.losses {
+            match summary
+                .iter_mut()
+                .find(|(kind, ..)| *kind == lost.loss.kind())
+            {
+                Some((_, count, _)) => *count += 1,
+                None => summary.push((lost.loss.kind(), 1, &lost.path)),
+            }
+        }
+        summary
+    }
+
+    fn lose(&mut self, path: &Path, loss: Loss, strict: bool) -> Result<(), ExtractError> {
+        if strict {
+            return Err(ExtractError::Unrestorable {
+                path: path.to_path_buf(),
+                loss,
+            });
+        }
+        self.losses.push(FileLoss {
+            path: path.to_path_buf(),
+            loss,
+        });
+        Ok(())
+    }
+}
+
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+// FS_IOC_GETFLAGS and FS_IOC_SETFLAGS are _IOR('f', 1, long) and _IOW('f', 2, long), but both
+// pass an int nonetheless
+nix::ioctl_read_bad!(
+    fs_ioc_getflags,
+    nix::request_code_read!(b'f', 1, std::mem::size_of::<libc::c_long>()),
+    libc::c_int
+);
+nix::ioctl_write_ptr_bad!(
+    fs_ioc_setflags,
+    nix::request_code_write!(b'f', 2, std::mem::size_of::<libc::c_long>()),
+    libc::c_int
+);
+
+fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    // SAFETY: flags outlives both ioctls
+    unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) }?;
+    let new_flags = if immutable {
+        flags | FS_IMMUTABLE_FL
+    } else {
+        flags & !FS_IMMUTABLE_FL
+    };
+    if new_flags != flags {
+        unsafe { fs_ioc_setflags(file.as_raw_fd(), &new_flags) }?;
+    }
+    Ok(())
+}
+
+// clears the immutable flag a previous extraction may have left on the regular file at `path`;
+// filesystems without flags have none to clear, and those we can't open weren't made immutable by
+// us
+fn clear_immutable(path: &Path) -> io::Result<()> {
+    match set_immutable(path, false) {
+        Err(e)
+            if matches!(
+                Errno::from_i32(e.raw_os_error().unwrap_or_default()),
+                Errno::ENOTTY | Errno::EOPNOTSUPP | Errno::EINVAL | Errno::EACCES
+            ) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+// removes `path`, along with what it contains if it's a directory, immutable files included
+fn remove_extracted(path: &Path, is_dir: bool) -> io::Result<()> {
+    for entry in walkdir::WalkDir::new(path).follow_links(false) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            clear_immutable(entry.path())?;
+        }
+    }
+    if is_dir {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+// writes to both of its writers
+struct Tee<'a, A, B>(&'a mut A, &'a mut B);
+
+impl<A: Write, B: Write> Write for Tee<'_, A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
 
 fn runs_privileged() -> bool {
     Uid::effective().is_root()
 }
 
-fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
+// creates a device node; false if it takes root
+fn make_device(path: &Path, kind: SFlag, major: u64, minor: u64) -> nix::Result<bool> {
+    match mknod(path, kind, Mode::S_IRWXU, makedev(major, minor)) {
+        Ok(()) => Ok(true),
+        Err(Errno::EPERM) if !runs_privileged() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// without root, chmod quietly drops the setgid bit of the files whose group we aren't in
+fn got_mode(path: &Path, mode: u16) -> io::Result<bool> {
+    Ok(runs_privileged() || fs::symlink_metadata(path)?.mode() & 0o7777 == u32::from(mode))
+}
+
+fn safe_path(dir: &Path, image_path: &Path) -> Result<PathBuf, ExtractError> {
     // need to be a bit careful here about paths in the case of malicious images so we don't write
     // things outside where we're supposed to. Bad cases are paths like "/../../.." or images
     // /usr/bin -> /bin and files in /usr/bin, we shouldn't write files anywhere outside the target
@@ -26,25 +336,35 @@ fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
     buf.push(dir);
     let mut level = 1;
 
-    for component in image_path.components() {
+    let mut components = image_path.components().peekable();
+    while let Some(component) = components.next() {
         match component {
-            Component::Prefix(..) => bail!("Path prefix not understood"), // "Does not occur on Unix."
+            // "Does not occur on Unix."
+            Component::Prefix(..) => {
+                return Err(ExtractError::EscapingPath {
+                    path: image_path.to_path_buf(),
+                })
+            }
             Component::RootDir => {}
             Component::CurDir => {}
             Component::Normal(c) => {
                 buf.push(c);
                 level += 1;
 
-                // make sure this isn't a symlink
+                // make sure this isn't a symlink; the file itself may be one left by a previous
+                // extraction, which gets replaced rather than followed
                 match fs::symlink_metadata(&buf) {
                     Ok(md) => {
-                        if md.file_type().is_symlink() {
-                            bail!("symlink prefixes are not allowed: {:#?}", buf)
+                        if md.file_type().is_symlink() && components.peek().is_some() {
+                            return Err(ExtractError::SymlinkPrefix { path: buf });
                         }
                     }
                     Err(e) => {
                         if e.kind() != io::ErrorKind::NotFound {
-                            bail!("problem accessing path component {:#?}: {}", buf, e)
+                            return Err(ExtractError::PathComponent {
+                                path: buf,
+                                source: e,
+                            });
                         }
 
                         // we render each dir, so the first ENOENT should be the lowest path. could
@@ -56,7 +376,9 @@ fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
             Component::ParentDir => {
                 level -= 1;
                 if level <= 0 {
-                    bail!("image path escapes extract dir: {:#?}", image_path)
+                    return Err(ExtractError::EscapingPath {
+                        path: image_path.to_path_buf(),
+                    });
                 }
                 buf.pop();
             }
@@ -66,31 +388,239 @@ fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
     Ok(buf)
 }
 
-pub fn extract_rootfs(oci_dir: &str, tag: &str, extract_dir: &str) -> anyhow::Result<()> {
+/// What to do about a file already where one of the image goes.
+enum Existing {
+    /// Nothing's left there, the file of the image can be created.
+    Cleared,
+    /// It already is the file of the image.
+    Kept,
+    /// It stays as it is.
+    Skipped,
+}
+
+fn make_room(
+    path: &Path,
+    md: &fs::Metadata,
+    policy: ExistingFiles,
+    matches: impl FnOnce() -> Result<bool, ExtractError>,
+) -> Result<Existing, ExtractError> {
+    match policy {
+        ExistingFiles::Fail => {
+            return Err(ExtractError::ExistingFile {
+                path: path.to_path_buf(),
+            })
+        }
+        ExistingFiles::Skip => return Ok(Existing::Skipped),
+        ExistingFiles::Merge if matches()? => return Ok(Existing::Kept),
+        ExistingFiles::Merge | ExistingFiles::Overwrite => (),
+    }
+    // removing rather than truncating leaves alone the other links to the file
+    remove_extracted(path, md.is_dir())?;
+    Ok(Existing::Cleared)
+}
+
+fn same_contents(mut image: impl Read, mut file: impl Read) -> io::Result<bool> {
+    let mut expected = vec![0; 1 << 16];
+    let mut found = vec![0; 1 << 16];
+    loop {
+        let n = image.read(&mut expected)?;
+        if n == 0 {
+            return Ok(file.read(&mut found[..1])? == 0);
+        }
+        match file.read_exact(&mut found[..n]) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        if expected[..n] != found[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+// whether the file at `path` is the one of the image, leaving aside owner, mode and xattrs
+fn matches_image(
+    dir_entry: &DirEntry,
+    path: &Path,
+    md: &fs::Metadata,
+) -> Result<bool, ExtractError> {
+    let file_type = md.file_type();
+    Ok(match dir_entry.inode.mode {
+        InodeMode::File { .. } => {
+            file_type.is_file()
+                && md.len() == dir_entry.inode.file_len()?
+                && same_contents(dir_entry.open()?, fs::File::open(path)?)?
+        }
+        InodeMode::Lnk => {
+            file_type.is_symlink()
+                && fs::read_link(path)?.as_os_str() == dir_entry.inode.symlink_target()?
+        }
+        InodeMode::Fifo => file_type.is_fifo(),
+        InodeMode::Chr { major, minor } => {
+            file_type.is_char_device() && md.rdev() == makedev(major, minor)
+        }
+        InodeMode::Blk { major, minor } => {
+            file_type.is_block_device() && md.rdev() == makedev(major, minor)
+        }
+        _ => false,
+    })
+}
+
+// deletes what's in `dir` but not in `extracted`
+fn delete_extraneous(dir: &Path, extracted: &HashSet<PathBuf>) -> Result<(), ExtractError> {
+    let mut entries = walkdir::WalkDir::new(dir).follow_links(false).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry.map_err(io::Error::from)?;
+        if extracted.contains(entry.path()) {
+            continue;
+        }
+        info!("deleting {:#?}", entry.path());
+        remove_extracted(entry.path(), entry.file_type().is_dir())?;
+        if entry.file_type().is_dir() {
+            entries.skip_current_dir();
+        }
+    }
+    Ok(())
+}
+
+/// Extracts `tag` into `extract_dir`, returning what couldn't be restored unless
+/// [`ExtractorConfig::strict`] makes that an error.
+pub fn extract_rootfs(
+    oci_dir: &str,
+    tag: &str,
+    extract_dir: &str,
+    config: &ExtractorConfig,
+) -> Result<ExtractionReport, ExtractError> {
     let oci_dir = Path::new(oci_dir);
-    let image = Image::open(oci_dir)?;
+    let mut image = Image::open(oci_dir)?.with_read_hints(config.read_hints);
+    if let Some(platform) = &config.platform {
+        image = image.with_platform(platform.clone());
+    }
+    if let Some(keys) = &config.decryption_keys {
+        image = image.with_decryption_keys(keys.clone());
+    }
     let dir = Path::new(extract_dir);
     fs::create_dir_all(dir)?;
     let mut pfs = PuzzleFS::open(image, tag, None)?;
+    pfs.set_id_maps(config.uid_map.clone(), config.gid_map.clone());
     let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
     let mut host_to_pfs = HashMap::<crate::format::Ino, PathBuf>::new();
+    // the modes of the directories are set once everything is extracted, so that read-only
+    // directories can still be filled
+    let mut dir_modes = Vec::<(PathBuf, PathBuf, u16)>::new();
+    // the image paths of the directories skipped along with their contents
+    let mut skipped = HashSet::<PathBuf>::new();
+    let mut extracted = HashSet::<PathBuf>::new();
+    // the opaque directories of the image which were already in the extract dir
+    let mut opaque_dirs = Vec::<PathBuf>::new();
+    // made immutable at the end, as they can't be linked to or have their mode changed then
+    let mut immutable = Vec::<PathBuf>::new();
+    let mut report = ExtractionReport::default();
 
-    walker.try_for_each(|de| -> anyhow::Result<()> {
+    walker.try_for_each(|de| -> Result<(), ExtractError> {
         let dir_entry = de?;
+        if dir_entry
+            .path
+            .parent()
+            .is_some_and(|parent| skipped.contains(parent))
+        {
+            skipped.insert(dir_entry.path);
+            return Ok(());
+        }
         let path = safe_path(dir, &dir_entry.path)?;
-        let mut is_symlink = false;
+        let is_symlink = matches!(dir_entry.inode.mode, InodeMode::Lnk);
         info!("extracting {:#?}", path);
+        if config.delete || !opaque_dirs.is_empty() {
+            extracted.insert(path.clone());
+        }
+
+        let existing = match fs::symlink_metadata(&path) {
+            Ok(md) => Some(md),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let is_dir = matches!(dir_entry.inode.mode, InodeMode::Dir { .. });
+        let is_file = matches!(dir_entry.inode.mode, InodeMode::File { .. });
+
+        // the files a previous extraction made immutable can't be updated as they are
+        if config.existing == ExistingFiles::Merge
+            && existing.as_ref().is_some_and(|md| md.is_file())
+        {
+            clear_immutable(&path)?;
+        }
+
         if let Some(existing_path) = host_to_pfs.get(&dir_entry.inode.ino) {
+            if let Some(md) = &existing {
+                let linked = fs::symlink_metadata(existing_path)?;
+                let same_inode = || Ok((md.dev(), md.ino()) == (linked.dev(), linked.ino()));
+                match make_room(&path, md, config.existing, same_inode)? {
+                    Existing::Cleared => (),
+                    Existing::Kept | Existing::Skipped => return Ok(()),
+                }
+            }
             fs::hard_link(existing_path, &path)?;
             return Ok(());
         }
+
+        if config.refuse_setuid
+            && dir_entry.inode.permissions & SETID_BITS != 0
+            && !matches!(dir_entry.inode.mode, InodeMode::Dir { .. })
+            && !config
+                .allow_setuid
+                .iter()
+                .any(|pattern| pattern.matches(&dir_entry.path))
+        {
+            return Err(ExtractError::Setuid {
+                path: dir_entry.path,
+                mode: dir_entry.inode.permissions,
+            });
+        }
+
+        let existing = match &existing {
+            None => Existing::Cleared,
+            Some(md) if is_dir && md.is_dir() => match config.existing {
+                ExistingFiles::Skip => return Ok(()),
+                _ => Existing::Kept,
+            },
+            Some(md) => make_room(&path, md, config.existing, || {
+                matches_image(&dir_entry, &path, md)
+            })?,
+        };
+        let kept = match existing {
+            Existing::Cleared => false,
+            Existing::Kept => true,
+            Existing::Skipped => {
+                skipped.insert(dir_entry.path.clone());
+                return Ok(());
+            }
+        };
+        if kept
+            && !config.delete
+            && matches!(&dir_entry.inode.mode, InodeMode::Dir { dir_list } if dir_list.opaque)
+        {
+            extracted.insert(path.clone());
+            opaque_dirs.push(path.clone());
+        }
+        // a skipped file isn't the one of the image, so the other links to it are extracted anew
         host_to_pfs.insert(dir_entry.inode.ino, path.clone());
 
+        let mut device_lost = None;
+        let mut verity_digest = None;
         match dir_entry.inode.mode {
+            _ if kept => (),
             InodeMode::File { .. } => {
                 let mut reader = dir_entry.open()?;
                 let mut f = fs::File::create(&path)?;
-                io::copy(&mut reader, &mut f)?;
+                match config.verity {
+                    // the digest the kernel should measure is computed on the way
+                    Some(hash) => {
+                        let mut digest = VerityDigest::new(hash);
+                        io::copy(&mut reader, &mut Tee(&mut f, &mut digest))?;
+                        verity_digest = Some(digest.finalize());
+                    }
+                    None => {
+                        reader.copy_to(&mut f)?;
+                    }
+                }
             }
             InodeMode::Dir { .. } => fs::create_dir_all(&path)?,
             // TODO: fix all the hard coded modes when we have modes
@@ -98,14 +628,17 @@ pub fn extract_rootfs(oci_dir: &str, tag: &str, extract_dir: &str) -> anyhow::Re
                 mkfifo(&path, Mode::S_IRWXU)?;
             }
             InodeMode::Chr { major, minor } => {
-                mknod(&path, SFlag::S_IFCHR, Mode::S_IRWXU, makedev(major, minor))?;
+                if !config.allow_devices || !make_device(&path, SFlag::S_IFCHR, major, minor)? {
+                    device_lost = Some(Loss::Device { major, minor });
+                }
             }
             InodeMode::Blk { major, minor } => {
-                mknod(&path, SFlag::S_IFBLK, Mode::S_IRWXU, makedev(major, minor))?;
+                if !config.allow_devices || !make_device(&path, SFlag::S_IFBLK, major, minor)? {
+                    device_lost = Some(Loss::Device { major, minor });
+                }
             }
             InodeMode::Lnk => {
                 let target = dir_entry.inode.symlink_target()?;
-                is_symlink = true;
                 symlinkat(target, None, &path)?;
             }
             InodeMode::Sock => {
@@ -115,35 +648,127 @@ pub fn extract_rootfs(oci_dir: &str, tag: &str, extract_dir: &str) -> anyhow::Re
                 todo!();
             }
             _ => {
-                bail!("bad inode mode {:#?}", dir_entry.inode.mode)
+                return Err(ExtractError::BadInodeMode {
+                    path: dir_entry.path,
+                })
+            }
+        }
+
+        if let Some(loss) = device_lost {
+            // the other links to the device can't be made either
+            host_to_pfs.remove(&dir_entry.inode.ino);
+            return report.lose(&dir_entry.path, loss, config.strict);
+        }
+
+        if let Some(expected) = dir_entry.inode.file_digest().filter(|_| config.check) {
+            let mut hasher = Sha256::new();
+            io::copy(&mut fs::File::open(&path)?, &mut hasher)?;
+            let found = hasher.finalize();
+            if found[..] != expected {
+                return Err(ExtractError::DigestMismatch {
+                    path: dir_entry.path,
+                    expected: hex::encode(expected),
+                    found: hex::encode(found),
+                });
             }
         }
+        if let Some(hash) = config.verity.filter(|_| is_file) {
+            // a merged file was already there
+            let expected = match verity_digest {
+                Some(digest) => digest,
+                None => read_fs_verity_digest(dir_entry.open()?, hash)?,
+            };
+            let file = cap_std::fs::File::from_std(fs::File::open(&path)?);
+            enable_and_check_verity_for_file(&file, &expected, None)?;
+        }
+        if is_file && config.immutable {
+            immutable.push(path.clone());
+        }
+
+        // chown clears the setuid and setgid bits and the file capabilities, so it goes first;
+        // without root, it only works for our own uid and groups
+        let (uid, gid) = (dir_entry.inode.uid, dir_entry.inode.gid);
+        match fchownat(
+            None,
+            &path,
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            FchownatFlags::NoFollowSymlink,
+        ) {
+            Ok(()) => (),
+            Err(Errno::EPERM | Errno::EINVAL) if !runs_privileged() => {
+                report.lose(&dir_entry.path, Loss::Ownership { uid, gid }, config.strict)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
         if let Some(x) = dir_entry.inode.additional {
             for x in &x.xattrs {
-                xattr::set(&path, OsStr::from_bytes(&x.key), &x.val)?;
+                if !config.xattr_filter.keeps(&x.key) {
+                    debug!(
+                        "not setting xattr {} of {}",
+                        String::from_utf8_lossy(&x.key),
+                        path.display()
+                    );
+                    continue;
+                }
+                let lost = Loss::Xattr {
+                    name: String::from_utf8_lossy(&x.key).into_owned(),
+                };
+                if x.key.starts_with(b"trusted.") && !runs_privileged() {
+                    report.lose(&dir_entry.path, lost, config.strict)?;
+                    continue;
+                }
+                match xattr::set(&path, OsStr::from_bytes(&x.key), &x.val) {
+                    Ok(()) => (),
+                    // e.g. security.capability, or a filesystem without user xattrs
+                    Err(e)
+                        if !runs_privileged()
+                            && matches!(
+                                Errno::from_i32(e.raw_os_error().unwrap_or_default()),
+                                Errno::EPERM | Errno::EOPNOTSUPP
+                            ) =>
+                    {
+                        report.lose(&dir_entry.path, lost, config.strict)?;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
             }
         }
 
         // trying to change permissions for a symlink would follow the symlink and we might not have extracted the target yet
         // anyway, symlink permissions are not used in Linux (although they are used in macOS and FreeBSD)
-        if !is_symlink {
-            std::fs::set_permissions(
-                &path,
-                Permissions::from_mode(dir_entry.inode.permissions.into()),
-            )?;
-        }
-
-        if runs_privileged() {
-            chown(
-                &path,
-                Some(Uid::from_raw(dir_entry.inode.uid)),
-                Some(Gid::from_raw(dir_entry.inode.gid)),
-            )?;
+        let mode = dir_entry.inode.permissions;
+        if is_dir {
+            dir_modes.push((path.clone(), dir_entry.path.clone(), mode));
+        } else if !is_symlink {
+            std::fs::set_permissions(&path, Permissions::from_mode(mode.into()))?;
+            if !got_mode(&path, mode)? {
+                report.lose(&dir_entry.path, Loss::Mode { mode }, config.strict)?;
+            }
         }
 
         Ok(())
     })?;
-    Ok(())
+
+    if config.delete {
+        delete_extraneous(dir, &extracted)?;
+    }
+    for opaque_dir in &opaque_dirs {
+        delete_extraneous(opaque_dir, &extracted)?;
+    }
+
+    // children come after their parents, so go backwards to not lock ourselves out
+    for (path, image_path, mode) in dir_modes.iter().rev() {
+        fs::set_permissions(path, Permissions::from_mode((*mode).into()))?;
+        if !got_mode(path, *mode)? {
+            report.lose(image_path, Loss::Mode { mode: *mode }, config.strict)?;
+        }
+    }
+    for path in immutable {
+        set_immutable(&path, true)?;
+    }
+    Ok(report)
 }
 
 #[cfg(test)]
@@ -152,7 +777,8 @@ mod tests {
 
     use std::fs::File;
 
-    use crate::builder::build_test_fs;
+    use crate::builder::{add_rootfs_delta, build_test_fs, BuilderConfig};
+    use crate::compression::Zstd;
     use std::os::unix::fs::MetadataExt;
     use walkdir::WalkDir;
 
@@ -185,6 +811,8 @@ mod tests {
                 xattr::set(f, key, val).unwrap();
                 xattr::set(f, key, val).unwrap();
             }
+            // overlayfs' own xattrs aren't stored in the image
+            xattr::set(f, "user.overlay.origin", b"").unwrap();
         }
 
         build_test_fs(&rootfs, &image, "test").unwrap();
@@ -193,6 +821,7 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractorConfig::default(),
         )
         .unwrap();
 
@@ -217,6 +846,7 @@ mod tests {
                 );
                 assert!(attribute.unwrap().as_ref().unwrap() == val);
             }
+            assert_eq!(xattr::get(ent.path(), "user.overlay.origin").unwrap(), None);
         }
     }
 
@@ -242,6 +872,7 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractorConfig::default(),
         )
         .unwrap();
 
@@ -252,6 +883,245 @@ mod tests {
         assert_eq!(metadata.permissions().mode() & 0xFFF, TESTED_PERMISSION);
     }
 
+    #[test]
+    fn test_setuid_policy() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+
+        fs::create_dir_all(rootfs.join("ro")).unwrap();
+        fs::write(rootfs.join("ro/foo"), b"foo").unwrap();
+        fs::write(rootfs.join("su"), b"su").unwrap();
+        fs::set_permissions(rootfs.join("su"), Permissions::from_mode(0o4755)).unwrap();
+        fs::set_permissions(rootfs.join("ro"), Permissions::from_mode(0o555)).unwrap();
+
+        build_test_fs(&rootfs, &image, "test").unwrap();
+
+        let extract = |config: &ExtractorConfig| {
+            let extract_dir = tempdir().unwrap();
+            let result = extract_rootfs(
+                oci_dir.to_str().unwrap(),
+                "test",
+                extract_dir.path().to_str().unwrap(),
+                config,
+            );
+            (extract_dir, result)
+        };
+
+        let config = ExtractorConfig {
+            refuse_setuid: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            extract(&config).1,
+            Err(ExtractError::Setuid { path, .. }) if path == Path::new("/su")
+        ));
+
+        let config = ExtractorConfig {
+            refuse_setuid: true,
+            allow_setuid: vec!["/su".parse().unwrap()],
+            ..Default::default()
+        };
+        let (extract_dir, result) = extract(&config);
+        result.unwrap();
+
+        // the read-only directory got its mode after its contents were extracted
+        let ro = extract_dir.path().join("ro");
+        assert_eq!(fs::read(ro.join("foo")).unwrap(), b"foo");
+        assert_eq!(
+            fs::metadata(&ro).unwrap().permissions().mode() & 0o7777,
+            0o555
+        );
+        assert_eq!(
+            fs::metadata(extract_dir.path().join("su"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777,
+            0o4755
+        );
+
+        for ro in [ro, rootfs.join("ro")] {
+            fs::set_permissions(ro, Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_existing_files() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let extract_dir = tempdir().unwrap();
+        let extracted = extract_dir.path();
+
+        fs::create_dir_all(rootfs.join("d")).unwrap();
+        fs::write(rootfs.join("foo"), b"foo").unwrap();
+        fs::write(rootfs.join("d/bar"), b"bar").unwrap();
+        std::os::unix::fs::symlink("foo", rootfs.join("lnk")).unwrap();
+        build_test_fs(&rootfs, &image, "test").unwrap();
+
+        let extract = |existing, delete| {
+            let config = ExtractorConfig {
+                existing,
+                delete,
+                ..Default::default()
+            };
+            extract_rootfs(
+                oci_dir.to_str().unwrap(),
+                "test",
+                extracted.to_str().unwrap(),
+                &config,
+            )
+        };
+        let ino = |path: &str| fs::symlink_metadata(extracted.join(path)).unwrap().ino();
+
+        extract(ExistingFiles::Fail, false).unwrap();
+        assert!(matches!(
+            extract(ExistingFiles::Fail, false),
+            Err(ExtractError::ExistingFile { .. })
+        ));
+
+        fs::write(extracted.join("foo"), b"changed").unwrap();
+        fs::create_dir(extracted.join("extra")).unwrap();
+        fs::write(extracted.join("extra/file"), b"extra").unwrap();
+        extract(ExistingFiles::Skip, false).unwrap();
+        assert_eq!(fs::read(extracted.join("foo")).unwrap(), b"changed");
+
+        let (foo, bar, lnk) = (ino("foo"), ino("d/bar"), ino("lnk"));
+        extract(ExistingFiles::Merge, false).unwrap();
+        assert_eq!(fs::read(extracted.join("foo")).unwrap(), b"foo");
+        assert_ne!(ino("foo"), foo);
+        assert_eq!(ino("d/bar"), bar);
+        assert_eq!(ino("lnk"), lnk);
+        assert!(extracted.join("extra/file").exists());
+
+        // a directory where the image has a file
+        fs::remove_file(extracted.join("foo")).unwrap();
+        fs::create_dir(extracted.join("foo")).unwrap();
+        extract(ExistingFiles::Overwrite, true).unwrap();
+        assert_eq!(fs::read(extracted.join("foo")).unwrap(), b"foo");
+        assert_ne!(ino("d/bar"), bar);
+        assert_eq!(
+            fs::read_link(extracted.join("lnk")).unwrap(),
+            Path::new("foo")
+        );
+        assert!(!extracted.join("extra").exists());
+    }
+
+    #[test]
+    fn test_immutable_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        // it takes CAP_LINUX_IMMUTABLE and a filesystem with flags
+        let probe = dir.path().join("probe");
+        fs::write(&probe, b"probe")?;
+        if set_immutable(&probe, true).is_err() {
+            return Ok(());
+        }
+        set_immutable(&probe, false)?;
+
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        let rootfs = dir.path().join("rootfs");
+        let extracted = dir.path().join("extracted");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("foo"), b"foo")?;
+        build_test_fs(&rootfs, &image, "test")?;
+
+        let extract = |immutable| {
+            let config = ExtractorConfig {
+                existing: ExistingFiles::Overwrite,
+                delete: true,
+                immutable,
+                ..Default::default()
+            };
+            extract_rootfs(
+                oci_dir.to_str().unwrap(),
+                "test",
+                extracted.to_str().unwrap(),
+                &config,
+            )
+        };
+        extract(true)?;
+        assert!(fs::write(extracted.join("foo"), b"changed").is_err());
+        fs::create_dir(extracted.join("extra"))?;
+        fs::write(extracted.join("extra/file"), b"extra")?;
+        set_immutable(&extracted.join("extra/file"), true)?;
+
+        // the files of the previous extraction are replaced and deleted all the same
+        extract(false)?;
+        assert!(!extracted.join("extra").exists());
+        fs::write(extracted.join("foo"), b"changed")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_opaque_dirs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir)?;
+        let rootfs = dir.path().join("rootfs");
+        let extracted = dir.path().join("extracted");
+        fs::create_dir_all(rootfs.join("d"))?;
+        fs::write(rootfs.join("d/old"), b"old")?;
+        build_test_fs(&rootfs, &image, "base")?;
+
+        fs::remove_file(rootfs.join("d/old"))?;
+        fs::write(rootfs.join("d/new"), b"new")?;
+        add_rootfs_delta::<Zstd>(&rootfs, image, "delta", "base", &BuilderConfig::default())?;
+
+        let extract = |tag| {
+            let config = ExtractorConfig {
+                existing: ExistingFiles::Merge,
+                ..Default::default()
+            };
+            extract_rootfs(
+                oci_dir.to_str().unwrap(),
+                tag,
+                extracted.to_str().unwrap(),
+                &config,
+            )
+        };
+        extract("base")?;
+        fs::write(extracted.join("extra"), b"extra")?;
+        // d was replaced, so what the base had in it goes away, unlike what's elsewhere
+        extract("delta")?;
+        assert!(!extracted.join("d/old").exists());
+        assert_eq!(fs::read(extracted.join("d/new"))?, b"new");
+        assert!(extracted.join("extra").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extraction_report() {
+        let mut report = ExtractionReport::default();
+        let owner = Loss::Ownership { uid: 0, gid: 0 };
+        report.lose(Path::new("/a"), owner.clone(), false).unwrap();
+        let device = Loss::Device { major: 1, minor: 3 };
+        report.lose(Path::new("/dev/null"), device, false).unwrap();
+        report.lose(Path::new("/b"), owner, false).unwrap();
+        assert!(!report.is_complete());
+        assert_eq!(
+            report.summary(),
+            vec![
+                ("ownership", 2, Path::new("/a")),
+                ("device", 1, Path::new("/dev/null"))
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&report).unwrap()["losses"][1],
+            serde_json::json!({"path": "/dev/null", "kind": "device", "major": 1, "minor": 3})
+        );
+
+        let strict = report.lose(Path::new("/c"), Loss::Mode { mode: 0o2755 }, true);
+        assert_eq!(
+            strict.unwrap_err().to_string(),
+            "cannot restore the mode 2755 of /c"
+        );
+        assert_eq!(report.losses.len(), 3);
+    }
+
     #[test]
     fn test_hardlink_extraction() {
         let dir = tempdir().unwrap();
@@ -279,6 +1149,7 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractorConfig::default(),
         )
         .unwrap();
 
@@ -309,9 +1180,45 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractorConfig::default(),
         )
         .unwrap();
         let extracted_foo = extract_dir.path().join("foo");
         assert_eq!(extracted_foo.metadata().unwrap().len(), 0);
     }
+
+    #[test]
+    fn test_read_hints() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let extract_dir = tempdir().unwrap();
+
+        // big enough for a few chunks
+        let data = (0..1024 * 1024)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("big"), &data).unwrap();
+
+        build_test_fs(&rootfs, &image, "test").unwrap();
+
+        let config = ExtractorConfig {
+            read_hints: ReadHints {
+                noatime: true,
+                sequential: true,
+                drop_cache: true,
+            },
+            ..Default::default()
+        };
+        extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(fs::read(extract_dir.path().join("big")).unwrap(), data);
+    }
 }
@@ -3,3 +3,9 @@ pub use types::*;
 
 mod error;
 pub use error::*;
+
+mod limits;
+pub use limits::*;
+
+mod shared_map;
+pub use shared_map::*;
@@ -1,28 +1,38 @@
 use std::backtrace::Backtrace;
 use std::io;
-use std::os::raw::c_int;
 
 use nix::errno::Errno;
 use thiserror::Error;
 
+use crate::builder::BuildError;
+use crate::oci::ImageError;
+use crate::reader::MountError;
+
+/// The errors of the library. Those of building, opening and mounting images have their own
+/// types, which are matched through [`WireFormatError::Build`], [`WireFormatError::Image`] and
+/// [`WireFormatError::Mount`].
 #[derive(Error, Debug)]
 pub enum WireFormatError {
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error(transparent)]
+    Image(#[from] ImageError),
+    #[error(transparent)]
+    Mount(#[from] MountError),
     #[error("cannot turn local ref into a digest")]
     LocalRefError(Backtrace),
     #[error("cannot seek to other blob")]
     SeekOtherError(Backtrace),
     #[error("invalid serialized data")]
     InvalidSerializedData(Backtrace),
+    #[error("invalid metadata: {0}")]
+    InvalidMetadata(String, Backtrace),
+    #[error("invalid entry in directory {0}: {1}")]
+    InvalidDirent(u64, String, Backtrace),
     #[error("invalid image schema: {0}")]
     InvalidImageSchema(i32, Backtrace),
-    #[error("invalid image version: {0}")]
-    InvalidImageVersion(String, Backtrace),
     #[error("invalid fs_verity data: {0}")]
     InvalidFsVerityData(String, Backtrace),
-    #[error("missing manifest: {0}")]
-    MissingManifest(String, Backtrace),
-    #[error("missing PuzzleFS rootfs")]
-    MissingRootfs(Backtrace),
     #[error("fs error: {0}")]
     IOError(#[from] io::Error, Backtrace),
     #[error("deserialization error (capnp): {0}")]
@@ -39,38 +49,45 @@ pub enum WireFormatError {
     OciError(#[from] ocidir::oci_spec::OciSpecError, Backtrace),
     #[error("Oci dir error: {0}")]
     OciDirError(#[from] ocidir::Error, Backtrace),
+    #[error("signature error: {0}")]
+    SignatureError(String, Backtrace),
+    #[error("openssl error: {0}")]
+    OpenSSLError(#[from] openssl::error::ErrorStack, Backtrace),
+    #[error("delta error: {0}")]
+    DeltaError(String, Backtrace),
+    #[error("remote error: {0}")]
+    RemoteError(String, Backtrace),
+    #[error("encryption error: {0}")]
+    EncryptionError(String, Backtrace),
+    #[error("trust store error: {0}")]
+    TrustError(String, Backtrace),
 }
 
 impl WireFormatError {
-    pub fn to_errno(&self) -> c_int {
-        match self {
-            WireFormatError::LocalRefError(..) => Errno::EINVAL as c_int,
-            WireFormatError::SeekOtherError(..) => Errno::ESPIPE as c_int,
-            WireFormatError::InvalidSerializedData(..) => Errno::EINVAL as c_int,
-            WireFormatError::InvalidImageSchema(..) => Errno::EINVAL as c_int,
-            WireFormatError::InvalidImageVersion(..) => Errno::EINVAL as c_int,
-            WireFormatError::InvalidFsVerityData(..) => Errno::EINVAL as c_int,
-            WireFormatError::MissingManifest(..) => Errno::EINVAL as c_int,
-            WireFormatError::MissingRootfs(..) => Errno::EINVAL as c_int,
-            WireFormatError::IOError(ioe, ..) => {
-                ioe.raw_os_error().unwrap_or(Errno::EINVAL as i32) as c_int
-            }
-            WireFormatError::CapnpError(..) => Errno::EINVAL as c_int,
-            WireFormatError::JSONError(..) => Errno::EINVAL as c_int,
-            WireFormatError::HexError(..) => Errno::EINVAL as c_int,
-            WireFormatError::FromIntError(..) => Errno::EINVAL as c_int,
-            WireFormatError::FromSliceError(..) => Errno::EINVAL as c_int,
-            WireFormatError::OciError(..) => Errno::EINVAL as c_int,
-            WireFormatError::OciDirError(..) => Errno::EINVAL as c_int,
-        }
-    }
-
     pub fn from_errno(errno: Errno) -> Self {
         Self::IOError(
             io::Error::from_raw_os_error(errno as i32),
             Backtrace::capture(),
         )
     }
+
+    /// The errno of a failed system call, like [`io::Error::raw_os_error`].
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            WireFormatError::IOError(e, _) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+// for the readers which implement io::Read on top of the image
+impl From<WireFormatError> for io::Error {
+    fn from(e: WireFormatError) -> Self {
+        match e {
+            WireFormatError::IOError(e, _) => e,
+            e => io::Error::new(io::ErrorKind::Other, e),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, WireFormatError>;
@@ -0,0 +1,349 @@
+use std::backtrace::Backtrace;
+use std::collections::HashSet;
+
+use capnp::{message, serialize};
+
+use super::error::{Result, WireFormatError};
+use super::types::{Ino, SHA256_BLOCK_SIZE, SHA512_BLOCK_SIZE};
+use crate::metadata_capnp;
+
+const ROOT_INO: Ino = 1;
+
+/// Bounds on the metadata of an image, checked when its rootfs is opened so that a malformed or
+/// malicious image is refused up front, rather than making the reader allocate or walk without
+/// end later on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataLimits {
+    /// The largest rootfs blob, in bytes.
+    pub max_size: u64,
+    /// How deep capnp structs and lists may nest.
+    pub nesting_limit: i32,
+    /// The most metadata layers of a rootfs.
+    pub max_layers: usize,
+    /// The most inodes, over all the layers.
+    pub max_inodes: u64,
+    /// The most entries of a directory.
+    pub max_dir_entries: usize,
+    /// The longest name of a directory entry.
+    pub max_name_len: usize,
+    /// The most chunks of a file.
+    pub max_chunks: usize,
+    /// The longest symlink target.
+    pub max_symlink_len: usize,
+    /// The most bytes of xattr names and values of a file.
+    pub max_xattr_size: usize,
+}
+
+impl Default for MetadataLimits {
+    fn default() -> Self {
+        MetadataLimits {
+            max_size: 1 << 32,
+            nesting_limit: 64,
+            max_layers: 4096,
+            max_inodes: 1 << 28,
+            max_dir_entries: 1 << 24,
+            // NAME_MAX and PATH_MAX
+            max_name_len: 255,
+            max_chunks: 1 << 24,
+            max_symlink_len: 4096,
+            max_xattr_size: 1 << 20,
+        }
+    }
+}
+
+fn invalid(message: String) -> WireFormatError {
+    WireFormatError::InvalidMetadata(message, Backtrace::capture())
+}
+
+fn invalid_dirent(dir: Ino, message: String) -> WireFormatError {
+    WireFormatError::InvalidDirent(dir, message, Backtrace::capture())
+}
+
+// entries named like this would escape their directory, or make it ambiguous, in FUSE lookups and
+// in the paths the extractor creates
+fn check_name(dir: Ino, name: &[u8]) -> Result<()> {
+    match name {
+        b"" => Err(invalid_dirent(dir, "empty name".to_string())),
+        b"." | b".." => Err(invalid_dirent(dir, format!("{}", name.escape_ascii()))),
+        _ if name.contains(&b'/') => Err(invalid_dirent(
+            dir,
+            format!("{} contains a /", name.escape_ascii()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl MetadataLimits {
+    /// Checks that `rootfs` is well formed puzzlefs metadata within these limits: besides the
+    /// limits themselves, the inodes of each layer must be sorted, the fs-verity data must have
+    /// the right sizes, the names of directory entries must be unique, neither `.` nor `..` and
+    /// without `/`, and each directory must have a single parent, so the image has no cycles.
+    pub fn check(&self, rootfs: &[u8]) -> Result<()> {
+        if rootfs.len() as u64 > self.max_size {
+            return Err(invalid(format!(
+                "the rootfs is {} bytes, more than {}",
+                rootfs.len(),
+                self.max_size
+            )));
+        }
+        // capnp counts the words it reads against the traversal limit. The checks below read each
+        // word of a well formed message about once, while pointers aliasing each other, which
+        // make a small message look huge, blow through the limit.
+        let options = message::ReaderOptions {
+            traversal_limit_in_words: Some(rootfs.len() / 8 * 2 + 1024),
+            nesting_limit: self.nesting_limit,
+        };
+        let message = serialize::read_message_from_flat_slice(&mut &rootfs[..], options)?;
+        let rootfs = message.get_root::<metadata_capnp::rootfs::Reader<'_>>()?;
+
+        for verity in rootfs.get_fs_verity_data()?.iter() {
+            let digest = verity.get_digest()?.len();
+            let verity = verity.get_verity()?.len();
+            if digest != SHA256_BLOCK_SIZE
+                || (verity != SHA256_BLOCK_SIZE && verity != SHA512_BLOCK_SIZE)
+            {
+                return Err(invalid(format!(
+                    "fs-verity data with a {digest} bytes digest and a {verity} bytes verity"
+                )));
+            }
+        }
+
+        let metadatas = rootfs.get_metadatas()?;
+        if metadatas.len() as usize > self.max_layers {
+            return Err(invalid(format!(
+                "{} metadata layers, more than {}",
+                metadatas.len(),
+                self.max_layers
+            )));
+        }
+        let mut inodes = 0;
+        // the inodes of the image, the first layer having an inode number hiding the next ones
+        let mut seen = HashSet::new();
+        let mut dirs = HashSet::new();
+        let mut children = Vec::new();
+        for metadata in metadatas.iter() {
+            let mut previous = None;
+            for inode in metadata.get_inodes()?.iter() {
+                inodes += 1;
+                if inodes > self.max_inodes {
+                    return Err(invalid(format!("more than {} inodes", self.max_inodes)));
+                }
+                let ino = inode.get_ino();
+                if previous.is_some_and(|previous| previous >= ino) {
+                    return Err(invalid(format!("inode {ino} isn't sorted in its layer")));
+                }
+                previous = Some(ino);
+
+                let len = children.len();
+                let is_dir = self.check_inode(inode, &mut children)?;
+                if !seen.insert(ino) {
+                    children.truncate(len);
+                } else if is_dir {
+                    dirs.insert(ino);
+                }
+            }
+        }
+
+        // with a single parent for each directory and none for the root, walking the image from
+        // the root visits each directory once
+        let mut parents = HashSet::new();
+        for child in children {
+            if dirs.contains(&child) && (child == ROOT_INO || !parents.insert(child)) {
+                return Err(invalid(format!(
+                    "directory {child} is linked more than once"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // checks `inode`, adding the inode numbers of its entries to `children` if it's a directory
+    fn check_inode(
+        &self,
+        inode: metadata_capnp::inode::Reader<'_>,
+        children: &mut Vec<Ino>,
+    ) -> Result<bool> {
+        use metadata_capnp::inode::mode;
+
+        let ino = inode.get_ino();
+        let is_dir = match inode.get_mode().which().map_err(capnp::Error::from)? {
+            mode::Dir(dir) => {
+                let entries = dir?.get_entries()?;
+                if entries.len() as usize > self.max_dir_entries {
+                    return Err(invalid(format!(
+                        "directory {ino} has {} entries, more than {}",
+                        entries.len(),
+                        self.max_dir_entries
+                    )));
+                }
+                let mut names = HashSet::with_capacity(entries.len() as usize);
+                for entry in entries.iter() {
+                    let name = entry.get_name()?;
+                    if name.len() > self.max_name_len {
+                        return Err(invalid(format!(
+                            "directory {ino} has an entry named with {} bytes, more than {}",
+                            name.len(),
+                            self.max_name_len
+                        )));
+                    }
+                    check_name(ino, name)?;
+                    if !names.insert(name) {
+                        return Err(invalid_dirent(
+                            ino,
+                            format!("{} appears twice", name.escape_ascii()),
+                        ));
+                    }
+                    children.push(entry.get_ino());
+                }
+                true
+            }
+            mode::File(chunks) => {
+                let chunks = chunks?;
+                if chunks.len() as usize > self.max_chunks {
+                    return Err(invalid(format!(
+                        "file {ino} has {} chunks, more than {}",
+                        chunks.len(),
+                        self.max_chunks
+                    )));
+                }
+                false
+            }
+            _ => false,
+        };
+
+        let additional = inode.get_additional()?;
+        let mut xattr_size = 0;
+        for xattr in additional.get_xattrs()?.iter() {
+            xattr_size += xattr.get_key()?.len() + xattr.get_val()?.len();
+        }
+        if xattr_size > self.max_xattr_size {
+            return Err(invalid(format!(
+                "inode {ino} has {xattr_size} bytes of xattrs, more than {}",
+                self.max_xattr_size
+            )));
+        }
+        let target = additional.get_symlink_target()?.len();
+        if target > self.max_symlink_len {
+            return Err(invalid(format!(
+                "symlink {ino} has a {target} bytes target, more than {}",
+                self.max_symlink_len
+            )));
+        }
+        Ok(is_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{DirEnt, DirList, Inode, InodeMode, InodeVector};
+
+    fn dir(ino: Ino, entries: &[(&str, Ino)]) -> Inode {
+        Inode {
+            ino,
+            mode: InodeMode::Dir {
+                dir_list: DirList {
+                    entries: entries
+                        .iter()
+                        .map(|(name, ino)| DirEnt {
+                            ino: *ino,
+                            name: name.as_bytes().to_vec(),
+                        })
+                        .collect(),
+                    look_below: false,
+                    opaque: false,
+                },
+            },
+            uid: 0,
+            gid: 0,
+            permissions: 0o755,
+            additional: None,
+        }
+    }
+
+    // the rootfs with these layers, in a buffer aligned like a mapped blob
+    fn rootfs(layers: &[Vec<Inode>]) -> anyhow::Result<Vec<capnp::Word>> {
+        let mut message = message::Builder::new_default();
+        let builder = message.init_root::<metadata_capnp::rootfs::Builder<'_>>();
+        let mut metadatas = builder.init_metadatas(layers.len() as u32);
+        for (i, inodes) in layers.iter().enumerate() {
+            InodeVector::fill_capnp(inodes, &mut metadatas.reborrow().get(i as u32))?;
+        }
+        let bytes = serialize::write_message_to_words(&message);
+        let mut words = capnp::Word::allocate_zeroed_vec(bytes.len() / 8);
+        capnp::Word::words_to_bytes_mut(&mut words).copy_from_slice(&bytes);
+        Ok(words)
+    }
+
+    fn check(limits: &MetadataLimits, layers: &[Vec<Inode>]) -> anyhow::Result<Result<()>> {
+        let rootfs = rootfs(layers)?;
+        Ok(limits.check(capnp::Word::words_to_bytes(&rootfs)))
+    }
+
+    #[test]
+    fn test_metadata_limits() -> anyhow::Result<()> {
+        let limits = MetadataLimits::default();
+        let tree = || vec![dir(1, &[("a", 2)]), dir(2, &[("b", 3)]), dir(3, &[])];
+        check(&limits, &[tree()])??;
+
+        // the upper layer moves b into the root, hiding the directories of the lower one
+        check(
+            &limits,
+            &[vec![dir(1, &[("a", 2), ("b", 3)]), dir(2, &[])], tree()],
+        )??;
+
+        for layers in [
+            vec![vec![dir(1, &[("a", 2)]), dir(2, &[("b", 1)])]],
+            vec![vec![dir(1, &[("a", 2), ("b", 2)]), dir(2, &[])]],
+            vec![vec![dir(1, &[("a", 3)]), dir(2, &[("b", 3)]), dir(3, &[])]],
+            vec![vec![dir(2, &[]), dir(1, &[("a", 2)])]],
+            vec![vec![dir(1, &[(&"x".repeat(256), 2)]), dir(2, &[])]],
+        ] {
+            assert!(check(&limits, &layers)?.is_err(), "{layers:?}");
+        }
+
+        let tight = MetadataLimits {
+            max_inodes: 2,
+            ..limits
+        };
+        assert!(check(&tight, &[tree()])?.is_err());
+        let tight = MetadataLimits {
+            max_dir_entries: 0,
+            ..limits
+        };
+        assert!(check(&tight, &[tree()])?.is_err());
+        let tight = MetadataLimits {
+            max_size: 8,
+            ..limits
+        };
+        assert!(check(&tight, &[tree()])?.is_err());
+
+        assert!(limits.check(&[0; 64]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_dirents() -> anyhow::Result<()> {
+        let limits = MetadataLimits::default();
+        check(
+            &limits,
+            &[vec![dir(1, &[("...", 2), (".a", 3)]), dir(2, &[])]],
+        )??;
+        for name in ["", ".", "..", "a/b", "/", "../etc"] {
+            let layers = [vec![dir(1, &[(name, 2)]), dir(2, &[])]];
+            assert!(
+                matches!(
+                    check(&limits, &layers)?,
+                    Err(WireFormatError::InvalidDirent(1, ..))
+                ),
+                "{name:?}"
+            );
+        }
+        let layers = [vec![dir(1, &[("a", 2), ("b", 3), ("a", 3)])]];
+        assert!(matches!(
+            check(&limits, &layers)?,
+            Err(WireFormatError::InvalidDirent(1, ..))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! The metadata blobs mapped by the process, each mapped once however many readers it has.
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex, Weak};
+
+use memmap2::{Mmap, MmapOptions};
+use nix::libc;
+use nix::sys::stat::fstat;
+
+use super::error::Result;
+
+// the live mappings; the readers own them, so a blob is unmapped when its last reader goes away
+static MAPS: Mutex<BTreeMap<(libc::dev_t, libc::ino_t), Weak<Mmap>>> = Mutex::new(BTreeMap::new());
+
+/// A read-only mapping of a metadata blob, shared with the other readers of the same file.
+#[derive(Clone)]
+pub struct SharedMap(Arc<Mmap>);
+
+impl SharedMap {
+    /// Maps `f`, or reuses the mapping another reader has of the same file.
+    pub fn map(f: &cap_std::fs::File) -> Result<Self> {
+        let st = fstat(f.as_raw_fd()).map_err(io::Error::from)?;
+        let key = (st.st_dev, st.st_ino);
+        let mut maps = MAPS.lock().unwrap();
+        if let Some(map) = maps.get(&key).and_then(Weak::upgrade) {
+            return Ok(SharedMap(map));
+        }
+        // the blobs are never written to, so nothing needs a private copy of their pages
+        let map = Arc::new(unsafe { MmapOptions::new().map(f)? });
+        maps.retain(|_, map| map.strong_count() > 0);
+        maps.insert(key, Arc::downgrade(&map));
+        Ok(SharedMap(map))
+    }
+}
+
+impl Deref for SharedMap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// What the process has mapped of the metadata of its images.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MappingStats {
+    /// The metadata blobs mapped.
+    pub mappings: u64,
+    /// The readers of the mapped blobs, a mounted tag being one reader of its blob.
+    pub readers: u64,
+    /// The size of the mapped blobs.
+    pub mapped_bytes: u64,
+    /// The part of the mapped blobs which is resident in memory.
+    pub resident_bytes: u64,
+}
+
+/// Accounts for the metadata mapped by the process.
+pub fn mapping_stats() -> MappingStats {
+    let page_size = page_size();
+    let maps = MAPS.lock().unwrap();
+    let mut stats = MappingStats::default();
+    for map in maps.values().filter_map(Weak::upgrade) {
+        stats.mappings += 1;
+        // the registry doesn't count, nor does the reference just taken
+        stats.readers += Arc::strong_count(&map) as u64 - 1;
+        stats.mapped_bytes += map.len() as u64;
+        stats.resident_bytes += resident_pages(&map, page_size) * page_size as u64;
+    }
+    stats
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// the pages of `map` which are in memory, from mincore
+fn resident_pages(map: &Mmap, page_size: usize) -> u64 {
+    let mut pages = vec![0u8; map.len().div_ceil(page_size)];
+    let ret = unsafe {
+        libc::mincore(
+            map.as_ptr() as *mut libc::c_void,
+            map.len(),
+            pages.as_mut_ptr(),
+        )
+    };
+    if ret != 0 {
+        return 0;
+    }
+    pages.iter().filter(|page| *page & 1 != 0).count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_shared_map() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("blob");
+        std::fs::write(&path, vec![1; 3 * page_size()])?;
+        let open = || cap_std::fs::File::from_std(std::fs::File::open(&path).unwrap());
+
+        let first = SharedMap::map(&open())?;
+        let second = SharedMap::map(&open())?;
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        assert_eq!(first[..], vec![1; 3 * page_size()][..]);
+
+        // another file with the same contents isn't the same blob
+        let copy = dir.path().join("copy");
+        std::fs::copy(&path, &copy)?;
+        let other = SharedMap::map(&cap_std::fs::File::from_std(std::fs::File::open(&copy)?))?;
+        assert_ne!(first.as_ptr(), other.as_ptr());
+
+        // the other tests of the process may have metadata mapped too
+        let stats = mapping_stats();
+        assert!(stats.mappings >= 2 && stats.readers >= 3);
+        assert!(stats.mapped_bytes >= 6 * page_size() as u64);
+        assert!(stats.resident_bytes >= 3 * page_size() as u64);
+
+        drop((first, second));
+        let first = SharedMap::map(&open())?;
+        assert_eq!(first.len(), 3 * page_size());
+        Ok(())
+    }
+}
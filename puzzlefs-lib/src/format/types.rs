@@ -1,9 +1,8 @@
 use capnp::{message, serialize};
-use memmap2::{Mmap, MmapOptions};
 use nix::errno::Errno;
 use nix::sys::stat;
 use std::backtrace::Backtrace;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fmt;
@@ -13,19 +12,26 @@ use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::Path;
+use std::sync::Mutex;
 
 use serde::de::Error as SerdeError;
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::debug;
 
 use super::error::{Result, WireFormatError};
+use super::limits::MetadataLimits;
+use super::shared_map::SharedMap;
+use crate::xattr_filter::XattrFilter;
 use hex::FromHexError;
 
 pub const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
 pub const SHA256_BLOCK_SIZE: usize = 32;
+pub const SHA512_BLOCK_SIZE: usize = 64;
 // We use a BTreeMap instead of a HashMap because the BTreeMap is sorted, thus we get a
-// reproducible representation of the serialized metadata
-pub type VerityData = BTreeMap<[u8; SHA256_BLOCK_SIZE], [u8; SHA256_BLOCK_SIZE]>;
+// reproducible representation of the serialized metadata. The fs-verity digests are sha256 or
+// sha512, depending on the algorithm chosen at build time.
+pub type VerityData = BTreeMap<[u8; SHA256_BLOCK_SIZE], Vec<u8>>;
 
 #[derive(Debug)]
 pub struct Rootfs {
@@ -54,7 +60,7 @@ impl Rootfs {
 
         for capnp_verity in capnp_verities {
             let digest = capnp_verity.get_digest()?.try_into()?;
-            let verity = capnp_verity.get_verity()?.try_into()?;
+            let verity = capnp_verity.get_verity()?.to_vec();
             fs_verity_data.insert(digest, verity);
         }
 
@@ -80,10 +86,17 @@ impl Rootfs {
             InodeVector::fill_capnp(metadata, &mut capnp_metadata)?;
         }
 
-        let verity_data_len = self.fs_verity_data.len().try_into()?;
+        Self::fill_verity_capnp(&self.fs_verity_data, builder)
+    }
+
+    pub(crate) fn fill_verity_capnp(
+        fs_verity_data: &VerityData,
+        builder: &mut crate::metadata_capnp::rootfs::Builder<'_>,
+    ) -> Result<()> {
+        let verity_data_len = fs_verity_data.len().try_into()?;
         let mut capnp_verities = builder.reborrow().init_fs_verity_data(verity_data_len);
 
-        for (i, (digest, verity)) in self.fs_verity_data.iter().enumerate() {
+        for (i, (digest, verity)) in fs_verity_data.iter().enumerate() {
             // we already checked that the length of verity_data fits inside a u32
             let mut capnp_verity = capnp_verities.reborrow().get(i as u32);
             capnp_verity.set_digest(digest);
@@ -96,23 +109,33 @@ impl Rootfs {
 
 pub struct RootfsReader {
     reader: message::TypedReader<
-        ::capnp::serialize::BufferSegments<Mmap>,
+        ::capnp::serialize::BufferSegments<SharedMap>,
         crate::metadata_capnp::rootfs::Owned,
     >,
+    // the directories spread across layers, merged the first time they're found
+    merged_dirs: Mutex<HashMap<Ino, Inode>>,
 }
 
 impl RootfsReader {
-    pub fn open(f: cap_std::fs::File) -> Result<Self> {
-        // We know the loaded message is safe, so we're allowing unlimited reads.
+    /// Reads the metadata blob `f`, sharing its mapping with the other readers of the blob in the
+    /// process. The metadata is checked against `limits` by each reader, since they may have
+    /// different limits.
+    pub fn open(f: cap_std::fs::File, limits: &MetadataLimits) -> Result<Self> {
+        let mmapped_region = SharedMap::map(&f)?;
+        limits.check(&mmapped_region)?;
+        // the whole message was just checked, so the reads don't count against a traversal limit,
+        // which a long running reader would eventually run out of
         let unlimited_reads = message::ReaderOptions {
             traversal_limit_in_words: None,
-            nesting_limit: 64,
+            nesting_limit: limits.nesting_limit,
         };
-        let mmapped_region = unsafe { MmapOptions::new().map_copy_read_only(&f)? };
         let segments = serialize::BufferSegments::new(mmapped_region, unlimited_reads)?;
         let reader = message::Reader::new(segments, unlimited_reads).into_typed();
 
-        Ok(Self { reader })
+        Ok(Self {
+            reader,
+            merged_dirs: Mutex::new(HashMap::new()),
+        })
     }
 
     pub fn get_manifest_version(&self) -> Result<u64> {
@@ -125,26 +148,81 @@ impl RootfsReader {
         let capnp_verities = self.reader.get()?.get_fs_verity_data()?;
         for capnp_verity in capnp_verities {
             let digest = capnp_verity.get_digest()?.try_into()?;
-            let verity = capnp_verity.get_verity()?.try_into()?;
+            let verity = capnp_verity.get_verity()?.to_vec();
             fs_verity_data.insert(digest, verity);
         }
         Ok(fs_verity_data)
     }
 
     pub fn find_inode(&self, ino: u64) -> Result<Inode> {
-        for layer in self.reader.get()?.get_metadatas()?.iter() {
-            let inode_vector = InodeVector { reader: layer };
+        if let Some(dir) = self.merged_dirs.lock().unwrap().get(&ino) {
+            return Ok(dir.clone());
+        }
+        let inode =
+            resolve_inode(self, ino)?.ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+        // merging sorts the entries of all the layers and checks them for whiteouts, which the
+        // lookups and listings of a directory would otherwise do each time
+        if matches!(inode.mode, InodeMode::Dir { .. }) && self.layer_count()? > 1 {
+            self.merged_dirs.lock().unwrap().insert(ino, inode.clone());
+        }
+        Ok(inode)
+    }
 
-            if let Some(inode) = inode_vector.find_inode(ino)? {
-                let inode = Inode::from_capnp(inode)?;
-                if let InodeMode::Wht = inode.mode {
-                    // TODO: seems like this should really be an Option.
-                    return Err(WireFormatError::from_errno(Errno::ENOENT));
+    /// Finds `name` in the directory numbered `dir` the way [`resolve_inode`] merges it, without
+    /// merging it: the entries of each layer are binary searched in place, so looking up a name in
+    /// a big directory spread across layers doesn't copy all of them.
+    pub fn dir_lookup(&self, dir: Ino, name: &[u8]) -> Result<Ino> {
+        use crate::metadata_capnp::inode::mode;
+
+        let metadatas = self.reader.get()?.get_metadatas()?;
+        let mut found_dir = false;
+        for layer in 0..metadatas.len() {
+            let inodes = InodeVector {
+                reader: metadatas.get(layer),
+            };
+            let Some(inode) = inodes.find_inode(dir)? else {
+                continue;
+            };
+            let dir_list = match inode.get_mode().which() {
+                Ok(mode::Dir(dir_list)) => dir_list?,
+                // a file the directory replaced, or a whiteout hiding what's below
+                _ if found_dir => break,
+                Ok(mode::Wht(())) => break,
+                _ => return Err(WireFormatError::from_errno(Errno::ENOTDIR)),
+            };
+            let entries = dir_list.get_entries()?;
+            // the entries are sorted by name
+            let (mut left, mut right) = (0, entries.len());
+            while left < right {
+                let mid = left + (right - left) / 2;
+                let entry = entries.get(mid);
+                match entry.get_name()?.cmp(name) {
+                    std::cmp::Ordering::Less => left = mid + 1,
+                    std::cmp::Ordering::Greater => right = mid,
+                    std::cmp::Ordering::Equal => {
+                        let ino = entry.get_ino();
+                        let mut hidden = false;
+                        // the entries of the topmost directory are never hidden
+                        if found_dir {
+                            for above in 0..=layer as usize {
+                                if self.is_whiteout(above, ino)? {
+                                    hidden = true;
+                                    break;
+                                }
+                            }
+                        }
+                        if !hidden {
+                            return Ok(ino);
+                        }
+                        break;
+                    }
                 }
-                return Ok(inode);
+            }
+            found_dir = true;
+            if !dir_list.get_look_below() || dir_list.get_opaque() {
+                break;
             }
         }
-
         Err(WireFormatError::from_errno(Errno::ENOENT))
     }
 
@@ -160,6 +238,119 @@ impl RootfsReader {
     }
 }
 
+impl MetadataLayers for RootfsReader {
+    fn layer_count(&self) -> Result<usize> {
+        Ok(self.reader.get()?.get_metadatas()?.len() as usize)
+    }
+
+    fn layer_inode(&self, layer: usize, ino: Ino) -> Result<Option<Inode>> {
+        let layer = self.reader.get()?.get_metadatas()?.get(layer as u32);
+        InodeVector { reader: layer }
+            .find_inode(ino)?
+            .map(Inode::from_capnp)
+            .transpose()
+    }
+
+    fn is_whiteout(&self, layer: usize, ino: Ino) -> Result<bool> {
+        let layer = self.reader.get()?.get_metadatas()?.get(layer as u32);
+        let Some(inode) = InodeVector { reader: layer }.find_inode(ino)? else {
+            return Ok(false);
+        };
+        Ok(matches!(
+            inode.get_mode().which(),
+            Ok(crate::metadata_capnp::inode::mode::Wht(()))
+        ))
+    }
+}
+
+// the layers of a deserialized Rootfs, each sorted by inode number
+impl MetadataLayers for [Vec<Inode>] {
+    fn layer_count(&self) -> Result<usize> {
+        Ok(self.len())
+    }
+
+    fn layer_inode(&self, layer: usize, ino: Ino) -> Result<Option<Inode>> {
+        let inodes = &self[layer];
+        Ok(inodes
+            .binary_search_by_key(&ino, |inode| inode.ino)
+            .ok()
+            .map(|i| inodes[i].clone()))
+    }
+
+    fn is_whiteout(&self, layer: usize, ino: Ino) -> Result<bool> {
+        let inodes = &self[layer];
+        Ok(inodes
+            .binary_search_by_key(&ino, |inode| inode.ino)
+            .is_ok_and(|i| matches!(inodes[i].mode, InodeMode::Wht)))
+    }
+}
+
+/// The metadata layers of an image, topmost first, as far as finding inodes goes.
+pub(crate) trait MetadataLayers {
+    fn layer_count(&self) -> Result<usize>;
+    /// The inode numbered `ino` in `layer`, if it has one.
+    fn layer_inode(&self, layer: usize, ino: Ino) -> Result<Option<Inode>>;
+    /// Whether `layer` has a whiteout numbered `ino`.
+    fn is_whiteout(&self, layer: usize, ino: Ino) -> Result<bool>;
+}
+
+/// Finds the inode numbered `ino` in `layers`; whiteouts are None. The topmost layer having the
+/// inode wins, except that a directory with `look_below` also gets the entries of the same
+/// directory in the layers below it which it doesn't have (by name), unless a layer above the
+/// one they come from, or that layer itself, has a whiteout for them. Merging stops at an
+/// `opaque` directory. The resolved directory has all its entries, so its `look_below` is false,
+/// and it's `opaque` if the topmost one is.
+pub(crate) fn resolve_inode<L: MetadataLayers + ?Sized>(
+    layers: &L,
+    ino: Ino,
+) -> Result<Option<Inode>> {
+    let mut below = 0..layers.layer_count()?;
+    let mut inode = loop {
+        let Some(layer) = below.next() else {
+            return Ok(None);
+        };
+        if let Some(inode) = layers.layer_inode(layer, ino)? {
+            break inode;
+        }
+    };
+    if let InodeMode::Wht = inode.mode {
+        return Ok(None);
+    }
+    if let InodeMode::Dir { dir_list } = &mut inode.mode {
+        for layer in below {
+            if !dir_list.look_below || dir_list.opaque {
+                break;
+            }
+            let Some(lower) = layers.layer_inode(layer, ino)? else {
+                continue;
+            };
+            // a whiteout, or a file the directory replaced
+            let InodeMode::Dir { dir_list: lower } = lower.mode else {
+                break;
+            };
+            for entry in lower.entries {
+                let mut hidden = false;
+                for above in 0..=layer {
+                    if layers.is_whiteout(above, entry.ino)? {
+                        hidden = true;
+                        break;
+                    }
+                }
+                if !hidden {
+                    dir_list.entries.push(entry);
+                }
+            }
+            // the sort is stable, so the entries of the upper layers come first and hide the
+            // ones with the same name below
+            dir_list.entries.sort_by(|a, b| a.name.cmp(&b.name));
+            dir_list.entries.dedup_by(|a, b| a.name == b.name);
+            dir_list.look_below = lower.look_below && !lower.opaque;
+        }
+        dir_list.look_below = false;
+    }
+    Ok(Some(inode))
+}
+
 // TODO: should this be an ociv1 digest and include size and media type?
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BlobRef {
@@ -184,16 +375,19 @@ impl BlobRef {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DirEnt {
     pub ino: Ino,
     pub name: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DirList {
     // TODO: flags instead?
     pub look_below: bool,
+    /// The directory replaced the one the layers below have, so none of their entries show
+    /// through, and they don't need whiteouts.
+    pub opaque: bool,
     pub entries: Vec<DirEnt>,
 }
 
@@ -202,7 +396,7 @@ pub struct FileChunkList {
     pub chunks: Vec<FileChunk>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileChunk {
     pub blob: BlobRef,
     pub len: u64,
@@ -298,6 +492,18 @@ mod tests {
                         },
                         len: 100,
                     }],
+                    inline: None,
+                },
+                uid: 0,
+                gid: 0,
+                permissions: DEFAULT_FILE_PERMISSIONS,
+                additional: None,
+            },
+            Inode {
+                ino: 3,
+                mode: InodeMode::File {
+                    chunks: Vec::new(),
+                    inline: Some(b"nameserver 127.0.0.1\n".to_vec()),
                 },
                 uid: 0,
                 gid: 0,
@@ -327,6 +533,22 @@ mod tests {
                         val: b"with some value".to_vec(),
                     }],
                     symlink_target: Some(b"some/other/path".to_vec()),
+                    digest: None,
+                }),
+            },
+            Inode {
+                ino: 0,
+                mode: InodeMode::File {
+                    chunks: Vec::new(),
+                    inline: Some(b"meshuggah rocks".to_vec()),
+                },
+                uid: 0,
+                gid: 0,
+                permissions: 0o644,
+                additional: Some(InodeAdditional {
+                    xattrs: Vec::new(),
+                    symlink_target: None,
+                    digest: Some([0x42; 32]),
                 }),
             },
         ];
@@ -347,7 +569,7 @@ mod tests {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Inode {
     pub ino: Ino,
     pub mode: InodeMode,
@@ -422,6 +644,27 @@ impl Inode {
 
         let mode = InodeMode::File {
             chunks: file_chunks,
+            inline: None,
+        };
+        Ok(Self::new_inode(ino, md, mode, additional))
+    }
+
+    pub fn new_inline_file(
+        ino: Ino,
+        md: &fs::Metadata,
+        data: Vec<u8>,
+        additional: Option<InodeAdditional>,
+    ) -> io::Result<Self> {
+        if !md.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{ino} is a file"),
+            ));
+        }
+
+        let mode = InodeMode::File {
+            chunks: Vec::new(),
+            inline: Some(data),
         };
         Ok(Self::new_inode(ino, md, mode, additional))
     }
@@ -508,11 +751,19 @@ impl Inode {
     }
 
     pub fn file_len(&self) -> Result<u64> {
-        let chunks = match &self.mode {
-            InodeMode::File { chunks } => chunks,
-            _ => return Err(WireFormatError::from_errno(Errno::ENOTDIR)),
-        };
-        Ok(chunks.iter().map(|c| c.len).sum())
+        match &self.mode {
+            InodeMode::File {
+                inline: Some(data), ..
+            } => Ok(data.len() as u64),
+            InodeMode::File { chunks, .. } => Ok(chunks.iter().map(|c| c.len).sum()),
+            _ => Err(WireFormatError::from_errno(Errno::ENOTDIR)),
+        }
+    }
+
+    /// The sha256 of the contents of a regular file, for images built with
+    /// [`crate::builder::BuilderConfig::file_digests`].
+    pub fn file_digest(&self) -> Option<[u8; 32]> {
+        self.additional.as_ref().and_then(|a| a.digest)
     }
 
     pub fn symlink_target(&self) -> Result<&OsStr> {
@@ -539,14 +790,27 @@ impl Inode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InodeMode {
     Unknown,
     Fifo,
-    Chr { major: u64, minor: u64 },
-    Dir { dir_list: DirList },
-    Blk { major: u64, minor: u64 },
-    File { chunks: Vec<FileChunk> },
+    Chr {
+        major: u64,
+        minor: u64,
+    },
+    Dir {
+        dir_list: DirList,
+    },
+    Blk {
+        major: u64,
+        minor: u64,
+    },
+    /// A regular file; small files may have their contents `inline` in the metadata, in which
+    /// case they have no chunks.
+    File {
+        chunks: Vec<FileChunk>,
+        inline: Option<Vec<u8>>,
+    },
     Lnk,
     Sock,
     Wht,
@@ -580,8 +844,15 @@ impl InodeMode {
                     .iter()
                     .map(FileChunk::from_capnp)
                     .collect::<Result<Vec<FileChunk>>>()?;
-                Ok(InodeMode::File { chunks })
+                Ok(InodeMode::File {
+                    chunks,
+                    inline: None,
+                })
             }
+            Ok(crate::metadata_capnp::inode::mode::InlineData(reader)) => Ok(InodeMode::File {
+                chunks: Vec::new(),
+                inline: Some(reader?.to_vec()),
+            }),
             Ok(crate::metadata_capnp::inode::mode::Dir(reader)) => {
                 let r = reader?;
                 let entries = r
@@ -600,6 +871,7 @@ impl InodeMode {
                 Ok(InodeMode::Dir {
                     dir_list: DirList {
                         look_below,
+                        opaque: r.get_opaque(),
                         entries,
                     },
                 })
@@ -625,6 +897,7 @@ impl InodeMode {
             Self::Dir { dir_list } => {
                 let mut dir_builder = builder.reborrow().init_dir();
                 dir_builder.set_look_below(dir_list.look_below);
+                dir_builder.set_opaque(dir_list.opaque);
                 let entries_len = dir_list.entries.len().try_into()?;
                 let mut entries_builder = dir_builder.reborrow().init_entries(entries_len);
 
@@ -640,7 +913,10 @@ impl InodeMode {
                 blk_builder.set_minor(*minor);
                 blk_builder.set_major(*major);
             }
-            Self::File { chunks } => {
+            Self::File {
+                inline: Some(data), ..
+            } => builder.set_inline_data(data),
+            Self::File { chunks, .. } => {
                 let chunks_len = chunks.len().try_into()?;
                 let mut chunks_builder = builder.reborrow().init_file(chunks_len);
 
@@ -660,17 +936,18 @@ impl InodeMode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct InodeAdditional {
     pub xattrs: Vec<Xattr>,
     pub symlink_target: Option<Vec<u8>>,
+    pub digest: Option<[u8; 32]>,
 }
 
 impl InodeAdditional {
     pub fn from_capnp(
         reader: crate::metadata_capnp::inode_additional::Reader<'_>,
     ) -> Result<Option<Self>> {
-        if !(reader.has_xattrs() || reader.has_symlink_target()) {
+        if !(reader.has_xattrs() || reader.has_symlink_target() || reader.has_digest()) {
             return Ok(None);
         }
 
@@ -688,9 +965,16 @@ impl InodeAdditional {
             None
         };
 
+        let digest = if reader.has_digest() {
+            Some(reader.get_digest()?.try_into()?)
+        } else {
+            None
+        };
+
         Ok(Some(InodeAdditional {
             xattrs,
             symlink_target,
+            digest,
         }))
     }
 
@@ -711,29 +995,41 @@ impl InodeAdditional {
             builder.set_symlink_target(symlink_target);
         }
 
+        if let Some(digest) = &self.digest {
+            builder.set_digest(digest);
+        }
+
         Ok(())
     }
 
-    pub fn new(p: &Path, md: &fs::Metadata) -> io::Result<Option<Self>> {
+    pub fn new(p: &Path, md: &fs::Metadata, filter: &XattrFilter) -> io::Result<Option<Self>> {
         let symlink_target = if md.file_type().is_symlink() {
             let t = fs::read_link(p)?;
             Some(OsString::from(t).into_vec())
         } else {
             None
         };
-        let xattrs = Self::get_xattrs(p)?;
+        let xattrs = Self::get_xattrs(p, filter)?;
         if symlink_target.is_none() && xattrs.is_empty() {
             Ok(None)
         } else {
             Ok(Some(InodeAdditional {
                 xattrs,
                 symlink_target,
+                digest: None,
             }))
         }
     }
 
-    fn get_xattrs(p: &Path) -> io::Result<Vec<Xattr>> {
+    fn get_xattrs(p: &Path, filter: &XattrFilter) -> io::Result<Vec<Xattr>> {
         xattr::list(p)?
+            .filter(|xa| {
+                let keep = filter.keeps(xa.as_bytes());
+                if !keep {
+                    debug!("dropping xattr {xa:?} of {}", p.display());
+                }
+                keep
+            })
             .map(|xa| {
                 let value = xattr::get(p, &xa)?;
                 Ok(Xattr {
@@ -745,7 +1041,7 @@ impl InodeAdditional {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Xattr {
     pub key: Vec<u8>,
     pub val: Vec<u8>,
@@ -779,7 +1075,9 @@ impl<'a> InodeVector<'a> {
     pub fn find_inode(&self, ino: Ino) -> Result<Option<crate::metadata_capnp::inode::Reader<'_>>> {
         let mut left = 0;
         let inodes = self.get_inode_vector()?;
-        let mut right = inodes.len() - 1;
+        let Some(mut right) = inodes.len().checked_sub(1) else {
+            return Ok(None);
+        };
 
         while left <= right {
             let mid = left + (right - left) / 2;
@@ -805,8 +1103,10 @@ impl<'a> InodeVector<'a> {
 
     pub fn max_ino(&self) -> Result<Option<Ino>> {
         let inodes = self.get_inode_vector()?;
-        let last_index = inodes.len() - 1;
-        Ok(Some(inodes.get(last_index).get_ino()))
+        Ok(inodes
+            .len()
+            .checked_sub(1)
+            .map(|last_index| inodes.get(last_index).get_ino()))
     }
 
     pub fn from_capnp(
@@ -819,7 +1119,7 @@ impl<'a> InodeVector<'a> {
             .collect()
     }
 
-    fn fill_capnp(
+    pub(crate) fn fill_capnp(
         inodes: &[Inode],
         builder: &mut crate::metadata_capnp::inode_vector::Builder<'_>,
     ) -> Result<()> {
@@ -1,34 +1,132 @@
-use crate::format::{Result, WireFormatError, SHA256_BLOCK_SIZE};
+use crate::format::{Result, WireFormatError, SHA256_BLOCK_SIZE, SHA512_BLOCK_SIZE};
 use std::backtrace::Backtrace;
-use std::io::Write;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
 
 pub use fs_verity::linux::fsverity_enable;
 use fs_verity::linux::fsverity_measure;
-use fs_verity::FsVeritySha256;
 pub use fs_verity::InnerHashAlgorithm;
+use fs_verity::{FsVeritySha256, FsVeritySha512};
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::X509;
 use sha2::Digest;
 
 pub const FS_VERITY_BLOCK_SIZE_DEFAULT: usize = 4096;
 
-pub fn get_fs_verity_digest(data: &[u8]) -> Result<[u8; SHA256_BLOCK_SIZE]> {
-    let mut digest = FsVeritySha256::new();
-    digest.write_all(data)?;
-    let result = digest.finalize();
-    Ok(result.into())
+/// The hash algorithm of fs-verity digests.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VerityHash {
+    #[default]
+    Sha256,
+    Sha512,
 }
 
-pub fn check_fs_verity(file: &cap_std::fs::File, expected: &[u8]) -> Result<()> {
-    if expected.len() != SHA256_BLOCK_SIZE {
-        return Err(WireFormatError::InvalidFsVerityData(
-            format!(
-                "fsverity invalid SHA256 hash length {}",
-                hex::encode(expected),
-            ),
-            Backtrace::capture(),
-        ));
+impl VerityHash {
+    /// Returns the algorithm of `digest`, which is told by its length.
+    pub fn from_digest(digest: &[u8]) -> Result<Self> {
+        match digest.len() {
+            SHA256_BLOCK_SIZE => Ok(VerityHash::Sha256),
+            SHA512_BLOCK_SIZE => Ok(VerityHash::Sha512),
+            _ => Err(WireFormatError::InvalidFsVerityData(
+                format!("fsverity invalid hash length {}", hex::encode(digest)),
+                Backtrace::capture(),
+            )),
+        }
+    }
+
+    pub fn inner_hash_algorithm(self) -> InnerHashAlgorithm {
+        match self {
+            VerityHash::Sha256 => InnerHashAlgorithm::Sha256,
+            VerityHash::Sha512 => InnerHashAlgorithm::Sha512,
+        }
+    }
+
+    // FS_VERITY_HASH_ALG_* from linux/fsverity.h
+    fn kernel_id(self) -> u16 {
+        match self {
+            VerityHash::Sha256 => 1,
+            VerityHash::Sha512 => 2,
+        }
+    }
+}
+
+impl FromStr for VerityHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(VerityHash::Sha256),
+            "sha512" => Ok(VerityHash::Sha512),
+            _ => Err(format!("unknown fs-verity hash algorithm {s}")),
+        }
     }
-    let (_, measurement) = fsverity_measure(file.as_raw_fd())?;
+}
+
+impl fmt::Display for VerityHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerityHash::Sha256 => write!(f, "sha256"),
+            VerityHash::Sha512 => write!(f, "sha512"),
+        }
+    }
+}
+
+pub fn get_fs_verity_digest(data: &[u8], hash: VerityHash) -> Result<Vec<u8>> {
+    read_fs_verity_digest(data, hash)
+}
+
+/// Returns the fs-verity digest of what `reader` reads, without holding it all in memory.
+pub fn read_fs_verity_digest(mut reader: impl Read, hash: VerityHash) -> Result<Vec<u8>> {
+    let mut digest = VerityDigest::new(hash);
+    io::copy(&mut reader, &mut digest)?;
+    Ok(digest.finalize())
+}
+
+/// The fs-verity digest of what's written to it, computed as it goes.
+pub(crate) enum VerityDigest {
+    Sha256(FsVeritySha256),
+    Sha512(FsVeritySha512),
+}
+
+impl VerityDigest {
+    pub(crate) fn new(hash: VerityHash) -> Self {
+        match hash {
+            VerityHash::Sha256 => VerityDigest::Sha256(FsVeritySha256::new()),
+            VerityHash::Sha512 => VerityDigest::Sha512(FsVeritySha512::new()),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            VerityDigest::Sha256(digest) => digest.finalize().to_vec(),
+            VerityDigest::Sha512(digest) => digest.finalize().to_vec(),
+        }
+    }
+}
+
+impl io::Write for VerityDigest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            VerityDigest::Sha256(digest) => Digest::update(digest, buf),
+            VerityDigest::Sha512(digest) => Digest::update(digest, buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn check_fs_verity(file: &cap_std::fs::File, expected: &[u8]) -> Result<()> {
+    VerityHash::from_digest(expected)?;
+    let measurement = get_fs_verity_measurement(file)?;
 
     if *expected != measurement[..] {
         return Err(WireFormatError::InvalidFsVerityData(
@@ -43,3 +141,134 @@ pub fn check_fs_verity(file: &cap_std::fs::File, expected: &[u8]) -> Result<()>
 
     Ok(())
 }
+
+/// Returns the fs-verity digest of `file` as measured by the kernel, failing if the file doesn't
+/// have fs-verity enabled.
+pub fn get_fs_verity_measurement(file: &cap_std::fs::File) -> Result<Vec<u8>> {
+    let (_, measurement) = fsverity_measure(file.as_raw_fd()).map_err(|e| {
+        if e.raw_os_error() == Some(nix::libc::ENODATA) {
+            WireFormatError::InvalidFsVerityData(
+                "fs-verity is not enabled".to_string(),
+                Backtrace::capture(),
+            )
+        } else {
+            WireFormatError::from(e)
+        }
+    })?;
+    Ok(measurement[..].to_vec())
+}
+
+pub(crate) fn enable_verity_for_file(
+    file: &cap_std::fs::File,
+    hash: VerityHash,
+    signature: &[u8],
+) -> Result<()> {
+    if let Err(e) = fsverity_enable(
+        file.as_raw_fd(),
+        FS_VERITY_BLOCK_SIZE_DEFAULT,
+        hash.inner_hash_algorithm(),
+        signature,
+    ) {
+        // if fsverity is enabled, ignore the error
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(WireFormatError::from(e));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn enable_and_check_verity_for_file(
+    file: &cap_std::fs::File,
+    expected: &[u8],
+    signer: Option<&FsVeritySigner>,
+) -> Result<()> {
+    let signature = signer
+        .map(|signer| signer.sign(expected))
+        .transpose()?
+        .unwrap_or_default();
+    // the hash algorithm is implied by the length of the digest we expect
+    enable_verity_for_file(file, VerityHash::from_digest(expected)?, &signature)?;
+    check_fs_verity(file, expected)
+}
+
+/// Signs fs-verity digests for kernels which only accept signed files
+/// (`fs.verity.require_signatures`); the certificate has to be loaded in the `.fs-verity` keyring.
+pub struct FsVeritySigner {
+    key: PKey<Private>,
+    cert: X509,
+}
+
+impl FsVeritySigner {
+    /// Loads the PEM encoded private key and certificate.
+    pub fn from_pem_files(key: &Path, cert: &Path) -> Result<Self> {
+        Ok(FsVeritySigner {
+            key: PKey::private_key_from_pem(&fs::read(key)?)?,
+            cert: X509::from_pem(&fs::read(cert)?)?,
+        })
+    }
+
+    /// Returns the PKCS#7 signature of a fs-verity digest, in the format expected by
+    /// `fsverity_enable`: a detached signature of the `fsverity_formatted_digest` struct, without
+    /// authenticated attributes and certificates, like `fsverity sign` generates.
+    pub fn sign(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        let mut formatted_digest = b"FSVerity".to_vec();
+        formatted_digest
+            .extend_from_slice(&VerityHash::from_digest(digest)?.kernel_id().to_le_bytes());
+        formatted_digest.extend_from_slice(&u16::try_from(digest.len())?.to_le_bytes());
+        formatted_digest.extend_from_slice(digest);
+
+        let signature = Pkcs7::sign(
+            &self.cert,
+            &self.key,
+            &Stack::new()?,
+            &formatted_digest,
+            Pkcs7Flags::BINARY
+                | Pkcs7Flags::DETACHED
+                | Pkcs7Flags::NOATTR
+                | Pkcs7Flags::NOCERTS
+                | Pkcs7Flags::NOSMIMECAP,
+        )?;
+        Ok(signature.to_der()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::rsa::Rsa;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::X509Builder;
+
+    #[test]
+    fn test_sign() -> anyhow::Result<()> {
+        let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        let mut cert = X509Builder::new()?;
+        cert.set_pubkey(&key)?;
+        cert.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+        cert.set_not_after(&*Asn1Time::days_from_now(1)?)?;
+        cert.sign(&key, MessageDigest::sha256())?;
+        let cert = cert.build();
+
+        let signer = FsVeritySigner {
+            key,
+            cert: cert.clone(),
+        };
+        let digest = get_fs_verity_digest(b"puzzlefs", VerityHash::Sha256)?;
+        let signature = Pkcs7::from_der(&signer.sign(&digest)?)?;
+
+        let mut expected = b"FSVerity\x01\x00\x20\x00".to_vec();
+        expected.extend_from_slice(&digest);
+        let mut certs = Stack::new()?;
+        certs.push(cert)?;
+        signature.verify(
+            &certs,
+            &X509StoreBuilder::new()?.build(),
+            Some(&expected),
+            None,
+            Pkcs7Flags::NOVERIFY,
+        )?;
+        Ok(())
+    }
+}
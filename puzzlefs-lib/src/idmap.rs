@@ -0,0 +1,110 @@
+use std::fmt;
+use std::str::FromStr;
+
+// the id unmapped uids and gids show up as, like /proc/sys/kernel/overflowuid
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// A range of an id mapping, in the format of podman's `--uidmap`: `count` ids starting at
+/// `inside` (the ids stored in the image) map to `count` ids starting at `outside` (the ids of
+/// the host).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl FromStr for IdRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ids = s
+            .split(':')
+            .map(u32::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid id mapping {s}: {e}"))?;
+        match ids[..] {
+            [inside, outside, count]
+                if inside.checked_add(count).is_some() && outside.checked_add(count).is_some() =>
+            {
+                Ok(IdRange {
+                    inside,
+                    outside,
+                    count,
+                })
+            }
+            _ => Err(format!(
+                "invalid id mapping {s}, expected inside:outside:count"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IdRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.inside, self.outside, self.count)
+    }
+}
+
+/// Maps the uids or gids of an image to the ones of the host. The empty mapping is the identity;
+/// otherwise, the ids which aren't covered by any range become [`OVERFLOW_ID`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IdMap(Vec<IdRange>);
+
+impl IdMap {
+    pub fn new(ranges: Vec<IdRange>) -> Self {
+        IdMap(ranges)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Maps an id of the image to the host.
+    pub fn map(&self, id: u32) -> u32 {
+        if self.is_empty() {
+            return id;
+        }
+        self.0
+            .iter()
+            .find(|r| id >= r.inside && id - r.inside < r.count)
+            .map(|r| r.outside + (id - r.inside))
+            .unwrap_or(OVERFLOW_ID)
+    }
+
+    /// Maps an id of the host back to the image.
+    pub fn map_back(&self, id: u32) -> u32 {
+        if self.is_empty() {
+            return id;
+        }
+        self.0
+            .iter()
+            .find(|r| id >= r.outside && id - r.outside < r.count)
+            .map(|r| r.inside + (id - r.outside))
+            .unwrap_or(OVERFLOW_ID)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_map() {
+        let map = IdMap::new(vec![
+            "0:100000:1000".parse().unwrap(),
+            "1000:1000:1".parse().unwrap(),
+        ]);
+        assert_eq!(map.map(0), 100000);
+        assert_eq!(map.map(999), 100999);
+        assert_eq!(map.map(1000), 1000);
+        assert_eq!(map.map(1001), OVERFLOW_ID);
+        assert_eq!(map.map_back(100010), 10);
+        assert_eq!(map.map_back(5), OVERFLOW_ID);
+        assert_eq!(IdMap::default().map(42), 42);
+
+        assert!("0:100000".parse::<IdRange>().is_err());
+        assert!("0:x:1".parse::<IdRange>().is_err());
+        assert!("0:4294967295:2".parse::<IdRange>().is_err());
+    }
+}
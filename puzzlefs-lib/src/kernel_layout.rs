@@ -0,0 +1,386 @@
+//! The byte level layout of the metadata of an image, for the kernel driver.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use capnp::{message, serialize};
+use memmap2::MmapOptions;
+use ocidir::oci_spec::image::{Descriptor, MediaType};
+use serde::Serialize;
+
+use crate::builder::BuildError;
+use crate::format::{Ino, Result};
+use crate::metadata_capnp::{inode, rootfs};
+use crate::oci::media_types::PUZZLEFS_ROOTFS;
+use crate::oci::{Image, ImageError};
+
+/// How deep directories may be nested below the root.
+pub const KERNEL_MAX_DEPTH: usize = 256;
+
+// the first bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl fmt::Display for Extent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}+{:#x}", self.offset, self.len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChunkTable {
+    pub ino: Ino,
+    pub chunks: u64,
+    pub extent: Extent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LayerLayout {
+    /// The list of inodes of the layer.
+    pub inodes: Extent,
+    pub inode_count: u64,
+    pub chunk_tables: Vec<ChunkTable>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KernelLayout {
+    /// The digest of the rootfs blob.
+    pub rootfs: String,
+    pub size: u64,
+    pub manifest_version: u64,
+    /// The segments of the capnp message, after its segment table.
+    pub segments: Vec<Extent>,
+    /// The metadata layers, topmost first.
+    pub layers: Vec<LayerLayout>,
+    /// The list of fs-verity digests of the blobs.
+    pub verity_data: Extent,
+    /// How deep the directories are nested.
+    pub max_depth: usize,
+    /// The invariants the image violates.
+    pub violations: Vec<String>,
+}
+
+impl KernelLayout {
+    pub fn is_compatible(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// Fails with all the violations, if any.
+    pub fn check(&self) -> Result<()> {
+        if self.is_compatible() {
+            return Ok(());
+        }
+        Err(BuildError::KernelCompat {
+            reason: self.violations.join("; "),
+        }
+        .into())
+    }
+}
+
+impl fmt::Display for KernelLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rootfs: {} ({} bytes)", self.rootfs, self.size)?;
+        writeln!(f, "manifest version: {}", self.manifest_version)?;
+        for (i, segment) in self.segments.iter().enumerate() {
+            writeln!(f, "segment {i}: {segment}")?;
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            writeln!(
+                f,
+                "layer {i}: {} inodes at {}",
+                layer.inode_count, layer.inodes
+            )?;
+            for table in &layer.chunk_tables {
+                writeln!(
+                    f,
+                    "  ino {}: {} chunks at {}",
+                    table.ino, table.chunks, table.extent
+                )?;
+            }
+        }
+        writeln!(f, "verity data: {}", self.verity_data)?;
+        writeln!(f, "max depth: {}", self.max_depth)?;
+        for violation in &self.violations {
+            writeln!(f, "violation: {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+// the bytes of the segments, after the segment table at the start of the message
+fn segments(buf: &[u8]) -> Option<Vec<Extent>> {
+    let word = |i: usize| {
+        buf.get(i * 4..i * 4 + 4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()) as u64)
+    };
+    let count = word(0)? + 1;
+    // the table is padded to a multiple of 8 bytes
+    let mut offset = (4 + count * 4).next_multiple_of(8);
+    let mut segments = Vec::new();
+    for i in 0..count {
+        let len = word(1 + i as usize)? * 8;
+        segments.push(Extent { offset, len });
+        offset += len;
+    }
+    (offset == buf.len() as u64).then_some(segments)
+}
+
+fn extent(buf: &[u8], bytes: &[u8]) -> Extent {
+    Extent {
+        offset: (bytes.as_ptr() as usize - buf.as_ptr() as usize) as u64,
+        len: bytes.len() as u64,
+    }
+}
+
+/// Returns the layout of the rootfs `desc` of an image.
+pub fn rootfs_layout(oci: &Image, desc: &Descriptor) -> Result<KernelLayout> {
+    let digest = desc.digest().digest();
+    let mut layout = KernelLayout {
+        rootfs: digest.to_string(),
+        size: 0,
+        manifest_version: 0,
+        segments: Vec::new(),
+        layers: Vec::new(),
+        verity_data: Extent { offset: 0, len: 0 },
+        max_depth: 0,
+        violations: Vec::new(),
+    };
+    if desc.media_type() != &MediaType::Other(PUZZLEFS_ROOTFS.to_string()) {
+        layout
+            .violations
+            .push(format!("the metadata is stored as {}", desc.media_type()));
+        return Ok(layout);
+    }
+
+    let file = oci.0.blobs_dir().open(digest)?;
+    // blobs are content addressed, nobody changes them while we read them
+    let buf = unsafe { MmapOptions::new().map_copy_read_only(&file)? };
+    layout.size = buf.len() as u64;
+    if buf.starts_with(&ZSTD_MAGIC) {
+        layout
+            .violations
+            .push("the metadata is compressed".to_string());
+        return Ok(layout);
+    }
+    layout.segments = segments(&buf).ok_or_else(|| BuildError::KernelCompat {
+        reason: "the segment table doesn't match the size of the rootfs".to_string(),
+    })?;
+
+    let limits = oci.metadata_limits();
+    limits.check(&buf)?;
+    let options = message::ReaderOptions {
+        traversal_limit_in_words: None,
+        nesting_limit: limits.nesting_limit,
+    };
+    let segments = serialize::BufferSegments::new(&buf[..], options)?;
+    let message = message::Reader::new(segments, options);
+    let root = message.get_root::<rootfs::Reader<'_>>()?;
+    layout.manifest_version = root.get_manifest_version();
+    layout.verity_data = extent(&buf, capnp::raw::get_list_bytes(root.get_fs_verity_data()?));
+
+    // the inodes which are visible, the ones of the upper layers hide the lower ones
+    let mut visible = HashMap::new();
+    for (i, metadata) in root.get_metadatas()?.iter().enumerate() {
+        let inodes = metadata.get_inodes()?;
+        let mut layer = LayerLayout {
+            inodes: extent(&buf, capnp::raw::get_list_bytes(inodes)),
+            inode_count: inodes.len() as u64,
+            chunk_tables: Vec::new(),
+        };
+        let mut previous = None;
+        for inode in inodes.iter() {
+            let ino = inode.get_ino();
+            if let Some(previous) = previous.filter(|previous| *previous >= ino) {
+                layout.violations.push(format!(
+                    "the inodes of layer {i} aren't sorted: {ino} comes after {previous}"
+                ));
+            }
+            previous = Some(ino);
+            visible.entry(ino).or_insert(inode);
+
+            match inode.get_mode().which() {
+                Ok(inode::mode::File(chunks)) => {
+                    let chunks = chunks?;
+                    layer.chunk_tables.push(ChunkTable {
+                        ino,
+                        chunks: chunks.len() as u64,
+                        extent: extent(&buf, capnp::raw::get_list_bytes(chunks)),
+                    });
+                }
+                Ok(inode::mode::InlineData(_)) => layout.violations.push(format!(
+                    "file {ino} of layer {i} has its contents inline, which the kernel driver \
+                     can't read"
+                )),
+                Ok(inode::mode::Dir(dir)) => {
+                    let dir = dir?;
+                    if dir.get_look_below() {
+                        layout.violations.push(format!(
+                            "directory {ino} of layer {i} looks below, the kernel driver doesn't \
+                             merge directories across layers"
+                        ));
+                    }
+                    let entries = dir.get_entries()?;
+                    let names = entries
+                        .iter()
+                        .map(|entry| entry.get_name())
+                        .collect::<capnp::Result<Vec<_>>>()?;
+                    if let Some(pair) = names.windows(2).find(|pair| pair[0] >= pair[1]) {
+                        layout.violations.push(format!(
+                            "the entries of directory {ino} of layer {i} aren't sorted: {:?} \
+                             comes after {:?}",
+                            String::from_utf8_lossy(pair[1]),
+                            String::from_utf8_lossy(pair[0])
+                        ));
+                    }
+                }
+                _ => (),
+            }
+        }
+        layout.layers.push(layer);
+    }
+
+    // walk the directories breadth first, so each one is reached at its lowest depth
+    let mut seen = HashSet::from([1]);
+    let mut dirs = VecDeque::from([(1, 0)]);
+    while let Some((ino, depth)) = dirs.pop_front() {
+        layout.max_depth = layout.max_depth.max(depth);
+        let Some(inode) = visible.get(&ino) else {
+            continue;
+        };
+        let Ok(inode::mode::Dir(dir)) = inode.get_mode().which() else {
+            continue;
+        };
+        for entry in dir?.get_entries()?.iter() {
+            let child = entry.get_ino();
+            let is_dir = visible
+                .get(&child)
+                .map(|child| matches!(child.get_mode().which(), Ok(inode::mode::Dir(_))))
+                .unwrap_or(false);
+            if !is_dir {
+                continue;
+            }
+            if !seen.insert(child) {
+                layout
+                    .violations
+                    .push(format!("directory {child} is linked more than once"));
+                continue;
+            }
+            dirs.push_back((child, depth + 1));
+        }
+    }
+    if layout.max_depth > KERNEL_MAX_DEPTH {
+        layout.violations.push(format!(
+            "directories are nested {} levels deep, more than {KERNEL_MAX_DEPTH}",
+            layout.max_depth
+        ));
+    }
+    Ok(layout)
+}
+
+/// Returns the layout of the rootfs of `tag`.
+pub fn kernel_layout(oci: &Image, tag: &str) -> Result<KernelLayout> {
+    let manifest = oci
+        .find_manifest(tag)?
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: tag.to_string(),
+        })?;
+    // a compressed rootfs has a media type of its own
+    let desc = manifest
+        .layers()
+        .iter()
+        .find(|desc| {
+            matches!(desc.media_type(), MediaType::Other(media_type)
+                if media_type.starts_with(PUZZLEFS_ROOTFS))
+        })
+        .ok_or_else(|| ImageError::MissingRootfs {
+            tag: tag.to_string(),
+        })?;
+    rootfs_layout(oci, desc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{build_test_fs, serialize_metadata};
+    use crate::compression::Noop;
+    use crate::format::{DirEnt, DirList, Inode, InodeMode, Rootfs, VerityData};
+    use crate::fsverity_helpers::VerityHash;
+    use crate::oci::media_types;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn dir_inode(ino: Ino, names: &[&str]) -> Inode {
+        let entries = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| DirEnt {
+                ino: ino + 1 + i as Ino,
+                name: name.as_bytes().to_vec(),
+            })
+            .collect();
+        Inode {
+            ino,
+            mode: InodeMode::Dir {
+                dir_list: DirList {
+                    look_below: false,
+                    opaque: false,
+                    entries,
+                },
+            },
+            uid: 0,
+            gid: 0,
+            permissions: 0o755,
+            additional: None,
+        }
+    }
+
+    #[test]
+    fn test_kernel_layout() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+
+        let layout = kernel_layout(&image, "test")?;
+        assert_eq!(layout.violations, Vec::<String>::new());
+        assert_eq!(layout.segments.len(), 1);
+        let segment = layout.segments[0];
+        assert_eq!(segment.offset + segment.len, layout.size);
+        assert_eq!(layout.layers.len(), 1);
+        let layer = &layout.layers[0];
+        assert_eq!(layer.inode_count, 2);
+        let table = &layer.chunk_tables[0];
+        assert_eq!(table.ino, 2);
+        assert!(table.chunks > 1);
+        assert!(table.extent.offset >= segment.offset);
+        assert!(table.extent.offset + table.extent.len <= layout.size);
+        assert_eq!(layout.max_depth, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_violations() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = serialize_metadata(Rootfs {
+            metadatas: vec![vec![dir_inode(2, &[]), dir_inode(1, &["b", "a"])]],
+            fs_verity_data: VerityData::new(),
+            manifest_version: crate::reader::PUZZLEFS_IMAGE_MANIFEST_VERSION,
+        })?;
+        let mut manifest = image.get_empty_manifest()?;
+        let blob = image.put_blob::<Noop>(
+            rootfs.as_slice(),
+            &mut manifest,
+            media_types::Rootfs {},
+            VerityHash::Sha256,
+        )?;
+
+        let layout = rootfs_layout(&image, &blob.descriptor)?;
+        assert_eq!(layout.violations.len(), 2, "{:?}", layout.violations);
+        assert!(layout.check().is_err());
+        Ok(())
+    }
+}
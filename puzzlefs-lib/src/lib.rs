@@ -3,14 +3,29 @@
 #[macro_use]
 extern crate anyhow;
 
+pub mod api;
 pub mod builder;
 mod common;
 pub mod compression;
+pub mod delta;
+pub mod disk_usage;
+pub mod export;
 pub mod extractor;
 mod format;
+pub use format::WireFormatError;
 pub mod fsverity_helpers;
+pub mod idmap;
+pub mod kernel_layout;
+pub mod metrics;
+pub mod mode_policy;
 pub mod oci;
 pub mod reader;
+pub mod symlink_policy;
+// only public for the benchmarks
+#[doc(hidden)]
+pub mod test_utils;
+pub mod trust_store;
+pub mod xattr_filter;
 
 #[allow(clippy::needless_lifetimes)]
 pub mod metadata_capnp {
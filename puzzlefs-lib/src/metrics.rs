@@ -0,0 +1,295 @@
+//! Process wide counters of the FUSE daemon, served in the Prometheus text format.
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::libc;
+use tracing::{debug_span, info, warn};
+
+use crate::format::{mapping_stats, Result};
+
+/// The FUSE operations with their own request counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Lookup,
+    Getattr,
+    Readlink,
+    Open,
+    Read,
+    Readdir,
+    Readdirplus,
+    Statfs,
+    Getxattr,
+    Listxattr,
+    Access,
+}
+
+const OPS: [(Op, &str); 11] = [
+    (Op::Lookup, "lookup"),
+    (Op::Getattr, "getattr"),
+    (Op::Readlink, "readlink"),
+    (Op::Open, "open"),
+    (Op::Read, "read"),
+    (Op::Readdir, "readdir"),
+    (Op::Readdirplus, "readdirplus"),
+    (Op::Statfs, "statfs"),
+    (Op::Getxattr, "getxattr"),
+    (Op::Listxattr, "listxattr"),
+    (Op::Access, "access"),
+];
+
+pub struct Metrics {
+    requests: [AtomicU64; OPS.len()],
+    errors: [AtomicU64; OPS.len()],
+    latency_us: [AtomicU64; OPS.len()],
+    chunk_reads: AtomicU64,
+    decompressed_bytes: AtomicU64,
+    verity_failures: AtomicU64,
+    open_handles: AtomicI64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            requests: [const { AtomicU64::new(0) }; OPS.len()],
+            errors: [const { AtomicU64::new(0) }; OPS.len()],
+            latency_us: [const { AtomicU64::new(0) }; OPS.len()],
+            chunk_reads: AtomicU64::new(0),
+            decompressed_bytes: AtomicU64::new(0),
+            verity_failures: AtomicU64::new(0),
+            open_handles: AtomicI64::new(0),
+        }
+    }
+
+    /// Runs the FUSE operation `f` in a span of its own, counting it and its latency.
+    pub(crate) fn timed<T>(&self, op: Op, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = debug_span!("fuse", op = OPS[op as usize].1).in_scope(f);
+        let op = op as usize;
+        self.requests[op].fetch_add(1, Ordering::Relaxed);
+        self.latency_us[op].fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if result.is_err() {
+            self.errors[op].fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Counts a read from a chunk; `decompressed` is the number of bytes it decompressed, if the
+    /// chunk is compressed.
+    pub(crate) fn chunk_read(&self, decompressed: Option<usize>) {
+        self.chunk_reads.fetch_add(1, Ordering::Relaxed);
+        if let Some(bytes) = decompressed {
+            self.decompressed_bytes
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn verity_failure(&self) {
+        self.verity_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handle_opened(&self) {
+        self.open_handles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handle_released(&self) {
+        self.open_handles.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        out.push_str("# HELP puzzlefs_fuse_requests_total FUSE requests served.\n");
+        out.push_str("# TYPE puzzlefs_fuse_requests_total counter\n");
+        for (op, name) in OPS {
+            let requests = load(&self.requests[op as usize]);
+            writeln!(
+                out,
+                "puzzlefs_fuse_requests_total{{op=\"{name}\"}} {requests}"
+            )
+            .unwrap();
+        }
+        out.push_str("# HELP puzzlefs_fuse_errors_total FUSE requests which failed.\n");
+        out.push_str("# TYPE puzzlefs_fuse_errors_total counter\n");
+        for (op, name) in OPS {
+            let errors = load(&self.errors[op as usize]);
+            writeln!(out, "puzzlefs_fuse_errors_total{{op=\"{name}\"}} {errors}").unwrap();
+        }
+        out.push_str("# HELP puzzlefs_fuse_request_seconds Time spent serving FUSE requests.\n");
+        out.push_str("# TYPE puzzlefs_fuse_request_seconds summary\n");
+        for (op, name) in OPS {
+            let seconds = load(&self.latency_us[op as usize]) as f64 / 1e6;
+            let requests = load(&self.requests[op as usize]);
+            writeln!(
+                out,
+                "puzzlefs_fuse_request_seconds_sum{{op=\"{name}\"}} {seconds}"
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "puzzlefs_fuse_request_seconds_count{{op=\"{name}\"}} {requests}"
+            )
+            .unwrap();
+        }
+
+        let counters = [
+            (
+                "puzzlefs_chunk_reads_total",
+                "Reads from the chunks of the image.",
+                &self.chunk_reads,
+            ),
+            (
+                "puzzlefs_decompressed_bytes_total",
+                "Bytes decompressed from compressed chunks.",
+                &self.decompressed_bytes,
+            ),
+            (
+                "puzzlefs_verity_failures_total",
+                "Blobs which failed their fs-verity check.",
+                &self.verity_failures,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} counter").unwrap();
+            writeln!(out, "{name} {}", load(counter)).unwrap();
+        }
+        out.push_str("# HELP puzzlefs_open_handles Files and directories currently open.\n");
+        out.push_str("# TYPE puzzlefs_open_handles gauge\n");
+        writeln!(
+            out,
+            "puzzlefs_open_handles {}",
+            self.open_handles.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        // the metadata is mapped once per blob, whatever the number of mounts reading it
+        let mappings = mapping_stats();
+        let gauges = [
+            (
+                "puzzlefs_metadata_mappings",
+                "Metadata blobs mapped by the process.",
+                mappings.mappings,
+            ),
+            (
+                "puzzlefs_metadata_readers",
+                "Readers sharing the mapped metadata blobs.",
+                mappings.readers,
+            ),
+            (
+                "puzzlefs_metadata_mapped_bytes",
+                "Size of the mapped metadata blobs.",
+                mappings.mapped_bytes,
+            ),
+            (
+                "puzzlefs_metadata_resident_bytes",
+                "Part of the mapped metadata blobs resident in memory.",
+                mappings.resident_bytes,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} gauge").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        }
+        if let Some(rss) = resident_set_size() {
+            out.push_str("# HELP puzzlefs_resident_bytes Resident set size of the process.\n");
+            out.push_str("# TYPE puzzlefs_resident_bytes gauge\n");
+            writeln!(out, "puzzlefs_resident_bytes {rss}").unwrap();
+        }
+        out
+    }
+}
+
+// the resident set size of the process, from the second field of /proc/self/statm, in pages
+fn resident_set_size() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64)
+}
+
+// how long a client has to send its request and read the answer; clients are served one at a
+// time, so one which hangs must not keep the others waiting for long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+// the most of a request read, which a scraper's headers are far from
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+// answers every request with the metrics, whatever its path; a scraper doesn't need more
+fn serve_client(stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_SIZE));
+    let mut line = String::new();
+    // skip the request headers
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+    let body = METRICS.render();
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Serves the metrics over HTTP on `listener`, from a thread of its own.
+pub fn serve(listener: TcpListener) -> Result<thread::JoinHandle<()>> {
+    info!("serving metrics on {}", listener.local_addr()?);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(serve_client) {
+                warn!("cannot serve metrics: {e}");
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() -> anyhow::Result<()> {
+        let metrics = Metrics::new();
+        metrics.timed(Op::Read, || Ok(()))?;
+        assert!(metrics
+            .timed(Op::Read, || Err::<(), _>(
+                crate::format::WireFormatError::from_errno(nix::errno::Errno::EIO)
+            ))
+            .is_err());
+        metrics.chunk_read(Some(100));
+        metrics.chunk_read(None);
+        metrics.handle_opened();
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("puzzlefs_fuse_requests_total{op=\"read\"} 2\n"));
+        assert!(rendered.contains("puzzlefs_fuse_errors_total{op=\"read\"} 1\n"));
+        assert!(rendered.contains("puzzlefs_chunk_reads_total 2\n"));
+        assert!(rendered.contains("puzzlefs_decompressed_bytes_total 100\n"));
+        assert!(rendered.contains("puzzlefs_open_handles 1\n"));
+        assert!(rendered.contains("# TYPE puzzlefs_metadata_mapped_bytes gauge\n"));
+        assert!(rendered.contains("# TYPE puzzlefs_resident_bytes gauge\n"));
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        serve(listener)?;
+        // a client which doesn't send its request doesn't keep the others waiting forever
+        let _idle = TcpStream::connect(addr)?;
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("# TYPE puzzlefs_open_handles gauge\n"));
+        Ok(())
+    }
+}
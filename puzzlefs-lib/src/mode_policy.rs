@@ -0,0 +1,135 @@
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The setuid and setgid bits of a mode.
+pub const SETID_BITS: u16 = 0o6000;
+
+/// A glob matching paths in an image, relative to its root: `*` matches anything but `/`, `**`
+/// matches anything and `?` matches one character other than `/`. A leading `/` is optional, so
+/// `usr/bin/*` and `/usr/bin/*` are the same pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPattern(String);
+
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let component = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=component).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(path, [c, tail @ ..] if *c != b'/' && glob_matches(rest, tail))
+        }
+        [c, rest @ ..] => matches!(path, [p, tail @ ..] if p == c && glob_matches(rest, tail)),
+    }
+}
+
+impl PathPattern {
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = path.as_os_str().as_bytes();
+        let path = path.strip_prefix(b"/").unwrap_or(path);
+        glob_matches(self.0.as_bytes(), path)
+    }
+}
+
+impl FromStr for PathPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty path pattern".to_string());
+        }
+        Ok(PathPattern(s.strip_prefix('/').unwrap_or(s).to_string()))
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.0)
+    }
+}
+
+/// Forces the mode of the paths matching a pattern, written `PATTERN=MODE` with an octal mode,
+/// e.g. `usr/bin/*=0755`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeRule {
+    pub pattern: PathPattern,
+    pub mode: u16,
+}
+
+impl FromStr for ModeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, mode) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid mode rule {s:?}, expected PATTERN=MODE"))?;
+        let mode = u16::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| format!("invalid mode {mode:?}, expected an octal mode like 0755"))?;
+        Ok(ModeRule {
+            pattern: pattern.parse()?,
+            mode,
+        })
+    }
+}
+
+impl fmt::Display for ModeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={:04o}", self.pattern, self.mode)
+    }
+}
+
+/// The mode forced on `path` by the last rule matching it, if any.
+pub fn forced_mode(rules: &[ModeRule], path: &Path) -> Option<u16> {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.pattern.matches(path))
+        .map(|rule| rule.mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_pattern() {
+        let pattern = "usr/bin/*".parse::<PathPattern>().unwrap();
+        assert!(pattern.matches(Path::new("/usr/bin/sudo")));
+        assert!(!pattern.matches(Path::new("/usr/bin/sub/sudo")));
+        assert!(!pattern.matches(Path::new("/usr/sbin/sudo")));
+
+        let pattern = "/usr/**".parse::<PathPattern>().unwrap();
+        assert!(pattern.matches(Path::new("/usr/bin/sub/sudo")));
+        assert!(!pattern.matches(Path::new("/etc/passwd")));
+
+        let pattern = "/bin/s?".parse::<PathPattern>().unwrap();
+        assert!(pattern.matches(Path::new("/bin/su")));
+        assert!(!pattern.matches(Path::new("/bin/sudo")));
+
+        let root = "/".parse::<PathPattern>().unwrap();
+        assert!(root.matches(Path::new("/")));
+        assert!("".parse::<PathPattern>().is_err());
+    }
+
+    #[test]
+    fn test_mode_rules() {
+        let rules = ["**=0644", "/usr/bin/*=0755"]
+            .iter()
+            .map(|rule| rule.parse::<ModeRule>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(forced_mode(&rules, Path::new("/usr/bin/sudo")), Some(0o755));
+        assert_eq!(forced_mode(&rules, Path::new("/etc/passwd")), Some(0o644));
+        assert_eq!(forced_mode(&rules[1..], Path::new("/etc/passwd")), None);
+        assert_eq!(rules[1].to_string(), "/usr/bin/*=0755");
+
+        assert!("/usr/bin/*".parse::<ModeRule>().is_err());
+        assert!("/usr/bin/*=0999".parse::<ModeRule>().is_err());
+        assert!("/usr/bin/*=17777".parse::<ModeRule>().is_err());
+    }
+}
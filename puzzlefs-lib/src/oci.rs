@@ -1,49 +1,779 @@
-use crate::fsverity_helpers::{check_fs_verity, get_fs_verity_digest};
+use crate::fsverity_helpers::{check_fs_verity, get_fs_verity_digest, VerityHash};
 use std::any::Any;
 use std::backtrace::Backtrace;
+use std::borrow::Cow;
 use std::fs;
 use std::io;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
 use sha2::{Digest as Sha2Digest, Sha256};
 
 use crate::compression::{Compression, Decompressor, Noop, Zstd};
-use crate::format::{Result, RootfsReader, VerityData, WireFormatError, SHA256_BLOCK_SIZE};
+use crate::format::{Result, RootfsReader, VerityData, WireFormatError};
+use crate::metrics::METRICS;
+use crate::reader::negotiate_version;
 use std::io::{Error, ErrorKind};
 
-pub use crate::format::Digest;
-use crate::oci::media_types::{PuzzleFSMediaType, PUZZLEFS_ROOTFS, VERITY_ROOT_HASH_ANNOTATION};
+pub use crate::format::{Digest, MetadataLimits};
+use crate::oci::encryption::{ChunkEncryption, DecryptionKeys, EncryptedBlob};
+use crate::oci::media_types::{
+    PuzzleFSMediaType, EMPTY_CONFIG, PUZZLEFS_ROOTFS, REQUIRE_VERITY_ANNOTATION,
+    VERITY_ROOT_HASH_ANNOTATION,
+};
+use crate::oci::remote::{BlobCache, RemoteStore};
+pub use fd_cache::DEFAULT_FD_CACHE_SIZE;
+use fd_cache::{FdCache, SharedBlob};
+pub use image_ref::{ImageRef, Reference};
 use ocidir::oci_spec::image;
-pub use ocidir::oci_spec::image::Descriptor;
-use ocidir::oci_spec::image::{ImageIndex, ImageManifest, MediaType};
+use ocidir::oci_spec::image::{
+    Arch, ImageIndex, ImageIndexBuilder, Os, PlatformBuilder, ANNOTATION_REF_NAME,
+};
+pub use ocidir::oci_spec::image::{
+    Descriptor, ImageConfiguration, ImageManifest, MediaType, Platform, ANNOTATION_REVISION,
+};
 use ocidir::OciDir;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::FromStr;
 
 use std::io::Cursor;
 
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, flock, posix_fadvise, FcntlArg, FlockArg, OFlag, PosixFadviseAdvice};
+use nix::unistd::fsync;
+use std::os::fd::AsRawFd;
+use thiserror::Error;
+use tracing::debug;
+
+#[cfg(feature = "async")]
+pub mod async_image;
+pub mod blob_store;
+pub mod copy;
+pub mod encryption;
+mod fd_cache;
+mod image_ref;
+mod legacy;
 pub mod media_types;
+pub mod remote;
+pub mod sbom;
+pub mod server;
+pub mod signature;
+
+/// An OCI image directory.
+pub struct Image(pub OciDir, ImageOptions);
+
+#[derive(Default)]
+struct ImageOptions {
+    // the platform whose manifests are used for the tags with one manifest per platform, the
+    // host's if None
+    platform: Option<Platform>,
+    // where the blobs missing from the directory are fetched from
+    remote: Option<Arc<dyn RemoteStore>>,
+    // where the fetched blobs are kept, the directory itself if None
+    cache: Option<Arc<BlobCache>>,
+    // the chunk blobs kept open
+    fds: FdCache,
+    read_hints: ReadHints,
+    metadata_limits: MetadataLimits,
+    // the private keys the encrypted chunks are decrypted with
+    decryption_keys: Option<Arc<DecryptionKeys>>,
+    // the encrypted blobs of the image, found the first time a chunk is read
+    encrypted: OnceLock<HashMap<String, EncryptedBlob>>,
+    // the most recently read encrypted chunk blobs, most recent first: files are mostly read from
+    // start to end, but concurrent readers each read their own
+    decrypted: Mutex<VecDeque<(String, Arc<[u8]>)>>,
+    // whether the blobs and index.json are synced to disk when written
+    durable: bool,
+}
+
+/// Hints on how the chunk blobs are read, to be a better neighbour on hosts serving large images.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReadHints {
+    /// Don't update the access time of the blobs when reading them. Only the owner of the blobs
+    /// and root may do that; the blobs of anyone else are read as usual.
+    pub noatime: bool,
+    /// Tell the kernel the blobs are read from start to end, so it reads ahead further.
+    pub sequential: bool,
+    /// Drop the chunks from the page cache once they're read to the end, so streaming a whole
+    /// image (e.g. extracting it) doesn't evict everything else.
+    pub drop_cache: bool,
+}
+
+/// A blob added to an image by [`Image::put_blob`].
+#[derive(Debug, Clone)]
+pub struct StoredBlob {
+    pub descriptor: Descriptor,
+    pub fs_verity_digest: Vec<u8>,
+    /// whether the blob is stored compressed, i.e. compression made it smaller
+    pub compressed: bool,
+    /// whether the image had the blob already
+    pub existing: bool,
+}
+
+/// A blob compressed and hashed by [`Image::prepare_blob`].
+pub struct PreparedBlob<'a> {
+    pub descriptor: Descriptor,
+    pub fs_verity_digest: Vec<u8>,
+    /// whether the blob is stored compressed, i.e. compression made it smaller
+    pub compressed: bool,
+    /// whether the image has the blob already
+    pub existing: bool,
+    data: Cow<'a, [u8]>,
+}
+
+/// What an image lacks, or has wrong, for an operation on one of its tags.
+#[derive(Error, Debug)]
+pub enum ImageError {
+    /// The image has no such tag.
+    #[error("missing manifest: {tag}")]
+    MissingManifest { tag: String },
+    /// `tag` is an index which has no manifest for the platform.
+    #[error("{tag} has no manifest for {platform}")]
+    MissingPlatform { tag: String, platform: String },
+    /// The manifest of `tag` has no PuzzleFS rootfs.
+    #[error("missing PuzzleFS rootfs in {tag}")]
+    MissingRootfs { tag: String },
+    /// Neither the image nor its remote has the blob.
+    #[error("missing blob {digest}")]
+    MissingBlob { digest: String },
+    /// The contents of the blob `expected` hash to `found`.
+    #[error("blob {expected} has digest {found}")]
+    DigestMismatch { expected: String, found: String },
+    /// The rootfs has a manifest version this puzzlefs can't read.
+    #[error(
+        "unsupported manifest version {found}, expected {supported}: {}",
+        version_hint(.found, .supported)
+    )]
+    UnsupportedManifestVersion { found: u64, supported: u64 },
+    /// The image has the layout of the puzzlefs releases older than the switch to ocidir, which
+    /// [`Image::upgrade`] converts.
+    #[error(
+        "{} has the layout of an older puzzlefs, convert it with `puzzlefs migrate`",
+        .path.display()
+    )]
+    LegacyLayout { path: PathBuf },
+}
+
+fn version_hint(found: &u64, supported: &u64) -> &'static str {
+    if found > supported {
+        "the image was built by a newer puzzlefs"
+    } else {
+        "this version is no longer supported, build the image again"
+    }
+}
+
+// the blobs neither the directory nor the remote has are told apart from the other errors
+fn blob_error(digest: &str, e: io::Error) -> WireFormatError {
+    if e.kind() == ErrorKind::NotFound {
+        ImageError::MissingBlob {
+            digest: digest.to_string(),
+        }
+        .into()
+    } else {
+        e.into()
+    }
+}
+
+/// Parses a platform written as `os/arch[/variant]`, e.g. `linux/arm64/v8`.
+pub fn parse_platform(s: &str) -> Result<Platform> {
+    let mut parts = s.split('/');
+    let (Some(os), Some(arch)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid platform {s}, expected os/arch[/variant]"),
+        )
+        .into());
+    };
+    let mut builder = PlatformBuilder::default()
+        .os(Os::from(os))
+        .architecture(Arch::from(arch));
+    if let Some(variant) = parts.next() {
+        builder = builder.variant(variant);
+    }
+    Ok(builder.build()?)
+}
+
+// the temporary files of this process get unique names, see write_blob_file
+static WRITE_ID: AtomicU64 = AtomicU64::new(0);
+
+// the largest manifest or index taken by digest, the limit registries put on manifests
+const MAX_MANIFEST_SIZE: u64 = 4 << 20;
 
-pub struct Image(pub OciDir);
+// how many decrypted chunk blobs an image keeps
+const DECRYPTED_CACHE_SIZE: usize = 8;
+
+fn temporary_name(name: &str) -> String {
+    // a name the garbage collection of blob stores leaves alone
+    format!(
+        ".{name}.{}.{}",
+        std::process::id(),
+        WRITE_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// whether `name` is a temporary file of temporary_name whose writer is gone; the pid may have
+// been reused since, which only delays the removal of the file
+fn writer_is_gone(name: &str) -> bool {
+    let mut parts = name.strip_prefix('.').unwrap_or_default().rsplitn(3, '.');
+    let (Some(n), Some(pid), Some(_)) = (parts.next(), parts.next(), parts.next()) else {
+        return false;
+    };
+    n.parse::<u64>().is_ok()
+        && pid
+            .parse::<u32>()
+            .is_ok_and(|pid| !Path::new("/proc").join(pid.to_string()).exists())
+}
+
+// writes `name` in `dir` through a temporary file renamed once it's complete, so that concurrent
+// writers, and readers, never see it half written; with `durable`, the file and then the rename
+// are synced to disk before returning
+fn replace_file(dir: &cap_std::fs::Dir, name: &str, data: &[u8], durable: bool) -> Result<()> {
+    let tmp = temporary_name(name);
+    let write = || -> io::Result<()> {
+        let mut file = dir.create(&tmp)?;
+        file.write_all(data)?;
+        if durable {
+            file.sync_all()?;
+        }
+        dir.rename(&tmp, dir, name)?;
+        if durable {
+            fsync(dir.as_raw_fd())?;
+        }
+        Ok(())
+    };
+    let result = write();
+    if result.is_err() {
+        let _ = dir.remove_file(&tmp);
+    }
+    Ok(result?)
+}
+
+// an exclusive flock of the oci dir, held while index.json is read, changed and written back so
+// that concurrent writers of the image don't drop each other's manifests; dropping it unlocks
+pub(crate) struct IndexLock {
+    _dir: cap_std::fs::Dir,
+}
+
+// locks the index of the image directory `oci_dir`, see Image::lock_index
+pub(crate) fn lock_dir(oci_dir: &cap_std::fs::Dir) -> Result<IndexLock> {
+    // flock locks belong to open file descriptions, which the duplicates of the descriptor of
+    // the oci dir would share with its other users in this process
+    let dir = oci_dir.open_dir(".")?;
+    let mut waited = false;
+    loop {
+        let arg = if waited {
+            FlockArg::LockExclusive
+        } else {
+            FlockArg::LockExclusiveNonblock
+        };
+        match flock(dir.as_raw_fd(), arg) {
+            Ok(()) => return Ok(IndexLock { _dir: dir }),
+            Err(Errno::EWOULDBLOCK) if !waited => {
+                debug!("waiting for another writer of the image");
+                waited = true;
+            }
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(WireFormatError::from_errno(e)),
+        }
+    }
+}
+
+// whether the manifest of platform `candidate` can serve `wanted`; a variant is only compared
+// when one is asked for
+fn platform_matches(candidate: &Platform, wanted: &Platform) -> bool {
+    candidate.os() == wanted.os()
+        && candidate.architecture() == wanted.architecture()
+        && (wanted.variant().is_none() || candidate.variant() == wanted.variant())
+}
 
 impl Image {
     pub fn new(oci_dir: &Path) -> Result<Self> {
+        // an image is only converted when it's written to
+        if oci_dir.exists() {
+            legacy::upgrade(oci_dir)?;
+        }
+        // images attached to a blob store have their blobs directory symlinked outside of the oci
+        // dir, which OciDir::ensure can't follow
+        if fs::symlink_metadata(oci_dir.join(Self::blob_path()))
+            .map(|md| md.is_symlink())
+            .unwrap_or(false)
+        {
+            return Self::open(oci_dir);
+        }
+
         fs::create_dir_all(oci_dir)?;
         let d = cap_std::fs::Dir::open_ambient_dir(oci_dir, cap_std::ambient_authority())?;
         let oci_dir = OciDir::ensure(d)?;
 
-        Ok(Self(oci_dir))
+        Ok(Self(oci_dir, ImageOptions::default()))
     }
 
+    /// Opens an existing image, without changing it. Images in the layout of the puzzlefs releases
+    /// older than the switch to ocidir are refused with [`ImageError::LegacyLayout`], see
+    /// [`Image::upgrade`].
     pub fn open(oci_dir: &Path) -> Result<Self> {
+        if legacy::is_legacy(oci_dir)? {
+            return Err(ImageError::LegacyLayout {
+                path: oci_dir.to_path_buf(),
+            }
+            .into());
+        }
         let d = cap_std::fs::Dir::open_ambient_dir(oci_dir, cap_std::ambient_authority())?;
         let blobs_dir = cap_std::fs::Dir::open_ambient_dir(
             oci_dir.join(Self::blob_path()),
             cap_std::ambient_authority(),
         )?;
         let oci_dir = OciDir::open_with_external_blobs(d, blobs_dir)?;
-        Ok(Self(oci_dir))
+        Ok(Self(oci_dir, ImageOptions::default()))
+    }
+
+    /// Opens an existing image like [`Image::open`], converting it first if it has the layout of
+    /// the puzzlefs releases older than the switch to ocidir.
+    pub fn upgrade(oci_dir: &Path) -> Result<Self> {
+        legacy::upgrade(oci_dir)?;
+        Self::open(oci_dir)
+    }
+
+    /// Uses the manifests of `platform` instead of the host's, both when reading tags with one
+    /// manifest per platform and when tagging new manifests.
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.1.platform = Some(platform);
+        self
+    }
+
+    /// Fetches the blobs the image doesn't have from `remote` the first time they're opened,
+    /// e.g. for tags which were pulled lazily, see [`remote::pull`].
+    pub fn with_remote(mut self, remote: Arc<dyn RemoteStore>) -> Self {
+        self.1.remote = Some(remote);
+        self
+    }
+
+    /// The remote blobs are fetched from, if any.
+    pub fn remote(&self) -> Option<Arc<dyn RemoteStore>> {
+        self.1.remote.clone()
+    }
+
+    /// Keeps the blobs fetched from the remote in `cache` rather than in the image directory.
+    pub fn with_cache(mut self, cache: Arc<BlobCache>) -> Self {
+        self.1.cache = Some(cache);
+        self
+    }
+
+    /// Keeps up to `size` chunk blobs open (by default [`DEFAULT_FD_CACHE_SIZE`]), so reading
+    /// the same chunks again doesn't open them again; 0 opens them for every read.
+    pub fn with_fd_cache_size(mut self, size: usize) -> Self {
+        self.1.fds = FdCache::new(size);
+        self
+    }
+
+    /// Reads the chunk blobs according to `hints`.
+    pub fn with_read_hints(mut self, hints: ReadHints) -> Self {
+        self.1.read_hints = hints;
+        self
+    }
+
+    /// How the chunk blobs are read.
+    pub fn read_hints(&self) -> ReadHints {
+        self.1.read_hints
+    }
+
+    /// Refuses to open the rootfs of the tags whose metadata isn't within `limits`.
+    pub fn with_metadata_limits(mut self, limits: MetadataLimits) -> Self {
+        self.1.metadata_limits = limits;
+        self
+    }
+
+    /// The limits the metadata of the tags is checked against when their rootfs is opened.
+    pub fn metadata_limits(&self) -> MetadataLimits {
+        self.1.metadata_limits
+    }
+
+    /// Syncs the blobs and index.json, and the directories they're in, to disk as they're
+    /// written, so a crash can't leave the index referencing a truncated blob.
+    pub fn with_durable_writes(mut self, durable: bool) -> Self {
+        self.1.durable = durable;
+        self
+    }
+
+    /// Decrypts the encrypted chunks of the image with `keys`, see [`ChunkEncryption`].
+    pub fn with_decryption_keys(mut self, keys: Arc<DecryptionKeys>) -> Self {
+        self.1.decryption_keys = Some(keys);
+        self
+    }
+
+    /// The keys the encrypted chunks are decrypted with, if any.
+    pub fn decryption_keys(&self) -> Option<Arc<DecryptionKeys>> {
+        self.1.decryption_keys.clone()
+    }
+
+    /// The cache of the blobs fetched from the remote, if any.
+    pub fn cache(&self) -> Option<Arc<BlobCache>> {
+        self.1.cache.clone()
+    }
+
+    /// The platform of the manifests this image reads and writes.
+    pub fn platform(&self) -> Platform {
+        // the default platform is the host's
+        self.1.platform.clone().unwrap_or_default()
+    }
+
+    /// The descriptor of the manifest of `tag`. Tags with one manifest per platform point to an
+    /// image index, and resolve to the manifest of [`Image::platform`].
+    ///
+    /// `tag` may also be the `sha256:<digest>` of a manifest or an index of the image, which is
+    /// then looked up directly, whatever tags point to it: tags can't have colons, so the two
+    /// can't be mistaken for one another. Wherever the image takes a tag, it takes a digest.
+    pub fn find_manifest_descriptor(&self, tag: &str) -> Result<Option<Descriptor>> {
+        let Some(desc) = self.tagged_descriptor(tag)? else {
+            return Ok(None);
+        };
+        if desc.media_type() != &MediaType::ImageIndex {
+            return Ok(Some(desc));
+        }
+        let platform = self.platform();
+        let index: ImageIndex = self.0.read_json_blob(&desc)?;
+        let manifest = index
+            .manifests()
+            .iter()
+            .find(|m| {
+                m.platform()
+                    .as_ref()
+                    .is_some_and(|p| platform_matches(p, &platform))
+            })
+            .ok_or_else(|| ImageError::MissingPlatform {
+                tag: tag.to_string(),
+                platform: format!("{}/{}", platform.os(), platform.architecture()),
+            })?;
+        Ok(Some(manifest.clone()))
+    }
+
+    /// The manifest of `tag`, see [`Image::find_manifest_descriptor`].
+    pub fn find_manifest(&self, tag: &str) -> Result<Option<ImageManifest>> {
+        self.find_manifest_descriptor(tag)?
+            .map(|desc| Ok(self.0.read_json_blob(&desc)?))
+            .transpose()
+    }
+
+    /// The manifests of every platform `tag` has, with their platform.
+    pub fn platform_manifests(&self, tag: &str) -> Result<Vec<Descriptor>> {
+        let Some(desc) = self.tagged_descriptor(tag)? else {
+            return Ok(Vec::new());
+        };
+        if desc.media_type() != &MediaType::ImageIndex {
+            return Ok(vec![desc]);
+        }
+        let index: ImageIndex = self.0.read_json_blob(&desc)?;
+        Ok(index.manifests().clone())
+    }
+
+    // the descriptor `tag` points to in index.json, or the one of the blob with that digest
+    fn tagged_descriptor(&self, tag: &str) -> Result<Option<Descriptor>> {
+        let Some(digest) = tag.strip_prefix("sha256:") else {
+            return Ok(self.0.find_manifest_descriptor_with_tag(tag)?);
+        };
+        let blob = match self.open_raw_blob(digest, None) {
+            Ok(blob) => blob,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // the blob could be any blob of the image, e.g. a chunk, and those are no manifest
+        if blob.metadata()?.len() > MAX_MANIFEST_SIZE {
+            return Ok(None);
+        }
+        let mut contents = Vec::new();
+        blob.take(MAX_MANIFEST_SIZE + 1)
+            .read_to_end(&mut contents)?;
+        if contents.len() as u64 > MAX_MANIFEST_SIZE {
+            return Ok(None);
+        }
+        let found = hex::encode(Sha256::digest(&contents));
+        if found != digest {
+            return Err(ImageError::DigestMismatch {
+                expected: digest.to_string(),
+                found,
+            }
+            .into());
+        }
+        // only indexes have a list of manifests
+        let Ok(json) = serde_json::from_slice::<serde_json::Value>(&contents) else {
+            return Ok(None);
+        };
+        let media_type = if json.get("manifests").is_some() {
+            MediaType::ImageIndex
+        } else if json.get("layers").is_some() {
+            MediaType::ImageManifest
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Descriptor::new(
+            media_type,
+            contents.len() as u64,
+            image::Digest::from_str(tag)?,
+        )))
+    }
+
+    /// Tags `manifest` as `tag` for [`Image::platform`]. The manifests `tag` has for other
+    /// platforms are kept: the tag then points to an image index listing the manifest of each
+    /// platform.
+    pub fn tag_manifest(&self, manifest: ImageManifest, tag: &str) -> Result<Descriptor> {
+        let mut desc =
+            self.write_blob(&serde_json::to_vec(&manifest)?, MediaType::ImageManifest)?;
+        desc.set_platform(Some(self.platform()));
+        self.tag_descriptor(desc, tag)
+    }
+
+    // locks index.json against the other writers of the image, in this process or others
+    pub(crate) fn lock_index(&self) -> Result<IndexLock> {
+        lock_dir(self.0.dir())
+    }
+
+    /// Tags the manifest `desc`, which must already be in the image, as `tag` for the platform of
+    /// `desc` ([`Image::platform`] if it has none), like [`Image::tag_manifest`].
+    pub(crate) fn tag_descriptor(&self, desc: Descriptor, tag: &str) -> Result<Descriptor> {
+        let _lock = self.lock_index()?;
+        self.tag_descriptor_locked(desc, tag)
+    }
+
+    // tag_descriptor, for callers which hold the lock of the index already
+    pub(crate) fn tag_descriptor_locked(
+        &self,
+        mut desc: Descriptor,
+        tag: &str,
+    ) -> Result<Descriptor> {
+        let platform = desc.platform().clone().unwrap_or_else(|| self.platform());
+        desc.set_platform(Some(platform.clone()));
+        let others = self
+            .platform_manifests(tag)?
+            .into_iter()
+            .filter(|desc| {
+                desc.platform()
+                    .as_ref()
+                    .is_some_and(|p| !platform_matches(p, &platform))
+            })
+            .collect::<Vec<_>>();
+        let mut tagged = if others.is_empty() {
+            desc.clone()
+        } else {
+            let mut manifests = others;
+            manifests.push(desc.clone());
+            let index = ImageIndexBuilder::default()
+                .schema_version(2_u32)
+                .media_type(MediaType::ImageIndex)
+                .manifests(manifests)
+                .build()?;
+            self.write_blob(&serde_json::to_vec(&index)?, MediaType::ImageIndex)?
+        };
+        tagged.set_annotations(Some(HashMap::from([(
+            ANNOTATION_REF_NAME.to_string(),
+            tag.to_string(),
+        )])));
+
+        let mut top = self.get_index()?;
+        let mut entries = top
+            .manifests()
+            .iter()
+            .filter(|desc| {
+                desc.annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(ANNOTATION_REF_NAME))
+                    .map_or(true, |t| t != tag)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.push(tagged);
+        top.set_manifests(entries);
+        self.write_index(&top)?;
+        Ok(desc)
+    }
+
+    // replaces index.json atomically, like ocidir does; the index lock must be held
+    fn write_index(&self, index: &ImageIndex) -> Result<()> {
+        replace_file(
+            self.0.dir(),
+            "index.json",
+            &serde_json::to_vec(index)?,
+            self.1.durable,
+        )
+    }
+
+    /// Writes the blob `digest`, see [`Image::with_durable_writes`].
+    pub(crate) fn write_blob_file(&self, digest: &str, data: &[u8]) -> Result<()> {
+        replace_file(self.0.blobs_dir(), digest, data, self.1.durable)
+    }
+
+    pub(crate) fn write_blob(&self, data: &[u8], media_type: MediaType) -> Result<Descriptor> {
+        let digest = hex::encode(Sha256::digest(data));
+        if !self.0.blobs_dir().exists(&digest) {
+            self.write_blob_file(&digest, data)?;
+        }
+        Ok(Descriptor::new(
+            media_type,
+            data.len() as u64,
+            image::Digest::from_str(&format!("sha256:{digest}"))?,
+        ))
+    }
+
+    /// Stores an OCI 1.1 referrer of `subject`: an artifact manifest of type `artifact_type`
+    /// whose only layer is `layer`, e.g. a signature or an SBOM of an image manifest.
+    pub(crate) fn attach_artifact(
+        &self,
+        subject: Descriptor,
+        artifact_type: &str,
+        layer: Descriptor,
+    ) -> Result<Descriptor> {
+        let manifest = image::ImageManifestBuilder::default()
+            .schema_version(2_u32)
+            .media_type(MediaType::ImageManifest)
+            .artifact_type(MediaType::Other(artifact_type.to_string()))
+            .config(self.write_blob(b"{}", MediaType::Other(EMPTY_CONFIG.to_string()))?)
+            .layers(vec![layer])
+            .subject(subject)
+            .build()?;
+        let mut desc =
+            self.write_blob(&serde_json::to_vec(&manifest)?, MediaType::ImageManifest)?;
+        desc.set_artifact_type(manifest.artifact_type().clone());
+
+        let _lock = self.lock_index()?;
+        let mut index = self.get_index()?;
+        let mut entries = index.manifests().clone();
+        entries.push(desc.clone());
+        index.set_manifests(entries);
+        self.write_index(&index)?;
+        Ok(desc)
+    }
+
+    /// The artifact manifests of one of `artifact_types` whose subject is `subject`, oldest
+    /// first.
+    pub(crate) fn find_artifacts(
+        &self,
+        subject: &Descriptor,
+        artifact_types: &[&str],
+    ) -> Result<Vec<(Descriptor, ImageManifest)>> {
+        let mut artifacts = self.referrers(&subject.digest().to_string())?;
+        artifacts.retain(|(_, manifest)| match manifest.artifact_type() {
+            Some(MediaType::Other(artifact_type)) => {
+                artifact_types.contains(&artifact_type.as_str())
+            }
+            _ => false,
+        });
+        Ok(artifacts)
+    }
+
+    // the artifact manifests whose subject is the manifest `digest`, oldest first
+    fn referrers(&self, digest: &str) -> Result<Vec<(Descriptor, ImageManifest)>> {
+        let mut referrers = Vec::new();
+        for desc in self.get_index()?.manifests() {
+            // the per platform manifests of a tag, which can't be artifacts
+            if desc.media_type() == &MediaType::ImageIndex {
+                continue;
+            }
+            let manifest: ImageManifest = self.0.read_json_blob(desc)?;
+            if manifest
+                .subject()
+                .as_ref()
+                .is_some_and(|s| s.digest().to_string() == digest)
+            {
+                referrers.push((desc.clone(), manifest));
+            }
+        }
+        Ok(referrers)
+    }
+
+    /// The referrers of the manifest `digest`, a `sha256:<digest>`: the artifacts attached to it,
+    /// like signatures and SBOMs, only those of type `artifact_type` if given. They are listed as
+    /// the referrers API of registries does, as the descriptors of their manifests carrying their
+    /// artifact type and annotations, oldest first.
+    pub fn list_referrers(
+        &self,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<Descriptor>> {
+        Ok(self
+            .referrers(digest)?
+            .into_iter()
+            .filter(|(_, manifest)| {
+                artifact_type.is_none_or(|wanted| {
+                    manifest
+                        .artifact_type()
+                        .as_ref()
+                        .is_some_and(|t| t.to_string() == wanted)
+                })
+            })
+            .map(|(mut desc, manifest)| {
+                desc.set_artifact_type(manifest.artifact_type().clone());
+                desc.set_annotations(manifest.annotations().clone());
+                desc
+            })
+            .collect())
+    }
+
+    /// Attaches `content` to `subject`, a tag or the `sha256:<digest>` of a manifest or an index
+    /// of the image, as a referrer of type `artifact_type` whose only layer is `content` with the
+    /// media type `media_type`. Any artifact can be attached this way, e.g. the verity metadata
+    /// of a deployment. Like everywhere else, a tag or an index with one manifest per platform
+    /// stands for the manifest of [`Image::platform`].
+    pub fn attach_referrer(
+        &self,
+        subject: &str,
+        artifact_type: &str,
+        media_type: &str,
+        content: &[u8],
+    ) -> Result<Descriptor> {
+        let subject =
+            self.find_manifest_descriptor(subject)?
+                .ok_or_else(|| ImageError::MissingManifest {
+                    tag: subject.to_string(),
+                })?;
+        let layer = self.write_blob(content, MediaType::Other(media_type.to_string()))?;
+        self.attach_artifact(subject, artifact_type, layer)
+    }
+
+    /// Forgets the referrers whose subject the image no longer has, e.g. the signatures of a
+    /// manifest its tag no longer points to, and the referrers of those in turn. Returns how many
+    /// were forgotten; their blobs are left to [`BlobStore::gc`](blob_store::BlobStore::gc).
+    pub fn prune_referrers(&self) -> Result<usize> {
+        let _lock = self.lock_index()?;
+        self.prune_referrers_locked()
+    }
+
+    // prune_referrers, for callers which hold the lock of the index already
+    pub(crate) fn prune_referrers_locked(&self) -> Result<usize> {
+        let mut index = self.get_index()?;
+        // the digests each entry makes present, and the subject of the referrers
+        let mut entries = Vec::new();
+        for desc in index.manifests() {
+            let mut digests = vec![desc.digest().to_string()];
+            let subject = if desc.media_type() == &MediaType::ImageIndex {
+                let platforms: ImageIndex = self.0.read_json_blob(desc)?;
+                digests.extend(platforms.manifests().iter().map(|m| m.digest().to_string()));
+                None
+            } else {
+                let manifest: ImageManifest = self.0.read_json_blob(desc)?;
+                manifest.subject().as_ref().map(|s| s.digest().to_string())
+            };
+            entries.push((desc.clone(), digests, subject));
+        }
+
+        let before = entries.len();
+        loop {
+            let present: HashSet<String> = entries
+                .iter()
+                .flat_map(|(_, digests, _)| digests.clone())
+                .collect();
+            let len = entries.len();
+            entries.retain(|(_, _, subject)| subject.as_ref().is_none_or(|s| present.contains(s)));
+            if entries.len() == len {
+                break;
+            }
+        }
+        let pruned = before - entries.len();
+        if pruned > 0 {
+            index.set_manifests(entries.into_iter().map(|(desc, _, _)| desc).collect());
+            self.write_index(&index)?;
+        }
+        Ok(pruned)
     }
 
     pub fn blob_path() -> PathBuf {
@@ -51,12 +781,26 @@ impl Image {
         PathBuf::from("blobs/sha256")
     }
 
-    pub fn put_blob<C: Compression + Any>(
+    /// Compresses and hashes `buf` like [`Image::put_blob`], without writing it or adding it to a
+    /// manifest, e.g. to find out whether the image already has it.
+    pub fn prepare_blob<'a, C: Compression + Any>(
         &self,
-        buf: &[u8],
-        image_manifest: &mut ImageManifest,
-        media_type: impl PuzzleFSMediaType,
-    ) -> Result<(Descriptor, [u8; SHA256_BLOCK_SIZE], bool)> {
+        buf: &'a [u8],
+        media_type: &impl PuzzleFSMediaType,
+        verity_hash: VerityHash,
+    ) -> Result<PreparedBlob<'a>> {
+        self.prepare_blob_with::<C>(buf, media_type, verity_hash, None)
+    }
+
+    /// Like [`Image::prepare_blob`], encrypting the blob once it's compressed if `encryption` is
+    /// given.
+    pub fn prepare_blob_with<'a, C: Compression + Any>(
+        &self,
+        buf: &'a [u8],
+        media_type: &impl PuzzleFSMediaType,
+        verity_hash: VerityHash,
+        encryption: Option<&ChunkEncryption>,
+    ) -> Result<PreparedBlob<'a>> {
         let mut compressed_data = Cursor::new(Vec::<u8>::new());
         let mut compressed = C::compress(&mut compressed_data)?;
         let mut hasher = Sha256::new();
@@ -71,23 +815,29 @@ impl Image {
         let uncompressed_size = io::copy(&mut <&[u8]>::clone(&buf), &mut compressed)?;
         compressed.end()?;
         let compressed_size = compressed_data.get_ref().len() as u64;
-        let final_size = std::cmp::min(compressed_size, uncompressed_size);
+        let mut final_size = std::cmp::min(compressed_size, uncompressed_size);
 
+        let mut fs_verity_digest =
+            get_fs_verity_digest(&compressed_data.get_ref()[..], verity_hash)?;
         // store the uncompressed blob if the compressed version has bigger size
-        let final_data = if compressed_blob && compressed_size >= uncompressed_size {
+        let mut final_data = if compressed_blob && compressed_size >= uncompressed_size {
             compressed_blob = false;
-            buf
+            Cow::Borrowed(buf)
         } else {
-            compressed_data.get_ref()
+            Cow::Owned(compressed_data.into_inner())
         };
+        if let Some(encryption) = encryption {
+            final_data = Cow::Owned(encryption.encrypt(&final_data)?);
+            final_size = final_data.len() as u64;
+            fs_verity_digest = get_fs_verity_digest(&final_data, verity_hash)?;
+        }
 
-        hasher.update(final_data);
+        hasher.update(&final_data);
         let digest = hasher.finalize();
         let media_type_with_extension = C::append_extension(media_type.name());
         let mut digest_string = "sha256:".to_string();
         digest_string.push_str(&hex::encode(digest.as_slice()));
 
-        let fs_verity_digest = get_fs_verity_digest(&compressed_data.get_ref()[..])?;
         let mut descriptor = Descriptor::new(
             MediaType::Other(media_type_with_extension),
             final_size,
@@ -99,28 +849,64 @@ impl Image {
             let mut annotations = HashMap::new();
             annotations.insert(
                 VERITY_ROOT_HASH_ANNOTATION.to_string(),
-                hex::encode(fs_verity_digest),
+                hex::encode(&fs_verity_digest),
             );
             descriptor.set_annotations(Some(annotations));
         }
-        let path = Self::blob_path().join(descriptor.digest().digest());
+        if let Some(encryption) = encryption {
+            encryption.annotate(&mut descriptor)?;
+        }
+        // the blobs directory may live outside of the oci dir, see BlobStore
+        let existing = self.0.blobs_dir().exists(descriptor.digest().digest());
+
+        Ok(PreparedBlob {
+            descriptor,
+            fs_verity_digest,
+            compressed: compressed_blob,
+            existing,
+            data: final_data,
+        })
+    }
+
+    pub fn put_blob<C: Compression + Any>(
+        &self,
+        buf: &[u8],
+        image_manifest: &mut ImageManifest,
+        media_type: impl PuzzleFSMediaType,
+        verity_hash: VerityHash,
+    ) -> Result<StoredBlob> {
+        self.put_blob_with::<C>(buf, image_manifest, media_type, verity_hash, None)
+    }
+
+    /// Like [`Image::put_blob`], encrypting the blob once it's compressed if `encryption` is
+    /// given.
+    pub fn put_blob_with<C: Compression + Any>(
+        &self,
+        buf: &[u8],
+        image_manifest: &mut ImageManifest,
+        media_type: impl PuzzleFSMediaType,
+        verity_hash: VerityHash,
+        encryption: Option<&ChunkEncryption>,
+    ) -> Result<StoredBlob> {
+        let blob = self.prepare_blob_with::<C>(buf, &media_type, verity_hash, encryption)?;
+        let path = blob.descriptor.digest().digest();
 
         // avoid replacing the data blob so we don't drop fsverity data
-        if self.0.dir().exists(&path) {
+        if blob.existing {
             let mut hasher = Sha256::new();
-            let mut file = self.0.dir().open(&path)?;
+            let mut file = self.0.blobs_dir().open(path)?;
             io::copy(&mut file, &mut hasher)?;
-            let existing_digest = hasher.finalize();
-            if existing_digest != digest {
+            let existing_digest = hex::encode(hasher.finalize());
+            if existing_digest != path {
                 return Err(Error::new(
                     ErrorKind::AlreadyExists,
                     format!("blob already exists and it's not content addressable existing digest {}, new digest {}",
-                    hex::encode(existing_digest), hex::encode(digest))
+                    existing_digest, path)
                 )
                 .into());
             }
         } else {
-            self.0.dir().write(&path, final_data)?;
+            self.write_blob_file(path, &blob.data)?;
         }
 
         // Let's make the PuzzleFS image rootfs the first layer so it's easy to find
@@ -128,21 +914,99 @@ impl Image {
         // type (see getlayermediatype):
         // https://github.com/lxc/lxc/commit/1a2da75b6e8431f3530ebd3f75442d3bd5eec5e2
         if media_type.name() == PUZZLEFS_ROOTFS {
-            image_manifest.layers_mut().insert(0, descriptor.clone());
+            image_manifest
+                .layers_mut()
+                .insert(0, blob.descriptor.clone());
         } else {
-            image_manifest.layers_mut().push(descriptor.clone());
+            image_manifest.layers_mut().push(blob.descriptor.clone());
         }
-        Ok((descriptor, fs_verity_digest, compressed_blob))
+        Ok(StoredBlob {
+            descriptor: blob.descriptor,
+            fs_verity_digest: blob.fs_verity_digest,
+            compressed: blob.compressed,
+            existing: blob.existing,
+        })
+    }
+
+    pub fn has_blob(&self, digest: &Digest) -> bool {
+        self.0.blobs_dir().exists(digest.to_string())
+    }
+
+    // Adds a blob which is already stored in the image to the manifest, without writing it again
+    pub fn reference_blob<C: Compression>(
+        &self,
+        digest: &Digest,
+        image_manifest: &mut ImageManifest,
+        media_type: impl PuzzleFSMediaType,
+    ) -> Result<Descriptor> {
+        let media_type = MediaType::Other(C::append_extension(media_type.name()));
+        self.reference_blob_as(digest, image_manifest, media_type)
+    }
+
+    /// Like [`Image::reference_blob`], for a blob whose media type is known already, e.g. from
+    /// the manifest it was written for.
+    pub fn reference_blob_as(
+        &self,
+        digest: &Digest,
+        image_manifest: &mut ImageManifest,
+        media_type: MediaType,
+    ) -> Result<Descriptor> {
+        let size = self.0.blobs_dir().metadata(digest.to_string())?.len();
+        let descriptor = Descriptor::new(
+            media_type,
+            size,
+            image::Digest::from_str(&format!("sha256:{digest}"))?,
+        );
+        image_manifest.layers_mut().push(descriptor.clone());
+        Ok(descriptor)
     }
 
     fn open_raw_blob(&self, digest: &str, verity: Option<&[u8]>) -> io::Result<cap_std::fs::File> {
-        let file = self.0.blobs_dir().open(digest)?;
+        let file = match (self.0.blobs_dir().open(digest), &self.1.remote) {
+            (Err(e), Some(remote)) if e.kind() == io::ErrorKind::NotFound => self
+                .open_remote_blob(remote.as_ref(), digest)
+                .map_err(io::Error::from)?,
+            (file, _) => file?,
+        };
         if let Some(verity) = verity {
-            check_fs_verity(&file, verity).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            check_fs_verity(&file, verity).map_err(|e| {
+                METRICS.verity_failure();
+                io::Error::new(io::ErrorKind::Other, e)
+            })?;
         }
         Ok(file)
     }
 
+    // checks that the contents of the blob `digest` hash to it
+    pub(crate) fn check_blob_digest(&self, digest: &str) -> Result<()> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut self.open_raw_blob(digest, None)?, &mut hasher)?;
+        let found = hex::encode(hasher.finalize());
+        if found != digest {
+            return Err(ImageError::DigestMismatch {
+                expected: digest.to_string(),
+                found,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    // opens a blob the directory doesn't have, from the cache or else from the remote
+    fn open_remote_blob(
+        &self,
+        remote: &dyn RemoteStore,
+        digest: &str,
+    ) -> Result<cap_std::fs::File> {
+        match &self.1.cache {
+            Some(cache) => cache.open_or_fetch(remote, digest),
+            None => {
+                remote::fetch_blob(self.0.blobs_dir(), remote, digest)?;
+                Ok(self.0.blobs_dir().open(digest)?)
+            }
+        }
+    }
+
     pub fn open_compressed_blob<C: Compression>(
         &self,
         digest: &Digest,
@@ -152,16 +1016,20 @@ impl Image {
         C::decompress(f)
     }
 
-    pub fn get_pfs_rootfs_verity(&self, tag: &str) -> Result<[u8; SHA256_BLOCK_SIZE]> {
-        let manifest = self.0.find_manifest_with_tag(tag)?.ok_or_else(|| {
-            WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
-        })?;
+    pub fn get_pfs_rootfs_verity(&self, tag: &str) -> Result<Vec<u8>> {
+        let manifest = self
+            .find_manifest(tag)?
+            .ok_or_else(|| ImageError::MissingManifest {
+                tag: tag.to_string(),
+            })?;
 
         let rootfs_desc = manifest
             .layers()
             .iter()
             .find(|desc| desc.media_type() == &MediaType::Other(PUZZLEFS_ROOTFS.to_string()))
-            .ok_or_else(|| WireFormatError::MissingRootfs(Backtrace::capture()))?;
+            .ok_or_else(|| ImageError::MissingRootfs {
+                tag: tag.to_string(),
+            })?;
 
         let rootfs_verity = rootfs_desc
             .annotations()
@@ -179,37 +1047,57 @@ impl Image {
                     Backtrace::capture(),
                 )
             })?;
-        let mut verity_digest: [u8; SHA256_BLOCK_SIZE] = [0; SHA256_BLOCK_SIZE];
-        hex::decode_to_slice(rootfs_verity, &mut verity_digest)?;
+        let verity_digest = hex::decode(rootfs_verity)?;
+        VerityHash::from_digest(&verity_digest)?;
 
         Ok(verity_digest)
     }
 
     pub fn get_pfs_rootfs(&self, tag: &str, verity: Option<&[u8]>) -> Result<cap_std::fs::File> {
-        let manifest = self.0.find_manifest_with_tag(tag)?.ok_or_else(|| {
-            WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
-        })?;
+        let manifest = self
+            .find_manifest(tag)?
+            .ok_or_else(|| ImageError::MissingManifest {
+                tag: tag.to_string(),
+            })?;
 
         let rootfs_desc = manifest
             .layers()
             .iter()
             .find(|desc| desc.media_type() == &MediaType::Other(PUZZLEFS_ROOTFS.to_string()))
-            .ok_or_else(|| WireFormatError::MissingRootfs(Backtrace::capture()))?;
+            .ok_or_else(|| ImageError::MissingRootfs {
+                tag: tag.to_string(),
+            })?;
 
         let rootfs_digest = rootfs_desc.digest().digest();
-        let file = self.open_raw_blob(rootfs_digest, verity)?;
-        Ok(file)
+        self.open_raw_blob(rootfs_digest, verity)
+            .map_err(|e| blob_error(rootfs_digest, e))
     }
 
-    pub fn get_image_manifest_fd(&self, tag: &str) -> Result<cap_std::fs::File> {
-        let image_manifest = self
-            .0
-            .find_manifest_descriptor_with_tag(tag)?
-            .ok_or_else(|| {
-                WireFormatError::MissingManifest(tag.to_string(), Backtrace::capture())
+    /// Whether the manifest of `tag` declares that the image must be mounted with fs-verity
+    /// checks.
+    pub fn requires_verity(&self, tag: &str) -> Result<bool> {
+        let manifest = self
+            .find_manifest(tag)?
+            .ok_or_else(|| ImageError::MissingManifest {
+                tag: tag.to_string(),
             })?;
-        let file = self.open_raw_blob(image_manifest.digest().digest(), None)?;
-        Ok(file)
+        Ok(manifest
+            .annotations()
+            .as_ref()
+            .and_then(|annotations| annotations.get(REQUIRE_VERITY_ANNOTATION))
+            .map(|value| value == "true")
+            .unwrap_or(false))
+    }
+
+    pub fn get_image_manifest_fd(&self, tag: &str) -> Result<cap_std::fs::File> {
+        let image_manifest =
+            self.find_manifest_descriptor(tag)?
+                .ok_or_else(|| ImageError::MissingManifest {
+                    tag: tag.to_string(),
+                })?;
+        let digest = image_manifest.digest().digest();
+        self.open_raw_blob(digest, None)
+            .map_err(|e| blob_error(digest, e))
     }
 
     pub fn open_rootfs_blob(&self, tag: &str, verity: Option<&[u8]>) -> Result<RootfsReader> {
@@ -224,7 +1112,146 @@ impl Image {
         };
 
         let rootfs_file = self.get_pfs_rootfs(tag, rootfs_verity)?;
-        RootfsReader::open(rootfs_file)
+        let rootfs = RootfsReader::open(rootfs_file, &self.1.metadata_limits)?;
+        negotiate_version(rootfs.get_manifest_version()?)?;
+        Ok(rootfs)
+    }
+
+    // the fs-verity digest a chunk blob must have, if the image is checked
+    fn chunk_verity<'a>(
+        digest: &Digest,
+        verity_data: &'a Option<VerityData>,
+    ) -> crate::format::Result<Option<&'a [u8]>> {
+        let Some(verity) = verity_data else {
+            return Ok(None);
+        };
+        let file_verity = verity.get(&digest.underlying()).ok_or_else(|| {
+            METRICS.verity_failure();
+            WireFormatError::InvalidFsVerityData(
+                format!("missing verity data {digest}"),
+                Backtrace::capture(),
+            )
+        })?;
+        Ok(Some(&file_verity[..]))
+    }
+
+    // opens a chunk blob, or reuses it if it's still open from a previous read
+    fn open_chunk_blob(
+        &self,
+        digest: &Digest,
+        verity_data: &Option<VerityData>,
+    ) -> crate::format::Result<Arc<fs::File>> {
+        let verity = Self::chunk_verity(digest, verity_data)?;
+        let digest = digest.to_string();
+        self.1
+            .fds
+            .get_or_open(&digest, verity.is_some(), || {
+                let file = self.open_raw_blob(&digest, verity)?.into_std();
+                self.apply_read_hints(&file);
+                Ok(file)
+            })
+            .map_err(|e| blob_error(&digest, e))
+    }
+
+    // the key the blob of a chunk is encrypted with, None if it isn't encrypted
+    fn chunk_key(&self, digest: &Digest) -> crate::format::Result<Option<[u8; 32]>> {
+        let encrypted = match self.1.encrypted.get() {
+            Some(encrypted) => encrypted,
+            None => {
+                let encrypted = encryption::encrypted_blobs(self)?;
+                self.1.encrypted.get_or_init(|| encrypted)
+            }
+        };
+        let Some(blob) = encrypted.get(&digest.to_string()) else {
+            return Ok(None);
+        };
+        let Some(keys) = &self.1.decryption_keys else {
+            return Err(WireFormatError::EncryptionError(
+                format!("chunk {digest} is encrypted and no decryption key was given"),
+                Backtrace::capture(),
+            ));
+        };
+        keys.key(blob).map(Some)
+    }
+
+    /// Whether the blob of `chunk` is encrypted, in which case it can't be used without being
+    /// read, see [`Image::open_chunk`].
+    pub fn is_encrypted(&self, chunk: crate::format::BlobRef) -> crate::format::Result<bool> {
+        Ok(self.chunk_key(&<Digest>::try_from(chunk)?)?.is_some())
+    }
+
+    // reads and decrypts the whole blob of an encrypted chunk
+    fn decrypted_chunk_blob(
+        &self,
+        digest: &Digest,
+        key: &[u8; 32],
+        verity_data: &Option<VerityData>,
+    ) -> crate::format::Result<Arc<[u8]>> {
+        let name = digest.to_string();
+        {
+            let mut decrypted = self.1.decrypted.lock().unwrap();
+            if let Some(i) = decrypted.iter().position(|(cached, _)| *cached == name) {
+                // .unwrap() here because i was just found
+                let entry = decrypted.remove(i).unwrap();
+                let data = Arc::clone(&entry.1);
+                decrypted.push_front(entry);
+                return Ok(data);
+            }
+        }
+        // decrypted without holding the lock, so that the other readers aren't held up
+        let mut blob = Vec::new();
+        SharedBlob::new(self.open_chunk_blob(digest, verity_data)?).read_to_end(&mut blob)?;
+        let data: Arc<[u8]> = encryption::decrypt(key, &name, &blob)?.into();
+        let mut decrypted = self.1.decrypted.lock().unwrap();
+        decrypted.retain(|(cached, _)| *cached != name);
+        decrypted.push_front((name, Arc::clone(&data)));
+        decrypted.truncate(DECRYPTED_CACHE_SIZE);
+        Ok(data)
+    }
+
+    fn apply_read_hints(&self, file: &fs::File) {
+        let fd = file.as_raw_fd();
+        if self.1.read_hints.noatime {
+            // O_NOATIME can be set after the fact, it's EPERM for the blobs of other users
+            let set = fcntl(fd, FcntlArg::F_GETFL).and_then(|flags| {
+                let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NOATIME;
+                fcntl(fd, FcntlArg::F_SETFL(flags))
+            });
+            if let Err(e) = set {
+                debug!("cannot read blob without updating its access time: {e}");
+            }
+        }
+        if self.1.read_hints.sequential {
+            if let Err(e) = posix_fadvise(fd, 0, 0, PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL) {
+                debug!("cannot advise sequential reads: {e}");
+            }
+        }
+    }
+
+    /// Tells the image `chunk` was read to its end; with [`ReadHints::drop_cache`], it leaves the
+    /// page cache. Only the range of an uncompressed chunk is dropped, so the chunks next to it in
+    /// the same pack stay cached; a compressed chunk can't be told apart from the rest of its
+    /// blob, which is dropped whole.
+    pub fn chunk_done(&self, chunk: &crate::format::FileChunk) {
+        if !self.1.read_hints.drop_cache {
+            return;
+        }
+        let (offset, len) = match chunk.blob.compressed {
+            true => (0, 0),
+            false => (chunk.blob.offset, chunk.len),
+        };
+        // the blob is most likely still open from reading the chunk
+        let dropped = <Digest>::try_from(chunk.blob)
+            .and_then(|digest| self.open_chunk_blob(&digest, &None))
+            .and_then(|file| {
+                let advice = PosixFadviseAdvice::POSIX_FADV_DONTNEED;
+                let (offset, len) = (offset.try_into()?, len.try_into()?);
+                posix_fadvise(file.as_raw_fd(), offset, len, advice)
+                    .map_err(|e| io::Error::from(e).into())
+            });
+        if let Err(e) = dropped {
+            debug!("cannot drop chunk from the page cache: {e}");
+        }
     }
 
     pub fn fill_from_chunk(
@@ -235,41 +1262,64 @@ impl Image {
         verity_data: &Option<VerityData>,
     ) -> crate::format::Result<usize> {
         let digest = &<Digest>::try_from(chunk)?;
-        let file_verity;
-        if let Some(verity) = verity_data {
-            file_verity = Some(
-                &verity
-                    .get(&digest.underlying())
-                    .ok_or(WireFormatError::InvalidFsVerityData(
-                        format!("missing verity data {digest}"),
-                        Backtrace::capture(),
-                    ))?[..],
-            );
-        } else {
-            file_verity = None;
-        }
-        let mut blob = if chunk.compressed {
-            self.open_compressed_blob::<Zstd>(digest, file_verity)?
+        let mut blob = if let Some(key) = self.chunk_key(digest)? {
+            let data = Cursor::new(self.decrypted_chunk_blob(digest, &key, verity_data)?);
+            if chunk.compressed {
+                Zstd::decompress(data)?
+            } else {
+                Noop::decompress(data)?
+            }
         } else {
-            self.open_compressed_blob::<Noop>(digest, file_verity)?
+            let file = SharedBlob::new(self.open_chunk_blob(digest, verity_data)?);
+            if chunk.compressed {
+                Zstd::decompress(file)?
+            } else {
+                Noop::decompress(file)?
+            }
         };
         blob.seek(io::SeekFrom::Start(chunk.offset + addl_offset))?;
         let n = blob.read(buf)?;
+        METRICS.chunk_read(chunk.compressed.then_some(n));
         Ok(n)
     }
 
+    /// Opens the blob of an uncompressed and unencrypted chunk, for copying the chunk without
+    /// reading it, e.g. with `copy_file_range`; the chunk starts at `chunk.offset` of the file.
+    pub fn open_chunk(
+        &self,
+        chunk: crate::format::BlobRef,
+        verity_data: &Option<VerityData>,
+    ) -> crate::format::Result<Arc<fs::File>> {
+        if chunk.compressed || self.is_encrypted(chunk)? {
+            return Err(WireFormatError::from_errno(Errno::EINVAL));
+        }
+        let file = self.open_chunk_blob(&<Digest>::try_from(chunk)?, verity_data)?;
+        METRICS.chunk_read(None);
+        Ok(file)
+    }
+
     pub fn get_index(&self) -> Result<ImageIndex> {
         Ok(self.0.read_index()?)
     }
 
     pub fn get_empty_manifest(&self) -> Result<ImageManifest> {
-        Ok(self.0.new_empty_manifest()?.build()?)
+        let manifest = self.0.new_empty_manifest()?.build()?;
+        // ocidir wrote the empty config itself
+        if self.1.durable {
+            self.0
+                .blobs_dir()
+                .open(manifest.config().digest().digest())?
+                .sync_all()?;
+            fsync(self.0.blobs_dir().as_raw_fd())?;
+        }
+        Ok(manifest)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::builder::build_test_fs;
     use ocidir::oci_spec::image::{ImageIndexBuilder, Platform, ANNOTATION_REF_NAME};
     use std::collections::HashMap;
     use tempfile::tempdir;
@@ -280,14 +1330,15 @@ mod tests {
         let dir = tempdir()?;
         let image: Image = Image::new(dir.path())?;
         let mut image_manifest = image.get_empty_manifest()?;
-        let (desc, ..) = image.put_blob::<Noop>(
+        let blob = image.put_blob::<Noop>(
             "meshuggah rocks".as_bytes(),
             &mut image_manifest,
             media_types::Chunk {},
+            VerityHash::default(),
         )?;
 
         const DIGEST: &str = "3abd5ce0f91f640d88dca1f26b37037b02415927cacec9626d87668a715ec12d";
-        assert_eq!(desc.digest().digest(), DIGEST);
+        assert_eq!(blob.descriptor.digest().digest(), DIGEST);
 
         let md = image
             .0
@@ -334,17 +1385,285 @@ mod tests {
         let dir = tempdir()?;
         let image = Image::new(dir.path())?;
         let mut image_manifest = image.get_empty_manifest()?;
-        let desc1 = image.put_blob::<DefaultCompression>(
+        let blob1 = image.put_blob::<DefaultCompression>(
             "meshuggah rocks".as_bytes(),
             &mut image_manifest,
             media_types::Chunk {},
+            VerityHash::default(),
         )?;
-        let desc2 = image.put_blob::<DefaultCompression>(
+        let blob2 = image.put_blob::<DefaultCompression>(
             "meshuggah rocks".as_bytes(),
             &mut image_manifest,
             media_types::Chunk {},
+            VerityHash::default(),
+        )?;
+        assert_eq!(blob1.descriptor, blob2.descriptor);
+        assert_eq!(blob1.fs_verity_digest, blob2.fs_verity_digest);
+        assert_eq!(blob1.compressed, blob2.compressed);
+        assert!(!blob1.existing);
+        assert!(blob2.existing);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_arch() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let amd64 = Image::new(dir.path())?.with_platform(parse_platform("linux/amd64")?);
+        build_test_fs(rootfs, &amd64, "test")?;
+        let arm64 = Image::open(dir.path())?.with_platform(parse_platform("linux/arm64/v8")?);
+        build_test_fs(rootfs, &arm64, "test")?;
+        // building a platform again replaces its manifest
+        build_test_fs(rootfs, &arm64, "test")?;
+
+        let amd64 = Image::open(dir.path())?.with_platform(parse_platform("linux/amd64")?);
+        assert_eq!(amd64.platform_manifests("test")?.len(), 2);
+        let tagged = amd64
+            .get_index()?
+            .manifests()
+            .iter()
+            .filter(|desc| desc.annotations().is_some())
+            .count();
+        assert_eq!(tagged, 1);
+
+        let amd64_manifest = amd64.find_manifest_descriptor("test")?.unwrap();
+        let arm64_manifest = arm64.find_manifest_descriptor("test")?.unwrap();
+        assert_ne!(amd64_manifest.digest(), arm64_manifest.digest());
+        assert_eq!(
+            arm64_manifest
+                .platform()
+                .as_ref()
+                .unwrap()
+                .variant()
+                .as_deref(),
+            Some("v8")
+        );
+        // without a variant, any variant of the architecture will do
+        let any_arm64 = Image::open(dir.path())?.with_platform(parse_platform("linux/arm64")?);
+        assert_eq!(
+            any_arm64
+                .find_manifest_descriptor("test")?
+                .unwrap()
+                .digest(),
+            arm64_manifest.digest()
+        );
+        amd64.open_rootfs_blob("test", None)?;
+
+        // a manifest digest resolves to that manifest whatever the platform, the digest of the
+        // index to the manifest of the platform
+        let arm64_digest = arm64_manifest.digest().to_string();
+        assert_eq!(
+            amd64
+                .find_manifest_descriptor(&arm64_digest)?
+                .unwrap()
+                .digest(),
+            arm64_manifest.digest()
+        );
+        amd64.open_rootfs_blob(&arm64_digest, None)?;
+        let index = amd64.0.find_manifest_descriptor_with_tag("test")?.unwrap();
+        assert_eq!(
+            amd64
+                .find_manifest_descriptor(&index.digest().to_string())?
+                .unwrap()
+                .digest(),
+            amd64_manifest.digest()
+        );
+        assert_eq!(
+            amd64.platform_manifests(&index.digest().to_string())?.len(),
+            2
+        );
+        let missing = format!("sha256:{}", "0".repeat(64));
+        assert!(amd64.find_manifest_descriptor(&missing)?.is_none());
+        // nor do blobs which aren't json, and blobs which don't have their digest fail
+        let binary = amd64.write_blob(b"\x00\xff", MediaType::Other("test".to_string()))?;
+        assert!(amd64
+            .find_manifest_descriptor(&binary.digest().to_string())?
+            .is_none());
+        let blobs = dir.path().join(Image::blob_path());
+        fs::write(blobs.join(binary.digest().digest()), b"{\"layers\": []}")?;
+        assert!(matches!(
+            amd64.find_manifest_descriptor(&binary.digest().to_string()),
+            Err(WireFormatError::Image(ImageError::DigestMismatch { .. }))
+        ));
+
+        // referrers of a tag or an index are attached to the manifest of the platform
+        amd64.attach_referrer(&index.digest().to_string(), "text/plain", "text/plain", b"")?;
+        let amd64_digest = amd64_manifest.digest().to_string();
+        assert_eq!(amd64.list_referrers(&amd64_digest, None)?.len(), 1);
+        assert!(amd64
+            .list_referrers(&index.digest().to_string(), None)?
+            .is_empty());
+
+        let riscv = Image::open(dir.path())?.with_platform(parse_platform("linux/riscv64")?);
+        assert!(matches!(
+            riscv.find_manifest_descriptor("test"),
+            Err(WireFormatError::Image(ImageError::MissingPlatform { .. }))
+        ));
+        assert!(parse_platform("linux").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_blob_sha512_verity() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let mut image_manifest = image.get_empty_manifest()?;
+        let verity = image
+            .put_blob::<Noop>(
+                "meshuggah rocks".as_bytes(),
+                &mut image_manifest,
+                media_types::Chunk {},
+                VerityHash::Sha512,
+            )?
+            .fs_verity_digest;
+        assert_eq!(
+            hex::encode(&verity),
+            "53b7a5b8691d02528c837528af5bc046b4f5f5e4880f49890dbda10ab7cd95b2\
+             92ef5c7ae4d8721f83556cad69ece1288f41a9d85a989e69aa001e90bd7d7377"
+        );
+        assert_eq!(VerityHash::from_digest(&verity)?, VerityHash::Sha512);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_limits() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        image.open_rootfs_blob("test", None)?;
+
+        let image = Image::open(dir.path())?.with_metadata_limits(MetadataLimits {
+            max_name_len: 8,
+            ..Default::default()
+        });
+        assert!(matches!(
+            image.open_rootfs_blob("test", None),
+            Err(WireFormatError::InvalidMetadata(..))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_durable_writes() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?.with_durable_writes(true);
+        let desc = build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let layer = image.write_blob(b"{}", MediaType::Other(EMPTY_CONFIG.to_string()))?;
+        let artifact =
+            image.attach_artifact(desc.clone(), "application/vnd.puzzlefs.test", layer)?;
+
+        let image = Image::open(dir.path())?;
+        image.open_rootfs_blob("test", None)?;
+        assert_eq!(
+            image.find_artifacts(&desc, &["application/vnd.puzzlefs.test"])?[0].0,
+            artifact
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_referrers() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let desc = build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let digest = desc.digest().to_string();
+        let sbom = image.attach_referrer("test", "application/spdx+json", "text/plain", b"{}")?;
+        let verity =
+            image.attach_referrer(&digest, "application/vnd.puzzlefs.test", "text/plain", b"1")?;
+        // a referrer of a referrer, like the signature of an SBOM
+        let signature = image.attach_referrer(
+            &sbom.digest().to_string(),
+            "application/vnd.puzzlefs.test",
+            "text/plain",
+            b"2",
         )?;
-        assert_eq!(desc1, desc2);
+
+        let referrers = image.list_referrers(&digest, None)?;
+        assert_eq!(
+            referrers.iter().map(|r| r.digest()).collect::<Vec<_>>(),
+            vec![sbom.digest(), verity.digest()]
+        );
+        assert_eq!(
+            referrers[0].artifact_type(),
+            &Some(MediaType::Other("application/spdx+json".to_string()))
+        );
+        let filtered = image.list_referrers(&digest, Some("application/vnd.puzzlefs.test"))?;
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].digest(), verity.digest());
+        assert_eq!(
+            image.list_referrers(&sbom.digest().to_string(), None)?[0].digest(),
+            signature.digest()
+        );
+        assert!(image
+            .list_referrers(&digest, Some("text/plain"))?
+            .is_empty());
+        assert!(image
+            .attach_referrer("missing", "text/plain", "text/plain", b"")
+            .is_err());
+
+        // nothing dangles while the tag points to the manifest
+        assert_eq!(image.prune_referrers()?, 0);
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("file"), "other")?;
+        build_test_fs(&rootfs, &image, "test")?;
+        assert_eq!(image.prune_referrers()?, 3);
+        assert!(image.list_referrers(&digest, None)?.is_empty());
+        assert_eq!(image.get_index()?.manifests().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_builds() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        Image::new(dir.path())?;
+        // the builds share their blobs, and each one tags a manifest and attaches an artifact
+        std::thread::scope(|scope| {
+            let builds = (0..8)
+                .map(|i| {
+                    let dir = dir.path();
+                    scope.spawn(move || -> anyhow::Result<()> {
+                        let image = Image::open(dir)?;
+                        let tag = format!("test-{i}");
+                        let desc =
+                            build_test_fs(Path::new("src/builder/test/test-1"), &image, &tag)?;
+                        let layer = image.write_blob(
+                            tag.as_bytes(),
+                            MediaType::Other("text/plain".to_string()),
+                        )?;
+                        image.attach_artifact(desc, "application/vnd.puzzlefs.test", layer)?;
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            builds
+                .into_iter()
+                .try_for_each(|build| build.join().unwrap())
+        })?;
+
+        let image = Image::open(dir.path())?;
+        for i in 0..8 {
+            let tag = format!("test-{i}");
+            image.open_rootfs_blob(&tag, None)?;
+            // the builds are reproducible, so the tags may share their manifest
+            let subject = image.find_manifest_descriptor(&tag)?.unwrap();
+            let layer =
+                image.write_blob(tag.as_bytes(), MediaType::Other("text/plain".to_string()))?;
+            assert!(image
+                .find_artifacts(&subject, &["application/vnd.puzzlefs.test"])?
+                .iter()
+                .any(|(_, artifact)| artifact.layers()[0].digest() == layer.digest()));
+        }
+        // nothing was left half written
+        for entry in
+            fs::read_dir(dir.path())?.chain(fs::read_dir(dir.path().join(Image::blob_path()))?)
+        {
+            let name = entry?.file_name();
+            assert!(
+                !name.to_string_lossy().starts_with('.'),
+                "{name:?} left behind"
+            );
+        }
         Ok(())
     }
 }
@@ -0,0 +1,125 @@
+//! Async variants of the accessors of [`Image`], run on tokio's blocking pool.
+use std::backtrace::Backtrace;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use ocidir::oci_spec::image::{Descriptor, ImageManifest};
+use tokio::task;
+
+use crate::format::{Result, WireFormatError};
+use crate::oci::{Digest, Image, ImageError};
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    task::spawn_blocking(f).await.map_err(|e| {
+        WireFormatError::IOError(
+            io::Error::new(io::ErrorKind::Other, e),
+            Backtrace::capture(),
+        )
+    })?
+}
+
+/// An [`Image`] which can be shared between tasks.
+#[derive(Clone)]
+pub struct AsyncImage(Arc<Image>);
+
+impl AsyncImage {
+    pub async fn open(oci_dir: &Path) -> Result<Self> {
+        let oci_dir = oci_dir.to_path_buf();
+        blocking(move || Image::open(&oci_dir))
+            .await
+            .map(Self::from)
+    }
+
+    /// The synchronous image, for the operations without an async variant.
+    pub fn image(&self) -> &Image {
+        &self.0
+    }
+
+    /// Resolves `tag` to the descriptor of its manifest and the manifest itself.
+    pub async fn resolve_manifest(&self, tag: &str) -> Result<(Descriptor, ImageManifest)> {
+        let image = self.0.clone();
+        let tag = tag.to_string();
+        blocking(move || {
+            let desc = image
+                .find_manifest_descriptor(&tag)?
+                .ok_or_else(|| ImageError::MissingManifest { tag })?;
+            let manifest = image.0.read_json_blob(&desc)?;
+            Ok((desc, manifest))
+        })
+        .await
+    }
+
+    /// Reads the raw (possibly compressed) contents of the blob `digest`, checking its fs-verity
+    /// digest first if `verity` is given.
+    pub async fn read_blob(&self, digest: &Digest, verity: Option<&[u8]>) -> Result<Vec<u8>> {
+        let image = self.0.clone();
+        let digest = digest.to_string();
+        let verity = verity.map(<[u8]>::to_vec);
+        blocking(move || {
+            let mut file = image.open_raw_blob(&digest, verity.as_deref())?;
+            let mut contents = Vec::new();
+            io::Read::read_to_end(&mut file, &mut contents)?;
+            Ok(contents)
+        })
+        .await
+    }
+
+    /// See [`Image::get_pfs_rootfs_verity`].
+    pub async fn get_pfs_rootfs_verity(&self, tag: &str) -> Result<Vec<u8>> {
+        let image = self.0.clone();
+        let tag = tag.to_string();
+        blocking(move || image.get_pfs_rootfs_verity(&tag)).await
+    }
+
+    /// See [`Image::requires_verity`].
+    pub async fn requires_verity(&self, tag: &str) -> Result<bool> {
+        let image = self.0.clone();
+        let tag = tag.to_string();
+        blocking(move || image.requires_verity(&tag)).await
+    }
+}
+
+impl From<Image> for AsyncImage {
+    fn from(image: Image) -> Self {
+        AsyncImage(Arc::new(image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_async_image() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+
+        let image = AsyncImage::open(dir.path()).await?;
+        let (desc, manifest) = image.resolve_manifest("test").await?;
+        assert_eq!(
+            desc.digest(),
+            image
+                .image()
+                .find_manifest_descriptor("test")?
+                .unwrap()
+                .digest()
+        );
+
+        for layer in manifest.layers() {
+            let digest = Digest::try_from(layer.digest().digest())?;
+            let contents = image.read_blob(&digest, None).await?;
+            assert_eq!(contents.len() as u64, layer.size());
+        }
+
+        assert!(image.resolve_manifest("missing").await.is_err());
+        Ok(())
+    }
+}
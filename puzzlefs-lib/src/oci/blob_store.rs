@@ -0,0 +1,404 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use ocidir::oci_spec::image::{ImageIndex, ImageManifest, MediaType};
+use tracing::{info, warn};
+
+use crate::format::{Result, RootfsReader};
+use crate::oci::media_types::PUZZLEFS_ROOTFS;
+use crate::oci::{lock_dir, writer_is_gone, Digest, Image, IndexLock};
+
+const REGISTRY: &str = "images.json";
+
+// how old the temporary files of writers which are gone must be for gc to remove them, in case
+// the writer runs in another pid namespace
+const STALE_TEMPORARY_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A content addressed blob store shared by several OCI image directories, so that hosts running
+/// many puzzlefs images keep exactly one copy of each chunk on disk.
+///
+/// The store keeps its blobs in `sha256/` and the blobs directory of each attached image is a
+/// symlink to it, which makes the images usable by other OCI tools as well. The store also keeps
+/// track of the attached images, so it can tell which blobs are still in use.
+pub struct BlobStore {
+    path: PathBuf,
+}
+
+impl BlobStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        fs::create_dir_all(path.join("sha256"))?;
+        Ok(BlobStore {
+            path: fs::canonicalize(path)?,
+        })
+    }
+
+    fn blobs_path(&self) -> PathBuf {
+        self.path.join("sha256")
+    }
+
+    // locks the registry of the attached images, and keeps gc from running concurrently
+    fn lock(&self) -> Result<IndexLock> {
+        lock_dir(&cap_std::fs::Dir::open_ambient_dir(
+            &self.path,
+            cap_std::ambient_authority(),
+        )?)
+    }
+
+    /// Creates or opens the image at `oci_dir` with its blobs in this store. The blobs of an
+    /// existing image are moved to the store.
+    pub fn attach(&self, oci_dir: &Path) -> Result<Image> {
+        let blobs = oci_dir.join(Image::blob_path());
+        let md = match fs::symlink_metadata(&blobs) {
+            Ok(md) => Some(md),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        if md.map(|md| md.is_symlink()).unwrap_or(false) {
+            if fs::canonicalize(&blobs)? != self.blobs_path() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} is attached to a different blob store",
+                        oci_dir.display()
+                    ),
+                )
+                .into());
+            }
+        } else {
+            // lay out the image first, then replace its blobs directory with the store
+            Image::new(oci_dir)?;
+            self.import_blobs(&blobs)?;
+            fs::remove_dir(&blobs)?;
+            symlink(self.blobs_path(), &blobs)?;
+        }
+
+        let _lock = self.lock()?;
+        self.register(&fs::canonicalize(oci_dir)?)?;
+        Image::open(oci_dir)
+    }
+
+    fn import_blobs(&self, blobs: &Path) -> Result<()> {
+        for entry in fs::read_dir(blobs)? {
+            let entry = entry?;
+            let dest = self.blobs_path().join(entry.file_name());
+            if dest.exists() {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            match fs::rename(entry.path(), &dest) {
+                Ok(()) => (),
+                Err(e) if e.raw_os_error() == Some(Errno::EXDEV as i32) => {
+                    fs::copy(entry.path(), &dest)?;
+                    fs::remove_file(entry.path())?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// The images attached to this store.
+    pub fn images(&self) -> Result<Vec<PathBuf>> {
+        match fs::read(self.path.join(REGISTRY)) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_images(&self, images: &[PathBuf]) -> Result<()> {
+        let registry = self.path.join(REGISTRY);
+        let tmp = self.path.join(format!("{REGISTRY}.tmp"));
+        fs::write(&tmp, serde_json::to_vec(images)?)?;
+        fs::rename(tmp, registry)?;
+        Ok(())
+    }
+
+    fn register(&self, oci_dir: &Path) -> Result<()> {
+        let mut images = self.images()?;
+        if !images.iter().any(|image| image == oci_dir) {
+            images.push(oci_dir.to_path_buf());
+            self.save_images(&images)?;
+        }
+        Ok(())
+    }
+
+    // an image counts as attached only as long as its blobs directory still points to the store,
+    // i.e. it has been neither deleted nor moved to a different store
+    fn is_attached(&self, oci_dir: &Path) -> bool {
+        fs::canonicalize(oci_dir.join(Image::blob_path()))
+            .map(|blobs| blobs == self.blobs_path())
+            .unwrap_or(false)
+    }
+
+    /// Returns the number of manifests referencing each blob in the store, across all the
+    /// attached images. Blobs which are not referenced at all are not included.
+    pub fn reference_counts(&self) -> Result<HashMap<String, u64>> {
+        let mut images = Vec::new();
+        for oci_dir in self.images()? {
+            if self.is_attached(&oci_dir) {
+                images.push(Image::open(&oci_dir)?);
+            }
+        }
+        count_references(&images)
+    }
+
+    /// Removes the blobs which are no longer referenced by any of the attached images, and
+    /// forgets about the images which were deleted. The referrers whose subject an image no
+    /// longer has are forgotten first, see [`Image::prune_referrers`], so the signatures and
+    /// SBOMs of the manifests which were replaced go with them. The temporary files which writers
+    /// that are gone left behind are removed too. Returns the number of removed files and the
+    /// space they used.
+    ///
+    /// An attached image which can't be read is skipped with a warning, and since the blobs it
+    /// uses are then unknown, only the temporary files are removed.
+    ///
+    /// The store and the indexes of the attached images are locked meanwhile, but this must still
+    /// not run concurrently with a build into one of them, since the blobs of an image are
+    /// written before its manifest.
+    pub fn gc(&self) -> Result<(u64, u64)> {
+        let _lock = self.lock()?;
+        let images = self.images()?;
+        let (attached, detached): (Vec<_>, Vec<_>) = images
+            .into_iter()
+            .partition(|image| self.is_attached(image));
+        for image in detached {
+            info!("forgetting detached image {}", image.display());
+        }
+
+        let mut counts = HashMap::new();
+        let mut skipped = 0;
+        // the tags can't move between the counting of the references and the removal of blobs
+        let mut locks = Vec::new();
+        for oci_dir in &attached {
+            let mut count = || -> Result<()> {
+                let image = Image::open(oci_dir)?;
+                locks.push(image.lock_index()?);
+                let pruned = image.prune_referrers_locked()?;
+                if pruned > 0 {
+                    info!("forgetting {pruned} referrers of {}", oci_dir.display());
+                }
+                count_image_references(&image, &mut counts)
+            };
+            if let Err(e) = count() {
+                warn!("skipping image {}: {e}", oci_dir.display());
+                skipped += 1;
+            }
+        }
+        if skipped > 0 {
+            warn!(
+                "keeping the unreferenced blobs, since the blobs of {skipped} images are unknown"
+            );
+        }
+
+        let mut removed = 0;
+        let mut removed_bytes = 0;
+        for entry in fs::read_dir(self.blobs_path())? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                warn!("unexpected file in blob store: {:?}", entry.path());
+                continue;
+            };
+            let md = entry.metadata()?;
+            // skip the temporary files of live writers and anything else that isn't a blob
+            let stale = writer_is_gone(name)
+                && md
+                    .modified()?
+                    .elapsed()
+                    .is_ok_and(|age| age > STALE_TEMPORARY_AGE);
+            if !stale
+                && (skipped > 0 || Digest::try_from(name).is_err() || counts.contains_key(name))
+            {
+                continue;
+            }
+            removed_bytes += md.len();
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+
+        self.save_images(&attached)?;
+        Ok((removed, removed_bytes))
+    }
+}
+
+// the number of manifests of `images` referencing each blob
+fn count_references(images: &[Image]) -> Result<HashMap<String, u64>> {
+    let mut counts = HashMap::new();
+    for image in images {
+        count_image_references(image, &mut counts)?;
+    }
+    Ok(counts)
+}
+
+// adds the references of the manifests of `image` to `counts`
+fn count_image_references(image: &Image, counts: &mut HashMap<String, u64>) -> Result<()> {
+    for desc in image.get_index()?.manifests() {
+        // a tag with one manifest per platform points to an image index listing them
+        let manifests = if desc.media_type() == &MediaType::ImageIndex {
+            *counts
+                .entry(desc.digest().digest().to_string())
+                .or_insert(0) += 1;
+            let index: ImageIndex = image.0.read_json_blob(desc)?;
+            index.manifests().clone()
+        } else {
+            vec![desc.clone()]
+        };
+        for desc in manifests {
+            let manifest: ImageManifest = image.0.read_json_blob(&desc)?;
+            for blob in referenced_blobs(image, desc.digest().digest(), &manifest)? {
+                *counts.entry(blob).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The blobs referenced by a manifest: the manifest itself, its config and its layers. For
+// puzzlefs images we also look at the rootfs, since a delta image only lists its new chunks in the
+// manifest but its metadata may reference chunks of the base image.
+pub(crate) fn referenced_blobs(
+    image: &Image,
+    manifest_digest: &str,
+    manifest: &ImageManifest,
+) -> Result<BTreeSet<String>> {
+    let mut blobs = BTreeSet::new();
+    blobs.insert(manifest_digest.to_string());
+    blobs.insert(manifest.config().digest().digest().to_string());
+    for layer in manifest.layers() {
+        blobs.insert(layer.digest().digest().to_string());
+        if layer.media_type() == &MediaType::Other(PUZZLEFS_ROOTFS.to_string()) {
+            let rootfs = RootfsReader::open(
+                image.0.blobs_dir().open(layer.digest().digest())?,
+                &image.metadata_limits(),
+            )?;
+            for digest in rootfs.get_verity_data()?.keys() {
+                blobs.insert(Digest::new(digest).to_string());
+            }
+        }
+    }
+    Ok(blobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use tempfile::tempdir;
+
+    fn blob_count(store: &BlobStore) -> anyhow::Result<usize> {
+        Ok(fs::read_dir(store.blobs_path())?.count())
+    }
+
+    #[test]
+    fn test_shared_blob_store() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = BlobStore::open(&dir.path().join("store"))?;
+
+        let image1 = store.attach(&dir.path().join("image1"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image1, "test")?;
+        let blobs = blob_count(&store)?;
+
+        // the second image only adds a manifest and a config, which differ in their timestamps
+        let image2 = store.attach(&dir.path().join("image2"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image2, "test")?;
+        assert!(blob_count(&store)? <= blobs + 2);
+        assert_eq!(store.images()?.len(), 2);
+
+        let counts = store.reference_counts()?;
+        assert!(counts.values().any(|count| *count == 2));
+        assert_eq!(store.gc()?.0, 0);
+
+        fs::remove_dir_all(dir.path().join("image1"))?;
+        store.gc()?;
+        assert_eq!(store.images()?.len(), 1);
+        image2.0.fsck()?;
+
+        fs::remove_dir_all(dir.path().join("image2"))?;
+        store.gc()?;
+        assert_eq!(blob_count(&store)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_unreadable_image() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = BlobStore::open(&dir.path().join("store"))?;
+        let image1 = store.attach(&dir.path().join("image1"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image1, "test")?;
+        let image2 = store.attach(&dir.path().join("image2"))?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("file"), "other")?;
+        build_test_fs(&rootfs, &image2, "test")?;
+        let blobs = blob_count(&store)?;
+
+        // the blobs of image2 can't be told apart from garbage anymore, so they stay
+        fs::write(dir.path().join("image2").join("index.json"), "garbage")?;
+        assert_eq!(store.gc()?.0, 0);
+        assert_eq!(blob_count(&store)?, blobs);
+        assert_eq!(store.images()?.len(), 2);
+        image1.0.fsck()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_temporary_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = BlobStore::open(&dir.path().join("store"))?;
+        let digest = "ab".repeat(32);
+        let write = |name: &str, age: Duration| -> anyhow::Result<()> {
+            let file = fs::File::create(store.blobs_path().join(name))?;
+            file.set_modified(std::time::SystemTime::now() - age)?;
+            Ok(())
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+        // no process has this pid
+        write(&format!(".{digest}.{}.0", u32::MAX), day)?;
+        write(&format!(".{digest}.{}.1", u32::MAX), Duration::ZERO)?;
+        write(&format!(".{digest}.{}.2", std::process::id()), day)?;
+        write(".unrelated", day)?;
+
+        assert_eq!(store.gc()?.0, 1);
+        assert_eq!(blob_count(&store)?, 3);
+        assert!(!store
+            .blobs_path()
+            .join(format!(".{digest}.{}.0", u32::MAX))
+            .exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_referrers() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = BlobStore::open(&dir.path().join("store"))?;
+        let image = store.attach(&dir.path().join("image"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        image.attach_referrer(
+            "test",
+            "application/vnd.puzzlefs.test",
+            "text/plain",
+            b"old",
+        )?;
+        store.gc()?;
+        assert_eq!(image.get_index()?.manifests().len(), 2);
+
+        // the tag moves to another manifest, which leaves the referrer dangling
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        fs::write(rootfs.join("file"), "other")?;
+        build_test_fs(&rootfs, &image, "test")?;
+        let (removed, _) = store.gc()?;
+        // the old manifest, its rootfs and chunks, and the artifact manifest and its layer
+        assert!(removed >= 4);
+        assert_eq!(image.get_index()?.manifests().len(), 1);
+        image.0.fsck()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! Copying tags, with their referrers, between OCI directories on the same host.
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::io;
+use std::os::fd::AsRawFd;
+
+use nix::unistd::fsync;
+use ocidir::oci_spec::image::{ImageManifest, MediaType};
+use tracing::{debug, info};
+
+use crate::format::Result;
+use crate::oci::blob_store::referenced_blobs;
+use crate::oci::remote::manifests_of;
+use crate::oci::{blob_error, temporary_name, Image, ImageError};
+
+/// What copying a tag took.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CopyStats {
+    /// blobs hard linked into the destination
+    pub linked: u64,
+    /// blobs copied, from another filesystem or where they couldn't be linked
+    pub copied: u64,
+    /// size of the copied blobs
+    pub copied_bytes: u64,
+    /// blobs the destination had already
+    pub existing: u64,
+    /// referrers of the manifests added to the destination, e.g. signatures
+    pub referrers: u64,
+}
+
+impl fmt::Display for CopyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "linked blobs: {}", self.linked)?;
+        writeln!(f, "copied blobs: {}", self.copied)?;
+        writeln!(f, "copied bytes: {}", self.copied_bytes)?;
+        writeln!(f, "existing blobs: {}", self.existing)?;
+        write!(f, "referrers: {}", self.referrers)
+    }
+}
+
+/// Copies `tag` of `src` to `dst` as `dst_tag`: its manifests, their configs and rootfs, the
+/// chunks they use which `dst` doesn't have yet, and their referrers, like signatures and SBOMs.
+/// `tag` may also be the `sha256:<digest>` of a manifest or an index, like wherever a tag is
+/// taken. The blobs are hard linked when both directories are on the same filesystem, and copied
+/// otherwise. The index of `dst` is locked meanwhile, and the manifests are tagged last, so the
+/// tag only shows up in `dst` once everything it needs is there.
+pub fn copy(src: &Image, tag: &str, dst: &Image, dst_tag: &str) -> Result<CopyStats> {
+    let _lock = dst.lock_index()?;
+    let top = src
+        .tagged_descriptor(tag)?
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: tag.to_string(),
+        })?;
+    let manifests = manifests_of(src, &top)?;
+
+    let mut blobs = BTreeSet::new();
+    for desc in &manifests {
+        let manifest: ImageManifest = src.0.read_json_blob(desc)?;
+        blobs.extend(referenced_blobs(src, desc.digest().digest(), &manifest)?);
+    }
+    if top.media_type() == &MediaType::ImageIndex {
+        blobs.insert(top.digest().digest().to_string());
+    }
+
+    // the referrers of the manifests, and the referrers of those in turn
+    let mut referrers = Vec::new();
+    let mut subjects = manifests
+        .iter()
+        .chain([&top])
+        .map(|desc| desc.digest().to_string())
+        .collect::<Vec<_>>();
+    while let Some(subject) = subjects.pop() {
+        for (desc, manifest) in src.referrers(&subject)? {
+            blobs.extend(referenced_blobs(src, desc.digest().digest(), &manifest)?);
+            subjects.push(desc.digest().to_string());
+            referrers.push(desc);
+        }
+    }
+
+    let mut stats = CopyStats::default();
+    for digest in blobs {
+        if dst.0.blobs_dir().exists(&digest) {
+            stats.existing += 1;
+            continue;
+        }
+        match transfer_blob(src, dst, &digest)? {
+            None => stats.linked += 1,
+            Some(size) => {
+                stats.copied += 1;
+                stats.copied_bytes += size;
+            }
+        }
+    }
+    if dst.1.durable {
+        fsync(dst.0.blobs_dir().as_raw_fd()).map_err(io::Error::from)?;
+    }
+
+    for desc in manifests {
+        dst.tag_descriptor_locked(desc, dst_tag)?;
+    }
+    let mut index = dst.get_index()?;
+    let mut entries = index.manifests().clone();
+    let mut present = entries
+        .iter()
+        .map(|desc| desc.digest().clone())
+        .collect::<HashSet<_>>();
+    for desc in referrers {
+        if present.insert(desc.digest().clone()) {
+            entries.push(desc);
+            stats.referrers += 1;
+        }
+    }
+    if stats.referrers > 0 {
+        index.set_manifests(entries);
+        dst.write_index(&index)?;
+    }
+    info!(
+        "copied {tag} as {dst_tag}, linked {} blobs and copied {}",
+        stats.linked, stats.copied
+    );
+    Ok(stats)
+}
+
+// links the blob `digest` of `src` into `dst`, or else copies it; the size copied, None if the
+// blob was linked
+fn transfer_blob(src: &Image, dst: &Image, digest: &str) -> Result<Option<u64>> {
+    match src
+        .0
+        .blobs_dir()
+        .hard_link(digest, dst.0.blobs_dir(), digest)
+    {
+        Ok(()) => return Ok(None),
+        // a concurrent writer of the destination got there first
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(blob_error(digest, e)),
+        Err(e) => debug!("copying blob {digest}, it can't be linked: {e}"),
+    }
+
+    // copied under a temporary name, so the blob never shows up truncated
+    let tmp = temporary_name(digest);
+    let copy = || -> io::Result<u64> {
+        let mut from = src.0.blobs_dir().open(digest)?.into_std();
+        let mut to = dst.0.blobs_dir().create(&tmp)?.into_std();
+        let size = io::copy(&mut from, &mut to)?;
+        if dst.1.durable {
+            to.sync_all()?;
+        }
+        dst.0.blobs_dir().rename(&tmp, dst.0.blobs_dir(), digest)?;
+        Ok(size)
+    };
+    let result = copy();
+    if result.is_err() {
+        let _ = dst.0.blobs_dir().remove_file(&tmp);
+    }
+    Ok(Some(result?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::reader::PuzzleFS;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let src = Image::new(&dir.path().join("src"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &src, "test")?;
+        let dst_dir = dir.path().join("dst");
+        let dst = Image::new(&dst_dir)?;
+
+        let stats = copy(&src, "test", &dst, "copy")?;
+        assert_eq!(stats.copied + stats.existing, 0);
+        assert_eq!(
+            dst.find_manifest_descriptor("copy")?.unwrap().digest(),
+            src.find_manifest_descriptor("test")?.unwrap().digest()
+        );
+        PuzzleFS::open(Image::open(&dst_dir)?, "copy", None)?;
+        // the blobs are shared with the source
+        let manifest = dst.find_manifest_descriptor("copy")?.unwrap();
+        let blob = Image::blob_path().join(manifest.digest().digest());
+        assert!(fs::metadata(dst_dir.join(&blob))?.nlink() > 1);
+
+        // copying again only tags the manifest
+        let again = copy(&src, "test", &dst, "again")?;
+        assert_eq!(again.linked + again.copied, 0);
+        assert_eq!(again.existing, stats.linked);
+
+        let digest = manifest.digest().to_string();
+        copy(&src, &digest, &dst, "pinned")?;
+
+        // the referrers go along, once
+        let signature = src.attach_referrer("test", "text/plain", "text/plain", b"signature")?;
+        src.attach_referrer(
+            &signature.digest().to_string(),
+            "text/plain",
+            "text/plain",
+            b"countersignature",
+        )?;
+        assert_eq!(copy(&src, "test", &dst, "signed")?.referrers, 2);
+        assert_eq!(dst.list_referrers(&digest, None)?.len(), 1);
+        assert_eq!(
+            dst.list_referrers(&signature.digest().to_string(), None)?
+                .len(),
+            1
+        );
+        assert_eq!(copy(&src, "test", &dst, "signed")?.referrers, 0);
+        dst.0.fsck()?;
+        assert!(matches!(
+            copy(&src, "missing", &dst, "missing"),
+            Err(crate::format::WireFormatError::Image(
+                ImageError::MissingManifest { .. }
+            ))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//! Encryption of the chunk blobs with AES-256-GCM, under a key wrapped for each recipient.
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ocidir::oci_spec::image::{Descriptor, ImageIndex, ImageManifest, MediaType};
+use openssl::encrypt::{Decrypter, Encrypter};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, PKey, PKeyRef, Private};
+use openssl::rand::rand_bytes;
+use openssl::rsa::Padding;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::media_types::{
+    ENCRYPTED_SUFFIX, ENCRYPTION_KEYS_ANNOTATION, ENCRYPTION_OPTS_ANNOTATION,
+};
+use super::Image;
+use crate::format::{Result, WireFormatError};
+
+const CIPHER: &str = "AES_256_GCM";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A copy of the key of a build, for one recipient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WrappedKey {
+    /// The sha256 digest of the DER public key of the recipient.
+    recipient: String,
+    /// The key encrypted with RSA-OAEP to the recipient, in base64.
+    key: String,
+}
+
+/// The parameters of the encryption of a blob, which aren't secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PubOpts {
+    cipher: String,
+    /// Names the key the blob is encrypted with, which all the blobs of a build share.
+    key_id: String,
+}
+
+fn encryption_error(msg: String) -> WireFormatError {
+    WireFormatError::EncryptionError(msg, Backtrace::capture())
+}
+
+fn recipient_id<T: HasPublic>(key: &PKeyRef<T>) -> Result<String> {
+    Ok(hex::encode(Sha256::digest(key.public_key_to_der()?)))
+}
+
+fn decode_annotation(desc: &Descriptor, name: &str) -> Result<Vec<u8>> {
+    let value = desc
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(name))
+        .ok_or_else(|| {
+            encryption_error(format!("{} lacks the {name} annotation", desc.digest()))
+        })?;
+    STANDARD
+        .decode(value)
+        .map_err(|e| encryption_error(format!("invalid {name} annotation: {e}")))
+}
+
+/// The key the chunks of a build are encrypted with, along with its copies for each recipient.
+#[derive(Clone)]
+pub struct ChunkEncryption {
+    key: [u8; 32],
+    opts: PubOpts,
+    wrapped: Vec<WrappedKey>,
+}
+
+impl fmt::Debug for ChunkEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkEncryption")
+            .field("key_id", &self.opts.key_id)
+            .field("recipients", &self.wrapped.len())
+            .finish()
+    }
+}
+
+impl ChunkEncryption {
+    /// Generates a key for the chunks of a build, which the holders of the private keys of
+    /// `recipients` (RSA public keys in PEM) can decrypt.
+    pub fn new(recipients: &[impl AsRef<Path>]) -> Result<Self> {
+        if recipients.is_empty() {
+            return Err(encryption_error("no recipient to encrypt for".to_string()));
+        }
+        let mut key = [0; 32];
+        rand_bytes(&mut key)?;
+        let mut key_id = [0; 16];
+        rand_bytes(&mut key_id)?;
+
+        let wrapped = recipients
+            .iter()
+            .map(|recipient| {
+                let public = PKey::public_key_from_pem(&fs::read(recipient)?)?;
+                let mut encrypter = Encrypter::new(&public)?;
+                encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+                let mut wrapped = vec![0; encrypter.encrypt_len(&key)?];
+                let len = encrypter.encrypt(&key, &mut wrapped)?;
+                wrapped.truncate(len);
+                Ok(WrappedKey {
+                    recipient: recipient_id(&public)?,
+                    key: STANDARD.encode(wrapped),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ChunkEncryption {
+            key,
+            opts: PubOpts {
+                cipher: CIPHER.to_string(),
+                key_id: hex::encode(key_id),
+            },
+            wrapped,
+        })
+    }
+
+    /// Encrypts a blob as its nonce, its ciphertext and its tag. The nonce is derived from the
+    /// contents, so that identical chunks still make identical blobs and are stored once.
+    pub(crate) fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce_key = Sha256::new()
+            .chain_update(b"puzzlefs chunk nonce")
+            .chain_update(self.key)
+            .finalize();
+        let nonce = Signer::new(MessageDigest::sha256(), &PKey::hmac(&nonce_key)?)?
+            .sign_oneshot_to_vec(data)?;
+        let nonce = &nonce[..NONCE_LEN];
+        let mut tag = [0; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            &[],
+            data,
+            &mut tag,
+        )?;
+        Ok([nonce, &ciphertext[..], &tag[..]].concat())
+    }
+
+    /// Marks the descriptor of a blob made by [`ChunkEncryption::encrypt`] as encrypted, and
+    /// records how to decrypt it.
+    pub(crate) fn annotate(&self, desc: &mut Descriptor) -> Result<()> {
+        desc.set_media_type(MediaType::Other(format!(
+            "{}{ENCRYPTED_SUFFIX}",
+            desc.media_type()
+        )));
+        let mut annotations = desc.annotations().clone().unwrap_or_default();
+        annotations.insert(
+            ENCRYPTION_KEYS_ANNOTATION.to_string(),
+            STANDARD.encode(serde_json::to_vec(&self.wrapped)?),
+        );
+        annotations.insert(
+            ENCRYPTION_OPTS_ANNOTATION.to_string(),
+            STANDARD.encode(serde_json::to_vec(&self.opts)?),
+        );
+        desc.set_annotations(Some(annotations));
+        Ok(())
+    }
+}
+
+/// How to decrypt an encrypted blob, from the annotations of its layer descriptor.
+#[derive(Debug, Clone)]
+pub(crate) struct EncryptedBlob {
+    key_id: String,
+    wrapped: Vec<WrappedKey>,
+}
+
+impl EncryptedBlob {
+    fn from_descriptor(desc: &Descriptor) -> Result<Option<Self>> {
+        if !desc.media_type().to_string().ends_with(ENCRYPTED_SUFFIX) {
+            return Ok(None);
+        }
+        let opts: PubOpts =
+            serde_json::from_slice(&decode_annotation(desc, ENCRYPTION_OPTS_ANNOTATION)?)?;
+        if opts.cipher != CIPHER {
+            return Err(encryption_error(format!(
+                "{} is encrypted with the unsupported cipher {}",
+                desc.digest(),
+                opts.cipher
+            )));
+        }
+        let wrapped =
+            serde_json::from_slice(&decode_annotation(desc, ENCRYPTION_KEYS_ANNOTATION)?)?;
+        Ok(Some(EncryptedBlob {
+            key_id: opts.key_id,
+            wrapped,
+        }))
+    }
+}
+
+/// The encrypted blobs listed in the manifests of `image`, by digest. The chunks a delta shares
+/// with its base layer are only listed in the manifest of the base layer, so all of them are
+/// looked at.
+pub(crate) fn encrypted_blobs(image: &Image) -> Result<HashMap<String, EncryptedBlob>> {
+    let mut blobs = HashMap::new();
+    let mut manifests = image.get_index()?.manifests().clone();
+    while let Some(desc) = manifests.pop() {
+        if desc.media_type() == &MediaType::ImageIndex {
+            let index: ImageIndex = image.0.read_json_blob(&desc)?;
+            manifests.extend(index.manifests().iter().cloned());
+            continue;
+        }
+        let manifest: ImageManifest = image.0.read_json_blob(&desc)?;
+        for layer in manifest.layers() {
+            if let Some(blob) = EncryptedBlob::from_descriptor(layer)? {
+                blobs.insert(layer.digest().digest().to_string(), blob);
+            }
+        }
+    }
+    Ok(blobs)
+}
+
+/// Decrypts a blob made by [`ChunkEncryption::encrypt`].
+pub(crate) fn decrypt(key: &[u8; 32], digest: &str, blob: &[u8]) -> Result<Vec<u8>> {
+    if blob.len() < NONCE_LEN + TAG_LEN {
+        return Err(encryption_error(format!("{digest} is truncated")));
+    }
+    let (nonce, rest) = blob.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| encryption_error(format!("{digest} was tampered with")))
+}
+
+/// The private keys the encrypted chunks of an image are decrypted with, see
+/// [`Image::with_decryption_keys`].
+pub struct DecryptionKeys {
+    keys: Vec<(String, PKey<Private>)>,
+    // the keys of the builds, by key id, once unwrapped
+    unwrapped: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+impl fmt::Debug for DecryptionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let recipients = self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        f.debug_struct("DecryptionKeys")
+            .field("recipients", &recipients)
+            .finish()
+    }
+}
+
+impl DecryptionKeys {
+    /// Loads RSA private keys in PEM.
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self> {
+        let keys = paths
+            .iter()
+            .map(|path| {
+                let key = PKey::private_key_from_pem(&fs::read(path)?)?;
+                Ok((recipient_id(&key)?, key))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(DecryptionKeys {
+            keys,
+            unwrapped: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The key `blob` is encrypted with, if one of the keys is among its recipients.
+    pub(crate) fn key(&self, blob: &EncryptedBlob) -> Result<[u8; 32]> {
+        let mut unwrapped = self.unwrapped.lock().unwrap();
+        if let Some(key) = unwrapped.get(&blob.key_id) {
+            return Ok(*key);
+        }
+        for wrapped in &blob.wrapped {
+            let Some((_, private)) = self.keys.iter().find(|(id, _)| *id == wrapped.recipient)
+            else {
+                continue;
+            };
+            let encrypted = STANDARD
+                .decode(&wrapped.key)
+                .map_err(|e| encryption_error(format!("invalid wrapped key: {e}")))?;
+            let mut decrypter = Decrypter::new(private)?;
+            decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+            let mut key = vec![0; decrypter.decrypt_len(&encrypted)?];
+            let len = decrypter.decrypt(&encrypted, &mut key)?;
+            let key: [u8; 32] = key[..len].try_into()?;
+            unwrapped.insert(blob.key_id.clone(), key);
+            return Ok(key);
+        }
+        Err(encryption_error(format!(
+            "none of the decryption keys can decrypt key {}",
+            blob.key_id
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_encryption() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rsa = Rsa::generate(2048)?;
+        fs::write(dir.path().join("key.pub"), rsa.public_key_to_pem()?)?;
+        fs::write(dir.path().join("key.pem"), rsa.private_key_to_pem()?)?;
+        let other = Rsa::generate(2048)?;
+        fs::write(dir.path().join("other.pem"), other.private_key_to_pem()?)?;
+
+        let encryption = ChunkEncryption::new(&[dir.path().join("key.pub")])?;
+        let blob = encryption.encrypt(b"meshuggah rocks")?;
+        assert_eq!(blob, encryption.encrypt(b"meshuggah rocks")?);
+        assert_ne!(&blob[NONCE_LEN..NONCE_LEN + 15], b"meshuggah rocks");
+
+        let mut desc = Descriptor::new(
+            MediaType::Other("application/vnd.puzzlefs.image.filedata.v1".to_string()),
+            blob.len() as u64,
+            format!("sha256:{}", hex::encode(Sha256::digest(&blob))).parse()?,
+        );
+        encryption.annotate(&mut desc)?;
+        let encrypted = EncryptedBlob::from_descriptor(&desc)?.unwrap();
+
+        let keys = DecryptionKeys::load(&[dir.path().join("key.pem")])?;
+        let key = keys.key(&encrypted)?;
+        assert_eq!(decrypt(&key, "blob", &blob)?, b"meshuggah rocks");
+
+        let mut tampered = blob.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(decrypt(&key, "blob", &tampered).is_err());
+
+        let others = DecryptionKeys::load(&[dir.path().join("other.pem")])?;
+        assert!(others.key(&encrypted).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+/// How many chunk blobs an image keeps open by default.
+pub const DEFAULT_FD_CACHE_SIZE: usize = 64;
+
+struct Entry {
+    digest: String,
+    file: Arc<fs::File>,
+    // whether the fs-verity digest of the blob was checked when it was opened
+    verified: bool,
+}
+
+// the most recently used chunk blobs of an image, so that reading the same chunks again doesn't
+// resolve their path and open them every time. The files are opened O_CLOEXEC like all the files
+// std opens, and shared between threads: they're only read with pread(), never seeked.
+pub(crate) struct FdCache {
+    limit: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl Default for FdCache {
+    fn default() -> Self {
+        FdCache::new(DEFAULT_FD_CACHE_SIZE)
+    }
+}
+
+impl FdCache {
+    pub(crate) fn new(limit: usize) -> Self {
+        FdCache {
+            limit,
+            entries: Mutex::new(VecDeque::with_capacity(limit)),
+        }
+    }
+
+    // the blob `digest`, opened with `open` unless it's in the cache; blobs opened without
+    // checking their fs-verity digest are opened again when it has to be checked
+    pub(crate) fn get_or_open(
+        &self,
+        digest: &str,
+        verify: bool,
+        open: impl FnOnce() -> io::Result<fs::File>,
+    ) -> io::Result<Arc<fs::File>> {
+        if self.limit == 0 {
+            return Ok(Arc::new(open()?));
+        }
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(i) = entries
+                .iter()
+                .position(|e| e.digest == digest && (e.verified || !verify))
+            {
+                let entry = entries.remove(i).unwrap();
+                let file = entry.file.clone();
+                entries.push_front(entry);
+                return Ok(file);
+            }
+        }
+
+        // don't hold the lock while opening, which may fetch the blob from a remote
+        let file = Arc::new(open()?);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.digest != digest);
+        entries.push_front(Entry {
+            digest: digest.to_string(),
+            file: file.clone(),
+            verified: verify,
+        });
+        entries.truncate(self.limit);
+        Ok(file)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+// a reader over a shared blob file, with a position of its own
+pub(crate) struct SharedBlob {
+    file: Arc<fs::File>,
+    offset: u64,
+}
+
+impl SharedBlob {
+    pub(crate) fn new(file: Arc<fs::File>) -> Self {
+        SharedBlob { file, offset: 0 }
+    }
+}
+
+impl Read for SharedBlob {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SharedBlob {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            io::SeekFrom::Start(offset) => (offset, 0),
+            io::SeekFrom::End(delta) => (self.file.metadata()?.len(), delta),
+            io::SeekFrom::Current(delta) => (self.offset, delta),
+        };
+        self.offset = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the blob",
+            )
+        })?;
+        Ok(self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fd_cache() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        for name in ["a", "b", "c"] {
+            fs::write(dir.path().join(name), name)?;
+        }
+        let cache = FdCache::new(2);
+        let opens = std::cell::Cell::new(0);
+        let open = |name: &str, verify| {
+            cache.get_or_open(name, verify, || {
+                opens.set(opens.get() + 1);
+                fs::File::open(dir.path().join(name))
+            })
+        };
+
+        open("a", false)?;
+        open("a", false)?;
+        assert_eq!(opens.get(), 1);
+        // the digest wasn't checked the first time
+        open("a", true)?;
+        open("a", false)?;
+        assert_eq!(opens.get(), 2);
+
+        open("b", false)?;
+        open("c", false)?;
+        assert_eq!(cache.len(), 2);
+        open("a", true)?;
+        assert_eq!(opens.get(), 5);
+
+        let mut blob = SharedBlob::new(open("c", false)?);
+        let mut data = String::new();
+        blob.read_to_string(&mut data)?;
+        assert_eq!(data, "c");
+        assert_eq!(blob.seek(io::SeekFrom::End(-1))?, 0);
+        assert!(blob.seek(io::SeekFrom::Current(-1)).is_err());
+        assert!(FdCache::new(0)
+            .get_or_open("a", false, || fs::File::open(dir.path().join("a")))
+            .is_ok());
+        Ok(())
+    }
+}
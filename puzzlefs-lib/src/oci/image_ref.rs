@@ -0,0 +1,153 @@
+//! References to the manifests of image directories, as the command line takes them.
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::format::{Digest, Result, WireFormatError};
+
+/// What an [`ImageRef`] designates in its image directory. It displays as the tag or as
+/// `sha256:<digest>`, which the functions of [`Image`](super::Image) taking a tag take too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    /// A tag of the image.
+    Tag(String),
+    /// A manifest, by the sha256 digest of its contents.
+    Digest(Digest),
+}
+
+/// A manifest of an image directory, written as `<oci_dir>:<tag>` or `<oci_dir>@sha256:<digest>`.
+///
+/// The tag or digest is split off the end, so the directory may contain colons and `@`s itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub oci_dir: PathBuf,
+    pub reference: Reference,
+}
+
+impl ImageRef {
+    /// The tag referenced, None if the manifest is referenced by digest.
+    pub fn tag(&self) -> Option<&str> {
+        match &self.reference {
+            Reference::Tag(tag) => Some(tag),
+            Reference::Digest(_) => None,
+        }
+    }
+}
+
+fn invalid(s: &str, reason: &str) -> WireFormatError {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid image reference {s}: {reason}"),
+    )
+    .into()
+}
+
+// the tags of the OCI distribution spec: [a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}
+fn valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+        && tag.len() <= 128
+}
+
+impl FromStr for ImageRef {
+    type Err = WireFormatError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // a digest is told from a directory which has an @ in its name by its algorithm
+        let digest = s.rsplit_once('@').and_then(|(oci_dir, digest)| {
+            let (algorithm, encoded) = digest.split_once(':')?;
+            matches!(algorithm, "sha256" | "sha512").then_some((oci_dir, algorithm, encoded))
+        });
+        let (oci_dir, reference) = match digest {
+            Some((_, "sha512", _)) => return Err(invalid(s, "only sha256 digests are supported")),
+            Some((oci_dir, _, encoded)) => {
+                let digest = Digest::try_from(encoded)
+                    .map_err(|_| invalid(s, "expected 64 hex digits after sha256:"))?;
+                (oci_dir, Reference::Digest(digest))
+            }
+            None => {
+                let (oci_dir, tag) = s
+                    .rsplit_once(':')
+                    .filter(|(_, tag)| !tag.contains('/'))
+                    .ok_or_else(|| {
+                        invalid(s, "expected <oci_dir>:<tag> or <oci_dir>@sha256:<digest>")
+                    })?;
+                if !valid_tag(tag) {
+                    return Err(invalid(s, &format!("{tag:?} isn't a valid tag")));
+                }
+                (oci_dir, Reference::Tag(tag.to_string()))
+            }
+        };
+        if oci_dir.is_empty() {
+            return Err(invalid(s, "missing image directory"));
+        }
+        Ok(ImageRef {
+            oci_dir: PathBuf::from(oci_dir),
+            reference,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Tag(tag) => write!(f, "{tag}"),
+            Reference::Digest(digest) => write!(f, "sha256:{digest}"),
+        }
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = match self.reference {
+            Reference::Tag(_) => ':',
+            Reference::Digest(_) => '@',
+        };
+        write!(f, "{}{separator}{}", self.oci_dir.display(), self.reference)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_ref() -> anyhow::Result<()> {
+        let image_ref: ImageRef = "/srv/images/oci:v1.2".parse()?;
+        assert_eq!(image_ref.oci_dir, PathBuf::from("/srv/images/oci"));
+        assert_eq!(image_ref.tag(), Some("v1.2"));
+        assert_eq!(image_ref.to_string(), "/srv/images/oci:v1.2");
+
+        // the tag is what follows the last colon
+        let image_ref: ImageRef = "/mnt/c:d/oci@1:latest".parse()?;
+        assert_eq!(image_ref.oci_dir, PathBuf::from("/mnt/c:d/oci@1"));
+        assert_eq!(image_ref.tag(), Some("latest"));
+
+        let hex = "ab".repeat(32);
+        let image_ref: ImageRef = format!("oci@sha256:{hex}").parse()?;
+        assert_eq!(image_ref.oci_dir, PathBuf::from("oci"));
+        assert_eq!(
+            image_ref.reference,
+            Reference::Digest(Digest::try_from(hex.as_str())?)
+        );
+        assert_eq!(image_ref.tag(), None);
+        assert_eq!(image_ref.to_string(), format!("oci@sha256:{hex}"));
+
+        for invalid in [
+            "oci",
+            "oci:",
+            ":tag",
+            "/a:b/oci",
+            "oci:-tag",
+            "oci@sha256:abcd",
+            "oci@sha512:abcd",
+        ] {
+            assert!(invalid.parse::<ImageRef>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+}
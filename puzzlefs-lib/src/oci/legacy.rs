@@ -0,0 +1,229 @@
+//! The layout of the OCI directories written by puzzlefs before it switched to ocidir.
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use ocidir::oci_spec::image::{self, Descriptor, MediaType, Platform, ANNOTATION_REF_NAME};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::compression::{Noop, Zstd};
+use crate::format::{
+    Digest, InodeMode, MetadataLimits, Result, Rootfs, RootfsReader, WireFormatError,
+};
+use crate::fsverity_helpers::{get_fs_verity_digest, VerityHash};
+use crate::oci::media_types::{self, PUZZLEFS_ROOTFS, VERITY_ROOT_HASH_ANNOTATION};
+use crate::oci::{lock_dir, Image};
+use crate::reader::negotiate_version;
+
+const LEGACY_SCHEMA_VERSION: i64 = -1;
+const INDEX: &str = "index.json";
+const LEGACY_INDEX: &str = "index.json.legacy";
+
+#[derive(Deserialize)]
+struct LegacyIndex {
+    #[serde(rename = "schemaVersion")]
+    version: i64,
+    manifests: Vec<LegacyDescriptor>,
+}
+
+#[derive(Deserialize)]
+struct LegacyDescriptor {
+    digest: Digest,
+    size: u64,
+    #[serde(alias = "mediaType")]
+    media_type: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+// a tag of the legacy index, checked and ready to be converted
+struct LegacyTag {
+    tag: String,
+    rootfs: LegacyDescriptor,
+    rootfs_verity: String,
+    // the chunks of the rootfs, and whether they're compressed
+    chunks: BTreeMap<[u8; 32], bool>,
+}
+
+pub(crate) fn is_legacy(oci_dir: &Path) -> Result<bool> {
+    #[derive(Deserialize)]
+    struct Schema {
+        #[serde(rename = "schemaVersion")]
+        version: i64,
+    }
+
+    let index = match fs::read(oci_dir.join(INDEX)) {
+        Ok(index) => index,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    // anything else is for ocidir to make sense of
+    Ok(serde_json::from_slice::<Schema>(&index)
+        .map_or(false, |schema| schema.version == LEGACY_SCHEMA_VERSION))
+}
+
+fn read_tag(oci_dir: &Path, tag: String, rootfs: LegacyDescriptor) -> Result<LegacyTag> {
+    let path = oci_dir
+        .join(Image::blob_path())
+        .join(rootfs.digest.to_string());
+    let file = cap_std::fs::File::from_std(fs::File::open(&path)?);
+    let reader = RootfsReader::open(file, &MetadataLimits::default())?;
+    negotiate_version(reader.get_manifest_version()?)?;
+
+    let mut chunks = BTreeMap::new();
+    for inode in Rootfs::try_from(reader)?.metadatas.iter().flatten() {
+        if let InodeMode::File {
+            chunks: file_chunks,
+            ..
+        } = &inode.mode
+        {
+            for chunk in file_chunks {
+                chunks.insert(chunk.blob.digest, chunk.blob.compressed);
+            }
+        }
+    }
+
+    let rootfs_verity = match rootfs.annotations.get(VERITY_ROOT_HASH_ANNOTATION) {
+        Some(verity) => verity.clone(),
+        None => hex::encode(get_fs_verity_digest(
+            &fs::read(&path)?,
+            VerityHash::default(),
+        )?),
+    };
+    Ok(LegacyTag {
+        tag,
+        rootfs,
+        rootfs_verity,
+        chunks,
+    })
+}
+
+fn write_manifests(oci_dir: &Path, tags: Vec<LegacyTag>) -> Result<()> {
+    let image = Image::new(oci_dir)?;
+    for tag in tags {
+        let mut manifest = image.get_empty_manifest()?;
+        let mut rootfs = Descriptor::new(
+            MediaType::Other(PUZZLEFS_ROOTFS.to_string()),
+            tag.rootfs.size,
+            image::Digest::from_str(&format!("sha256:{}", tag.rootfs.digest))?,
+        );
+        rootfs.set_annotations(Some(HashMap::from([(
+            VERITY_ROOT_HASH_ANNOTATION.to_string(),
+            tag.rootfs_verity,
+        )])));
+        manifest.layers_mut().push(rootfs);
+        for (digest, compressed) in tag.chunks {
+            let digest = Digest::new(&digest);
+            if compressed {
+                image.reference_blob::<Zstd>(&digest, &mut manifest, media_types::Chunk {})?;
+            } else {
+                image.reference_blob::<Noop>(&digest, &mut manifest, media_types::Chunk {})?;
+            }
+        }
+        image
+            .0
+            .insert_manifest(manifest, Some(&tag.tag), Platform::default())?;
+    }
+    Ok(())
+}
+
+/// Converts `oci_dir` to the current layout if it has the legacy one. Nothing is changed unless
+/// every tag can be converted.
+pub(crate) fn upgrade(oci_dir: &Path) -> Result<()> {
+    if !is_legacy(oci_dir)? {
+        return Ok(());
+    }
+    // the manifests are inserted in the index under its lock, and another writer may have
+    // converted the image while this one waited for it
+    let dir = cap_std::fs::Dir::open_ambient_dir(oci_dir, cap_std::ambient_authority())?;
+    let _lock = lock_dir(&dir)?;
+    if !is_legacy(oci_dir)? {
+        return Ok(());
+    }
+    info!("converting {} from the legacy layout", oci_dir.display());
+    let index: LegacyIndex = serde_json::from_slice(&fs::read(oci_dir.join(INDEX))?)?;
+    if index.version != LEGACY_SCHEMA_VERSION {
+        return Err(WireFormatError::InvalidImageSchema(
+            index.version as i32,
+            Backtrace::capture(),
+        ));
+    }
+
+    let mut tags = Vec::new();
+    for desc in index.manifests {
+        let Some(tag) = desc.annotations.get(ANNOTATION_REF_NAME).cloned() else {
+            warn!("skipping {}, which has no tag", desc.digest);
+            continue;
+        };
+        if desc.media_type != PUZZLEFS_ROOTFS {
+            warn!("skipping {tag}, which isn't a puzzlefs rootfs");
+            continue;
+        }
+        tags.push(read_tag(oci_dir, tag, desc)?);
+    }
+
+    fs::rename(oci_dir.join(INDEX), oci_dir.join(LEGACY_INDEX))?;
+    if let Err(e) = write_manifests(oci_dir, tags) {
+        // put the legacy index back, so the conversion can be tried again
+        let _ = fs::rename(oci_dir.join(LEGACY_INDEX), oci_dir.join(INDEX));
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::oci::ImageError;
+    use crate::reader::PuzzleFS;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_legacy_layout() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let layers = image.find_manifest("test")?.unwrap().layers().len();
+        drop(image);
+
+        // the index the old puzzlefs would have written for the same rootfs
+        let legacy_index = serde_json::json!({
+            "schemaVersion": -1,
+            "manifests": [{
+                "digest": format!("sha256:{}", rootfs.digest().digest()),
+                "size": rootfs.size(),
+                "media_type": PUZZLEFS_ROOTFS,
+                "annotations": {
+                    ANNOTATION_REF_NAME: "legacy",
+                },
+            }],
+            "annotations": {},
+        });
+        fs::write(dir.path().join(INDEX), legacy_index.to_string())?;
+
+        // opening the image doesn't change it
+        assert!(matches!(
+            Image::open(dir.path()),
+            Err(WireFormatError::Image(ImageError::LegacyLayout { .. }))
+        ));
+        assert!(!dir.path().join(LEGACY_INDEX).exists());
+
+        let image = Image::upgrade(dir.path())?;
+        assert!(dir.path().join(LEGACY_INDEX).exists());
+        let manifest = image.find_manifest("legacy")?.unwrap();
+        assert_eq!(manifest.layers().len(), layers);
+        assert_eq!(
+            image.get_pfs_rootfs_verity("legacy")?,
+            hex::decode(&rootfs.annotations().as_ref().unwrap()[VERITY_ROOT_HASH_ANNOTATION])?
+        );
+
+        let pfs = PuzzleFS::open(image, "legacy", None)?;
+        assert!(pfs.lookup(Path::new("/SekienAkashita.jpg"))?.is_some());
+        Ok(())
+    }
+}
@@ -24,3 +24,43 @@ impl PuzzleFSMediaType for Chunk {
 
 pub(crate) const VERITY_ROOT_HASH_ANNOTATION: &str =
     "io.puzzlefsoci.puzzlefs.puzzlefs_verity_root_hash";
+
+// set to "true" on the manifests of images which must only be mounted with fs-verity checks
+pub(crate) const REQUIRE_VERITY_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.require_verity";
+
+// the version of puzzlefs which built the image, with BuilderConfig::stamp
+pub(crate) const VERSION_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.version";
+
+// the chunking algorithm and its parameters, with BuilderConfig::stamp or BuilderConfig::chunker
+pub(crate) const CHUNKER_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.chunker";
+
+// set to "files" when the chunker restarts at the start of each file, see
+// BuilderConfig::anchor_files
+pub(crate) const CHUNK_ANCHOR_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.chunk-anchor";
+
+// the size above which files are chunked on their own, see BuilderConfig::parallel_chunking_above
+pub(crate) const PARALLEL_CHUNKING_ANNOTATION: &str =
+    "io.puzzlefsoci.puzzlefs.parallel-chunking-above";
+
+// set to "true" on the chunks an image reads first, e.g. while a container starts; they come
+// right after the rootfs in the layers of the manifest, in the order they are read, and lazy pulls
+// fetch them
+pub(crate) const LANDMARK_ANNOTATION: &str = "io.puzzlefsoci.puzzlefs.landmark";
+
+// the config of the artifact manifests, which have nothing to configure
+pub(crate) const EMPTY_CONFIG: &str = "application/vnd.oci.empty.v1+json";
+
+// the artifact type of the per-file listings attached to images, and the media type of their only
+// layer
+pub(crate) const CONTENT_MANIFEST_ARTIFACT: &str = "application/vnd.puzzlefs.content-manifest.v1";
+pub(crate) const CONTENT_MANIFEST: &str = "application/vnd.puzzlefs.content-manifest.v1+json";
+
+// the media types of SBOMs registered with IANA, which scanners look for in referrers
+pub(crate) const SPDX_JSON: &str = "application/spdx+json";
+pub(crate) const CYCLONEDX_JSON: &str = "application/vnd.cyclonedx+json";
+
+// the suffix of the media types of encrypted layers, and the annotations saying how to decrypt
+// them, as in OCI image encryption; the keys are wrapped with the puzzlefs protocol
+pub(crate) const ENCRYPTED_SUFFIX: &str = "+encrypted";
+pub(crate) const ENCRYPTION_KEYS_ANNOTATION: &str = "org.opencontainers.image.enc.keys.puzzlefs";
+pub(crate) const ENCRYPTION_OPTS_ANNOTATION: &str = "org.opencontainers.image.enc.pubopts";
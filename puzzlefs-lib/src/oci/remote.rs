@@ -0,0 +1,406 @@
+//! Remote blob stores: object stores and web servers holding the layout of an OCI directory.
+use std::backtrace::Backtrace;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use ocidir::oci_spec::image::{
+    ImageIndex, ImageIndexBuilder, ImageManifest, MediaType, ANNOTATION_REF_NAME,
+};
+use sha2::{Digest as Sha2Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::format::{Result, WireFormatError};
+use crate::oci::blob_store::referenced_blobs;
+use crate::oci::media_types::LANDMARK_ANNOTATION;
+use crate::oci::{Descriptor, Digest, Image, ImageError};
+
+pub mod cache;
+pub mod http;
+pub mod s3;
+
+pub use cache::BlobCache;
+pub use http::HttpStore;
+pub use s3::S3Store;
+
+const INDEX: &str = "index.json";
+const OCI_LAYOUT: &str = "oci-layout";
+
+// blobs are fetched in parts of this size, so the large ones don't have to fit in memory
+const FETCH_PART_SIZE: u64 = 8 << 20;
+
+/// Where the blobs and the index of images are stored, keyed by their path in an OCI directory.
+pub trait RemoteStore: Send + Sync {
+    /// The contents of `key`, or only its bytes in `range`. Missing keys are NotFound errors.
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Vec<u8>>;
+    /// Writes `data` to `key`, replacing what was there.
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    /// The size of `key`, None if it doesn't exist.
+    fn size(&self, key: &str) -> io::Result<Option<u64>>;
+}
+
+fn blob_key(digest: &str) -> String {
+    format!("{}/{digest}", Image::blob_path().display())
+}
+
+fn remote_error(msg: String) -> WireFormatError {
+    WireFormatError::RemoteError(msg, Backtrace::capture())
+}
+
+/// Opens the remote at `url`: `s3://bucket[/prefix]` for an S3 compatible object store, see
+/// [`S3Store`], or `http[s]://...` for a read-only web server, see [`HttpStore`].
+pub fn open_remote(url: &str) -> Result<Arc<dyn RemoteStore>> {
+    if let Some(location) = url.strip_prefix("s3://") {
+        Ok(Arc::new(S3Store::from_env(location)?))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Arc::new(HttpStore::new(url)?))
+    } else {
+        Err(remote_error(format!(
+            "unsupported remote {url}, expected s3://bucket[/prefix] or http[s]://..."
+        )))
+    }
+}
+
+static FETCH_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Downloads the blob `digest` from `remote` into the blobs directory `blobs`, checking its
+/// digest. The blob is written under a temporary name first, so readers never see a partial blob.
+pub(crate) fn fetch_blob(
+    blobs: &cap_std::fs::Dir,
+    remote: &dyn RemoteStore,
+    digest: &str,
+) -> Result<()> {
+    if Digest::try_from(digest).is_err() {
+        return Err(remote_error(format!("invalid blob digest {digest}")));
+    }
+    let key = blob_key(digest);
+    let size = remote.size(&key)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("blob {digest} not found on the remote"),
+        )
+    })?;
+    debug!("fetching blob {digest}, {size} bytes");
+
+    // a name the garbage collection of blob stores leaves alone
+    let tmp = format!(
+        ".{digest}.{}.{}",
+        std::process::id(),
+        FETCH_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let fetch = || -> Result<()> {
+        let mut file = blobs.create(&tmp)?;
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+        while offset < size {
+            let end = size.min(offset + FETCH_PART_SIZE);
+            let part = remote.get(&key, Some(offset..end))?;
+            if part.len() as u64 != end - offset {
+                return Err(remote_error(format!(
+                    "got {} bytes of blob {digest} at offset {offset}, expected {}",
+                    part.len(),
+                    end - offset
+                )));
+            }
+            hasher.update(&part);
+            file.write_all(&part)?;
+            offset = end;
+        }
+        let actual = hex::encode(hasher.finalize());
+        if actual != digest {
+            return Err(ImageError::DigestMismatch {
+                expected: digest.to_string(),
+                found: actual,
+            }
+            .into());
+        }
+        file.sync_all()?;
+        blobs.rename(&tmp, blobs, digest)?;
+        Ok(())
+    };
+    let result = fetch();
+    if result.is_err() {
+        let _ = blobs.remove_file(&tmp);
+    }
+    result
+}
+
+// fetches the blob unless the image already has it, returns whether it was fetched
+fn fetch_missing(image: &Image, remote: &dyn RemoteStore, digest: &str) -> Result<bool> {
+    if image.0.blobs_dir().exists(digest) {
+        return Ok(false);
+    }
+    fetch_blob(image.0.blobs_dir(), remote, digest)?;
+    Ok(true)
+}
+
+fn tag_of(desc: &Descriptor) -> Option<&String> {
+    desc.annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(ANNOTATION_REF_NAME))
+}
+
+// the manifests a tag points to: itself, or those listed by its image index
+pub(crate) fn manifests_of(image: &Image, top: &Descriptor) -> Result<Vec<Descriptor>> {
+    if top.media_type() != &MediaType::ImageIndex {
+        return Ok(vec![top.clone()]);
+    }
+    let index: ImageIndex = image.0.read_json_blob(top)?;
+    Ok(index.manifests().clone())
+}
+
+/// Uploads `tag` to `remote`: the blobs the remote doesn't have yet, then the manifests, and
+/// finally the `index.json` of the remote, so the tag only shows up once everything it needs is
+/// there. The other tags of the remote are kept, but concurrent pushes to the same remote may
+/// lose each other's tags. Returns the number of uploaded blobs.
+pub fn push(image: &Image, remote: &dyn RemoteStore, tag: &str) -> Result<usize> {
+    let top = image
+        .0
+        .find_manifest_descriptor_with_tag(tag)?
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: tag.to_string(),
+        })?;
+    let manifests = manifests_of(image, &top)?;
+
+    let mut blobs = BTreeSet::new();
+    for desc in &manifests {
+        let manifest: ImageManifest = image.0.read_json_blob(desc)?;
+        blobs.extend(referenced_blobs(image, desc.digest().digest(), &manifest)?);
+    }
+    // the manifests go after the blobs they reference
+    let mut ordered = blobs
+        .into_iter()
+        .filter(|blob| !manifests.iter().any(|desc| desc.digest().digest() == blob))
+        .collect::<Vec<_>>();
+    ordered.extend(
+        manifests
+            .iter()
+            .map(|desc| desc.digest().digest().to_string()),
+    );
+    if top.media_type() == &MediaType::ImageIndex {
+        ordered.push(top.digest().digest().to_string());
+    }
+
+    let mut uploaded = 0;
+    for digest in ordered {
+        let key = blob_key(&digest);
+        if remote.size(&key)?.is_some() {
+            continue;
+        }
+        debug!("uploading blob {digest}");
+        remote.put(&key, &image.0.blobs_dir().read(&digest)?)?;
+        uploaded += 1;
+    }
+
+    let mut index = match remote.get(INDEX, None) {
+        Ok(index) => serde_json::from_slice(&index)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            remote.put(OCI_LAYOUT, br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+            ImageIndexBuilder::default()
+                .schema_version(2_u32)
+                .manifests(Vec::new())
+                .build()?
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = index
+        .manifests()
+        .iter()
+        .filter(|desc| tag_of(desc).map_or(true, |t| t != tag))
+        .cloned()
+        .collect::<Vec<_>>();
+    entries.push(top);
+    index.set_manifests(entries);
+    remote.put(INDEX, &serde_json::to_vec(&index)?)?;
+    info!("pushed {tag}, uploaded {uploaded} blobs");
+    Ok(uploaded)
+}
+
+fn is_landmark(desc: &Descriptor) -> bool {
+    desc.annotations()
+        .as_ref()
+        .is_some_and(|annotations| annotations.contains_key(LANDMARK_ANNOTATION))
+}
+
+/// Downloads `tag` from `remote` and tags it in the image. A lazy pull only downloads the
+/// manifests, their configs and the chunks marked as
+/// [landmarks](crate::builder::BuilderConfig::landmarks), the image must then be opened
+/// [with the remote](Image::with_remote) to read the tag. Returns the number of downloaded blobs.
+pub fn pull(image: &Image, remote: &dyn RemoteStore, tag: &str, lazy: bool) -> Result<usize> {
+    let index: ImageIndex = serde_json::from_slice(&remote.get(INDEX, None)?)?;
+    let top = index
+        .manifests()
+        .iter()
+        .find(|desc| tag_of(desc).is_some_and(|t| t == tag))
+        .cloned()
+        .ok_or_else(|| ImageError::MissingManifest {
+            tag: format!("{tag} on the remote"),
+        })?;
+
+    let mut fetched = usize::from(fetch_missing(image, remote, top.digest().digest())?);
+    let manifests = manifests_of(image, &top)?;
+    for desc in &manifests {
+        fetched += usize::from(fetch_missing(image, remote, desc.digest().digest())?);
+        let manifest: ImageManifest = image.0.read_json_blob(desc)?;
+        let config = manifest.config().digest().digest();
+        fetched += usize::from(fetch_missing(image, remote, config)?);
+        if lazy {
+            // the chunks the image reads first, if it was built with landmarks
+            for layer in manifest.layers().iter().filter(|layer| is_landmark(layer)) {
+                fetched += usize::from(fetch_missing(image, remote, layer.digest().digest())?);
+            }
+            continue;
+        }
+        // the layers first, since the rootfs tells which chunks of the base a delta uses
+        for layer in manifest.layers() {
+            fetched += usize::from(fetch_missing(image, remote, layer.digest().digest())?);
+        }
+        for digest in referenced_blobs(image, desc.digest().digest(), &manifest)? {
+            fetched += usize::from(fetch_missing(image, remote, &digest)?);
+        }
+    }
+
+    for desc in manifests {
+        image.tag_descriptor(desc, tag)?;
+    }
+    info!("pulled {tag}, downloaded {fetched} blobs");
+    Ok(fetched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{build_initial_rootfs, build_test_fs, BuilderConfig};
+    use crate::compression::Zstd;
+    use crate::reader::{FileReader, PuzzleFS};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    #[derive(Default)]
+    pub(super) struct MemoryStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl RemoteStore for MemoryStore {
+        fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Vec<u8>> {
+            let objects = self.0.lock().unwrap();
+            let data = objects
+                .get(key)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            Ok(match range {
+                Some(range) => data[range.start as usize..range.end as usize].to_vec(),
+                None => data.clone(),
+            })
+        }
+
+        fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+
+        fn size(&self, key: &str) -> io::Result<Option<u64>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|data| data.len() as u64))
+        }
+    }
+
+    fn blob_count(dir: &Path) -> anyhow::Result<usize> {
+        Ok(fs::read_dir(dir.join(Image::blob_path()))?.count())
+    }
+
+    #[test]
+    fn test_push_pull() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("source"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let remote = Arc::new(MemoryStore::default());
+
+        let pushed = push(&image, remote.as_ref(), "test")?;
+        assert!(remote.size(OCI_LAYOUT)?.is_some());
+        // pushing again only updates the index
+        assert_eq!(push(&image, remote.as_ref(), "test")?, 0);
+
+        let full = Image::new(&dir.path().join("full"))?;
+        assert_eq!(pull(&full, remote.as_ref(), "test", false)?, pushed);
+        assert_eq!(
+            full.find_manifest_descriptor("test")?.unwrap().digest(),
+            image.find_manifest_descriptor("test")?.unwrap().digest()
+        );
+        PuzzleFS::open(full, "test", None)?;
+
+        // the lazy image only has the manifest and its config until it's read
+        let lazy_dir = dir.path().join("lazy");
+        let lazy = Image::new(&lazy_dir)?;
+        assert_eq!(pull(&lazy, remote.as_ref(), "test", true)?, 2);
+        assert!(PuzzleFS::open(Image::open(&lazy_dir)?, "test", None).is_err());
+        assert!(matches!(
+            pull(&lazy, remote.as_ref(), "missing", true),
+            Err(WireFormatError::Image(ImageError::MissingManifest { .. }))
+        ));
+
+        let lazy = Image::open(&lazy_dir)?.with_remote(remote);
+        let pfs = PuzzleFS::open(lazy, "test", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        let mut contents = Vec::new();
+        io::copy(&mut FileReader::new(&pfs.oci, &inode)?, &mut contents)?;
+        assert_eq!(
+            contents,
+            fs::read("src/builder/test/test-1/SekienAkashita.jpg")?
+        );
+        // the image has a single file, which uses every chunk
+        assert_eq!(blob_count(&lazy_dir)?, pushed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lazy_pull_landmarks() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("source"))?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        build_test_fs(rootfs, &image, "test")?;
+        let chunk = image.find_manifest("test")?.unwrap().layers()[1].clone();
+        let config = BuilderConfig {
+            landmarks: vec![chunk.digest().digest().to_string()],
+            ..Default::default()
+        };
+        build_initial_rootfs::<Zstd>(rootfs, &image, "marked", &config)?;
+        let remote = MemoryStore::default();
+        push(&image, &remote, "marked")?;
+
+        // the manifest, the config and the landmark
+        let lazy_dir = dir.path().join("lazy");
+        assert_eq!(pull(&Image::new(&lazy_dir)?, &remote, "marked", true)?, 3);
+        assert!(lazy_dir
+            .join(Image::blob_path())
+            .join(chunk.digest().digest())
+            .exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_checks_digest() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let remote = MemoryStore::default();
+        let digest = hex::encode(Sha256::digest(b"meshuggah rocks"));
+        remote.put(&blob_key(&digest), b"meshuggah sucks")?;
+
+        assert!(matches!(
+            fetch_blob(image.0.blobs_dir(), &remote, &digest),
+            Err(WireFormatError::Image(ImageError::DigestMismatch { .. }))
+        ));
+        assert_eq!(blob_count(dir.path())?, 0);
+        assert!(fetch_blob(image.0.blobs_dir(), &remote, "../../index.json").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! A cache of the blobs fetched from remotes, shared by the mounts of a host.
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use cap_std::fs::Dir;
+use nix::fcntl::{flock, FlockArg};
+use tracing::{debug, info};
+
+use super::{fetch_blob, RemoteStore};
+use crate::format::Result;
+use crate::fsverity_helpers::{fsverity_enable, VerityHash, FS_VERITY_BLOCK_SIZE_DEFAULT};
+use crate::oci::Digest;
+
+const BLOBS: &str = "sha256";
+const LOCK: &str = "lock";
+// eviction makes some room below the limit, so it doesn't run again for each new blob
+const LOW_WATERMARK_PERCENT: u64 = 90;
+
+struct Entry {
+    name: String,
+    size: u64,
+    used: SystemTime,
+}
+
+impl Entry {
+    // the other files are blobs being fetched
+    fn is_blob(&self) -> bool {
+        Digest::try_from(self.name.as_str()).is_ok()
+    }
+}
+
+pub struct BlobCache {
+    path: PathBuf,
+    blobs: Dir,
+    max_size: u64,
+    verity_hash: VerityHash,
+    // the size of the cached blobs as of our last look, other processes add blobs too
+    size: AtomicU64,
+}
+
+impl BlobCache {
+    /// Opens or creates the cache at `path`, which evicts blobs once they use more than
+    /// `max_size` bytes. The fs-verity digests of the cached blobs use `verity_hash`, which must
+    /// be the hash the images were built with for their fs-verity digests to be checked.
+    pub fn open(path: &Path, max_size: u64, verity_hash: VerityHash) -> Result<Self> {
+        fs::create_dir_all(path.join(BLOBS))?;
+        // the mount daemons change their working directory
+        let path = fs::canonicalize(path)?;
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.join(LOCK))?;
+        let blobs = Dir::open_ambient_dir(path.join(BLOBS), cap_std::ambient_authority())?;
+        let cache = BlobCache {
+            path,
+            blobs,
+            max_size,
+            verity_hash,
+            size: AtomicU64::new(0),
+        };
+        cache.size.store(cache.size()?, Ordering::Relaxed);
+        Ok(cache)
+    }
+
+    /// The space used by the cached blobs.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self
+            .entries()?
+            .iter()
+            .filter(|entry| entry.is_blob())
+            .map(|entry| entry.size)
+            .sum())
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for entry in self.blobs.entries()? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            entries.push(Entry {
+                name,
+                size: metadata.len(),
+                used: metadata.modified()?.into_std(),
+            });
+        }
+        Ok(entries)
+    }
+
+    // the lock is released when the returned file is closed; each call opens the lock file again
+    // so the threads of a process don't share their locks
+    fn lock(&self, arg: FlockArg) -> Result<fs::File> {
+        let file = fs::File::open(self.path.join(LOCK))?;
+        flock(file.as_raw_fd(), arg).map_err(io::Error::from)?;
+        Ok(file)
+    }
+
+    // opens a cached blob, marking it as used
+    fn open_blob(&self, digest: &str) -> io::Result<Option<cap_std::fs::File>> {
+        let file = match self.blobs.open(digest) {
+            Ok(file) => file.into_std(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            debug!("cannot mark cached blob {digest} as used: {e}");
+        }
+        Ok(Some(cap_std::fs::File::from_std(file)))
+    }
+
+    // best effort, not every filesystem supports fs-verity
+    fn enable_verity(&self, file: &cap_std::fs::File) {
+        if let Err(e) = fsverity_enable(
+            file.as_raw_fd(),
+            FS_VERITY_BLOCK_SIZE_DEFAULT,
+            self.verity_hash.inner_hash_algorithm(),
+            &[],
+        ) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                debug!("cannot enable fs-verity on a cached blob: {e}");
+            }
+        }
+    }
+
+    /// Opens the blob `digest`, fetching it from `remote` first if it isn't cached.
+    pub(crate) fn open_or_fetch(
+        &self,
+        remote: &dyn RemoteStore,
+        digest: &str,
+    ) -> Result<cap_std::fs::File> {
+        if let Some(file) = self.open_blob(digest)? {
+            return Ok(file);
+        }
+        let file = {
+            let _lock = self.lock(FlockArg::LockShared)?;
+            fetch_blob(&self.blobs, remote, digest)?;
+            self.blobs.open(digest)?
+        };
+        self.enable_verity(&file);
+
+        let len = file.metadata()?.len();
+        if self.size.fetch_add(len, Ordering::Relaxed) + len > self.max_size {
+            // the blob stays readable through `file` even if it's evicted right away
+            self.evict()?;
+        }
+        Ok(file)
+    }
+
+    /// Removes the least recently used blobs until the cache is comfortably below its size
+    /// limit, along with the partial blobs of the processes which died while fetching them.
+    /// Returns the number of removed blobs and the space they used.
+    pub fn evict(&self) -> Result<(u64, u64)> {
+        let _lock = self.lock(FlockArg::LockExclusive)?;
+        let (mut blobs, partial): (Vec<_>, Vec<_>) =
+            self.entries()?.into_iter().partition(Entry::is_blob);
+        // nobody is fetching anything while we hold the lock
+        for entry in partial {
+            let _ = self.blobs.remove_file(&entry.name);
+        }
+
+        let mut size = blobs.iter().map(|entry| entry.size).sum::<u64>();
+        let target = self.max_size / 100 * LOW_WATERMARK_PERCENT;
+        blobs.sort_by_key(|entry| entry.used);
+        let mut removed = 0;
+        let mut removed_bytes = 0;
+        for entry in blobs {
+            if size <= target {
+                break;
+            }
+            match self.blobs.remove_file(&entry.name) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            size -= entry.size;
+            removed += 1;
+            removed_bytes += entry.size;
+        }
+        self.size.store(size, Ordering::Relaxed);
+        if removed > 0 {
+            info!("evicted {removed} blobs ({removed_bytes} bytes) from the blob cache");
+        }
+        Ok((removed, removed_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::MemoryStore;
+    use super::super::{pull, push};
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::oci::Image;
+    use crate::reader::{FileReader, PuzzleFS};
+    use sha2::{Digest as Sha2Digest, Sha256};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    fn put(remote: &MemoryStore, data: &[u8]) -> anyhow::Result<String> {
+        let digest = hex::encode(Sha256::digest(data));
+        remote.put(&format!("blobs/sha256/{digest}"), data)?;
+        Ok(digest)
+    }
+
+    fn cached(cache: &BlobCache, digest: &str) -> bool {
+        cache.blobs.exists(digest)
+    }
+
+    #[test]
+    fn test_eviction() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let remote = MemoryStore::default();
+        let a = put(&remote, &[b'a'; 1000])?;
+        let b = put(&remote, &[b'b'; 1000])?;
+        let c = put(&remote, &[b'c'; 1000])?;
+
+        let cache = BlobCache::open(dir.path(), 2500, VerityHash::default())?;
+        cache.open_or_fetch(&remote, &a)?;
+        cache.open_or_fetch(&remote, &b)?;
+        assert_eq!(cache.size()?, 2000);
+        // the third blob doesn't fit, the least recently used one makes room for it
+        cache.open_or_fetch(&remote, &c)?;
+        assert!(!cached(&cache, &a) && cached(&cache, &b) && cached(&cache, &c));
+
+        cache.open_or_fetch(&remote, &b)?;
+        cache.open_or_fetch(&remote, &a)?;
+        assert!(cached(&cache, &a) && cached(&cache, &b) && !cached(&cache, &c));
+
+        // another cache in the same directory sees the same blobs
+        let other = BlobCache::open(dir.path(), 2500, VerityHash::default())?;
+        assert_eq!(other.size()?, 2000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_image() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("source"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let remote = Arc::new(MemoryStore::default());
+        push(&image, remote.as_ref(), "test")?;
+
+        let lazy_dir = dir.path().join("lazy");
+        pull(&Image::new(&lazy_dir)?, remote.as_ref(), "test", true)?;
+        let cache = Arc::new(BlobCache::open(
+            &dir.path().join("cache"),
+            u64::MAX,
+            VerityHash::default(),
+        )?);
+        let lazy = Image::open(&lazy_dir)?
+            .with_remote(remote)
+            .with_cache(cache.clone());
+        let pfs = PuzzleFS::open(lazy, "test", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        io::copy(&mut FileReader::new(&pfs.oci, &inode)?, &mut io::sink())?;
+
+        // the fetched blobs went to the cache, the image only has what was pulled
+        assert_eq!(fs::read_dir(lazy_dir.join(Image::blob_path()))?.count(), 2);
+        assert!(cache.size()? > 0);
+        Ok(())
+    }
+}
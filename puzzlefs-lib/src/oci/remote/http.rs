@@ -0,0 +1,370 @@
+//! A minimal HTTP/1.1 client, and the read-only [`HttpStore`] built on it.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::ops::Range;
+use std::time::Duration;
+
+use openssl::ssl::{SslConnector, SslMethod};
+
+use super::RemoteStore;
+use crate::format::Result;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+// more than any sensible server sends
+const MAX_HEADERS: usize = 100;
+
+/// An `http://` or `https://` URL, split into the parts requests need.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Url {
+    pub(crate) tls: bool,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    /// Empty or starting with a slash, without a trailing slash.
+    pub(crate) path: String,
+}
+
+impl Url {
+    pub(crate) fn parse(url: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid URL {url}"));
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            // the colons of an IPv6 address
+            Some(_) if authority.ends_with(']') => (authority, default_port),
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let path = path.trim_matches('/');
+        Ok(Url {
+            tls,
+            host: host.to_string(),
+            port,
+            path: if path.is_empty() {
+                String::new()
+            } else {
+                format!("/{path}")
+            },
+        })
+    }
+
+    /// The value of the `Host` header of the requests to this URL.
+    pub(crate) fn host_header(&self) -> String {
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => self.host.clone(),
+            (_, port) => format!("{}:{port}", self.host),
+        }
+    }
+}
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    // with lowercase names
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Fails unless the request succeeded; a 404 is a NotFound error.
+    pub(crate) fn check(&self, what: &str) -> io::Result<()> {
+        match self.status {
+            200..=299 => Ok(()),
+            404 => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{what} not found"),
+            )),
+            status => {
+                // object stores explain their errors in the body
+                let body = String::from_utf8_lossy(&self.body[..self.body.len().min(512)]);
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{what}: HTTP status {status} {}", body.trim()),
+                ))
+            }
+        }
+    }
+
+    pub(crate) fn content_length(&self) -> io::Result<u64> {
+        self.header("content-length")
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| invalid_data("missing Content-Length".to_string()))
+    }
+
+    /// The body of the answer to a request for `range`. Servers may ignore ranges and send
+    /// everything, in which case the range is cut out of the body.
+    pub(crate) fn into_range(self, range: Option<Range<u64>>) -> Vec<u8> {
+        match range {
+            Some(range) if self.status == 200 => {
+                let len = self.body.len();
+                let start = (range.start as usize).min(len);
+                let end = (range.end as usize).min(len);
+                self.body[start..end].to_vec()
+            }
+            _ => self.body,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The `Range` header asking for `range`, which must not be empty.
+pub(crate) fn range_header(range: &Range<u64>) -> String {
+    format!("bytes={}-{}", range.start, range.end - 1)
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+fn connect(url: &Url) -> io::Result<Box<dyn Stream>> {
+    let host = url.host.trim_start_matches('[').trim_end_matches(']');
+    let tcp = TcpStream::connect((host, url.port))?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+    if !url.tls {
+        return Ok(Box::new(tcp));
+    }
+    let connector = SslConnector::builder(SslMethod::tls())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .build();
+    let stream = connector
+        .connect(host, tcp)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Ok(Box::new(stream))
+}
+
+/// Sends a request for `path` to the server of `url` and reads the whole response. `headers`
+/// come on top of `Host`, `Connection` and `Content-Length`.
+pub(crate) fn request(
+    url: &Url,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<Response> {
+    let mut stream = connect(url)?;
+    let mut head = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        url.host_header(),
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    read_response(&mut BufReader::new(stream), method == "HEAD")
+}
+
+fn read_response(reader: &mut impl BufRead, head: bool) -> io::Result<Response> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid_data(format!("bad status line {line:?}")))?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid_data("too many headers".to_string()));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let mut response = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    // the Content-Length of the answer to a HEAD is the one a GET would have
+    if head || status == 204 || status == 304 {
+        return Ok(response);
+    }
+    if response
+        .header("transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        response.body = read_chunked(reader)?;
+    } else if response.header("content-length").is_some() {
+        let len = response.content_length()?;
+        reader.by_ref().take(len).read_to_end(&mut response.body)?;
+        if (response.body.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    } else {
+        reader.read_to_end(&mut response.body)?;
+    }
+    Ok(response)
+}
+
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let size = line
+            .split(';')
+            .next()
+            .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+            .ok_or_else(|| invalid_data(format!("bad chunk size {line:?}")))?;
+        // the trailers don't matter, the connection is closed anyway
+        if size == 0 {
+            return Ok(body);
+        }
+        let start = body.len();
+        reader.by_ref().take(size).read_to_end(&mut body)?;
+        if ((body.len() - start) as u64) < size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // the CRLF ending the chunk
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+}
+
+/// A read-only remote served over HTTP: `puzzlefs serve`, or any web server exposing an OCI
+/// directory. Images can be pulled and mounted from it, but not pushed to it.
+pub struct HttpStore {
+    url: Url,
+}
+
+impl HttpStore {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(HttpStore {
+            url: Url::parse(url)?,
+        })
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}/{key}", self.url.path)
+    }
+}
+
+impl RemoteStore for HttpStore {
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Vec<u8>> {
+        let headers = range
+            .iter()
+            .map(|range| ("Range", range_header(range)))
+            .collect::<Vec<_>>();
+        let response = request(&self.url, "GET", &self.path(key), &headers, &[])?;
+        response.check(key)?;
+        Ok(response.into_range(range))
+    }
+
+    fn put(&self, _key: &str, _data: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "HTTP remotes are read-only",
+        ))
+    }
+
+    fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        let response = request(&self.url, "HEAD", &self.path(key), &[], &[])?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        response.check(key)?;
+        response.content_length().map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::oci::{server, Image};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_url() -> anyhow::Result<()> {
+        let url = Url::parse("https://s3.example.com")?;
+        assert_eq!(
+            url,
+            Url {
+                tls: true,
+                host: "s3.example.com".to_string(),
+                port: 443,
+                path: String::new(),
+            }
+        );
+        assert_eq!(url.host_header(), "s3.example.com");
+
+        let url = Url::parse("http://[::1]:8080/images/base/")?;
+        assert_eq!(url.host, "[::1]");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/images/base");
+        assert_eq!(url.host_header(), "[::1]:8080");
+
+        assert!(Url::parse("ftp://example.com").is_err());
+        assert!(Url::parse("http://example.com:http").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_chunked() -> anyhow::Result<()> {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+        let response = read_response(&mut &response[..], false)?;
+        assert_eq!(response.body, b"hello, world");
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_store() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let digest = rootfs.digest().digest().to_string();
+        let blob = fs::read(dir.path().join(Image::blob_path()).join(&digest))?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let store = HttpStore::new(&format!("http://{}", listener.local_addr()?))?;
+        server::serve(image, listener)?;
+
+        let key = format!("blobs/sha256/{digest}");
+        assert_eq!(store.size(&key)?, Some(blob.len() as u64));
+        assert_eq!(store.get(&key, None)?, blob);
+        assert_eq!(store.get(&key, Some(4..8))?, blob[4..8]);
+        assert_eq!(store.size("blobs/sha256/missing")?, None);
+        assert_eq!(
+            store.get("index.json", None)?,
+            fs::read(dir.path().join("index.json"))?
+        );
+        assert_eq!(
+            store.put(&key, &blob).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use ocidir::oci_spec::image::{Descriptor, MediaType};
+use serde_json::Value;
+use tracing::info;
+
+use super::media_types::{CYCLONEDX_JSON, SPDX_JSON};
+use super::{Image, ImageError};
+use crate::format::Result;
+
+/// The formats of the SBOMs which can be attached to an image, in their JSON encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    Spdx,
+    CycloneDx,
+}
+
+impl SbomFormat {
+    /// The format of `sbom`, told apart by the fields SPDX and CycloneDX documents must have.
+    pub fn detect(sbom: &[u8]) -> Option<Self> {
+        let document: Value = serde_json::from_slice(sbom).ok()?;
+        if document.get("spdxVersion").is_some() {
+            Some(SbomFormat::Spdx)
+        } else if document.get("bomFormat").and_then(Value::as_str) == Some("CycloneDX") {
+            Some(SbomFormat::CycloneDx)
+        } else {
+            None
+        }
+    }
+
+    /// The IANA media type of the format, used both as the artifact type of the referrer and as
+    /// the media type of its layer, which is what scanners look for.
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            SbomFormat::Spdx => SPDX_JSON,
+            SbomFormat::CycloneDx => CYCLONEDX_JSON,
+        }
+    }
+}
+
+impl FromStr for SbomFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "spdx" => Ok(SbomFormat::Spdx),
+            "cyclonedx" => Ok(SbomFormat::CycloneDx),
+            _ => Err(format!("unknown SBOM format {s}")),
+        }
+    }
+}
+
+impl fmt::Display for SbomFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SbomFormat::Spdx => write!(f, "spdx"),
+            SbomFormat::CycloneDx => write!(f, "cyclonedx"),
+        }
+    }
+}
+
+impl Image {
+    fn sbom_subject(&self, tag: &str) -> Result<Descriptor> {
+        self.find_manifest_descriptor(tag)?.ok_or_else(|| {
+            ImageError::MissingManifest {
+                tag: tag.to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// Attaches `sbom` to the manifest of `tag`, as an OCI 1.1 referrer whose only layer is the
+    /// SBOM. Without a `format`, it is detected from the document.
+    pub fn attach_sbom(
+        &self,
+        tag: &str,
+        sbom: &[u8],
+        format: Option<SbomFormat>,
+    ) -> Result<Descriptor> {
+        let Some(format) = format.or_else(|| SbomFormat::detect(sbom)) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an SPDX or CycloneDX JSON document",
+            )
+            .into());
+        };
+        let subject = self.sbom_subject(tag)?;
+        let layer = self.write_blob(sbom, MediaType::Other(format.media_type().to_string()))?;
+        info!("attaching the {format} SBOM of {tag}");
+        self.attach_artifact(subject, format.media_type(), layer)
+    }
+
+    /// The SBOM last attached to the manifest of `tag`, in either format, if any.
+    pub fn find_sbom(&self, tag: &str) -> Result<Option<(SbomFormat, Vec<u8>)>> {
+        let subject = self.sbom_subject(tag)?;
+        let formats = [SbomFormat::Spdx, SbomFormat::CycloneDx];
+        let media_types = formats.map(|format| format.media_type());
+        let Some((_, manifest)) = self.find_artifacts(&subject, &media_types)?.pop() else {
+            return Ok(None);
+        };
+        let format = formats.into_iter().find(|format| {
+            manifest.artifact_type() == &Some(MediaType::Other(format.media_type().to_string()))
+        });
+        let (Some(format), Some(layer)) = (format, manifest.layers().first()) else {
+            return Ok(None);
+        };
+        Ok(Some((
+            format,
+            self.0.blobs_dir().read(layer.digest().digest())?,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    const SPDX: &[u8] = br#"{"spdxVersion": "SPDX-2.3", "name": "test", "packages": []}"#;
+    const CYCLONEDX: &[u8] = br#"{"bomFormat": "CycloneDX", "specVersion": "1.5"}"#;
+
+    #[test]
+    fn test_sbom() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        assert_eq!(image.find_sbom("test")?, None);
+
+        assert!(image.attach_sbom("test", b"{}", None).is_err());
+        assert!(image.attach_sbom("missing", SPDX, None).is_err());
+
+        image.attach_sbom("test", SPDX, None)?;
+        assert_eq!(
+            image.find_sbom("test")?,
+            Some((SbomFormat::Spdx, SPDX.to_vec()))
+        );
+        image.attach_sbom("test", CYCLONEDX, Some(SbomFormat::CycloneDx))?;
+        assert_eq!(
+            image.find_sbom("test")?,
+            Some((SbomFormat::CycloneDx, CYCLONEDX.to_vec()))
+        );
+        Ok(())
+    }
+}
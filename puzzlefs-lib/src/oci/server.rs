@@ -0,0 +1,394 @@
+//! Serves the blobs and manifests of an image over HTTP.
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ocidir::oci_spec::image::MediaType;
+use sha2::{Digest as Sha2Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::format::Result;
+use crate::oci::Image;
+
+// more than any sensible client sends
+const MAX_HEADERS: usize = 100;
+const MAX_LINE: u64 = 8192;
+// how long a client may keep a connection without sending anything, or reading the response
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// the connections served at once; the others are closed as soon as they're accepted
+const MAX_CONNECTIONS: usize = 256;
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+    if_none_match: Option<String>,
+    keep_alive: bool,
+}
+
+// reads a line of the request into `line`, failing rather than buffering a line without end
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let n = reader.by_ref().take(MAX_LINE).read_line(line)?;
+    if n as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line too long",
+        ));
+    }
+    Ok(n)
+}
+
+// reads the next request of the connection, None once the client is done
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if read_line(reader, &mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad request line {line:?}"),
+        ));
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        range: None,
+        if_none_match: None,
+        keep_alive: version == "HTTP/1.1",
+    };
+
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if read_line(reader, &mut line)? == 0 || line.trim().is_empty() {
+            return Ok(Some(request));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match name.to_ascii_lowercase().as_str() {
+            "range" => request.range = Some(value),
+            "if-none-match" => request.if_none_match = Some(value),
+            "connection" => request.keep_alive = !value.eq_ignore_ascii_case("close"),
+            _ => (),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "too many headers",
+    ))
+}
+
+// the byte range of a `Range` header, None to send everything; ranges we don't support are
+// ignored, as HTTP allows
+fn parse_range(range: &str, len: u64) -> std::result::Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = range.strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        // the last `end` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+struct Resource {
+    body: Box<dyn ReadSeek>,
+    len: u64,
+    etag: String,
+    content_type: String,
+    immutable: bool,
+}
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+fn is_digest(s: &str) -> bool {
+    s.len() == 64
+        && s.bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+fn find(image: &Image, path: &str) -> Result<Option<Resource>> {
+    let blob = |digest: &str, content_type: String| -> Result<Option<Resource>> {
+        let file = match image.0.blobs_dir().open(digest) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(Resource {
+            len: file.metadata()?.len(),
+            body: Box::new(file.into_std()),
+            etag: format!("\"sha256:{digest}\""),
+            content_type,
+            immutable: true,
+        }))
+    };
+
+    if path == "/index.json" {
+        let index = image.0.dir().read("index.json")?;
+        return Ok(Some(Resource {
+            len: index.len() as u64,
+            etag: format!("\"sha256:{}\"", hex::encode(Sha256::digest(&index))),
+            body: Box::new(io::Cursor::new(index)),
+            content_type: MediaType::ImageIndex.to_string(),
+            immutable: false,
+        }));
+    }
+    if let Some(digest) = path.strip_prefix("/blobs/sha256/") {
+        if !is_digest(digest) {
+            return Ok(None);
+        }
+        return blob(digest, "application/octet-stream".to_string());
+    }
+    if let Some(tag) = path.strip_prefix("/manifests/") {
+        let Some(desc) = image.0.find_manifest_descriptor_with_tag(tag)? else {
+            return Ok(None);
+        };
+        let mut resource = blob(desc.digest().digest(), desc.media_type().to_string())?;
+        // the tag can move, unlike the blob it points to
+        if let Some(resource) = &mut resource {
+            resource.immutable = false;
+        }
+        return Ok(resource);
+    }
+    Ok(None)
+}
+
+fn respond(image: &Image, request: &Request, stream: &mut impl Write) -> io::Result<()> {
+    let connection = if request.keep_alive {
+        "keep-alive"
+    } else {
+        "close"
+    };
+    let status = |stream: &mut dyn Write, line: &str| {
+        write!(
+            stream,
+            "HTTP/1.1 {line}\r\nContent-Length: 0\r\nConnection: {connection}\r\n\r\n"
+        )
+    };
+    if request.method != "GET" && request.method != "HEAD" {
+        return status(stream, "405 Method Not Allowed");
+    }
+    let mut resource = match find(image, &request.path) {
+        Ok(Some(resource)) => resource,
+        Ok(None) => return status(stream, "404 Not Found"),
+        Err(e) => {
+            warn!("cannot serve {}: {e}", request.path);
+            return status(stream, "500 Internal Server Error");
+        }
+    };
+    if request.if_none_match.as_deref() == Some(resource.etag.as_str()) {
+        return status(stream, "304 Not Modified");
+    }
+
+    let range = match request
+        .range
+        .as_deref()
+        .map(|r| parse_range(r, resource.len))
+    {
+        Some(Err(())) => {
+            return write!(
+                stream,
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: {connection}\r\n\r\n",
+                resource.len
+            );
+        }
+        Some(Ok(range)) => range,
+        None => None,
+    };
+    let (start, len) = match range {
+        Some((start, end)) => {
+            write!(
+                stream,
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{end}/{}\r\n",
+                resource.len
+            )?;
+            (start, end - start + 1)
+        }
+        None => {
+            write!(stream, "HTTP/1.1 200 OK\r\n")?;
+            (0, resource.len)
+        }
+    };
+    let cache_control = if resource.immutable {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    };
+    write!(
+        stream,
+        "Content-Length: {len}\r\nContent-Type: {}\r\nETag: {}\r\nCache-Control: {cache_control}\r\nAccept-Ranges: bytes\r\nConnection: {connection}\r\n\r\n",
+        resource.content_type, resource.etag
+    )?;
+    if request.method == "GET" {
+        resource.body.seek(SeekFrom::Start(start))?;
+        io::copy(&mut resource.body.take(len), stream)?;
+    }
+    Ok(())
+}
+
+fn serve_client(image: &Image, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = io::BufWriter::new(&stream);
+    while let Some(request) = read_request(&mut reader)? {
+        debug!("{} {}", request.method, request.path);
+        respond(image, &request, &mut writer)?;
+        writer.flush()?;
+        if !request.keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// a connection being served, counted until its thread is done
+struct Connection(Arc<AtomicUsize>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves `image` over HTTP on `listener`, each connection from a thread of its own. At most
+/// `MAX_CONNECTIONS` are served at once, and idle ones are closed after `IDLE_TIMEOUT`.
+pub fn serve(image: Image, listener: TcpListener) -> Result<thread::JoinHandle<()>> {
+    info!("serving the image on {}", listener.local_addr()?);
+    let image = Arc::new(image);
+    let active = Arc::new(AtomicUsize::new(0));
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let image = Arc::clone(&image);
+            let result = stream.map(|stream| {
+                if active.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                    active.fetch_sub(1, Ordering::Relaxed);
+                    warn!(
+                        "too many connections, closing the one from {:?}",
+                        stream.peer_addr()
+                    );
+                    return;
+                }
+                let connection = Connection(Arc::clone(&active));
+                thread::spawn(move || {
+                    let _connection = connection;
+                    if let Err(e) = serve_client(&image, stream) {
+                        debug!("connection error: {e}");
+                    }
+                });
+            });
+            if let Err(e) = result {
+                warn!("cannot accept a connection: {e}");
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn get(addr: std::net::SocketAddr, request: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "{request}\r\nConnection: close\r\n\r\n")?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok(String::from_utf8_lossy(&response).into_owned())
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some((0, 9))));
+        assert_eq!(parse_range("bytes=90-", 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range("bytes=50-500", 100), Ok(Some((50, 99))));
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("items=0-1", 100), Ok(None));
+    }
+
+    #[test]
+    fn test_read_request() -> anyhow::Result<()> {
+        let request = "GET /index.json HTTP/1.1\r\nRange: bytes=0-9\r\n\r\n";
+        let request = read_request(&mut io::Cursor::new(request))?.unwrap();
+        assert_eq!(request.path, "/index.json");
+        assert_eq!(request.range.as_deref(), Some("bytes=0-9"));
+
+        // lines are only read up to MAX_LINE
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE as usize));
+        assert!(read_request(&mut io::Cursor::new(long)).is_err());
+        let header = format!("GET / HTTP/1.1\r\nX: {}", "a".repeat(MAX_LINE as usize));
+        assert!(read_request(&mut io::Cursor::new(header)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let digest = rootfs.digest().digest().to_string();
+        let blob = fs::read(dir.path().join(Image::blob_path()).join(&digest))?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        serve(image, listener)?;
+
+        let response = get(addr, &format!("GET /blobs/sha256/{digest} HTTP/1.1"))?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("ETag: \"sha256:{digest}\"\r\n")));
+        assert!(response.ends_with(&String::from_utf8_lossy(&blob).into_owned()));
+
+        let response = get(
+            addr,
+            &format!("GET /blobs/sha256/{digest} HTTP/1.1\r\nRange: bytes=4-7"),
+        )?;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains(&format!("Content-Range: bytes 4-7/{}\r\n", blob.len())));
+        assert!(response.contains("Content-Length: 4\r\n"));
+
+        let response = get(
+            addr,
+            &format!("HEAD /blobs/sha256/{digest} HTTP/1.1\r\nIf-None-Match: \"sha256:{digest}\""),
+        )?;
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+
+        let response = get(addr, "GET /manifests/test HTTP/1.1")?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("application/vnd.oci.image.manifest.v1+json"));
+        assert!(get(addr, "GET /index.json HTTP/1.1")?.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        assert!(get(addr, "GET /manifests/missing HTTP/1.1")?.starts_with("HTTP/1.1 404"));
+        assert!(
+            get(addr, "GET /blobs/sha256/../../index.json HTTP/1.1")?.starts_with("HTTP/1.1 404")
+        );
+        assert!(get(addr, "DELETE /index.json HTTP/1.1")?.starts_with("HTTP/1.1 405"));
+        Ok(())
+    }
+}
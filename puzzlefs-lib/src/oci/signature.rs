@@ -0,0 +1,209 @@
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ocidir::oci_spec::image::{Descriptor, ImageManifest, MediaType};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::{Image, ImageError, ImageRef};
+use crate::format::{Result, WireFormatError};
+
+// the media types and annotations cosign uses, so `cosign verify` understands our signatures
+const COSIGN_SIGNATURE_ARTIFACT: &str = "application/vnd.dev.cosign.artifact.sig.v1+json";
+const SIMPLE_SIGNING: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+const SIMPLE_SIGNING_TYPE: &str = "cosign container image signature";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Identity {
+    #[serde(rename = "docker-reference")]
+    docker_reference: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Critical {
+    identity: Identity,
+    image: SignedImage,
+    #[serde(rename = "type")]
+    signature_type: String,
+}
+
+/// The payload of a signature, in the "simple signing" format of cosign.
+#[derive(Debug, Serialize, Deserialize)]
+struct SimpleSigning {
+    critical: Critical,
+    optional: Option<HashMap<String, String>>,
+}
+
+fn signature_error(msg: String) -> WireFormatError {
+    WireFormatError::SignatureError(msg, Backtrace::capture())
+}
+
+impl Image {
+    /// Signs the manifest `image_ref` references in this image with the PEM private key in `key`.
+    /// The signature is stored like cosign does with OCI 1.1 referrers: an artifact manifest
+    /// whose subject is the signed manifest, and whose only layer is the signed payload, with the
+    /// signature in its annotations.
+    pub fn sign(&self, image_ref: &ImageRef, key: &Path) -> Result<Descriptor> {
+        let key = PKey::private_key_from_pem(&fs::read(key)?)?;
+        let tag = image_ref.reference.to_string();
+        let subject = self
+            .find_manifest_descriptor(&tag)?
+            .ok_or_else(|| ImageError::MissingManifest { tag: tag.clone() })?;
+
+        let payload = serde_json::to_vec(&SimpleSigning {
+            critical: Critical {
+                identity: Identity {
+                    docker_reference: image_ref.to_string(),
+                },
+                image: SignedImage {
+                    docker_manifest_digest: subject.digest().to_string(),
+                },
+                signature_type: SIMPLE_SIGNING_TYPE.to_string(),
+            },
+            optional: None,
+        })?;
+        let signature =
+            Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot_to_vec(&payload)?;
+
+        let mut layer = self.write_blob(&payload, MediaType::Other(SIMPLE_SIGNING.to_string()))?;
+        layer.set_annotations(Some(HashMap::from([(
+            SIGNATURE_ANNOTATION.to_string(),
+            STANDARD.encode(signature),
+        )])));
+        info!("signing {tag}");
+        self.attach_artifact(subject, COSIGN_SIGNATURE_ARTIFACT, layer)
+    }
+
+    /// Checks that the manifest of `tag` has a valid signature made with the private key
+    /// matching the PEM public key in `key`, and that its blobs have the digests it was signed
+    /// with, which reads all of them. Returns the descriptor of the verified manifest: `tag` may
+    /// be moved to another manifest afterwards, so the image must be used through its digest.
+    pub fn verify_signature(&self, tag: &str, key: &Path) -> Result<Descriptor> {
+        let key = PKey::public_key_from_pem(&fs::read(key)?)?;
+        let subject =
+            self.find_manifest_descriptor(tag)?
+                .ok_or_else(|| ImageError::MissingManifest {
+                    tag: tag.to_string(),
+                })?;
+
+        for (desc, manifest) in self.find_artifacts(&subject, &[COSIGN_SIGNATURE_ARTIFACT])? {
+            for layer in manifest.layers() {
+                let Some(signature) = layer
+                    .annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+                else {
+                    continue;
+                };
+                let Ok(signature) = STANDARD.decode(signature) else {
+                    debug!("invalid signature encoding in {}", desc.digest());
+                    continue;
+                };
+                let payload = self.0.blobs_dir().read(layer.digest().digest())?;
+                if !Verifier::new(MessageDigest::sha256(), &key)?
+                    .verify_oneshot(&signature, &payload)?
+                {
+                    debug!("signature {} made by another key", desc.digest());
+                    continue;
+                }
+                // the signature is valid, but it must also be about this manifest
+                let payload: SimpleSigning = serde_json::from_slice(&payload)?;
+                if payload.critical.image.docker_manifest_digest != subject.digest().to_string() {
+                    debug!("signature {} is for another manifest", desc.digest());
+                    continue;
+                }
+                self.check_manifest_blobs(&subject)?;
+                return Ok(subject);
+            }
+        }
+
+        Err(signature_error(format!(
+            "no valid signature for {tag} ({})",
+            subject.digest()
+        )))
+    }
+
+    // checks the manifest `desc` and the blobs it references against their digests
+    fn check_manifest_blobs(&self, desc: &Descriptor) -> Result<()> {
+        self.check_blob_digest(desc.digest().digest())?;
+        let manifest: ImageManifest = self.0.read_json_blob(desc)?;
+        self.check_blob_digest(manifest.config().digest().digest())?;
+        for layer in manifest.layers() {
+            self.check_blob_digest(layer.digest().digest())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sign_and_verify() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+
+        let write_key = |name: &str| -> anyhow::Result<_> {
+            let key = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?;
+            let private = dir.path().join(format!("{name}.key"));
+            let public = dir.path().join(format!("{name}.pub"));
+            fs::write(&private, key.private_key_to_pem()?)?;
+            fs::write(&public, key.public_key_to_pem()?)?;
+            Ok((private, public))
+        };
+        let (private, public) = write_key("signer")?;
+        let (_, other_public) = write_key("other")?;
+
+        let image_ref: ImageRef = format!("{}:test", dir.path().join("oci").display()).parse()?;
+        image.verify_signature("test", &public).unwrap_err();
+        image.sign(&image_ref, &private)?;
+        let verified = image.verify_signature("test", &public)?;
+        assert_eq!(
+            verified.digest(),
+            image.find_manifest_descriptor("test")?.unwrap().digest()
+        );
+        image.verify_signature("test", &other_public).unwrap_err();
+
+        // a signature of another manifest by the same key is skipped, not an error
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir(&rootfs)?;
+        fs::write(rootfs.join("file"), b"other")?;
+        build_test_fs(&rootfs, &image, "other")?;
+        image.sign(
+            &format!("{}:other", image_ref.oci_dir.display()).parse()?,
+            &private,
+        )?;
+        image.verify_signature("test", &public)?;
+
+        // a blob which doesn't match the signed digests is refused
+        let manifest = image.find_manifest("test")?.unwrap();
+        let layer = manifest.layers()[0].digest().digest().to_string();
+        let blob = dir.path().join("oci").join(Image::blob_path()).join(layer);
+        let mut contents = fs::read(&blob)?;
+        contents[0] ^= 0xff;
+        fs::remove_file(&blob)?;
+        fs::write(&blob, contents)?;
+        image.verify_signature("test", &public).unwrap_err();
+        Ok(())
+    }
+}
@@ -1,20 +1,69 @@
 extern crate fuser as fuse_ffi;
 
+use std::backtrace::Backtrace;
+use std::net::TcpListener;
 use std::path::Path;
+#[cfg(feature = "async")]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
 
-use crate::format::Result;
+use crate::format::{Result, WireFormatError};
+use crate::metrics;
 use crate::oci::Image;
+use crate::trust_store::TrustStore;
+use thiserror::Error;
+use tracing::{info, warn};
 
+pub mod access_log;
 mod puzzlefs;
-pub use puzzlefs::PuzzleFS;
-pub use puzzlefs::PUZZLEFS_IMAGE_MANIFEST_VERSION;
+pub use puzzlefs::{
+    negotiate_version, version_support, VersionSupport, MANIFEST_VERSIONS,
+    PUZZLEFS_IMAGE_MANIFEST_VERSION,
+};
+pub use puzzlefs::{verify_file, FileReader, PuzzleFS};
 
+pub mod control;
+pub mod fscache;
 pub mod fuse;
-pub use fuse::Fuse;
+#[cfg(feature = "kernel-mount")]
+pub mod kernel;
+pub use fuse::{Fuse, FuseConfig};
 
+pub mod layer_store;
+pub mod mount_state;
+pub mod ninep;
+mod prefetch;
+pub use prefetch::{prefetch, PrefetchStats};
+mod sandbox;
 mod walk;
-use fuse::PipeDescriptor;
-pub use walk::WalkPuzzleFS;
+use control::{ControlSocket, RemountPolicy};
+use fuse::{ImageSwitch, PipeDescriptor};
+pub use walk::{DirEntry, WalkPuzzleFS};
+
+pub use fuse_ffi::BackgroundSession;
+use fuse_ffi::Notifier;
+
+/// Why an image can't be mounted, or a file of a mount can't be served.
+#[derive(Error, Debug)]
+pub enum MountError {
+    /// The manifest of `tag` requires fs-verity checks, and the mount doesn't have its digest.
+    #[error("{tag} must be mounted with the fs-verity digest of its manifest")]
+    VerityRequired { tag: String },
+    /// Some of the stacked tags are mounted with the digest of their manifest, others aren't.
+    #[error("either all the stacked tags or none of them must be verified")]
+    MixedVerity,
+    /// The contents of inode `ino` don't hash to the digest it was built with.
+    #[error("the contents of inode {ino} have digest {found}, expected {expected}")]
+    FileDigestMismatch {
+        ino: u64,
+        expected: String,
+        found: String,
+    },
+    /// The daemon couldn't confine itself.
+    #[error("sandbox error: {what}: {reason}")]
+    Sandbox { what: String, reason: String },
+}
 
 // copied from the fuser function 'MountOption::from_str' because it's not exported
 fn mount_option_from_str(s: &str) -> fuse_ffi::MountOption {
@@ -42,6 +91,118 @@ fn mount_option_from_str(s: &str) -> fuse_ffi::MountOption {
     }
 }
 
+// Other users may only access the mount with `allow_other`, in that case we want the kernel to
+// check their permissions too, rather than rely on whoever mounted the image to remember it.
+// Likewise, the device nodes of the image are only usable when the config allows them.
+fn mount_options<T: AsRef<str>>(options: &[T], config: &FuseConfig) -> Vec<fuse_ffi::MountOption> {
+    let mut options = options
+        .iter()
+        .map(|option| mount_option_from_str(option.as_ref()))
+        .collect::<Vec<_>>();
+    if options.contains(&fuse_ffi::MountOption::AllowOther)
+        && !options.contains(&fuse_ffi::MountOption::DefaultPermissions)
+    {
+        options.push(fuse_ffi::MountOption::DefaultPermissions);
+    }
+    if !config.allow_devices {
+        if options.contains(&fuse_ffi::MountOption::Dev) {
+            warn!("ignoring the dev mount option, the device nodes of the image aren't allowed");
+            options.retain(|option| *option != fuse_ffi::MountOption::Dev);
+        }
+        if !options.contains(&fuse_ffi::MountOption::NoDev) {
+            options.push(fuse_ffi::MountOption::NoDev);
+        }
+    }
+    options
+}
+
+// the mounted tag followed by the tags stacked below it
+fn stacked_tags<'a>(
+    tag: &'a str,
+    manifest_verity: Option<&'a [u8]>,
+    config: &'a FuseConfig,
+) -> Vec<(&'a str, Option<&'a [u8]>)> {
+    let mut tags = vec![(tag, manifest_verity)];
+    tags.extend(
+        config
+            .lower
+            .iter()
+            .map(|(tag, verity)| (tag.as_str(), verity.as_deref())),
+    );
+    tags
+}
+
+fn open_layers(
+    image: Image,
+    tag: &str,
+    manifest_verity: Option<&[u8]>,
+    config: &FuseConfig,
+) -> Result<PuzzleFS> {
+    let tags = stacked_tags(tag, manifest_verity, config);
+    PuzzleFS::open_layers(image, &tags, config.require_verity)
+}
+
+// the manifest a switch to `tag` goes to under the remount policy of the mount, and the fs-verity
+// digest it's opened with; the control socket is open to any process of the user, so the policy
+// the mount was made with is checked again
+fn remount_allowed(
+    image: &Image,
+    tag: &str,
+    manifest_verity: Option<&[u8]>,
+    config: &FuseConfig,
+) -> Result<(String, Option<Vec<u8>>)> {
+    let manifest_verity = manifest_verity.map(<[u8]>::to_vec);
+    match &config.remount_policy {
+        RemountPolicy::Any => Ok((tag.to_string(), manifest_verity)),
+        RemountPolicy::Signed(key) => {
+            let desc = image.verify_signature(tag, key)?;
+            Ok((desc.digest().to_string(), manifest_verity))
+        }
+        RemountPolicy::Trusted(name) => {
+            let trusted = TrustStore::user().resolve(name)?;
+            if manifest_verity.is_some_and(|verity| verity != trusted) {
+                return Err(WireFormatError::TrustError(
+                    format!("the digest isn't the one trusted as {name}"),
+                    Backtrace::capture(),
+                ));
+            }
+            Ok((tag.to_string(), Some(trusted)))
+        }
+    }
+}
+
+// switches the mount to another tag, stacked on the same lower tags; the kernel is told about the
+// files which changed before the switch is committed, so that a failure leaves the mount as it
+// was, and again once the session switched, for the requests it answered from the previous image
+// meanwhile. The control socket serializes the switches, so nothing else switches in between.
+fn switch_tag(
+    switch: &Arc<ImageSwitch>,
+    notifier: &Notifier,
+    tag: &str,
+    manifest_verity: Option<&[u8]>,
+    config: &FuseConfig,
+) -> Result<(Vec<String>, Option<Vec<u8>>)> {
+    let previous = switch.latest();
+    let (tag, manifest_verity) = remount_allowed(&previous.oci, tag, manifest_verity, config)?;
+    let tags = stacked_tags(&tag, manifest_verity.as_deref(), config);
+    let pfs = Arc::new(previous.reopen(&tags, config.require_verity)?);
+    let changes = fuse::image_changes(&previous, &pfs)?;
+    info!("{} files changed", changes.len());
+    fuse::notify_changes(&previous, &changes, notifier)?;
+
+    let served = (pfs.tags.clone(), pfs.manifest_verity.clone());
+    let number = switch.request(pfs);
+    let switch = Arc::clone(switch);
+    let notifier = notifier.clone();
+    thread::spawn(move || {
+        switch.wait(number);
+        if let Err(e) = fuse::notify_changes(&previous, &changes, &notifier) {
+            warn!("cannot tell the kernel about the changed files: {e}");
+        }
+    });
+    Ok(served)
+}
+
 pub fn mount<T: AsRef<str>>(
     image: Image,
     tag: &str,
@@ -49,17 +210,46 @@ pub fn mount<T: AsRef<str>>(
     options: &[T],
     init_notify: Option<PipeDescriptor>,
     manifest_verity: Option<&[u8]>,
+    config: &FuseConfig,
 ) -> Result<()> {
-    let pfs = PuzzleFS::open(image, tag, manifest_verity)?;
-    let fuse = Fuse::new(pfs, None, init_notify);
-    fuse_ffi::mount2(
-        fuse,
-        mountpoint,
-        &options
-            .iter()
-            .map(|option| mount_option_from_str(option.as_ref()))
-            .collect::<Vec<_>>(),
-    )?;
+    // the daemon of an image with a remote needs the network to fetch its blobs
+    let network = image.remote().is_some();
+    let pfs = open_layers(image, tag, manifest_verity, config)?;
+    let mut control = config
+        .control_dir
+        .as_deref()
+        .map(ControlSocket::bind)
+        .transpose()?;
+    let status = control::mount_status(&pfs, mountpoint, config);
+    let fuse = Fuse::new(pfs, None, init_notify, config)?;
+    let switch = fuse.image_switch();
+    let mut session = fuse_ffi::Session::new(fuse, mountpoint, &mount_options(options, config))?;
+    let remounter = {
+        let notifier = session.notifier();
+        let config = config.clone();
+        Box::new(move |tag: &str, manifest_verity: Option<&[u8]>| {
+            switch_tag(&switch, &notifier, tag, manifest_verity, &config)
+        }) as control::Remounter
+    };
+    // once sandboxed, the daemon lives in a mount namespace of its own and can't unmount the
+    // filesystem anymore
+    let unmounter = (!config.sandbox).then(|| {
+        let mut unmounter = session.unmount_callable();
+        Box::new(move || unmounter.unmount()) as control::Unmounter
+    });
+    // the listener is bound before the sandbox moves the daemon to an empty network namespace,
+    // but its thread is spawned afterwards so it's sandboxed too
+    let metrics_listener = config.metrics_addr.map(TcpListener::bind).transpose()?;
+    if config.sandbox {
+        sandbox::enter(config.upper_dir.is_some(), network)?;
+    }
+    if let Some(listener) = metrics_listener {
+        metrics::serve(listener)?;
+    }
+    if let Some(control) = &mut control {
+        control.serve(status, unmounter, Some(remounter));
+    }
+    session.run()?;
     Ok(())
 }
 
@@ -71,15 +261,109 @@ pub fn spawn_mount<T: AsRef<str>>(
     init_notify: Option<PipeDescriptor>,
     sender: Option<std::sync::mpsc::Sender<()>>,
     manifest_verity: Option<&[u8]>,
+    config: &FuseConfig,
 ) -> Result<fuse_ffi::BackgroundSession> {
-    let pfs = PuzzleFS::open(image, tag, manifest_verity)?;
-    let fuse = Fuse::new(pfs, sender, init_notify);
+    let pfs = open_layers(image, tag, manifest_verity, config)?;
+    let fuse = Fuse::new(pfs, sender, init_notify, config)?;
+    if let Some(addr) = config.metrics_addr {
+        metrics::serve(TcpListener::bind(addr)?)?;
+    }
     Ok(fuse_ffi::spawn_mount2(
         fuse,
         mountpoint,
-        &options
-            .iter()
-            .map(|option| mount_option_from_str(option.as_ref()))
-            .collect::<Vec<_>>(),
+        &mount_options(options, config),
     )?)
 }
+
+/// Like [`spawn_mount`], but opens the image and mounts it on tokio's blocking pool, so it can be
+/// awaited from async code. The image is served by a thread of its own, as with `spawn_mount`.
+#[cfg(feature = "async")]
+pub async fn spawn_mount_async(
+    oci_dir: PathBuf,
+    tag: String,
+    mountpoint: PathBuf,
+    options: Vec<String>,
+    manifest_verity: Option<Vec<u8>>,
+    config: FuseConfig,
+) -> Result<BackgroundSession> {
+    tokio::task::spawn_blocking(move || {
+        let image = Image::open(&oci_dir)?;
+        spawn_mount(
+            image,
+            &tag,
+            &mountpoint,
+            &options,
+            None,
+            None,
+            manifest_verity.as_deref(),
+            &config,
+        )
+    })
+    .await
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuse_ffi::MountOption;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    #[test]
+    fn test_mount_options() {
+        let config = FuseConfig::default();
+        assert_eq!(
+            mount_options(&["allow_other", "dev"], &config),
+            [
+                MountOption::AllowOther,
+                MountOption::DefaultPermissions,
+                MountOption::NoDev
+            ]
+        );
+
+        let config = FuseConfig {
+            allow_devices: true,
+            ..Default::default()
+        };
+        assert_eq!(mount_options(&["dev"], &config), [MountOption::Dev]);
+    }
+
+    #[test]
+    fn test_remount_allowed() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        crate::builder::build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let key = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?;
+        let private = dir.path().join("signer.key");
+        let public = dir.path().join("signer.pub");
+        std::fs::write(&private, key.private_key_to_pem()?)?;
+        std::fs::write(&public, key.public_key_to_pem()?)?;
+
+        let config = FuseConfig::default();
+        assert_eq!(
+            remount_allowed(&image, "test", None, &config)?,
+            ("test".to_string(), None)
+        );
+
+        let config = FuseConfig {
+            remount_policy: RemountPolicy::Signed(public),
+            ..Default::default()
+        };
+        remount_allowed(&image, "test", None, &config).unwrap_err();
+        image.sign(
+            &format!("{}:test", dir.path().join("oci").display()).parse()?,
+            &private,
+        )?;
+        let digest = image
+            .find_manifest_descriptor("test")?
+            .unwrap()
+            .digest()
+            .to_string();
+        assert_eq!(
+            remount_allowed(&image, "test", None, &config)?,
+            (digest, None)
+        );
+        Ok(())
+    }
+}
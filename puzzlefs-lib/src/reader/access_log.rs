@@ -0,0 +1,81 @@
+//! Records which chunks a mount reads, to build the image again with them as landmarks.
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::format::{Inode, InodeMode, Result, SHA256_BLOCK_SIZE};
+use crate::oci::Digest;
+
+pub(crate) struct AccessLog {
+    file: fs::File,
+    seen: HashSet<[u8; SHA256_BLOCK_SIZE]>,
+}
+
+impl AccessLog {
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        Ok(AccessLog {
+            file: fs::File::create(path)?,
+            seen: HashSet::new(),
+        })
+    }
+
+    /// Records the chunks of `inode` which hold the `len` bytes at `offset`.
+    pub(crate) fn record(&mut self, inode: &Inode, offset: u64, len: u64) -> io::Result<()> {
+        let InodeMode::File { chunks, .. } = &inode.mode else {
+            return Ok(());
+        };
+        let end = offset + len;
+        let mut start = 0;
+        for chunk in chunks {
+            if start >= end {
+                break;
+            }
+            if start + chunk.len > offset && self.seen.insert(chunk.blob.digest) {
+                writeln!(self.file, "{}", Digest::new(&chunk.blob.digest))?;
+            }
+            start += chunk.len;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the digests of an access log, in the order the chunks were read.
+pub fn read_access_log(path: &Path) -> Result<Vec<String>> {
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(Digest::try_from(line)?.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::oci::Image;
+    use crate::reader::PuzzleFS;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_access_log() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("image"))?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let inode = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        let InodeMode::File { chunks, .. } = &inode.mode else {
+            panic!("not a file");
+        };
+        let digest = |i: usize| Digest::new(&chunks[i].blob.digest).to_string();
+
+        let path = dir.path().join("access.log");
+        let mut log = AccessLog::create(&path)?;
+        // the last byte of the second chunk, then the start of the file twice
+        log.record(&inode, chunks[0].len + chunks[1].len - 1, 1)?;
+        log.record(&inode, 0, 10)?;
+        log.record(&inode, 0, chunks[0].len + 1)?;
+        assert_eq!(read_access_log(&path)?, [digest(1), digest(0)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,461 @@
+//! The control socket of a background mount, which answers one line of JSON per command.
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::FuseConfig;
+use crate::format::{Result, WireFormatError};
+use crate::metrics::METRICS;
+
+type LogLevelHandler = Box<dyn Fn(&str) -> std::result::Result<(), String> + Send + Sync>;
+
+static LOG_LEVEL_HANDLER: OnceLock<LogLevelHandler> = OnceLock::new();
+
+/// Lets the `log-level` command change the log filter of the process; puzzlefs-lib doesn't
+/// install a subscriber itself, so it's up to the caller to apply the filter.
+pub fn set_log_level_handler(
+    handler: impl Fn(&str) -> std::result::Result<(), String> + Send + Sync + 'static,
+) {
+    if LOG_LEVEL_HANDLER.set(Box::new(handler)).is_err() {
+        warn!("the log level handler is already set");
+    }
+}
+
+/// Where the control sockets live: `$XDG_RUNTIME_DIR/puzzlefs`, or `/run/puzzlefs` for root
+/// without `XDG_RUNTIME_DIR`. Other users fall back to `puzzlefs-<uid>` in the temporary
+/// directory, which anyone could have created first: it's only used if it belongs to the
+/// effective user, with mode 0700.
+pub fn socket_dir() -> Result<PathBuf> {
+    let uid = Uid::effective();
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => return Ok(Path::new(&dir).join("puzzlefs")),
+        None if uid.is_root() => return Ok(PathBuf::from("/run/puzzlefs")),
+        None => (),
+    }
+    let dir = std::env::temp_dir().join(format!("puzzlefs-{uid}"));
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => (),
+    }
+    let md = fs::symlink_metadata(&dir)?;
+    if !md.is_dir() || md.uid() != uid.as_raw() || md.mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must be a directory of uid {uid} with mode 0700, set XDG_RUNTIME_DIR instead",
+                dir.display()
+            ),
+        )
+        .into());
+    }
+    Ok(dir)
+}
+
+/// What a daemon reports about its mount.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MountStatus {
+    pub pid: u32,
+    pub mountpoint: PathBuf,
+    /// The mounted tag, followed by the tags stacked below it.
+    pub tags: Vec<String>,
+    pub manifest_verity: Option<String>,
+    pub upper_dir: Option<PathBuf>,
+    /// The overlay making the mount writable, when there's one on top of it.
+    #[serde(default)]
+    pub overlay: Option<OverlayMount>,
+    pub sandboxed: bool,
+    /// Seconds since the epoch.
+    pub started_at: u64,
+}
+
+impl MountStatus {
+    /// Whether the files of `mountpoint` come from this mount, directly or through its overlay.
+    pub fn serves(&self, mountpoint: &Path) -> bool {
+        self.mountpoint == mountpoint
+            || self
+                .overlay
+                .as_ref()
+                .is_some_and(|overlay| overlay.mountpoint == mountpoint)
+    }
+
+    /// The directory holding the changes made to the mount, if it's writable and the directory
+    /// can be reached: the overlay hides an upper directory inside its own mountpoint.
+    pub fn changes_dir(&self) -> Option<&Path> {
+        self.upper_dir.as_deref().or_else(|| {
+            self.overlay
+                .as_ref()
+                .filter(|overlay| !overlay.upper_dir.starts_with(&overlay.mountpoint))
+                .map(|overlay| overlay.upper_dir.as_path())
+        })
+    }
+}
+
+/// An overlay mounted on top of a puzzlefs mount, with the puzzlefs mount as its lower
+/// directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlayMount {
+    pub mountpoint: PathBuf,
+    pub upper_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(MountStatus),
+    /// The metrics of the daemon, in the Prometheus text format.
+    Stats(String),
+    Error(String),
+}
+
+/// Which manifests `remount-tag` may switch a mount to: the same ones it could be mounted with.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum RemountPolicy {
+    /// Any tag of the image.
+    #[default]
+    Any,
+    /// Only manifests with a valid signature made with the private key matching this PEM public
+    /// key, see [`Image::verify_signature`](crate::oci::Image::verify_signature). The mount is
+    /// switched to the verified manifest by digest.
+    Signed(PathBuf),
+    /// Only the manifest whose fs-verity digest is trusted under this name when the switch is
+    /// requested, see [`TrustStore`](crate::trust_store::TrustStore).
+    Trusted(String),
+}
+
+/// Unmounts the filesystem served by the daemon, which ends its FUSE session.
+pub(crate) type Unmounter = Box<dyn FnMut() -> io::Result<()> + Send>;
+
+/// Switches the mount to another tag of its image, with the fs-verity digest of its manifest,
+/// returning the tags it serves from then on and the digest they were opened with.
+pub(crate) type Remounter =
+    Box<dyn FnMut(&str, Option<&[u8]>) -> Result<(Vec<String>, Option<Vec<u8>>)> + Send>;
+
+// what the commands act on, shared by the clients of the socket
+struct Daemon {
+    status: MountStatus,
+    unmounter: Option<Unmounter>,
+    remounter: Option<Remounter>,
+}
+
+/// A bound control socket, removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: Option<UnixListener>,
+}
+
+impl ControlSocket {
+    pub(crate) fn bind(dir: &Path) -> Result<Self> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let path = dir.join(format!("{}.sock", std::process::id()));
+        // a leftover of a daemon which had our pid and was killed
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        Ok(ControlSocket {
+            path,
+            listener: Some(listener),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Answers requests from a thread of its own.
+    pub(crate) fn serve(
+        &mut self,
+        status: MountStatus,
+        unmounter: Option<Unmounter>,
+        remounter: Option<Remounter>,
+    ) {
+        let Some(listener) = self.listener.take() else {
+            return;
+        };
+        info!("control socket listening on {}", self.path.display());
+        let daemon = Arc::new(Mutex::new(Daemon {
+            status,
+            unmounter,
+            remounter,
+        }));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| serve_client(stream, &daemon));
+                if let Err(e) = result {
+                    warn!("control socket error: {e}");
+                }
+            }
+        });
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn handle(command: &str, daemon: &mut Daemon) -> Response {
+    let (command, arg) = command
+        .split_once(' ')
+        .map_or((command, None), |(command, arg)| {
+            (command, Some(arg.trim()))
+        });
+    match (command, arg) {
+        ("status", None) => Response::Status(daemon.status.clone()),
+        ("stats", None) => Response::Stats(METRICS.render()),
+        ("log-level", Some(filter)) => match LOG_LEVEL_HANDLER.get() {
+            Some(handler) => match handler(filter) {
+                Ok(()) => {
+                    info!("log level changed to {filter}");
+                    Response::Ok
+                }
+                Err(e) => Response::Error(e),
+            },
+            None => Response::Error("the log level can't be changed".to_string()),
+        },
+        ("remount-tag", Some(arg)) => {
+            let (tag, digest) = arg
+                .split_once(' ')
+                .map_or((arg, None), |(tag, digest)| (tag, Some(digest.trim())));
+            remount_tag(daemon, tag, digest)
+        }
+        ("shutdown", None) => match daemon.unmounter.as_mut() {
+            Some(unmount) => match unmount() {
+                Ok(()) => {
+                    info!("shutting down on request");
+                    Response::Ok
+                }
+                Err(e) => Response::Error(format!("cannot unmount: {e}")),
+            },
+            None => Response::Error(
+                "the daemon is sandboxed and cannot unmount itself, unmount the mountpoint instead"
+                    .to_string(),
+            ),
+        },
+        _ => Response::Error(format!("unknown command {command}")),
+    }
+}
+
+fn remount_tag(daemon: &mut Daemon, tag: &str, digest: Option<&str>) -> Response {
+    // the upper layer and the overlay keep the inodes of the image they shadow
+    if daemon.status.upper_dir.is_some() || daemon.status.overlay.is_some() {
+        return Response::Error("a writable mount can't switch to another tag".to_string());
+    }
+    let Some(remount) = daemon.remounter.as_mut() else {
+        return Response::Error("the daemon can't switch to another tag".to_string());
+    };
+    let manifest_verity = match digest.map(hex::decode).transpose() {
+        Ok(manifest_verity) => manifest_verity,
+        Err(e) => return Response::Error(format!("invalid digest: {e}")),
+    };
+    match remount(tag, manifest_verity.as_deref()) {
+        Ok((tags, manifest_verity)) => {
+            info!("switched to {tag} on request");
+            daemon.status.tags = tags;
+            daemon.status.manifest_verity = manifest_verity.as_ref().map(hex::encode);
+            Response::Ok
+        }
+        Err(e) => Response::Error(format!("cannot switch to {tag}: {e}")),
+    }
+}
+
+fn serve_client(stream: UnixStream, daemon: &Mutex<Daemon>) -> io::Result<()> {
+    let mut writer = &stream;
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        let response = handle(line.trim(), &mut daemon.lock().unwrap());
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn mount_status(
+    pfs: &super::PuzzleFS,
+    mountpoint: &Path,
+    config: &FuseConfig,
+) -> MountStatus {
+    MountStatus {
+        pid: std::process::id(),
+        mountpoint: mountpoint.to_path_buf(),
+        tags: pfs.tags.clone(),
+        manifest_verity: pfs.manifest_verity.as_ref().map(hex::encode),
+        upper_dir: config.upper_dir.clone(),
+        overlay: config.overlay.clone(),
+        sandboxed: config.sandbox,
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    }
+}
+
+/// Sends `command` to the daemon listening on `socket`.
+pub fn request(socket: &Path, command: &str) -> Result<Response> {
+    let stream = UnixStream::connect(socket)?;
+    let mut writer = &stream;
+    writer.write_all(command.as_bytes())?;
+    writer.write_all(b"\n")?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// The status of the background mounts with a control socket in `dir`, along with their sockets.
+/// The sockets left behind by daemons which are gone are removed.
+pub fn list_mounts(dir: &Path) -> Result<Vec<(PathBuf, MountStatus)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut mounts = Vec::new();
+    for entry in entries {
+        let socket = entry?.path();
+        if socket.extension().map_or(true, |ext| ext != "sock") {
+            continue;
+        }
+        match request(&socket, "status") {
+            Ok(Response::Status(status)) => mounts.push((socket, status)),
+            Ok(response) => warn!("unexpected response {response:?} from {}", socket.display()),
+            Err(WireFormatError::IOError(e, _))
+                if e.raw_os_error() == Some(Errno::ECONNREFUSED as i32) =>
+            {
+                let _ = fs::remove_file(&socket);
+            }
+            Err(e) => warn!("cannot query {}: {e}", socket.display()),
+        }
+    }
+    mounts.sort_by(|(_, a), (_, b)| a.mountpoint.cmp(&b.mountpoint));
+    Ok(mounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_control_socket() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let status = MountStatus {
+            pid: std::process::id(),
+            mountpoint: PathBuf::from("/mnt"),
+            tags: vec!["test".to_string()],
+            manifest_verity: None,
+            upper_dir: None,
+            overlay: Some(OverlayMount {
+                mountpoint: PathBuf::from("/mnt/rw"),
+                upper_dir: PathBuf::from("/var/upper"),
+            }),
+            sandboxed: true,
+            started_at: 0,
+        };
+        assert!(status.serves(Path::new("/mnt")) && status.serves(Path::new("/mnt/rw")));
+        assert_eq!(status.changes_dir(), Some(Path::new("/var/upper")));
+        let hidden = MountStatus {
+            overlay: Some(OverlayMount {
+                mountpoint: PathBuf::from("/mnt/rw"),
+                upper_dir: PathBuf::from("/mnt/rw/upper"),
+            }),
+            ..status.clone()
+        };
+        assert_eq!(hidden.changes_dir(), None);
+        let mut socket = ControlSocket::bind(dir.path())?;
+        socket.serve(status.clone(), None, None);
+
+        assert_eq!(
+            list_mounts(dir.path())?,
+            vec![(socket.path().to_path_buf(), status)]
+        );
+        assert!(matches!(
+            request(socket.path(), "stats")?,
+            Response::Stats(stats) if stats.contains("puzzlefs_open_handles")
+        ));
+        assert!(matches!(
+            request(socket.path(), "shutdown")?,
+            Response::Error(_)
+        ));
+        assert!(matches!(
+            request(socket.path(), "frobnicate")?,
+            Response::Error(_)
+        ));
+        // the overlay shadows the inodes of the mounted tag
+        assert!(matches!(
+            request(socket.path(), "remount-tag v2")?,
+            Response::Error(_)
+        ));
+
+        let path = socket.path().to_path_buf();
+        drop(socket);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remount_tag() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let status = MountStatus {
+            pid: std::process::id(),
+            mountpoint: PathBuf::from("/mnt"),
+            tags: vec!["v1".to_string(), "base".to_string()],
+            manifest_verity: None,
+            upper_dir: None,
+            overlay: None,
+            sandboxed: false,
+            started_at: 0,
+        };
+        let mut socket = ControlSocket::bind(dir.path())?;
+        let remounts = Arc::new(Mutex::new(Vec::new()));
+        let remounter = {
+            let remounts = Arc::clone(&remounts);
+            Box::new(
+                move |tag: &str, verity: Option<&[u8]>| -> Result<(Vec<String>, Option<Vec<u8>>)> {
+                    let verity = verity.map(<[u8]>::to_vec);
+                    remounts
+                        .lock()
+                        .unwrap()
+                        .push((tag.to_string(), verity.clone()));
+                    Ok((vec![tag.to_string(), "base".to_string()], verity))
+                },
+            ) as Remounter
+        };
+        socket.serve(status.clone(), None, Some(remounter));
+
+        assert!(matches!(
+            request(socket.path(), "remount-tag v2 xyz")?,
+            Response::Error(_)
+        ));
+        assert_eq!(request(socket.path(), "remount-tag v2 00ff")?, Response::Ok);
+        assert_eq!(
+            request(socket.path(), "status")?,
+            Response::Status(MountStatus {
+                tags: vec!["v2".to_string(), "base".to_string()],
+                manifest_verity: Some("00ff".to_string()),
+                ..status
+            })
+        );
+        assert_eq!(
+            *remounts.lock().unwrap(),
+            vec![("v2".to_string(), Some(vec![0, 0xff]))]
+        );
+        Ok(())
+    }
+}
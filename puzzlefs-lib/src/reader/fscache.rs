@@ -0,0 +1,210 @@
+//! Serves an image to the EROFS driver through the on-demand mode of cachefiles.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use tracing::{debug, info, warn};
+
+use crate::format::Result;
+use crate::reader::PuzzleFS;
+
+mod erofs;
+use erofs::ErofsImage;
+
+const DEVICE: &str = "/dev/cachefiles";
+// the tag of the device slot of the data blob, which is also its cookie key
+const DATA_TAG: &str = "puzzlefs-data";
+
+// from linux/cachefiles.h
+const MSG_MAX_SIZE: usize = 1024;
+const MSG_HEADER_SIZE: usize = 16;
+const OP_OPEN: u32 = 0;
+const OP_CLOSE: u32 = 1;
+const OP_READ: u32 = 2;
+// _IOW(0x98, 1, int)
+const IOC_READ_COMPLETE: libc::Ioctl = 0x4004_9801;
+
+#[derive(Clone, Copy, Debug)]
+enum Blob {
+    Metadata,
+    Data,
+}
+
+struct Object {
+    // the anonymous fd of the cache file, which the daemon writes the requested data to
+    file: File,
+    blob: Blob,
+}
+
+/// A cachefiles daemon serving one image.
+pub struct FscacheDaemon {
+    pfs: PuzzleFS,
+    erofs: ErofsImage,
+    fsid: String,
+    dev: File,
+    objects: HashMap<u32, Object>,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+impl FscacheDaemon {
+    /// Renders the image and binds a cache in `cache_dir` in on-demand mode; the image is then
+    /// mounted with `fsid`.
+    pub fn bind(pfs: PuzzleFS, fsid: &str, cache_dir: &Path) -> Result<Self> {
+        if fsid.is_empty() || fsid.contains([',', '\0']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid fsid {fsid}"),
+            )
+            .into());
+        }
+        let erofs = ErofsImage::build(&pfs, DATA_TAG)?;
+        fs::create_dir_all(cache_dir)?;
+        let mut dev = OpenOptions::new().read(true).write(true).open(DEVICE)?;
+        for command in [
+            format!("dir {}", fs::canonicalize(cache_dir)?.display()),
+            "tag puzzlefs".to_string(),
+            "bind ondemand".to_string(),
+        ] {
+            dev.write_all(command.as_bytes())?;
+        }
+        info!(
+            "serving {} bytes of metadata and {} bytes of data as {fsid}",
+            erofs.metadata.len(),
+            erofs.data_size
+        );
+        Ok(FscacheDaemon {
+            pfs,
+            erofs,
+            fsid: fsid.to_string(),
+            dev,
+            objects: HashMap::new(),
+        })
+    }
+
+    /// Answers the requests of the kernel, until the cache device fails.
+    pub fn run(&mut self) -> Result<()> {
+        let mut buf = [0; MSG_MAX_SIZE];
+        loop {
+            match poll(&mut [PollFd::new(&self.dev, PollFlags::POLLIN)], -1) {
+                Err(Errno::EINTR) => continue,
+                result => result.map_err(io::Error::from)?,
+            };
+            let len = match self.dev.read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            };
+            // no request after all
+            if len < MSG_HEADER_SIZE {
+                continue;
+            }
+            let (msg_id, opcode, object_id) = (u32_at(&buf, 0), u32_at(&buf, 4), u32_at(&buf, 12));
+            let payload = &buf[MSG_HEADER_SIZE..len];
+            match opcode {
+                OP_OPEN => self.open(msg_id, object_id, payload)?,
+                OP_CLOSE => {
+                    self.objects.remove(&object_id);
+                }
+                OP_READ => self.read(msg_id, object_id, payload),
+                _ => warn!("unknown cachefiles request {opcode}"),
+            }
+        }
+    }
+
+    fn open(&mut self, msg_id: u32, object_id: u32, payload: &[u8]) -> Result<()> {
+        let volume_key_size = u32_at(payload, 0) as usize;
+        let cookie_key_size = u32_at(payload, 4) as usize;
+        // SAFETY: the kernel installed the fd for us, closing it tells the kernel we're done with
+        // the object
+        let file = unsafe { File::from_raw_fd(u32_at(payload, 8) as i32) };
+        let keys = &payload[16..];
+        let volume_key = &keys[..volume_key_size];
+        let cookie_key = &keys[volume_key_size..volume_key_size + cookie_key_size];
+
+        let volume = format!("erofs,{}\0", self.fsid);
+        let blob = match cookie_key {
+            _ if volume_key != volume.as_bytes() => None,
+            key if key == self.fsid.as_bytes() => Some(Blob::Metadata),
+            key if key == DATA_TAG.as_bytes() => Some(Blob::Data),
+            _ => None,
+        };
+        let size = match blob {
+            Some(Blob::Metadata) => self.erofs.metadata.len() as i64,
+            Some(Blob::Data) => self.erofs.data_size as i64,
+            None => {
+                debug!(
+                    "unknown cookie {} in volume {}",
+                    String::from_utf8_lossy(cookie_key),
+                    String::from_utf8_lossy(volume_key)
+                );
+                -(Errno::ENOENT as i64)
+            }
+        };
+        self.dev
+            .write_all(format!("copen {msg_id},{size}").as_bytes())?;
+        if let Some(blob) = blob {
+            self.objects.insert(object_id, Object { file, blob });
+        }
+        Ok(())
+    }
+
+    // the kernel waits for the request to complete, even if the data can't be read
+    fn read(&self, msg_id: u32, object_id: u32, payload: &[u8]) {
+        let Some(object) = self.objects.get(&object_id) else {
+            warn!("read of unknown object {object_id}");
+            return;
+        };
+        let (offset, len) = (u64_at(payload, 0), u64_at(payload, 8));
+        if let Err(e) = self.fill(object, offset, len) {
+            warn!(
+                "cannot read {len} bytes at {offset} of {:?}: {e}",
+                object.blob
+            );
+        }
+        // SAFETY: the argument of the ioctl is an int, passed by value
+        if unsafe {
+            libc::ioctl(
+                object.file.as_fd().as_raw_fd(),
+                IOC_READ_COMPLETE,
+                msg_id as libc::c_int,
+            )
+        } < 0
+        {
+            warn!(
+                "cannot complete read {msg_id}: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    fn fill(&self, object: &Object, offset: u64, len: u64) -> Result<()> {
+        let data = match object.blob {
+            Blob::Metadata => {
+                let start = (offset as usize).min(self.erofs.metadata.len());
+                let end = (start + len as usize).min(self.erofs.metadata.len());
+                self.erofs.metadata[start..end].to_vec()
+            }
+            Blob::Data => {
+                let len = len.min(self.erofs.data_size.saturating_sub(offset));
+                let mut buf = vec![0; len as usize];
+                self.erofs
+                    .read_data(&self.pfs.oci, &self.pfs.verity_data, offset, &mut buf)?;
+                buf
+            }
+        };
+        object.file.write_all_at(&data, offset)?;
+        Ok(())
+    }
+}
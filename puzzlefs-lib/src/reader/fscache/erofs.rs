@@ -0,0 +1,515 @@
+//! Renders an image as an EROFS filesystem, whose data blob the daemon provides.
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use nix::sys::stat::SFlag;
+
+use crate::format::{Inode, InodeMode, Result, VerityData};
+use crate::oci::Image;
+use crate::reader::puzzlefs::{file_read, PuzzleFS};
+
+const BLOCK_BITS: u8 = 12;
+const BLOCK_SIZE: u64 = 1 << BLOCK_BITS;
+const SUPER_OFFSET: usize = 1024;
+const SUPER_SIZE: usize = 128;
+const MAGIC: u32 = 0xE0F5_E1E2;
+const FEATURE_INCOMPAT_CHUNKED_FILE: u32 = 0x4;
+const FEATURE_INCOMPAT_DEVICE_TABLE: u32 = 0x8;
+// the device table follows the superblock
+const DEVICE_SLOT_SIZE: usize = 128;
+const DEVICE_TABLE_OFFSET: usize = SUPER_OFFSET + SUPER_SIZE;
+const DEVICE_TAG_SIZE: usize = 64;
+
+const INODE_SIZE: u64 = 64;
+const INODE_SLOT_SIZE: u64 = 32;
+const LAYOUT_FLAT_PLAIN: u16 = 0;
+const LAYOUT_FLAT_INLINE: u16 = 2;
+const LAYOUT_CHUNK_BASED: u16 = 4;
+// chunks of 1MiB, which map to the data blob with 8 byte indexes
+const CHUNK_BITS: u16 = 8;
+const CHUNK_FORMAT_INDEXES: u16 = 0x20;
+const CHUNK_INDEX_SIZE: u64 = 8;
+const DIRENT_SIZE: usize = 12;
+const XATTR_HEADER_SIZE: usize = 12;
+
+// the xattr prefixes EROFS knows, the other xattrs can't be stored
+const XATTR_PREFIXES: &[(&[u8], u8)] = &[
+    (b"system.posix_acl_access", 2),
+    (b"system.posix_acl_default", 3),
+    (b"user.", 1),
+    (b"trusted.", 4),
+    (b"security.", 6),
+];
+
+fn align_up(n: u64, alignment: u64) -> u64 {
+    n.div_ceil(alignment) * alignment
+}
+
+fn file_type(inode: &Inode) -> (SFlag, u8) {
+    match inode.mode {
+        InodeMode::File { .. } => (SFlag::S_IFREG, 1),
+        InodeMode::Dir { .. } => (SFlag::S_IFDIR, 2),
+        InodeMode::Chr { .. } => (SFlag::S_IFCHR, 3),
+        InodeMode::Blk { .. } => (SFlag::S_IFBLK, 4),
+        InodeMode::Fifo => (SFlag::S_IFIFO, 5),
+        InodeMode::Sock => (SFlag::S_IFSOCK, 6),
+        _ => (SFlag::S_IFLNK, 7),
+    }
+}
+
+// the inline xattrs of an inode: a header, then the entries aligned to 4 bytes
+fn encode_xattrs(inode: &Inode) -> Vec<u8> {
+    let Some(additional) = &inode.additional else {
+        return Vec::new();
+    };
+    let mut buf = Vec::new();
+    for xattr in &additional.xattrs {
+        let Some((suffix, index)) = XATTR_PREFIXES
+            .iter()
+            .find_map(|(prefix, index)| Some((xattr.key.strip_prefix(*prefix)?, *index)))
+        else {
+            continue;
+        };
+        let (Ok(name_len), Ok(value_size)) =
+            (u8::try_from(suffix.len()), u16::try_from(xattr.val.len()))
+        else {
+            continue;
+        };
+        buf.push(name_len);
+        buf.push(index);
+        buf.extend_from_slice(&value_size.to_le_bytes());
+        buf.extend_from_slice(suffix);
+        buf.extend_from_slice(&xattr.val);
+        buf.resize(align_up(buf.len() as u64, 4) as usize, 0);
+    }
+    if !buf.is_empty() {
+        buf.splice(0..0, [0; XATTR_HEADER_SIZE]);
+    }
+    buf
+}
+
+// the blocks of a directory, each with its dirents then their names; the last one is truncated
+fn encode_dir(entries: &[(&[u8], u64, u8)]) -> Vec<u8> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    while start < entries.len() {
+        let mut end = start;
+        let mut used = 0;
+        while end < entries.len()
+            && used + DIRENT_SIZE + entries[end].0.len() <= BLOCK_SIZE as usize
+        {
+            used += DIRENT_SIZE + entries[end].0.len();
+            end += 1;
+        }
+        let block = &entries[start..end];
+        let mut names = Vec::new();
+        let block_start = blocks.len();
+        let mut name_offset = block.len() * DIRENT_SIZE;
+        for (name, nid, file_type) in block {
+            blocks.extend_from_slice(&nid.to_le_bytes());
+            blocks.extend_from_slice(&(name_offset as u16).to_le_bytes());
+            blocks.push(*file_type);
+            blocks.push(0);
+            names.extend_from_slice(name);
+            name_offset += name.len();
+        }
+        blocks.extend_from_slice(&names);
+        if end < entries.len() {
+            blocks.resize(block_start + BLOCK_SIZE as usize, 0);
+        }
+        start = end;
+    }
+    blocks
+}
+
+struct Node {
+    inode: Inode,
+    nlink: u32,
+    parent: usize,
+    // the entries of a directory, with the indexes of their nodes
+    entries: Vec<(Vec<u8>, usize)>,
+    xattrs: Vec<u8>,
+    nid: u64,
+    // the start of the file on the data blob, or of the directory on the primary device, in
+    // blocks
+    block: u64,
+}
+
+impl Node {
+    fn size(&self) -> u64 {
+        match &self.inode.mode {
+            InodeMode::File { .. } => self.inode.file_len().unwrap_or(0),
+            // the size of a directory doesn't depend on the nids of its entries
+            InodeMode::Dir { .. } => self.encode_dir(&[]).len() as u64,
+            InodeMode::Lnk => self.symlink_target().len() as u64,
+            _ => 0,
+        }
+    }
+
+    fn symlink_target(&self) -> &[u8] {
+        self.inode
+            .additional
+            .as_ref()
+            .and_then(|additional| additional.symlink_target.as_deref())
+            .unwrap_or_default()
+    }
+
+    // with the entries sorted by name, "." and ".." included
+    fn encode_dir(&self, nodes: &[Node]) -> Vec<u8> {
+        let entry = |name: &'static [u8], index: usize| (name.to_vec(), index);
+        let mut entries = [entry(b".", usize::MAX), entry(b"..", self.parent)]
+            .into_iter()
+            .chain(self.entries.iter().cloned())
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let entries = entries
+            .iter()
+            .map(|(name, index)| {
+                let (nid, file_type) = match nodes.get(*index) {
+                    Some(node) => (node.nid, file_type(&node.inode).1),
+                    None if *index == usize::MAX => (self.nid, 2),
+                    // while sizing the directory
+                    None => (0, 0),
+                };
+                (name.as_slice(), nid, file_type)
+            })
+            .collect::<Vec<_>>();
+        encode_dir(&entries)
+    }
+
+    // the layout of the inode and the size of what follows it and its xattrs
+    fn layout(&self) -> (u16, u64) {
+        let size = self.size();
+        match self.inode.mode {
+            InodeMode::File { .. } if size > 0 => {
+                let chunks = size.div_ceil(BLOCK_SIZE << CHUNK_BITS);
+                (LAYOUT_CHUNK_BASED, chunks * CHUNK_INDEX_SIZE)
+            }
+            InodeMode::Lnk => (LAYOUT_FLAT_INLINE, size),
+            InodeMode::Dir { .. }
+                if INODE_SIZE + (self.xattrs.len() as u64) + size < BLOCK_SIZE =>
+            {
+                (LAYOUT_FLAT_INLINE, size)
+            }
+            _ => (LAYOUT_FLAT_PLAIN, 0),
+        }
+    }
+}
+
+/// An image rendered as EROFS: its metadata, and where its files are on the data blob.
+pub(crate) struct ErofsImage {
+    pub(crate) metadata: Vec<u8>,
+    pub(crate) data_size: u64,
+    // the regular files with data, by their first block on the data blob
+    files: Vec<(u64, Inode)>,
+}
+
+impl ErofsImage {
+    /// Renders the image; `data_tag` names the data blob for the kernel.
+    pub(crate) fn build(pfs: &PuzzleFS, data_tag: &str) -> Result<Self> {
+        // breadth first, the root directory needs one of the smallest nids
+        let root = pfs.find_inode(1)?;
+        let mut seen = HashSet::from([root.ino]);
+        let mut queue = VecDeque::from([(root, 0)]);
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut children = Vec::new();
+        let mut indexes = HashMap::new();
+        while let Some((inode, parent)) = queue.pop_front() {
+            let index = nodes.len();
+            indexes.insert(inode.ino, index);
+            let mut entries = Vec::new();
+            if let InodeMode::Dir { dir_list } = &inode.mode {
+                for entry in &dir_list.entries {
+                    let child = pfs.find_inode(entry.ino)?;
+                    if matches!(child.mode, InodeMode::Wht | InodeMode::Unknown) {
+                        continue;
+                    }
+                    entries.push((entry.name.clone(), child.ino));
+                    // hard links show up several times
+                    if seen.insert(child.ino) {
+                        queue.push_back((child, index));
+                    }
+                }
+            }
+            children.push(entries);
+            nodes.push(Node {
+                xattrs: encode_xattrs(&inode),
+                inode,
+                nlink: 0,
+                parent,
+                entries: Vec::new(),
+                nid: 0,
+                block: 0,
+            });
+        }
+        for (index, entries) in children.into_iter().enumerate() {
+            let mut subdirs = 0;
+            for (name, ino) in entries {
+                let child = indexes[&ino];
+                if matches!(nodes[child].inode.mode, InodeMode::Dir { .. }) {
+                    subdirs += 1;
+                } else {
+                    nodes[child].nlink += 1;
+                }
+                nodes[index].entries.push((name, child));
+            }
+            if matches!(nodes[index].inode.mode, InodeMode::Dir { .. }) {
+                nodes[index].nlink = 2 + subdirs;
+            }
+        }
+
+        // the inodes start on the second block, after the superblock and the device table
+        let mut offset = BLOCK_SIZE;
+        for node in &mut nodes {
+            let inode_size = INODE_SIZE + node.xattrs.len() as u64;
+            let (layout, mut tail) = node.layout();
+            if layout == LAYOUT_CHUNK_BASED {
+                tail += align_up(inode_size, CHUNK_INDEX_SIZE) - inode_size;
+            }
+            // neither the inode nor its inline data may cross a block boundary
+            let end = offset % BLOCK_SIZE + inode_size;
+            if end > BLOCK_SIZE || (layout == LAYOUT_FLAT_INLINE && end + tail > BLOCK_SIZE) {
+                offset = align_up(offset, BLOCK_SIZE);
+            }
+            node.nid = offset / INODE_SLOT_SIZE;
+            offset = align_up(offset + inode_size + tail, INODE_SLOT_SIZE);
+        }
+        offset = align_up(offset, BLOCK_SIZE);
+        let mut data_blocks = 0;
+        for node in &mut nodes {
+            let size = node.size();
+            match (node.layout().0, &node.inode.mode) {
+                (LAYOUT_CHUNK_BASED, _) => {
+                    node.block = data_blocks;
+                    data_blocks += size.div_ceil(BLOCK_SIZE);
+                }
+                (LAYOUT_FLAT_PLAIN, InodeMode::Dir { .. }) => {
+                    node.block = offset / BLOCK_SIZE;
+                    offset += align_up(size, BLOCK_SIZE);
+                }
+                _ => (),
+            }
+        }
+
+        let mut metadata = vec![0; offset as usize];
+        for node in &nodes {
+            let (layout, _) = node.layout();
+            let (file_type, _) = file_type(&node.inode);
+            let size = node.size();
+            let i_u = match (&node.inode.mode, layout) {
+                (_, LAYOUT_CHUNK_BASED) => u32::from(CHUNK_BITS | CHUNK_FORMAT_INDEXES),
+                (InodeMode::Dir { .. }, LAYOUT_FLAT_PLAIN) => node.block as u32,
+                (InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor }, _) => {
+                    // the kernel's new_encode_dev()
+                    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+                }
+                _ => 0,
+            };
+            let xattr_count = match node.xattrs.len() {
+                0 => 0,
+                len => (len - XATTR_HEADER_SIZE) / 4 + 1,
+            };
+
+            let start = (node.nid * INODE_SLOT_SIZE) as usize;
+            let mut inode = Vec::with_capacity(INODE_SIZE as usize);
+            inode.extend_from_slice(&((layout << 1) | 1).to_le_bytes());
+            inode.extend_from_slice(&(xattr_count as u16).to_le_bytes());
+            inode.extend_from_slice(
+                &(file_type.bits() as u16 | node.inode.permissions).to_le_bytes(),
+            );
+            inode.extend_from_slice(&[0; 2]);
+            inode.extend_from_slice(&size.to_le_bytes());
+            inode.extend_from_slice(&i_u.to_le_bytes());
+            inode.extend_from_slice(&(node.nid as u32).to_le_bytes());
+            inode.extend_from_slice(&node.inode.uid.to_le_bytes());
+            inode.extend_from_slice(&node.inode.gid.to_le_bytes());
+            // puzzlefs images don't keep timestamps
+            inode.extend_from_slice(&[0; 12]);
+            inode.extend_from_slice(&node.nlink.max(1).to_le_bytes());
+            inode.resize(INODE_SIZE as usize, 0);
+            inode.extend_from_slice(&node.xattrs);
+
+            let tail = match (&node.inode.mode, layout) {
+                (_, LAYOUT_CHUNK_BASED) => {
+                    inode.resize(align_up(inode.len() as u64, CHUNK_INDEX_SIZE) as usize, 0);
+                    let chunk_blocks = 1 << CHUNK_BITS;
+                    let mut indexes = Vec::new();
+                    for chunk in 0..size.div_ceil(BLOCK_SIZE << CHUNK_BITS) {
+                        // no advise, and the first extra device
+                        indexes.extend_from_slice(&0_u16.to_le_bytes());
+                        indexes.extend_from_slice(&1_u16.to_le_bytes());
+                        let block = node.block + chunk * chunk_blocks;
+                        indexes.extend_from_slice(&(block as u32).to_le_bytes());
+                    }
+                    indexes
+                }
+                (InodeMode::Dir { .. }, LAYOUT_FLAT_INLINE) => node.encode_dir(&nodes),
+                (InodeMode::Dir { .. }, _) => {
+                    let block = (node.block * BLOCK_SIZE) as usize;
+                    let data = node.encode_dir(&nodes);
+                    metadata[block..block + data.len()].copy_from_slice(&data);
+                    Vec::new()
+                }
+                (InodeMode::Lnk, _) => node.symlink_target().to_vec(),
+                _ => Vec::new(),
+            };
+            inode.extend_from_slice(&tail);
+            metadata[start..start + inode.len()].copy_from_slice(&inode);
+        }
+
+        let mut sb = Vec::with_capacity(SUPER_SIZE);
+        sb.extend_from_slice(&MAGIC.to_le_bytes());
+        // no checksum and no compatible features
+        sb.extend_from_slice(&[0; 8]);
+        sb.push(BLOCK_BITS);
+        sb.push(0);
+        sb.extend_from_slice(&(nodes[0].nid as u16).to_le_bytes());
+        sb.extend_from_slice(&(nodes.len() as u64).to_le_bytes());
+        // no build time, the inodes don't have timestamps
+        sb.extend_from_slice(&[0; 12]);
+        sb.extend_from_slice(&((offset / BLOCK_SIZE) as u32).to_le_bytes());
+        // the metadata starts at block 0, no shared xattrs, uuid nor volume name
+        sb.extend_from_slice(&[0; 40]);
+        sb.extend_from_slice(
+            &(FEATURE_INCOMPAT_CHUNKED_FILE | FEATURE_INCOMPAT_DEVICE_TABLE).to_le_bytes(),
+        );
+        sb.extend_from_slice(&[0; 2]);
+        sb.extend_from_slice(&1_u16.to_le_bytes());
+        sb.extend_from_slice(&((DEVICE_TABLE_OFFSET / DEVICE_SLOT_SIZE) as u16).to_le_bytes());
+        sb.resize(SUPER_SIZE, 0);
+        metadata[SUPER_OFFSET..SUPER_OFFSET + SUPER_SIZE].copy_from_slice(&sb);
+
+        let mut slot = data_tag.as_bytes().to_vec();
+        slot.resize(DEVICE_TAG_SIZE, 0);
+        slot.extend_from_slice(&(data_blocks as u32).to_le_bytes());
+        slot.resize(DEVICE_SLOT_SIZE, 0);
+        metadata[DEVICE_TABLE_OFFSET..DEVICE_TABLE_OFFSET + DEVICE_SLOT_SIZE]
+            .copy_from_slice(&slot);
+
+        let files = nodes
+            .into_iter()
+            .filter(|node| node.layout().0 == LAYOUT_CHUNK_BASED)
+            .map(|node| (node.block, node.inode))
+            .collect();
+        Ok(ErofsImage {
+            metadata,
+            data_size: data_blocks * BLOCK_SIZE,
+            files,
+        })
+    }
+
+    /// Fills `buf` with the data blob from `offset`.
+    pub(crate) fn read_data(
+        &self,
+        oci: &Image,
+        verity_data: &Option<VerityData>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        buf.fill(0);
+        let end = offset + buf.len() as u64;
+        let first = self
+            .files
+            .partition_point(|(block, _)| block * BLOCK_SIZE <= offset)
+            .saturating_sub(1);
+        for (block, inode) in &self.files[first..] {
+            let start = block * BLOCK_SIZE;
+            if start >= end {
+                break;
+            }
+            let len = inode.file_len()?;
+            if start + len <= offset {
+                continue;
+            }
+            let file_offset = offset.saturating_sub(start);
+            let buf_offset = start.saturating_sub(offset) as usize;
+            let n = ((len - file_offset) as usize).min(buf.len() - buf_offset);
+            file_read(
+                oci,
+                inode,
+                file_offset as usize,
+                &mut buf[buf_offset..buf_offset + n],
+                verity_data,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn u16_at(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    // where the data following an inode starts
+    fn inode_end(buf: &[u8], offset: usize) -> usize {
+        match u16_at(buf, offset + 2) as usize {
+            0 => offset + 64,
+            count => offset + 64 + XATTR_HEADER_SIZE + 4 * (count - 1),
+        }
+    }
+
+    #[test]
+    fn test_erofs_image() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let erofs = ErofsImage::build(&pfs, "data")?;
+        let meta = &erofs.metadata;
+        assert_eq!(u32_at(meta, SUPER_OFFSET), MAGIC);
+        assert_eq!(
+            &meta[DEVICE_TABLE_OFFSET..DEVICE_TABLE_OFFSET + 5],
+            b"data\0"
+        );
+
+        // the root directory has its entries inline
+        let root = u16_at(meta, SUPER_OFFSET + 14) as usize * 32;
+        assert_eq!(u16_at(meta, root), (LAYOUT_FLAT_INLINE << 1) | 1);
+        assert_eq!(
+            u16_at(meta, root + 4) as u32 & SFlag::S_IFMT.bits(),
+            SFlag::S_IFDIR.bits()
+        );
+        let dir_size = u64_at(meta, root + 8) as usize;
+        let entries = &meta[inode_end(meta, root)..inode_end(meta, root) + dir_size];
+        let count = u16_at(entries, 8) as usize / DIRENT_SIZE;
+        assert_eq!(count, 3);
+        let name_start = u16_at(entries, 2 * DIRENT_SIZE + 8) as usize;
+        assert_eq!(&entries[name_start..], b"SekienAkashita.jpg");
+        assert_eq!(entries[2 * DIRENT_SIZE + 10], 1);
+
+        // the file maps to the start of the data blob
+        let file = u64_at(entries, 2 * DIRENT_SIZE) as usize * 32;
+        assert_eq!(u16_at(meta, file), (LAYOUT_CHUNK_BASED << 1) | 1);
+        let size = u64_at(meta, file + 8);
+        assert_eq!(size, 109466);
+        let indexes = align_up(inode_end(meta, file) as u64, CHUNK_INDEX_SIZE) as usize;
+        assert_eq!(u16_at(meta, indexes + 2), 1);
+        assert_eq!(u32_at(meta, indexes + 4), 0);
+        assert_eq!(erofs.data_size, align_up(size, BLOCK_SIZE));
+
+        let mut data = vec![0; erofs.data_size as usize];
+        erofs.read_data(&pfs.oci, &pfs.verity_data, 0, &mut data)?;
+        let contents = fs::read("src/builder/test/test-1/SekienAkashita.jpg")?;
+        assert_eq!(&data[..contents.len()], contents);
+        assert!(data[contents.len()..].iter().all(|b| *b == 0));
+        let mut part = [0; 100];
+        erofs.read_data(&pfs.oci, &pfs.verity_data, 4000, &mut part)?;
+        assert_eq!(part, contents[4000..4100]);
+        Ok(())
+    }
+}
@@ -1,43 +1,421 @@
-use log::{debug, warn};
 use os_pipe::PipeWriter;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fs;
 use std::fs::OpenOptions;
-use std::io::Write;
+use std::io::{self, Write};
+use std::net::SocketAddr;
 use std::os::raw::c_int;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use tracing::{debug, info, warn};
 
 use fuser::{
-    FileAttr, FileType, Filesystem, KernelConfig, ReplyData, ReplyEntry, ReplyOpen, Request,
-    TimeOrNow,
+    FileAttr, FileType, Filesystem, KernelConfig, Notifier, ReplyData, ReplyDirectoryPlus,
+    ReplyEntry, ReplyOpen, Request, TimeOrNow,
 };
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
+use nix::libc;
 use std::time::{Duration, SystemTime};
 
-use crate::format::{DirEnt, Inode, InodeMode, Result, WireFormatError};
+use crate::builder::BuildError;
+use crate::format::{DirEnt, Ino, Inode, InodeMode, Result, WireFormatError};
+use crate::idmap::IdMap;
+use crate::oci::ImageError;
 
-use super::puzzlefs::{file_read, PuzzleFS};
+use super::access_log::AccessLog;
+use super::control::{OverlayMount, RemountPolicy};
+use super::puzzlefs::{file_data, verify_file, PuzzleFS};
+use super::MountError;
+use crate::metrics::{Op, METRICS};
+
+mod upper;
+use upper::UpperLayer;
 
 pub enum PipeDescriptor {
     UnnamedPipe(PipeWriter),
     NamedPipe(PathBuf),
 }
 
+/// Options of the FUSE daemon.
+#[derive(Debug, Default, Clone)]
+pub struct FuseConfig {
+    /// Makes the mount writable: changes are stored in this directory, which uses the OCI layer
+    /// conventions for deleted files (`.wh.<name>` whiteouts).
+    pub upper_dir: Option<PathBuf>,
+    /// Other tags of the image to stack below the mounted one, topmost first, with the
+    /// fs-verity digests of their manifests.
+    pub lower: Vec<(String, Option<Vec<u8>>)>,
+    /// Refuse to read anything which doesn't have fs-verity enabled, even without the digests of
+    /// the manifests.
+    pub require_verity: bool,
+    /// Once the filesystem is mounted, move the daemon to its own namespaces, drop its
+    /// capabilities and install a seccomp filter before serving any request. Only honoured by
+    /// [`crate::reader::mount`], since the process has to be single threaded.
+    pub sandbox: bool,
+    /// Shift the owners of the files of the image, e.g. for rootless containers. The files of the
+    /// upper layer keep the owners they have on the host.
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    /// Describe the mounted image with `user.puzzlefs.*` xattrs on the root directory: its tag,
+    /// the digest of its manifest, the fs-verity digest it was mounted with and the stacked tags.
+    pub provenance_xattrs: bool,
+    /// Serve the metrics of the daemon in the Prometheus text format over HTTP on this address.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Answer requests on a control socket in this directory, see [`crate::reader::control`].
+    /// Only honoured by [`crate::reader::mount`].
+    pub control_dir: Option<PathBuf>,
+    /// Write the digests of the chunks of the image to this file as they are first read, see
+    /// [`crate::reader::access_log`].
+    pub access_log: Option<PathBuf>,
+    /// The overlay the caller mounts on top of the filesystem to make it writable, only reported
+    /// on the control socket.
+    pub overlay: Option<OverlayMount>,
+    /// Let the device nodes of the image be opened. Otherwise the filesystem is mounted `nodev`,
+    /// whatever the mount options say, so an untrusted image can't hand out access to the
+    /// devices of the host. Only root can mount filesystems with usable device nodes anyway.
+    pub allow_devices: bool,
+    /// Check the whole contents of the regular files of the image against their digests (see
+    /// [`crate::builder::BuilderConfig::file_digests`]) the first time they're opened, failing
+    /// the open with `EIO` on a mismatch. A debugging aid: opening a big file reads it all.
+    pub verify_files: bool,
+    /// How long the kernel caches the names it looks up, along with the attributes they come
+    /// with. By default forever, since the image never changes, or a second for writable mounts,
+    /// whose upper layer changes behind the kernel's back (e.g. renames carry inode numbers
+    /// around).
+    pub entry_timeout: Option<Duration>,
+    /// How long the kernel caches the attributes it asks for, with the same defaults.
+    pub attr_timeout: Option<Duration>,
+    /// The most the kernel reads ahead of the reads from a file, in bytes, instead of the default
+    /// it negotiates.
+    pub max_readahead: Option<u32>,
+    /// Have the kernel drop the cached contents of a file when its size or modification time
+    /// change, for mounts whose files may change under it. Writable mounts always have it.
+    pub auto_inval_data: bool,
+    /// The policy the mount was made with, which the tags it's switched to on the control socket
+    /// must meet too.
+    pub remount_policy: RemountPolicy,
+    // the lookups and directory listings the kernel sends, for the tests to check which ones it
+    // sends
+    #[cfg(test)]
+    pub(crate) requests: Option<Arc<Mutex<Vec<Op>>>>,
+}
+
 pub struct Fuse {
-    pfs: PuzzleFS,
+    pfs: Arc<PuzzleFS>,
+    switch: Arc<ImageSwitch>,
     sender: Option<std::sync::mpsc::Sender<()>>,
     init_notify: Option<PipeDescriptor>,
+    upper: Option<UpperLayer>,
+    // read-only xattrs describing the image, on the root directory
+    root_xattrs: Vec<(OsString, Vec<u8>)>,
+    // whether the files of the image are being counted for statfs, see PuzzleFS::statistics
+    counting: bool,
+    access_log: Option<AccessLog>,
+    verify_files: bool,
+    // the files whose contents were checked against their digest, with verify_files
+    verified: HashSet<Ino>,
+    // the entries of the open directories, by file handle, listed when they're opened so that
+    // each readdir doesn't merge the directory across the layers again
+    dir_handles: HashMap<u64, Arc<Vec<(Ino, OsString)>>>,
+    // the image each open file was opened on, which it keeps reading after a switch
+    file_handles: HashMap<u64, Arc<PuzzleFS>>,
+    next_handle: u64,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    max_readahead: Option<u32>,
+    auto_inval_data: bool,
+    #[cfg(test)]
+    requests: Option<Arc<Mutex<Vec<Op>>>>,
     // TODO: LRU cache inodes or something. I had problems fiddling with the borrow checker for the
     // cache, so for now we just do each lookup every time.
 }
 
+/// Hands the image a mount switches to over to its session, which serves it from the next
+/// request on.
+pub(crate) struct ImageSwitch {
+    state: Mutex<SwitchState>,
+    switched: Condvar,
+}
+
+struct SwitchState {
+    // the image the session serves, and the one it switches to next
+    current: Arc<PuzzleFS>,
+    next: Option<Arc<PuzzleFS>>,
+    // the number of switches requested, and the number the session went through
+    requested: u64,
+    done: u64,
+}
+
+impl ImageSwitch {
+    fn new(pfs: Arc<PuzzleFS>) -> Self {
+        ImageSwitch {
+            state: Mutex::new(SwitchState {
+                current: pfs,
+                next: None,
+                requested: 0,
+                done: 0,
+            }),
+            switched: Condvar::new(),
+        }
+    }
+
+    /// Has the session serve `pfs` from its next request on. Returns the number of the switch to
+    /// [`ImageSwitch::wait`] for.
+    pub(crate) fn request(&self, pfs: Arc<PuzzleFS>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next = Some(pfs);
+        state.requested += 1;
+        state.requested
+    }
+
+    /// The image the session serves from its next request on: the one it was last switched to,
+    /// even if it didn't serve any request since.
+    pub(crate) fn latest(&self) -> Arc<PuzzleFS> {
+        let state = self.state.lock().unwrap();
+        Arc::clone(state.next.as_ref().unwrap_or(&state.current))
+    }
+
+    /// Waits until the session went through the switch `switch`, or a later one.
+    pub(crate) fn wait(&self, switch: u64) {
+        let state = self.state.lock().unwrap();
+        drop(
+            self.switched
+                .wait_while(state, |state| state.done < switch)
+                .unwrap(),
+        );
+    }
+
+    fn take(&self) -> Option<Arc<PuzzleFS>> {
+        let mut state = self.state.lock().unwrap();
+        let next = state.next.take()?;
+        state.current = Arc::clone(&next);
+        state.done = state.requested;
+        self.switched.notify_all();
+        Some(next)
+    }
+}
+
+/// What switching a mount from one image to another changes, in terms of the inode numbers of
+/// the old image, which are the ones the kernel knows.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Change {
+    /// A directory kept its inode number, but its entries or attributes changed.
+    Dir(Ino),
+    /// An entry of a directory which was removed, now names another inode, or names the same
+    /// inode with other contents or attributes.
+    Entry {
+        parent: Ino,
+        name: Vec<u8>,
+        ino: Ino,
+    },
+}
+
+/// The changes from `old` to `new`, compared path by path from the root. Only the topmost
+/// changed entry of a subtree is listed, along with the directories which kept their inode
+/// number but gained entries.
+pub(crate) fn image_changes(old: &PuzzleFS, new: &PuzzleFS) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    dir_changes(
+        old,
+        new,
+        &old.find_inode(1)?,
+        &new.find_inode(1)?,
+        &mut changes,
+    )?;
+    Ok(changes)
+}
+
+fn dir_changes(
+    old: &PuzzleFS,
+    new: &PuzzleFS,
+    old_dir: &Inode,
+    new_dir: &Inode,
+    changes: &mut Vec<Change>,
+) -> Result<()> {
+    if old_dir != new_dir {
+        changes.push(Change::Dir(old_dir.ino));
+    }
+    let new_entries = new_dir
+        .dir_entries()?
+        .iter()
+        .map(|entry| (&entry.name[..], entry.ino))
+        .collect::<HashMap<_, _>>();
+    for entry in old_dir.dir_entries()? {
+        let old_inode = old.find_inode(entry.ino)?;
+        let new_inode = match new_entries.get(&entry.name[..]) {
+            Some(ino) => Some(new.find_inode(*ino)?),
+            None => None,
+        };
+        match new_inode {
+            // the directories which kept their inode number are compared entry by entry
+            Some(new_inode)
+                if new_inode.ino == old_inode.ino
+                    && matches!(new_inode.mode, InodeMode::Dir { .. })
+                    && matches!(old_inode.mode, InodeMode::Dir { .. }) =>
+            {
+                dir_changes(old, new, &old_inode, &new_inode, changes)?;
+                continue;
+            }
+            Some(new_inode) if new_inode == old_inode => continue,
+            _ => (),
+        }
+        changes.push(Change::Entry {
+            parent: old_dir.ino,
+            name: entry.name.clone(),
+            ino: old_inode.ino,
+        });
+    }
+    Ok(())
+}
+
+/// Tells the kernel about the `changes` from the image `old`: the directories which changed have
+/// their attributes and listings dropped from its caches, and the entries which changed are
+/// deleted along with the names cached below them, the way inotify watchers see files being
+/// deleted. The files the kernel looks up next come from the new image. The kernel only refuses
+/// to drop what it doesn't have.
+pub(crate) fn notify_changes(
+    old: &PuzzleFS,
+    changes: &[Change],
+    notifier: &Notifier,
+) -> Result<()> {
+    for change in changes {
+        match change {
+            Change::Dir(ino) => {
+                uncached(notifier.inval_inode(*ino, 0, 0))?;
+            }
+            Change::Entry { parent, name, ino } => {
+                notify_deleted(old, *parent, name, &old.find_inode(*ino)?, notifier)?
+            }
+        }
+    }
+    Ok(())
+}
+
+// the kernel refuses to delete a directory with names cached below it, so they go first
+fn notify_deleted(
+    old: &PuzzleFS,
+    parent: Ino,
+    name: &[u8],
+    inode: &Inode,
+    notifier: &Notifier,
+) -> Result<()> {
+    // nothing is cached below the inodes the kernel doesn't know, and their contents go with them
+    if uncached(notifier.inval_inode(inode.ino, 0, 0))? {
+        return Ok(());
+    }
+    if let InodeMode::Dir { dir_list } = &inode.mode {
+        for entry in &dir_list.entries {
+            notify_deleted(
+                old,
+                inode.ino,
+                &entry.name,
+                &old.find_inode(entry.ino)?,
+                notifier,
+            )?;
+        }
+    }
+    uncached(notifier.delete(parent, inode.ino, OsStr::from_bytes(name)))?;
+    Ok(())
+}
+
+fn uncached(result: io::Result<()>) -> io::Result<bool> {
+    match result {
+        Ok(()) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// The errno the kernel gets for `e`. The errors of the library only turn into errnos at the
+/// boundary with the kernel; the 9p server and [`crate::api`] report theirs with the same ones.
+pub(crate) fn to_errno(e: &WireFormatError) -> c_int {
+    let errno = match e {
+        WireFormatError::IOError(e, _) => return e.raw_os_error().unwrap_or(Errno::EIO as c_int),
+        WireFormatError::Build(e) => match e {
+            BuildError::SourceChanged { .. } => Errno::EAGAIN,
+            BuildError::EscapingSymlink { .. } => Errno::EXDEV,
+            BuildError::Io { source, .. } => {
+                return source.raw_os_error().unwrap_or(Errno::EIO as c_int)
+            }
+            BuildError::KernelCompat { .. } => Errno::EINVAL,
+        },
+        WireFormatError::Image(e) => match e {
+            ImageError::MissingManifest { .. }
+            | ImageError::MissingPlatform { .. }
+            | ImageError::MissingRootfs { .. } => Errno::ENOENT,
+            ImageError::MissingBlob { .. } | ImageError::DigestMismatch { .. } => Errno::EIO,
+            ImageError::UnsupportedManifestVersion { .. } | ImageError::LegacyLayout { .. } => {
+                Errno::ENOTSUP
+            }
+        },
+        WireFormatError::Mount(e) => match e {
+            MountError::VerityRequired { .. } | MountError::Sandbox { .. } => Errno::EPERM,
+            MountError::MixedVerity => Errno::EINVAL,
+            MountError::FileDigestMismatch { .. } => Errno::EIO,
+        },
+        WireFormatError::LocalRefError(..) => Errno::EINVAL,
+        WireFormatError::SeekOtherError(..) => Errno::ESPIPE,
+        WireFormatError::InvalidSerializedData(..) => Errno::EINVAL,
+        WireFormatError::InvalidMetadata(..) => Errno::EINVAL,
+        WireFormatError::InvalidDirent(..) => Errno::EINVAL,
+        WireFormatError::InvalidImageSchema(..) => Errno::EINVAL,
+        WireFormatError::InvalidFsVerityData(..) => Errno::EINVAL,
+        WireFormatError::CapnpError(..) => Errno::EINVAL,
+        WireFormatError::JSONError(..) => Errno::EINVAL,
+        WireFormatError::HexError(..) => Errno::EINVAL,
+        WireFormatError::FromIntError(..) => Errno::EINVAL,
+        WireFormatError::FromSliceError(..) => Errno::EINVAL,
+        WireFormatError::OciError(..) => Errno::EINVAL,
+        WireFormatError::OciDirError(..) => Errno::EINVAL,
+        WireFormatError::SignatureError(..) => Errno::EKEYREJECTED,
+        WireFormatError::OpenSSLError(..) => Errno::EINVAL,
+        WireFormatError::DeltaError(..) => Errno::EINVAL,
+        WireFormatError::RemoteError(..) => Errno::EIO,
+        WireFormatError::EncryptionError(..) => Errno::EACCES,
+        WireFormatError::TrustError(..) => Errno::ENOKEY,
+    };
+    errno as c_int
+}
+
+// the xattrs describing which image a mount serves
+fn provenance_xattrs(pfs: &PuzzleFS) -> Result<Vec<(OsString, Vec<u8>)>> {
+    let Some((tag, lower)) = pfs.tags.split_first() else {
+        return Ok(Vec::new());
+    };
+    let manifest = pfs
+        .oci
+        .find_manifest_descriptor(tag)?
+        .ok_or_else(|| ImageError::MissingManifest { tag: tag.clone() })?;
+    let mut xattrs = vec![
+        ("user.puzzlefs.tag".into(), tag.clone().into_bytes()),
+        (
+            "user.puzzlefs.manifest_digest".into(),
+            manifest.digest().to_string().into_bytes(),
+        ),
+    ];
+    if let Some(verity) = &pfs.manifest_verity {
+        xattrs.push((
+            "user.puzzlefs.manifest_verity".into(),
+            hex::encode(verity).into_bytes(),
+        ));
+    }
+    if !lower.is_empty() {
+        xattrs.push(("user.puzzlefs.lower".into(), lower.join(",").into_bytes()));
+    }
+    Ok(xattrs)
+}
+
+// the block size we report in statfs
+const STATFS_BLOCK_SIZE: u64 = 4096;
+
 fn mode_to_fuse_type(inode: &Inode) -> Result<FileType> {
     Ok(match inode.mode {
         InodeMode::File { .. } => FileType::RegularFile,
@@ -51,49 +429,254 @@ fn mode_to_fuse_type(inode: &Inode) -> Result<FileType> {
     })
 }
 
+fn inode_attr(ic: &Inode) -> Result<FileAttr> {
+    let kind = mode_to_fuse_type(ic)?;
+    let len = ic.file_len().unwrap_or(0);
+    Ok(FileAttr {
+        ino: ic.ino,
+        size: len,
+        blocks: 0,
+        atime: SystemTime::UNIX_EPOCH,
+        mtime: SystemTime::UNIX_EPOCH,
+        ctime: SystemTime::UNIX_EPOCH,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind,
+        perm: ic.permissions,
+        nlink: 0,
+        uid: ic.uid,
+        gid: ic.gid,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    })
+}
+
+// the groups of the process `pid`: FUSE only tells us its primary group `gid`, the supplementary
+// ones come from the `Groups:` line of /proc/<pid>/status, if it can still be read
+fn caller_groups(pid: u32, gid: u32) -> Vec<u32> {
+    let mut groups = vec![gid];
+    let status = match fs::read_to_string(format!("/proc/{pid}/status")) {
+        Ok(status) => status,
+        Err(e) => {
+            debug!("cannot read the groups of pid {pid}: {e}");
+            return groups;
+        }
+    };
+    if let Some(line) = status.lines().find_map(|line| line.strip_prefix("Groups:")) {
+        groups.extend(
+            line.split_whitespace()
+                .filter_map(|group| group.parse::<u32>().ok()),
+        );
+    }
+    groups
+}
+
+// checks the caller's access to a file for the R_OK, W_OK and X_OK bits of `mask` like the kernel's
+// generic_permission; `groups` are the primary and supplementary groups of the caller
+fn check_access(attr: &FileAttr, uid: u32, groups: &[u32], mask: i32) -> Result<()> {
+    let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+    let allowed = if uid == 0 {
+        // root may do anything, except execute files which nobody can execute
+        if attr.kind == FileType::Directory || attr.perm & 0o111 != 0 {
+            0o7
+        } else {
+            0o6
+        }
+    } else if uid == attr.uid {
+        (attr.perm >> 6) & 0o7
+    } else if groups.contains(&attr.gid) {
+        (attr.perm >> 3) & 0o7
+    } else {
+        attr.perm & 0o7
+    };
+    if mask & allowed != mask {
+        return Err(WireFormatError::from_errno(Errno::EACCES));
+    }
+    Ok(())
+}
+
+// the access checks needed to open a file with `flags`
+fn open_mask(flags: OFlag) -> i32 {
+    let mut mask = match flags & OFlag::O_ACCMODE {
+        OFlag::O_WRONLY => libc::W_OK,
+        OFlag::O_RDWR => libc::R_OK | libc::W_OK,
+        _ => libc::R_OK,
+    };
+    if flags.contains(OFlag::O_TRUNC) {
+        mask |= libc::W_OK;
+    }
+    mask
+}
+
+// replies with the result of an operation of the upper layer, or EROFS if the mount is read-only
+macro_rules! upper_op {
+    ($self:ident, $reply:ident, $name:literal, |$upper:ident, $pfs:ident| $op:expr, $ok:expr) => {
+        match $self.upper.as_mut() {
+            None => {
+                debug!("{} not supported on a read-only mount!", $name);
+                $reply.error(Errno::EROFS as i32)
+            }
+            Some($upper) => {
+                let $pfs = &$self.pfs;
+                match $op {
+                    Ok(result) => $ok(result),
+                    Err(e) => {
+                        debug!("cannot {} {e}!", $name);
+                        $reply.error(to_errno(&e))
+                    }
+                }
+            }
+        }
+    };
+}
+
 impl Fuse {
     pub fn new(
-        pfs: PuzzleFS,
+        mut pfs: PuzzleFS,
         sender: Option<std::sync::mpsc::Sender<()>>,
         init_notify: Option<PipeDescriptor>,
-    ) -> Fuse {
-        Fuse {
+        config: &FuseConfig,
+    ) -> Result<Fuse> {
+        pfs.set_id_maps(config.uid_map.clone(), config.gid_map.clone());
+        let upper = config
+            .upper_dir
+            .as_deref()
+            .map(|dir| UpperLayer::new(dir, &pfs))
+            .transpose()?;
+        let root_xattrs = if config.provenance_xattrs {
+            provenance_xattrs(&pfs)?
+        } else {
+            Vec::new()
+        };
+        // opened before the daemon is sandboxed
+        let access_log = config
+            .access_log
+            .as_deref()
+            .map(AccessLog::create)
+            .transpose()?;
+        // the image never changes, but the upper layer does
+        let ttl = if upper.is_some() {
+            Duration::from_secs(1)
+        } else {
+            Duration::new(u64::MAX, 0)
+        };
+        let pfs = Arc::new(pfs);
+        Ok(Fuse {
+            switch: Arc::new(ImageSwitch::new(Arc::clone(&pfs))),
+            // the replies to lookups have a single ttl, for the name and for its attributes
+            entry_ttl: std::cmp::min(
+                config.entry_timeout.unwrap_or(ttl),
+                config.attr_timeout.unwrap_or(ttl),
+            ),
+            attr_ttl: config.attr_timeout.unwrap_or(ttl),
+            max_readahead: config.max_readahead,
+            auto_inval_data: config.auto_inval_data || upper.is_some(),
             pfs,
             sender,
             init_notify,
+            upper,
+            root_xattrs,
+            counting: false,
+            access_log,
+            verify_files: config.verify_files,
+            verified: HashSet::new(),
+            dir_handles: HashMap::new(),
+            file_handles: HashMap::new(),
+            // 0 is left for the files opened before an open we didn't see
+            next_handle: 1,
+            #[cfg(test)]
+            requests: config.requests.clone(),
+        })
+    }
+
+    // records a request for FuseConfig::requests
+    fn record(&self, _op: Op) {
+        #[cfg(test)]
+        if let Some(requests) = &self.requests {
+            requests.lock().unwrap().push(_op);
+        }
+    }
+
+    /// Lets the image the mount serves be switched from another thread, see
+    /// [`crate::reader::control`]. Only for read-only mounts: the upper layer records the inodes
+    /// of the image it shadows.
+    pub(crate) fn image_switch(&self) -> Arc<ImageSwitch> {
+        Arc::clone(&self.switch)
+    }
+
+    // serves the image the mount was switched to, if any, dropping what was kept about the
+    // previous one; the open directories are listed again from the new image
+    fn switch_image(&mut self) {
+        let Some(pfs) = self.switch.take() else {
+            return;
+        };
+        info!("switching to {}", pfs.tags.join(", "));
+        // the root only has xattrs with provenance_xattrs
+        if !self.root_xattrs.is_empty() {
+            match provenance_xattrs(&pfs) {
+                Ok(xattrs) => self.root_xattrs = xattrs,
+                Err(e) => warn!("cannot describe the new image: {e}"),
+            }
         }
+        self.pfs = pfs;
+        self.counting = false;
+        self.verified.clear();
+        self.dir_handles.clear();
     }
 
     fn _lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr> {
-        let dir = self.pfs.find_inode(parent)?;
-        let ino = dir.dir_lookup(name.as_bytes())?;
+        if let Some(upper) = &mut self.upper {
+            return upper.lookup(&self.pfs, parent, name);
+        }
+        let ino = self.pfs.dir_lookup(parent, name.as_bytes())?;
         self._getattr(ino)
     }
 
     fn _getattr(&mut self, ino: u64) -> Result<FileAttr> {
-        let ic = self.pfs.find_inode(ino)?;
-        let kind = mode_to_fuse_type(&ic)?;
-        let len = ic.file_len().unwrap_or(0);
-        Ok(FileAttr {
-            ino: ic.ino,
-            size: len,
-            blocks: 0,
-            atime: SystemTime::UNIX_EPOCH,
-            mtime: SystemTime::UNIX_EPOCH,
-            ctime: SystemTime::UNIX_EPOCH,
-            crtime: SystemTime::UNIX_EPOCH,
-            kind,
-            perm: ic.permissions,
-            nlink: 0,
-            uid: ic.uid,
-            gid: ic.gid,
-            rdev: 0,
-            blksize: 0,
-            flags: 0,
-        })
+        if let Some(upper) = &mut self.upper {
+            return upper.getattr(&self.pfs, ino);
+        }
+        inode_attr(&self.pfs.find_inode(ino)?)
+    }
+
+    // returns the total and free blocks, the blocks available to unprivileged users, the total
+    // and the free inodes; only the upper layer, if any, has free space
+    fn _statfs(&mut self) -> Result<(u64, u64, u64, u64, u64)> {
+        // the image is counted on another thread, so the requests aren't held up walking it; it
+        // looks empty until then
+        let (files, size) = match self.pfs.known_statistics() {
+            Some(statistics) => statistics,
+            None => {
+                if !self.counting {
+                    self.counting = true;
+                    let pfs = Arc::clone(&self.pfs);
+                    thread::spawn(move || {
+                        if let Err(e) = pfs.statistics() {
+                            warn!("cannot count the files of the image: {e}");
+                        }
+                    });
+                }
+                (0, 0)
+            }
+        };
+        let (bfree, bavail, ffree) = match &self.upper {
+            Some(upper) => upper.free_space(STATFS_BLOCK_SIZE)?,
+            None => (0, 0, 0),
+        };
+        let blocks = size.div_ceil(STATFS_BLOCK_SIZE) + bfree;
+        Ok((blocks, bfree, bavail, files + ffree, ffree))
+    }
+
+    fn _access(&mut self, req: &Request<'_>, ino: u64, mask: i32) -> Result<()> {
+        if mask & libc::W_OK != 0 && self.upper.is_none() {
+            return Err(WireFormatError::from_errno(Errno::EROFS));
+        }
+        let groups = caller_groups(req.pid(), req.gid());
+        check_access(&self._getattr(ino)?, req.uid(), &groups, mask)
     }
 
-    fn _open(&self, flags_i: i32, reply: ReplyOpen) {
+    // returns the flags to reply with
+    fn _open(&mut self, req: &Request<'_>, ino: u64, flags_i: i32) -> Result<u32> {
         let allowed_flags = OFlag::O_RDONLY
             | OFlag::O_PATH
             | OFlag::O_NONBLOCK
@@ -101,48 +684,149 @@ impl Fuse {
             | OFlag::O_NOFOLLOW
             | OFlag::O_NOATIME;
         let flags = OFlag::from_bits_truncate(flags_i);
-        if !allowed_flags.contains(flags) {
+        if self.upper.is_none() && !allowed_flags.contains(flags) {
             warn!("invalid flags {flags:?}, only allowed {allowed_flags:?}");
-            reply.error(Errno::EROFS as i32)
-        } else {
-            // stateless open for now, slower maybe
-            reply.opened(0, flags_i.try_into().unwrap());
+            return Err(WireFormatError::from_errno(Errno::EROFS));
+        }
+        // O_PATH only gives a handle, it doesn't need any permissions
+        if !flags.contains(OFlag::O_PATH) {
+            self._access(req, ino, open_mask(flags))?;
+        }
+        // the files of the upper layer have no digest
+        if self.verify_files
+            && self.upper.is_none()
+            && !flags.contains(OFlag::O_PATH)
+            && !self.verified.contains(&ino)
+        {
+            let inode = self.pfs.find_inode(ino)?;
+            if matches!(inode.mode, InodeMode::File { .. }) {
+                if let Err(e) = verify_file(&self.pfs.oci, &inode) {
+                    warn!("ino {ino} is corrupted: {e}");
+                    return Err(e);
+                }
+                self.verified.insert(ino);
+            }
         }
+        // stateless open for now, slower maybe; with an upper layer, files are copied up on
+        // their first write rather than on open
+        Ok(flags_i.try_into().unwrap())
     }
 
-    fn _read(&mut self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
-        let inode = self.pfs.find_inode(ino)?;
-        let mut buf = vec![0_u8; size as usize];
-        let read = file_read(
-            &self.pfs.oci,
-            &inode,
-            offset as usize,
-            &mut buf,
-            &self.pfs.verity_data,
-        )?;
-        buf.truncate(read);
-        Ok(buf)
-    }
-
-    fn _readdir(&mut self, ino: u64, offset: i64, reply: &mut fuser::ReplyDirectory) -> Result<()> {
+    fn new_handle(&mut self) -> u64 {
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        fh
+    }
+
+    fn open_handle(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match METRICS.timed(Op::Open, || self._open(req, ino, flags)) {
+            Ok(flags) => {
+                METRICS.handle_opened();
+                let fh = self.new_handle();
+                self.file_handles.insert(fh, Arc::clone(&self.pfs));
+                reply.opened(fh, flags)
+            }
+            Err(e) => {
+                debug!("cannot open ino {ino} with flags {flags:#o} {e}!");
+                reply.error(to_errno(&e))
+            }
+        }
+    }
+
+    fn _read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let pfs = match self.file_handles.get(&fh) {
+            Some(pfs) => Arc::clone(pfs),
+            None => Arc::clone(&self.pfs),
+        };
+        if let Some(upper) = &self.upper {
+            return upper.read(&pfs, ino, offset, size);
+        }
+        let inode = pfs.find_inode(ino)?;
+        // reads within an uncompressed chunk are a single pread of its blob
+        let data = file_data(&pfs.oci, &inode, offset, size as usize, &pfs.verity_data)?;
+        if let Some(log) = &mut self.access_log {
+            if let Err(e) = log.record(&inode, offset, data.len() as u64) {
+                warn!("cannot record the chunks read from ino {ino}: {e}");
+            }
+        }
+        Ok(data)
+    }
+
+    fn _opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32) -> Result<(u64, u32)> {
+        let flags = self._open(req, ino, flags)?;
+        let entries = self.list_dir(ino)?;
+        let fh = self.new_handle();
+        self.dir_handles.insert(fh, Arc::new(entries));
+        Ok((fh, flags))
+    }
+
+    fn list_dir(&mut self, ino: u64) -> Result<Vec<(Ino, OsString)>> {
+        if let Some(upper) = &mut self.upper {
+            let entries = upper.readdir(&self.pfs, ino)?;
+            return Ok(entries
+                .into_iter()
+                .map(|(ino, _, name)| (ino, name))
+                .collect());
+        }
         let inode = self.pfs.find_inode(ino)?;
-        let entries = inode.dir_entries()?;
-        for (index, DirEnt { name, ino: ino_r }) in entries.iter().enumerate().skip(offset as usize)
-        {
-            let ino = *ino_r;
-            let inode = self.pfs.find_inode(ino)?;
-            let kind = mode_to_fuse_type(&inode)?;
+        Ok(inode
+            .dir_entries()?
+            .iter()
+            .map(|DirEnt { ino, name }| (*ino, OsString::from_vec(name.clone())))
+            .collect())
+    }
 
+    // the entries listed when the directory was opened
+    fn dir_handle(&mut self, ino: u64, fh: u64) -> Result<Arc<Vec<(Ino, OsString)>>> {
+        match self.dir_handles.get(&fh) {
+            Some(entries) => Ok(entries.clone()),
+            None => Ok(Arc::new(self.list_dir(ino)?)),
+        }
+    }
+
+    fn _readdir(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: &mut fuser::ReplyDirectory,
+    ) -> Result<()> {
+        let entries = self.dir_handle(ino, fh)?;
+        for (index, (ino, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let kind = self._getattr(*ino)?.kind;
             // if the buffer is full, let's skip the extra lookups
-            if reply.add(ino, (index + 1) as i64, kind, OsStr::from_bytes(name)) {
+            if reply.add(*ino, (index + 1) as i64, kind, name) {
                 break;
             }
         }
+        Ok(())
+    }
 
+    // like readdir, with the attributes of the entries so that the kernel doesn't look each of
+    // them up
+    fn _readdirplus(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: &mut ReplyDirectoryPlus,
+    ) -> Result<()> {
+        let ttl = self.entry_ttl;
+        let entries = self.dir_handle(ino, fh)?;
+        for (index, (ino, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let attr = self._getattr(*ino)?;
+            let generation = 0;
+            if reply.add(*ino, (index + 1) as i64, name, &ttl, &attr, generation) {
+                break;
+            }
+        }
         Ok(())
     }
 
     fn _readlink(&mut self, ino: u64) -> Result<OsString> {
+        if let Some(upper) = &self.upper {
+            return upper.readlink(&self.pfs, ino);
+        }
         let inode = self.pfs.find_inode(ino)?;
         let error = WireFormatError::from_errno(Errno::EINVAL);
         let kind = mode_to_fuse_type(&inode)?;
@@ -155,9 +839,30 @@ impl Fuse {
         }
     }
 
+    fn root_xattr(&self, ino: u64, name: &OsStr) -> Option<&[u8]> {
+        if ino != 1 {
+            return None;
+        }
+        self.root_xattrs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.as_slice())
+    }
+
     fn _listxattr(&mut self, ino: u64) -> Result<Vec<u8>> {
+        let mut xattr_list = Vec::new();
+        if ino == 1 {
+            for (key, _) in &self.root_xattrs {
+                xattr_list.extend_from_slice(key.as_bytes());
+                xattr_list.push(0);
+            }
+        }
+        if let Some(upper) = &self.upper {
+            xattr_list.extend(upper.listxattr(&self.pfs, ino)?);
+            return Ok(xattr_list);
+        }
         let inode = self.pfs.find_inode(ino)?;
-        let xattr_list = inode
+        let image_xattrs = inode
             .additional
             .map(|add| {
                 add.xattrs
@@ -171,11 +876,18 @@ impl Fuse {
                     .collect::<Vec<u8>>()
             })
             .unwrap_or_else(Vec::<u8>::new);
+        xattr_list.extend(image_xattrs);
 
         Ok(xattr_list)
     }
 
     fn _getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        if let Some(val) = self.root_xattr(ino, name) {
+            return Ok(val.to_vec());
+        }
+        if let Some(upper) = &self.upper {
+            return upper.getxattr(&self.pfs, ino, name);
+        }
         let inode = self.pfs.find_inode(ino)?;
         inode
             .additional
@@ -204,8 +916,26 @@ impl Filesystem for Fuse {
     fn init(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut KernelConfig,
+        config: &mut KernelConfig,
     ) -> std::result::Result<(), c_int> {
+        // let the kernel list directories along with the attributes of their entries, when it
+        // thinks they'll be looked up
+        let mut capabilities =
+            fuser::consts::FUSE_DO_READDIRPLUS | fuser::consts::FUSE_READDIRPLUS_AUTO;
+        if self.auto_inval_data {
+            capabilities |= fuser::consts::FUSE_AUTO_INVAL_DATA;
+        }
+        // the writeback cache is never asked for: writes to the upper layer go straight to it
+        if let Err(unsupported) = config.add_capabilities(capabilities) {
+            debug!("the kernel doesn't support the capabilities {unsupported:#x}");
+        }
+        if let Some(max_readahead) = self.max_readahead {
+            if let Err(nearest) = config.set_max_readahead(max_readahead) {
+                warn!("the kernel reads at most {nearest} bytes ahead, not {max_readahead}");
+                // the nearest value is the kernel's maximum, which it accepts
+                let _ = config.set_max_readahead(nearest);
+            }
+        }
         if let Some(init_notify) = self.init_notify.take() {
             match init_notify {
                 PipeDescriptor::UnnamedPipe(mut pipe_writer) => {
@@ -254,17 +984,17 @@ impl Filesystem for Fuse {
     fn destroy(&mut self) {}
     fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
 
-    // puzzlefs is readonly, so we can ignore a bunch of requests
+    // without an upper layer puzzlefs is readonly, so we can ignore a bunch of requests
     fn setattr(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
         _fh: Option<u64>,
         _crtime: Option<SystemTime>,
@@ -273,105 +1003,149 @@ impl Filesystem for Fuse {
         _flags: Option<u32>,
         reply: fuser::ReplyAttr,
     ) {
-        debug!("setattr not supported!");
-        reply.error(Errno::EROFS as i32)
+        let ttl = self.attr_ttl;
+        upper_op!(
+            self,
+            reply,
+            "setattr",
+            |upper, pfs| upper.setattr(pfs, ino, mode, uid, gid, size, atime, mtime),
+            |attr| reply.attr(&ttl, &attr)
+        )
     }
 
     fn mknod(
         &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _rdev: u32,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
         reply: ReplyEntry,
     ) {
-        debug!("mknod not supported!");
-        reply.error(Errno::EROFS as i32)
+        let ttl = self.entry_ttl;
+        upper_op!(
+            self,
+            reply,
+            "mknod",
+            |upper, pfs| upper.mknod(pfs, parent, name, mode, umask, rdev, req.uid(), req.gid()),
+            |attr| reply.entry(&ttl, &attr, 0)
+        )
     }
 
     fn mkdir(
         &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
-        debug!("mkdir not supported!");
-        reply.error(Errno::EROFS as i32)
+        let ttl = self.entry_ttl;
+        upper_op!(
+            self,
+            reply,
+            "mkdir",
+            |upper, pfs| upper.mkdir(pfs, parent, name, mode, umask, req.uid(), req.gid()),
+            |attr| reply.entry(&ttl, &attr, 0)
+        )
     }
 
-    fn unlink(
-        &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        reply: fuser::ReplyEmpty,
-    ) {
-        debug!("unlink not supported!");
-        reply.error(Errno::EROFS as i32)
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        upper_op!(
+            self,
+            reply,
+            "unlink",
+            |upper, pfs| upper.unlink(pfs, parent, name),
+            |_| reply.ok()
+        )
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: fuser::ReplyEmpty) {
-        debug!("rmdir not supported!");
-        reply.error(Errno::EROFS as i32)
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        upper_op!(
+            self,
+            reply,
+            "rmdir",
+            |upper, pfs| upper.rmdir(pfs, parent, name),
+            |_| reply.ok()
+        )
     }
 
     fn symlink(
         &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _link: &Path,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
         reply: ReplyEntry,
     ) {
-        debug!("symlink not supported!");
-        reply.error(Errno::EROFS as i32)
+        let ttl = self.entry_ttl;
+        upper_op!(
+            self,
+            reply,
+            "symlink",
+            |upper, pfs| upper.symlink(pfs, parent, name, link, req.uid(), req.gid()),
+            |attr| reply.entry(&ttl, &attr, 0)
+        )
     }
 
     fn rename(
         &mut self,
         _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _newparent: u64,
-        _newname: &OsStr,
-        _flags: u32,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("rename not supported!");
-        reply.error(Errno::EROFS as i32)
+        upper_op!(
+            self,
+            reply,
+            "rename",
+            |upper, pfs| upper.rename(pfs, parent, name, newparent, newname, flags),
+            |_| reply.ok()
+        )
     }
 
     fn link(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _newparent: u64,
-        _newname: &OsStr,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        debug!("link not supported!");
-        reply.error(Errno::EROFS as i32)
+        let ttl = self.entry_ttl;
+        upper_op!(
+            self,
+            reply,
+            "link",
+            |upper, pfs| upper.link(pfs, ino, newparent, newname),
+            |attr| reply.entry(&ttl, &attr, 0)
+        )
     }
 
     fn write(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _offset: i64,
-        _data: &[u8],
+        offset: i64,
+        data: &[u8],
         _write_flags: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
-        debug!("write not supported!");
-        reply.error(Errno::EROFS as i32)
+        upper_op!(
+            self,
+            reply,
+            "write",
+            |upper, pfs| upper.write(pfs, ino, offset, data),
+            |written| reply.written(written)
+        )
     }
 
     fn flush(
@@ -413,39 +1187,65 @@ impl Filesystem for Fuse {
     fn setxattr(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _name: &OsStr,
-        _value: &[u8],
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
         _flags: i32,
         _position: u32,
         reply: fuser::ReplyEmpty,
     ) {
-        reply.error(Errno::EROFS as i32)
+        if self.root_xattr(ino, name).is_some() {
+            return reply.error(Errno::EPERM as i32);
+        }
+        upper_op!(
+            self,
+            reply,
+            "setxattr",
+            |upper, pfs| upper.setxattr(pfs, ino, name, value),
+            |_| reply.ok()
+        )
     }
 
     fn removexattr(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _name: &OsStr,
+        ino: u64,
+        name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!("removexattr not supported!");
-        reply.error(Errno::EROFS as i32)
+        if self.root_xattr(ino, name).is_some() {
+            return reply.error(Errno::EPERM as i32);
+        }
+        upper_op!(
+            self,
+            reply,
+            "removexattr",
+            |upper, pfs| upper.removexattr(pfs, ino, name),
+            |_| reply.ok()
+        )
     }
 
     fn create(
         &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _flags: i32,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
         reply: fuser::ReplyCreate,
     ) {
-        debug!("create not supported!");
-        reply.error(Errno::EROFS as i32)
+        let ttl = self.entry_ttl;
+        upper_op!(
+            self,
+            reply,
+            "create",
+            |upper, pfs| upper.create(pfs, parent, name, mode, umask, req.uid(), req.gid()),
+            |attr| {
+                METRICS.handle_opened();
+                reply.created(&ttl, &attr, 0, 0, flags as u32)
+            }
+        )
     }
 
     fn getlk(
@@ -482,66 +1282,72 @@ impl Filesystem for Fuse {
     }
 
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match self._lookup(parent, name) {
+        self.switch_image();
+        self.record(Op::Lookup);
+        match METRICS.timed(Op::Lookup, || self._lookup(parent, name)) {
             Ok(attr) => {
                 // http://libfuse.github.io/doxygen/structfuse__entry__param.html
-                let ttl = Duration::new(u64::MAX, 0);
+                let ttl = self.entry_ttl;
                 let generation = 0;
                 reply.entry(&ttl, &attr, generation)
             }
             Err(e) => {
                 debug!("cannot lookup parent: {parent}, name {name:?} {e}!");
-                reply.error(e.to_errno());
+                reply.error(to_errno(&e));
             }
         }
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
-        match self._getattr(ino) {
+        self.switch_image();
+        match METRICS.timed(Op::Getattr, || self._getattr(ino)) {
             Ok(attr) => {
                 // http://libfuse.github.io/doxygen/structfuse__entry__param.html
-                let ttl = Duration::new(u64::MAX, 0);
+                let ttl = self.attr_ttl;
                 reply.attr(&ttl, &attr)
             }
             Err(e) => {
                 debug!("cannot getattr for ino {ino} {e}!");
-                reply.error(e.to_errno())
+                reply.error(to_errno(&e))
             }
         }
     }
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
-        match self._readlink(ino) {
+        self.switch_image();
+        match METRICS.timed(Op::Readlink, || self._readlink(ino)) {
             Ok(symlink) => reply.data(symlink.as_bytes()),
             Err(e) => {
                 debug!("cannot readlink ino: {ino} {e}!");
-                reply.error(e.to_errno())
+                reply.error(to_errno(&e))
             }
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, flags: i32, reply: ReplyOpen) {
-        self._open(flags, reply)
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.switch_image();
+        self.open_handle(req, ino, flags, reply)
     }
 
     fn read(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
+        self.switch_image();
         // TODO: why i64 from the fuse API here?
         let uoffset: u64 = offset.try_into().unwrap();
-        match self._read(ino, uoffset, size) {
-            Ok(data) => reply.data(data.as_slice()),
+        match METRICS.timed(Op::Read, || self._read(ino, fh, uoffset, size)) {
+            Ok(data) => reply.data(&data),
             Err(e) => {
                 debug!("cannot read ino {ino}, offset: {uoffset} {e}!");
-                reply.error(e.to_errno())
+                reply.error(to_errno(&e))
             }
         }
     }
@@ -550,33 +1356,68 @@ impl Filesystem for Fuse {
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
         // TODO: purge from our cache here? dcache should save us too...
+        self.file_handles.remove(&fh);
+        METRICS.handle_released();
         reply.ok()
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, _ino: u64, flags: i32, reply: ReplyOpen) {
-        self._open(flags, reply)
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.switch_image();
+        match METRICS.timed(Op::Open, || self._opendir(req, ino, flags)) {
+            Ok((fh, flags)) => {
+                METRICS.handle_opened();
+                reply.opened(fh, flags)
+            }
+            Err(e) => {
+                debug!("cannot open directory ino {ino} with flags {flags:#o} {e}!");
+                reply.error(to_errno(&e))
+            }
+        }
     }
 
     fn readdir(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
-        match self._readdir(ino, offset, &mut reply) {
+        self.switch_image();
+        self.record(Op::Readdir);
+        match METRICS.timed(Op::Readdir, || self._readdir(ino, fh, offset, &mut reply)) {
             Ok(_) => reply.ok(),
             Err(e) => {
                 debug!("cannot readdir ino: {ino}, offset {offset} {e}!");
-                reply.error(e.to_errno())
+                reply.error(to_errno(&e))
+            }
+        }
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        self.switch_image();
+        self.record(Op::Readdirplus);
+        match METRICS.timed(Op::Readdirplus, || {
+            self._readdirplus(ino, fh, offset, &mut reply)
+        }) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                debug!("cannot readdirplus ino: {ino}, offset {offset} {e}!");
+                reply.error(to_errno(&e))
             }
         }
     }
@@ -585,25 +1426,33 @@ impl Filesystem for Fuse {
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        // TODO: again maybe purge from cache?
+        self.dir_handles.remove(&fh);
+        METRICS.handle_released();
         reply.ok()
     }
 
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
-        reply.statfs(
-            0,   // blocks
-            0,   // bfree
-            0,   // bavail
-            0,   // files
-            0,   // ffree
-            0,   // bsize
-            256, // namelen
-            0,   // frsize
-        )
+        self.switch_image();
+        match METRICS.timed(Op::Statfs, || self._statfs()) {
+            Ok((blocks, bfree, bavail, files, ffree)) => reply.statfs(
+                blocks,
+                bfree,
+                bavail,
+                files,
+                ffree,
+                STATFS_BLOCK_SIZE as u32, // bsize
+                256,                      // namelen
+                STATFS_BLOCK_SIZE as u32, // frsize
+            ),
+            Err(e) => {
+                debug!("cannot statfs {e}!");
+                reply.error(to_errno(&e))
+            }
+        }
     }
 
     fn getxattr(
@@ -614,7 +1463,8 @@ impl Filesystem for Fuse {
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        match self._getxattr(ino, name) {
+        self.switch_image();
+        match METRICS.timed(Op::Getxattr, || self._getxattr(ino, name)) {
             Ok(xattr) => {
                 let xattr_len: u32 = xattr
                     .len()
@@ -630,13 +1480,14 @@ impl Filesystem for Fuse {
             }
             Err(e) => {
                 debug!("cannot getxattr, ino: {ino}, name {name:?} {e}!");
-                reply.error(e.to_errno())
+                reply.error(to_errno(&e))
             }
         }
     }
 
     fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
-        match self._listxattr(ino) {
+        self.switch_image();
+        match METRICS.timed(Op::Listxattr, || self._listxattr(ino)) {
             Ok(xattr) => {
                 let xattr_len: u32 = xattr
                     .len()
@@ -652,13 +1503,21 @@ impl Filesystem for Fuse {
             }
             Err(e) => {
                 debug!("cannot listxattr, ino {ino}, size {size} {e}!");
-                reply.error(e.to_errno())
+                reply.error(to_errno(&e))
             }
         }
     }
 
-    fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: i32, reply: fuser::ReplyEmpty) {
-        reply.ok()
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        self.switch_image();
+        // F_OK only asks whether the file exists, which the kernel already knows
+        match METRICS.timed(Op::Access, || self._access(req, ino, mask)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                debug!("access denied for ino {ino}, mask {mask} {e}!");
+                reply.error(to_errno(&e))
+            }
+        }
     }
 
     fn bmap(
@@ -678,12 +1537,16 @@ mod tests {
     use std::fs;
     use std::io;
     use std::path::Path;
+    use std::sync::{Arc, Mutex};
 
+    use nix::libc;
     use sha2::{Digest, Sha256};
     use tempfile::tempdir;
 
+    use super::{check_access, image_changes, to_errno, Change, FuseConfig, Op, PuzzleFS};
     use crate::builder::build_test_fs;
-    use crate::oci::Image;
+    use crate::format::WireFormatError;
+    use crate::oci::{Image, ImageError};
 
     #[test]
     fn test_fuse() {
@@ -691,6 +1554,7 @@ mod tests {
         let image = Image::new(dir.path()).unwrap();
         build_test_fs(Path::new("src/builder/test/test-1"), &image, "test").unwrap();
         let mountpoint = tempdir().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
         let _bg = crate::reader::spawn_mount::<&str>(
             image,
             "test",
@@ -699,6 +1563,10 @@ mod tests {
             None,
             None,
             None,
+            &FuseConfig {
+                requests: Some(Arc::clone(&requests)),
+                ..Default::default()
+            },
         )
         .unwrap();
         let ents = fs::read_dir(mountpoint.path())
@@ -710,6 +1578,15 @@ mod tests {
             ents[0].path().strip_prefix(mountpoint.path()).unwrap(),
             Path::new("SekienAkashita.jpg")
         );
+        // the attributes of the entries come along with them, so they aren't looked up again
+        assert_eq!(ents[0].metadata().unwrap().len(), 109466);
+        let requests = requests.lock().unwrap().clone();
+        let listed = requests
+            .iter()
+            .position(|op| *op == Op::Readdirplus)
+            .unwrap();
+        assert!(!requests.contains(&Op::Readdir), "{requests:?}");
+        assert!(!requests[listed..].contains(&Op::Lookup), "{requests:?}");
 
         let mut hasher = Sha256::new();
         let mut f = fs::File::open(ents[0].path()).unwrap();
@@ -718,5 +1595,157 @@ mod tests {
         const FILE_DIGEST: &str =
             "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed";
         assert_eq!(hex::encode(digest), FILE_DIGEST);
+
+        // the root directory and the image, once they're counted
+        let statvfs = || nix::sys::statvfs::statvfs(mountpoint.path()).unwrap();
+        let mut st = statvfs();
+        for _ in 0..100 {
+            if st.files() != 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            st = statvfs();
+        }
+        assert_eq!(st.files(), 2);
+        assert_eq!(
+            st.blocks(),
+            f.metadata().unwrap().len().div_ceil(st.fragment_size())
+        );
+        assert_eq!(st.blocks_free(), 0);
+    }
+
+    #[test]
+    fn test_provenance_xattrs() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test").unwrap();
+        let manifest = image.find_manifest_descriptor("test").unwrap().unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::reader::spawn_mount::<&str>(
+            image,
+            "test",
+            Path::new(mountpoint.path()),
+            &[],
+            None,
+            None,
+            None,
+            &FuseConfig {
+                provenance_xattrs: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let get = |name| xattr::get(mountpoint.path(), name).unwrap();
+        assert_eq!(get("user.puzzlefs.tag"), Some(b"test".to_vec()));
+        assert_eq!(
+            get("user.puzzlefs.manifest_digest"),
+            Some(manifest.digest().to_string().into_bytes())
+        );
+        assert_eq!(get("user.puzzlefs.manifest_verity"), None);
+    }
+
+    #[test]
+    fn test_check_access() {
+        let attr = fuser::FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: std::time::SystemTime::UNIX_EPOCH,
+            mtime: std::time::SystemTime::UNIX_EPOCH,
+            ctime: std::time::SystemTime::UNIX_EPOCH,
+            crtime: std::time::SystemTime::UNIX_EPOCH,
+            kind: fuser::FileType::RegularFile,
+            perm: 0o640,
+            nlink: 0,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        };
+        let (r, w, x) = (libc::R_OK, libc::W_OK, libc::X_OK);
+        check_access(&attr, 1000, &[1000], r | w).unwrap();
+        check_access(&attr, 1000, &[1000], x).unwrap_err();
+        check_access(&attr, 1001, &[100], r).unwrap();
+        check_access(&attr, 1001, &[100], w).unwrap_err();
+        check_access(&attr, 1001, &[1001], r).unwrap_err();
+        check_access(&attr, 1001, &[1001], 0).unwrap();
+        // a supplementary group
+        check_access(&attr, 1001, &[1001, 100], r).unwrap();
+        check_access(&attr, 0, &[0], r | w).unwrap();
+        check_access(&attr, 0, &[0], x).unwrap_err();
+
+        let groups = caller_groups(std::process::id(), 12345);
+        let supplementary = nix::unistd::getgroups().unwrap();
+        assert_eq!(groups[0], 12345);
+        assert_eq!(groups.len(), supplementary.len() + 1);
+        assert!(supplementary
+            .iter()
+            .all(|gid| groups.contains(&gid.as_raw())));
+    }
+
+    #[test]
+    fn test_to_errno() {
+        let errno = |e: ImageError| to_errno(&WireFormatError::from(e));
+        let tag = "missing".to_string();
+        assert_eq!(errno(ImageError::MissingManifest { tag }), libc::ENOENT);
+        let digest = "00".repeat(32);
+        assert_eq!(errno(ImageError::MissingBlob { digest }), libc::EIO);
+        let unsupported = ImageError::UnsupportedManifestVersion {
+            found: 4,
+            supported: 3,
+        };
+        assert_eq!(
+            unsupported.to_string(),
+            "unsupported manifest version 4, expected 3: the image was built by a newer puzzlefs"
+        );
+        assert_eq!(errno(unsupported), libc::ENOTSUP);
+        let e = WireFormatError::from(io::Error::from_raw_os_error(libc::ENOTDIR));
+        assert_eq!(to_errno(&e), libc::ENOTDIR);
+        let e = WireFormatError::from(io::Error::new(io::ErrorKind::Other, "remote"));
+        assert_eq!(to_errno(&e), libc::EIO);
+    }
+
+    #[test]
+    fn test_image_changes() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(&dir.path().join("oci"))?;
+        for (tag, files) in [
+            ("v1", [("a/x", "1"), ("a/y", "y"), ("b/z", "z")]),
+            ("v2", [("a/x", "2"), ("a/y", "y"), ("c", "c")]),
+        ] {
+            let rootfs = dir.path().join(tag);
+            for (path, contents) in files {
+                let path = rootfs.join(path);
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, contents)?;
+            }
+            build_test_fs(&rootfs, &image, tag)?;
+        }
+        let v1 = PuzzleFS::open(Image::open(&dir.path().join("oci"))?, "v1", None)?;
+        let v2 = v1.reopen(&[("v2", None)], false)?;
+        let ino = |pfs: &PuzzleFS, path: &str| pfs.lookup(Path::new(path)).unwrap().unwrap().ino;
+        assert_eq!(ino(&v1, "/a"), ino(&v2, "/a"));
+        assert_eq!(ino(&v1, "/a/x"), ino(&v2, "/a/x"));
+
+        assert_eq!(
+            image_changes(&v1, &v2)?,
+            vec![
+                Change::Dir(1),
+                Change::Entry {
+                    parent: ino(&v1, "/a"),
+                    name: b"x".to_vec(),
+                    ino: ino(&v1, "/a/x"),
+                },
+                Change::Entry {
+                    parent: 1,
+                    name: b"b".to_vec(),
+                    ino: ino(&v1, "/b"),
+                },
+            ]
+        );
+        assert_eq!(image_changes(&v2, &v2)?, vec![]);
+        Ok(())
     }
 }
@@ -0,0 +1,840 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    symlink, DirBuilderExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType, TimeOrNow};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::statvfs::statvfs;
+use nix::sys::time::TimeSpec;
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use tracing::debug;
+
+use super::{inode_attr, mode_to_fuse_type};
+use crate::format::{Inode, InodeMode, Result, WireFormatError};
+use crate::reader::puzzlefs::{file_read, FileReader, PuzzleFS};
+
+// whiteouts and opaque directories use the OCI layer conventions, so the upper directory can be
+// turned into a layer as it is
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+enum Entry {
+    Upper(fs::Metadata),
+    Lower(Inode),
+}
+
+fn errno(e: Errno) -> WireFormatError {
+    WireFormatError::from_errno(e)
+}
+
+fn is_whiteout(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX)
+}
+
+fn system_time(secs: i64, nsecs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(secs.max(0) as u64, nsecs as u32)
+}
+
+fn upper_attr(ino: u64, md: &fs::Metadata) -> Result<FileAttr> {
+    let ft = md.file_type();
+    let kind = if ft.is_dir() {
+        FileType::Directory
+    } else if ft.is_file() {
+        FileType::RegularFile
+    } else if ft.is_symlink() {
+        FileType::Symlink
+    } else if ft.is_fifo() {
+        FileType::NamedPipe
+    } else if ft.is_char_device() {
+        FileType::CharDevice
+    } else if ft.is_block_device() {
+        FileType::BlockDevice
+    } else if ft.is_socket() {
+        FileType::Socket
+    } else {
+        return Err(errno(Errno::EINVAL));
+    };
+
+    Ok(FileAttr {
+        ino,
+        size: md.size(),
+        blocks: md.blocks(),
+        atime: system_time(md.atime(), md.atime_nsec()),
+        mtime: system_time(md.mtime(), md.mtime_nsec()),
+        ctime: system_time(md.ctime(), md.ctime_nsec()),
+        crtime: UNIX_EPOCH,
+        kind,
+        perm: (md.mode() & 0o7777) as u16,
+        nlink: md.nlink() as u32,
+        uid: md.uid(),
+        gid: md.gid(),
+        rdev: md.rdev() as u32,
+        blksize: md.blksize() as u32,
+        flags: 0,
+    })
+}
+
+fn time_spec(time: Option<TimeOrNow>) -> TimeSpec {
+    match time {
+        None => TimeSpec::UTIME_OMIT,
+        Some(TimeOrNow::Now) => TimeSpec::UTIME_NOW,
+        Some(TimeOrNow::SpecificTime(time)) => {
+            TimeSpec::from_duration(time.duration_since(UNIX_EPOCH).unwrap_or_default())
+        }
+    }
+}
+
+// files created through the mount belong to the caller, but only root can give them away
+fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if !Uid::effective().is_root() {
+        return Ok(());
+    }
+    fchownat(
+        None,
+        path,
+        uid.map(Uid::from_raw),
+        gid.map(Gid::from_raw),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+/// A directory-backed, copy-on-write upper layer on top of a puzzlefs image. Modified files are
+/// copied up from the image the first time they are written, deleted files are hidden with
+/// `.wh.<name>` whiteouts.
+///
+/// The kernel only knows inode numbers, so we keep track of the path of each inode it looked up;
+/// files coming from the image keep their puzzlefs inode numbers, new files get numbers above the
+/// image's largest one.
+pub struct UpperLayer {
+    root: PathBuf,
+    paths: HashMap<u64, PathBuf>,
+    upper_inos: HashMap<PathBuf, u64>,
+    next_ino: u64,
+}
+
+impl UpperLayer {
+    pub fn new(root: &Path, pfs: &PuzzleFS) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        let mut paths = HashMap::new();
+        paths.insert(1, PathBuf::from("/"));
+        Ok(UpperLayer {
+            root: root.to_path_buf(),
+            paths,
+            upper_inos: HashMap::new(),
+            next_ino: pfs.max_inode()? + 1,
+        })
+    }
+
+    fn upper_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    fn whiteout_path(&self, path: &Path) -> Option<PathBuf> {
+        let mut whiteout = OsString::from(OsStr::from_bytes(WHITEOUT_PREFIX));
+        whiteout.push(path.file_name()?);
+        Some(self.upper_path(path.parent()?).join(whiteout))
+    }
+
+    fn path(&self, ino: u64) -> Result<PathBuf> {
+        self.paths
+            .get(&ino)
+            .cloned()
+            .ok_or_else(|| errno(Errno::ENOENT))
+    }
+
+    // whether the image's version of `path` is hidden by a whiteout of the path or one of its
+    // ancestors, or by an opaque directory
+    fn lower_hidden(&self, path: &Path) -> bool {
+        path.ancestors().filter(|p| p.parent().is_some()).any(|p| {
+            self.whiteout_path(p).map(|w| w.exists()).unwrap_or(false)
+                || self
+                    .upper_path(p.parent().unwrap())
+                    .join(OPAQUE_MARKER)
+                    .exists()
+        })
+    }
+
+    fn lower(&self, pfs: &PuzzleFS, path: &Path) -> Result<Option<Inode>> {
+        if self.lower_hidden(path) {
+            return Ok(None);
+        }
+        pfs.lookup(path)
+    }
+
+    fn entry(&self, pfs: &PuzzleFS, path: &Path) -> Result<Option<Entry>> {
+        if path.file_name().map(is_whiteout).unwrap_or(false) {
+            return Ok(None);
+        }
+        match fs::symlink_metadata(self.upper_path(path)) {
+            Ok(md) => return Ok(Some(Entry::Upper(md))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(self.lower(pfs, path)?.map(Entry::Lower))
+    }
+
+    fn existing_entry(&self, pfs: &PuzzleFS, path: &Path) -> Result<Entry> {
+        self.entry(pfs, path)?.ok_or_else(|| errno(Errno::ENOENT))
+    }
+
+    fn ino_for(&mut self, pfs: &PuzzleFS, path: &Path, entry: &Entry) -> Result<u64> {
+        let ino = match entry {
+            Entry::Lower(inode) => inode.ino,
+            Entry::Upper(_) => match self.upper_inos.get(path) {
+                Some(ino) => *ino,
+                None => match self.lower(pfs, path)? {
+                    Some(inode) => inode.ino,
+                    None => {
+                        let ino = self.next_ino;
+                        self.next_ino += 1;
+                        self.upper_inos.insert(path.to_path_buf(), ino);
+                        ino
+                    }
+                },
+            },
+        };
+        self.paths.insert(ino, path.to_path_buf());
+        Ok(ino)
+    }
+
+    fn entry_attr(&mut self, pfs: &PuzzleFS, path: &Path, entry: Entry) -> Result<FileAttr> {
+        let ino = self.ino_for(pfs, path, &entry)?;
+        match entry {
+            Entry::Upper(md) => upper_attr(ino, &md),
+            Entry::Lower(inode) => inode_attr(&inode),
+        }
+    }
+
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf> {
+        if is_whiteout(name) {
+            return Err(errno(Errno::EINVAL));
+        }
+        Ok(self.path(parent)?.join(name))
+    }
+
+    pub fn lookup(&mut self, pfs: &PuzzleFS, parent: u64, name: &OsStr) -> Result<FileAttr> {
+        let path = self.path(parent)?.join(name);
+        let entry = self.existing_entry(pfs, &path)?;
+        self.entry_attr(pfs, &path, entry)
+    }
+
+    pub fn getattr(&mut self, pfs: &PuzzleFS, ino: u64) -> Result<FileAttr> {
+        let path = self.path(ino)?;
+        match self.existing_entry(pfs, &path)? {
+            Entry::Upper(md) => upper_attr(ino, &md),
+            Entry::Lower(inode) => inode_attr(&inode),
+        }
+    }
+
+    pub fn readdir(&mut self, pfs: &PuzzleFS, ino: u64) -> Result<Vec<(u64, FileType, OsString)>> {
+        let path = self.path(ino)?;
+        let mut entries = BTreeMap::new();
+
+        let upper_dir = match self.existing_entry(pfs, &path)? {
+            Entry::Upper(md) if !md.is_dir() => return Err(errno(Errno::ENOTDIR)),
+            Entry::Upper(_) => Some(self.upper_path(&path)),
+            Entry::Lower(_) => None,
+        };
+
+        if let Some(InodeMode::Dir { dir_list }) = self.lower(pfs, &path)?.map(|inode| inode.mode) {
+            for dirent in dir_list.entries {
+                let name = OsStr::from_bytes(&dirent.name).to_os_string();
+                let child = path.join(&name);
+                if let Some(Entry::Lower(inode)) = self.entry(pfs, &child)? {
+                    entries.insert(name, (inode.ino, mode_to_fuse_type(&inode)?));
+                }
+            }
+        }
+
+        if let Some(upper_dir) = upper_dir {
+            for dirent in fs::read_dir(upper_dir)? {
+                let dirent = dirent?;
+                let name = dirent.file_name();
+                if is_whiteout(&name) {
+                    continue;
+                }
+                let child = path.join(&name);
+                let md = fs::symlink_metadata(dirent.path())?;
+                let ino = self.ino_for(pfs, &child, &Entry::Upper(md.clone()))?;
+                entries.insert(name, (ino, upper_attr(ino, &md)?.kind));
+            }
+        }
+
+        Ok(entries
+            .into_iter()
+            .map(|(name, (ino, kind))| (ino, kind, name))
+            .collect())
+    }
+
+    /// Returns the free blocks of `block_size` bytes, the ones available to unprivileged users and
+    /// the free inodes of the filesystem holding the upper layer.
+    pub fn free_space(&self, block_size: u64) -> Result<(u64, u64, u64)> {
+        let st = statvfs(&self.root).map_err(WireFormatError::from_errno)?;
+        let to_blocks = |blocks: u64| blocks * st.fragment_size() / block_size;
+        Ok((
+            to_blocks(st.blocks_free()),
+            to_blocks(st.blocks_available()),
+            st.files_free(),
+        ))
+    }
+
+    pub fn readlink(&self, pfs: &PuzzleFS, ino: u64) -> Result<OsString> {
+        let path = self.path(ino)?;
+        match self.existing_entry(pfs, &path)? {
+            Entry::Upper(_) => Ok(fs::read_link(self.upper_path(&path))?.into_os_string()),
+            Entry::Lower(inode) => Ok(inode.symlink_target()?.to_os_string()),
+        }
+    }
+
+    pub fn read(&self, pfs: &PuzzleFS, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        let path = self.path(ino)?;
+        let mut buf = vec![0_u8; size as usize];
+        let read = match self.existing_entry(pfs, &path)? {
+            Entry::Upper(_) => {
+                let file = fs::File::open(self.upper_path(&path))?;
+                let mut read = 0;
+                while read < buf.len() {
+                    let n = file.read_at(&mut buf[read..], offset + read as u64)?;
+                    if n == 0 {
+                        break;
+                    }
+                    read += n;
+                }
+                read
+            }
+            Entry::Lower(inode) => file_read(
+                &pfs.oci,
+                &inode,
+                offset as usize,
+                &mut buf,
+                &pfs.verity_data,
+            )?,
+        };
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    // copies `path` and its ancestors from the image to the upper layer, unless they're there
+    fn copy_up(&self, pfs: &PuzzleFS, path: &Path) -> Result<()> {
+        let upper = self.upper_path(path);
+        if fs::symlink_metadata(&upper).is_ok() {
+            return Ok(());
+        }
+        let inode = self.lower(pfs, path)?.ok_or_else(|| errno(Errno::ENOENT))?;
+        if let Some(parent) = path.parent() {
+            self.copy_up(pfs, parent)?;
+        }
+
+        debug!("copying up {}", path.display());
+        let mode = Mode::from_bits_truncate(inode.permissions.into());
+        match &inode.mode {
+            InodeMode::Dir { .. } => fs::create_dir(&upper)?,
+            InodeMode::File { .. } => {
+                let mut file = fs::File::create(&upper)?;
+                io::copy(&mut FileReader::new(&pfs.oci, &inode)?, &mut file)?;
+            }
+            InodeMode::Lnk => symlink(inode.symlink_target()?, &upper)?,
+            InodeMode::Fifo => mknod(&upper, SFlag::S_IFIFO, mode, 0)?,
+            InodeMode::Sock => mknod(&upper, SFlag::S_IFSOCK, mode, 0)?,
+            InodeMode::Chr { major, minor } => mknod(
+                &upper,
+                SFlag::S_IFCHR,
+                mode,
+                nix::sys::stat::makedev(*major, *minor),
+            )?,
+            InodeMode::Blk { major, minor } => mknod(
+                &upper,
+                SFlag::S_IFBLK,
+                mode,
+                nix::sys::stat::makedev(*major, *minor),
+            )?,
+            InodeMode::Unknown | InodeMode::Wht => return Err(errno(Errno::EINVAL)),
+        }
+
+        if !matches!(inode.mode, InodeMode::Lnk) {
+            fs::set_permissions(&upper, Permissions::from_mode(inode.permissions.into()))?;
+        }
+        chown(&upper, Some(inode.uid), Some(inode.gid))?;
+        if let Some(additional) = &inode.additional {
+            for x in &additional.xattrs {
+                // e.g. trusted.* xattrs can't be set by regular users, losing them is no reason
+                // to fail the write
+                if let Err(e) = xattr::set(&upper, OsStr::from_bytes(&x.key), &x.val) {
+                    debug!("cannot copy up xattr {:?}: {e}", OsStr::from_bytes(&x.key));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn whiteout(&self, pfs: &PuzzleFS, path: &Path) -> Result<()> {
+        if self.lower(pfs, path)?.is_some() {
+            if let Some(parent) = path.parent() {
+                self.copy_up(pfs, parent)?;
+            }
+            if let Some(whiteout) = self.whiteout_path(path) {
+                fs::File::create(whiteout)?;
+            }
+        }
+        Ok(())
+    }
+
+    // a new entry replaces the whiteout of its name; a new directory is opaque, so the contents
+    // of the image's directory which was deleted don't show up again
+    fn drop_whiteout(&self, path: &Path) -> Result<()> {
+        let Some(whiteout) = self.whiteout_path(path) else {
+            return Ok(());
+        };
+        match fs::remove_file(whiteout) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let upper = self.upper_path(path);
+        if fs::symlink_metadata(&upper)?.is_dir() {
+            fs::File::create(upper.join(OPAQUE_MARKER))?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, pfs: &PuzzleFS, ino: u64, offset: i64, data: &[u8]) -> Result<u32> {
+        let offset = u64::try_from(offset).map_err(|_| errno(Errno::EINVAL))?;
+        let path = self.path(ino)?;
+        self.copy_up(pfs, &path)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(self.upper_path(&path))?;
+        file.write_all_at(data, offset)?;
+        Ok(data.len().try_into()?)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn setattr(
+        &mut self,
+        pfs: &PuzzleFS,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<FileAttr> {
+        let path = self.path(ino)?;
+        self.copy_up(pfs, &path)?;
+        let upper = self.upper_path(&path);
+
+        if let Some(mode) = mode {
+            fs::set_permissions(&upper, Permissions::from_mode(mode & 0o7777))?;
+        }
+        if uid.is_some() || gid.is_some() {
+            if !Uid::effective().is_root() {
+                return Err(errno(Errno::EPERM));
+            }
+            chown(&upper, uid, gid)?;
+        }
+        if let Some(size) = size {
+            OpenOptions::new().write(true).open(&upper)?.set_len(size)?;
+        }
+        if atime.is_some() || mtime.is_some() {
+            utimensat(
+                None,
+                &upper,
+                &time_spec(atime),
+                &time_spec(mtime),
+                UtimensatFlags::NoFollowSymlink,
+            )?;
+        }
+
+        self.getattr(pfs, ino)
+    }
+
+    // prepares the creation of `name` in `parent`, returning the path of the new entry
+    fn prepare_create(&self, pfs: &PuzzleFS, parent: u64, name: &OsStr) -> Result<PathBuf> {
+        let path = self.child_path(parent, name)?;
+        if self.entry(pfs, &path)?.is_some() {
+            return Err(errno(Errno::EEXIST));
+        }
+        self.copy_up(pfs, &self.path(parent)?)?;
+        Ok(path)
+    }
+
+    fn created(&mut self, pfs: &PuzzleFS, path: &Path, uid: u32, gid: u32) -> Result<FileAttr> {
+        chown(&self.upper_path(path), Some(uid), Some(gid))?;
+        self.drop_whiteout(path)?;
+        let entry = self.existing_entry(pfs, path)?;
+        self.entry_attr(pfs, path, entry)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        &mut self,
+        pfs: &PuzzleFS,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr> {
+        let path = self.prepare_create(pfs, parent, name)?;
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode & !umask & 0o7777)
+            .open(self.upper_path(&path))?;
+        self.created(pfs, &path, uid, gid)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn mkdir(
+        &mut self,
+        pfs: &PuzzleFS,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr> {
+        let path = self.prepare_create(pfs, parent, name)?;
+        fs::DirBuilder::new()
+            .mode(mode & !umask & 0o7777)
+            .create(self.upper_path(&path))?;
+        self.created(pfs, &path, uid, gid)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn mknod(
+        &mut self,
+        pfs: &PuzzleFS,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr> {
+        let path = self.prepare_create(pfs, parent, name)?;
+        mknod(
+            &self.upper_path(&path),
+            SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits()),
+            Mode::from_bits_truncate(mode & !umask & 0o7777),
+            rdev.into(),
+        )?;
+        self.created(pfs, &path, uid, gid)
+    }
+
+    pub fn symlink(
+        &mut self,
+        pfs: &PuzzleFS,
+        parent: u64,
+        name: &OsStr,
+        target: &Path,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr> {
+        let path = self.prepare_create(pfs, parent, name)?;
+        symlink(target, self.upper_path(&path))?;
+        self.created(pfs, &path, uid, gid)
+    }
+
+    pub fn link(
+        &mut self,
+        pfs: &PuzzleFS,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<FileAttr> {
+        let source = self.path(ino)?;
+        let path = self.prepare_create(pfs, newparent, newname)?;
+        self.copy_up(pfs, &source)?;
+        fs::hard_link(self.upper_path(&source), self.upper_path(&path))?;
+        self.drop_whiteout(&path)?;
+        // both names are the same inode
+        self.upper_inos.insert(path, ino);
+        self.getattr(pfs, ino)
+    }
+
+    pub fn unlink(&mut self, pfs: &PuzzleFS, parent: u64, name: &OsStr) -> Result<()> {
+        let path = self.child_path(parent, name)?;
+        match self.existing_entry(pfs, &path)? {
+            Entry::Upper(md) if md.is_dir() => return Err(errno(Errno::EISDIR)),
+            Entry::Lower(inode) if matches!(inode.mode, InodeMode::Dir { .. }) => {
+                return Err(errno(Errno::EISDIR))
+            }
+            Entry::Upper(_) => fs::remove_file(self.upper_path(&path))?,
+            Entry::Lower(_) => (),
+        }
+        self.whiteout(pfs, &path)?;
+        self.upper_inos.remove(&path);
+        Ok(())
+    }
+
+    pub fn rmdir(&mut self, pfs: &PuzzleFS, parent: u64, name: &OsStr) -> Result<()> {
+        let path = self.child_path(parent, name)?;
+        let entry = self.existing_entry(pfs, &path)?;
+        let ino = self.ino_for(pfs, &path, &entry)?;
+        match entry {
+            Entry::Upper(md) if !md.is_dir() => return Err(errno(Errno::ENOTDIR)),
+            Entry::Lower(inode) if !matches!(inode.mode, InodeMode::Dir { .. }) => {
+                return Err(errno(Errno::ENOTDIR))
+            }
+            _ => (),
+        }
+        if !self.readdir(pfs, ino)?.is_empty() {
+            return Err(errno(Errno::ENOTEMPTY));
+        }
+
+        // the upper directory may still contain whiteouts
+        let upper = self.upper_path(&path);
+        if upper.exists() {
+            fs::remove_dir_all(upper)?;
+        }
+        self.whiteout(pfs, &path)?;
+        self.upper_inos.remove(&path);
+        Ok(())
+    }
+
+    pub fn rename(
+        &mut self,
+        pfs: &PuzzleFS,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        // RENAME_EXCHANGE and RENAME_WHITEOUT aren't supported
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(errno(Errno::EINVAL));
+        }
+        let source = self.child_path(parent, name)?;
+        let target = self.child_path(newparent, newname)?;
+        let entry = self.existing_entry(pfs, &source)?;
+        let ino = self.ino_for(pfs, &source, &entry)?;
+        let source_is_dir = match &entry {
+            Entry::Upper(md) => md.is_dir(),
+            Entry::Lower(inode) => matches!(inode.mode, InodeMode::Dir { .. }),
+        };
+        // like overlayfs without redirect_dir: userspace falls back to copying the directory
+        if source_is_dir && self.lower(pfs, &source)?.is_some() {
+            return Err(errno(Errno::EXDEV));
+        }
+
+        if let Some(target_entry) = self.entry(pfs, &target)? {
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(errno(Errno::EEXIST));
+            }
+            let target_is_dir = match &target_entry {
+                Entry::Upper(md) => md.is_dir(),
+                Entry::Lower(inode) => matches!(inode.mode, InodeMode::Dir { .. }),
+            };
+            match (source_is_dir, target_is_dir) {
+                (true, false) => return Err(errno(Errno::ENOTDIR)),
+                (false, true) => return Err(errno(Errno::EISDIR)),
+                (true, true) => {
+                    let target_ino = self.ino_for(pfs, &target, &target_entry)?;
+                    if !self.readdir(pfs, target_ino)?.is_empty() {
+                        return Err(errno(Errno::ENOTEMPTY));
+                    }
+                    if let Entry::Upper(_) = target_entry {
+                        fs::remove_dir_all(self.upper_path(&target))?;
+                    }
+                }
+                (false, false) => (),
+            }
+            // the renamed entry must hide whatever the image has at the target
+            self.whiteout(pfs, &target)?;
+        }
+
+        self.copy_up(pfs, &source)?;
+        self.copy_up(pfs, &self.path(newparent)?)?;
+        fs::rename(self.upper_path(&source), self.upper_path(&target))?;
+        self.drop_whiteout(&target)?;
+        self.whiteout(pfs, &source)?;
+
+        // the inode keeps its number, and so do the ones below it if it's a directory
+        for path in self.paths.values_mut() {
+            if let Ok(rest) = path.strip_prefix(&source) {
+                *path = target.join(rest);
+            }
+        }
+        self.upper_inos = self
+            .upper_inos
+            .drain()
+            .map(|(path, ino)| match path.strip_prefix(&source) {
+                Ok(rest) => (target.join(rest), ino),
+                Err(_) => (path, ino),
+            })
+            .collect();
+        self.upper_inos.insert(target.clone(), ino);
+        self.paths.insert(ino, target);
+        Ok(())
+    }
+
+    pub fn getxattr(&self, pfs: &PuzzleFS, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        let path = self.path(ino)?;
+        let value = match self.existing_entry(pfs, &path)? {
+            Entry::Upper(_) => xattr::get(self.upper_path(&path), name)?,
+            Entry::Lower(inode) => inode.additional.and_then(|add| {
+                add.xattrs
+                    .into_iter()
+                    .find(|x| x.key == name.as_bytes())
+                    .map(|x| x.val)
+            }),
+        };
+        value.ok_or_else(|| errno(Errno::ENODATA))
+    }
+
+    pub fn listxattr(&self, pfs: &PuzzleFS, ino: u64) -> Result<Vec<u8>> {
+        let path = self.path(ino)?;
+        let names = match self.existing_entry(pfs, &path)? {
+            Entry::Upper(_) => xattr::list(self.upper_path(&path))?
+                .map(|name| name.as_bytes().to_vec())
+                .collect::<Vec<_>>(),
+            Entry::Lower(inode) => inode
+                .additional
+                .map(|add| add.xattrs.into_iter().map(|x| x.key).collect())
+                .unwrap_or_default(),
+        };
+        Ok(names
+            .into_iter()
+            .flat_map(|mut name| {
+                name.push(0);
+                name
+            })
+            .collect())
+    }
+
+    pub fn setxattr(&self, pfs: &PuzzleFS, ino: u64, name: &OsStr, value: &[u8]) -> Result<()> {
+        let path = self.path(ino)?;
+        self.copy_up(pfs, &path)?;
+        xattr::set(self.upper_path(&path), name, value)?;
+        Ok(())
+    }
+
+    pub fn removexattr(&self, pfs: &PuzzleFS, ino: u64, name: &OsStr) -> Result<()> {
+        let path = self.path(ino)?;
+        self.copy_up(pfs, &path)?;
+        xattr::remove(self.upper_path(&path), name)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use super::OPAQUE_MARKER;
+    use crate::builder::build_test_fs;
+    use crate::oci::Image;
+    use crate::reader::fuse::FuseConfig;
+
+    #[test]
+    fn test_writable_fuse() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test").unwrap();
+        let mountpoint = tempdir().unwrap();
+        let upper = tempdir().unwrap();
+        let config = FuseConfig {
+            upper_dir: Some(upper.path().to_path_buf()),
+            ..Default::default()
+        };
+        let _bg = crate::reader::spawn_mount::<&str>(
+            image,
+            "test",
+            mountpoint.path(),
+            &[],
+            None,
+            None,
+            None,
+            &config,
+        )
+        .unwrap();
+
+        let jpg = mountpoint.path().join("SekienAkashita.jpg");
+        let original = fs::read(&jpg).unwrap();
+
+        // new files only go to the upper directory
+        fs::write(mountpoint.path().join("foo"), b"bar").unwrap();
+        assert_eq!(fs::read(upper.path().join("foo")).unwrap(), b"bar");
+
+        // modified files are copied up
+        fs::rename(&jpg, mountpoint.path().join("renamed.jpg")).unwrap();
+        assert_eq!(
+            fs::read(mountpoint.path().join("renamed.jpg")).unwrap(),
+            original
+        );
+        assert!(upper.path().join(".wh.SekienAkashita.jpg").exists());
+
+        fs::remove_file(mountpoint.path().join("renamed.jpg")).unwrap();
+
+        // creating a deleted file again replaces its whiteout
+        fs::write(&jpg, b"new").unwrap();
+        assert!(!upper.path().join(".wh.SekienAkashita.jpg").exists());
+        assert_eq!(fs::read(&jpg).unwrap(), b"new");
+        fs::remove_file(&jpg).unwrap();
+        assert!(upper.path().join(".wh.SekienAkashita.jpg").exists());
+
+        let mut names = fs::read_dir(mountpoint.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["foo"]);
+    }
+
+    #[test]
+    fn test_recreated_dir() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        fs::write(rootfs.join("dir/file"), b"lower").unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        build_test_fs(&rootfs, &image, "test").unwrap();
+        let mountpoint = tempdir().unwrap();
+        let upper = tempdir().unwrap();
+        let config = FuseConfig {
+            upper_dir: Some(upper.path().to_path_buf()),
+            ..Default::default()
+        };
+        let _bg = crate::reader::spawn_mount::<&str>(
+            image,
+            "test",
+            mountpoint.path(),
+            &[],
+            None,
+            None,
+            None,
+            &config,
+        )
+        .unwrap();
+
+        // the directory created again replaces the whiteout, without the files of the image's
+        let recreated = mountpoint.path().join("dir");
+        fs::remove_file(recreated.join("file")).unwrap();
+        fs::remove_dir(&recreated).unwrap();
+        assert!(upper.path().join(".wh.dir").exists());
+        fs::create_dir(&recreated).unwrap();
+        assert!(!upper.path().join(".wh.dir").exists());
+        assert!(upper.path().join("dir").join(OPAQUE_MARKER).exists());
+        assert_eq!(fs::read_dir(&recreated).unwrap().count(), 0);
+        assert!(!recreated.join("file").exists());
+    }
+}
@@ -0,0 +1,138 @@
+//! Mounts images with the in-kernel puzzlefs driver, for the kernels which have it.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use nix::mount::MsFlags;
+
+use super::MountError;
+use crate::format::Result;
+use crate::oci::{Image, ImageError};
+
+pub const FILESYSTEM_TYPE: &str = "puzzlefs";
+
+// whether /proc/filesystems lists the driver; it's a nodev filesystem, so its lines look like
+// "nodev\tpuzzlefs"
+fn lists_driver(filesystems: &str) -> bool {
+    filesystems
+        .lines()
+        .any(|line| line.split_whitespace().last() == Some(FILESYSTEM_TYPE))
+}
+
+/// Whether the running kernel has the puzzlefs driver, built in or loaded.
+pub fn driver_available() -> bool {
+    fs::read_to_string("/proc/filesystems")
+        .map(|filesystems| lists_driver(&filesystems))
+        .unwrap_or(false)
+}
+
+/// The mount options which make the driver mount `tag`.
+pub fn mount_data(
+    oci_dir: &Path,
+    image: &Image,
+    tag: &str,
+    manifest_verity: Option<&[u8]>,
+) -> Result<String> {
+    let oci_dir = fs::canonicalize(oci_dir)?;
+    let oci_dir = oci_dir.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} isn't valid UTF-8", oci_dir.display()),
+        )
+    })?;
+    // the options are separated by commas, and the kernel doesn't unescape them
+    if oci_dir.contains(',') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{oci_dir} has a comma, which mount options can't have"),
+        )
+        .into());
+    }
+    let manifest =
+        image
+            .find_manifest_descriptor(tag)?
+            .ok_or_else(|| ImageError::MissingManifest {
+                tag: tag.to_string(),
+            })?;
+    let mut data = format!(
+        "oci_root_dir={oci_dir},image_manifest={}",
+        manifest.digest().digest()
+    );
+    if let Some(verity) = manifest_verity {
+        data.push_str(&format!(",verity_root_hash={}", hex::encode(verity)));
+    }
+    Ok(data)
+}
+
+/// Mounts `tag` on `mountpoint` with the kernel driver, read only, and `nodev` unless
+/// `allow_devices`. The driver only checks fs-verity given the digest of the manifest, so the
+/// manifests which require fs-verity can't be mounted without it.
+pub fn mount(
+    oci_dir: &Path,
+    image: &Image,
+    tag: &str,
+    mountpoint: &Path,
+    manifest_verity: Option<&[u8]>,
+    allow_devices: bool,
+) -> Result<()> {
+    if manifest_verity.is_none() && image.requires_verity(tag)? {
+        return Err(MountError::VerityRequired {
+            tag: tag.to_string(),
+        }
+        .into());
+    }
+    let data = mount_data(oci_dir, image, tag, manifest_verity)?;
+    let mut flags = MsFlags::MS_RDONLY;
+    if !allow_devices {
+        flags |= MsFlags::MS_NODEV;
+    }
+    nix::mount::mount(
+        Some(FILESYSTEM_TYPE),
+        mountpoint,
+        Some(FILESYSTEM_TYPE),
+        flags,
+        Some(data.as_str()),
+    )
+    .map_err(io::Error::from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_lists_driver() {
+        assert!(lists_driver("nodev\tsysfs\nnodev\tpuzzlefs\n\text4\n"));
+        assert!(!lists_driver("nodev\tsysfs\n\text4\nnodev\tfuse\n"));
+        assert!(!lists_driver("nodev\tpuzzlefs2\n"));
+    }
+
+    #[test]
+    fn test_mount_data() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let manifest = image.find_manifest_descriptor("test")?.unwrap();
+        let oci_dir = fs::canonicalize(dir.path())?;
+
+        assert_eq!(
+            mount_data(dir.path(), &image, "test", None)?,
+            format!(
+                "oci_root_dir={},image_manifest={}",
+                oci_dir.display(),
+                manifest.digest().digest()
+            )
+        );
+        assert!(mount_data(dir.path(), &image, "test", Some(&[0xab; 32]))?
+            .ends_with(&format!(",verity_root_hash={}", "ab".repeat(32))));
+        assert!(mount_data(dir.path(), &image, "missing", None).is_err());
+
+        let comma = dir.path().join("a,b");
+        fs::create_dir(&comma)?;
+        assert!(mount_data(&comma, &image, "test", None).is_err());
+        Ok(())
+    }
+}
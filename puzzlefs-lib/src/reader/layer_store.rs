@@ -0,0 +1,121 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ocidir::oci_spec::image::MediaType;
+use serde::Serialize;
+use tracing::info;
+
+use super::{spawn_mount, BackgroundSession, FuseConfig};
+use crate::format::Result;
+use crate::oci::media_types::PUZZLEFS_ROOTFS;
+use crate::oci::{Image, ImageError};
+
+/// The layer metadata containers/storage expects in the `info` file of a layer, a subset of its
+/// `Layer` struct, plus the media type of the layer blob, which isn't a tar but a puzzlefs rootfs.
+#[derive(Debug, Serialize)]
+struct LayerInfo {
+    id: String,
+    #[serde(rename = "media-type")]
+    media_type: String,
+    #[serde(rename = "compressed-diff-digest")]
+    compressed_diff_digest: String,
+    #[serde(rename = "compressed-size")]
+    compressed_size: u64,
+    #[serde(rename = "diff-digest")]
+    diff_digest: String,
+    #[serde(rename = "diff-size")]
+    diff_size: u64,
+    // 0 means uncompressed
+    compression: u8,
+}
+
+/// An "additional layer store" for containers/storage (podman, CRI-O, buildah), which lets them
+/// use puzzlefs images without pulling and unpacking them.
+///
+/// containers/storage looks up layers as `<root>/<base64(image reference)>/<layer digest>/`, and
+/// expects to find there the layer's contents in `diff`, its metadata in `info` and the layer
+/// blob itself in `blob`. A puzzlefs image has a single layer, its rootfs, whose `diff` is the
+/// mounted image and whose `blob` is the rootfs blob, reported with its own media type. The
+/// mounts go away when the store is dropped.
+pub struct LayerStore {
+    root: PathBuf,
+    sessions: Vec<BackgroundSession>,
+}
+
+impl LayerStore {
+    pub fn new(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(LayerStore {
+            root: root.to_path_buf(),
+            sessions: Vec::new(),
+        })
+    }
+
+    /// Exposes the image tagged `tag` in `oci_dir` as the image `reference` (e.g.
+    /// `docker.io/library/ubuntu:latest`), returning the layer directory.
+    pub fn add_image<T: AsRef<str>>(
+        &mut self,
+        oci_dir: &Path,
+        tag: &str,
+        reference: &str,
+        options: &[T],
+    ) -> Result<PathBuf> {
+        let oci_dir = fs::canonicalize(oci_dir)?;
+        let image = Image::open(&oci_dir)?;
+        let manifest = image
+            .find_manifest(tag)?
+            .ok_or_else(|| ImageError::MissingManifest {
+                tag: tag.to_string(),
+            })?;
+        let rootfs = manifest
+            .layers()
+            .iter()
+            .find(|desc| desc.media_type() == &MediaType::Other(PUZZLEFS_ROOTFS.to_string()))
+            .ok_or_else(|| ImageError::MissingRootfs {
+                tag: tag.to_string(),
+            })?;
+
+        let digest = rootfs.digest().to_string();
+        let layer_dir = self.root.join(STANDARD.encode(reference)).join(&digest);
+        let diff = layer_dir.join("diff");
+        fs::create_dir_all(&diff)?;
+
+        let layer_info = LayerInfo {
+            id: rootfs.digest().digest().to_string(),
+            media_type: rootfs.media_type().to_string(),
+            compressed_diff_digest: digest.clone(),
+            compressed_size: rootfs.size(),
+            diff_digest: digest,
+            diff_size: rootfs.size(),
+            compression: 0,
+        };
+        fs::write(layer_dir.join("info"), serde_json::to_vec(&layer_info)?)?;
+
+        let blob = layer_dir.join("blob");
+        if !blob.exists() {
+            symlink(
+                oci_dir
+                    .join(Image::blob_path())
+                    .join(rootfs.digest().digest()),
+                &blob,
+            )?;
+        }
+
+        let session = spawn_mount(
+            image,
+            tag,
+            &diff,
+            options,
+            None,
+            None,
+            None,
+            &FuseConfig::default(),
+        )?;
+        self.sessions.push(session);
+        info!("exposing {}:{tag} as {reference}", oci_dir.display());
+        Ok(layer_dir)
+    }
+}
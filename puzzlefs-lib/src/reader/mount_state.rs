@@ -0,0 +1,284 @@
+//! The state files of mounts, so they can be taken down again.
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use nix::libc;
+use nix::sched::{setns, CloneFlags};
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use super::control;
+use crate::common::mountpoints;
+use crate::format::{Result, WireFormatError};
+
+/// How the image is mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountKind {
+    /// By a FUSE daemon.
+    Fuse,
+    /// By the puzzlefs kernel driver.
+    Kernel,
+    /// By a FUSE daemon, below a kernel overlay making it writable.
+    Overlay,
+    /// By a FUSE daemon, below fuse-overlayfs making it writable without root.
+    FuseOverlayfs,
+}
+
+/// What was mounted at a mountpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MountState {
+    pub kind: MountKind,
+    pub mountpoint: PathBuf,
+    pub oci_dir: PathBuf,
+    pub tag: String,
+    /// The FUSE daemon serving the image, or its supervisor.
+    pub pid: Option<u32>,
+    /// Where the image itself is mounted: the mountpoint, or the lower directory of the overlay.
+    pub image_mountpoint: PathBuf,
+    /// The upper and work directories of the overlay.
+    pub upper_dir: Option<PathBuf>,
+    pub work_dir: Option<PathBuf>,
+    /// The user and mount namespaces the mount was made in, as named in `/proc/<pid>/ns`.
+    pub user_ns: String,
+    pub mnt_ns: String,
+}
+
+/// Where the state files live: a directory next to the control sockets.
+pub fn state_dir() -> Result<PathBuf> {
+    Ok(control::socket_dir()?.join("mounts"))
+}
+
+fn state_path(dir: &Path, mountpoint: &Path) -> PathBuf {
+    // a path can be longer than a file name
+    let digest = Sha256::digest(mountpoint.as_os_str().as_bytes());
+    dir.join(format!("{}.json", hex::encode(digest)))
+}
+
+fn namespace(pid: &str, kind: &str) -> io::Result<String> {
+    let link = fs::read_link(format!("/proc/{pid}/ns/{kind}"))?;
+    Ok(link.to_string_lossy().into_owned())
+}
+
+// a process living in the mount namespace `mnt_ns`, the only way to reach it
+fn namespace_holder(mnt_ns: &str) -> io::Result<Option<String>> {
+    Ok(fs::read_dir("/proc")?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|pid| pid.bytes().all(|b| b.is_ascii_digit()))
+        .find(|pid| namespace(pid, "mnt").is_ok_and(|ns| ns == mnt_ns)))
+}
+
+// the state directory and files must belong to the effective user, and nobody else may write
+// to them
+fn check_private(path: &Path, md: &fs::Metadata) -> io::Result<()> {
+    let uid = Uid::effective();
+    if md.uid() != uid.as_raw() || md.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must belong to uid {uid} and be writable by it alone",
+                path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// checks `dir`, false if it doesn't exist
+fn check_dir(dir: &Path) -> io::Result<bool> {
+    match fs::symlink_metadata(dir) {
+        Ok(md) if md.is_dir() => check_private(dir, &md).map(|()| true),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} isn't a directory", dir.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_state(path: &Path) -> Result<MountState> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    check_private(path, &file.metadata()?)?;
+    let mut state = Vec::new();
+    file.read_to_end(&mut state)?;
+    Ok(serde_json::from_slice(&state)?)
+}
+
+impl MountState {
+    /// The state of a mount of `tag` made by this process, in its namespaces.
+    pub fn new(
+        kind: MountKind,
+        mountpoint: &Path,
+        image_mountpoint: &Path,
+        oci_dir: &Path,
+        tag: &str,
+    ) -> Result<Self> {
+        Ok(MountState {
+            kind,
+            mountpoint: mountpoint.to_path_buf(),
+            oci_dir: oci_dir.to_path_buf(),
+            tag: tag.to_string(),
+            pid: None,
+            image_mountpoint: image_mountpoint.to_path_buf(),
+            upper_dir: None,
+            work_dir: None,
+            user_ns: namespace("self", "user")?,
+            mnt_ns: namespace("self", "mnt")?,
+        })
+    }
+
+    /// Writes the state file of the mountpoint in `dir`, replacing the one of a previous mount.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        check_dir(dir)?;
+        let path = state_path(dir, &self.mountpoint);
+        let tmp = path.with_extension("tmp");
+        let _ = fs::remove_file(&tmp);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// The state recorded in `dir` for `mountpoint`, if any.
+    pub fn load(dir: &Path, mountpoint: &Path) -> Result<Option<Self>> {
+        if !check_dir(dir)? {
+            return Ok(None);
+        }
+        match read_state(&state_path(dir, mountpoint)) {
+            Ok(state) => Ok(Some(state)),
+            Err(WireFormatError::IOError(e, _)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the mount is gone: its mount namespace has no process left, or it's ours and
+    /// nothing is mounted at the mountpoint anymore.
+    pub fn is_stale(&self) -> Result<bool> {
+        if namespace("self", "mnt")? == self.mnt_ns {
+            return Ok(!mountpoints()?.contains(&self.mountpoint));
+        }
+        Ok(namespace_holder(&self.mnt_ns)?.is_none())
+    }
+
+    /// Removes the state files of `dir` whose mounts are gone, e.g. because their namespace
+    /// died with them or they were unmounted without puzzlefs.
+    pub fn remove_stale(dir: &Path) -> Result<()> {
+        if !check_dir(dir)? {
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            let state = read_state(&path)?;
+            if state.is_stale()? {
+                info!(
+                    "removing the state of {}, which isn't mounted anymore",
+                    state.mountpoint.display()
+                );
+                state.remove(dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, dir: &Path) -> Result<()> {
+        match fs::remove_file(state_path(dir, &self.mountpoint)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves this process to the user and mount namespaces the mount was made in, if it isn't
+    /// there already, so that it can be unmounted; returns whether it moved. The process must be
+    /// single threaded.
+    pub fn enter_namespaces(&self) -> Result<bool> {
+        if namespace("self", "mnt")? == self.mnt_ns {
+            return Ok(false);
+        }
+        let Some(pid) = namespace_holder(&self.mnt_ns)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no process is left in {}, where {} was mounted",
+                    self.mnt_ns,
+                    self.mountpoint.display()
+                ),
+            )
+            .into());
+        };
+        if namespace(&pid, "user")? != namespace("self", "user")? {
+            let user_ns = fs::File::open(format!("/proc/{pid}/ns/user"))?;
+            setns(user_ns, CloneFlags::CLONE_NEWUSER).map_err(WireFormatError::from_errno)?;
+        }
+        let mnt_ns = fs::File::open(format!("/proc/{pid}/ns/mnt"))?;
+        setns(mnt_ns, CloneFlags::CLONE_NEWNS).map_err(WireFormatError::from_errno)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_mount_state() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let mountpoint = Path::new("/mnt/image");
+        let mut state = MountState::new(
+            MountKind::Overlay,
+            mountpoint,
+            &mountpoint.join("ro"),
+            Path::new("/var/lib/images"),
+            "v1",
+        )?;
+        state.upper_dir = Some(PathBuf::from("/var/lib/upper"));
+        assert_eq!(MountState::load(dir.path(), mountpoint)?, None);
+
+        state.save(dir.path())?;
+        assert_eq!(
+            MountState::load(dir.path(), mountpoint)?,
+            Some(state.clone())
+        );
+        assert_eq!(MountState::load(dir.path(), Path::new("/mnt"))?, None);
+        // we're in the namespaces we just recorded
+        assert!(!state.enter_namespaces()?);
+
+        // nothing is mounted at /mnt/image
+        assert!(state.is_stale()?);
+        MountState::remove_stale(dir.path())?;
+        assert_eq!(MountState::load(dir.path(), mountpoint)?, None);
+        state.remove(dir.path())?;
+
+        // files others can write are refused
+        state.save(dir.path())?;
+        let path = state_path(dir.path(), mountpoint);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+        assert!(MountState::load(dir.path(), mountpoint).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777))?;
+        assert!(MountState::load(dir.path(), mountpoint).is_err());
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))?;
+        Ok(())
+    }
+}
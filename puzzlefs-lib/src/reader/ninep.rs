@@ -0,0 +1,703 @@
+//! Exports an image over 9P2000.L, for the hosts which don't allow FUSE.
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+use std::thread;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::stat::{makedev, SFlag};
+use tracing::{debug, info, warn};
+
+use crate::format::{Ino, Inode, InodeMode, Result, WireFormatError};
+
+use super::fuse::to_errno;
+use super::puzzlefs::{file_read, PuzzleFS};
+
+const VERSION: &str = "9P2000.L";
+// the client picks the smaller of this and its own maximum
+const MAX_MSIZE: u32 = 1 << 20;
+// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+// the header of Rread and Rreaddir, the rest of the message is left for the data
+const IO_HEADER_SIZE: u32 = 11;
+const MAX_WALK: usize = 16;
+const BLOCK_SIZE: u64 = 4096;
+const V9FS_MAGIC: u32 = 0x0102_1997;
+// the fields of Rgetattr we fill, everything up to the number of blocks
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// the replies are the requests + 1
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+fn errno(errno: Errno) -> WireFormatError {
+    WireFormatError::from_errno(errno)
+}
+
+// the fields of a request
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated request",
+            ));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u16()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+}
+
+// the fields of a reply
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, val: u8) -> &mut Self {
+        self.0.push(val);
+        self
+    }
+
+    fn u16(&mut self, val: u16) -> &mut Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, val: u32) -> &mut Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, val: u64) -> &mut Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, val: &[u8]) -> &mut Self {
+        self.u16(val.len() as u16);
+        self.0.extend_from_slice(val);
+        self
+    }
+
+    fn qid(&mut self, inode: &Inode) -> &mut Self {
+        let kind = match inode.mode {
+            InodeMode::Dir { .. } => QTDIR,
+            InodeMode::Lnk => QTSYMLINK,
+            _ => QTFILE,
+        };
+        self.u8(kind).u32(0).u64(inode.ino)
+    }
+}
+
+fn file_type(inode: &Inode) -> Result<SFlag> {
+    Ok(match inode.mode {
+        InodeMode::File { .. } => SFlag::S_IFREG,
+        InodeMode::Dir { .. } => SFlag::S_IFDIR,
+        InodeMode::Fifo => SFlag::S_IFIFO,
+        InodeMode::Chr { .. } => SFlag::S_IFCHR,
+        InodeMode::Blk { .. } => SFlag::S_IFBLK,
+        InodeMode::Lnk => SFlag::S_IFLNK,
+        InodeMode::Sock => SFlag::S_IFSOCK,
+        InodeMode::Unknown | InodeMode::Wht => return Err(errno(Errno::EINVAL)),
+    })
+}
+
+// the d_type of a directory entry
+fn dirent_type(file_type: SFlag) -> u8 {
+    (file_type.bits() >> 12) as u8
+}
+
+enum Fid {
+    // the inodes from the root to the file, so the client can walk back up with ..
+    Node(Vec<Ino>),
+    // an xattr, or the list of their names, which the client reads like a file
+    Xattr(Vec<u8>),
+}
+
+struct Session<'a> {
+    pfs: &'a PuzzleFS,
+    // the number of files of the image and their size
+    statistics: (u64, u64),
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Session<'_> {
+    fn path(&self, fid: u32) -> Result<&Vec<Ino>> {
+        match self.fids.get(&fid) {
+            Some(Fid::Node(path)) => Ok(path),
+            _ => Err(errno(Errno::EBADF)),
+        }
+    }
+
+    fn inode(&self, fid: u32) -> Result<Inode> {
+        let ino = *self.path(fid)?.last().unwrap();
+        self.pfs.find_inode(ino)
+    }
+
+    fn new_fid(&mut self, fid: u32, val: Fid) -> Result<()> {
+        if self.fids.contains_key(&fid) {
+            return Err(errno(Errno::EBADF));
+        }
+        self.fids.insert(fid, val);
+        Ok(())
+    }
+
+    // returns the body of the reply
+    fn handle(&mut self, kind: u8, request: &mut Decoder<'_>) -> Result<Encoder> {
+        let mut reply = Encoder::default();
+        match kind {
+            TVERSION => {
+                let msize = request.u32()?;
+                let version = request.string()?;
+                // a new version starts the session over
+                self.fids.clear();
+                self.msize = msize.min(MAX_MSIZE);
+                let version = if version == VERSION.as_bytes() {
+                    VERSION
+                } else {
+                    "unknown"
+                };
+                reply.u32(self.msize).string(version.as_bytes());
+            }
+            TAUTH => return Err(errno(Errno::EOPNOTSUPP)),
+            TATTACH => {
+                let fid = request.u32()?;
+                self.new_fid(fid, Fid::Node(vec![1]))?;
+                reply.qid(&self.pfs.find_inode(1)?);
+            }
+            TWALK => {
+                let (fid, newfid) = (request.u32()?, request.u32()?);
+                let names = (0..request.u16()?)
+                    .map(|_| request.string())
+                    .collect::<io::Result<Vec<_>>>()?;
+                if names.len() > MAX_WALK {
+                    return Err(errno(Errno::EINVAL));
+                }
+                if newfid != fid && self.fids.contains_key(&newfid) {
+                    return Err(errno(Errno::EBADF));
+                }
+                let mut path = self.path(fid)?.clone();
+                let mut qids = Encoder::default();
+                let mut walked = 0;
+                for name in &names {
+                    if name == b".." {
+                        if path.len() > 1 {
+                            path.pop();
+                        }
+                    } else {
+                        match self.pfs.dir_lookup(*path.last().unwrap(), name) {
+                            Ok(ino) => path.push(ino),
+                            // the client only learns how far it got, unless the first name is
+                            // already missing
+                            Err(e) if walked == 0 => return Err(e),
+                            Err(_) => break,
+                        }
+                    }
+                    qids.qid(&self.pfs.find_inode(*path.last().unwrap())?);
+                    walked += 1;
+                }
+                if walked == names.len() {
+                    self.fids.insert(newfid, Fid::Node(path));
+                }
+                reply.u16(walked as u16).0.extend(qids.0);
+            }
+            TLOPEN => {
+                let (fid, flags) = (request.u32()?, request.u32()? as i32);
+                let inode = self.inode(fid)?;
+                if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+                    return Err(errno(Errno::EROFS));
+                }
+                // the client sizes its reads after msize
+                reply.qid(&inode).u32(0);
+            }
+            TREAD => {
+                let (fid, offset) = (request.u32()?, request.u64()?);
+                let count = request
+                    .u32()?
+                    .min(self.msize.saturating_sub(IO_HEADER_SIZE))
+                    as usize;
+                let data = match self.fids.get(&fid) {
+                    Some(Fid::Node(_)) => {
+                        let mut buf = vec![0; count];
+                        let inode = self.inode(fid)?;
+                        let read = file_read(
+                            &self.pfs.oci,
+                            &inode,
+                            offset as usize,
+                            &mut buf,
+                            &self.pfs.verity_data,
+                        )?;
+                        buf.truncate(read);
+                        buf
+                    }
+                    Some(Fid::Xattr(val)) => {
+                        let start = (offset as usize).min(val.len());
+                        val[start..(start + count).min(val.len())].to_vec()
+                    }
+                    None => return Err(errno(Errno::EBADF)),
+                };
+                reply.u32(data.len() as u32).0.extend(data);
+            }
+            TREADDIR => {
+                let (fid, offset, count) = (request.u32()?, request.u64()?, request.u32()?);
+                let count = count.min(self.msize.saturating_sub(IO_HEADER_SIZE)) as usize;
+                let path = self.path(fid)?;
+                let ino = *path.last().unwrap();
+                let parent = path.len().checked_sub(2).map_or(ino, |i| path[i]);
+                let dir = self.pfs.find_inode(ino)?;
+                let mut entries = vec![(b".".as_slice(), ino), (b"..".as_slice(), parent)];
+                entries.extend(
+                    dir.dir_entries()?
+                        .iter()
+                        .map(|entry| (entry.name.as_slice(), entry.ino)),
+                );
+                let mut data = Encoder::default();
+                for (index, (name, ino)) in entries.into_iter().enumerate().skip(offset as usize) {
+                    // qid[13] offset[8] type[1] name[s]
+                    if data.0.len() + 24 + name.len() > count {
+                        break;
+                    }
+                    let inode = self.pfs.find_inode(ino)?;
+                    data.qid(&inode)
+                        .u64(index as u64 + 1)
+                        .u8(dirent_type(file_type(&inode)?))
+                        .string(name);
+                }
+                reply.u32(data.0.len() as u32).0.extend(data.0);
+            }
+            TGETATTR => {
+                let inode = self.inode(request.u32()?)?;
+                let file_type = file_type(&inode)?;
+                let size = inode.file_len().unwrap_or(0);
+                let rdev = match inode.mode {
+                    InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+                        makedev(major, minor)
+                    }
+                    _ => 0,
+                };
+                // the kernel drops the inodes without links
+                let nlink = if file_type == SFlag::S_IFDIR { 2 } else { 1 };
+                reply
+                    .u64(GETATTR_BASIC)
+                    .qid(&inode)
+                    .u32(file_type.bits() | inode.permissions as u32)
+                    .u32(inode.uid)
+                    .u32(inode.gid)
+                    .u64(nlink)
+                    .u64(rdev)
+                    .u64(size)
+                    .u64(BLOCK_SIZE)
+                    .u64(size.div_ceil(512));
+                // the times of the image are all zero, as with FUSE, then gen and data_version
+                for _ in 0..10 {
+                    reply.u64(0);
+                }
+            }
+            TREADLINK => {
+                let inode = self.inode(request.u32()?)?;
+                let target = inode.symlink_target().map_err(|_| errno(Errno::EINVAL))?;
+                reply.string(target.as_bytes());
+            }
+            TXATTRWALK => {
+                let (fid, newfid, name) = (request.u32()?, request.u32()?, request.string()?);
+                let xattrs = self
+                    .inode(fid)?
+                    .additional
+                    .map(|additional| additional.xattrs)
+                    .unwrap_or_default();
+                // an empty name lists the xattrs
+                let val = if name.is_empty() {
+                    xattrs
+                        .into_iter()
+                        .flat_map(|xattr| xattr.key.into_iter().chain([0]))
+                        .collect()
+                } else {
+                    xattrs
+                        .into_iter()
+                        .find(|xattr| xattr.key == name)
+                        .map(|xattr| xattr.val)
+                        .ok_or_else(|| errno(Errno::ENODATA))?
+                };
+                reply.u64(val.len() as u64);
+                self.new_fid(newfid, Fid::Xattr(val))?;
+            }
+            TSTATFS => {
+                self.path(request.u32()?)?;
+                let (files, size) = self.statistics;
+                reply
+                    .u32(V9FS_MAGIC)
+                    .u32(BLOCK_SIZE as u32)
+                    .u64(size.div_ceil(BLOCK_SIZE))
+                    .u64(0)
+                    .u64(0)
+                    .u64(files)
+                    .u64(0)
+                    .u64(0)
+                    .u32(255);
+            }
+            TCLUNK => {
+                self.fids
+                    .remove(&request.u32()?)
+                    .ok_or_else(|| errno(Errno::EBADF))?;
+            }
+            // requests are answered in order, there's nothing left to flush
+            TFLUSH | TFSYNC => (),
+            // nobody else can change the files, so locks always succeed
+            TLOCK => {
+                reply.u8(0);
+            }
+            TGETLOCK => {
+                let fid = request.u32()?;
+                self.path(fid)?;
+                request.u8()?;
+                let (start, length, proc_id) = (request.u64()?, request.u64()?, request.u32()?);
+                let client_id = request.string()?;
+                reply
+                    .u8(LOCK_TYPE_UNLCK)
+                    .u64(start)
+                    .u64(length)
+                    .u32(proc_id)
+                    .string(&client_id);
+            }
+            // the fid is gone even if the file isn't
+            TREMOVE => {
+                self.fids.remove(&request.u32()?);
+                return Err(errno(Errno::EROFS));
+            }
+            TLCREATE | TSYMLINK | TMKNOD | TRENAME | TSETATTR | TXATTRCREATE | TLINK | TMKDIR
+            | TRENAMEAT | TUNLINKAT | TWRITE => return Err(errno(Errno::EROFS)),
+            _ => {
+                debug!("unknown request {kind}");
+                return Err(errno(Errno::EOPNOTSUPP));
+            }
+        }
+        Ok(reply)
+    }
+}
+
+fn serve_client<S>(pfs: &PuzzleFS, statistics: (u64, u64), stream: &S) -> io::Result<()>
+where
+    for<'a> &'a S: Read + Write,
+{
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+    let mut session = Session {
+        pfs,
+        statistics,
+        msize: MAX_MSIZE,
+        fids: HashMap::new(),
+    };
+    loop {
+        let mut header = [0; HEADER_SIZE];
+        match reader.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let size = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        if size < HEADER_SIZE || size > session.msize as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("request of {size} bytes"),
+            ));
+        }
+        let (kind, tag) = (header[4], &header[5..]);
+        let mut body = vec![0; size - HEADER_SIZE];
+        reader.read_exact(&mut body)?;
+
+        let (kind, reply) = match session.handle(kind, &mut Decoder(&body)) {
+            Ok(reply) => (kind + 1, reply.0),
+            Err(e) => {
+                debug!("request {kind} failed: {e}");
+                (RLERROR, (to_errno(&e) as u32).to_le_bytes().to_vec())
+            }
+        };
+        writer.write_all(&((HEADER_SIZE + reply.len()) as u32).to_le_bytes())?;
+        writer.write_all(&[kind])?;
+        writer.write_all(tag)?;
+        writer.write_all(&reply)?;
+        writer.flush()?;
+    }
+}
+
+/// Where an image is exported. There is no authentication: a unix socket is only as protected as
+/// its permissions, and whoever can connect to a TCP port can read the whole image.
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+fn spawn_client<S>(pfs: &Arc<PuzzleFS>, statistics: (u64, u64), stream: S)
+where
+    S: Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    let pfs = Arc::clone(pfs);
+    thread::spawn(move || {
+        if let Err(e) = serve_client(&pfs, statistics, &stream) {
+            debug!("connection error: {e}");
+        }
+    });
+}
+
+/// Exports `pfs` over 9P2000.L on `listener`, read only, each connection from a thread of its
+/// own.
+pub fn serve(pfs: PuzzleFS, listener: Listener) -> Result<thread::JoinHandle<()>> {
+    let statistics = pfs.statistics()?;
+    match &listener {
+        Listener::Unix(unix) => info!("exporting the image on {:?}", unix.local_addr()?),
+        Listener::Tcp(tcp) => warn!(
+            "exporting the image on {}, to anyone who can connect",
+            tcp.local_addr()?
+        ),
+    }
+    let pfs = Arc::new(pfs);
+    Ok(thread::spawn(move || loop {
+        let result = match &listener {
+            Listener::Unix(unix) => unix
+                .accept()
+                .map(|(stream, _)| spawn_client(&pfs, statistics, stream)),
+            Listener::Tcp(tcp) => tcp
+                .accept()
+                .map(|(stream, _)| spawn_client(&pfs, statistics, stream)),
+        };
+        if let Err(e) = result {
+            warn!("cannot accept a connection: {e}");
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::oci::Image;
+    use std::fs;
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    const TAG: u16 = 1;
+
+    // sends a request and returns the type and the body of the reply
+    fn rpc<S: Read + Write>(stream: &mut S, kind: u8, body: &Encoder) -> io::Result<(u8, Vec<u8>)> {
+        let mut request = Encoder::default();
+        request
+            .u32((HEADER_SIZE + body.0.len()) as u32)
+            .u8(kind)
+            .u16(TAG)
+            .0
+            .extend(&body.0);
+        stream.write_all(&request.0)?;
+        let mut header = [0; HEADER_SIZE];
+        stream.read_exact(&mut header)?;
+        let size = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        assert_eq!(u16::from_le_bytes(header[5..].try_into().unwrap()), TAG);
+        let mut reply = vec![0; size - HEADER_SIZE];
+        stream.read_exact(&mut reply)?;
+        Ok((header[4], reply))
+    }
+
+    #[test]
+    fn test_serve() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let socket = dir.path().join("9p.sock");
+        serve(pfs, Listener::Unix(UnixListener::bind(&socket)?))?;
+        let mut stream = UnixStream::connect(&socket)?;
+
+        let (kind, reply) = rpc(
+            &mut stream,
+            TVERSION,
+            Encoder::default().u32(8192).string(VERSION.as_bytes()),
+        )?;
+        assert_eq!(kind, TVERSION + 1);
+        let mut reply = Decoder(&reply);
+        assert_eq!(reply.u32()?, 8192);
+        assert_eq!(reply.string()?, VERSION.as_bytes());
+
+        let (kind, _) = rpc(
+            &mut stream,
+            TATTACH,
+            Encoder::default()
+                .u32(0)
+                .u32(!0)
+                .string(b"root")
+                .string(b"")
+                .u32(0),
+        )?;
+        assert_eq!(kind, TATTACH + 1);
+
+        let (kind, reply) = rpc(
+            &mut stream,
+            TREADDIR,
+            Encoder::default().u32(0).u64(0).u32(4096),
+        )?;
+        assert_eq!(kind, TREADDIR + 1);
+        let mut reply = Decoder(&reply);
+        reply.u32()?;
+        let mut names = Vec::new();
+        while !reply.0.is_empty() {
+            reply.bytes(13 + 8 + 1)?;
+            names.push(reply.string()?);
+        }
+        assert_eq!(
+            names,
+            [
+                b".".to_vec(),
+                b"..".to_vec(),
+                b"SekienAkashita.jpg".to_vec()
+            ]
+        );
+
+        let (kind, reply) = rpc(
+            &mut stream,
+            TWALK,
+            Encoder::default()
+                .u32(0)
+                .u32(1)
+                .u16(1)
+                .string(b"SekienAkashita.jpg"),
+        )?;
+        assert_eq!(kind, TWALK + 1);
+        assert_eq!(Decoder(&reply).u16()?, 1);
+        let (kind, reply) = rpc(
+            &mut stream,
+            TWALK,
+            Encoder::default().u32(0).u32(2).u16(1).string(b"missing"),
+        )?;
+        assert_eq!(kind, RLERROR);
+        assert_eq!(Decoder(&reply).u32()?, Errno::ENOENT as u32);
+
+        let (kind, reply) = rpc(
+            &mut stream,
+            TGETATTR,
+            Encoder::default().u32(1).u64(GETATTR_BASIC),
+        )?;
+        assert_eq!(kind, TGETATTR + 1);
+        let mut reply = Decoder(&reply);
+        reply.bytes(8 + 13)?;
+        assert_eq!(reply.u32()? & libc::S_IFMT, libc::S_IFREG);
+        reply.bytes(4 + 4 + 8 + 8)?;
+        assert_eq!(reply.u64()?, 109466);
+
+        let (kind, reply) = rpc(
+            &mut stream,
+            TLOPEN,
+            Encoder::default().u32(1).u32(libc::O_RDWR as u32),
+        )?;
+        assert_eq!(kind, RLERROR);
+        assert_eq!(Decoder(&reply).u32()?, Errno::EROFS as u32);
+        let (kind, _) = rpc(&mut stream, TLOPEN, Encoder::default().u32(1).u32(0))?;
+        assert_eq!(kind, TLOPEN + 1);
+
+        let expected = fs::read("src/builder/test/test-1/SekienAkashita.jpg")?;
+        let mut contents = Vec::new();
+        loop {
+            let (kind, reply) = rpc(
+                &mut stream,
+                TREAD,
+                Encoder::default()
+                    .u32(1)
+                    .u64(contents.len() as u64)
+                    .u32(8192),
+            )?;
+            assert_eq!(kind, TREAD + 1);
+            let mut reply = Decoder(&reply);
+            let count = reply.u32()? as usize;
+            if count == 0 {
+                break;
+            }
+            contents.extend(reply.bytes(count)?);
+        }
+        assert_eq!(contents, expected);
+
+        let (kind, _) = rpc(&mut stream, TCLUNK, Encoder::default().u32(1))?;
+        assert_eq!(kind, TCLUNK + 1);
+        let (kind, _) = rpc(&mut stream, TCLUNK, Encoder::default().u32(1))?;
+        assert_eq!(kind, RLERROR);
+        let (kind, reply) = rpc(
+            &mut stream,
+            TMKDIR,
+            Encoder::default().u32(0).string(b"dir").u32(0o755).u32(0),
+        )?;
+        assert_eq!(kind, RLERROR);
+        assert_eq!(Decoder(&reply).u32()?, Errno::EROFS as u32);
+
+        // or over TCP, when asked for
+        let pfs = PuzzleFS::open(Image::open(dir.path())?, "test", None)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        serve(pfs, Listener::Tcp(listener))?;
+        let (kind, _) = rpc(
+            &mut TcpStream::connect(addr)?,
+            TVERSION,
+            Encoder::default().u32(8192).string(VERSION.as_bytes()),
+        )?;
+        assert_eq!(kind, TVERSION + 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! Reads the chunks of files ahead of time, for images pulled lazily.
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use tracing::debug;
+
+use super::PuzzleFS;
+use crate::compression::{Noop, Zstd};
+use crate::format::{BlobRef, InodeMode, Result, WireFormatError};
+use crate::oci::Digest;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetchStats {
+    pub files: u64,
+    pub chunks: u64,
+    /// The size of the chunks once decompressed.
+    pub bytes: u64,
+}
+
+impl fmt::Display for PrefetchStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "prefetched {} chunks ({} bytes) of {} files",
+            self.chunks, self.bytes, self.files
+        )
+    }
+}
+
+// the chunks of the files under `paths`, each once, along with the number of files
+fn collect_chunks(pfs: &PuzzleFS, paths: &[PathBuf]) -> Result<(u64, Vec<BlobRef>)> {
+    let mut queue = Vec::new();
+    for path in paths {
+        let inode = pfs.lookup(&Path::new("/").join(path))?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} isn't in the image", path.display()),
+            )
+        })?;
+        queue.push(inode);
+    }
+    if paths.is_empty() {
+        queue.push(pfs.find_inode(1)?);
+    }
+
+    let mut seen = HashSet::new();
+    let mut files = 0;
+    // ordered by digest so the chunks are fetched in a stable order
+    let mut blobs = BTreeMap::new();
+    while let Some(inode) = queue.pop() {
+        if !seen.insert(inode.ino) {
+            continue;
+        }
+        match &inode.mode {
+            InodeMode::File { chunks, .. } => {
+                files += 1;
+                for chunk in chunks {
+                    blobs.insert(chunk.blob.digest, chunk.blob);
+                }
+            }
+            InodeMode::Dir { dir_list } => {
+                for entry in &dir_list.entries {
+                    queue.push(pfs.find_inode(entry.ino)?);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok((files, blobs.into_values().collect()))
+}
+
+fn read_chunk(pfs: &PuzzleFS, chunk: &BlobRef) -> Result<u64> {
+    let digest = Digest::new(&chunk.digest);
+    let verity = pfs
+        .verity_data
+        .as_ref()
+        .map(|verity| {
+            verity.get(&chunk.digest).ok_or_else(|| {
+                WireFormatError::InvalidFsVerityData(
+                    format!("missing verity data {digest}"),
+                    Backtrace::capture(),
+                )
+            })
+        })
+        .transpose()?;
+    let mut blob = if chunk.compressed {
+        pfs.oci
+            .open_compressed_blob::<Zstd>(&digest, verity.map(Vec::as_slice))?
+    } else {
+        pfs.oci
+            .open_compressed_blob::<Noop>(&digest, verity.map(Vec::as_slice))?
+    };
+    Ok(io::copy(&mut blob, &mut io::sink())?)
+}
+
+/// Fetches and decompresses every chunk of the files under `paths`, or of the whole image if
+/// `paths` is empty, with `jobs` threads.
+pub fn prefetch(pfs: &PuzzleFS, paths: &[PathBuf], jobs: usize) -> Result<PrefetchStats> {
+    let (files, chunks) = collect_chunks(pfs, paths)?;
+    debug!("prefetching {} chunks of {files} files", chunks.len());
+
+    let next = AtomicUsize::new(0);
+    let bytes = AtomicU64::new(0);
+    let error = Mutex::new(None);
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, chunks.len().max(1)) {
+            s.spawn(|| {
+                while let Some(chunk) = chunks.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    match read_chunk(pfs, chunk) {
+                        Ok(n) => {
+                            bytes.fetch_add(n, Ordering::Relaxed);
+                        }
+                        Err(e) => {
+                            error.lock().unwrap().get_or_insert(e);
+                            // make the other threads stop as well
+                            next.store(chunks.len(), Ordering::Relaxed);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+
+    Ok(PrefetchStats {
+        files,
+        chunks: chunks.len() as u64,
+        bytes: bytes.into_inner(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::build_test_fs;
+    use crate::oci::Image;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_prefetch() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+
+        let stats = prefetch(&pfs, &[], 4)?;
+        assert_eq!(stats.files, 1);
+        assert!(stats.chunks > 1);
+        assert_eq!(stats.bytes, 109466);
+        assert_eq!(
+            prefetch(&pfs, &[PathBuf::from("SekienAkashita.jpg")], 1)?,
+            stats
+        );
+        assert!(prefetch(&pfs, &[PathBuf::from("/missing")], 4).is_err());
+        Ok(())
+    }
+}
@@ -1,17 +1,90 @@
 use nix::errno::Errno;
+use nix::fcntl::copy_file_range;
 use std::backtrace::Backtrace;
 use std::cmp::min;
-use std::io;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Read};
+use std::os::fd::AsFd;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
 use std::path::{Component, Path};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use tracing::{debug, info};
 
 use crate::format::{
-    DirEnt, Ino, Inode, InodeMode, Result, RootfsReader, VerityData, WireFormatError,
+    DirEnt, FileChunk, Ino, Inode, InodeMode, Result, RootfsReader, VerityData, WireFormatError,
 };
-use crate::oci::Image;
+use crate::fsverity_helpers::get_fs_verity_measurement;
+use crate::idmap::IdMap;
+use crate::oci::{Image, ImageError};
+
+use super::MountError;
+
+pub const PUZZLEFS_IMAGE_MANIFEST_VERSION: u64 = 5;
+
+/// How this release handles the rootfs of a given manifest version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionSupport {
+    /// The current version.
+    Current,
+    /// Read as is; `puzzlefs migrate` rewrites it in the current version.
+    Migratable,
+    /// Can't be read, the image has to be built again from its sources.
+    Unsupported,
+}
+
+/// The manifest versions puzzlefs has used, oldest first, with how this release handles them.
+pub const MANIFEST_VERSIONS: &[(u64, VersionSupport, &str)] = &[
+    (1, VersionSupport::Unsupported, "CBOR metadata"),
+    (
+        2,
+        VersionSupport::Unsupported,
+        "CBOR metadata with fs-verity data",
+    ),
+    (3, VersionSupport::Migratable, "capnp metadata"),
+    (
+        4,
+        VersionSupport::Migratable,
+        "capnp metadata with inline file contents",
+    ),
+    (
+        PUZZLEFS_IMAGE_MANIFEST_VERSION,
+        VersionSupport::Current,
+        "capnp metadata with directories merged across layers",
+    ),
+];
+
+/// How this release handles `version`; versions it doesn't know come from a newer release.
+pub fn version_support(version: u64) -> VersionSupport {
+    MANIFEST_VERSIONS
+        .iter()
+        .find(|(v, _, _)| *v == version)
+        .map_or(VersionSupport::Unsupported, |(_, support, _)| *support)
+}
 
-pub const PUZZLEFS_IMAGE_MANIFEST_VERSION: u64 = 3;
+/// Checks that a rootfs of manifest version `version` can be read.
+pub fn negotiate_version(version: u64) -> Result<VersionSupport> {
+    let support = version_support(version);
+    match support {
+        VersionSupport::Current => Ok(support),
+        VersionSupport::Migratable => {
+            info!(
+                "manifest version {version} is outdated, puzzlefs migrate upgrades it to version \
+                 {PUZZLEFS_IMAGE_MANIFEST_VERSION}"
+            );
+            Ok(support)
+        }
+        VersionSupport::Unsupported => Err(ImageError::UnsupportedManifestVersion {
+            found: version,
+            supported: PUZZLEFS_IMAGE_MANIFEST_VERSION,
+        }
+        .into()),
+    }
+}
 
 pub(crate) fn file_read(
     oci: &Image,
@@ -21,7 +94,16 @@ pub(crate) fn file_read(
     verity_data: &Option<VerityData>,
 ) -> Result<usize> {
     let chunks = match &inode.mode {
-        InodeMode::File { chunks } => chunks,
+        InodeMode::File {
+            inline: Some(inline),
+            ..
+        } => {
+            let inline = inline.get(offset..).unwrap_or_default();
+            let n = min(inline.len(), data.len());
+            data[..n].copy_from_slice(&inline[..n]);
+            return Ok(n);
+        }
+        InodeMode::File { chunks, .. } => chunks,
         _ => return Err(WireFormatError::from_errno(Errno::ENOTDIR)),
     };
 
@@ -59,6 +141,9 @@ pub(crate) fn file_read(
             &mut data[start..finish],
             verity_data,
         )?;
+        if addl_offset + n == chunk.len as usize {
+            oci.chunk_done(chunk);
+        }
         file_offset += n;
         buf_offset += n;
     }
@@ -67,44 +152,368 @@ pub(crate) fn file_read(
     Ok(buf_offset)
 }
 
+/// Reads up to `size` bytes at `offset`, like [`file_read`]; but when they all come from the
+/// same uncompressed and unencrypted chunk, they're read from its blob with a single `pread`.
+pub(crate) fn file_data(
+    oci: &Image,
+    inode: &Inode,
+    offset: u64,
+    size: usize,
+    verity_data: &Option<VerityData>,
+) -> Result<Vec<u8>> {
+    if let InodeMode::File {
+        chunks,
+        inline: None,
+    } = &inode.mode
+    {
+        let end = min(offset + size as u64, inode.file_len()?);
+        let mut chunk_start = 0;
+        for chunk in chunks {
+            let chunk_end = chunk_start + chunk.len;
+            if offset < chunk_end {
+                if !chunk.blob.compressed && end <= chunk_end && !oci.is_encrypted(chunk.blob)? {
+                    let file = oci.open_chunk(chunk.blob, verity_data)?;
+                    let mut buf = vec![0; (end - offset) as usize];
+                    file.read_exact_at(&mut buf, chunk.blob.offset + offset - chunk_start)?;
+                    return Ok(buf);
+                }
+                break;
+            }
+            chunk_start = chunk_end;
+        }
+    }
+
+    let mut buf = vec![0; size];
+    let read = file_read(oci, inode, offset as usize, &mut buf, verity_data)?;
+    buf.truncate(read);
+    Ok(buf)
+}
+
+// OCI layer conventions, used by tags which are meant to be stacked on top of other ones
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+
+// the rootfs of a tag; since each tag numbers its inodes from 1, the inodes of stacked tags are
+// shifted by ino_offset to keep them unique
+struct Layer {
+    rootfs: RootfsReader,
+    ino_offset: Ino,
+    max_ino: Ino,
+}
+
+// how the fs-verity requirements are enforced when tags are opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VerityMode {
+    // the manifests which require fs-verity must come with their digest
+    Manifest,
+    // every blob must have fs-verity enabled
+    Required,
+    // the builder reads the image it writes, whatever its manifests require
+    Builder,
+}
+
+impl From<bool> for VerityMode {
+    fn from(require_verity: bool) -> Self {
+        if require_verity {
+            VerityMode::Required
+        } else {
+            VerityMode::Manifest
+        }
+    }
+}
+
 pub struct PuzzleFS {
     pub oci: Arc<Image>,
-    rootfs: RootfsReader,
+    layers: Vec<Layer>,
+    // the entries of the directories which exist in more than one layer, merged the first time
+    // they're used
+    merged_dirs: Mutex<HashMap<Ino, Arc<Vec<DirEnt>>>>,
+    // the directories which exist in more than one layer and weren't merged yet, with their
+    // inodes in each layer, topmost first; they're found as their parents are merged
+    unmerged_dirs: Mutex<HashMap<Ino, Vec<Ino>>>,
+    // the number of files and the size of the image, see PuzzleFS::statistics
+    statistics: OnceLock<(u64, u64)>,
     pub verity_data: Option<VerityData>,
     pub manifest_verity: Option<Vec<u8>>,
+    /// The tags of the layers, topmost first.
+    pub tags: Vec<String>,
+    uid_map: IdMap,
+    gid_map: IdMap,
 }
 
 impl PuzzleFS {
     pub fn open(oci: Image, tag: &str, manifest_verity: Option<&[u8]>) -> Result<PuzzleFS> {
-        let rootfs = oci.open_rootfs_blob(tag, manifest_verity)?;
+        Self::open_layers(oci, &[(tag, manifest_verity)], false)
+    }
 
-        if rootfs.get_manifest_version()? != PUZZLEFS_IMAGE_MANIFEST_VERSION {
-            return Err(WireFormatError::InvalidImageVersion(
-                format!(
-                    "got {}, expected {}",
-                    rootfs.get_manifest_version()?,
-                    PUZZLEFS_IMAGE_MANIFEST_VERSION
-                ),
-                Backtrace::capture(),
-            ));
-        }
+    /// Opens several tags of the image stacked on top of each other, topmost first, like the
+    /// layers of an overlay filesystem. Directories are merged, the other files of the upper tags
+    /// hide the ones of the lower tags, and `.wh.<name>` whiteouts and `.wh..wh..opq` opaque
+    /// directories in the upper tags, as well as opaque directories, hide files of the lower tags.
+    /// Either all of the tags or none of them must come with their manifest's fs-verity digest.
+    ///
+    /// With `require_verity`, every blob must have fs-verity enabled, even for the tags whose
+    /// manifest digest isn't given: their manifests are trusted as they are, but nothing is read
+    /// without checking its fs-verity measurement.
+    pub fn open_layers(
+        oci: Image,
+        tags: &[(&str, Option<&[u8]>)],
+        require_verity: bool,
+    ) -> Result<PuzzleFS> {
+        Self::open_image_layers(Arc::new(oci), tags, VerityMode::from(require_verity))
+    }
 
-        let verity_data = if manifest_verity.is_some() {
-            Some(rootfs.get_verity_data()?)
-        } else {
-            None
+    /// Opens `tag` as the base of a delta being built. The builder reads the image it writes as
+    /// it is, even if its manifest requires fs-verity, which the mounts of the delta check.
+    pub(crate) fn open_base(oci: Image, tag: &str) -> Result<PuzzleFS> {
+        Self::open_image_layers(Arc::new(oci), &[(tag, None)], VerityMode::Builder)
+    }
+
+    /// Opens other tags of the same image, like [`PuzzleFS::open_layers`], with the same owner
+    /// mappings, e.g. to switch a mount to them.
+    pub fn reopen(&self, tags: &[(&str, Option<&[u8]>)], require_verity: bool) -> Result<PuzzleFS> {
+        let mut pfs = Self::open_image_layers(
+            Arc::clone(&self.oci),
+            tags,
+            VerityMode::from(require_verity),
+        )?;
+        pfs.set_id_maps(self.uid_map.clone(), self.gid_map.clone());
+        Ok(pfs)
+    }
+
+    fn open_image_layers(
+        oci: Arc<Image>,
+        tags: &[(&str, Option<&[u8]>)],
+        mode: VerityMode,
+    ) -> Result<PuzzleFS> {
+        let Some((_, manifest_verity)) = tags.first() else {
+            return Err(WireFormatError::from_errno(Errno::EINVAL));
         };
+        if mode != VerityMode::Required
+            && tags
+                .iter()
+                .any(|(_, v)| v.is_some() != manifest_verity.is_some())
+        {
+            return Err(MountError::MixedVerity.into());
+        }
 
+        let mut layers = Vec::new();
+        let mut verity_data: Option<VerityData> = None;
+        let mut ino_offset = 0;
+        let mut top_verity = None;
+        for (tag, manifest_verity) in tags {
+            if manifest_verity.is_none()
+                && mode == VerityMode::Manifest
+                && oci.requires_verity(tag)?
+            {
+                return Err(MountError::VerityRequired {
+                    tag: tag.to_string(),
+                }
+                .into());
+            }
+            let measured;
+            let manifest_verity = match manifest_verity {
+                None if mode == VerityMode::Required => {
+                    measured = get_fs_verity_measurement(&oci.get_image_manifest_fd(tag)?)
+                        .map_err(|e| {
+                            WireFormatError::InvalidFsVerityData(
+                                format!("manifest of {tag}: {e}"),
+                                Backtrace::capture(),
+                            )
+                        })?;
+                    Some(&measured[..])
+                }
+                _ => *manifest_verity,
+            };
+            top_verity.get_or_insert(manifest_verity.map(|v| v.to_vec()));
+            let rootfs = oci.open_rootfs_blob(tag, manifest_verity)?;
+
+            // all the tags live in the same image, so their chunks can share one table
+            if manifest_verity.is_some() {
+                verity_data
+                    .get_or_insert_with(VerityData::new)
+                    .extend(rootfs.get_verity_data()?);
+            }
+
+            let max_ino = rootfs.max_inode()?;
+            layers.push(Layer {
+                rootfs,
+                ino_offset,
+                max_ino,
+            });
+            ino_offset += max_ino;
+        }
+
+        // only the roots are known to be merged, the directories they share are found as the
+        // roots are merged
+        let mut unmerged_dirs = HashMap::new();
+        if layers.len() > 1 {
+            unmerged_dirs.insert(1, layers.iter().map(|layer| layer.ino_offset + 1).collect());
+        }
         Ok(PuzzleFS {
-            oci: Arc::new(oci),
-            rootfs,
+            oci,
+            layers,
+            merged_dirs: Mutex::new(HashMap::new()),
+            unmerged_dirs: Mutex::new(unmerged_dirs),
+            statistics: OnceLock::new(),
             verity_data,
-            manifest_verity: manifest_verity.map(|e| e.to_vec()),
+            manifest_verity: top_verity.flatten(),
+            tags: tags.iter().map(|(tag, _)| tag.to_string()).collect(),
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
         })
     }
 
+    // finds an inode in the layer it belongs to, without merging directories
+    fn layer_inode(&self, ino: Ino) -> Result<Inode> {
+        let layer = self
+            .layers
+            .iter()
+            .find(|layer| ino > layer.ino_offset && ino <= layer.ino_offset + layer.max_ino)
+            .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+        let mut inode = layer.rootfs.find_inode(ino - layer.ino_offset)?;
+        if layer.ino_offset != 0 {
+            inode.ino += layer.ino_offset;
+            if let InodeMode::Dir { dir_list } = &mut inode.mode {
+                for entry in &mut dir_list.entries {
+                    entry.ino += layer.ino_offset;
+                }
+            }
+        }
+        Ok(inode)
+    }
+
+    // the merged entries of the directory `ino`, None if it only exists in one layer
+    fn merged_entries(&self, ino: Ino) -> Result<Option<Arc<Vec<DirEnt>>>> {
+        if let Some(entries) = self.merged_dirs.lock().unwrap().get(&ino) {
+            return Ok(Some(Arc::clone(entries)));
+        }
+        let Some(inos) = self.unmerged_dirs.lock().unwrap().get(&ino).cloned() else {
+            return Ok(None);
+        };
+        let dirs = inos
+            .iter()
+            .map(|ino| self.layer_inode(*ino))
+            .collect::<Result<Vec<_>>>()?;
+        let entries = Arc::new(self.merge_dirs(dirs)?);
+        self.merged_dirs
+            .lock()
+            .unwrap()
+            .insert(ino, Arc::clone(&entries));
+        self.unmerged_dirs.lock().unwrap().remove(&ino);
+        Ok(Some(entries))
+    }
+
+    // merges the directories found at the same path in different layers, topmost first, and
+    // records the subdirectories they have in common to be merged in turn
+    fn merge_dirs(&self, dirs: Vec<Inode>) -> Result<Vec<DirEnt>> {
+        let mut entries = Vec::new();
+        // the subdirectories to merge later, by name
+        let mut subdirs = Vec::<(Vec<u8>, Vec<Ino>)>::new();
+        // None once a name is resolved and hides whatever lower layers have
+        let mut names = HashMap::<Vec<u8>, Option<usize>>::new();
+
+        for dir in &dirs {
+            let InodeMode::Dir { dir_list } = &dir.mode else {
+                unreachable!("only directories are merged");
+            };
+            // like the opaque marker, a directory built to replace the one of its base hides the
+            // ones of the lower tags
+            let mut opaque = dir_list.opaque;
+            // whiteouts only hide the files of the layers below
+            let mut hidden = HashSet::new();
+            for DirEnt { ino, name } in &dir_list.entries {
+                if name == OPAQUE_MARKER {
+                    opaque = true;
+                    continue;
+                }
+                if let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    hidden.insert(target.to_vec());
+                    continue;
+                }
+                let inode = match self.layer_inode(*ino) {
+                    Ok(inode) => inode,
+                    // puzzlefs whiteouts
+                    Err(e) if e.raw_os_error() == Some(Errno::ENOENT as i32) => {
+                        hidden.insert(name.clone());
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let is_dir = matches!(inode.mode, InodeMode::Dir { .. });
+                match names.get(name) {
+                    None => {
+                        entries.push(DirEnt {
+                            ino: *ino,
+                            name: name.clone(),
+                        });
+                        let subdir = if is_dir {
+                            subdirs.push((name.clone(), vec![*ino]));
+                            Some(subdirs.len() - 1)
+                        } else {
+                            None
+                        };
+                        names.insert(name.clone(), subdir);
+                    }
+                    Some(Some(index)) if is_dir => subdirs[*index].1.push(*ino),
+                    Some(Some(_)) => {
+                        names.insert(name.clone(), None);
+                    }
+                    Some(None) => (),
+                }
+            }
+            for name in hidden {
+                names.insert(name, None);
+            }
+            if opaque {
+                break;
+            }
+        }
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut unmerged_dirs = self.unmerged_dirs.lock().unwrap();
+        for (_, inos) in subdirs {
+            if inos.len() > 1 {
+                unmerged_dirs.insert(inos[0], inos);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Shifts the owners of all the inodes, e.g. so rootless containers see the uids they expect.
+    pub fn set_id_maps(&mut self, uid_map: IdMap, gid_map: IdMap) {
+        self.uid_map = uid_map;
+        self.gid_map = gid_map;
+    }
+
     pub fn find_inode(&self, ino: u64) -> Result<Inode> {
-        self.rootfs.find_inode(ino)
+        let mut inode = self.layer_inode(ino)?;
+        if let InodeMode::Dir { dir_list } = &mut inode.mode {
+            if let Some(entries) = self.merged_entries(ino)? {
+                dir_list.entries = entries.to_vec();
+            }
+        }
+        inode.uid = self.uid_map.map(inode.uid);
+        inode.gid = self.gid_map.map(inode.gid);
+        Ok(inode)
+    }
+
+    /// Finds `name` in the directory numbered `dir`, failing with ENOENT if it has no such entry.
+    /// Unlike [`Inode::dir_lookup`] on the result of [`PuzzleFS::find_inode`], the entries of the
+    /// metadata layers aren't merged first.
+    pub fn dir_lookup(&self, dir: Ino, name: &[u8]) -> Result<Ino> {
+        if let Some(entries) = self.merged_entries(dir)? {
+            return entries
+                .binary_search_by(|entry| entry.name.as_slice().cmp(name))
+                .map(|i| entries[i].ino)
+                .map_err(|_| WireFormatError::from_errno(Errno::ENOENT));
+        }
+        let layer = self
+            .layers
+            .iter()
+            .find(|layer| dir > layer.ino_offset && dir <= layer.ino_offset + layer.max_ino)
+            .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+        Ok(layer.rootfs.dir_lookup(dir - layer.ino_offset, name)? + layer.ino_offset)
     }
 
     // lookup performs a path-based lookup in this puzzlefs
@@ -114,33 +523,64 @@ impl PuzzleFS {
             return Err(WireFormatError::from_errno(Errno::EINVAL));
         }
 
-        let mut cur = self.find_inode(1)?;
+        let mut cur = 1;
 
         // TODO: better path resolution with .. and such?
         for comp in components.into_iter().skip(1) {
             match comp {
-                Component::Normal(p) => {
-                    if let InodeMode::Dir { dir_list } = cur.mode {
-                        if let Some(DirEnt { ino, name: _ }) = dir_list
-                            .entries
-                            .into_iter()
-                            .find(|dir_entry| dir_entry.name == p.as_bytes())
-                        {
-                            cur = self.find_inode(ino)?;
-                            continue;
-                        }
+                Component::Normal(p) => match self.dir_lookup(cur, p.as_bytes()) {
+                    Ok(ino) => cur = ino,
+                    Err(e)
+                        if e.raw_os_error() == Some(Errno::ENOENT as i32)
+                            || e.raw_os_error() == Some(Errno::ENOTDIR as i32) =>
+                    {
+                        return Ok(None)
                     }
-                    return Ok(None);
-                }
+                    Err(e) => return Err(e),
+                },
                 _ => return Err(WireFormatError::from_errno(Errno::EINVAL)),
             }
         }
 
-        Ok(Some(cur))
+        self.find_inode(cur).map(Some)
+    }
+
+    /// Returns the number of files of the filesystem and their total size, counting hard links
+    /// once. They're counted the first time, which walks the whole filesystem.
+    pub fn statistics(&self) -> Result<(u64, u64)> {
+        if let Some(statistics) = self.statistics.get() {
+            return Ok(*statistics);
+        }
+        // the files reachable from the root, each hard link counted once
+        let mut seen = HashSet::from([1]);
+        let mut queue = VecDeque::from([1]);
+        let mut size = 0;
+        while let Some(ino) = queue.pop_front() {
+            let inode = self.find_inode(ino)?;
+            size += inode.file_len().unwrap_or(0);
+            if let InodeMode::Dir { dir_list } = &inode.mode {
+                for entry in &dir_list.entries {
+                    if seen.insert(entry.ino) {
+                        queue.push_back(entry.ino);
+                    }
+                }
+            }
+        }
+        Ok(*self.statistics.get_or_init(|| (seen.len() as u64, size)))
+    }
+
+    /// The statistics of the filesystem, if [`PuzzleFS::statistics`] counted them already.
+    pub(crate) fn known_statistics(&self) -> Option<(u64, u64)> {
+        self.statistics.get().copied()
     }
 
     pub fn max_inode(&self) -> Result<Ino> {
-        self.rootfs.max_inode()
+        Ok(self
+            .layers
+            .iter()
+            .map(|layer| layer.ino_offset + layer.max_ino)
+            .max()
+            .unwrap_or(1))
     }
 }
 
@@ -161,28 +601,167 @@ impl<'a> FileReader<'a> {
             len,
         })
     }
-}
 
-impl io::Read for FileReader<'_> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let to_read = min(self.len - self.offset, buf.len());
+    /// The size of the file.
+    pub fn len(&self) -> u64 {
+        self.len as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads from `offset` without moving the position of the reader, like `pread`.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+        let to_read = min(self.len.saturating_sub(offset), buf.len());
         if to_read == 0 {
             return Ok(0);
         }
 
-        let read = file_read(
+        Ok(file_read(
             self.oci,
             self.inode,
-            self.offset,
+            offset,
             &mut buf[0..to_read],
             &None,
-        )
-        .map_err(|e| io::Error::from_raw_os_error(e.to_errno()))?;
+        )?)
+    }
+
+    /// Copies the rest of the file to `out`, at its position. The uncompressed chunks are copied
+    /// from their blobs with `copy_file_range`, so that on filesystems with reflinks (btrfs, XFS)
+    /// they share their extents with the blobs instead of taking space of their own. The others
+    /// are read and written, like all of them when the blobs live on another filesystem.
+    pub fn copy_to(&mut self, out: &mut fs::File) -> io::Result<u64> {
+        let start = self.offset;
+        let inode = self.inode;
+        let chunks = match &inode.mode {
+            InodeMode::File {
+                chunks,
+                inline: None,
+            } => chunks.as_slice(),
+            _ => &[],
+        };
+        let mut reflink = true;
+        let mut chunk_end = 0;
+        for chunk in chunks {
+            let chunk_start = chunk_end;
+            chunk_end += chunk.len as usize;
+            if self.offset >= chunk_end {
+                continue;
+            }
+            if reflink && !chunk.blob.compressed {
+                match self.copy_chunk_range(chunk, chunk_start, chunk_end, out) {
+                    Ok(()) => continue,
+                    Err(e) if unsupported_copy(&e) => {
+                        debug!("copying chunks with copy_file_range: {e}");
+                        reflink = false;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            io::copy(
+                &mut self.by_ref().take((chunk_end - self.offset) as u64),
+                out,
+            )?;
+        }
+        // the inline contents, if any
+        io::copy(self, out)?;
+        Ok((self.offset - start) as u64)
+    }
+
+    // copies the chunk spanning `chunk_start..chunk_end` of the file from the position onwards
+    fn copy_chunk_range(
+        &mut self,
+        chunk: &FileChunk,
+        chunk_start: usize,
+        chunk_end: usize,
+        out: &fs::File,
+    ) -> io::Result<()> {
+        let blob = self
+            .oci
+            .open_chunk(chunk.blob, &None)
+            .map_err(io::Error::from)?;
+        let mut blob_offset = (chunk.blob.offset + (self.offset - chunk_start) as u64) as i64;
+        while self.offset < chunk_end {
+            let n = copy_file_range(
+                blob.as_fd(),
+                Some(&mut blob_offset),
+                out.as_fd(),
+                None,
+                chunk_end - self.offset,
+            )?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.offset += n;
+        }
+        self.oci.chunk_done(chunk);
+        Ok(())
+    }
+}
+
+// whether copy_file_range failed because it can't copy between these files
+fn unsupported_copy(e: &io::Error) -> bool {
+    let unsupported = [
+        Errno::EXDEV,
+        Errno::EINVAL,
+        Errno::ENOSYS,
+        Errno::EOPNOTSUPP,
+    ];
+    e.raw_os_error()
+        .is_some_and(|errno| unsupported.contains(&Errno::from_i32(errno)))
+}
+
+/// Checks the contents of the regular file `inode` against the digest it was built with, see
+/// [`crate::builder::BuilderConfig::file_digests`], reading the whole file. Returns whether the
+/// file had a digest to check.
+pub fn verify_file(oci: &Image, inode: &Inode) -> Result<bool> {
+    let Some(expected) = inode.file_digest() else {
+        return Ok(false);
+    };
+    let mut hasher = Sha256::new();
+    io::copy(&mut FileReader::new(oci, inode)?, &mut hasher)?;
+    let found = hasher.finalize();
+    if found[..] != expected {
+        return Err(MountError::FileDigestMismatch {
+            ino: inode.ino,
+            expected: hex::encode(expected),
+            found: hex::encode(found),
+        }
+        .into());
+    }
+    Ok(true)
+}
+
+impl io::Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.read_at(buf, self.offset as u64)?;
         self.offset += read;
         Ok(read)
     }
 }
 
+impl io::Seek for FileReader<'_> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            io::SeekFrom::Start(offset) => (offset, 0),
+            io::SeekFrom::End(delta) => (self.len as u64, delta),
+            io::SeekFrom::Current(delta) => (self.offset as u64, delta),
+        };
+        // like files, seeking past the end is fine, reads just return nothing there
+        let offset = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative offset",
+            )
+        })?;
+        self.offset =
+            usize::try_from(offset).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(offset)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use sha2::{Digest, Sha256};
@@ -213,6 +792,104 @@ mod tests {
         assert_eq!(pfs.max_inode().unwrap(), 2);
     }
 
+    #[test]
+    fn test_file_reader_seek() -> anyhow::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let oci_dir = tempdir()?;
+        let image = Image::new(oci_dir.path())?;
+        build_test_fs(Path::new("src/builder/test/test-1"), &image, "test")?;
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let inode = pfs.find_inode(2)?;
+        let mut reader = FileReader::new(&pfs.oci, &inode)?;
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(reader.len(), content.len() as u64);
+
+        let mut buf = [0; 100];
+        assert_eq!(reader.seek(SeekFrom::Start(1000))?, 1000);
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, content[1000..1100]);
+        assert_eq!(reader.seek(SeekFrom::Current(-200))?, 900);
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, content[900..1000]);
+        assert_eq!(reader.seek(SeekFrom::End(-50))?, reader.len() - 50);
+        assert_eq!(reader.read(&mut buf)?, 50);
+        assert_eq!(buf[..50], content[content.len() - 50..]);
+        reader.seek(SeekFrom::End(10))?;
+        assert_eq!(reader.read(&mut buf)?, 0);
+        assert!(reader.seek(SeekFrom::Current(-100_000_000)).is_err());
+
+        // read_at doesn't care about the position
+        assert_eq!(reader.read_at(&mut buf, 5)?, 100);
+        assert_eq!(buf, content[5..105]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_data() -> anyhow::Result<()> {
+        use crate::builder::{build_initial_rootfs, BuilderConfig};
+        use crate::compression::{Noop, Zstd};
+
+        let content = std::fs::read("src/builder/test/test-1/SekienAkashita.jpg")?;
+        let oci_dir = tempdir()?;
+        let image = Image::new(oci_dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        build_initial_rootfs::<Noop>(rootfs, &image, "noop", &BuilderConfig::default())?;
+        build_initial_rootfs::<Zstd>(rootfs, &image, "zstd", &BuilderConfig::default())?;
+
+        let pfs = PuzzleFS::open(Image::open(oci_dir.path())?, "noop", None)?;
+        let inode = pfs.find_inode(2)?;
+        let data = file_data(&pfs.oci, &inode, 1000, 4096, &None)?;
+        assert_eq!(data, &content[1000..5096]);
+        // past the end of the file
+        let data = file_data(&pfs.oci, &inode, content.len() as u64 - 10, 4096, &None)?;
+        assert_eq!(data, &content[content.len() - 10..]);
+        assert!(file_data(&pfs.oci, &inode, content.len() as u64 + 10, 4096, &None)?.is_empty());
+
+        let pfs = PuzzleFS::open(Image::open(oci_dir.path())?, "zstd", None)?;
+        let inode = pfs.find_inode(2)?;
+        let data = file_data(&pfs.oci, &inode, 1000, 4096, &None)?;
+        assert_eq!(data, &content[1000..5096]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_reader_copy_to() -> anyhow::Result<()> {
+        use crate::builder::{build_initial_rootfs, BuilderConfig};
+        use crate::compression::{Noop, Zstd};
+        use std::io::{Seek, SeekFrom};
+
+        let content = std::fs::read("src/builder/test/test-1/SekienAkashita.jpg")?;
+        let oci_dir = tempdir()?;
+        let image = Image::new(oci_dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        build_initial_rootfs::<Noop>(rootfs, &image, "noop", &BuilderConfig::default())?;
+        build_initial_rootfs::<Zstd>(rootfs, &image, "zstd", &BuilderConfig::default())?;
+
+        for tag in ["noop", "zstd"] {
+            let pfs = PuzzleFS::open(Image::open(oci_dir.path())?, tag, None)?;
+            let inode = pfs.find_inode(2)?;
+            let mut reader = FileReader::new(&pfs.oci, &inode)?;
+            let mut out = tempfile::tempfile()?;
+            assert_eq!(reader.copy_to(&mut out)?, content.len() as u64);
+            assert_eq!(reader.copy_to(&mut out)?, 0);
+
+            // from the middle of a chunk
+            let mut rest = tempfile::tempfile()?;
+            reader.seek(SeekFrom::Start(1000))?;
+            reader.copy_to(&mut rest)?;
+
+            for (mut file, expected) in [(out, &content[..]), (rest, &content[1000..])] {
+                let mut copied = Vec::new();
+                file.rewind()?;
+                file.read_to_end(&mut copied)?;
+                assert_eq!(copied, expected);
+            }
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_path_lookup() {
         let oci_dir = tempdir().unwrap();
@@ -232,4 +909,56 @@ mod tests {
         pfs.lookup(Path::new("./invalid-path")).unwrap_err();
         pfs.lookup(Path::new("invalid-path")).unwrap_err();
     }
+
+    #[test]
+    fn test_open_layers() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+
+        let base = tempdir().unwrap();
+        std::fs::create_dir_all(base.path().join("etc")).unwrap();
+        std::fs::create_dir_all(base.path().join("bin")).unwrap();
+        std::fs::write(base.path().join("etc/a"), b"a").unwrap();
+        std::fs::write(base.path().join("etc/b"), b"b").unwrap();
+        std::fs::write(base.path().join("bin/x"), b"x").unwrap();
+        build_test_fs(base.path(), &image, "base").unwrap();
+
+        let addon = tempdir().unwrap();
+        std::fs::create_dir_all(addon.path().join("etc")).unwrap();
+        std::fs::write(addon.path().join("etc/b"), b"new b").unwrap();
+        std::fs::write(addon.path().join("etc/c"), b"c").unwrap();
+        std::fs::write(addon.path().join("etc/.wh.a"), b"").unwrap();
+        build_test_fs(addon.path(), &image, "addon").unwrap();
+
+        let pfs = PuzzleFS::open_layers(image, &[("addon", None), ("base", None)], false).unwrap();
+        // nothing is merged before it's used
+        assert!(pfs.merged_dirs.lock().unwrap().is_empty());
+        let names = |path: &str| {
+            pfs.lookup(Path::new(path))
+                .unwrap()
+                .unwrap()
+                .dir_entries()
+                .unwrap()
+                .iter()
+                .map(|e| String::from_utf8(e.name.clone()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("/"), vec!["bin", "etc"]);
+        assert_eq!(names("/etc"), vec!["b", "c"]);
+        assert!(pfs.lookup(Path::new("/etc/a")).unwrap().is_none());
+        assert_eq!(pfs.merged_dirs.lock().unwrap().len(), 2);
+
+        let read = |path: &str| {
+            let inode = pfs.lookup(Path::new(path)).unwrap().unwrap();
+            let mut contents = Vec::new();
+            io::copy(
+                &mut FileReader::new(&pfs.oci, &inode).unwrap(),
+                &mut contents,
+            )
+            .unwrap();
+            contents
+        };
+        assert_eq!(read("/etc/b"), b"new b");
+        assert_eq!(read("/bin/x"), b"x");
+    }
 }
@@ -0,0 +1,171 @@
+//! Confines the FUSE daemon once the filesystem is mounted.
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+
+use caps::{CapSet, Capability};
+use nix::libc;
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{Gid, Uid};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule, TargetArch,
+};
+use tracing::{info, warn};
+
+use super::MountError;
+use crate::format::{Result, WireFormatError};
+
+// the capabilities root needs to manage the files of the upper layer on behalf of other users
+const UPPER_CAPABILITIES: [Capability; 5] = [
+    Capability::CAP_CHOWN,
+    Capability::CAP_DAC_OVERRIDE,
+    Capability::CAP_FOWNER,
+    Capability::CAP_FSETID,
+    Capability::CAP_MKNOD,
+];
+
+// syscalls the daemon never needs, which are the usual way out of a compromised process
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_open_tree,
+    libc::SYS_move_mount,
+    libc::SYS_fsopen,
+    libc::SYS_fsmount,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+];
+
+fn sandbox_error(what: &str, e: impl Display) -> WireFormatError {
+    MountError::Sandbox {
+        what: what.to_string(),
+        reason: e.to_string(),
+    }
+    .into()
+}
+
+/// Moves the daemon into its own mount, network, IPC and UTS namespaces, keeping the network of
+/// its parent if `network` is set. Unprivileged users need a user namespace for that, in which
+/// they keep their own uid and gid so the files of the upper layer still belong to them.
+fn unshare_namespaces(network: bool) -> Result<()> {
+    let mut flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWUTS;
+    if !network {
+        flags |= CloneFlags::CLONE_NEWNET;
+    }
+    let uid = Uid::effective();
+    let gid = Gid::effective();
+    if !uid.is_root() {
+        flags |= CloneFlags::CLONE_NEWUSER;
+    }
+    unshare(flags).map_err(|e| sandbox_error("cannot unshare namespaces", e))?;
+    if !uid.is_root() {
+        fs::write("/proc/self/uid_map", format!("{uid} {uid} 1"))?;
+        fs::write("/proc/self/setgroups", "deny")?;
+        fs::write("/proc/self/gid_map", format!("{gid} {gid} 1"))?;
+    }
+    Ok(())
+}
+
+/// Drops all the capabilities, except the ones needed to write to the upper layer if there is
+/// one.
+fn drop_capabilities(writable: bool) -> Result<()> {
+    let keep: HashSet<Capability> = if writable {
+        UPPER_CAPABILITIES.into_iter().collect()
+    } else {
+        HashSet::new()
+    };
+    let cap_error = |e| sandbox_error("cannot drop capabilities", e);
+
+    // the bounding set can only be changed with CAP_SETPCAP, without it there's nothing to drop
+    if caps::has_cap(None, CapSet::Effective, Capability::CAP_SETPCAP).map_err(cap_error)? {
+        for cap in caps::read(None, CapSet::Bounding).map_err(cap_error)? {
+            if !keep.contains(&cap) {
+                caps::drop(None, CapSet::Bounding, cap).map_err(cap_error)?;
+            }
+        }
+    }
+    caps::clear(None, CapSet::Ambient).map_err(cap_error)?;
+    caps::clear(None, CapSet::Inheritable).map_err(cap_error)?;
+    let permitted = caps::read(None, CapSet::Permitted).map_err(cap_error)?;
+    let keep = keep.intersection(&permitted).copied().collect();
+    // the effective set must stay a subset of the permitted set, so it goes first
+    caps::set(None, CapSet::Effective, &keep).map_err(cap_error)?;
+    caps::set(None, CapSet::Permitted, &keep).map_err(cap_error)?;
+    Ok(())
+}
+
+/// Installs a seccomp filter which fails the syscalls in `DENIED_SYSCALLS` with EPERM, and only
+/// allows unix sockets (for syslog), plus IPv4 and IPv6 ones if `network` is set. It also sets
+/// `no_new_privs`.
+fn apply_seccomp_filter(network: bool) -> Result<()> {
+    let seccomp_error = |e| sandbox_error("cannot apply seccomp filter", e);
+    let mut rules: BTreeMap<i64, Vec<SeccompRule>> = DENIED_SYSCALLS
+        .iter()
+        .map(|syscall| (*syscall, vec![]))
+        .collect();
+    let mut families = vec![libc::AF_UNIX];
+    if network {
+        families.extend([libc::AF_INET, libc::AF_INET6]);
+    }
+    // the socket calls matching every condition, i.e. for none of the allowed families, fail
+    let other_family = families
+        .into_iter()
+        .map(|family| {
+            SeccompCondition::new(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Ne, family as u64)
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(seccomp_error)?;
+    rules.insert(
+        libc::SYS_socket,
+        vec![SeccompRule::new(other_family).map_err(seccomp_error)?],
+    );
+
+    let arch: TargetArch = std::env::consts::ARCH.try_into().map_err(seccomp_error)?;
+    let program: BpfProgram = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )
+    .and_then(|filter| filter.try_into())
+    .map_err(seccomp_error)?;
+    seccompiler::apply_filter(&program).map_err(|e| sandbox_error("cannot apply seccomp filter", e))
+}
+
+/// Sandboxes the current process; `writable` tells whether it has an upper layer to write to and
+/// `network` whether it fetches blobs from a remote. It has to be called while the process is
+/// still single threaded, the threads spawned afterwards inherit the sandbox.
+pub(crate) fn enter(writable: bool, network: bool) -> Result<()> {
+    // user namespaces aren't always available to unprivileged users, the other steps still help
+    if let Err(e) = unshare_namespaces(network) {
+        warn!("{e}, the daemon keeps the namespaces of its parent");
+    }
+    drop_capabilities(writable)?;
+    apply_seccomp_filter(network)?;
+    info!("sandboxed the FUSE daemon");
+    Ok(())
+}
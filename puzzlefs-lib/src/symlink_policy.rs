@@ -0,0 +1,118 @@
+use std::path::{Component, Path};
+
+use crate::mode_policy::PathPattern;
+
+/// How the builder stores the symlinks of a rootfs, e.g. one copied from a host directory whose
+/// symlinks point at absolute paths of the host.
+#[derive(Debug, Default, Clone)]
+pub struct SymlinkPolicy {
+    /// Store what the symlinks matching these patterns point to rather than the symlinks. A
+    /// symlink to a directory is walked like a directory.
+    pub follow: Vec<PathPattern>,
+    /// Rewrite absolute targets relative to the directory of the symlink, so that they point
+    /// inside the image wherever it's mounted or extracted: `/usr/bin/x -> /etc/alternatives/x`
+    /// becomes `/usr/bin/x -> ../../etc/alternatives/x`.
+    pub relative: bool,
+    /// Refuse the symlinks whose relative target climbs above the root of the image, like
+    /// `/etc/x -> ../../host`. Absolute targets are resolved against the root of the image by
+    /// whoever uses it, so they don't escape.
+    pub reject_escaping: bool,
+}
+
+// how many directories below the root the symlink at `path` is
+fn depth(path: &Path) -> usize {
+    path.parent().map_or(0, |parent| {
+        parent
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .count()
+    })
+}
+
+// whether `target`, resolved from the symlink at `path` without looking at the files it goes
+// through, climbs above the root
+fn escapes(path: &Path, target: &[u8]) -> bool {
+    if target.starts_with(b"/") {
+        return false;
+    }
+    let mut depth = depth(path);
+    for component in target.split(|c| *c == b'/') {
+        match component {
+            b"" | b"." => (),
+            b".." if depth == 0 => return true,
+            b".." => depth -= 1,
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+impl SymlinkPolicy {
+    /// Whether the symlink at `path`, relative to the root of the image, is followed.
+    pub fn follows(&self, path: &Path) -> bool {
+        self.follow.iter().any(|pattern| pattern.matches(path))
+    }
+
+    /// The target to store for the symlink at `path` pointing to `target`, or `None` if the
+    /// symlink escapes the image and such symlinks are refused.
+    pub fn target(&self, path: &Path, target: &[u8]) -> Option<Vec<u8>> {
+        let target = if self.relative && target.starts_with(b"/") {
+            let start = target
+                .iter()
+                .position(|c| *c != b'/')
+                .unwrap_or(target.len());
+            let mut relative = b"../".repeat(depth(path));
+            relative.extend_from_slice(&target[start..]);
+            if relative.is_empty() {
+                relative.push(b'.');
+            }
+            relative
+        } else {
+            target.to_vec()
+        };
+        if self.reject_escaping && escapes(path, &target) {
+            return None;
+        }
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symlink_policy() {
+        let policy = SymlinkPolicy {
+            follow: vec!["/etc/*.conf".parse().unwrap()],
+            relative: true,
+            reject_escaping: true,
+        };
+        assert!(policy.follows(Path::new("/etc/resolv.conf")));
+        assert!(!policy.follows(Path::new("/etc/ssl/certs")));
+
+        let target = |path: &str, target: &str| policy.target(Path::new(path), target.as_bytes());
+        assert_eq!(
+            target("/usr/bin/x", "/etc/alternatives/x").as_deref(),
+            Some(&b"../../etc/alternatives/x"[..])
+        );
+        assert_eq!(target("/lib", "/usr/lib").as_deref(), Some(&b"usr/lib"[..]));
+        assert_eq!(target("/root", "/").as_deref(), Some(&b"."[..]));
+        assert_eq!(
+            target("/usr/lib/x", "../../bin").as_deref(),
+            Some(&b"../../bin"[..])
+        );
+        assert_eq!(target("/usr/lib/x", "../../../bin"), None);
+        assert_eq!(target("/x", "a/../../b"), None);
+
+        let lenient = SymlinkPolicy::default();
+        assert_eq!(
+            lenient.target(Path::new("/x"), b"../../b").as_deref(),
+            Some(&b"../../b"[..])
+        );
+        assert_eq!(
+            lenient.target(Path::new("/x"), b"/etc").as_deref(),
+            Some(&b"/etc"[..])
+        );
+    }
+}
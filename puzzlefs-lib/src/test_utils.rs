@@ -0,0 +1,22 @@
+/// A xorshift generator of pseudo-random bytes, the same on every run, for the tests and the
+/// benchmarks which need data that neither compresses nor dedups.
+pub struct XorShift(u32);
+
+impl XorShift {
+    /// Starts from `seed`, made odd since xorshift never leaves 0.
+    pub fn new(seed: u32) -> Self {
+        XorShift(seed | 1)
+    }
+
+    /// The next `len` bytes.
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 17;
+                self.0 ^= self.0 << 5;
+                self.0 as u8
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,317 @@
+//! Named fs-verity root digests, so images can be mounted by a name.
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use nix::unistd::Uid;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Verifier;
+use serde::{Deserialize, Serialize};
+
+use crate::format::{Result, WireFormatError};
+
+/// Where root keeps its trusted digests, and where everyone else looks after their own.
+pub const SYSTEM_TRUST_DIR: &str = "/etc/puzzlefs/trust";
+
+fn trust_error(msg: String) -> WireFormatError {
+    WireFormatError::TrustError(msg, Backtrace::capture())
+}
+
+// the digests of sha256 and sha512 fs-verity
+fn parse_digest(digest: &str) -> Result<Vec<u8>> {
+    let digest = hex::decode(digest.trim())?;
+    if digest.len() != 32 && digest.len() != 64 {
+        return Err(trust_error(format!(
+            "{} isn't a sha256 or sha512 digest",
+            hex::encode(&digest)
+        )));
+    }
+    Ok(digest)
+}
+
+/// Reads the hex digest `name` is trusted with from `file`, checking it against the detached
+/// `signature` made with the private key matching the PEM public key in `key`. The file holds the
+/// digest and the name, separated by whitespace like `sha256sum` prints them, so that a signed
+/// file can't be used under another name. The signature is of the whole file, with sha256, e.g.
+/// made with `openssl dgst -sha256 -sign` or, base64 encoded, `cosign sign-blob`.
+pub fn read_signed_digest(
+    name: &str,
+    file: &Path,
+    signature: &Path,
+    key: &Path,
+) -> Result<Vec<u8>> {
+    let contents = fs::read(file)?;
+    let signature = fs::read(signature)?;
+    let key = PKey::public_key_from_pem(&fs::read(key)?)?;
+    let signature = std::str::from_utf8(&signature)
+        .ok()
+        .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .unwrap_or(signature);
+    if !Verifier::new(MessageDigest::sha256(), &key)?.verify_oneshot(&signature, &contents)? {
+        return Err(WireFormatError::SignatureError(
+            format!("invalid signature of {}", file.display()),
+            Backtrace::capture(),
+        ));
+    }
+    let contents = String::from_utf8_lossy(&contents);
+    match contents.split_whitespace().collect::<Vec<_>>()[..] {
+        [digest, signed_name] if signed_name == name => parse_digest(digest),
+        [_, signed_name] => Err(trust_error(format!(
+            "{} is signed for {signed_name:?}, not {name:?}",
+            file.display()
+        ))),
+        _ => Err(trust_error(format!(
+            "expected a digest and a name in {}",
+            file.display()
+        ))),
+    }
+}
+
+/// What a name of the trust store resolves to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustedDigest {
+    /// A hex fs-verity root digest.
+    Digest(String),
+    /// A file holding the hex digest, see [`read_signed_digest`].
+    Signed {
+        file: PathBuf,
+        signature: PathBuf,
+        key: PathBuf,
+    },
+}
+
+impl TrustedDigest {
+    /// The digest trusted under `name`, reading and checking the signed file if needed.
+    pub fn resolve(&self, name: &str) -> Result<Vec<u8>> {
+        match self {
+            TrustedDigest::Digest(digest) => parse_digest(digest),
+            TrustedDigest::Signed {
+                file,
+                signature,
+                key,
+            } => read_signed_digest(name, file, signature, key),
+        }
+    }
+}
+
+/// Directories of trusted digests, one JSON file per name. Names are looked up in every
+/// directory, in order, and added to the last one.
+#[derive(Debug, Clone)]
+pub struct TrustStore {
+    dirs: Vec<PathBuf>,
+}
+
+impl TrustStore {
+    /// A trust store kept in `dir` alone.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TrustStore {
+            dirs: vec![dir.into()],
+        }
+    }
+
+    /// The trust store of the current user: [`SYSTEM_TRUST_DIR`], whose names can't be
+    /// overridden, and then `$XDG_CONFIG_HOME/puzzlefs/trust` (by default
+    /// `~/.config/puzzlefs/trust`). Root only has the former.
+    pub fn user() -> Self {
+        let mut dirs = vec![PathBuf::from(SYSTEM_TRUST_DIR)];
+        if !Uid::effective().is_root() {
+            let config = env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+            if let Some(config) = config {
+                dirs.push(config.join("puzzlefs").join("trust"));
+            }
+        }
+        TrustStore { dirs }
+    }
+
+    fn entry_path(dir: &Path, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(trust_error(format!("invalid name {name:?}")));
+        }
+        Ok(dir.join(format!("{name}.json")))
+    }
+
+    // the directory names are added to and removed from
+    fn own_dir(&self) -> &Path {
+        // .unwrap() here because a trust store has at least one directory
+        self.dirs.last().unwrap()
+    }
+
+    /// Trusts `digest` under `name`, replacing what the name resolved to. The names of the
+    /// earlier directories can't be replaced. The digest must resolve, so that a mistyped digest
+    /// or a bad signature is caught now rather than at mount time.
+    pub fn add(&self, name: &str, digest: &TrustedDigest) -> Result<()> {
+        digest.resolve(name)?;
+        let dir = self.own_dir();
+        for earlier in &self.dirs[..self.dirs.len() - 1] {
+            if Self::entry_path(earlier, name)?.exists() {
+                return Err(trust_error(format!(
+                    "{name} is already trusted in {}",
+                    earlier.display()
+                )));
+            }
+        }
+        let path = Self::entry_path(dir, name)?;
+        fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!(".{name}.json.tmp"));
+        fs::write(&tmp, serde_json::to_vec_pretty(digest)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Stops trusting `name`, returning whether it was trusted. Only the last directory is
+    /// changed, the others may still trust it.
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        match fs::remove_file(Self::entry_path(self.own_dir(), name)?) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// What `name` resolves to, from the first directory which has it.
+    pub fn get(&self, name: &str) -> Result<Option<TrustedDigest>> {
+        for dir in &self.dirs {
+            match fs::read(Self::entry_path(dir, name)?) {
+                Ok(entry) => return Ok(Some(serde_json::from_slice(&entry)?)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// The digest `name` resolves to.
+    pub fn resolve(&self, name: &str) -> Result<Vec<u8>> {
+        self.get(name)?
+            .ok_or_else(|| trust_error(format!("{name} isn't in the trust store")))?
+            .resolve(name)
+    }
+
+    /// All the trusted names, the earlier directories hiding the names of the later ones.
+    pub fn list(&self) -> Result<BTreeMap<String, TrustedDigest>> {
+        let mut entries = BTreeMap::new();
+        for dir in self.dirs.iter().rev() {
+            let files = match fs::read_dir(dir) {
+                Ok(files) => files,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for file in files {
+                let file = file?;
+                let file_name = file.file_name();
+                let Some(name) = file_name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".json"))
+                    .filter(|name| !name.starts_with('.'))
+                else {
+                    continue;
+                };
+                let entry = serde_json::from_slice(&fs::read(file.path())?)?;
+                entries.insert(name.to_string(), entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::sign::Signer;
+    use tempfile::tempdir;
+
+    const DIGEST: &str = "9ac9abc098870c55cc61431dae8635806273d8f61274d34bec062560e79dc2f5";
+
+    #[test]
+    fn test_trust_store() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let system = TrustStore::new(dir.path().join("system"));
+        let store = TrustStore {
+            dirs: vec![dir.path().join("system"), dir.path().join("user")],
+        };
+        assert!(store.resolve("base").is_err());
+        assert!(store
+            .add("base", &TrustedDigest::Digest("abcd".to_string()))
+            .is_err());
+        assert!(store
+            .add("../base", &TrustedDigest::Digest(DIGEST.to_string()))
+            .is_err());
+
+        let other = "00".repeat(32);
+        store.add("base", &TrustedDigest::Digest(other.clone()))?;
+        assert_eq!(store.resolve("base")?, hex::decode(&other)?);
+
+        // the system directory takes precedence
+        system.add("base", &TrustedDigest::Digest(DIGEST.to_string()))?;
+        assert_eq!(store.resolve("base")?, hex::decode(DIGEST)?);
+        assert_eq!(
+            store.list()?.into_iter().collect::<Vec<_>>(),
+            [(
+                "base".to_string(),
+                TrustedDigest::Digest(DIGEST.to_string())
+            )]
+        );
+        assert!(store
+            .add("base", &TrustedDigest::Digest(other.clone()))
+            .is_err());
+
+        assert!(store.remove("base")?);
+        assert!(!store.remove("base")?);
+        assert_eq!(store.resolve("base")?, hex::decode(DIGEST)?);
+        assert!(system.remove("base")?);
+        assert!(store.resolve("base").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_digest() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let store = TrustStore::new(dir.path().join("trust"));
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(
+            Nid::X9_62_PRIME256V1,
+        )?)?)?;
+        let file = dir.path().join("digest");
+        let signature = dir.path().join("digest.sig");
+        let public = dir.path().join("key.pub");
+        fs::write(&public, key.public_key_to_pem()?)?;
+        let sign = |contents: &str| -> anyhow::Result<Vec<u8>> {
+            fs::write(&file, contents)?;
+            Ok(Signer::new(MessageDigest::sha256(), &key)?
+                .sign_oneshot_to_vec(contents.as_bytes())?)
+        };
+        let signed = sign(&format!("{DIGEST}  signed\n"))?;
+        fs::write(&signature, STANDARD.encode(&signed))?;
+
+        let entry = TrustedDigest::Signed {
+            file: file.clone(),
+            signature: signature.clone(),
+            key: public,
+        };
+        store.add("signed", &entry)?;
+        assert_eq!(store.resolve("signed")?, hex::decode(DIGEST)?);
+        fs::write(&signature, &signed)?;
+        assert_eq!(store.resolve("signed")?, hex::decode(DIGEST)?);
+        // it's only good for the name it was signed for
+        assert!(store.add("other", &entry).is_err());
+
+        // the digest changed without being signed again
+        fs::write(&file, format!("{}  signed\n", "00".repeat(32)))?;
+        assert!(store.resolve("signed").is_err());
+
+        // nor does a signed digest without a name do
+        fs::write(&signature, sign(&format!("{DIGEST}\n"))?)?;
+        assert!(store.resolve("signed").is_err());
+        Ok(())
+    }
+}
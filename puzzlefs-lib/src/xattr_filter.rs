@@ -0,0 +1,115 @@
+use std::fmt;
+use std::str::FromStr;
+
+// the xattrs overlayfs sets on the files of its upper directories for itself; they leak into
+// images built from a container's rootfs and confuse the overlayfs mounted on the extracted image
+const OVERLAY_XATTRS: [&str; 2] = ["trusted.overlay.*", "user.overlay.*"];
+
+/// A pattern matching the names of xattrs: either a whole name, like `security.selinux`, or a
+/// prefix followed by `*`, like `trusted.overlay.*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XattrPattern(String);
+
+impl XattrPattern {
+    pub fn matches(&self, name: &[u8]) -> bool {
+        match self.0.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix.as_bytes()),
+            None => name == self.0.as_bytes(),
+        }
+    }
+}
+
+impl FromStr for XattrPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.trim_end_matches('*').contains('*') {
+            return Err(format!(
+                "invalid xattr pattern {s:?}, expected a name or a prefix followed by *"
+            ));
+        }
+        Ok(XattrPattern(s.to_string()))
+    }
+}
+
+impl fmt::Display for XattrPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Decides which xattrs are stored in images and set on extracted files. The xattrs matching a
+/// kept pattern are kept; otherwise, the ones matching a dropped pattern are dropped. By default,
+/// the xattrs overlayfs keeps for itself are dropped and everything else, SELinux labels included,
+/// is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XattrFilter {
+    kept: Vec<XattrPattern>,
+    dropped: Vec<XattrPattern>,
+}
+
+impl Default for XattrFilter {
+    fn default() -> Self {
+        XattrFilter {
+            kept: Vec::new(),
+            dropped: OVERLAY_XATTRS
+                .iter()
+                .map(|pattern| XattrPattern(pattern.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl XattrFilter {
+    /// A filter which keeps every xattr.
+    pub fn keep_all() -> Self {
+        XattrFilter {
+            kept: Vec::new(),
+            dropped: Vec::new(),
+        }
+    }
+
+    pub fn with_kept(mut self, patterns: impl IntoIterator<Item = XattrPattern>) -> Self {
+        self.kept.extend(patterns);
+        self
+    }
+
+    pub fn with_dropped(mut self, patterns: impl IntoIterator<Item = XattrPattern>) -> Self {
+        self.dropped.extend(patterns);
+        self
+    }
+
+    pub fn keeps(&self, name: &[u8]) -> bool {
+        self.kept.iter().any(|pattern| pattern.matches(name))
+            || !self.dropped.iter().any(|pattern| pattern.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xattr_filter() {
+        let filter = XattrFilter::default();
+        assert!(!filter.keeps(b"trusted.overlay.opaque"));
+        assert!(!filter.keeps(b"user.overlay.origin"));
+        assert!(filter.keeps(b"security.selinux"));
+        assert!(filter.keeps(b"user.comment"));
+        assert!(XattrFilter::keep_all().keeps(b"trusted.overlay.opaque"));
+
+        let filter = XattrFilter::default()
+            .with_dropped(["security.*".parse().unwrap()])
+            .with_kept(["trusted.overlay.opaque".parse().unwrap()]);
+        assert!(!filter.keeps(b"security.selinux"));
+        assert!(!filter.keeps(b"security.capability2"));
+        assert!(filter.keeps(b"trusted.overlay.opaque"));
+        assert!(!filter.keeps(b"trusted.overlay.redirect"));
+
+        let pattern = "security.selinux".parse::<XattrPattern>().unwrap();
+        assert!(pattern.matches(b"security.selinux"));
+        assert!(!pattern.matches(b"security.selinux2"));
+        assert!("".parse::<XattrPattern>().is_err());
+        assert!("user.*.x".parse::<XattrPattern>().is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::{Parser, ValueEnum};
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::Server;
+use tracing::info;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+mod snapshotter;
+use snapshotter::PuzzleFsSnapshotter;
+
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Opts {
+    /// directory holding the snapshots and their metadata
+    #[arg(long, default_value = "/var/lib/puzzlefs-snapshotter")]
+    root: PathBuf,
+    /// unix socket containerd connects to
+    #[arg(long, default_value = "/run/puzzlefs-snapshotter/snapshotter.sock")]
+    address: PathBuf,
+    /// the format of the log messages; RUST_LOG selects which ones are logged
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+fn init_logging(log_format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    match log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+    init_logging(opts.log_format);
+
+    let snapshotter = PuzzleFsSnapshotter::new(&opts.root)?;
+
+    if let Some(parent) = opts.address.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // a stale socket from a previous run would make bind fail
+    if opts.address.exists() {
+        fs::remove_file(&opts.address)?;
+    }
+    let incoming = UnixListenerStream::new(UnixListener::bind(&opts.address)?);
+
+    info!("serving snapshots on {}", opts.address.display());
+    Server::builder()
+        .add_service(containerd_snapshots::server(Arc::new(snapshotter)))
+        .serve_with_incoming(incoming)
+        .await?;
+    Ok(())
+}
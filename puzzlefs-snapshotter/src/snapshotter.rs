@@ -0,0 +1,591 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use containerd_snapshots::api::types::Mount;
+use containerd_snapshots::{Info, Kind, Snapshotter, Usage};
+use puzzlefs_lib::oci::Image;
+use puzzlefs_lib::reader::{spawn_mount, BackgroundSession, FuseConfig};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+use tracing::{info, warn};
+
+/// Label containerd sets on the snapshots it prepares while unpacking an image, with the chain id
+/// of the layer it's about to unpack. Remote snapshotters use it to provide the layer themselves.
+const TARGET_SNAPSHOT_LABEL: &str = "containerd.io/snapshot.ref";
+
+/// Label telling the snapshotter which puzzlefs image provides the contents of a layer, in the
+/// same `oci_dir:tag` format the puzzlefs cli uses.
+pub const PUZZLEFS_IMAGE_LABEL: &str = "puzzlefs.io/image";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SnapshotKind {
+    View,
+    Active,
+    Committed,
+}
+
+impl From<SnapshotKind> for Kind {
+    fn from(kind: SnapshotKind) -> Self {
+        match kind {
+            SnapshotKind::View => Kind::View,
+            SnapshotKind::Active => Kind::Active,
+            SnapshotKind::Committed => Kind::Committed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Snapshot {
+    id: u64,
+    kind: SnapshotKind,
+    parent: Option<String>,
+    labels: HashMap<String, String>,
+    created_at: SystemTime,
+    updated_at: SystemTime,
+    // the puzzlefs image mounted as the contents of this snapshot
+    image: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Metadata {
+    next_id: u64,
+    snapshots: BTreeMap<String, Snapshot>,
+}
+
+/// A containerd snapshotter which mounts puzzlefs images as read-only layers and stacks overlayfs
+/// on top of them for the containers' writable layers.
+///
+/// All the state lives under `root`: the snapshots' metadata in `metadata.json` and each snapshot's
+/// contents in `snapshots/<id>/fs` (which is the FUSE mountpoint for puzzlefs layers).
+pub struct PuzzleFsSnapshotter {
+    snapshots: Arc<Snapshots>,
+}
+
+// the state of the snapshotter, which its requests use on the blocking threads of the runtime,
+// since they write the metadata and mount and remove snapshots
+struct Snapshots {
+    root: PathBuf,
+    metadata: Mutex<Metadata>,
+    sessions: Mutex<HashMap<u64, BackgroundSession>>,
+}
+
+fn internal(e: impl std::fmt::Display) -> Status {
+    Status::internal(e.to_string())
+}
+
+fn not_found(key: &str) -> Status {
+    Status::not_found(format!("snapshot {key} does not exist"))
+}
+
+fn info(name: &str, snapshot: &Snapshot) -> Info {
+    Info {
+        kind: snapshot.kind.into(),
+        name: name.to_string(),
+        parent: snapshot.parent.clone().unwrap_or_default(),
+        labels: snapshot.labels.clone(),
+        created_at: snapshot.created_at,
+        updated_at: snapshot.updated_at,
+    }
+}
+
+fn bind_mount(source: &Path, readonly: bool) -> Mount {
+    Mount {
+        r#type: "bind".to_string(),
+        source: source.to_string_lossy().into_owned(),
+        options: vec![
+            "rbind".to_string(),
+            if readonly { "ro" } else { "rw" }.to_string(),
+        ],
+        ..Default::default()
+    }
+}
+
+fn overlay_mount(lower: &[PathBuf], upper: Option<(&Path, &Path)>) -> Mount {
+    let lowerdir = lower
+        .iter()
+        .map(|dir| dir.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(":");
+    let mut options = vec![format!("lowerdir={lowerdir}")];
+    if let Some((upperdir, workdir)) = upper {
+        options.push(format!("upperdir={}", upperdir.display()));
+        options.push(format!("workdir={}", workdir.display()));
+    }
+    Mount {
+        r#type: "overlay".to_string(),
+        source: "overlay".to_string(),
+        options,
+        ..Default::default()
+    }
+}
+
+fn disk_usage(path: &Path, usage: &mut Usage) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let md = entry.metadata()?;
+        usage.inodes += 1;
+        usage.size += md.blocks() as i64 * 512;
+        if md.is_dir() {
+            disk_usage(&entry.path(), usage)?;
+        }
+    }
+    Ok(())
+}
+
+impl PuzzleFsSnapshotter {
+    pub fn new(root: &Path) -> anyhow::Result<Self> {
+        Ok(PuzzleFsSnapshotter {
+            snapshots: Arc::new(Snapshots::open(root)?),
+        })
+    }
+
+    // runs `f` on a blocking thread, so the runtime can serve other requests meanwhile
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Snapshots) -> Result<T, Status> + Send + 'static,
+    ) -> Result<T, Status> {
+        let snapshots = Arc::clone(&self.snapshots);
+        tokio::task::spawn_blocking(move || f(&snapshots))
+            .await
+            .map_err(internal)?
+    }
+}
+
+impl Snapshots {
+    fn open(root: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(root.join("snapshots"))?;
+        let metadata = match fs::read(root.join("metadata.json")) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Metadata::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let snapshotter = Snapshots {
+            root: root.to_path_buf(),
+            metadata: Mutex::new(metadata),
+            sessions: Mutex::new(HashMap::new()),
+        };
+
+        // the FUSE mounts went away together with the previous snapshotter process
+        let images = snapshotter
+            .metadata
+            .lock()
+            .unwrap()
+            .snapshots
+            .values()
+            .filter_map(|s| s.image.clone().map(|image| (s.id, image)))
+            .collect::<Vec<_>>();
+        for (id, image) in images {
+            if let Err(e) = snapshotter.mount_image(id, &image) {
+                warn!("cannot mount {image} for snapshot {id}: {e}");
+            }
+        }
+
+        Ok(snapshotter)
+    }
+
+    fn snapshot_dir(&self, id: u64) -> PathBuf {
+        self.root.join("snapshots").join(id.to_string())
+    }
+
+    fn fs_dir(&self, id: u64) -> PathBuf {
+        self.snapshot_dir(id).join("fs")
+    }
+
+    fn work_dir(&self, id: u64) -> PathBuf {
+        self.snapshot_dir(id).join("work")
+    }
+
+    fn save(&self, metadata: &Metadata) -> Result<(), Status> {
+        let tmp = self.root.join("metadata.json.tmp");
+        fs::write(&tmp, serde_json::to_vec(metadata).map_err(internal)?).map_err(internal)?;
+        fs::rename(&tmp, self.root.join("metadata.json")).map_err(internal)
+    }
+
+    fn mount_image(&self, id: u64, image: &str) -> Result<(), Status> {
+        let (oci_dir, tag) = image.rsplit_once(':').ok_or_else(|| {
+            Status::invalid_argument(format!("{image} is not in the oci_dir:tag format"))
+        })?;
+        let mountpoint = self.fs_dir(id);
+        fs::create_dir_all(&mountpoint).map_err(internal)?;
+        let oci = Image::open(Path::new(oci_dir)).map_err(internal)?;
+        let session = spawn_mount(
+            oci,
+            tag,
+            &mountpoint,
+            &["ro"],
+            None,
+            None,
+            None,
+            &FuseConfig::default(),
+        )
+        .map_err(internal)?;
+        self.sessions.lock().unwrap().insert(id, session);
+        Ok(())
+    }
+
+    // the content directories of `parent` and all its ancestors, topmost first
+    fn lower_dirs(
+        &self,
+        metadata: &Metadata,
+        parent: Option<&str>,
+    ) -> Result<Vec<PathBuf>, Status> {
+        let mut dirs = Vec::new();
+        let mut current = parent;
+        while let Some(key) = current {
+            let snapshot = metadata.snapshots.get(key).ok_or_else(|| not_found(key))?;
+            dirs.push(self.fs_dir(snapshot.id));
+            current = snapshot.parent.as_deref();
+        }
+        Ok(dirs)
+    }
+
+    fn mounts_for(&self, metadata: &Metadata, key: &str) -> Result<Vec<Mount>, Status> {
+        let snapshot = metadata.snapshots.get(key).ok_or_else(|| not_found(key))?;
+        let lower = self.lower_dirs(metadata, snapshot.parent.as_deref())?;
+        let mount = match (snapshot.kind, lower.len()) {
+            (SnapshotKind::Committed, _) => {
+                return Err(Status::failed_precondition(format!(
+                    "snapshot {key} is committed"
+                )))
+            }
+            (SnapshotKind::Active, 0) => bind_mount(&self.fs_dir(snapshot.id), false),
+            (SnapshotKind::Active, _) => overlay_mount(
+                &lower,
+                Some((&self.fs_dir(snapshot.id), &self.work_dir(snapshot.id))),
+            ),
+            (SnapshotKind::View, 0) => bind_mount(&self.fs_dir(snapshot.id), true),
+            (SnapshotKind::View, 1) => bind_mount(&lower[0], true),
+            (SnapshotKind::View, _) => overlay_mount(&lower, None),
+        };
+        Ok(vec![mount])
+    }
+
+    fn create_snapshot(
+        &self,
+        kind: SnapshotKind,
+        key: String,
+        parent: String,
+        labels: HashMap<String, String>,
+    ) -> Result<Vec<Mount>, Status> {
+        let mut metadata = self.metadata.lock().unwrap();
+        if metadata.snapshots.contains_key(&key) {
+            return Err(Status::already_exists(format!(
+                "snapshot {key} already exists"
+            )));
+        }
+        let parent = (!parent.is_empty()).then_some(parent);
+        if let Some(parent) = &parent {
+            match metadata.snapshots.get(parent) {
+                Some(p) if p.kind == SnapshotKind::Committed => (),
+                Some(_) => {
+                    return Err(Status::failed_precondition(format!(
+                        "parent {parent} is not committed"
+                    )))
+                }
+                None => return Err(not_found(parent)),
+            }
+        }
+
+        let now = SystemTime::now();
+        let id = metadata.next_id;
+        metadata.next_id += 1;
+
+        // containerd is unpacking a layer we can provide from a puzzlefs image: mount the image as
+        // the committed target snapshot and tell containerd the layer already exists
+        let target = labels.get(TARGET_SNAPSHOT_LABEL);
+        let image = labels.get(PUZZLEFS_IMAGE_LABEL);
+        if let (SnapshotKind::Active, Some(target), Some(image)) = (kind, target, image) {
+            if metadata.snapshots.contains_key(target) {
+                return Err(Status::already_exists(format!(
+                    "target snapshot {target} already exists"
+                )));
+            }
+            self.mount_image(id, image)?;
+            info!("mounted {image} as snapshot {target}");
+            metadata.snapshots.insert(
+                target.clone(),
+                Snapshot {
+                    id,
+                    kind: SnapshotKind::Committed,
+                    parent,
+                    labels: labels.clone(),
+                    created_at: now,
+                    updated_at: now,
+                    image: Some(image.clone()),
+                },
+            );
+            self.save(&metadata)?;
+            return Err(Status::already_exists(format!(
+                "target snapshot {target} already exists"
+            )));
+        }
+
+        fs::create_dir_all(self.fs_dir(id)).map_err(internal)?;
+        fs::create_dir_all(self.work_dir(id)).map_err(internal)?;
+        metadata.snapshots.insert(
+            key.clone(),
+            Snapshot {
+                id,
+                kind,
+                parent,
+                labels,
+                created_at: now,
+                updated_at: now,
+                image: None,
+            },
+        );
+        self.save(&metadata)?;
+        self.mounts_for(&metadata, &key)
+    }
+
+    fn stat(&self, key: &str) -> Result<Info, Status> {
+        let metadata = self.metadata.lock().unwrap();
+        let snapshot = metadata.snapshots.get(key).ok_or_else(|| not_found(key))?;
+        Ok(info(key, snapshot))
+    }
+
+    fn update(&self, info_update: Info, fieldpaths: Option<Vec<String>>) -> Result<Info, Status> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let key = info_update.name.clone();
+        let snapshot = metadata
+            .snapshots
+            .get_mut(&key)
+            .ok_or_else(|| not_found(&key))?;
+
+        match fieldpaths {
+            Some(paths) if !paths.is_empty() => {
+                for path in paths {
+                    if path == "labels" {
+                        snapshot.labels = info_update.labels.clone();
+                    } else if let Some(label) = path.strip_prefix("labels.") {
+                        match info_update.labels.get(label) {
+                            Some(value) => snapshot.labels.insert(label.to_string(), value.clone()),
+                            None => snapshot.labels.remove(label),
+                        };
+                    } else {
+                        return Err(Status::invalid_argument(format!(
+                            "cannot update field {path}"
+                        )));
+                    }
+                }
+            }
+            _ => snapshot.labels = info_update.labels,
+        }
+        snapshot.updated_at = SystemTime::now();
+
+        let updated = info(&key, snapshot);
+        self.save(&metadata)?;
+        Ok(updated)
+    }
+
+    fn usage(&self, key: &str) -> Result<Usage, Status> {
+        let (id, image) = {
+            let metadata = self.metadata.lock().unwrap();
+            let snapshot = metadata.snapshots.get(key).ok_or_else(|| not_found(key))?;
+            (snapshot.id, snapshot.image.is_some())
+        };
+
+        let mut usage = Usage::default();
+        // puzzlefs layers live in the image's blobs, they don't use any space in the snapshot
+        if !image {
+            disk_usage(&self.fs_dir(id), &mut usage).map_err(internal)?;
+        }
+        Ok(usage)
+    }
+
+    fn mounts(&self, key: &str) -> Result<Vec<Mount>, Status> {
+        let metadata = self.metadata.lock().unwrap();
+        self.mounts_for(&metadata, key)
+    }
+
+    fn commit(
+        &self,
+        name: String,
+        key: String,
+        labels: HashMap<String, String>,
+    ) -> Result<(), Status> {
+        let mut metadata = self.metadata.lock().unwrap();
+        if metadata.snapshots.contains_key(&name) {
+            return Err(Status::already_exists(format!(
+                "snapshot {name} already exists"
+            )));
+        }
+        let mut snapshot = metadata
+            .snapshots
+            .remove(&key)
+            .ok_or_else(|| not_found(&key))?;
+        if snapshot.kind != SnapshotKind::Active {
+            metadata.snapshots.insert(key.clone(), snapshot);
+            return Err(Status::failed_precondition(format!(
+                "snapshot {key} is not active"
+            )));
+        }
+
+        snapshot.kind = SnapshotKind::Committed;
+        snapshot.labels.extend(labels);
+        snapshot.updated_at = SystemTime::now();
+        metadata.snapshots.insert(name, snapshot);
+        self.save(&metadata)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), Status> {
+        let mut metadata = self.metadata.lock().unwrap();
+        if metadata
+            .snapshots
+            .values()
+            .any(|s| s.parent.as_deref() == Some(key))
+        {
+            return Err(Status::failed_precondition(format!(
+                "snapshot {key} has children"
+            )));
+        }
+        let snapshot = metadata
+            .snapshots
+            .remove(key)
+            .ok_or_else(|| not_found(key))?;
+        self.save(&metadata)?;
+
+        // dropping the session unmounts the puzzlefs image
+        self.sessions.lock().unwrap().remove(&snapshot.id);
+        fs::remove_dir_all(self.snapshot_dir(snapshot.id)).map_err(internal)
+    }
+
+    fn list(&self) -> Vec<Result<Info, Status>> {
+        let metadata = self.metadata.lock().unwrap();
+        metadata
+            .snapshots
+            .iter()
+            .map(|(name, snapshot)| Ok(info(name, snapshot)))
+            .collect()
+    }
+}
+
+#[tonic::async_trait]
+impl Snapshotter for PuzzleFsSnapshotter {
+    type Error = Status;
+
+    async fn stat(&self, key: String) -> Result<Info, Self::Error> {
+        self.blocking(move |snapshots| snapshots.stat(&key)).await
+    }
+
+    async fn update(
+        &self,
+        info_update: Info,
+        fieldpaths: Option<Vec<String>>,
+    ) -> Result<Info, Self::Error> {
+        self.blocking(move |snapshots| snapshots.update(info_update, fieldpaths))
+            .await
+    }
+
+    async fn usage(&self, key: String) -> Result<Usage, Self::Error> {
+        self.blocking(move |snapshots| snapshots.usage(&key)).await
+    }
+
+    async fn mounts(&self, key: String) -> Result<Vec<Mount>, Self::Error> {
+        self.blocking(move |snapshots| snapshots.mounts(&key)).await
+    }
+
+    async fn prepare(
+        &self,
+        key: String,
+        parent: String,
+        labels: HashMap<String, String>,
+    ) -> Result<Vec<Mount>, Self::Error> {
+        self.blocking(move |snapshots| {
+            snapshots.create_snapshot(SnapshotKind::Active, key, parent, labels)
+        })
+        .await
+    }
+
+    async fn view(
+        &self,
+        key: String,
+        parent: String,
+        labels: HashMap<String, String>,
+    ) -> Result<Vec<Mount>, Self::Error> {
+        self.blocking(move |snapshots| {
+            snapshots.create_snapshot(SnapshotKind::View, key, parent, labels)
+        })
+        .await
+    }
+
+    async fn commit(
+        &self,
+        name: String,
+        key: String,
+        labels: HashMap<String, String>,
+    ) -> Result<(), Self::Error> {
+        self.blocking(move |snapshots| snapshots.commit(name, key, labels))
+            .await
+    }
+
+    async fn remove(&self, key: String) -> Result<(), Self::Error> {
+        self.blocking(move |snapshots| snapshots.remove(&key)).await
+    }
+
+    type InfoStream = futures::stream::Iter<std::vec::IntoIter<Result<Info, Self::Error>>>;
+
+    async fn list(
+        &self,
+        _snapshotter: String,
+        _filters: Vec<String>,
+    ) -> Result<Self::InfoStream, Self::Error> {
+        let infos = self.blocking(|snapshots| Ok(snapshots.list())).await?;
+        Ok(futures::stream::iter(infos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_snapshot_chain() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let snapshotter = PuzzleFsSnapshotter::new(dir.path())?;
+
+        let mounts = snapshotter
+            .prepare("base-active".into(), "".into(), HashMap::new())
+            .await?;
+        assert_eq!(mounts[0].r#type, "bind");
+        snapshotter
+            .commit("base".into(), "base-active".into(), HashMap::new())
+            .await?;
+
+        let mounts = snapshotter
+            .prepare("container".into(), "base".into(), HashMap::new())
+            .await?;
+        assert_eq!(mounts[0].r#type, "overlay");
+        assert_eq!(
+            mounts[0].options[0],
+            format!("lowerdir={}", snapshotter.snapshots.fs_dir(0).display())
+        );
+
+        let mounts = snapshotter
+            .view("view".into(), "base".into(), HashMap::new())
+            .await?;
+        assert_eq!(mounts[0].r#type, "bind");
+        assert!(mounts[0].options.contains(&"ro".to_string()));
+
+        assert!(snapshotter.remove("base".into()).await.is_err());
+        snapshotter.remove("container".into()).await?;
+        snapshotter.remove("view".into()).await?;
+        snapshotter.remove("base".into()).await?;
+
+        // the metadata survives restarts
+        snapshotter
+            .prepare("other".into(), "".into(), HashMap::new())
+            .await?;
+        let snapshotter = PuzzleFsSnapshotter::new(dir.path())?;
+        assert_eq!(snapshotter.stat("other".into()).await?.name, "other");
+        Ok(())
+    }
+}